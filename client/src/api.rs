@@ -0,0 +1,265 @@
+//! Typed clients for the inspector and relay HTTP APIs
+//!
+//! The APIs are described by the OpenAPI documents each server serves
+//! at `/openapi.json` (`client/assets/openapi.json` for the inspector,
+//! `relay/assets/openapi.json` for the relay). These clients speak the
+//! same contract with the types the servers serialize, so tools built
+//! on them break at compile time rather than on a renamed field.
+
+use crate::export::SessionExport;
+use crate::frames::WsFrame;
+use crate::inspector::{EntryQuery, InspectorEntry, PauseRequest};
+use crate::intercept::HeldRequest;
+use crate::replay::ReplayOverrides;
+use crate::stats::{Timeline, TrafficStats};
+use anyhow::{Context, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+/// One page of `/api/entries`
+#[derive(Debug)]
+pub struct EntryPage {
+    /// Matches across all pages
+    pub total: usize,
+    pub entries: Vec<InspectorEntry>,
+}
+
+/// Answer to `/api/import`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct ImportResult {
+    pub imported: usize,
+    /// Entries already present
+    pub skipped: usize,
+}
+
+/// Answer to the relay's `/health`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct RelayHealth {
+    pub status: String,
+    pub active_tunnels: u64,
+}
+
+/// Client for a running inspector
+#[derive(Debug, Clone)]
+pub struct InspectorClient {
+    http: reqwest::Client,
+    base: String,
+}
+
+impl InspectorClient {
+    /// The inspector on this machine's `port`
+    pub fn new(port: u16) -> Self {
+        Self::with_base(format!("http://127.0.0.1:{}", port))
+    }
+
+    pub fn with_base(base: impl Into<String>) -> Self {
+        Self { http: reqwest::Client::new(), base: base.into().trim_end_matches('/').to_string() }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base, path)
+    }
+
+    pub async fn openapi(&self) -> Result<serde_json::Value> {
+        json(self.http.get(self.url("/openapi.json"))).await
+    }
+
+    pub async fn entries(&self, query: &EntryQuery) -> Result<EntryPage> {
+        let resp = send(self.http.get(self.url("/api/entries")).query(query)).await?;
+        let total = resp
+            .headers()
+            .get("x-total-count")
+            .and_then(|v| v.to_str().ok()?.parse().ok())
+            .context("Missing X-Total-Count")?;
+        Ok(EntryPage { total, entries: resp.json().await? })
+    }
+
+    /// Delete every entry, or one tunnel's; returns how many went
+    pub async fn clear(&self, tunnel: Option<&str>) -> Result<usize> {
+        #[derive(Deserialize)]
+        struct Cleared {
+            deleted: usize,
+        }
+        let cleared: Cleared = json(self.http.delete(self.url("/api/entries")).query(&[("tunnel", tunnel)])).await?;
+        Ok(cleared.deleted)
+    }
+
+    /// Delete one entry; false if there was none
+    pub async fn delete(&self, id: &str) -> Result<bool> {
+        let resp = self.http.delete(self.url(&format!("/api/entries/{}", id))).send().await?;
+        if resp.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        check(resp).await?;
+        Ok(true)
+    }
+
+    /// Replay an entry, returning the new entry's ID
+    pub async fn replay(&self, id: &str, overrides: &ReplayOverrides, target: Option<&str>) -> Result<String> {
+        #[derive(Deserialize)]
+        struct Replayed {
+            id: String,
+        }
+        let request = self.http.post(self.url(&format!("/replay/{}", id))).query(&[("target", target)]).json(overrides);
+        let replayed: Replayed = json(request).await?;
+        Ok(replayed.id)
+    }
+
+    pub async fn frames(&self, id: &str) -> Result<Vec<WsFrame>> {
+        json(self.http.get(self.url(&format!("/api/entries/{}/frames", id)))).await
+    }
+
+    pub async fn stats(&self, query: &EntryQuery) -> Result<TrafficStats> {
+        json(self.http.get(self.url("/api/stats")).query(query)).await
+    }
+
+    pub async fn timeline(&self, query: &EntryQuery) -> Result<Timeline> {
+        json(self.http.get(self.url("/api/timeline")).query(query)).await
+    }
+
+    pub async fn export(&self, query: &EntryQuery) -> Result<SessionExport> {
+        json(self.http.get(self.url("/api/export")).query(query)).await
+    }
+
+    pub async fn import(&self, session: &SessionExport) -> Result<ImportResult> {
+        json(self.http.post(self.url("/api/import")).json(session)).await
+    }
+
+    pub async fn restart_tunnel(&self, name: &str) -> Result<()> {
+        send(self.http.post(self.url(&format!("/api/tunnels/{}/restart", name)))).await?;
+        Ok(())
+    }
+
+    /// Answer 503 on a tunnel with `page`, or its `pause_page`
+    pub async fn pause_tunnel(&self, name: &str, page: Option<String>) -> Result<()> {
+        let request = self.http.post(self.url(&format!("/api/tunnels/{}/pause", name))).json(&PauseRequest { page });
+        send(request).await?;
+        Ok(())
+    }
+
+    pub async fn resume_tunnel(&self, name: &str) -> Result<()> {
+        send(self.http.post(self.url(&format!("/api/tunnels/{}/resume", name)))).await?;
+        Ok(())
+    }
+
+    pub async fn intercepts(&self) -> Result<Vec<HeldRequest>> {
+        json(self.http.get(self.url("/api/intercepts"))).await
+    }
+
+    pub async fn approve(&self, id: &str, edits: &ReplayOverrides) -> Result<()> {
+        send(self.http.post(self.url(&format!("/api/intercepts/{}/approve", id))).json(edits)).await?;
+        Ok(())
+    }
+
+    /// Answer a held request with `status` (403 when `None`) and `body`
+    pub async fn reject(&self, id: &str, status: Option<u16>, body: Option<&str>) -> Result<()> {
+        #[derive(Serialize)]
+        struct Reject<'a> {
+            status: Option<u16>,
+            body: Option<&'a str>,
+        }
+        let request = self.http.post(self.url(&format!("/api/intercepts/{}/reject", id)));
+        send(request.json(&Reject { status, body })).await?;
+        Ok(())
+    }
+}
+
+/// Client for a relay's public endpoints
+#[derive(Debug, Clone)]
+pub struct RelayClient {
+    http: reqwest::Client,
+    base: String,
+}
+
+impl RelayClient {
+    /// `base` is the relay's HTTP(S) address, e.g. `https://relay.example.com`
+    pub fn new(base: impl Into<String>) -> Self {
+        Self { http: reqwest::Client::new(), base: base.into().trim_end_matches('/').to_string() }
+    }
+
+    pub async fn openapi(&self) -> Result<serde_json::Value> {
+        json(self.http.get(format!("{}/openapi.json", self.base))).await
+    }
+
+    pub async fn health(&self) -> Result<RelayHealth> {
+        json(self.http.get(format!("{}/health", self.base))).await
+    }
+
+    /// Prometheus text exposition
+    pub async fn metrics(&self) -> Result<String> {
+        Ok(send(self.http.get(format!("{}/metrics", self.base))).await?.text().await?)
+    }
+}
+
+async fn send(request: reqwest::RequestBuilder) -> Result<reqwest::Response> {
+    check(request.send().await?).await
+}
+
+/// Fail with the server's explanation on a non-2xx status
+async fn check(resp: reqwest::Response) -> Result<reqwest::Response> {
+    if resp.status().is_success() {
+        return Ok(resp);
+    }
+    let status = resp.status();
+    anyhow::bail!("HTTP {}: {}", status, resp.text().await.unwrap_or_default().trim())
+}
+
+async fn json<T: DeserializeOwned>(request: reqwest::RequestBuilder) -> Result<T> {
+    Ok(send(request).await?.json().await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inspector::{self, InspectorState};
+
+    /// Every path the client calls is in the served document, and the
+    /// typed answers parse what the inspector sends
+    #[tokio::test]
+    async fn test_inspector_client_matches_server() {
+        let state = InspectorState::new(tokio::sync::mpsc::channel(1).0);
+        state
+            .import(vec![InspectorEntry {
+                id: "r1".into(),
+                tunnel: "web".into(),
+                timestamp: "2024-05-01T10:00:00Z".into(),
+                method: "GET".into(),
+                path: "/health".into(),
+                status: 200,
+                ..Default::default()
+            }])
+            .await;
+        let pause = crate::pause::Pause::default();
+        state.pauses().insert("web", pause.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, inspector::router(state)).await });
+        let client = InspectorClient::with_base(base);
+
+        let spec = client.openapi().await.unwrap();
+        assert_eq!(spec, serde_json::from_str::<serde_json::Value>(inspector::OPENAPI).unwrap());
+        let paths = ["/api/entries", "/api/entries/{id}/frames", "/api/stats", "/api/export", "/api/import", "/replay/{id}"];
+        let added = ["/api/tunnels/{name}/pause", "/api/tunnels/{name}/resume", "/api/timeline"];
+        for path in paths.into_iter().chain(added) {
+            assert!(spec["paths"].get(path).is_some(), "{} missing from openapi.json", path);
+        }
+
+        let page = client.entries(&EntryQuery { tunnel: Some("web".into()), ..Default::default() }).await.unwrap();
+        assert_eq!((page.total, page.entries[0].id.as_str()), (1, "r1"));
+        assert_eq!(client.stats(&EntryQuery::default()).await.unwrap().total.count, 1);
+        assert_eq!(client.timeline(&EntryQuery::default()).await.unwrap().buckets[0].requests, 1);
+
+        let session = client.export(&EntryQuery::default()).await.unwrap();
+        assert_eq!(client.import(&session).await.unwrap(), ImportResult { imported: 0, skipped: 1 });
+        assert!(client.intercepts().await.unwrap().is_empty());
+        assert!(client.frames("r1").await.is_err());
+        assert!(!client.delete("nope").await.unwrap());
+        assert_eq!(client.clear(None).await.unwrap(), 1);
+
+        client.pause_tunnel("web", Some("Back soon".into())).await.unwrap();
+        assert_eq!(pause.response().unwrap().2, b"Back soon");
+        client.resume_tunnel("web").await.unwrap();
+        assert!(!pause.is_paused());
+        assert!(client.pause_tunnel("nope", None).await.is_err());
+    }
+}
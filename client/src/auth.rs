@@ -0,0 +1,81 @@
+//! Client-enforced HTTP basic auth
+//!
+//! Lets a tunnel demand credentials before any request reaches the
+//! local service, independent of what the relay enforces.
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+
+/// Expected basic auth credentials for a tunnel
+#[derive(Debug, Clone, PartialEq)]
+pub struct BasicAuth {
+    credentials: String,
+}
+
+impl BasicAuth {
+    /// Parse a `user:pass` spec
+    pub fn parse(spec: &str) -> Option<Self> {
+        let (user, _pass) = spec.split_once(':')?;
+        if user.is_empty() {
+            return None;
+        }
+        Some(Self { credentials: spec.to_string() })
+    }
+
+    /// Check the request's Authorization header
+    pub fn is_authorized(&self, headers: &[(String, String)]) -> bool {
+        headers
+            .iter()
+            .filter(|(k, _)| k.eq_ignore_ascii_case("authorization"))
+            .any(|(_, v)| self.matches(v))
+    }
+
+    fn matches(&self, value: &str) -> bool {
+        let Some((scheme, encoded)) = value.trim().split_once(' ') else {
+            return false;
+        };
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return false;
+        }
+        match STANDARD.decode(encoded.trim()) {
+            Ok(decoded) => constant_time_eq(&decoded, self.credentials.as_bytes()),
+            Err(_) => false,
+        }
+    }
+}
+
+/// Compare without short-circuiting on the first differing byte
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(value: &str) -> Vec<(String, String)> {
+        vec![("Authorization".to_string(), value.to_string())]
+    }
+
+    #[test]
+    fn test_basic_auth() {
+        let auth = BasicAuth::parse("demo:s3cret").unwrap();
+        let good = format!("Basic {}", STANDARD.encode("demo:s3cret"));
+        let bad = format!("Basic {}", STANDARD.encode("demo:wrong"));
+
+        assert!(auth.is_authorized(&header(&good)));
+        assert!(!auth.is_authorized(&header(&bad)));
+        assert!(!auth.is_authorized(&header("Bearer abc")));
+        assert!(!auth.is_authorized(&[]));
+    }
+
+    #[test]
+    fn test_parse() {
+        assert!(BasicAuth::parse("user:pass:with:colons").is_some());
+        assert!(BasicAuth::parse("nopassword").is_none());
+        assert!(BasicAuth::parse(":pass").is_none());
+    }
+}
@@ -0,0 +1,85 @@
+//! Reconnect backoff
+//!
+//! Jittered exponential backoff used when a tunnel loses its
+//! relay connection.
+
+use std::time::Duration;
+
+/// Exponential backoff with "equal jitter": each delay is half the
+/// capped exponential step plus a random share of the other half.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    attempt: u32,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new(Duration::from_millis(500), Duration::from_secs(60))
+    }
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Self { base, max, attempt: 0 }
+    }
+
+    /// Number of delays handed out since the last reset
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    /// Reset after a successful connection
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+
+    /// Delay before the next attempt
+    pub fn next_delay(&mut self) -> Duration {
+        let step = self
+            .base
+            .saturating_mul(1u32 << self.attempt.min(16))
+            .min(self.max);
+        self.attempt = self.attempt.saturating_add(1);
+
+        let half = step / 2;
+        half + half.mul_f64(jitter())
+    }
+}
+
+/// Pseudo-random fraction in [0, 1) — good enough to spread reconnects
+fn jitter() -> f64 {
+    use std::time::{SystemTime, UNIX_EPOCH};
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos();
+    (nanos % 1_000_000) as f64 / 1_000_000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_grows_and_caps() {
+        let mut b = Backoff::new(Duration::from_secs(1), Duration::from_secs(8));
+        let bounds = [(500, 1000), (1000, 2000), (2000, 4000), (4000, 8000), (4000, 8000)];
+        for (lo, hi) in bounds {
+            let d = b.next_delay().as_millis();
+            assert!(d >= lo && d <= hi, "{} not in {}..={}", d, lo, hi);
+        }
+        assert_eq!(b.attempt(), 5);
+    }
+
+    #[test]
+    fn test_backoff_reset() {
+        let mut b = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+        b.next_delay();
+        b.next_delay();
+        b.reset();
+        assert_eq!(b.attempt(), 0);
+        assert!(b.next_delay() <= Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,151 @@
+//! Batched writes to the relay
+//!
+//! `BatchSink` sits in front of the relay WebSocket when the relay takes
+//! batches (`capability::BATCH`). Small binary messages written to it
+//! are held in a `Batcher` instead of going out one by one; the serve
+//! loop calls `release` when the batch's deadline comes. Anything else
+//! (large messages, pings, ...) first releases the batch so order is
+//! kept. With `with_deflate`, binary messages are compressed on their
+//! way out (see `ztunnel_shared::deflate`).
+
+use futures_util::{Sink, SinkExt};
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Instant;
+use tokio_tungstenite::tungstenite::Message;
+use ztunnel_shared::batch::{self, Batcher};
+use ztunnel_shared::deflate;
+
+pub struct BatchSink<S> {
+    inner: S,
+    /// `None` when the relay doesn't take batches
+    batcher: Option<Batcher>,
+    /// Messages ready for `inner`, in order
+    queue: VecDeque<Message>,
+    /// Compress binary messages
+    deflate: bool,
+}
+
+impl<S: Sink<Message> + Unpin> BatchSink<S> {
+    pub fn new(inner: S, batching: bool) -> Self {
+        Self { inner, batcher: batching.then(Batcher::default), queue: VecDeque::new(), deflate: false }
+    }
+
+    pub fn with_deflate(mut self, deflate: bool) -> Self {
+        self.deflate = deflate;
+        self
+    }
+
+    /// When the held batch must go out
+    pub fn deadline(&self) -> Option<Instant> {
+        self.batcher.as_ref().and_then(Batcher::deadline)
+    }
+
+    /// Send the held batch with the next flush
+    pub fn release(&mut self) {
+        if let Some(data) = self.batcher.as_mut().and_then(Batcher::take) {
+            self.enqueue(Message::Binary(data));
+        }
+    }
+
+    fn enqueue(&mut self, message: Message) {
+        let message = match message {
+            Message::Binary(data) if self.deflate => Message::Binary(deflate::compress(data)),
+            message => message,
+        };
+        self.queue.push_back(message);
+    }
+
+    /// Hand queued messages to the inner sink
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        while !self.queue.is_empty() {
+            ready!(self.inner.poll_ready_unpin(cx))?;
+            if let Some(message) = self.queue.pop_front() {
+                self.inner.start_send_unpin(message)?;
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: Sink<Message> + Unpin> Sink<Message> for BatchSink<S> {
+    type Error = S::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.get_mut().poll_drain(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, message: Message) -> Result<(), Self::Error> {
+        let this = self.get_mut();
+        match (&mut this.batcher, message) {
+            (Some(batcher), Message::Binary(data)) if batch::fits(&data) => {
+                if let Some(full) = batcher.push(&data) {
+                    this.enqueue(Message::Binary(full));
+                }
+            }
+            (_, message) => {
+                this.release();
+                this.enqueue(message);
+            }
+        }
+        Ok(())
+    }
+
+    /// Flushes what has been handed on; a held batch waits for `release`
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        this.inner.poll_flush_unpin(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        this.release();
+        ready!(this.poll_drain(cx))?;
+        this.inner.poll_close_unpin(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_small_messages_wait_for_release() {
+        let mut sink = BatchSink::new(Vec::<Message>::new(), true);
+        sink.send(Message::Binary(b"{\"id\":1}".to_vec())).await.unwrap();
+        sink.send(Message::Binary(b"{\"id\":2}".to_vec())).await.unwrap();
+        assert!(sink.inner.is_empty());
+        assert!(sink.deadline().is_some());
+
+        // A control message pushes the batch out ahead of it
+        sink.send(Message::Ping(Vec::new())).await.unwrap();
+        assert_eq!(sink.inner, [Message::Binary(b"{\"id\":1}\n{\"id\":2}".to_vec()), Message::Ping(Vec::new())]);
+
+        sink.send(Message::Binary(b"{\"id\":3}".to_vec())).await.unwrap();
+        sink.release();
+        sink.flush().await.unwrap();
+        assert_eq!(sink.inner.last(), Some(&Message::Binary(b"{\"id\":3}".to_vec())));
+        assert_eq!(sink.deadline(), None);
+
+        let mut plain = BatchSink::new(Vec::<Message>::new(), false);
+        plain.send(Message::Binary(b"{}".to_vec())).await.unwrap();
+        assert_eq!(plain.inner.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_deflate_on_the_way_out() {
+        let mut sink = BatchSink::new(Vec::<Message>::new(), true).with_deflate(true);
+        let message = br#"{"id":"r1","status":200,"headers":[]}"#.to_vec();
+        for _ in 0..20 {
+            sink.send(Message::Binary(message.clone())).await.unwrap();
+        }
+        sink.release();
+        sink.flush().await.unwrap();
+        let Some(Message::Binary(sent)) = sink.inner.pop() else { panic!("nothing sent") };
+        assert!(deflate::is_compressed(&sent));
+        let inflated = deflate::decompress(&sent).unwrap();
+        assert_eq!(batch::split(&inflated).count(), 20);
+    }
+}
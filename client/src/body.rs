@@ -0,0 +1,159 @@
+//! Captured request and response bodies
+//!
+//! Bodies are kept as bytes, cut at the inspector's `max_body_bytes`.
+//! In JSON they appear as `{data, encoding, size, truncated}`: UTF-8
+//! text as-is, anything else base64, so binary payloads survive the
+//! round trip through the API and the history database. The bytes are
+//! shared with the response sent through the tunnel, not copied.
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use bytes::Bytes;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::borrow::Cow;
+
+/// A body as captured, possibly cut short
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Body {
+    bytes: Bytes,
+    /// Size before truncation
+    size: usize,
+}
+
+/// How `data` is encoded in JSON
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Encoding {
+    Utf8,
+    Base64,
+}
+
+/// `data` borrows the body's text when serializing
+#[derive(Serialize, Deserialize)]
+struct Repr<D> {
+    data: D,
+    encoding: Encoding,
+    size: usize,
+    truncated: bool,
+}
+
+impl Body {
+    pub fn new(bytes: impl Into<Bytes>) -> Self {
+        let bytes = bytes.into();
+        Self { size: bytes.len(), bytes }
+    }
+
+    /// The first bytes of a body that was `size` long but not kept whole
+    pub fn partial(bytes: impl Into<Bytes>, size: usize) -> Self {
+        let bytes = bytes.into();
+        Self { size: size.max(bytes.len()), bytes }
+    }
+
+    /// The captured bytes; fewer than `size` if truncated
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn truncated(&self) -> bool {
+        self.bytes.len() < self.size
+    }
+
+    /// Keep at most `limit` bytes
+    pub fn truncate(&mut self, limit: usize) {
+        self.bytes.truncate(limit);
+    }
+
+    /// A copy holding at most `limit` bytes
+    pub fn clipped(&self, limit: usize) -> Self {
+        Self { bytes: self.bytes.slice(..self.bytes.len().min(limit)), size: self.size }
+    }
+
+    /// The bytes as text, if they are UTF-8. A character split by
+    /// truncation is dropped rather than making the body binary.
+    pub fn text(&self) -> Option<&str> {
+        match std::str::from_utf8(&self.bytes) {
+            Ok(text) => Some(text),
+            Err(e) if self.truncated() && e.error_len().is_none() => {
+                std::str::from_utf8(&self.bytes[..e.valid_up_to()]).ok()
+            }
+            Err(_) => None,
+        }
+    }
+}
+
+impl Serialize for Body {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let (data, encoding) = match self.text() {
+            Some(text) => (Cow::Borrowed(text), Encoding::Utf8),
+            None => (Cow::Owned(STANDARD.encode(&self.bytes)), Encoding::Base64),
+        };
+        Repr { data, encoding, size: self.size, truncated: self.truncated() }.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Body {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // Entries saved before bodies were bytes hold plain strings
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Stored {
+            Repr(Repr<String>),
+            Text(String),
+        }
+        match Stored::deserialize(deserializer)? {
+            Stored::Text(text) => Ok(Body::new(text)),
+            Stored::Repr(repr) => {
+                let bytes = match repr.encoding {
+                    Encoding::Utf8 => repr.data.into_bytes(),
+                    Encoding::Base64 => STANDARD.decode(&repr.data).map_err(serde::de::Error::custom)?,
+                };
+                Ok(Body::partial(bytes, repr.size))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(body: &Body) -> (serde_json::Value, Body) {
+        let json = serde_json::to_value(body).unwrap();
+        (json.clone(), serde_json::from_value(json).unwrap())
+    }
+
+    #[test]
+    fn test_text_and_binary_round_trip() {
+        let (json, back) = round_trip(&Body::new("{\"ok\":true}"));
+        assert_eq!((json["encoding"].as_str(), json["data"].as_str()), (Some("utf8"), Some("{\"ok\":true}")));
+        assert_eq!(back, Body::new("{\"ok\":true}"));
+
+        let png = Body::new(vec![0x89, b'P', b'N', b'G', 0, 0xff]);
+        let (json, back) = round_trip(&png);
+        assert_eq!(json["encoding"], "base64");
+        assert_eq!(back, png);
+
+        // Legacy entries stored the body as a string
+        let legacy: Body = serde_json::from_str("\"hello\"").unwrap();
+        assert_eq!(legacy, Body::new("hello"));
+    }
+
+    #[test]
+    fn test_truncation() {
+        let mut body = Body::new("héllo wörld");
+        body.truncate(2);
+        assert!(body.truncated());
+        assert_eq!((body.size(), body.text()), (13, Some("h")));
+
+        let (json, back) = round_trip(&body);
+        assert_eq!((json["truncated"].as_bool(), json["size"].as_u64()), (Some(true), Some(13)));
+        assert_eq!((back.bytes(), back.size()), (&b"h"[..], 13));
+
+        let clipped = Body::new(vec![1, 2, 3]).clipped(2);
+        assert_eq!((clipped.bytes(), clipped.truncated()), (&[1u8, 2][..], true));
+    }
+}
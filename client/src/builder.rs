@@ -0,0 +1,238 @@
+//! Programmatic tunnels
+//!
+//! `TunnelBuilder` opens one tunnel the way `ztunnel http` and `ztunnel
+//! tcp` do, for Rust programs and test harnesses. `start` returns once
+//! the relay has registered the tunnel; after that it reconnects on its
+//! own until the `Tunnel` is dropped. Progress is reported to an
+//! optional `on_event` callback instead of the console, and metrics to
+//! an optional `Telemetry` backend.
+
+use crate::config::TunnelConfig;
+use crate::inspector::{self, InspectorEntry, InspectorState};
+use crate::replay::{self, ReplayTarget};
+use crate::session::{self, Registration, TunnelContext};
+use anyhow::Result;
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use ztunnel_shared::Telemetry;
+
+/// Relay used when none is given, as for the CLI
+const DEFAULT_RELAY: &str = "ws://localhost:8080/tunnel";
+
+/// Something that happened to a tunnel
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub enum TunnelEvent {
+    /// Registered with a relay; `attempts` is 0 the first time and the
+    /// number of failed rounds after a reconnect
+    Connected { url: String, relay: String, attempts: u32 },
+    /// An HTTP request was answered
+    Request(Box<InspectorEntry>),
+    /// The relay refused the tunnel after it had been up; it won't retry
+    Failed(String),
+}
+
+type EventHandler = Arc<dyn Fn(TunnelEvent) + Send + Sync>;
+
+/// Options for one tunnel
+pub struct TunnelBuilder {
+    conf: TunnelConfig,
+    relays: Vec<String>,
+    auth_token: Option<String>,
+    inspector_port: Option<u16>,
+    on_event: Option<EventHandler>,
+    telemetry: Option<Arc<dyn Telemetry>>,
+}
+
+impl TunnelBuilder {
+    /// An HTTP tunnel to `localhost:port`
+    pub fn http(port: u16) -> Self {
+        Self::from_config(TunnelConfig { name: "http".to_string(), local_port: port, ..Default::default() })
+    }
+
+    /// A raw TCP tunnel to `localhost:port`
+    pub fn tcp(port: u16) -> Self {
+        Self::from_config(TunnelConfig {
+            name: "tcp".to_string(),
+            proto: "tcp".to_string(),
+            local_port: port,
+            inspect: false,
+            ..Default::default()
+        })
+    }
+
+    /// A tunnel with every `ztunnel.yml` option available
+    pub fn from_config(conf: TunnelConfig) -> Self {
+        Self { conf, relays: Vec::new(), auth_token: None, inspector_port: None, on_event: None, telemetry: None }
+    }
+
+    /// Relay to register with; call again to add failover relays
+    pub fn relay(mut self, url: impl Into<String>) -> Self {
+        self.relays.push(url.into());
+        self
+    }
+
+    pub fn subdomain(mut self, subdomain: impl Into<String>) -> Self {
+        self.conf.subdomain = Some(subdomain.into());
+        self
+    }
+
+    pub fn auth_token(mut self, token: impl Into<String>) -> Self {
+        self.auth_token = Some(token.into());
+        self
+    }
+
+    /// Serve the inspector dashboard and API on `port`
+    pub fn inspector(mut self, port: u16) -> Self {
+        self.inspector_port = Some(port);
+        self
+    }
+
+    /// Called from the tunnel's tasks for every `TunnelEvent`
+    pub fn on_event(mut self, handler: impl Fn(TunnelEvent) + Send + Sync + 'static) -> Self {
+        self.on_event = Some(Arc::new(handler));
+        self
+    }
+
+    /// Record request and reconnect metrics to `backend`
+    pub fn telemetry(mut self, backend: impl Telemetry + 'static) -> Self {
+        self.telemetry = Some(Arc::new(backend));
+        self
+    }
+
+    /// Connect and register. Fails if the relay refuses the tunnel; an
+    /// unreachable relay is retried with backoff, so callers that can't
+    /// wait should wrap this in a timeout.
+    pub async fn start(self) -> Result<Tunnel> {
+        self.conf.validate()?;
+        let relays = if self.relays.is_empty() { vec![DEFAULT_RELAY.to_string()] } else { self.relays };
+        let on_event = self.on_event;
+
+        let (entry_tx, mut entry_rx) = mpsc::channel::<InspectorEntry>(256);
+        let mut ctx = TunnelContext::new(self.conf, entry_tx);
+        ctx.auth_token = self.auth_token;
+        if let Some(telemetry) = self.telemetry {
+            ctx.telemetry = telemetry;
+        }
+
+        let mut tasks = Vec::new();
+        let inspector = match self.inspector_port {
+            Some(port) if ctx.conf.proto == "http" => {
+                let (replay_tx, replay_rx) = mpsc::channel(32);
+                let state = InspectorState::new(replay_tx);
+                state.replay_targets().insert(&ctx.conf.name, ReplayTarget::for_tunnel(&ctx));
+                ctx.frames = Some(state.frames());
+                tasks.push(tokio::spawn(replay::serve(replay_rx, state.clone(), state.replay_targets())));
+                tasks.push(tokio::spawn(inspector::start_inspector(state.clone(), port)));
+                Some(state)
+            }
+            _ => None,
+        };
+
+        let (recorder, handler) = (inspector.clone(), on_event.clone());
+        tasks.push(tokio::spawn(async move {
+            while let Some(entry) = entry_rx.recv().await {
+                if let Some(handler) = &handler {
+                    handler(TunnelEvent::Request(Box::new(entry.clone())));
+                }
+                if let Some(inspector) = &recorder {
+                    inspector.record(entry).await;
+                }
+            }
+        }));
+
+        let (ready_tx, ready_rx) = oneshot::channel::<Result<Registration>>();
+        tasks.push(tokio::spawn(async move {
+            let mut ready = Some(ready_tx);
+            let result = session::run_with_reconnect(&relays, &mut ctx, |reg, attempts| {
+                if let Some(ready) = ready.take() {
+                    let _ = ready.send(Ok(reg.clone()));
+                }
+                if let Some(handler) = &on_event {
+                    handler(TunnelEvent::Connected { url: reg.url.clone(), relay: reg.relay.clone(), attempts });
+                }
+            })
+            .await;
+            if let Err(e) = result {
+                match (ready.take(), &on_event) {
+                    (Some(ready), _) => {
+                        let _ = ready.send(Err(e));
+                    }
+                    (None, Some(handler)) => handler(TunnelEvent::Failed(e.to_string())),
+                    (None, None) => {}
+                }
+            }
+        }));
+
+        // Dropped, and so torn down, if registration fails
+        let mut tunnel = Tunnel { registration: None, inspector, tasks };
+        match ready_rx.await {
+            Ok(Ok(registration)) => {
+                tunnel.registration = Some(registration);
+                Ok(tunnel)
+            }
+            Ok(Err(e)) => Err(e),
+            Err(_) => anyhow::bail!("Tunnel task ended before registering"),
+        }
+    }
+}
+
+/// A running tunnel; dropping it closes the tunnel
+pub struct Tunnel {
+    /// Set once registered, which `start` waits for
+    registration: Option<Registration>,
+    inspector: Option<InspectorState>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Tunnel {
+    /// Public URL from the first registration
+    pub fn url(&self) -> &str {
+        self.registration.as_ref().map_or("", |r| r.url.as_str())
+    }
+
+    /// Subdomain the relay assigned
+    pub fn subdomain(&self) -> &str {
+        self.registration.as_ref().map_or("", |r| r.subdomain.as_str())
+    }
+
+    /// Relay the tunnel first registered with
+    pub fn relay(&self) -> &str {
+        self.registration.as_ref().map_or("", |r| r.relay.as_str())
+    }
+
+    /// Recorded requests, when started with `inspector`
+    pub fn inspector(&self) -> Option<&InspectorState> {
+        self.inspector.as_ref()
+    }
+
+    /// Close the tunnel and its inspector
+    pub fn stop(self) {}
+}
+
+impl Drop for Tunnel {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_start_reports_registration_failure() {
+        let err = TunnelBuilder::http(0).start().await.err().unwrap();
+        assert!(err.to_string().contains("Invalid port 0"));
+
+        let err = TunnelBuilder::http(3000)
+            .relay("not a url")
+            .on_event(|_| panic!("no events expected"))
+            .start();
+        // An unreachable relay is retried, so `start` keeps waiting
+        assert!(tokio::time::timeout(std::time::Duration::from_millis(200), err).await.is_err());
+    }
+}
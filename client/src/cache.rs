@@ -0,0 +1,196 @@
+//! Local response cache
+//!
+//! A tunnel with a `cache` section answers repeated GET and HEAD
+//! requests from memory for `ttl`, so polling clients and asset-heavy
+//! pages don't hammer a slow dev server. The key is the method, the
+//! path with its query, and the values of the `vary` headers. Only 2xx
+//! responses without `Cache-Control: no-store`/`private` or
+//! `Set-Cookie` are stored. Inspector entries are tagged hit or miss.
+
+use crate::config::CacheConfig;
+use crate::tunnel::TunnelRequest;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use ztunnel_shared::glob::matches_glob;
+
+/// Header added to answers that went through the cache
+pub const HEADER: &str = "X-Ztunnel-Cache";
+
+/// Whether a cacheable request was answered from the cache
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CacheStatus {
+    Hit,
+    Miss,
+}
+
+impl CacheStatus {
+    fn as_str(self) -> &'static str {
+        match self {
+            CacheStatus::Hit => "HIT",
+            CacheStatus::Miss => "MISS",
+        }
+    }
+}
+
+type Response = (u16, Vec<(String, String)>, Vec<u8>);
+
+struct Cached {
+    response: Response,
+    expires: Instant,
+}
+
+#[derive(Default)]
+struct Store {
+    entries: HashMap<String, Cached>,
+    /// Keys, oldest first
+    order: VecDeque<String>,
+}
+
+/// A tunnel's cached responses
+pub struct ResponseCache {
+    ttl: Duration,
+    paths: Vec<String>,
+    vary: Vec<String>,
+    max_entries: usize,
+    store: Mutex<Store>,
+}
+
+impl ResponseCache {
+    /// None when the TTL doesn't parse, which `TunnelConfig::validate`
+    /// reports
+    pub fn from_config(conf: &CacheConfig) -> Option<Self> {
+        Some(Self {
+            ttl: crate::config::parse_duration(&conf.ttl)?,
+            paths: conf.paths.clone(),
+            vary: conf.vary.iter().map(|h| h.to_ascii_lowercase()).collect(),
+            max_entries: conf.max_entries,
+            store: Mutex::new(Store::default()),
+        })
+    }
+
+    /// Cache key, or none if the request can't be answered from cache
+    pub fn key(&self, request: &TunnelRequest) -> Option<String> {
+        if !matches!(request.method.as_str(), "GET" | "HEAD") {
+            return None;
+        }
+        let path = request.path.split(['?', '#']).next().unwrap_or(&request.path);
+        if !self.paths.is_empty() && !self.paths.iter().any(|glob| matches_glob(glob, path)) {
+            return None;
+        }
+        let mut key = format!("{} {}", request.method, request.path);
+        for name in &self.vary {
+            let value = request.headers.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)).map_or("", |(_, v)| v);
+            key.push('\n');
+            key.push_str(value);
+        }
+        Some(key)
+    }
+
+    pub fn get(&self, key: &str) -> Option<Response> {
+        let mut store = self.lock();
+        match store.entries.get(key) {
+            Some(cached) if cached.expires > Instant::now() => Some(cached.response.clone()),
+            Some(_) => {
+                store.entries.remove(key);
+                store.order.retain(|k| k != key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Store a response if it may be reused
+    pub fn put(&self, key: String, response: &Response) {
+        let (status, headers, _) = response;
+        if !(200..300).contains(status) || !storable(headers) {
+            return;
+        }
+        let mut store = self.lock();
+        if store.entries.remove(&key).is_some() {
+            store.order.retain(|k| *k != key);
+        }
+        while store.order.len() >= self.max_entries.max(1) {
+            let Some(oldest) = store.order.pop_front() else { break };
+            store.entries.remove(&oldest);
+        }
+        store.order.push_back(key.clone());
+        store.entries.insert(key, Cached { response: response.clone(), expires: Instant::now() + self.ttl });
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Store> {
+        self.store.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Mark a response as served through the cache
+pub fn tag(headers: &mut Vec<(String, String)>, status: CacheStatus) {
+    headers.push((HEADER.to_string(), status.as_str().to_string()));
+}
+
+fn storable(headers: &[(String, String)]) -> bool {
+    headers.iter().all(|(k, v)| {
+        let v = v.to_ascii_lowercase();
+        !k.eq_ignore_ascii_case("set-cookie")
+            && !(k.eq_ignore_ascii_case("cache-control") && (v.contains("no-store") || v.contains("private")))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(ttl: &str) -> ResponseCache {
+        ResponseCache::from_config(&CacheConfig {
+            ttl: ttl.to_string(),
+            paths: vec!["/static/**".to_string()],
+            vary: vec!["Accept".to_string()],
+            max_entries: 2,
+        })
+        .unwrap()
+    }
+
+    fn request(method: &str, path: &str, accept: &str) -> TunnelRequest {
+        TunnelRequest {
+            id: "r".into(),
+            method: method.into(),
+            path: path.into(),
+            headers: vec![("Accept".into(), accept.into())],
+            body: None,
+            streamed: false,
+        }
+    }
+
+    #[test]
+    fn test_keys() {
+        let c = cache("1m");
+        assert!(c.key(&request("POST", "/static/a.js", "*/*")).is_none());
+        assert!(c.key(&request("GET", "/api/x", "*/*")).is_none());
+        let js = c.key(&request("GET", "/static/a.js?v=1", "*/*")).unwrap();
+        assert_ne!(js, c.key(&request("GET", "/static/a.js?v=1", "text/html")).unwrap());
+        assert_ne!(js, c.key(&request("GET", "/static/a.js?v=2", "*/*")).unwrap());
+    }
+
+    #[test]
+    fn test_store_and_expiry() {
+        let c = cache("1m");
+        let ok = (200, vec![("Content-Type".to_string(), "text/css".to_string())], b"body".to_vec());
+        c.put("a".into(), &ok);
+        assert_eq!(c.get("a"), Some(ok.clone()));
+
+        c.put("err".into(), &(500, Vec::new(), Vec::new()));
+        c.put("private".into(), &(200, vec![("Cache-Control".into(), "private, max-age=60".into())], Vec::new()));
+        assert!(c.get("err").is_none() && c.get("private").is_none());
+
+        // Oldest goes past max_entries
+        c.put("b".into(), &ok);
+        c.put("c".into(), &ok);
+        assert!(c.get("a").is_none() && c.get("c").is_some());
+
+        let expired = cache("0s");
+        expired.put("a".into(), &ok);
+        assert!(expired.get("a").is_none());
+    }
+}
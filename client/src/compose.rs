@@ -0,0 +1,213 @@
+//! docker-compose projects
+//!
+//! `ztunnel compose` tunnels to the services of a docker-compose.yml
+//! through the ports they publish on the host, so a compose project
+//! needs no ztunnel.yml. Each service gets one tunnel, named after it
+//! and asking for its name as the subdomain (`PREFIX-NAME` with a
+//! prefix). A service publishing several TCP ports is reached on the
+//! first; ports without a host side, UDP ports and variables compose
+//! would interpolate are skipped.
+
+use crate::config::TunnelConfig;
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use tracing::{debug, warn};
+use ztunnel_shared::validate;
+
+/// Files `docker compose` looks for, in its order
+pub const FILE_NAMES: &[&str] = &["compose.yaml", "compose.yml", "docker-compose.yaml", "docker-compose.yml"];
+
+/// Which services to tunnel and how
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Services to tunnel; empty for every service that publishes a port
+    pub services: Vec<String>,
+    /// Subdomains become `PREFIX-SERVICE`
+    pub prefix: Option<String>,
+    /// Services tunneled as raw TCP instead of HTTP
+    pub tcp: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ComposeFile {
+    #[serde(default)]
+    services: serde_yaml::Mapping,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Service {
+    #[serde(default)]
+    ports: Vec<serde_yaml::Value>,
+}
+
+/// A host port published for a container port
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Published {
+    host_ip: Option<String>,
+    port: u16,
+}
+
+/// The compose file in the current directory, if there is one
+pub fn find() -> Option<PathBuf> {
+    FILE_NAMES.iter().map(PathBuf::from).find(|p| p.exists())
+}
+
+/// Read a compose file and work out its tunnels
+pub fn load(path: &Path, opts: &Options) -> Result<Vec<TunnelConfig>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read compose file: {}", path.display()))?;
+    tunnels(&content, opts).with_context(|| format!("In {}", path.display()))
+}
+
+/// Tunnels for the selected services of a compose file's text
+pub fn tunnels(content: &str, opts: &Options) -> Result<Vec<TunnelConfig>> {
+    let compose: ComposeFile = serde_yaml::from_str(content)?;
+    let names: Vec<&str> = compose.services.keys().filter_map(|k| k.as_str()).collect();
+    for wanted in opts.services.iter().chain(&opts.tcp) {
+        if !names.contains(&wanted.as_str()) {
+            anyhow::bail!("No service '{}' (services: {})", wanted, names.join(", "));
+        }
+    }
+
+    let mut tunnels = Vec::new();
+    for (name, service) in &compose.services {
+        let Some(name) = name.as_str() else { continue };
+        let selected = opts.services.iter().any(|s| s == name);
+        if !opts.services.is_empty() && !selected {
+            continue;
+        }
+        let service: Service = serde_yaml::from_value(service.clone())
+            .with_context(|| format!("Invalid service '{}'", name))?;
+        let ports: Vec<Published> = service.ports.iter().filter_map(|p| published(name, p)).collect();
+        let Some(first) = ports.first() else {
+            if selected {
+                anyhow::bail!("Service '{}' publishes no TCP port on the host", name);
+            }
+            debug!("Service '{}' publishes no port, skipping", name);
+            continue;
+        };
+        if ports.len() > 1 {
+            warn!("Service '{}' publishes {} ports, tunneling {}", name, ports.len(), first.port);
+        }
+
+        let subdomain = match &opts.prefix {
+            Some(prefix) => format!("{}-{}", prefix, name),
+            None => name.to_string(),
+        }
+        .to_ascii_lowercase()
+        .replace('_', "-");
+        let tcp = opts.tcp.iter().any(|s| s == name);
+        let tunnel = TunnelConfig {
+            name: name.to_string(),
+            proto: if tcp { "tcp" } else { "http" }.to_string(),
+            subdomain: (!tcp && validate::check_subdomain(&subdomain).is_ok()).then_some(subdomain),
+            local_host: first.host_ip.clone().unwrap_or_else(|| "127.0.0.1".into()),
+            local_port: first.port,
+            ..TunnelConfig::default()
+        };
+        tunnel.validate()?;
+        tunnels.push(tunnel);
+    }
+    if tunnels.is_empty() {
+        anyhow::bail!("No service publishes a port to tunnel to");
+    }
+    Ok(tunnels)
+}
+
+/// The host side of one `ports` entry, short (`"127.0.0.1:8080:80/tcp"`)
+/// or long (`{target: 80, published: 8080}`) syntax
+fn published(service: &str, entry: &serde_yaml::Value) -> Option<Published> {
+    let (host_ip, port, protocol) = match entry {
+        // A bare container port gets a random host port
+        serde_yaml::Value::Number(_) => return None,
+        serde_yaml::Value::String(spec) => {
+            let (spec, protocol) = spec.split_once('/').unwrap_or((spec, "tcp"));
+            let mut parts = spec.rsplitn(3, ':');
+            let _target = parts.next();
+            let port = parts.next()?.to_string();
+            (parts.next().map(String::from), port, protocol.to_string())
+        }
+        serde_yaml::Value::Mapping(long) => {
+            let port = match long.get("published")? {
+                serde_yaml::Value::Number(n) => n.to_string(),
+                serde_yaml::Value::String(s) => s.clone(),
+                _ => return None,
+            };
+            let host_ip = long.get("host_ip").and_then(|v| v.as_str()).map(String::from);
+            let protocol = long.get("protocol").and_then(|v| v.as_str()).unwrap_or("tcp").to_string();
+            (host_ip, port, protocol)
+        }
+        _ => return None,
+    };
+    if protocol != "tcp" {
+        return None;
+    }
+    // A range publishes its first port first
+    let first = port.split('-').next().unwrap_or_default();
+    let Ok(port) = first.parse::<u16>() else {
+        if !first.is_empty() {
+            warn!("Service '{}': can't read published port '{}'", service, port);
+        }
+        return None;
+    };
+    // Listening on every interface includes loopback
+    let host_ip = host_ip
+        .map(|ip| ip.trim_start_matches('[').trim_end_matches(']').to_string())
+        .filter(|ip| ip != "0.0.0.0" && ip != "::");
+    Some(Published { host_ip, port })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COMPOSE: &str = r#"
+services:
+  web:
+    image: nginx
+    ports:
+      - "8080:80"
+      - "127.0.0.1:8443:443"
+  api_server:
+    build: .
+    ports:
+      - target: 3000
+        published: "3001"
+        host_ip: 127.0.0.2
+  db:
+    image: postgres
+    ports: ["127.0.0.1:5433:5432/tcp"]
+  worker:
+    image: worker
+  metrics:
+    image: statsd
+    ports: ["8125:8125/udp", "9102"]
+"#;
+
+    #[test]
+    fn test_compose_tunnels() {
+        let tunnels = tunnels(COMPOSE, &Options { tcp: vec!["db".into()], ..Options::default() }).unwrap();
+        let summary: Vec<_> = tunnels
+            .iter()
+            .map(|t| (t.name.as_str(), t.proto.as_str(), t.subdomain.as_deref(), t.local_host.as_str(), t.local_port))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("web", "http", Some("web"), "127.0.0.1", 8080),
+                ("api_server", "http", Some("api-server"), "127.0.0.2", 3001),
+                ("db", "tcp", None, "127.0.0.1", 5433),
+            ]
+        );
+
+        let opts = Options { services: vec!["web".into()], prefix: Some("pr42".into()), ..Options::default() };
+        let tunnels = super::tunnels(COMPOSE, &opts).unwrap();
+        assert_eq!(tunnels.len(), 1);
+        assert_eq!(tunnels[0].subdomain.as_deref(), Some("pr42-web"));
+
+        let err = super::tunnels(COMPOSE, &Options { services: vec!["worker".into()], ..Options::default() });
+        assert!(err.unwrap_err().to_string().contains("publishes no TCP port"));
+        assert!(super::tunnels(COMPOSE, &Options { services: vec!["nope".into()], ..Options::default() }).is_err());
+    }
+}
@@ -1,11 +1,15 @@
 //! Configuration file parser for ZTunnel
 //!
 //! Supports ztunnel.yml with multi-tunnel definitions,
-//! IP filtering, and auth token configuration.
+//! IP filtering, and auth token configuration. Values may reference
+//! environment variables as `${VAR}` or `${VAR:-default}`.
 
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::path::Path;
 use anyhow::{Context, Result};
+use ztunnel_shared::protocol::Policy;
+use ztunnel_shared::validate;
 
 /// Root configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -14,9 +18,17 @@ pub struct ZTunnelConfig {
     #[serde(default = "default_relay")]
     pub relay: String,
 
+    /// Fallback relays, tried in order when `relay` is unreachable
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub relays: Vec<String>,
+
     /// Optional authentication token
     pub auth_token: Option<String>,
 
+    /// Relay URLs by name for `--region`, added to the built-in regions
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub regions: BTreeMap<String, String>,
+
     /// Inspector settings
     #[serde(default)]
     pub inspector: InspectorConfig,
@@ -28,10 +40,51 @@ pub struct ZTunnelConfig {
     /// Global IP filter rules
     #[serde(default)]
     pub ip_filter: IpFilterConfig,
+
+    /// Named overrides selected with `--profile`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub profiles: BTreeMap<String, ProfileConfig>,
+
+    /// Send panics and repeated failures to Sentry
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_reporting: Option<ErrorReportingConfig>,
+
+    /// Announce tunnels (and optionally the inspector) on the LAN over mDNS
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mdns: Option<MdnsConfig>,
+}
+
+impl Default for ZTunnelConfig {
+    fn default() -> Self {
+        Self {
+            relay: default_relay(),
+            relays: Vec::new(),
+            auth_token: None,
+            regions: BTreeMap::new(),
+            inspector: InspectorConfig::default(),
+            tunnels: Vec::new(),
+            ip_filter: IpFilterConfig::default(),
+            profiles: BTreeMap::new(),
+            error_reporting: None,
+            mdns: None,
+        }
+    }
+}
+
+/// Settings a profile replaces; anything left out keeps the top-level value
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Default)]
+pub struct ProfileConfig {
+    pub relay: Option<String>,
+    pub relays: Option<Vec<String>>,
+    pub auth_token: Option<String>,
+    pub inspector: Option<InspectorConfig>,
+    /// Replaces the top-level tunnel list entirely
+    pub tunnels: Option<Vec<TunnelConfig>>,
+    pub ip_filter: Option<IpFilterConfig>,
 }
 
 /// Single tunnel definition
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct TunnelConfig {
     /// Human-readable name
     pub name: String,
@@ -41,6 +94,7 @@ pub struct TunnelConfig {
     pub proto: String,
 
     /// Local port to forward traffic to
+    #[serde(default)]
     pub local_port: u16,
 
     /// Optional custom subdomain (HTTP only)
@@ -57,13 +111,245 @@ pub struct TunnelConfig {
     #[serde(default)]
     pub throttle_bps: u64,
 
+    /// Bytes the throttle lets through at once before pacing to
+    /// `throttle_bps` (0 = one second's worth)
+    #[serde(default)]
+    pub throttle_burst: u64,
+
+    /// Simultaneous TCP connections to the local service (0 = unlimited)
+    #[serde(default)]
+    pub max_connections: usize,
+
+    /// Start TCP connections to the local service with a PROXY protocol
+    /// header naming the visitor
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy_protocol: Option<crate::proxy_protocol::Version>,
+
+    /// Let token holders reach other hosts from this machine through
+    /// the tunnel (`ztunnel socks`) instead of only the local service
+    pub gateway: Option<GatewayConfig>,
+
     /// Local hostname to forward to (default: 127.0.0.1)
     #[serde(default = "default_host")]
     pub local_host: String,
+
+    /// Unix socket path to forward to instead of local_host:local_port
+    pub local_socket: Option<String>,
+
+    /// Host header sent to the local service (default: local_host:local_port)
+    pub host_header: Option<String>,
+
+    /// Only forward paths matching these globs (empty = allow all)
+    #[serde(default)]
+    pub allow_paths: Vec<String>,
+
+    /// Reject paths matching these globs with 403
+    #[serde(default)]
+    pub deny_paths: Vec<String>,
+
+    /// Require HTTP basic auth ("user:pass") before forwarding requests
+    pub basic_auth: Option<String>,
+
+    /// Header rules applied to requests before they reach the local service
+    #[serde(default)]
+    pub request_headers: HeaderRulesConfig,
+
+    /// Header rules applied to responses before they go back to the relay
+    #[serde(default)]
+    pub response_headers: HeaderRulesConfig,
+
+    /// Hold requests matching these `[METHOD] /glob` rules for approval
+    /// in the inspector
+    #[serde(default)]
+    pub intercept: Vec<String>,
+
+    /// Send inspector replays here instead of the local service
+    /// (`PORT`, `HOST:PORT`, or `unix:PATH`)
+    pub replay_target: Option<String>,
+
+    /// Commands run when the tunnel connects, disconnects, or answers a
+    /// request
+    #[serde(default)]
+    pub hooks: HooksConfig,
+
+    /// Verify webhook signatures on incoming requests
+    pub webhook: Option<WebhookConfig>,
+
+    /// Accept only requests sealed end to end by `ztunnel receive`
+    #[serde(default)]
+    pub e2e: bool,
+
+    /// Answer 504 when the local service takes longer than this
+    /// (e.g. "10s", "500ms"); unset waits for the relay's own timeout
+    pub local_timeout: Option<String>,
+
+    /// Times a timed-out or refused idempotent request (GET, HEAD,
+    /// OPTIONS, PUT, DELETE) is sent again
+    #[serde(default)]
+    pub retries: u32,
+
+    /// Answer repeated GET/HEAD requests from memory
+    pub cache: Option<CacheConfig>,
+
+    /// Add permissive CORS headers and answer preflights locally
+    #[serde(default)]
+    pub cors: bool,
+
+    /// HTML or text file served with 503 while the tunnel is paused
+    pub pause_page: Option<String>,
+
+    /// Local times the tunnel is connected, e.g. "Mon-Fri 09:00-18:00";
+    /// see `schedule`
+    pub active_hours: Option<String>,
+
+    /// Requests the local service takes longer than this to answer are
+    /// flagged in the inspector and counted as slow
+    pub slow_threshold_ms: Option<u64>,
+
+    /// Log a warning for each slow request
+    #[serde(default = "default_true")]
+    pub slow_warn: bool,
+
+    /// Rules the relay applies before a request reaches the tunnel
+    /// (block, redirect, require auth, add a header)
+    #[serde(default)]
+    pub policies: Vec<Policy>,
+
+    /// Compress messages on the relay link when the relay supports it
+    #[serde(default = "default_true")]
+    pub compress: bool,
+}
+
+/// Header add/set/remove rules (applied as remove, set, add)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Default)]
+pub struct HeaderRulesConfig {
+    /// Headers added only when not already present
+    #[serde(default)]
+    pub add: BTreeMap<String, String>,
+
+    /// Headers set, replacing any existing value
+    #[serde(default)]
+    pub set: BTreeMap<String, String>,
+
+    /// Header names removed
+    #[serde(default)]
+    pub remove: Vec<String>,
+}
+
+impl Default for TunnelConfig {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            proto: default_proto(),
+            local_port: 0,
+            subdomain: None,
+            inspect: true,
+            ip_filter: None,
+            throttle_bps: 0,
+            throttle_burst: 0,
+            max_connections: 0,
+            proxy_protocol: None,
+            gateway: None,
+            local_host: default_host(),
+            local_socket: None,
+            host_header: None,
+            allow_paths: Vec::new(),
+            deny_paths: Vec::new(),
+            basic_auth: None,
+            request_headers: HeaderRulesConfig::default(),
+            response_headers: HeaderRulesConfig::default(),
+            intercept: Vec::new(),
+            replay_target: None,
+            hooks: HooksConfig::default(),
+            webhook: None,
+            e2e: false,
+            local_timeout: None,
+            retries: 0,
+            cache: None,
+            cors: false,
+            pause_page: None,
+            active_hours: None,
+            slow_threshold_ms: None,
+            slow_warn: true,
+            policies: Vec::new(),
+            compress: true,
+        }
+    }
+}
+
+/// Lifecycle hook commands, run with `sh -c` (`cmd /C` on Windows)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Default)]
+pub struct HooksConfig {
+    pub on_connect: Option<String>,
+    pub on_disconnect: Option<String>,
+    pub on_request: Option<RequestHookConfig>,
+}
+
+/// `on_request` hook and the requests it runs for
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RequestHookConfig {
+    pub run: String,
+
+    /// `[METHOD] /glob` rules, as for `intercept` (empty = every request)
+    #[serde(default, rename = "match")]
+    pub rules: Vec<String>,
+
+    /// Only responses with at least this status, e.g. 500
+    pub min_status: Option<u16>,
+}
+
+/// Local response cache
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CacheConfig {
+    /// How long a response is reused, e.g. "30s"
+    pub ttl: String,
+
+    /// Only cache paths matching these globs (empty = all)
+    #[serde(default)]
+    pub paths: Vec<String>,
+
+    /// Request headers whose values are part of the key, e.g. Accept
+    #[serde(default)]
+    pub vary: Vec<String>,
+
+    /// Responses kept; the oldest are dropped past this
+    #[serde(default = "default_cache_entries")]
+    pub max_entries: usize,
+}
+
+/// Gateway mode for a TCP tunnel
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GatewayConfig {
+    /// Consumers must present this token
+    pub token: String,
+
+    /// `host:port` patterns consumers may connect to, `*` matching any
+    /// run of characters (e.g. `10.0.0.*:5432`, `*.internal:*`)
+    pub allow: Vec<String>,
+}
+
+/// Webhook signature verification
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WebhookConfig {
+    /// stripe, github, slack, or generic
+    pub provider: String,
+
+    /// Signing secret shared with the provider
+    pub secret: String,
+
+    /// Header carrying the signature (generic only)
+    pub header: Option<String>,
+
+    /// sha256 (default) or sha1 (generic only)
+    pub algorithm: Option<String>,
+
+    /// Answer requests with a bad or missing signature with 401
+    #[serde(default)]
+    pub reject_invalid: bool,
 }
 
 /// Inspector configuration
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct InspectorConfig {
     /// Enable the inspector dashboard
     #[serde(default = "default_true")]
@@ -72,6 +358,26 @@ pub struct InspectorConfig {
     /// Port for the inspector UI
     #[serde(default = "default_inspect_port")]
     pub port: u16,
+
+    /// Keep request history in SQLite across restarts
+    #[serde(default)]
+    pub history: Option<HistoryConfig>,
+
+    /// Bodies are recorded up to this many bytes
+    #[serde(default = "default_max_body_bytes")]
+    pub max_body_bytes: usize,
+
+    /// Entries kept in memory
+    #[serde(default = "default_max_entries")]
+    pub max_entries: usize,
+
+    /// Memory for entries in MiB; the oldest are dropped past it (0 = unlimited)
+    #[serde(default = "default_max_memory_mb")]
+    pub max_memory_mb: usize,
+
+    /// Drop a tunnel's entries when it reconnects to the relay
+    #[serde(default)]
+    pub clear_on_reconnect: bool,
 }
 
 impl Default for InspectorConfig {
@@ -79,12 +385,68 @@ impl Default for InspectorConfig {
         Self {
             enabled: true,
             port: 4040,
+            history: None,
+            max_body_bytes: DEFAULT_MAX_BODY_BYTES,
+            max_entries: DEFAULT_MAX_ENTRIES,
+            max_memory_mb: DEFAULT_MAX_MEMORY_MB,
+            clear_on_reconnect: false,
+        }
+    }
+}
+
+/// Where errors are reported
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ErrorReportingConfig {
+    /// Sentry DSN, `https://KEY@HOST/PROJECT`
+    pub dsn: String,
+
+    /// Reported with each event, e.g. `production`
+    #[serde(default)]
+    pub environment: Option<String>,
+}
+
+/// mDNS / DNS-SD announcement
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Default)]
+pub struct MdnsConfig {
+    /// Shown in service browsers (default: this machine's hostname)
+    #[serde(default)]
+    pub name: Option<String>,
+
+    /// Also serve the inspector on every interface and announce it.
+    /// Anyone on the network can then see captured requests, headers
+    /// included; replaying and other changes stay local.
+    #[serde(default)]
+    pub inspector: bool,
+}
+
+/// Persistent inspector history
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryConfig {
+    /// Database file; a leading `~/` is the home directory
+    #[serde(default = "default_history_path")]
+    pub path: String,
+
+    /// Drop entries older than this many days (0 = keep forever)
+    #[serde(default = "default_history_days")]
+    pub max_age_days: u64,
+
+    /// Keep at most this many entries (0 = unlimited)
+    #[serde(default = "default_history_entries")]
+    pub max_entries: usize,
+}
+
+impl HistoryConfig {
+    pub fn at(path: impl Into<String>) -> Self {
+        Self {
+            path: path.into(),
+            max_age_days: default_history_days(),
+            max_entries: default_history_entries(),
         }
     }
 }
 
 /// IP filtering configuration
-#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Default)]
 pub struct IpFilterConfig {
     /// Allowed CIDR ranges (empty = allow all)
     #[serde(default)]
@@ -115,39 +477,422 @@ fn default_inspect_port() -> u16 {
     4040
 }
 
+/// Inspector body capture limit when none is configured
+pub const DEFAULT_MAX_BODY_BYTES: usize = 1024 * 1024;
+
+fn default_max_body_bytes() -> usize {
+    DEFAULT_MAX_BODY_BYTES
+}
+
+/// Inspector ring buffer size when none is configured
+pub const DEFAULT_MAX_ENTRIES: usize = 500;
+
+/// Inspector memory budget in MiB when none is configured
+pub const DEFAULT_MAX_MEMORY_MB: usize = 128;
+
+/// Upper bound for a tunnel's `retries`
+pub const MAX_RETRIES: u32 = 5;
+
+fn default_cache_entries() -> usize {
+    256
+}
+
+fn default_max_entries() -> usize {
+    DEFAULT_MAX_ENTRIES
+}
+
+fn default_max_memory_mb() -> usize {
+    DEFAULT_MAX_MEMORY_MB
+}
+
+fn default_history_path() -> String {
+    "~/.ztunnel/history.db".to_string()
+}
+
+fn default_history_days() -> u64 {
+    7
+}
+
+fn default_history_entries() -> usize {
+    100_000
+}
+
+/// A `${VAR}` reference with no value and no default
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+#[error("line {line}: {message}")]
+pub struct InterpolationError {
+    /// 1-based line number
+    pub line: usize,
+    pub message: String,
+}
+
+/// Expand `${VAR}` and `${VAR:-default}` in config text before parsing,
+/// so numeric fields like `local_port` can come from the environment too.
+/// `$${` is a literal `${`. Comment lines are left untouched.
+pub fn interpolate_env(
+    content: &str,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> std::result::Result<String, InterpolationError> {
+    let mut out = String::with_capacity(content.len());
+
+    for (i, line) in content.split_inclusive('\n').enumerate() {
+        if line.trim_start().starts_with('#') {
+            out.push_str(line);
+            continue;
+        }
+
+        let mut rest = line;
+        while let Some(pos) = rest.find("${") {
+            if rest[..pos].ends_with('$') {
+                out.push_str(&rest[..pos - 1]);
+                out.push_str("${");
+                rest = &rest[pos + 2..];
+                continue;
+            }
+            out.push_str(&rest[..pos]);
+
+            let err = |message: String| InterpolationError { line: i + 1, message };
+            let Some(end) = rest[pos..].find('}') else {
+                return Err(err("unterminated ${ reference".to_string()));
+            };
+            let expr = &rest[pos + 2..pos + end];
+            let (var, default) = match expr.split_once(":-") {
+                Some((var, default)) => (var, Some(default)),
+                None => (expr, None),
+            };
+            if var.is_empty() || !var.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                return Err(err(format!("invalid variable name '{}'", var)));
+            }
+
+            // Like the shell, an empty variable falls back to the default
+            match (lookup(var).filter(|v| !v.is_empty()), default) {
+                (Some(value), _) => out.push_str(&value),
+                (None, Some(default)) => out.push_str(default),
+                (None, None) => {
+                    return Err(err(format!("environment variable '{}' is not set", var)));
+                }
+            }
+            rest = &rest[pos + end + 1..];
+        }
+        out.push_str(rest);
+    }
+
+    Ok(out)
+}
+
+/// Parse a duration like `500ms`, `10s`, or `1.5m`; a plain number is
+/// seconds
+pub fn parse_duration(s: &str) -> Option<std::time::Duration> {
+    let s = s.trim().to_lowercase();
+    let pos = s.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(s.len());
+    let num: f64 = s[..pos].parse().ok()?;
+    let secs = match &s[pos..] {
+        "ms" => num / 1000.0,
+        "" | "s" => num,
+        "m" => num * 60.0,
+        _ => return None,
+    };
+    std::time::Duration::try_from_secs_f64(secs).ok()
+}
+
+/// A problem reported by `ZTunnelConfig::lint`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigIssue {
+    /// Errors make the file unusable; warnings don't
+    pub error: bool,
+    /// 1-based line number, when known
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+/// 1-based line of the first line whose content (after any list dash)
+/// starts with `needle`
+fn find_line(content: &str, needle: &str) -> Option<usize> {
+    content.lines().position(|line| {
+        let line = line.trim_start();
+        line.trim_start_matches('-').trim_start().starts_with(needle)
+    }).map(|i| i + 1)
+}
+
+/// Validate a tunnel list: each definition, plus unique names
+fn validate_tunnels(tunnels: &[TunnelConfig]) -> Result<()> {
+    let mut names = std::collections::HashSet::new();
+    for tunnel in tunnels {
+        tunnel.validate()?;
+        if !names.insert(tunnel.name.as_str()) {
+            anyhow::bail!("Duplicate tunnel name '{}'", tunnel.name);
+        }
+    }
+    Ok(())
+}
+
+impl TunnelConfig {
+    /// Validate a single tunnel definition
+    pub fn validate(&self) -> Result<()> {
+        if self.name.is_empty() {
+            anyhow::bail!("Tunnel name cannot be empty");
+        }
+        match self.proto.as_str() {
+            "http" | "tcp" | "udp" => {}
+            other => anyhow::bail!("Invalid protocol '{}' for tunnel '{}'", other, self.name),
+        }
+        if let Some(subdomain) = &self.subdomain {
+            if let Err(e) = validate::check_subdomain(subdomain) {
+                anyhow::bail!("{} for tunnel '{}'", e, self.name);
+            }
+        }
+        match &self.local_socket {
+            Some(socket) => {
+                if socket.is_empty() {
+                    anyhow::bail!("Empty local_socket for tunnel '{}'", self.name);
+                }
+                if self.proto == "udp" {
+                    anyhow::bail!("local_socket is not supported for udp tunnel '{}'", self.name);
+                }
+            }
+            None if self.local_port == 0 && self.gateway.is_none() => {
+                anyhow::bail!("Invalid port 0 for tunnel '{}'", self.name);
+            }
+            None => {}
+        }
+        if let Some(spec) = &self.basic_auth {
+            if crate::auth::BasicAuth::parse(spec).is_none() {
+                anyhow::bail!("Invalid basic_auth for tunnel '{}', expected user:pass", self.name);
+            }
+        }
+        for spec in &self.intercept {
+            if crate::intercept::InterceptRule::parse(spec).is_none() {
+                anyhow::bail!("Invalid intercept rule '{}' for tunnel '{}', expected [METHOD] /path", spec, self.name);
+            }
+        }
+        if let Some(hook) = &self.hooks.on_request {
+            for spec in &hook.rules {
+                if crate::intercept::InterceptRule::parse(spec).is_none() {
+                    anyhow::bail!(
+                        "Invalid on_request match '{}' for tunnel '{}', expected [METHOD] /path",
+                        spec,
+                        self.name
+                    );
+                }
+            }
+        }
+        if self.proxy_protocol.is_some() && self.proto != "tcp" {
+            anyhow::bail!("proxy_protocol is only supported for tcp tunnels, not '{}'", self.name);
+        }
+        if let Some(gateway) = &self.gateway {
+            if self.proto != "tcp" {
+                anyhow::bail!("gateway is only supported for tcp tunnels, not '{}'", self.name);
+            }
+            if gateway.token.is_empty() {
+                anyhow::bail!("Empty gateway token for tunnel '{}'", self.name);
+            }
+            if gateway.allow.is_empty() {
+                anyhow::bail!("Gateway for tunnel '{}' allows no destinations", self.name);
+            }
+            if self.proxy_protocol.is_some() {
+                anyhow::bail!("proxy_protocol can't be combined with gateway in tunnel '{}'", self.name);
+            }
+        }
+        if self.e2e && self.proto != "http" {
+            anyhow::bail!("e2e is only supported for http tunnels, not '{}'", self.name);
+        }
+        if let Some(spec) = &self.local_timeout {
+            if parse_duration(spec).is_none_or(|d| d.is_zero()) {
+                anyhow::bail!("Invalid local_timeout '{}' for tunnel '{}', expected e.g. 10s or 500ms", spec, self.name);
+            }
+        }
+        if let Some(spec) = &self.active_hours {
+            if let Err(e) = crate::schedule::Schedule::parse(spec) {
+                anyhow::bail!("Invalid active_hours for tunnel '{}': {}", self.name, e);
+            }
+        }
+        if let Some(cache) = &self.cache {
+            if parse_duration(&cache.ttl).is_none() {
+                anyhow::bail!("Invalid cache ttl '{}' for tunnel '{}', expected e.g. 30s", cache.ttl, self.name);
+            }
+        }
+        for policy in &self.policies {
+            if let Err(e) = policy.validate() {
+                anyhow::bail!("{} in tunnel '{}'", e.message(), self.name);
+            }
+        }
+        if self.retries > MAX_RETRIES {
+            anyhow::bail!("retries for tunnel '{}' is {}, at most {} allowed", self.name, self.retries, MAX_RETRIES);
+        }
+        if let Some(webhook) = &self.webhook {
+            if !crate::webhook::PROVIDERS.contains(&webhook.provider.as_str()) {
+                anyhow::bail!(
+                    "Invalid webhook provider '{}' for tunnel '{}', expected one of: {}",
+                    webhook.provider,
+                    self.name,
+                    crate::webhook::PROVIDERS.join(", ")
+                );
+            }
+            if webhook.secret.is_empty() {
+                anyhow::bail!("Empty webhook secret for tunnel '{}'", self.name);
+            }
+            if webhook.provider == "generic" && webhook.header.is_none() {
+                anyhow::bail!("Webhook provider 'generic' needs a header for tunnel '{}'", self.name);
+            }
+            if crate::webhook::WebhookVerifier::from_config(webhook).is_none() {
+                anyhow::bail!(
+                    "Invalid webhook algorithm '{}' for tunnel '{}', {} signs with sha256",
+                    webhook.algorithm.as_deref().unwrap_or_default(),
+                    self.name,
+                    webhook.provider
+                );
+            }
+        }
+        if let Some(spec) = &self.replay_target {
+            if crate::proxy::LocalTarget::parse(spec).is_none() {
+                anyhow::bail!(
+                    "Invalid replay_target '{}' for tunnel '{}', expected PORT, HOST:PORT, or unix:PATH",
+                    spec,
+                    self.name
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
 impl ZTunnelConfig {
-    /// Load configuration from a YAML file
-    pub fn load(path: &Path) -> Result<Self> {
+    /// Load configuration from a YAML file, applying `profile` if given
+    pub fn load(path: &Path, profile: Option<&str>) -> Result<Self> {
         let content = std::fs::read_to_string(path)
             .with_context(|| format!("Failed to read config file: {}", path.display()))?;
-        
-        let config: ZTunnelConfig = serde_yaml::from_str(&content)
+        let content = interpolate_env(&content, |var| std::env::var(var).ok())
+            .with_context(|| format!("Failed to expand config file: {}", path.display()))?;
+
+        let mut config: ZTunnelConfig = serde_yaml::from_str(&content)
             .with_context(|| format!("Failed to parse config file: {}", path.display()))?;
-        
+
         config.validate()?;
+        if let Some(name) = profile {
+            config.apply_profile(name)?;
+        }
+        if config.tunnels.is_empty() {
+            anyhow::bail!(
+                "No top-level tunnels defined; select a profile with --profile ({})",
+                config.profile_names()
+            );
+        }
         Ok(config)
     }
 
-    /// Validate the configuration
-    fn validate(&self) -> Result<()> {
-        if self.tunnels.is_empty() {
+    /// Validate the configuration and every profile in it
+    pub(crate) fn validate(&self) -> Result<()> {
+        let profile_tunnels = self.profiles.values().any(|p| p.tunnels.is_some());
+        if self.tunnels.is_empty() && !profile_tunnels {
             anyhow::bail!("No tunnels defined in configuration");
         }
 
-        for tunnel in &self.tunnels {
-            if tunnel.name.is_empty() {
-                anyhow::bail!("Tunnel name cannot be empty");
+        validate_tunnels(&self.tunnels)?;
+        crate::regions::validate(&self.regions)?;
+        if let Some(reporting) = &self.error_reporting {
+            reporting.dsn.parse::<ztunnel_shared::report::Dsn>().map_err(anyhow::Error::msg)?;
+        }
+        for (name, profile) in &self.profiles {
+            if let Some(tunnels) = &profile.tunnels {
+                validate_tunnels(tunnels).with_context(|| format!("In profile '{}'", name))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Overlay a named profile onto the top-level settings
+    pub fn apply_profile(&mut self, name: &str) -> Result<()> {
+        let Some(profile) = self.profiles.get(name).cloned() else {
+            anyhow::bail!("Unknown profile '{}' (available: {})", name, self.profile_names());
+        };
+        if let Some(relay) = profile.relay {
+            self.relay = relay;
+        }
+        if let Some(relays) = profile.relays {
+            self.relays = relays;
+        }
+        if profile.auth_token.is_some() {
+            self.auth_token = profile.auth_token;
+        }
+        if let Some(inspector) = profile.inspector {
+            self.inspector = inspector;
+        }
+        if let Some(tunnels) = profile.tunnels {
+            self.tunnels = tunnels;
+        }
+        if let Some(ip_filter) = profile.ip_filter {
+            self.ip_filter = ip_filter;
+        }
+        Ok(())
+    }
+
+    /// Primary relay followed by the fallbacks, without duplicates
+    pub fn relay_urls(&self) -> Vec<String> {
+        let mut urls = vec![self.relay.clone()];
+        for url in &self.relays {
+            if !urls.contains(url) {
+                urls.push(url.clone());
             }
-            match tunnel.proto.as_str() {
-                "http" | "tcp" | "udp" => {}
-                other => anyhow::bail!("Invalid protocol '{}' for tunnel '{}'", other, tunnel.name),
+        }
+        urls
+    }
+
+    fn profile_names(&self) -> String {
+        if self.profiles.is_empty() {
+            return "none defined".to_string();
+        }
+        self.profiles.keys().cloned().collect::<Vec<_>>().join(", ")
+    }
+
+    /// Check config file contents without stopping at the first problem:
+    /// parse errors and validation failures are errors (with a line when
+    /// one can be found), unknown keys are warnings.
+    pub fn lint(content: &str) -> (Option<Self>, Vec<ConfigIssue>) {
+        let mut issues = Vec::new();
+        let mut unknown = Vec::new();
+
+        let expanded = match interpolate_env(content, |var| std::env::var(var).ok()) {
+            Ok(expanded) => expanded,
+            Err(e) => {
+                issues.push(ConfigIssue { error: true, line: Some(e.line), message: e.to_string() });
+                return (None, issues);
             }
-            if tunnel.local_port == 0 {
-                anyhow::bail!("Invalid port 0 for tunnel '{}'", tunnel.name);
+        };
+
+        let de = serde_yaml::Deserializer::from_str(&expanded);
+        let config: ZTunnelConfig = match serde_ignored::deserialize(de, |path| unknown.push(path.to_string())) {
+            Ok(config) => config,
+            Err(e) => {
+                issues.push(ConfigIssue {
+                    error: true,
+                    line: e.location().map(|l| l.line()),
+                    message: e.to_string(),
+                });
+                return (None, issues);
             }
+        };
+
+        for path in unknown {
+            let key = path.rsplit('.').next().unwrap_or(&path);
+            issues.push(ConfigIssue {
+                error: false,
+                line: find_line(content, &format!("{}:", key)),
+                message: format!("Unknown key '{}'", path),
+            });
         }
 
-        Ok(())
+        if let Err(e) = config.validate() {
+            // Point at the offending tunnel when the message names one
+            let line = config
+                .tunnels
+                .iter()
+                .find(|t| !t.name.is_empty() && e.to_string().contains(&format!("'{}'", t.name)))
+                .and_then(|t| find_line(content, &format!("name: {}", t.name)));
+            issues.push(ConfigIssue { error: true, line, message: e.to_string() });
+        }
+
+        (Some(config), issues)
     }
 
     /// Search for config file in standard locations
@@ -210,4 +955,118 @@ ip_filter:
         assert_eq!(config.tunnels[1].proto, "tcp");
         assert_eq!(config.ip_filter.allow.len(), 1);
     }
+
+    #[test]
+    fn test_local_socket() {
+        let yaml = r#"
+tunnels:
+  - name: php
+    local_socket: /var/run/php-fpm.sock
+"#;
+        let config: ZTunnelConfig = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(config.tunnels[0].local_socket.as_deref(), Some("/var/run/php-fpm.sock"));
+        assert_eq!(config.tunnels[0].local_port, 0);
+        assert!(config.validate().is_ok());
+
+        let yaml = r#"
+tunnels:
+  - name: nothing
+    proto: http
+"#;
+        let config: ZTunnelConfig = serde_yaml::from_str(yaml).unwrap();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_lint_reports_lines() {
+        let yaml = "relay: ws://localhost:8080/tunnel\ntunnels:\n  - name: web\n    local_port: 3000\n    subdomian: web\n  - name: db\n    proto: ftp\n    local_port: 21\n";
+        let (config, issues) = ZTunnelConfig::lint(yaml);
+        assert!(config.is_some());
+        assert_eq!(issues.len(), 2);
+        assert_eq!(issues[0], ConfigIssue {
+            error: false,
+            line: Some(5),
+            message: "Unknown key 'tunnels.0.subdomian'".to_string(),
+        });
+        assert!(issues[1].error);
+        assert_eq!(issues[1].line, Some(6));
+
+        let (config, issues) = ZTunnelConfig::lint("tunnels:\n  - name: web\n    local_port: http\n");
+        assert!(config.is_none());
+        assert_eq!(issues[0].line, Some(3));
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        use std::time::Duration;
+        assert_eq!(parse_duration("500ms"), Some(Duration::from_millis(500)));
+        assert_eq!(parse_duration(" 10S "), Some(Duration::from_secs(10)));
+        assert_eq!(parse_duration("1.5m"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration("3"), Some(Duration::from_secs(3)));
+        assert_eq!(parse_duration("10h"), None);
+        assert_eq!(parse_duration("-1s"), None);
+    }
+
+    #[test]
+    fn test_interpolate_env() {
+        let env = |var: &str| match var {
+            "RELAY" => Some("wss://relay.example.com/tunnel".to_string()),
+            "PORT" => Some("8080".to_string()),
+            "EMPTY" => Some(String::new()),
+            _ => None,
+        };
+        let yaml = "relay: ${RELAY}\n# auth_token: ${NOT_SET}\ntunnels:\n  - name: web\n    local_port: ${PORT:-3000}\n    subdomain: ${SUB:-demo}-${EMPTY:-x}\n    host_header: $${LITERAL}\n";
+        let expanded = interpolate_env(yaml, env).unwrap();
+        let config: ZTunnelConfig = serde_yaml::from_str(&expanded).unwrap();
+        assert_eq!(config.relay, "wss://relay.example.com/tunnel");
+        assert_eq!(config.tunnels[0].local_port, 8080);
+        assert_eq!(config.tunnels[0].subdomain.as_deref(), Some("demo-x"));
+        assert_eq!(config.tunnels[0].host_header.as_deref(), Some("${LITERAL}"));
+
+        let err = interpolate_env("relay: x\nauth_token: ${TOKEN}\n", env).unwrap_err();
+        assert_eq!(err.line, 2);
+        assert!(err.message.contains("TOKEN"));
+        assert!(interpolate_env("relay: ${RELAY", env).is_err());
+    }
+
+    #[test]
+    fn test_profiles() {
+        let yaml = r#"
+relay: ws://localhost:8080/tunnel
+tunnels:
+  - name: web
+    local_port: 3000
+profiles:
+  staging:
+    relay: wss://staging.example.com/tunnel
+    inspector:
+      enabled: false
+  demo:
+    tunnels:
+      - name: demo
+        local_port: 5173
+        subdomain: product-demo
+"#;
+        let config: ZTunnelConfig = serde_yaml::from_str(yaml).unwrap();
+        config.validate().unwrap();
+
+        let mut staging = config.clone();
+        staging.apply_profile("staging").unwrap();
+        assert_eq!(staging.relay, "wss://staging.example.com/tunnel");
+        assert!(!staging.inspector.enabled);
+        assert_eq!(staging.tunnels, config.tunnels);
+
+        let mut demo = config.clone();
+        demo.apply_profile("demo").unwrap();
+        assert_eq!(demo.relay, config.relay);
+        assert_eq!(demo.tunnels.len(), 1);
+        assert_eq!(demo.tunnels[0].name, "demo");
+
+        let mut failover = config.clone();
+        failover.relays = vec!["wss://b.example.com/tunnel".into(), "ws://localhost:8080/tunnel".into()];
+        assert_eq!(failover.relay_urls(), ["ws://localhost:8080/tunnel", "wss://b.example.com/tunnel"]);
+
+        let err = config.clone().apply_profile("prod").unwrap_err().to_string();
+        assert!(err.contains("demo, staging"), "{}", err);
+    }
 }
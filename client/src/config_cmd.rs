@@ -0,0 +1,201 @@
+//! `ztunnel config` subcommands
+//!
+//! `validate` checks a ztunnel.yml the way `start` would load it, plus
+//! unknown keys and whether each local target is reachable. `init`
+//! writes a commented starter config from a few prompts.
+
+use ztunnel_client::config::{ConfigIssue, TunnelConfig, ZTunnelConfig};
+use ztunnel_client::proxy::LocalTarget;
+use anyhow::{Context, Result};
+use std::io::{BufRead, Write};
+use std::path::Path;
+use std::time::Duration;
+
+/// How long to wait for a local target before calling it unreachable
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Validate a config file and report every problem found
+pub async fn run_validate(path: &Path, profile: Option<&str>, check_targets: bool) -> Result<()> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read config file: {}", path.display()))?;
+    let (mut config, mut issues) = ZTunnelConfig::lint(&content);
+
+    if let (Some(config), Some(profile)) = (config.as_mut(), profile) {
+        if let Err(e) = config.apply_profile(profile) {
+            issues.push(ConfigIssue { error: true, line: None, message: e.to_string() });
+        }
+    }
+
+    println!("\n\x1b[1;36m⚡ Validating {}\x1b[0m\n", path.display());
+
+    for issue in &issues {
+        let location = match issue.line {
+            Some(line) => format!("{}:{}", path.display(), line),
+            None => path.display().to_string(),
+        };
+        if issue.error {
+            println!("  \x1b[31m✗ error\x1b[0m   {}: {}", location, issue.message);
+        } else {
+            println!("  \x1b[33m⚠ warning\x1b[0m {}: {}", location, issue.message);
+        }
+    }
+
+    let errors = issues.iter().filter(|i| i.error).count();
+    let mut unreachable = 0;
+
+    if let (Some(config), true) = (&config, check_targets && errors == 0) {
+        for tunnel in &config.tunnels {
+            let target = LocalTarget::from_config(tunnel);
+            match tokio::time::timeout(PROBE_TIMEOUT, target.connect()).await {
+                Ok(Ok(_)) => println!("  \x1b[32m●\x1b[0m {:<16} {} is reachable", tunnel.name, target),
+                Ok(Err(e)) => {
+                    unreachable += 1;
+                    println!("  \x1b[33m○\x1b[0m {:<16} {} is not reachable ({})", tunnel.name, target, e);
+                }
+                Err(_) => {
+                    unreachable += 1;
+                    println!("  \x1b[33m○\x1b[0m {:<16} {} timed out", tunnel.name, target);
+                }
+            }
+        }
+    }
+
+    println!();
+    if errors > 0 {
+        anyhow::bail!("{} error(s) in {}", errors, path.display());
+    }
+    let warnings = issues.len() + unreachable;
+    if warnings > 0 {
+        println!("\x1b[33m✓ Config is valid with {} warning(s)\x1b[0m\n", warnings);
+    } else {
+        println!("\x1b[32m✓ Config is valid\x1b[0m\n");
+    }
+    Ok(())
+}
+
+/// Prompt for a starter config and write it to `path`
+pub fn run_init(path: &Path, force: bool) -> Result<()> {
+    if path.exists() && !force {
+        anyhow::bail!("{} already exists (use --force to overwrite)", path.display());
+    }
+
+    let stdin = std::io::stdin();
+    let mut input = stdin.lock();
+    println!("\n\x1b[1;36m⚡ ZTunnel config init\x1b[0m  (press Enter to accept [defaults])\n");
+
+    let relay = prompt(&mut input, "Relay URL", "wss://ztunnel.onrender.com/tunnel")?;
+    let mut tunnels = Vec::new();
+    loop {
+        let n = tunnels.len() + 1;
+        let name = prompt(&mut input, "Tunnel name", if n == 1 { "web" } else { "" })?;
+        if name.is_empty() {
+            break;
+        }
+        let proto = loop {
+            let proto = prompt(&mut input, "Protocol (http/tcp)", "http")?;
+            if proto == "http" || proto == "tcp" {
+                break proto;
+            }
+            println!("  Please enter http or tcp");
+        };
+        let local_port = loop {
+            let default = if proto == "http" { "3000" } else { "5432" };
+            match prompt(&mut input, "Local port", default)?.parse::<u16>() {
+                Ok(port) if port > 0 => break port,
+                _ => println!("  Please enter a port between 1 and 65535"),
+            }
+        };
+        let subdomain = prompt(&mut input, "Subdomain (optional)", "")?;
+
+        tunnels.push(TunnelConfig {
+            name,
+            proto,
+            local_port,
+            subdomain: (!subdomain.is_empty()).then_some(subdomain),
+            ..Default::default()
+        });
+
+        if !prompt(&mut input, "Add another tunnel? (y/N)", "n")?.eq_ignore_ascii_case("y") {
+            break;
+        }
+    }
+    if tunnels.is_empty() {
+        anyhow::bail!("At least one tunnel is required");
+    }
+
+    std::fs::write(path, render_config(&relay, &tunnels))
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    println!("\n\x1b[32m✓ Wrote {}\x1b[0m — start it with `ztunnel start`\n", path.display());
+    Ok(())
+}
+
+/// Ask one question; EOF or an empty answer picks the default
+fn prompt(input: &mut impl BufRead, question: &str, default: &str) -> Result<String> {
+    if default.is_empty() {
+        print!("  {}: ", question);
+    } else {
+        print!("  {} [{}]: ", question, default);
+    }
+    std::io::stdout().flush()?;
+
+    let mut line = String::new();
+    input.read_line(&mut line)?;
+    let answer = line.trim();
+    Ok(if answer.is_empty() { default.to_string() } else { answer.to_string() })
+}
+
+/// Starter config with the optional settings left as comments
+fn render_config(relay: &str, tunnels: &[TunnelConfig]) -> String {
+    let mut out = String::new();
+    out.push_str("# ZTunnel configuration — generated by `ztunnel config init`\n");
+    out.push_str("# Check it with `ztunnel config validate`\n\n");
+    out.push_str(&format!("relay: {}\n", relay));
+    out.push_str("# auth_token: \"your-secret-token\"\n\n");
+    out.push_str("inspector:\n  enabled: true\n  port: 4040\n\n");
+    out.push_str("tunnels:\n");
+    for t in tunnels {
+        out.push_str(&format!("  - name: {}\n", t.name));
+        out.push_str(&format!("    proto: {}\n", t.proto));
+        out.push_str(&format!("    local_port: {}\n", t.local_port));
+        match &t.subdomain {
+            Some(sub) => out.push_str(&format!("    subdomain: {}\n", sub)),
+            None => out.push_str("    # subdomain: my-app\n"),
+        }
+        if t.proto == "http" {
+            out.push_str("    # basic_auth: user:pass\n");
+            out.push_str("    # allow_paths: [\"/api/**\"]\n");
+        }
+        out.push_str("    # throttle_bps: 125000\n\n");
+    }
+    out.push_str("# ip_filter:\n#   allow: [\"192.168.1.0/24\"]\n#   deny: []\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rendered_config_is_valid() {
+        let tunnels = vec![
+            TunnelConfig { name: "web".into(), local_port: 3000, subdomain: Some("app".into()), ..Default::default() },
+            TunnelConfig { name: "db".into(), proto: "tcp".into(), local_port: 5432, ..Default::default() },
+        ];
+        let yaml = render_config("ws://localhost:8080/tunnel", &tunnels);
+        let (config, issues) = ZTunnelConfig::lint(&yaml);
+        assert!(issues.is_empty(), "{:?}", issues);
+        let config = config.unwrap();
+        assert_eq!(config.tunnels.len(), 2);
+        assert_eq!(config.tunnels[0].subdomain.as_deref(), Some("app"));
+        assert_eq!(config.tunnels[1].proto, "tcp");
+    }
+
+    #[test]
+    fn test_prompt_defaults() {
+        let mut input = std::io::Cursor::new("\n8080\n");
+        assert_eq!(prompt(&mut input, "Name", "web").unwrap(), "web");
+        assert_eq!(prompt(&mut input, "Port", "3000").unwrap(), "8080");
+        // EOF
+        assert_eq!(prompt(&mut input, "Sub", "x").unwrap(), "x");
+    }
+}
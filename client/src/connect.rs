@@ -0,0 +1,139 @@
+//! Consuming TCP tunnels through the relay
+//!
+//! The relay's `/connect/NAME` WebSocket carries one connection to
+//! tunnel NAME: binary messages either way are its bytes, and a close
+//! ends it. The tunnel's client sees an ordinary TCP connection, or a
+//! request to dial a host when the tunnel is a gateway.
+//!
+//! `ztunnel forward NAME PORT` serves a tunnel this way on a local
+//! port, so someone else's database or dev server is reachable at
+//! `localhost:PORT` without exposing it to anyone but the relay.
+
+use anyhow::{Context, Result};
+use futures_util::{SinkExt, StreamExt};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+use tracing::{debug, info, warn};
+use ztunnel_shared::protocol::connect::{TARGET_HEADER, TOKEN_HEADER};
+
+pub type RelayStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// `/connect/NAME` on the relay a `/tunnel` URL points at
+pub fn connect_url(relay: &str, tunnel: &str) -> String {
+    let base = relay.trim_end_matches('/');
+    let base = base.strip_suffix("/tunnel").unwrap_or(base);
+    format!("{}/connect/{}", base, tunnel)
+}
+
+/// Open a stream to `tunnel`; `target` and `token` are for gateway
+/// tunnels
+pub async fn open(relay: &str, tunnel: &str, target: Option<&str>, token: Option<&str>) -> Result<RelayStream> {
+    let url = connect_url(relay, tunnel);
+    let mut request = url.as_str().into_client_request().with_context(|| format!("Invalid relay URL {}", url))?;
+    for (name, value) in [(TARGET_HEADER, target), (TOKEN_HEADER, token)] {
+        if let Some(value) = value {
+            request.headers_mut().insert(name, HeaderValue::from_str(value).context("Invalid header value")?);
+        }
+    }
+    match connect_async(request).await {
+        Ok((ws, _)) => Ok(ws),
+        Err(tungstenite::Error::Http(response)) => match response.status().as_u16() {
+            404 => anyhow::bail!("No tunnel named '{}' on {}", tunnel, relay),
+            400 => anyhow::bail!("Tunnel '{}' is not a TCP tunnel", tunnel),
+            403 => anyhow::bail!("Access to tunnel '{}' denied", tunnel),
+            status => anyhow::bail!("Relay refused the connection (HTTP {})", status),
+        },
+        Err(e) => Err(e).with_context(|| format!("Could not reach {}", url)),
+    }
+}
+
+/// Copy between a local connection and a relay stream until either
+/// side closes. Returns the bytes sent and received.
+pub async fn pipe(local: TcpStream, mut relay: RelayStream) -> Result<(u64, u64)> {
+    let (mut reader, mut writer) = local.into_split();
+    let mut buf = vec![0u8; 16 * 1024];
+    let (mut sent, mut received) = (0u64, 0u64);
+    loop {
+        tokio::select! {
+            n = reader.read(&mut buf) => {
+                let n = n?;
+                if n == 0 {
+                    break;
+                }
+                sent += n as u64;
+                relay.send(Message::Binary(buf[..n].to_vec())).await?;
+            }
+            msg = relay.next() => match msg {
+                Some(Ok(Message::Binary(data))) => {
+                    received += data.len() as u64;
+                    writer.write_all(&data).await?;
+                }
+                Some(Ok(Message::Close(_))) | None => break,
+                Some(Err(e)) => return Err(e.into()),
+                _ => {}
+            },
+        }
+    }
+    let _ = relay.close(None).await;
+    let _ = writer.shutdown().await;
+    Ok((sent, received))
+}
+
+/// Accept connections on `listener` and carry each to `tunnel`
+pub async fn forward(listener: TcpListener, relay: String, tunnel: String) -> Result<()> {
+    loop {
+        let (local, peer) = listener.accept().await?;
+        let (relay, tunnel) = (relay.clone(), tunnel.clone());
+        tokio::spawn(async move {
+            let piped = async {
+                let remote = open(&relay, &tunnel, None, None).await?;
+                info!("{} → {}", peer, tunnel);
+                pipe(local, remote).await
+            };
+            match piped.await {
+                Ok((sent, received)) => debug!("{} closed ({} bytes out, {} in)", peer, sent, received),
+                Err(e) => warn!("Connection from {}: {:#}", peer, e),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connect_url() {
+        assert_eq!(connect_url("wss://relay.example.com/tunnel", "db"), "wss://relay.example.com/connect/db");
+        assert_eq!(connect_url("ws://localhost:8080/tunnel/", "db"), "ws://localhost:8080/connect/db");
+        assert_eq!(connect_url("ws://localhost:8080", "db"), "ws://localhost:8080/connect/db");
+    }
+
+    #[tokio::test]
+    async fn test_forward_through_relay() {
+        // A relay whose tunnel echoes what it's sent
+        let relay = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let relay_url = format!("ws://{}/tunnel", relay.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (stream, _) = relay.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            while let Some(Ok(Message::Binary(data))) = ws.next().await {
+                ws.send(Message::Binary(data)).await.unwrap();
+            }
+        });
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(forward(listener, relay_url, "db".into()));
+
+        let mut local = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        local.write_all(b"SELECT 1;").await.unwrap();
+        let mut buf = [0u8; 9];
+        local.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"SELECT 1;");
+    }
+}
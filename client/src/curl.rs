@@ -0,0 +1,156 @@
+//! cURL commands for recorded requests
+//!
+//! `/api/entries/{id}/curl` renders an entry as a ready-to-paste curl
+//! command, aimed at the tunnel's local service (the default) or at the
+//! public URL the visitor used. Arguments are single-quoted for POSIX
+//! shells; binary bodies are piped in through `printf`.
+
+use crate::inspector::InspectorEntry;
+use crate::proxy::LocalTarget;
+use crate::replay::ReplayTarget;
+
+/// Headers curl sets itself, or that would no longer be true
+const SKIPPED: &[&str] = &["content-length", "transfer-encoding", "connection"];
+
+/// Headers the relay adds on the way in; it adds them again
+const RELAY_ADDED: &[&str] = &["forwarded", "x-forwarded-for", "x-forwarded-proto", "x-forwarded-host", "x-real-ip"];
+
+/// Where the command sends the request
+pub enum CurlTarget<'a> {
+    Local(&'a ReplayTarget),
+    Public,
+}
+
+/// Build the command, or explain why the entry can't be reproduced
+pub fn command(entry: &InspectorEntry, target: CurlTarget<'_>) -> Result<String, String> {
+    if let Some(body) = entry.req_body.as_ref().filter(|b| b.truncated()) {
+        return Err(format!(
+            "Request body was recorded only in part ({} of {} bytes)",
+            body.bytes().len(),
+            body.size()
+        ));
+    }
+
+    let mut args = Vec::new();
+    let url = match target {
+        CurlTarget::Local(to) => {
+            let base = match &to.target {
+                LocalTarget::Tcp { host, port } => format!("http://{}:{}", host, port),
+                LocalTarget::Unix(path) => {
+                    args.push(format!("--unix-socket {}", quote(&path.to_string_lossy())));
+                    "http://localhost".to_string()
+                }
+            };
+            args.push(format!("-H {}", quote(&format!("Host: {}", to.host_header))));
+            base + &entry.path
+        }
+        CurlTarget::Public => {
+            let host = header(entry, "x-forwarded-host")
+                .or_else(|| header(entry, "host"))
+                .ok_or_else(|| "No Host header was recorded for this request".to_string())?;
+            let scheme = header(entry, "x-forwarded-proto").unwrap_or("https");
+            format!("{}://{}{}", scheme, host, entry.path)
+        }
+    };
+
+    match entry.method.as_str() {
+        "GET" => {}
+        "HEAD" => args.push("--head".to_string()),
+        method => args.push(format!("-X {}", quote(method))),
+    }
+    for (k, v) in &entry.req_headers {
+        let name = k.to_ascii_lowercase();
+        if name == "host" || SKIPPED.contains(&name.as_str()) || RELAY_ADDED.contains(&name.as_str()) {
+            continue;
+        }
+        args.push(format!("-H {}", quote(&format!("{}: {}", k, v))));
+    }
+
+    let mut pipe = None;
+    if let Some(body) = entry.req_body.as_ref().filter(|b| !b.bytes().is_empty()) {
+        match body.text() {
+            Some(text) => args.push(format!("--data-binary {}", quote(text))),
+            None => {
+                pipe = Some(format!("printf {} | ", quote(&octal_escape(body.bytes()))));
+                args.push("--data-binary @-".to_string());
+            }
+        }
+    }
+
+    let mut out = format!("{}curl {}", pipe.unwrap_or_default(), quote(&url));
+    for arg in args {
+        out.push_str(" \\\n  ");
+        out.push_str(&arg);
+    }
+    Ok(out)
+}
+
+fn header<'a>(entry: &'a InspectorEntry, name: &str) -> Option<&'a str> {
+    entry.req_headers.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+}
+
+/// Single-quote for a POSIX shell
+fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+/// Every byte as a `printf` octal escape, which all POSIX shells read
+fn octal_escape(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("\\{:03o}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::body::Body;
+    use std::path::PathBuf;
+
+    fn entry(method: &str, headers: &[(&str, &str)], body: Option<Body>) -> InspectorEntry {
+        InspectorEntry {
+            method: method.to_string(),
+            path: "/hooks?x=1".to_string(),
+            req_headers: headers.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            req_body: body,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_local_command() {
+        let e = entry(
+            "POST",
+            &[("Host", "demo.example.com"), ("Content-Type", "application/json"), ("Content-Length", "9"), ("X-Real-IP", "1.2.3.4")],
+            Some(Body::new("{\"n\":\"it's\"}")),
+        );
+        let to = ReplayTarget {
+            target: LocalTarget::Tcp { host: "127.0.0.1".into(), port: 3000 },
+            host_header: "localhost:3000".into(),
+        };
+        assert_eq!(
+            command(&e, CurlTarget::Local(&to)).unwrap(),
+            "curl 'http://127.0.0.1:3000/hooks?x=1' \\\n  -H 'Host: localhost:3000' \\\n  -X 'POST' \\\n  \
+             -H 'Content-Type: application/json' \\\n  --data-binary '{\"n\":\"it'\\''s\"}'"
+        );
+
+        let sock = ReplayTarget { target: LocalTarget::Unix(PathBuf::from("/tmp/app.sock")), host_header: "localhost".into() };
+        let cmd = command(&entry("GET", &[], None), CurlTarget::Local(&sock)).unwrap();
+        assert!(cmd.starts_with("curl 'http://localhost/hooks?x=1' \\\n  --unix-socket '/tmp/app.sock'"));
+    }
+
+    #[test]
+    fn test_public_command() {
+        let e = entry("GET", &[("Host", "internal"), ("X-Forwarded-Host", "demo.example.com"), ("Accept", "*/*")], None);
+        assert_eq!(
+            command(&e, CurlTarget::Public).unwrap(),
+            "curl 'https://demo.example.com/hooks?x=1' \\\n  -H 'Accept: */*'"
+        );
+        assert!(command(&entry("GET", &[], None), CurlTarget::Public).is_err());
+
+        let bin = entry("PUT", &[("Host", "demo.example.com")], Some(Body::new(vec![0, 0xff])));
+        assert!(command(&bin, CurlTarget::Public).unwrap().starts_with("printf '\\000\\377' | curl"));
+
+        let mut cut = Body::new("abcdef");
+        cut.truncate(2);
+        assert!(command(&entry("POST", &[("Host", "h")], Some(cut)), CurlTarget::Public).unwrap_err().contains("in part"));
+    }
+}
@@ -0,0 +1,322 @@
+//! Background daemon
+//!
+//! `ztunnel start --daemon` detaches from the terminal and keeps the
+//! configured tunnels running. A pidfile and a unix control socket in the
+//! state directory let later invocations list, add, remove, and stop
+//! tunnels. The control protocol is one JSON request per line, answered
+//! with one JSON response per line.
+
+use ztunnel_client::config::TunnelConfig;
+use ztunnel_client::multi::{TunnelInfo, TunnelManager};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{Mutex, Notify};
+use tracing::{info, warn};
+
+/// Where the daemon keeps its pidfile, control socket, and log
+#[derive(Debug, Clone)]
+pub struct DaemonPaths {
+    dir: PathBuf,
+}
+
+impl DaemonPaths {
+    /// `$ZTUNNEL_STATE_DIR`, or `~/.ztunnel`
+    pub fn from_env() -> Self {
+        let dir = std::env::var_os("ZTUNNEL_STATE_DIR")
+            .map(PathBuf::from)
+            .or_else(|| dirs::home_dir().map(|h| h.join(".ztunnel")))
+            .unwrap_or_else(|| PathBuf::from(".ztunnel"));
+        Self { dir }
+    }
+
+    pub fn pidfile(&self) -> PathBuf {
+        self.dir.join("ztunnel.pid")
+    }
+
+    pub fn socket(&self) -> PathBuf {
+        self.dir.join("ztunnel.sock")
+    }
+
+    pub fn log(&self) -> PathBuf {
+        self.dir.join("ztunnel.log")
+    }
+}
+
+/// Request sent over the control socket
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+pub enum ControlRequest {
+    /// List running tunnels
+    List,
+    /// Start another tunnel
+    Add { tunnel: Box<TunnelConfig> },
+    /// Stop one tunnel
+    Remove { name: String },
+    /// Reconnect one tunnel to the relay
+    Restart { name: String },
+    /// Answer one tunnel's requests with 503 and `page`, or its
+    /// `pause_page`, until resumed
+    Pause {
+        name: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        page: Option<String>,
+    },
+    Resume { name: String },
+    /// Stop every tunnel and exit
+    Stop,
+}
+
+/// Response to a `ControlRequest`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ControlResponse {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tunnels: Vec<TunnelInfo>,
+}
+
+impl ControlResponse {
+    fn ok() -> Self {
+        Self { ok: true, ..Default::default() }
+    }
+
+    fn error(msg: impl Into<String>) -> Self {
+        Self { ok: false, error: Some(msg.into()), ..Default::default() }
+    }
+}
+
+/// Re-launch this command in the background and return its pid.
+/// The child gets `--foreground` so it runs the daemon in place.
+pub fn detach(paths: &DaemonPaths) -> Result<u32> {
+    use std::os::unix::process::CommandExt;
+
+    if let Some(pid) = running_pid(paths) {
+        anyhow::bail!("Daemon already running (pid {})", pid);
+    }
+    std::fs::create_dir_all(&paths.dir)
+        .with_context(|| format!("Failed to create {}", paths.dir.display()))?;
+    let log = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(paths.log())
+        .with_context(|| format!("Failed to open {}", paths.log().display()))?;
+
+    let child = std::process::Command::new(std::env::current_exe()?)
+        .args(std::env::args_os().skip(1))
+        .arg("--foreground")
+        .stdin(std::process::Stdio::null())
+        .stdout(log.try_clone()?)
+        .stderr(log)
+        // Own process group, so the terminal's Ctrl+C doesn't reach it
+        .process_group(0)
+        .spawn()
+        .context("Failed to start daemon")?;
+
+    Ok(child.id())
+}
+
+/// Send one request to the running daemon
+pub async fn request(paths: &DaemonPaths, request: &ControlRequest) -> Result<ControlResponse> {
+    let stream = UnixStream::connect(paths.socket())
+        .await
+        .context("ZTunnel daemon is not running (start it with `ztunnel start --daemon`)")?;
+    let (read, mut write) = stream.into_split();
+
+    let mut line = serde_json::to_vec(request)?;
+    line.push(b'\n');
+    write.write_all(&line).await?;
+
+    let reply = BufReader::new(read)
+        .lines()
+        .next_line()
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("Daemon closed the control connection"))?;
+    Ok(serde_json::from_str(&reply)?)
+}
+
+/// Pid of a live daemon: the pidfile exists and its control socket answers
+pub fn running_pid(paths: &DaemonPaths) -> Option<u32> {
+    let pid: u32 = std::fs::read_to_string(paths.pidfile()).ok()?.trim().parse().ok()?;
+    std::os::unix::net::UnixStream::connect(paths.socket()).is_ok().then_some(pid)
+}
+
+/// Serve the control socket until asked to stop (or SIGTERM/Ctrl+C),
+/// then stop all tunnels and clean up the pidfile and socket.
+pub async fn serve(manager: Arc<Mutex<TunnelManager>>, paths: &DaemonPaths) -> Result<()> {
+    std::fs::create_dir_all(&paths.dir)
+        .with_context(|| format!("Failed to create {}", paths.dir.display()))?;
+    std::fs::write(paths.pidfile(), std::process::id().to_string())
+        .with_context(|| format!("Failed to write {}", paths.pidfile().display()))?;
+
+    // A leftover socket from a crashed daemon would make bind fail
+    let _ = std::fs::remove_file(paths.socket());
+    let listener = UnixListener::bind(paths.socket())
+        .with_context(|| format!("Failed to bind {}", paths.socket().display()))?;
+    info!("Control socket listening on {}", paths.socket().display());
+
+    let shutdown = Arc::new(Notify::new());
+    let mut sigterm = signal(SignalKind::terminate())?;
+
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => {
+                    let manager = manager.clone();
+                    let shutdown = shutdown.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = handle_client(stream, manager, shutdown).await {
+                            warn!("Control connection error: {}", e);
+                        }
+                    });
+                }
+                Err(e) => warn!("Control socket accept failed: {}", e),
+            },
+            _ = shutdown.notified() => break,
+            _ = sigterm.recv() => break,
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+
+    info!("Shutting down all tunnels...");
+    manager.lock().await.stop_all();
+    let _ = std::fs::remove_file(paths.socket());
+    let _ = std::fs::remove_file(paths.pidfile());
+    Ok(())
+}
+
+async fn handle_client(
+    stream: UnixStream,
+    manager: Arc<Mutex<TunnelManager>>,
+    shutdown: Arc<Notify>,
+) -> Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        let (response, stop) = match serde_json::from_str::<ControlRequest>(&line) {
+            Ok(request) => {
+                let stop = matches!(request, ControlRequest::Stop);
+                (dispatch(request, &mut *manager.lock().await), stop)
+            }
+            Err(e) => (ControlResponse::error(format!("Invalid request: {}", e)), false),
+        };
+
+        let mut out = serde_json::to_vec(&response)?;
+        out.push(b'\n');
+        write.write_all(&out).await?;
+
+        if stop {
+            shutdown.notify_one();
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Apply one control request to the manager
+fn dispatch(request: ControlRequest, manager: &mut TunnelManager) -> ControlResponse {
+    match request {
+        ControlRequest::List => ControlResponse { tunnels: manager.list(), ..ControlResponse::ok() },
+        ControlRequest::Add { tunnel } => match manager.start(*tunnel) {
+            Ok(()) => ControlResponse::ok(),
+            Err(e) => ControlResponse::error(e.to_string()),
+        },
+        ControlRequest::Remove { name } => {
+            if manager.stop(&name) {
+                ControlResponse::ok()
+            } else {
+                ControlResponse::error(format!("No tunnel named '{}'", name))
+            }
+        }
+        ControlRequest::Restart { name } => match manager.restart(&name) {
+            Ok(()) => ControlResponse::ok(),
+            Err(e) => ControlResponse::error(e.to_string()),
+        },
+        ControlRequest::Pause { name, page } => match manager.pause(&name, page) {
+            Ok(()) => ControlResponse::ok(),
+            Err(e) => ControlResponse::error(e.to_string()),
+        },
+        ControlRequest::Resume { name } => match manager.resume(&name) {
+            Ok(()) => ControlResponse::ok(),
+            Err(e) => ControlResponse::error(e.to_string()),
+        },
+        ControlRequest::Stop => ControlResponse::ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_control_request_format() {
+        let req: ControlRequest = serde_json::from_str(r#"{"cmd":"remove","name":"api"}"#).unwrap();
+        assert!(matches!(req, ControlRequest::Remove { name } if name == "api"));
+
+        let req: ControlRequest = serde_json::from_str(r#"{"cmd":"restart","name":"web"}"#).unwrap();
+        assert!(matches!(req, ControlRequest::Restart { name } if name == "web"));
+
+        let req: ControlRequest = serde_json::from_str(r#"{"cmd":"pause","name":"web"}"#).unwrap();
+        assert!(matches!(req, ControlRequest::Pause { name, page: None } if name == "web"));
+
+        let req: ControlRequest =
+            serde_json::from_str(r#"{"cmd":"add","tunnel":{"name":"web","local_port":3000}}"#).unwrap();
+        let ControlRequest::Add { tunnel } = req else { panic!("expected add") };
+        assert_eq!(tunnel.proto, "http");
+
+        let json = serde_json::to_string(&ControlRequest::Stop).unwrap();
+        assert_eq!(json, r#"{"cmd":"stop"}"#);
+    }
+
+    #[tokio::test]
+    async fn test_control_socket_roundtrip() {
+        let dir = std::env::temp_dir().join(format!("ztunnel-daemon-test-{}", std::process::id()));
+        let paths = DaemonPaths { dir: dir.clone() };
+        let config: ztunnel_client::config::ZTunnelConfig = serde_yaml::from_str(
+            "relay: ws://127.0.0.1:9/tunnel\ntunnels:\n  - name: web\n    local_port: 3000\n",
+        )
+        .unwrap();
+        let (entry_tx, _) = tokio::sync::mpsc::channel(1);
+        let mut manager = TunnelManager::new(config, entry_tx, ztunnel_client::inspector::InspectorState::new(tokio::sync::mpsc::channel(1).0));
+        manager.start(TunnelConfig { name: "web".into(), local_port: 3000, ..Default::default() }).unwrap();
+
+        let server_paths = paths.clone();
+        let manager = Arc::new(Mutex::new(manager));
+        let server = tokio::spawn(async move { serve(manager, &server_paths).await });
+        while !paths.socket().exists() {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        let stream = UnixStream::connect(paths.socket()).await.unwrap();
+        let (read, mut write) = stream.into_split();
+        let mut lines = BufReader::new(read).lines();
+
+        for (req, ok) in [
+            (r#"{"cmd":"list"}"#, true),
+            (r#"{"cmd":"add","tunnel":{"name":"web","local_port":3001}}"#, false),
+            (r#"{"cmd":"remove","name":"web"}"#, true),
+            (r#"{"cmd":"remove","name":"web"}"#, false),
+        ] {
+            write.write_all(format!("{}\n", req).as_bytes()).await.unwrap();
+            let resp: ControlResponse = serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+            assert_eq!(resp.ok, ok, "{}", req);
+            if req.contains("list") {
+                assert_eq!(resp.tunnels.len(), 1);
+                assert_eq!(resp.tunnels[0].target, "127.0.0.1:3000");
+            }
+        }
+
+        assert!(request(&paths, &ControlRequest::Stop).await.unwrap().ok);
+        server.await.unwrap().unwrap();
+        assert!(!paths.socket().exists());
+        assert!(!paths.pidfile().exists());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,77 @@
+//! Compressed bodies for display
+//!
+//! Responses go back to the visitor exactly as the local service sent
+//! them. The inspector keeps a decoded copy instead, so gzip, deflate
+//! and brotli bodies are readable, and notes the original
+//! `Content-Encoding` on the entry.
+
+use crate::body::Body;
+use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
+use std::io::{self, Read};
+
+/// Decode a body by its `Content-Encoding`, keeping at most `limit`
+/// bytes but counting the rest. Returns the decoded body and the
+/// encoding, or none if the body isn't compressed in a way we know,
+/// is already cut short, or doesn't decode.
+pub fn decode(headers: &[(String, String)], body: &Body, limit: usize) -> Option<(Body, String)> {
+    let encoding = headers
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case("content-encoding"))
+        .map(|(_, v)| v.trim().to_ascii_lowercase())?;
+    if body.truncated() || body.bytes().is_empty() {
+        return None;
+    }
+    let bytes = body.bytes();
+    let decoded = match encoding.as_str() {
+        "gzip" | "x-gzip" => read_limited(GzDecoder::new(bytes), limit),
+        // Usually zlib-wrapped, but some servers send raw deflate
+        "deflate" => read_limited(ZlibDecoder::new(bytes), limit)
+            .or_else(|_| read_limited(DeflateDecoder::new(bytes), limit)),
+        "br" => read_limited(brotli::Decompressor::new(bytes, 4096), limit),
+        _ => return None,
+    };
+    Some((decoded.ok()?, encoding))
+}
+
+fn read_limited(mut reader: impl Read, limit: usize) -> io::Result<Body> {
+    let mut kept = Vec::new();
+    (&mut reader).take(limit as u64).read_to_end(&mut kept)?;
+    let rest = io::copy(&mut reader, &mut io::sink())? as usize;
+    let size = kept.len() + rest;
+    Ok(Body::partial(kept, size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn encoded(encoding: &str) -> Vec<(String, String)> {
+        vec![("Content-Encoding".to_string(), encoding.to_string())]
+    }
+
+    #[test]
+    fn test_decode() {
+        let text = b"{\"hello\":\"world\"}".repeat(10);
+
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(&text).unwrap();
+        let (body, encoding) = decode(&encoded("gzip"), &Body::new(gz.finish().unwrap()), 1024).unwrap();
+        assert_eq!((body.bytes(), encoding.as_str()), (&text[..], "gzip"));
+
+        let mut br = brotli::CompressorWriter::new(Vec::new(), 4096, 5, 22);
+        br.write_all(&text).unwrap();
+        let (body, _) = decode(&encoded("br"), &Body::new(br.into_inner()), 1024).unwrap();
+        assert_eq!(body.bytes(), &text[..]);
+
+        let mut raw = flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+        raw.write_all(&text).unwrap();
+        let (body, _) = decode(&encoded("deflate"), &Body::new(raw.finish().unwrap()), 16).unwrap();
+        assert_eq!((body.bytes(), body.size()), (&text[..16], text.len()));
+        assert!(body.truncated());
+
+        assert!(decode(&[], &Body::new(text.clone()), 1024).is_none());
+        assert!(decode(&encoded("gzip"), &Body::new(text.clone()), 1024).is_none());
+        assert!(decode(&encoded("zstd"), &Body::new(text), 1024).is_none());
+    }
+}
@@ -0,0 +1,241 @@
+//! Response diffing between inspector entries
+//!
+//! `/api/diff?a={id}&b={id}` compares two responses: status, headers
+//! (by name, case-insensitive) and body. Bodies that both parse as JSON
+//! are compared structurally and reported as JSON Pointer paths; other
+//! text is diffed line by line; binary bodies are only compared whole.
+
+use crate::body::Body;
+use crate::inspector::InspectorEntry;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// Largest line-diff table (lines of a × lines of b) worth computing
+const MAX_LINE_CELLS: usize = 4_000_000;
+
+#[derive(Debug, Serialize)]
+pub struct EntryDiff {
+    pub a: String,
+    pub b: String,
+    /// Whether status, headers, and body all match
+    pub equal: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<Change<u16>>,
+    /// Headers that differ, by lowercase name
+    pub headers: Vec<HeaderChange>,
+    pub body: BodyDiff,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Change<T> {
+    pub a: T,
+    pub b: T,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct HeaderChange {
+    pub name: String,
+    /// Absent when only `b` has the header
+    pub a: Option<String>,
+    /// Absent when only `a` has the header
+    pub b: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum BodyDiff {
+    /// Both bodies are JSON
+    Json { changes: Vec<JsonChange>, truncated: bool },
+    /// Unified lines: ` ` same, `-` only in a, `+` only in b. Empty when
+    /// the bodies are equal or too large to diff by line.
+    Text { equal: bool, lines: Vec<String>, truncated: bool },
+    /// At least one body isn't text
+    Binary { equal: bool, a_size: usize, b_size: usize },
+}
+
+impl BodyDiff {
+    fn is_equal(&self) -> bool {
+        match self {
+            BodyDiff::Json { changes, .. } => changes.is_empty(),
+            BodyDiff::Text { equal, .. } | BodyDiff::Binary { equal, .. } => *equal,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct JsonChange {
+    /// JSON Pointer to the value, e.g. `/items/0/price`
+    pub path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub a: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub b: Option<Value>,
+}
+
+/// Compare the responses of two entries
+pub fn diff_entries(a: &InspectorEntry, b: &InspectorEntry) -> EntryDiff {
+    let status = (a.status != b.status).then_some(Change { a: a.status, b: b.status });
+    let headers = diff_headers(&a.res_headers, &b.res_headers);
+    let empty = Body::default();
+    let body = diff_bodies(a.res_body.as_ref().unwrap_or(&empty), b.res_body.as_ref().unwrap_or(&empty));
+    EntryDiff {
+        a: a.id.clone(),
+        b: b.id.clone(),
+        equal: status.is_none() && headers.is_empty() && body.is_equal(),
+        status,
+        headers,
+        body,
+    }
+}
+
+fn diff_headers(a: &[(String, String)], b: &[(String, String)]) -> Vec<HeaderChange> {
+    // Repeated headers are compared as one comma-joined value
+    let collect = |headers: &[(String, String)]| {
+        let mut map: BTreeMap<String, String> = BTreeMap::new();
+        for (k, v) in headers {
+            map.entry(k.to_ascii_lowercase())
+                .and_modify(|existing| {
+                    existing.push_str(", ");
+                    existing.push_str(v);
+                })
+                .or_insert_with(|| v.clone());
+        }
+        map
+    };
+    let (a, b) = (collect(a), collect(b));
+    let mut names: Vec<&String> = a.keys().chain(b.keys()).collect();
+    names.sort();
+    names.dedup();
+    names
+        .into_iter()
+        .filter(|name| a.get(*name) != b.get(*name))
+        .map(|name| HeaderChange { name: name.clone(), a: a.get(name).cloned(), b: b.get(name).cloned() })
+        .collect()
+}
+
+fn diff_bodies(a: &Body, b: &Body) -> BodyDiff {
+    let truncated = a.truncated() || b.truncated();
+    let (Some(text_a), Some(text_b)) = (a.text(), b.text()) else {
+        return BodyDiff::Binary { equal: a == b, a_size: a.size(), b_size: b.size() };
+    };
+    if let (Ok(json_a), Ok(json_b)) = (serde_json::from_str::<Value>(text_a), serde_json::from_str::<Value>(text_b)) {
+        let mut changes = Vec::new();
+        diff_json(&json_a, &json_b, &mut String::new(), &mut changes);
+        return BodyDiff::Json { changes, truncated };
+    }
+    let equal = text_a == text_b;
+    let lines = if equal { Vec::new() } else { diff_lines(text_a, text_b) };
+    BodyDiff::Text { equal, lines, truncated }
+}
+
+fn diff_json(a: &Value, b: &Value, path: &mut String, changes: &mut Vec<JsonChange>) {
+    match (a, b) {
+        (Value::Object(map_a), Value::Object(map_b)) => {
+            let mut keys: Vec<&String> = map_a.keys().chain(map_b.keys()).collect();
+            keys.sort();
+            keys.dedup();
+            for key in keys {
+                let len = path.len();
+                path.push('/');
+                path.push_str(&key.replace('~', "~0").replace('/', "~1"));
+                match (map_a.get(key), map_b.get(key)) {
+                    (Some(va), Some(vb)) => diff_json(va, vb, path, changes),
+                    (va, vb) => changes.push(JsonChange { path: path.clone(), a: va.cloned(), b: vb.cloned() }),
+                }
+                path.truncate(len);
+            }
+        }
+        (Value::Array(arr_a), Value::Array(arr_b)) => {
+            for i in 0..arr_a.len().max(arr_b.len()) {
+                let len = path.len();
+                path.push_str(&format!("/{}", i));
+                match (arr_a.get(i), arr_b.get(i)) {
+                    (Some(va), Some(vb)) => diff_json(va, vb, path, changes),
+                    (va, vb) => changes.push(JsonChange { path: path.clone(), a: va.cloned(), b: vb.cloned() }),
+                }
+                path.truncate(len);
+            }
+        }
+        _ if a != b => changes.push(JsonChange { path: path.clone(), a: Some(a.clone()), b: Some(b.clone()) }),
+        _ => {}
+    }
+}
+
+/// Line diff from the longest common subsequence
+fn diff_lines(a: &str, b: &str) -> Vec<String> {
+    let (a, b): (Vec<&str>, Vec<&str>) = (a.lines().collect(), b.lines().collect());
+    if a.len().saturating_mul(b.len()) > MAX_LINE_CELLS {
+        return Vec::new();
+    }
+
+    // lcs[i][j]: common lines between a[i..] and b[j..]
+    let mut lcs = vec![vec![0u32; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] { lcs[i + 1][j + 1] + 1 } else { lcs[i + 1][j].max(lcs[i][j + 1]) };
+        }
+    }
+
+    let (mut i, mut j) = (0, 0);
+    let mut lines = Vec::new();
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            lines.push(format!(" {}", a[i]));
+            i += 1;
+            j += 1;
+        } else if i < a.len() && (j == b.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            lines.push(format!("-{}", a[i]));
+            i += 1;
+        } else {
+            lines.push(format!("+{}", b[j]));
+            j += 1;
+        }
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(id: &str, status: u16, headers: &[(&str, &str)], body: &str) -> InspectorEntry {
+        InspectorEntry {
+            id: id.to_string(),
+            status,
+            res_headers: headers.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            res_body: Some(Body::new(body.to_string())),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_json_diff() {
+        let a = entry("a", 200, &[("Content-Type", "application/json"), ("X-Old", "1")], r#"{"user":{"name":"ann","tags":["x"]},"n":1}"#);
+        let b = entry("b", 500, &[("content-type", "application/json")], r#"{"user":{"name":"bob","tags":["x","y"]},"n":1}"#);
+        let diff = diff_entries(&a, &b);
+
+        assert!(!diff.equal);
+        assert_eq!(diff.status, Some(Change { a: 200, b: 500 }));
+        assert_eq!(diff.headers, [HeaderChange { name: "x-old".into(), a: Some("1".into()), b: None }]);
+        let BodyDiff::Json { changes, .. } = diff.body else { panic!("expected a JSON diff") };
+        let paths: Vec<_> = changes.iter().map(|c| c.path.as_str()).collect();
+        assert_eq!(paths, ["/user/name", "/user/tags/1"]);
+        assert_eq!(changes[1].a, None);
+
+        assert!(diff_entries(&a, &a).equal);
+    }
+
+    #[test]
+    fn test_text_and_binary_diff() {
+        let a = entry("a", 200, &[], "one\ntwo\nthree");
+        let b = entry("b", 200, &[], "one\n2\nthree");
+        let BodyDiff::Text { equal, lines, .. } = diff_entries(&a, &b).body else { panic!("expected a text diff") };
+        assert!(!equal);
+        assert_eq!(lines, [" one", "-two", "+2", " three"]);
+
+        let mut bin = entry("c", 200, &[], "");
+        bin.res_body = Some(Body::new(vec![0xff, 0x00]));
+        assert_eq!(diff_entries(&a, &bin).body, BodyDiff::Binary { equal: false, a_size: 13, b_size: 2 });
+    }
+}
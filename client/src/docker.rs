@@ -0,0 +1,303 @@
+//! Docker label autodiscovery
+//!
+//! `ztunnel docker` opens a tunnel for each running container that opts
+//! in with labels:
+//!
+//! ```text
+//! ztunnel.enable=true     required
+//! ztunnel.port=8080       container port (default: its only exposed port)
+//! ztunnel.subdomain=shop  requested subdomain (default: the relay picks)
+//! ztunnel.proto=tcp       http (default) or tcp
+//! ```
+//!
+//! Labeled containers are listed from the Docker socket at startup and
+//! again on every container start or stop event, and the resulting
+//! tunnels are applied like a config reload: new containers get a
+//! tunnel, stopped ones lose theirs, and the rest keep their connection.
+//! A container is reached on the host port its port is published on,
+//! or else directly at its network address.
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+use ztunnel_client::config::{TunnelConfig, ZTunnelConfig};
+use ztunnel_client::logging::banner;
+use ztunnel_client::multi::TunnelManager;
+use ztunnel_shared::http::{self, BodyLength, ChunkedDecoder};
+
+pub const LABEL_ENABLE: &str = "ztunnel.enable";
+pub const LABEL_PORT: &str = "ztunnel.port";
+pub const LABEL_SUBDOMAIN: &str = "ztunnel.subdomain";
+pub const LABEL_PROTO: &str = "ztunnel.proto";
+
+/// Containers that start or stop together produce a burst of events
+const SETTLE: Duration = Duration::from_millis(500);
+
+/// Wait before reconnecting to a Docker daemon that went away
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// `{"label":["ztunnel.enable=true"]}`
+const LABEL_FILTER: &str = "%7B%22label%22%3A%5B%22ztunnel.enable%3Dtrue%22%5D%7D";
+
+/// `{"type":["container"],"event":["start","die"]}`
+const EVENT_FILTER: &str = "%7B%22type%22%3A%5B%22container%22%5D%2C%22event%22%3A%5B%22start%22%2C%22die%22%5D%7D";
+
+/// The Docker socket: `--socket`, a `unix://` DOCKER_HOST, or the
+/// default path
+pub fn socket_path(socket: Option<String>) -> PathBuf {
+    socket
+        .or_else(|| std::env::var("DOCKER_HOST").ok().and_then(|h| h.strip_prefix("unix://").map(String::from)))
+        .unwrap_or_else(|| "/var/run/docker.sock".into())
+        .into()
+}
+
+/// A container as `GET /containers/json` lists it
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Container {
+    names: Vec<String>,
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+    #[serde(default)]
+    ports: Vec<Port>,
+    #[serde(default)]
+    network_settings: NetworkSettings,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Port {
+    private_port: u16,
+    public_port: Option<u16>,
+    #[serde(rename = "Type")]
+    proto: String,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct NetworkSettings {
+    #[serde(default)]
+    networks: BTreeMap<String, Network>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Network {
+    #[serde(rename = "IPAddress", default)]
+    ip_address: String,
+}
+
+impl Container {
+    fn name(&self) -> &str {
+        self.names.first().map_or("", |n| n.trim_start_matches('/'))
+    }
+
+    /// The tunnel this container asks for
+    fn tunnel(&self) -> Result<TunnelConfig> {
+        let tcp_ports: BTreeSet<u16> = self.ports.iter().filter(|p| p.proto == "tcp").map(|p| p.private_port).collect();
+        let port = match self.labels.get(LABEL_PORT) {
+            Some(port) => port.parse().with_context(|| format!("Invalid {} '{}'", LABEL_PORT, port))?,
+            None if tcp_ports.len() == 1 => tcp_ports.into_iter().next().unwrap_or_default(),
+            None => anyhow::bail!("No {} label and {} exposed ports to choose from", LABEL_PORT, tcp_ports.len()),
+        };
+        let published = self.ports.iter().find(|p| p.proto == "tcp" && p.private_port == port).and_then(|p| p.public_port);
+        let (local_host, local_port) = match published {
+            Some(public) => ("127.0.0.1".to_string(), public),
+            None => {
+                let Some(ip) = self.network_settings.networks.values().map(|n| &n.ip_address).find(|ip| !ip.is_empty()) else {
+                    anyhow::bail!("Port {} isn't published and the container has no network address", port);
+                };
+                (ip.clone(), port)
+            }
+        };
+
+        let tunnel = TunnelConfig {
+            name: self.name().to_string(),
+            proto: self.labels.get(LABEL_PROTO).cloned().unwrap_or_else(|| "http".into()),
+            subdomain: self.labels.get(LABEL_SUBDOMAIN).cloned(),
+            local_host,
+            local_port,
+            ..TunnelConfig::default()
+        };
+        tunnel.validate()?;
+        Ok(tunnel)
+    }
+}
+
+/// Tunnels for the containers that enable them; the others are
+/// reported and skipped
+pub fn tunnels(containers: &[Container]) -> Vec<TunnelConfig> {
+    containers
+        .iter()
+        .filter(|c| c.labels.get(LABEL_ENABLE).is_some_and(|v| v == "true"))
+        .filter_map(|c| match c.tunnel() {
+            Ok(tunnel) => Some(tunnel),
+            Err(e) => {
+                warn!("Container '{}' skipped: {:#}", c.name(), e);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Keep `manager`'s tunnels in step with the labeled containers
+pub async fn watch(socket: PathBuf, base: ZTunnelConfig, manager: Arc<Mutex<TunnelManager>>) {
+    info!("Watching Docker at {}", socket.display());
+    loop {
+        if let Err(e) = follow(&socket, &base, &manager).await {
+            warn!("Docker: {:#}; retrying in {}s", e, RECONNECT_DELAY.as_secs());
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+/// Sync once, then again after each burst of container events, until
+/// the event stream ends
+async fn follow(socket: &Path, base: &ZTunnelConfig, manager: &Mutex<TunnelManager>) -> Result<()> {
+    let mut events = Response::get(socket, &format!("/events?filters={}", EVENT_FILTER)).await?;
+    sync(socket, base, manager).await?;
+    loop {
+        if events.read().await?.is_empty() {
+            anyhow::bail!("Event stream closed");
+        }
+        // Let the rest of the burst arrive; the list shows all of it
+        while let Ok(chunk) = tokio::time::timeout(SETTLE, events.read()).await {
+            if chunk?.is_empty() {
+                break;
+            }
+        }
+        sync(socket, base, manager).await?;
+    }
+}
+
+async fn sync(socket: &Path, base: &ZTunnelConfig, manager: &Mutex<TunnelManager>) -> Result<()> {
+    let body = Response::get(socket, &format!("/containers/json?filters={}", LABEL_FILTER)).await?.read_to_end().await?;
+    let containers: Vec<Container> = serde_json::from_slice(&body).context("Unexpected container list")?;
+    let config = ZTunnelConfig { tunnels: tunnels(&containers), ..base.clone() };
+
+    let summary = manager.lock().await.reload(config);
+    for name in &summary.added {
+        banner!("  + {}", name);
+    }
+    for name in &summary.restarted {
+        banner!("  ~ {}", name);
+    }
+    for name in &summary.removed {
+        banner!("  - {}", name);
+    }
+    for name in &summary.failed {
+        warn!("Tunnel '{}' failed to start", name);
+    }
+    Ok(())
+}
+
+/// A response from the Docker API, body read as it arrives
+struct Response {
+    stream: UnixStream,
+    length: BodyLength,
+    chunked: ChunkedDecoder,
+    /// Body bytes read along with the head
+    early: Vec<u8>,
+}
+
+impl Response {
+    async fn get(socket: &Path, path: &str) -> Result<Self> {
+        let mut stream = UnixStream::connect(socket)
+            .await
+            .with_context(|| format!("Can't connect to {}", socket.display()))?;
+        let headers = [("Host".to_string(), "docker".to_string()), ("Connection".to_string(), "close".to_string())];
+        stream.write_all(http::request_head("GET", path, &headers).as_bytes()).await?;
+
+        let mut buf = Vec::new();
+        let head = loop {
+            if let Some(head) = http::parse_response(&buf)? {
+                break head;
+            }
+            if stream.read_buf(&mut buf).await? == 0 {
+                anyhow::bail!("Docker closed the connection");
+            }
+        };
+        if head.status != 200 {
+            anyhow::bail!("Docker answered {} {} for {}", head.status, head.reason, path);
+        }
+        let length = http::response_body_length("GET", &head)?;
+        Ok(Self { stream, length, chunked: ChunkedDecoder::default(), early: buf.split_off(head.len) })
+    }
+
+    /// The next body bytes; empty at the end of the body
+    async fn read(&mut self) -> Result<Vec<u8>> {
+        loop {
+            if self.length == BodyLength::Chunked && self.chunked.is_done() {
+                return Ok(Vec::new());
+            }
+            let mut input = std::mem::take(&mut self.early);
+            if input.is_empty() && self.stream.read_buf(&mut input).await? == 0 {
+                return Ok(Vec::new());
+            }
+            if self.length != BodyLength::Chunked {
+                return Ok(input);
+            }
+            let mut out = Vec::new();
+            self.chunked.push(&input, &mut out)?;
+            if !out.is_empty() {
+                return Ok(out);
+            }
+        }
+    }
+
+    async fn read_to_end(mut self) -> Result<Vec<u8>> {
+        let mut body = Vec::new();
+        loop {
+            let chunk = self.read().await?;
+            if chunk.is_empty() {
+                return Ok(body);
+            }
+            body.extend(chunk);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn container(json: &str) -> Container {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn test_tunnels_from_labels() {
+        let containers = [
+            container(
+                r#"{"Names":["/shop"],"Labels":{"ztunnel.enable":"true","ztunnel.subdomain":"shop"},
+                    "Ports":[{"PrivatePort":3000,"PublicPort":49153,"Type":"tcp"},{"PrivatePort":3000,"Type":"tcp"}]}"#,
+            ),
+            container(
+                r#"{"Names":["/db"],"Labels":{"ztunnel.enable":"true","ztunnel.port":"5432","ztunnel.proto":"tcp"},
+                    "Ports":[{"PrivatePort":5432,"Type":"tcp"},{"PrivatePort":9187,"Type":"tcp"}],
+                    "NetworkSettings":{"Networks":{"bridge":{"IPAddress":"172.17.0.3"}}}}"#,
+            ),
+            // Two ports and no label to pick one
+            container(
+                r#"{"Names":["/api"],"Labels":{"ztunnel.enable":"true"},
+                    "Ports":[{"PrivatePort":80,"Type":"tcp"},{"PrivatePort":443,"Type":"tcp"}]}"#,
+            ),
+            container(r#"{"Names":["/off"],"Labels":{"ztunnel.enable":"false"},"Ports":[{"PrivatePort":80,"Type":"tcp"}]}"#),
+        ];
+
+        let tunnels = tunnels(&containers);
+        assert_eq!(tunnels.len(), 2);
+        let shop = &tunnels[0];
+        assert_eq!((shop.name.as_str(), shop.proto.as_str(), shop.subdomain.as_deref()), ("shop", "http", Some("shop")));
+        assert_eq!((shop.local_host.as_str(), shop.local_port), ("127.0.0.1", 49153));
+        let db = &tunnels[1];
+        assert_eq!((db.proto.as_str(), db.local_host.as_str(), db.local_port), ("tcp", "172.17.0.3", 5432));
+    }
+}
@@ -0,0 +1,463 @@
+//! End-to-end encrypted tunnels
+//!
+//! With `--e2e` the tunnel generates an X25519 key for the run and
+//! only accepts requests sealed to it. Visitors reach it through a
+//! `ztunnel receive URL` on their own machine, which serves a local
+//! port and seals each request (method, path, headers, body) with a key
+//! derived from both sides' public keys. The relay carries an opaque
+//! `POST /.ztunnel/e2e` and an opaque response and never sees plaintext.
+//! It could replay a sealed request, so the tunnel refuses one it has
+//! opened before or that was sealed more than `REPLAY_WINDOW` ago; the
+//! two machines' clocks need to agree that closely.
+//!
+//! The receiver pins the tunnel's key with `--key`. Without it the key
+//! is fetched through the relay once, which trusts the relay not to
+//! substitute its own.
+//!
+//! Envelope: `nonce (12) ‖ tag (16) ‖ ciphertext` of the JSON-encoded
+//! `TunnelRequest` or `TunnelResponse`. Each direction has its own key.
+//! A nonce is the sending time in seconds (4) and 64 random bits, so it
+//! is never repeated under a key, however often the key is derived.
+
+use crate::proxy::FixedResponse;
+use crate::tunnel::{TunnelRequest, TunnelResponse};
+use anyhow::{Context, Result};
+use axum::body::Body as AxumBody;
+use axum::extract::{Request, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use bytes::Bytes;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+use ztunnel_shared::crypto::{Session, X25519Keypair, HAS_LIBZCRYPTO};
+
+/// Where sealed requests are posted
+pub const PATH: &str = "/.ztunnel/e2e";
+
+/// Where the tunnel publishes its public key
+pub const KEY_PATH: &str = "/.ztunnel/e2e/key";
+
+/// Receiver's public key, base64url
+pub const KEY_HEADER: &str = "x-ztunnel-e2e-key";
+
+const CONTENT_TYPE: &str = "application/x-ztunnel-e2e";
+
+const REQUEST_INFO: &[u8] = b"ztunnel-e2e-request-v1";
+const RESPONSE_INFO: &[u8] = b"ztunnel-e2e-response-v1";
+
+/// Receivers remembered by a tunnel; the oldest are forgotten past this
+const MAX_PEERS: usize = 1024;
+
+/// How far a sealed request's time may be from the tunnel's clock
+const REPLAY_WINDOW: Duration = Duration::from_secs(300);
+
+/// Headers that only mean something on the outer request
+const HOP_BY_HOP: &[&str] = &["connection", "upgrade", "content-length", "transfer-encoding", "host"];
+
+pub fn encode_key(key: &[u8; 32]) -> String {
+    URL_SAFE_NO_PAD.encode(key)
+}
+
+pub fn decode_key(s: &str) -> Option<[u8; 32]> {
+    URL_SAFE_NO_PAD.decode(s.trim()).ok()?.try_into().ok()
+}
+
+/// Warn once per process when only the placeholder ciphers are built in
+pub fn warn_if_insecure() {
+    if !HAS_LIBZCRYPTO {
+        warn!("Built without libzcrypto: end-to-end encryption uses placeholder ciphers and is NOT secure");
+    }
+}
+
+/// One sending and one receiving key between two parties
+pub struct Channel {
+    send: Session,
+    recv: Session,
+}
+
+impl Channel {
+    fn new(keypair: &X25519Keypair, peer: &[u8; 32], send_info: &[u8], recv_info: &[u8]) -> Self {
+        let shared = keypair.shared_secret(peer);
+        Self { send: Session::derive(&shared, send_info), recv: Session::derive(&shared, recv_info) }
+    }
+
+    pub fn seal(&self, plaintext: &[u8]) -> Vec<u8> {
+        let nonce = fresh_nonce(unix_secs());
+        // Only the libzcrypto build can fail, and it doesn't
+        let (ciphertext, tag) = self.send.encrypt_with_nonce(plaintext, &nonce).unwrap_or_default();
+        [&nonce[..], &tag[..], &ciphertext].concat()
+    }
+
+    pub fn open(&self, envelope: &[u8]) -> Option<Vec<u8>> {
+        if envelope.len() < 28 {
+            return None;
+        }
+        let nonce: [u8; 12] = envelope[..12].try_into().ok()?;
+        let tag: [u8; 16] = envelope[12..28].try_into().ok()?;
+        self.recv.decrypt(&envelope[28..], &nonce, &tag).ok()
+    }
+}
+
+fn unix_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// A nonce dated `secs`, unique by its random part
+fn fresh_nonce(secs: u64) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[..4].copy_from_slice(&(secs as u32).to_be_bytes());
+    SystemRandom::new().fill(&mut nonce[4..]).expect("system randomness unavailable");
+    nonce
+}
+
+/// Nonces of requests opened within the replay window, oldest first
+#[derive(Default)]
+struct Seen {
+    order: VecDeque<(u64, [u8; 12])>,
+    nonces: HashSet<[u8; 12]>,
+}
+
+impl Seen {
+    /// Record a request's nonce, false if it's stale or a replay
+    fn check(&mut self, nonce: [u8; 12], now: u64) -> bool {
+        let window = REPLAY_WINDOW.as_secs();
+        while let Some(&(_, old)) = self.order.front().filter(|(at, _)| at + window < now) {
+            self.order.pop_front();
+            self.nonces.remove(&old);
+        }
+        let sent = u64::from(u32::from_be_bytes(nonce[..4].try_into().unwrap_or_default()));
+        if sent.abs_diff(now) > window || !self.nonces.insert(nonce) {
+            return false;
+        }
+        self.order.push_back((now, nonce));
+        true
+    }
+}
+
+/// What the tunnel does with an incoming request
+pub enum Unwrapped {
+    /// The request was decrypted in place; seal the response with this
+    Request(Arc<Channel>),
+    /// Answer directly, without reaching the local service
+    Respond(FixedResponse),
+}
+
+/// The tunnel's side: a key for the run and a channel per receiver
+pub struct E2eEndpoint {
+    keypair: X25519Keypair,
+    peers: Mutex<HashMap<[u8; 32], Arc<Channel>>>,
+    seen: Mutex<Seen>,
+}
+
+impl Default for E2eEndpoint {
+    fn default() -> Self {
+        warn_if_insecure();
+        Self { keypair: X25519Keypair::generate(), peers: Mutex::new(HashMap::new()), seen: Mutex::default() }
+    }
+}
+
+impl E2eEndpoint {
+    /// Public key receivers pin with `--key`
+    pub fn public_key(&self) -> String {
+        encode_key(&self.keypair.public_key)
+    }
+
+    /// Decrypt a sealed request in place, keeping its relay id. Anything
+    /// else but a key lookup is refused, so plaintext never reaches the
+    /// local service through the relay.
+    pub fn unwrap(&self, request: &mut TunnelRequest) -> Unwrapped {
+        let path = request.path.split('?').next().unwrap_or_default();
+        if path == KEY_PATH && request.method == "GET" {
+            return Unwrapped::Respond(FixedResponse { status: 200, body: self.public_key() });
+        }
+        if path != PATH || request.method != "POST" {
+            return Unwrapped::Respond(FixedResponse {
+                status: 403,
+                body: "This tunnel is end-to-end encrypted; connect with `ztunnel receive`".to_string(),
+            });
+        }
+        let refuse = |body: &str| Unwrapped::Respond(FixedResponse { status: 400, body: body.to_string() });
+
+        let Some(peer) = header(&request.headers, KEY_HEADER).and_then(decode_key) else {
+            return refuse("Missing or invalid receiver key");
+        };
+        let channel = self.channel(&peer);
+        let envelope = request.body.clone().unwrap_or_default();
+        let inner = channel.open(&envelope).and_then(|plain| serde_json::from_slice::<TunnelRequest>(&plain).ok());
+        let Some(mut inner) = inner else {
+            return refuse("Could not decrypt request; the tunnel's key may have changed");
+        };
+        // Opened, so the envelope starts with a whole nonce
+        let nonce = envelope[..12].try_into().unwrap_or_default();
+        if !self.seen.lock().unwrap_or_else(|e| e.into_inner()).check(nonce, unix_secs()) {
+            return refuse("Stale or replayed request");
+        }
+        // Only receivers that sealed a request are remembered
+        self.remember(peer, channel.clone());
+        inner.headers.retain(|(k, _)| !HOP_BY_HOP.contains(&k.to_ascii_lowercase().as_str()));
+        inner.id = std::mem::take(&mut request.id);
+        *request = inner;
+        Unwrapped::Request(channel)
+    }
+
+    fn channel(&self, peer: &[u8; 32]) -> Arc<Channel> {
+        let peers = self.peers.lock().unwrap_or_else(|e| e.into_inner());
+        match peers.get(peer) {
+            Some(channel) => channel.clone(),
+            None => Arc::new(Channel::new(&self.keypair, peer, RESPONSE_INFO, REQUEST_INFO)),
+        }
+    }
+
+    fn remember(&self, peer: [u8; 32], channel: Arc<Channel>) {
+        let mut peers = self.peers.lock().unwrap_or_else(|e| e.into_inner());
+        if !peers.contains_key(&peer) && peers.len() >= MAX_PEERS {
+            peers.clear();
+        }
+        peers.insert(peer, channel);
+    }
+}
+
+/// Seal a response for the receiver, as status, headers, and body for
+/// the relay
+pub fn seal_response(
+    channel: &Channel,
+    id: &str,
+    status: u16,
+    headers: &[(String, String)],
+    body: &[u8],
+) -> (u16, Vec<(String, String)>, Vec<u8>) {
+    let inner = TunnelResponse { id: id.to_string(), status, headers: headers.to_vec(), body: Some(Bytes::copy_from_slice(body)), streamed: false };
+    let sealed = channel.seal(&serde_json::to_vec(&inner).unwrap_or_default());
+    let headers = vec![
+        ("Content-Type".to_string(), CONTENT_TYPE.to_string()),
+        ("Content-Length".to_string(), sealed.len().to_string()),
+    ];
+    (200, headers, sealed)
+}
+
+fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+}
+
+/// The visitor's side, serving a local port
+struct Receiver {
+    url: String,
+    public_key: String,
+    channel: Channel,
+    client: reqwest::Client,
+}
+
+/// Serve `port` on localhost, forwarding every request to the tunnel at
+/// `url` sealed to its key
+pub async fn run_receiver(url: &str, port: u16, key: Option<&str>) -> Result<()> {
+    warn_if_insecure();
+    let url = url.trim_end_matches('/').to_string();
+    let client = reqwest::Client::new();
+
+    let tunnel_key = match key {
+        Some(key) => decode_key(key).context("Invalid --key, expected the key printed by `ztunnel http --e2e`")?,
+        None => {
+            let resp = client
+                .get(format!("{}{}", url, KEY_PATH))
+                .send()
+                .await
+                .with_context(|| format!("Could not reach {}", url))?;
+            if !resp.status().is_success() {
+                anyhow::bail!("{} is not an end-to-end encrypted tunnel (HTTP {})", url, resp.status());
+            }
+            let key = resp.text().await?;
+            let decoded = decode_key(&key).context("Tunnel sent an invalid key")?;
+            println!("\x1b[33m⚠  Fetched the tunnel's key through the relay; pin it with --key {}\x1b[0m", key.trim());
+            decoded
+        }
+    };
+
+    let keypair = X25519Keypair::generate();
+    let receiver = Arc::new(Receiver {
+        url,
+        public_key: encode_key(&keypair.public_key),
+        channel: Channel::new(&keypair, &tunnel_key, REQUEST_INFO, RESPONSE_INFO),
+        client,
+    });
+
+    let addr = std::net::SocketAddr::from(([127, 0, 0, 1], port));
+    let listener =
+        tokio::net::TcpListener::bind(addr).await.with_context(|| format!("Failed to listen on port {}", port))?;
+    println!("\n\x1b[1;36m🔒 Receiving {}\x1b[0m\n", receiver.url);
+    println!("  Encrypted end to end at \x1b[1mhttp://localhost:{}\x1b[0m (Ctrl-C to stop)\n", port);
+    info!("E2E receiver for {} on port {}", receiver.url, port);
+
+    let app = axum::Router::new().fallback(forward).with_state(receiver);
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+async fn forward(State(receiver): State<Arc<Receiver>>, request: Request) -> Response {
+    match forward_sealed(&receiver, request).await {
+        Ok(response) => response,
+        Err(e) => (StatusCode::BAD_GATEWAY, format!("ztunnel receive: {:#}\n", e)).into_response(),
+    }
+}
+
+async fn forward_sealed(receiver: &Receiver, request: Request) -> Result<Response> {
+    let (parts, body) = request.into_parts();
+    let body = axum::body::to_bytes(body, usize::MAX).await?;
+    let inner = TunnelRequest {
+        id: String::new(),
+        method: parts.method.to_string(),
+        path: parts.uri.path_and_query().map_or("/", |p| p.as_str()).to_string(),
+        headers: parts
+            .headers
+            .iter()
+            .filter(|(k, _)| !HOP_BY_HOP.contains(&k.as_str()))
+            .map(|(k, v)| (k.to_string(), String::from_utf8_lossy(v.as_bytes()).to_string()))
+            .collect(),
+        body: (!body.is_empty()).then_some(body),
+        streamed: false,
+    };
+
+    let resp = receiver
+        .client
+        .post(format!("{}{}", receiver.url, PATH))
+        .header(KEY_HEADER, &receiver.public_key)
+        .body(receiver.channel.seal(&serde_json::to_vec(&inner)?))
+        .send()
+        .await?;
+    let sealed = resp.headers().get("content-type").is_some_and(|v| v.as_bytes() == CONTENT_TYPE.as_bytes());
+    let status = resp.status();
+    let bytes = resp.bytes().await?;
+    if !sealed {
+        anyhow::bail!("Tunnel answered HTTP {}: {}", status, String::from_utf8_lossy(&bytes).trim());
+    }
+    let plain = receiver.channel.open(&bytes).context("Could not decrypt the tunnel's response")?;
+    let inner: TunnelResponse = serde_json::from_slice(&plain).context("Could not decrypt the tunnel's response")?;
+
+    let mut response = Response::builder().status(inner.status);
+    for (k, v) in &inner.headers {
+        if !HOP_BY_HOP.contains(&k.to_ascii_lowercase().as_str()) {
+            response = response.header(k, v);
+        }
+    }
+    Ok(response.body(AxumBody::from(inner.body.unwrap_or_default()))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let tunnel = E2eEndpoint::default();
+        let tunnel_key = decode_key(&tunnel.public_key()).unwrap();
+        let keypair = X25519Keypair::generate();
+        let receiver = Channel::new(&keypair, &tunnel_key, REQUEST_INFO, RESPONSE_INFO);
+
+        let inner = TunnelRequest {
+            id: String::new(),
+            method: "PUT".into(),
+            path: "/secret?q=1".into(),
+            headers: vec![("Authorization".into(), "Bearer x".into()), ("Host".into(), "localhost:8000".into())],
+            body: Some(Bytes::from_static(b"payload")),
+            streamed: false,
+        };
+        let mut outer = TunnelRequest {
+            id: "relay-1".into(),
+            method: "POST".into(),
+            path: PATH.into(),
+            headers: vec![(KEY_HEADER.into(), encode_key(&keypair.public_key))],
+            body: Some(receiver.seal(&serde_json::to_vec(&inner).unwrap()).into()),
+            streamed: false,
+        };
+        let Unwrapped::Request(channel) = tunnel.unwrap(&mut outer) else { panic!("not decrypted") };
+        assert_eq!((outer.id.as_str(), outer.method.as_str(), outer.path.as_str()), ("relay-1", "PUT", "/secret?q=1"));
+        assert_eq!(outer.headers, [("Authorization".to_string(), "Bearer x".to_string())]);
+        assert_eq!(outer.body.as_deref(), Some(&b"payload"[..]));
+
+        let (status, headers, sealed) = seal_response(&channel, "relay-1", 201, &[], b"done");
+        assert_eq!((status, headers[0].1.as_str()), (200, CONTENT_TYPE));
+        let response: TunnelResponse = serde_json::from_slice(&receiver.open(&sealed).unwrap()).unwrap();
+        assert_eq!((response.status, response.body.as_deref()), (201, Some(&b"done"[..])));
+    }
+
+    #[test]
+    fn test_refuses_plaintext() {
+        let tunnel = E2eEndpoint::default();
+        let request = |method: &str, path: &str| TunnelRequest {
+            id: "r".into(),
+            method: method.into(),
+            path: path.into(),
+            headers: Vec::new(),
+            body: None,
+            streamed: false,
+        };
+        let status = |mut r: TunnelRequest| match tunnel.unwrap(&mut r) {
+            Unwrapped::Respond(fixed) => fixed.status,
+            Unwrapped::Request(_) => 0,
+        };
+        assert_eq!(status(request("GET", "/")), 403);
+        assert_eq!(status(request("POST", PATH)), 400);
+        assert_eq!(status(request("GET", KEY_PATH)), 200);
+    }
+
+    #[test]
+    fn test_nonces_never_repeat() {
+        let tunnel = E2eEndpoint::default();
+        let tunnel_key = decode_key(&tunnel.public_key()).unwrap();
+        let keypair = X25519Keypair::generate();
+        let receiver = Channel::new(&keypair, &tunnel_key, REQUEST_INFO, RESPONSE_INFO);
+        let sealed = |key: &str, body: Vec<u8>| TunnelRequest {
+            id: "r".into(),
+            method: "POST".into(),
+            path: PATH.into(),
+            headers: vec![(KEY_HEADER.into(), key.to_string())],
+            body: Some(body.into()),
+            streamed: false,
+        };
+        let get = serde_json::to_vec(&TunnelRequest {
+            id: String::new(),
+            method: "GET".into(),
+            path: "/".into(),
+            headers: Vec::new(),
+            body: None,
+            streamed: false,
+        })
+        .unwrap();
+        let own_key = encode_key(&keypair.public_key);
+        let mut nonces = HashSet::new();
+        let mut respond = |tunnel: &E2eEndpoint| {
+            let Unwrapped::Request(channel) = tunnel.unwrap(&mut sealed(&own_key, receiver.seal(&get))) else {
+                panic!("not decrypted")
+            };
+            let (_, _, envelope) = seal_response(&channel, "r", 200, &[], b"ok");
+            assert!(nonces.insert(envelope[..12].to_vec()), "nonce reused");
+        };
+        respond(&tunnel);
+
+        // Junk from other keys isn't remembered, so it can't push out a
+        // receiver; forgetting one anyway doesn't restart its nonces
+        for i in 0..MAX_PEERS as u16 + 10 {
+            let mut key = [7u8; 32];
+            key[..2].copy_from_slice(&i.to_le_bytes());
+            tunnel.unwrap(&mut sealed(&encode_key(&key), vec![0; 64]));
+        }
+        assert_eq!(tunnel.peers.lock().unwrap().len(), 1);
+        for _ in 0..50 {
+            tunnel.peers.lock().unwrap().clear();
+            respond(&tunnel);
+        }
+
+        // The relay can't replay a request, or send one sealed long ago
+        let envelope = receiver.seal(&get);
+        assert!(matches!(tunnel.unwrap(&mut sealed(&own_key, envelope.clone())), Unwrapped::Request(_)));
+        assert!(matches!(tunnel.unwrap(&mut sealed(&own_key, envelope)), Unwrapped::Respond(f) if f.status == 400));
+        let nonce = fresh_nonce(unix_secs() - 2 * REPLAY_WINDOW.as_secs());
+        let (ciphertext, tag) = receiver.send.encrypt_with_nonce(&get, &nonce).unwrap();
+        let stale = [&nonce[..], &tag[..], &ciphertext].concat();
+        assert!(matches!(tunnel.unwrap(&mut sealed(&own_key, stale)), Unwrapped::Respond(f) if f.status == 400));
+    }
+}
@@ -0,0 +1,126 @@
+//! Inspector session export and import
+//!
+//! `GET /api/export` dumps the recorded entries, full bodies included,
+//! as one JSON document that `POST /api/import` loads back. `ztunnel
+//! inspect export|import` wrap both for a running inspector; when none
+//! is listening, `import` serves the file on its own so a session
+//! attached to a bug report can be opened without a tunnel.
+
+use crate::api::InspectorClient;
+use crate::inspector::{EntryQuery, InspectorEntry, InspectorState};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Bumped when the file layout changes incompatibly
+pub const FORMAT_VERSION: u32 = 1;
+
+/// An exported session
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SessionExport {
+    pub version: u32,
+    pub exported_at: String,
+    /// Newest first, as listed by `/api/entries`
+    pub entries: Vec<InspectorEntry>,
+}
+
+impl SessionExport {
+    pub fn new(entries: Vec<InspectorEntry>) -> Self {
+        Self { version: FORMAT_VERSION, exported_at: chrono::Utc::now().to_rfc3339(), entries }
+    }
+
+    /// Read an export, refusing versions this build doesn't know
+    pub fn parse(bytes: &[u8]) -> Result<Self, String> {
+        let export: Self = serde_json::from_slice(bytes).map_err(|e| format!("not a ztunnel session export: {}", e))?;
+        if export.version > FORMAT_VERSION {
+            return Err(format!(
+                "session export version {} is newer than this ztunnel supports ({})",
+                export.version, FORMAT_VERSION
+            ));
+        }
+        Ok(export)
+    }
+}
+
+/// Save a running inspector's entries to `output`, or stdout
+pub async fn run_export(port: u16, output: Option<&Path>) -> Result<()> {
+    let export = InspectorClient::new(port)
+        .export(&EntryQuery::default())
+        .await
+        .with_context(|| format!("Export from the inspector on port {} failed", port))?;
+    let body = serde_json::to_vec(&export)?;
+
+    match output {
+        Some(path) => {
+            std::fs::write(path, &body).with_context(|| format!("Failed to write {}", path.display()))?;
+            println!("\x1b[32m✓ Exported {} request(s) to {}\x1b[0m", export.entries.len(), path.display());
+        }
+        None => {
+            use std::io::Write;
+            std::io::stdout().write_all(&body)?;
+        }
+    }
+    Ok(())
+}
+
+/// Load an export into the inspector on `port`, or serve it there
+/// when no inspector is running
+pub async fn run_import(port: u16, file: &Path) -> Result<()> {
+    let bytes = std::fs::read(file).with_context(|| format!("Failed to read {}", file.display()))?;
+    let export = SessionExport::parse(&bytes).map_err(anyhow::Error::msg)?;
+
+    match InspectorClient::new(port).import(&export).await {
+        Ok(result) => {
+            println!(
+                "\x1b[32m✓ Imported {} request(s) into http://localhost:{}\x1b[0m ({} already present)",
+                result.imported, port, result.skipped
+            );
+            Ok(())
+        }
+        Err(e) if e.downcast_ref::<reqwest::Error>().is_some_and(reqwest::Error::is_connect) => serve(export, port).await,
+        Err(e) => Err(e.context("Import failed")),
+    }
+}
+
+/// Serve an export on a standalone inspector until interrupted.
+/// Replay has no tunnel to go through and reports so.
+async fn serve(export: SessionExport, port: u16) -> Result<()> {
+    let count = export.entries.len();
+    let state = InspectorState::new(tokio::sync::mpsc::channel(1).0)
+        .with_capacity(count.max(crate::config::DEFAULT_MAX_ENTRIES), 0);
+    state.import(export.entries).await;
+
+    println!("\n\x1b[1;36m⚡ Inspecting {}\x1b[0m\n", export.exported_at);
+    println!("  {} request(s) at \x1b[1mhttp://localhost:{}\x1b[0m (Ctrl-C to stop)\n", count, port);
+    tokio::select! {
+        _ = crate::inspector::start_inspector(state, port) => anyhow::bail!("Inspector stopped"),
+        _ = tokio::signal::ctrl_c() => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::body::Body;
+
+    #[tokio::test]
+    async fn test_export_round_trip() {
+        let entry = |id: &str| InspectorEntry {
+            id: id.to_string(),
+            res_body: Some(Body::new(vec![0xff, 0x00])),
+            ..Default::default()
+        };
+        let json = serde_json::to_vec(&SessionExport::new(vec![entry("b"), entry("a")])).unwrap();
+        let export = SessionExport::parse(&json).unwrap();
+        assert_eq!(export.entries[0].res_body, Some(Body::new(vec![0xff, 0x00])));
+
+        let state = InspectorState::new(tokio::sync::mpsc::channel(1).0);
+        state.record(entry("a")).await;
+        assert_eq!(state.import(export.entries).await, 1);
+        assert!(state.get_entry("b").await.is_some());
+
+        let future = format!(r#"{{"version":{},"exported_at":"","entries":[]}}"#, FORMAT_VERSION + 1);
+        assert!(SessionExport::parse(future.as_bytes()).unwrap_err().contains("newer"));
+        assert!(SessionExport::parse(b"[]").is_err());
+    }
+}
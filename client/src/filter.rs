@@ -0,0 +1,61 @@
+//! Client-side request filtering
+//!
+//! Per-tunnel allow/deny path globs checked before a request
+//! reaches the local service.
+
+use ztunnel_shared::glob::matches_glob;
+
+/// Path filter for a tunnel
+#[derive(Debug, Clone, Default)]
+pub struct PathFilter {
+    /// Allowed path globs (empty = allow all)
+    pub allow: Vec<String>,
+    /// Denied path globs
+    pub deny: Vec<String>,
+}
+
+impl PathFilter {
+    pub fn new(allow: &[String], deny: &[String]) -> Self {
+        Self {
+            allow: allow.to_vec(),
+            deny: deny.to_vec(),
+        }
+    }
+
+    /// Check if a request path (query string ignored) may be forwarded
+    pub fn is_allowed(&self, path: &str) -> bool {
+        let path = path.split(['?', '#']).next().unwrap_or(path);
+
+        // Check deny list first
+        if self.deny.iter().any(|p| matches_glob(p, path)) {
+            return false;
+        }
+
+        // If allow list is empty, allow all (that aren't denied)
+        self.allow.is_empty() || self.allow.iter().any(|p| matches_glob(p, path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_path_filter() {
+        let filter = PathFilter::new(
+            &["/api/**".to_string(), "/health".to_string()],
+            &["/api/admin/**".to_string()],
+        );
+
+        assert!(filter.is_allowed("/api/users?page=2"));
+        assert!(filter.is_allowed("/health"));
+        assert!(!filter.is_allowed("/api/admin/users")); // denied
+        assert!(!filter.is_allowed("/.env")); // not in allow
+    }
+
+    #[test]
+    fn test_empty_filter() {
+        let filter = PathFilter::default();
+        assert!(filter.is_allowed("/anything"));
+    }
+}
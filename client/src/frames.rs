@@ -0,0 +1,359 @@
+//! WebSocket frame capture
+//!
+//! Upgraded WebSocket connections are pumped as raw bytes (see
+//! `stream`). When the tunnel is inspected, each direction also runs
+//! through a `FrameParser` so the inspector can list the frames of a
+//! connection at `/api/entries/{id}/frames`. Only the first
+//! `PREVIEW_BYTES` of each payload are kept; frames live in memory only.
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Payload bytes kept per frame
+const PREVIEW_BYTES: usize = 1024;
+
+/// Frames kept per connection; older ones are dropped
+const MAX_FRAMES: usize = 1000;
+
+/// Connections with frames kept, matching the inspector's ring buffer
+const MAX_CONNECTIONS: usize = 500;
+
+/// Which way a frame travelled
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    /// Visitor → local service
+    Inbound,
+    /// Local service → visitor
+    Outbound,
+}
+
+/// One captured frame
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WsFrame {
+    pub direction: Direction,
+    /// `text`, `binary`, `continuation`, `close`, `ping`, `pong`, or the
+    /// raw opcode number for reserved ones
+    pub opcode: String,
+    pub fin: bool,
+    /// RSV1 is set, i.e. the payload is permessage-deflate compressed
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub compressed: bool,
+    /// Full payload length
+    pub length: u64,
+    /// Start of the payload, as text or base64
+    pub payload: String,
+    /// `utf8` or `base64`
+    pub encoding: Cow<'static, str>,
+    pub truncated: bool,
+    pub timestamp: String,
+    /// Time since the connection was upgraded
+    pub elapsed_ms: u64,
+}
+
+/// A frame as read off the wire, before it is stamped
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParsedFrame {
+    pub fin: bool,
+    pub rsv1: bool,
+    pub opcode: u8,
+    pub length: u64,
+    /// Unmasked start of the payload
+    pub preview: Vec<u8>,
+}
+
+/// Frame header plus progress through its payload
+#[derive(Debug)]
+struct Partial {
+    frame: ParsedFrame,
+    mask: Option<[u8; 4]>,
+    seen: u64,
+}
+
+impl Partial {
+    fn remaining(&self) -> u64 {
+        self.frame.length - self.seen
+    }
+
+    fn consume(&mut self, bytes: &[u8]) {
+        let room = PREVIEW_BYTES.saturating_sub(self.frame.preview.len()).min(bytes.len());
+        for (i, b) in bytes[..room].iter().enumerate() {
+            let offset = self.seen as usize + i;
+            self.frame.preview.push(match self.mask {
+                Some(mask) => b ^ mask[offset % 4],
+                None => *b,
+            });
+        }
+        self.seen += bytes.len() as u64;
+    }
+}
+
+/// Incremental frame parser for one direction of a connection. Bytes
+/// can arrive in any chunking; payloads past the preview are skipped
+/// rather than buffered.
+#[derive(Debug, Default)]
+pub struct FrameParser {
+    buf: Vec<u8>,
+    current: Option<Partial>,
+}
+
+impl FrameParser {
+    /// Feed bytes, returning every frame they complete
+    pub fn push(&mut self, data: &[u8]) -> Vec<ParsedFrame> {
+        self.buf.extend_from_slice(data);
+        let mut frames = Vec::new();
+        let mut pos = 0;
+        loop {
+            if self.current.is_none() {
+                match parse_header(&self.buf[pos..]) {
+                    Some((partial, used)) => {
+                        pos += used;
+                        self.current = Some(partial);
+                    }
+                    None => break,
+                }
+            }
+            let Some(partial) = self.current.as_mut() else { break };
+            let take = partial.remaining().min((self.buf.len() - pos) as u64) as usize;
+            partial.consume(&self.buf[pos..pos + take]);
+            pos += take;
+            if partial.remaining() > 0 {
+                break;
+            }
+            if let Some(done) = self.current.take() {
+                frames.push(done.frame);
+            }
+        }
+        self.buf.drain(..pos);
+        frames
+    }
+}
+
+/// Parse a frame header (RFC 6455 §5.2); none until it's all there
+fn parse_header(buf: &[u8]) -> Option<(Partial, usize)> {
+    let (&b0, &b1) = (buf.first()?, buf.get(1)?);
+    let mut pos = 2;
+    let length = match b1 & 0x7f {
+        126 => {
+            let bytes = buf.get(pos..pos + 2)?;
+            pos += 2;
+            u16::from_be_bytes([bytes[0], bytes[1]]) as u64
+        }
+        127 => {
+            let bytes = buf.get(pos..pos + 8)?;
+            pos += 8;
+            u64::from_be_bytes(bytes.try_into().ok()?)
+        }
+        n => n as u64,
+    };
+    let mask = if b1 & 0x80 != 0 {
+        let key = buf.get(pos..pos + 4)?;
+        pos += 4;
+        Some([key[0], key[1], key[2], key[3]])
+    } else {
+        None
+    };
+    let frame = ParsedFrame {
+        fin: b0 & 0x80 != 0,
+        rsv1: b0 & 0x40 != 0,
+        opcode: b0 & 0x0f,
+        length,
+        preview: Vec::new(),
+    };
+    Some((Partial { frame, mask, seen: 0 }, pos))
+}
+
+fn opcode_name(opcode: u8) -> String {
+    match opcode {
+        0x0 => "continuation".to_string(),
+        0x1 => "text".to_string(),
+        0x2 => "binary".to_string(),
+        0x8 => "close".to_string(),
+        0x9 => "ping".to_string(),
+        0xa => "pong".to_string(),
+        other => other.to_string(),
+    }
+}
+
+/// Payload preview as text where it is text, base64 otherwise
+fn encode_preview(opcode: u8, preview: &[u8], truncated: bool) -> (String, &'static str) {
+    if opcode != 0x2 {
+        match std::str::from_utf8(preview) {
+            Ok(text) => return (text.to_string(), "utf8"),
+            // Truncation may have split a character
+            Err(e) if truncated && e.error_len().is_none() => {
+                return (String::from_utf8_lossy(&preview[..e.valid_up_to()]).to_string(), "utf8");
+            }
+            Err(_) => {}
+        }
+    }
+    (STANDARD.encode(preview), "base64")
+}
+
+#[derive(Default)]
+struct Store {
+    frames: HashMap<String, VecDeque<WsFrame>>,
+    /// Connection ids, oldest first
+    order: VecDeque<String>,
+}
+
+/// Captured frames by connection, shared by the tunnels and the
+/// inspector API
+#[derive(Clone, Default)]
+pub struct FrameLog {
+    store: Arc<Mutex<Store>>,
+}
+
+impl FrameLog {
+    /// Start capturing the connection upgraded by request `id`
+    pub fn start(&self, id: &str) -> Capture {
+        let mut store = self.lock();
+        if store.order.len() >= MAX_CONNECTIONS {
+            if let Some(oldest) = store.order.pop_front() {
+                store.frames.remove(&oldest);
+            }
+        }
+        store.order.push_back(id.to_string());
+        store.frames.insert(id.to_string(), VecDeque::new());
+        Capture { id: id.to_string(), log: self.clone(), started: Instant::now() }
+    }
+
+    /// Frames of one connection, oldest first; none if it wasn't captured
+    pub fn frames(&self, id: &str) -> Option<Vec<WsFrame>> {
+        self.lock().frames.get(id).map(|f| f.iter().cloned().collect())
+    }
+
+    /// Drop the frames of one connection
+    pub fn remove(&self, id: &str) {
+        let mut store = self.lock();
+        store.frames.remove(id);
+        store.order.retain(|o| o != id);
+    }
+
+    pub fn clear(&self) {
+        *self.lock() = Store::default();
+    }
+
+    fn push(&self, id: &str, frame: WsFrame) {
+        if let Some(frames) = self.lock().frames.get_mut(id) {
+            if frames.len() >= MAX_FRAMES {
+                frames.pop_front();
+            }
+            frames.push_back(frame);
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Store> {
+        self.store.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Recorder for one connection, cloned into both pump tasks
+#[derive(Clone)]
+pub struct Capture {
+    id: String,
+    log: FrameLog,
+    started: Instant,
+}
+
+impl Capture {
+    pub fn record(&self, direction: Direction, frame: ParsedFrame) {
+        let truncated = (frame.preview.len() as u64) < frame.length;
+        let (payload, encoding) = encode_preview(frame.opcode, &frame.preview, truncated);
+        self.log.push(
+            &self.id,
+            WsFrame {
+                direction,
+                opcode: opcode_name(frame.opcode),
+                fin: frame.fin,
+                compressed: frame.rsv1,
+                length: frame.length,
+                payload,
+                encoding: encoding.into(),
+                truncated,
+                timestamp: chrono::Utc::now().to_rfc3339(),
+                elapsed_ms: self.started.elapsed().as_millis() as u64,
+            },
+        );
+    }
+}
+
+/// Whether an upgrade request asks for WebSocket
+pub fn is_websocket(headers: &[(String, String)]) -> bool {
+    headers.iter().any(|(k, v)| k.eq_ignore_ascii_case("upgrade") && v.trim().eq_ignore_ascii_case("websocket"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(opcode: u8, payload: &[u8], mask: Option<[u8; 4]>) -> Vec<u8> {
+        let mut out = vec![0x80 | opcode];
+        let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+        match payload.len() {
+            n if n < 126 => out.push(mask_bit | n as u8),
+            n if n <= u16::MAX as usize => {
+                out.push(mask_bit | 126);
+                out.extend_from_slice(&(n as u16).to_be_bytes());
+            }
+            n => {
+                out.push(mask_bit | 127);
+                out.extend_from_slice(&(n as u64).to_be_bytes());
+            }
+        }
+        match mask {
+            Some(key) => {
+                out.extend_from_slice(&key);
+                out.extend(payload.iter().enumerate().map(|(i, b)| b ^ key[i % 4]));
+            }
+            None => out.extend_from_slice(payload),
+        }
+        out
+    }
+
+    #[test]
+    fn test_parser_handles_masking_and_chunking() {
+        let mut wire = frame(0x1, b"hello", Some([1, 2, 3, 4]));
+        wire.extend(frame(0x9, b"", None));
+        let big = vec![7u8; 70_000];
+        wire.extend(frame(0x2, &big, None));
+
+        // Byte at a time through the small frames, then the rest
+        let mut parser = FrameParser::default();
+        let mut frames = Vec::new();
+        for b in &wire[..9] {
+            frames.extend(parser.push(&[*b]));
+        }
+        frames.extend(parser.push(&wire[9..]));
+
+        assert_eq!(frames.len(), 3);
+        assert_eq!((frames[0].opcode, frames[0].preview.as_slice()), (0x1, &b"hello"[..]));
+        assert_eq!((frames[1].opcode, frames[1].length), (0x9, 0));
+        assert_eq!((frames[2].length, frames[2].preview.len()), (70_000, PREVIEW_BYTES));
+        assert!(parser.buf.is_empty());
+    }
+
+    #[test]
+    fn test_capture_records_frames() {
+        let log = FrameLog::default();
+        let capture = log.start("r1");
+        let mut parser = FrameParser::default();
+        for f in parser.push(&frame(0x1, "héllo".as_bytes(), Some([9, 9, 9, 9]))) {
+            capture.record(Direction::Inbound, f);
+        }
+        for f in parser.push(&frame(0x2, &[0, 255], None)) {
+            capture.record(Direction::Outbound, f);
+        }
+
+        let frames = log.frames("r1").unwrap();
+        assert_eq!((frames[0].opcode.as_str(), frames[0].payload.as_str()), ("text", "héllo"));
+        assert_eq!((frames[1].encoding.as_ref(), frames[1].payload.as_str()), ("base64", "AP8="));
+        assert_eq!(frames[1].direction, Direction::Outbound);
+        assert!(log.frames("r2").is_none());
+    }
+}
@@ -0,0 +1,150 @@
+//! Client-side header rewriting
+//!
+//! Per-tunnel add/set/remove rules applied to requests before they
+//! reach the local service and to responses before they go back
+//! through the relay, and the permissive CORS headers added with
+//! `cors: true`.
+
+use crate::config::HeaderRulesConfig;
+
+/// Header rewrite rule
+#[derive(Debug, Clone, PartialEq)]
+pub enum HeaderRule {
+    /// Add header (won't overwrite existing)
+    Add(String, String),
+    /// Set header (overwrites existing)
+    Set(String, String),
+    /// Remove header by name
+    Remove(String),
+}
+
+/// Ordered list of header rules
+#[derive(Debug, Clone, Default)]
+pub struct HeaderRules {
+    pub rules: Vec<HeaderRule>,
+}
+
+impl HeaderRules {
+    /// Compile a config section: removals first, then sets, then adds
+    pub fn from_config(conf: &HeaderRulesConfig) -> Self {
+        let mut rules = Vec::new();
+        rules.extend(conf.remove.iter().map(|k| HeaderRule::Remove(k.clone())));
+        rules.extend(conf.set.iter().map(|(k, v)| HeaderRule::Set(k.clone(), v.clone())));
+        rules.extend(conf.add.iter().map(|(k, v)| HeaderRule::Add(k.clone(), v.clone())));
+        Self { rules }
+    }
+
+    /// Apply rules in order
+    pub fn apply(&self, headers: &mut Vec<(String, String)>) {
+        for rule in &self.rules {
+            match rule {
+                HeaderRule::Add(k, v) => {
+                    if !headers.iter().any(|(name, _)| name.eq_ignore_ascii_case(k)) {
+                        headers.push((k.clone(), v.clone()));
+                    }
+                }
+                HeaderRule::Set(k, v) => {
+                    upsert(headers, k, v);
+                }
+                HeaderRule::Remove(k) => {
+                    headers.retain(|(name, _)| !name.eq_ignore_ascii_case(k));
+                }
+            }
+        }
+    }
+}
+
+/// Methods advertised to CORS preflights
+const CORS_METHODS: &str = "GET, POST, PUT, PATCH, DELETE, OPTIONS, HEAD";
+
+/// Whether a request is a CORS preflight, which `cors` answers itself
+pub fn is_preflight(method: &str, headers: &[(String, String)]) -> bool {
+    method.eq_ignore_ascii_case("OPTIONS")
+        && headers.iter().any(|(k, _)| k.eq_ignore_ascii_case("access-control-request-method"))
+}
+
+/// Allow any origin: the request's `Origin` is echoed back, so
+/// credentialed requests work too, and a preflight's requested headers
+/// are allowed as asked
+pub fn inject_cors(request: &[(String, String)], response: &mut Vec<(String, String)>) {
+    let get = |name: &str| request.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str());
+    match get("origin") {
+        Some(origin) => {
+            upsert(response, "Access-Control-Allow-Origin", origin);
+            upsert(response, "Access-Control-Allow-Credentials", "true");
+            match response.iter_mut().find(|(k, _)| k.eq_ignore_ascii_case("vary")) {
+                Some((_, v)) if !v.to_ascii_lowercase().contains("origin") => v.push_str(", Origin"),
+                Some(_) => {}
+                None => response.push(("Vary".to_string(), "Origin".to_string())),
+            }
+        }
+        None => upsert(response, "Access-Control-Allow-Origin", "*"),
+    }
+    upsert(response, "Access-Control-Allow-Methods", CORS_METHODS);
+    upsert(response, "Access-Control-Allow-Headers", get("access-control-request-headers").unwrap_or("*"));
+    upsert(response, "Access-Control-Expose-Headers", "*");
+    upsert(response, "Access-Control-Max-Age", "86400");
+}
+
+/// Insert or update a header
+fn upsert(headers: &mut Vec<(String, String)>, key: &str, value: &str) {
+    if let Some(h) = headers.iter_mut().find(|(k, _)| k.eq_ignore_ascii_case(key)) {
+        h.1 = value.to_string();
+    } else {
+        headers.push((key.to_string(), value.to_string()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rules_from_config() {
+        let conf: HeaderRulesConfig = serde_yaml::from_str(
+            r#"
+add:
+  X-Env: dev
+set:
+  X-Api-Key: secret
+remove: [Cookie]
+"#,
+        )
+        .unwrap();
+        let rules = HeaderRules::from_config(&conf);
+
+        let mut h = vec![
+            ("cookie".to_string(), "session=1".to_string()),
+            ("x-api-key".to_string(), "public".to_string()),
+            ("x-env".to_string(), "prod".to_string()),
+        ];
+        rules.apply(&mut h);
+
+        assert!(!h.iter().any(|(k, _)| k.eq_ignore_ascii_case("cookie")));
+        assert!(h.iter().any(|(k, v)| k == "x-api-key" && v == "secret"));
+        // Add never overwrites
+        assert!(h.iter().any(|(k, v)| k == "x-env" && v == "prod"));
+    }
+
+    #[test]
+    fn test_cors() {
+        let preflight = vec![
+            ("Origin".to_string(), "https://app.example.com".to_string()),
+            ("Access-Control-Request-Method".to_string(), "PUT".to_string()),
+            ("Access-Control-Request-Headers".to_string(), "x-token".to_string()),
+        ];
+        assert!(is_preflight("OPTIONS", &preflight));
+        assert!(!is_preflight("OPTIONS", &[]));
+
+        let mut headers = vec![("Access-Control-Allow-Origin".to_string(), "https://other".to_string())];
+        inject_cors(&preflight, &mut headers);
+        let get = |name: &str| headers.iter().find(|(k, _)| k == name).map(|(_, v)| v.as_str());
+        assert_eq!(get("Access-Control-Allow-Origin"), Some("https://app.example.com"));
+        assert_eq!(get("Access-Control-Allow-Headers"), Some("x-token"));
+        assert_eq!(get("Access-Control-Allow-Credentials"), Some("true"));
+
+        let mut headers = Vec::new();
+        inject_cors(&[], &mut headers);
+        assert_eq!(headers[0], ("Access-Control-Allow-Origin".to_string(), "*".to_string()));
+    }
+}
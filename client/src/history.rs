@@ -0,0 +1,246 @@
+//! Persistent inspector history
+//!
+//! Entries are stored in SQLite as JSON, so request history survives
+//! restarts and can grow past the in-memory ring buffer, which stays in
+//! front as a hot cache. Retention by age and count is applied when the
+//! database is opened and every few hundred inserts after that.
+
+use crate::config::HistoryConfig;
+use crate::inspector::{EntryFilter, InspectorEntry};
+use anyhow::{Context, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Inserts between retention passes
+const PRUNE_EVERY: u64 = 500;
+
+/// Handle to the history database
+#[derive(Clone)]
+pub struct History {
+    conn: Arc<Mutex<Connection>>,
+    max_age_secs: Option<u64>,
+    max_entries: Option<usize>,
+    inserts: Arc<AtomicU64>,
+}
+
+impl History {
+    /// Open (or create) the database and apply retention
+    pub fn open(conf: &HistoryConfig) -> Result<Self> {
+        let path = expand_home(&conf.path);
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        let conn = Connection::open(&path)
+            .with_context(|| format!("Failed to open history database {}", path.display()))?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS entries (
+                seq INTEGER PRIMARY KEY AUTOINCREMENT,
+                id TEXT NOT NULL UNIQUE,
+                recorded_at INTEGER NOT NULL,
+                entry TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS entries_recorded_at ON entries (recorded_at);",
+        )?;
+
+        let history = Self {
+            conn: Arc::new(Mutex::new(conn)),
+            max_age_secs: (conf.max_age_days > 0).then_some(conf.max_age_days * 86_400),
+            max_entries: (conf.max_entries > 0).then_some(conf.max_entries),
+            inserts: Arc::new(AtomicU64::new(0)),
+        };
+        history.prune(&history.lock())?;
+        Ok(history)
+    }
+
+    /// Store one entry, replacing any earlier entry with the same ID
+    pub async fn insert(&self, entry: &InspectorEntry) -> Result<()> {
+        let json = serde_json::to_string(entry)?;
+        let id = entry.id.clone();
+        let prune = self.inserts.fetch_add(1, Ordering::Relaxed) % PRUNE_EVERY == PRUNE_EVERY - 1;
+        self.run(move |h, conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO entries (id, recorded_at, entry) VALUES (?1, ?2, ?3)",
+                params![id, now_secs() as i64, json],
+            )?;
+            if prune {
+                h.prune(conn)?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    /// Newest entries first, at most `limit`
+    pub async fn recent(&self, limit: usize) -> Result<Vec<InspectorEntry>> {
+        self.run(move |_, conn| {
+            let mut stmt = conn.prepare("SELECT entry FROM entries ORDER BY seq DESC LIMIT ?1")?;
+            let rows = stmt.query_map([limit as i64], |row| row.get::<_, String>(0))?;
+            Ok(rows.filter_map(|r| r.ok().and_then(|json| serde_json::from_str(&json).ok())).collect())
+        })
+        .await
+    }
+
+    pub async fn get(&self, id: &str) -> Result<Option<InspectorEntry>> {
+        let id = id.to_string();
+        self.run(move |_, conn| {
+            let json: Option<String> = conn
+                .query_row("SELECT entry FROM entries WHERE id = ?1", [id], |row| row.get(0))
+                .optional()?;
+            Ok(json.and_then(|j| serde_json::from_str(&j).ok()))
+        })
+        .await
+    }
+
+    /// Filter the whole history, newest first. Returns the number of
+    /// matches and the requested page of them.
+    pub async fn query(
+        &self,
+        filter: EntryFilter,
+        offset: usize,
+        limit: Option<usize>,
+    ) -> Result<(usize, Vec<InspectorEntry>)> {
+        self.run(move |_, conn| {
+            let mut stmt = conn.prepare("SELECT entry FROM entries ORDER BY seq DESC")?;
+            let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+            let mut total = 0;
+            let mut page = Vec::new();
+            for json in rows {
+                let Ok(entry) = serde_json::from_str::<InspectorEntry>(&json?) else {
+                    continue;
+                };
+                if !filter.matches(&entry) {
+                    continue;
+                }
+                if total >= offset && limit.is_none_or(|l| page.len() < l) {
+                    page.push(entry);
+                }
+                total += 1;
+            }
+            Ok((total, page))
+        })
+        .await
+    }
+
+    /// Delete one entry; false if it wasn't stored
+    pub async fn delete(&self, id: &str) -> Result<bool> {
+        let id = id.to_string();
+        self.run(move |_, conn| Ok(conn.execute("DELETE FROM entries WHERE id = ?1", [id])? > 0)).await
+    }
+
+    /// Delete every entry, or only those of one tunnel. Returns how many
+    /// were deleted.
+    pub async fn clear(&self, tunnel: Option<&str>) -> Result<usize> {
+        let tunnel = tunnel.map(str::to_string);
+        self.run(move |_, conn| match tunnel {
+            Some(tunnel) => conn.execute("DELETE FROM entries WHERE json_extract(entry, '$.tunnel') = ?1", [tunnel]),
+            None => conn.execute("DELETE FROM entries", []),
+        })
+        .await
+    }
+
+    fn prune(&self, conn: &Connection) -> rusqlite::Result<()> {
+        if let Some(max_age) = self.max_age_secs {
+            let cutoff = now_secs().saturating_sub(max_age);
+            conn.execute("DELETE FROM entries WHERE recorded_at < ?1", [cutoff as i64])?;
+        }
+        if let Some(max) = self.max_entries {
+            conn.execute(
+                "DELETE FROM entries WHERE seq <= (SELECT seq FROM entries ORDER BY seq DESC LIMIT 1 OFFSET ?1)",
+                [max as i64],
+            )?;
+        }
+        Ok(())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Run a query on the blocking pool
+    async fn run<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&History, &Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let history = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let conn = history.lock();
+            f(&history, &conn)
+        })
+        .await?
+        .context("History database error")
+    }
+}
+
+/// `~/x` → `$HOME/x`
+fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), dirs::home_dir()) {
+        (Some(rest), Some(home)) => home.join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(id: &str, status: u16) -> InspectorEntry {
+        InspectorEntry {
+            id: id.to_string(),
+            timestamp: "2024-05-01T10:00:00Z".to_string(),
+            method: "GET".to_string(),
+            path: format!("/{}", id),
+            status,
+            latency_ms: 1,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_history_persists_and_prunes() {
+        let path = std::env::temp_dir().join(format!("ztunnel-history-test-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let conf = HistoryConfig { path: path.display().to_string(), max_age_days: 7, max_entries: 3 };
+
+        let history = History::open(&conf).unwrap();
+        for (i, status) in [200, 404, 500, 200].into_iter().enumerate() {
+            history.insert(&entry(&format!("r{}", i), status)).await.unwrap();
+        }
+        drop(history);
+
+        // Reopening applies the 3-entry cap and keeps the newest
+        let history = History::open(&conf).unwrap();
+        let ids: Vec<_> = history.recent(10).await.unwrap().into_iter().map(|e| e.id).collect();
+        assert_eq!(ids, ["r3", "r2", "r1"]);
+        assert!(history.get("r0").await.unwrap().is_none());
+        assert_eq!(history.get("r2").await.unwrap().unwrap().status, 500);
+
+        let filter = EntryFilter::from_query(&crate::inspector::EntryQuery {
+            status: Some("2xx".into()),
+            ..Default::default()
+        })
+        .unwrap();
+        let (total, page) = history.query(filter, 0, Some(10)).await.unwrap();
+        assert_eq!((total, page[0].id.as_str()), (1, "r3"));
+
+        let (total, page) = history.query(EntryFilter::default(), 1, Some(1)).await.unwrap();
+        assert_eq!((total, page.len(), page[0].id.as_str()), (3, 1, "r2"));
+
+        assert!(history.delete("r2").await.unwrap());
+        assert!(!history.delete("r2").await.unwrap());
+        history.insert(&InspectorEntry { tunnel: "api".into(), ..entry("r4", 200) }).await.unwrap();
+        assert_eq!(history.clear(Some("api")).await.unwrap(), 1);
+        assert_eq!(history.clear(None).await.unwrap(), 2);
+        assert!(history.recent(10).await.unwrap().is_empty());
+
+        drop(history);
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,209 @@
+//! Lifecycle hook commands
+//!
+//! A tunnel's `hooks` run a shell command when it registers with a relay
+//! (`on_connect`), loses its relay connection (`on_disconnect`), or
+//! answers a matching request (`on_request`). Event details are passed
+//! in `ZTUNNEL_*` environment variables. Commands run in the background;
+//! a failing hook is logged and never affects the tunnel.
+
+use crate::config::HooksConfig;
+use crate::intercept::InterceptRule;
+use tokio::process::Command;
+use tracing::{debug, warn};
+
+/// Which hook an event runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookEvent {
+    Connect,
+    Disconnect,
+    Request,
+}
+
+impl HookEvent {
+    fn name(self) -> &'static str {
+        match self {
+            HookEvent::Connect => "connect",
+            HookEvent::Disconnect => "disconnect",
+            HookEvent::Request => "request",
+        }
+    }
+}
+
+/// `on_request` with its rules parsed
+#[derive(Debug, Clone)]
+struct RequestHook {
+    run: String,
+    rules: Vec<InterceptRule>,
+    min_status: Option<u16>,
+}
+
+/// A tunnel's hooks, ready to run
+#[derive(Debug, Clone)]
+pub struct Hooks {
+    tunnel: String,
+    on_connect: Option<String>,
+    on_disconnect: Option<String>,
+    on_request: Option<RequestHook>,
+}
+
+impl Hooks {
+    /// None when no hook is configured. Rules are checked by
+    /// `TunnelConfig::validate`; invalid ones are skipped here.
+    pub fn from_config(tunnel: &str, conf: &HooksConfig) -> Option<Self> {
+        let on_request = conf.on_request.as_ref().map(|hook| RequestHook {
+            run: hook.run.clone(),
+            rules: hook.rules.iter().filter_map(|spec| InterceptRule::parse(spec)).collect(),
+            min_status: hook.min_status,
+        });
+        if conf.on_connect.is_none() && conf.on_disconnect.is_none() && on_request.is_none() {
+            return None;
+        }
+        Some(Self {
+            tunnel: tunnel.to_string(),
+            on_connect: conf.on_connect.clone(),
+            on_disconnect: conf.on_disconnect.clone(),
+            on_request,
+        })
+    }
+
+    /// Registered with a relay. `previous_url` is the URL of the last
+    /// registration, if there was one, so a hook can tell when it changed.
+    pub fn connected(&self, url: &str, subdomain: &str, relay: &str, previous_url: Option<&str>) {
+        let Some(command) = &self.on_connect else { return };
+        let mut env = vec![
+            ("ZTUNNEL_URL", url.to_string()),
+            ("ZTUNNEL_SUBDOMAIN", subdomain.to_string()),
+            ("ZTUNNEL_RELAY", relay.to_string()),
+            ("ZTUNNEL_URL_CHANGED", bool_env(previous_url.is_some_and(|p| p != url))),
+        ];
+        if let Some(previous) = previous_url {
+            env.push(("ZTUNNEL_PREVIOUS_URL", previous.to_string()));
+        }
+        self.spawn(HookEvent::Connect, command, env);
+    }
+
+    /// The relay connection closed; `reason` is the error, if any
+    pub fn disconnected(&self, url: &str, relay: &str, reason: Option<&str>) {
+        let Some(command) = &self.on_disconnect else { return };
+        let env = vec![
+            ("ZTUNNEL_URL", url.to_string()),
+            ("ZTUNNEL_RELAY", relay.to_string()),
+            ("ZTUNNEL_REASON", reason.unwrap_or_default().to_string()),
+        ];
+        self.spawn(HookEvent::Disconnect, command, env);
+    }
+
+    /// A request was answered
+    pub fn request(&self, id: &str, method: &str, path: &str, status: u16, latency_ms: u64) {
+        let Some(hook) = &self.on_request else { return };
+        if !hook.matches(method, path, status) {
+            return;
+        }
+        let env = vec![
+            ("ZTUNNEL_REQUEST_ID", id.to_string()),
+            ("ZTUNNEL_METHOD", method.to_string()),
+            ("ZTUNNEL_PATH", path.to_string()),
+            ("ZTUNNEL_STATUS", status.to_string()),
+            ("ZTUNNEL_LATENCY_MS", latency_ms.to_string()),
+        ];
+        self.spawn(HookEvent::Request, &hook.run, env);
+    }
+
+    fn spawn(&self, event: HookEvent, command: &str, env: Vec<(&'static str, String)>) {
+        let mut cmd = shell(command);
+        cmd.env("ZTUNNEL_EVENT", event.name()).env("ZTUNNEL_TUNNEL", &self.tunnel);
+        for (k, v) in env {
+            cmd.env(k, v);
+        }
+        cmd.stdin(std::process::Stdio::null());
+
+        let tunnel = self.tunnel.clone();
+        match cmd.spawn() {
+            Ok(mut child) => {
+                tokio::spawn(async move {
+                    match child.wait().await {
+                        Ok(status) if status.success() => debug!("[{}] on_{} hook finished", tunnel, event.name()),
+                        Ok(status) => warn!("[{}] on_{} hook exited with {}", tunnel, event.name(), status),
+                        Err(e) => warn!("[{}] on_{} hook failed: {}", tunnel, event.name(), e),
+                    }
+                });
+            }
+            Err(e) => warn!("[{}] Could not run on_{} hook: {}", tunnel, event.name(), e),
+        }
+    }
+}
+
+impl RequestHook {
+    fn matches(&self, method: &str, path: &str, status: u16) -> bool {
+        self.min_status.is_none_or(|min| status >= min)
+            && (self.rules.is_empty() || self.rules.iter().any(|r| r.matches(method, path)))
+    }
+}
+
+fn bool_env(value: bool) -> String {
+    if value { "1" } else { "0" }.to_string()
+}
+
+#[cfg(unix)]
+fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(command);
+    cmd
+}
+
+#[cfg(not(unix))]
+fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("cmd");
+    cmd.arg("/C").arg(command);
+    cmd
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RequestHookConfig;
+
+    #[test]
+    fn test_request_hook_filter() {
+        let conf = HooksConfig {
+            on_request: Some(RequestHookConfig {
+                run: "true".to_string(),
+                rules: vec!["POST /webhooks/**".to_string()],
+                min_status: Some(500),
+            }),
+            ..Default::default()
+        };
+        let hooks = Hooks::from_config("web", &conf).unwrap();
+        let hook = hooks.on_request.as_ref().unwrap();
+        assert!(hook.matches("POST", "/webhooks/stripe?x=1", 502));
+        assert!(!hook.matches("POST", "/webhooks/stripe", 200));
+        assert!(!hook.matches("GET", "/webhooks/stripe", 500));
+        assert!(Hooks::from_config("web", &HooksConfig::default()).is_none());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_connect_hook_env() {
+        let out = std::env::temp_dir().join(format!("ztunnel-hook-test-{}", std::process::id()));
+        let conf = HooksConfig {
+            on_connect: Some(format!(
+                "echo \"$ZTUNNEL_EVENT $ZTUNNEL_TUNNEL $ZTUNNEL_URL $ZTUNNEL_URL_CHANGED\" > {}",
+                out.display()
+            )),
+            ..Default::default()
+        };
+        let hooks = Hooks::from_config("web", &conf).unwrap();
+        hooks.connected("https://b.example.com", "b", "ws://relay", Some("https://a.example.com"));
+
+        let mut written = String::new();
+        for _ in 0..50 {
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+            written = std::fs::read_to_string(&out).unwrap_or_default();
+            if !written.is_empty() {
+                break;
+            }
+        }
+        let _ = std::fs::remove_file(&out);
+        assert_eq!(written.trim(), "connect web https://b.example.com 1");
+    }
+}
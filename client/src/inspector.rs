@@ -3,15 +3,28 @@
 //! Provides a local web UI showing real-time request/response logs
 //! with replay capability via Server-Sent Events (SSE).
 
+use crate::body::Body;
+use crate::cache::CacheStatus;
+use crate::curl::CurlTarget;
+use crate::decode;
+use crate::frames::FrameLog;
+use crate::history::History;
+use crate::intercept::{Interceptor, Verdict};
+use crate::multi::RestartRequest;
+use crate::pause::Pauses;
+use crate::proxy::{FixedResponse, LocalTarget};
+use crate::replay::{ReplayOverrides, ReplayRequest, ReplayTargets};
+use crate::webhook::SignatureCheck;
 use axum::{
-    extract::State as AxumState,
+    extract::{ConnectInfo, Query, State as AxumState},
     http::StatusCode,
     response::{Html, IntoResponse, Sse},
     routing::{get, post},
     Router,
 };
 use axum::response::sse::{Event, KeepAlive};
-use futures_util::stream::Stream;
+use chrono::{DateTime, Utc};
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 use std::collections::VecDeque;
 use std::convert::Infallible;
@@ -19,76 +32,434 @@ use std::sync::Arc;
 use tokio::sync::{broadcast, Mutex};
 use tracing::{info, warn};
 
-/// Max entries kept in the ring buffer
-const MAX_ENTRIES: usize = 500;
+
+/// Body bytes included per entry in listings and live events; the rest
+/// is at `/api/entries/{id}/body`
+const PREVIEW_BYTES: usize = 16 * 1024;
+
+/// What an entry records
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    #[default]
+    Http,
+    /// A WebSocket handshake; its frames are at `/api/entries/{id}/frames`
+    WebSocket,
+}
 
 /// An inspector entry representing a single request/response pair
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct InspectorEntry {
     pub id: String,
+    /// Name of the tunnel that carried the request
+    #[serde(default)]
+    pub tunnel: String,
+    #[serde(default)]
+    pub kind: EntryKind,
     pub timestamp: String,
     pub method: String,
     pub path: String,
     pub status: u16,
     pub latency_ms: u64,
     pub req_headers: Vec<(String, String)>,
-    pub req_body: Option<String>,
+    pub req_body: Option<Body>,
     pub res_headers: Vec<(String, String)>,
-    pub res_body: Option<String>,
+    pub res_body: Option<Body>,
     pub res_body_size: usize,
+    /// Content-Encoding `res_body` was decoded from for display
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub res_encoding: Option<String>,
+    /// ID of the entry this one replayed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub replay_of: Option<String>,
+    /// Webhook signature check, when the tunnel verifies them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<SignatureCheck>,
+    /// Whether the tunnel's cache answered, for cacheable requests
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache: Option<CacheStatus>,
+    /// Slower than the tunnel's `slow_threshold_ms`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub slow: bool,
+}
+
+impl InspectorEntry {
+    /// Rough heap footprint, for the memory budget
+    pub fn approx_size(&self) -> usize {
+        let headers = |h: &[(String, String)]| h.iter().map(|(k, v)| k.len() + v.len()).sum::<usize>();
+        let body = |b: &Option<Body>| b.as_ref().map_or(0, |b| b.bytes().len());
+        std::mem::size_of::<Self>()
+            + self.id.len()
+            + self.tunnel.len()
+            + self.timestamp.len()
+            + self.method.len()
+            + self.path.len()
+            + headers(&self.req_headers)
+            + headers(&self.res_headers)
+            + body(&self.req_body)
+            + body(&self.res_body)
+    }
+
+    /// Copy with bodies cut to the listing preview size
+    pub fn preview(&self) -> Self {
+        Self {
+            req_body: self.req_body.as_ref().map(|b| b.clipped(PREVIEW_BYTES)),
+            res_body: self.res_body.as_ref().map(|b| b.clipped(PREVIEW_BYTES)),
+            ..self.clone()
+        }
+    }
+}
+
+/// Recent entries, newest first, bounded by count and total size. The
+/// newest entry is always kept, even if it alone exceeds the budget.
+pub struct Ring {
+    /// Shared with live-event subscribers rather than copied
+    items: VecDeque<Arc<InspectorEntry>>,
+    /// Sum of `approx_size` over `items`
+    bytes: usize,
+    capacity: usize,
+    /// Memory budget in bytes (0 = unlimited)
+    budget: usize,
+    /// Entries dropped to stay within the limits
+    evicted: u64,
+}
+
+impl Ring {
+    pub fn new(capacity: usize, budget: usize) -> Self {
+        Self { items: VecDeque::new(), bytes: 0, capacity: capacity.max(1), budget, evicted: 0 }
+    }
+
+    /// Add the newest entry, evicting the oldest to make room
+    pub fn push_front(&mut self, entry: impl Into<Arc<InspectorEntry>>) {
+        let entry = entry.into();
+        self.bytes += entry.approx_size();
+        self.items.push_front(entry);
+        while self.items.len() > 1 && (self.items.len() > self.capacity || self.over_budget()) {
+            if let Some(old) = self.items.pop_back() {
+                self.bytes -= old.approx_size();
+                self.evicted += 1;
+            }
+        }
+    }
+
+    /// Add an older entry, if it fits; returns false once full
+    fn push_back(&mut self, entry: InspectorEntry) -> bool {
+        let size = entry.approx_size();
+        if self.items.len() >= self.capacity || (self.budget > 0 && self.bytes + size > self.budget) {
+            return false;
+        }
+        self.bytes += size;
+        self.items.push_back(Arc::new(entry));
+        true
+    }
+
+    fn over_budget(&self) -> bool {
+        self.budget > 0 && self.bytes > self.budget
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &InspectorEntry> {
+        self.items.iter().map(|e| &**e)
+    }
+
+    pub fn retain(&mut self, mut keep: impl FnMut(&InspectorEntry) -> bool) {
+        let mut bytes = self.bytes;
+        self.items.retain(|e| {
+            let kept = keep(e);
+            if !kept {
+                bytes -= e.approx_size();
+            }
+            kept
+        });
+        self.bytes = bytes;
+    }
+
+    pub fn clear(&mut self) {
+        self.items.clear();
+        self.bytes = 0;
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn usage(&self) -> Usage {
+        Usage {
+            entries: self.items.len(),
+            capacity: self.capacity,
+            memory_bytes: self.bytes,
+            memory_budget_bytes: self.budget,
+            evicted: self.evicted,
+        }
+    }
+}
+
+/// How full the ring buffer is, reported by `/api/stats`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Usage {
+    pub entries: usize,
+    pub capacity: usize,
+    pub memory_bytes: usize,
+    /// 0 = unlimited
+    pub memory_budget_bytes: usize,
+    pub evicted: u64,
 }
 
 /// Shared inspector state
 #[derive(Clone)]
 pub struct InspectorState {
     /// Ring buffer of recent entries
-    entries: Arc<Mutex<VecDeque<InspectorEntry>>>,
+    entries: Arc<Mutex<Ring>>,
     /// Broadcast channel for SSE
-    tx: broadcast::Sender<InspectorEntry>,
-    /// Replay callback: sends a request ID to replay
-    replay_tx: tokio::sync::mpsc::Sender<String>,
+    tx: broadcast::Sender<Arc<InspectorEntry>>,
+    /// Replay requests, answered by `replay::serve`
+    replay_tx: tokio::sync::mpsc::Sender<ReplayRequest>,
+    /// Persistent store behind the ring buffer
+    history: Option<History>,
+    /// Requests held for approval
+    interceptor: Interceptor,
+    /// Frames of captured WebSocket connections
+    frames: FrameLog,
+    /// Bodies are cut at this many bytes when recorded
+    max_body_bytes: usize,
+    /// Where each tunnel's requests are replayed
+    replay_targets: ReplayTargets,
+    /// Pause switches of the tunnels
+    pauses: Pauses,
+    /// Tunnel restarts, answered by `multi::serve_restarts`; multi mode only
+    restart_tx: Option<tokio::sync::mpsc::Sender<RestartRequest>>,
 }
 
 impl InspectorState {
-    pub fn new(replay_tx: tokio::sync::mpsc::Sender<String>) -> Self {
+    pub fn new(replay_tx: tokio::sync::mpsc::Sender<ReplayRequest>) -> Self {
         let (tx, _) = broadcast::channel(256);
         Self {
-            entries: Arc::new(Mutex::new(VecDeque::with_capacity(MAX_ENTRIES))),
+            entries: Arc::new(Mutex::new(Ring::new(
+                crate::config::DEFAULT_MAX_ENTRIES,
+                crate::config::DEFAULT_MAX_MEMORY_MB * 1024 * 1024,
+            ))),
             tx,
             replay_tx,
+            history: None,
+            interceptor: Interceptor::default(),
+            frames: FrameLog::default(),
+            max_body_bytes: crate::config::DEFAULT_MAX_BODY_BYTES,
+            replay_targets: ReplayTargets::default(),
+            pauses: Pauses::default(),
+            restart_tx: None,
         }
     }
 
+    /// Cut recorded bodies at `limit` bytes
+    pub fn with_body_limit(mut self, limit: usize) -> Self {
+        self.max_body_bytes = limit;
+        self
+    }
+
+    /// Keep at most `capacity` entries using about `memory_mb` MiB
+    /// (0 = unlimited) in memory. Call before `with_history`.
+    pub fn with_capacity(mut self, capacity: usize, memory_mb: usize) -> Self {
+        self.entries = Arc::new(Mutex::new(Ring::new(capacity, memory_mb * 1024 * 1024)));
+        self
+    }
+
+    /// Allow restarting tunnels through `/api/tunnels/{name}/restart`
+    pub fn with_restart(mut self, restart_tx: tokio::sync::mpsc::Sender<RestartRequest>) -> Self {
+        self.restart_tx = Some(restart_tx);
+        self
+    }
+
+    /// Where tunnels hold intercepted requests
+    pub fn interceptor(&self) -> Interceptor {
+        self.interceptor.clone()
+    }
+
+    /// Where tunnels register their replay targets
+    pub fn replay_targets(&self) -> ReplayTargets {
+        self.replay_targets.clone()
+    }
+
+    /// Where tunnels register their pause switches
+    pub fn pauses(&self) -> Pauses {
+        self.pauses.clone()
+    }
+
+    /// Where tunnels record WebSocket frames
+    pub fn frames(&self) -> FrameLog {
+        self.frames.clone()
+    }
+
+    /// Persist entries to `history`, warming the ring buffer from it
+    pub async fn with_history(mut self, history: History) -> Self {
+        let capacity = self.entries.lock().await.capacity;
+        match history.recent(capacity).await {
+            Ok(recent) => {
+                let mut ring = self.entries.lock().await;
+                for entry in recent {
+                    if !ring.push_back(entry) {
+                        break;
+                    }
+                }
+            }
+            Err(e) => warn!("Could not load inspector history: {}", e),
+        }
+        self.history = Some(history);
+        self
+    }
+
     /// Record a new request/response pair
-    pub async fn record(&self, entry: InspectorEntry) {
+    pub async fn record(&self, mut entry: InspectorEntry) {
+        // Imported entries may have been decoded already
+        if entry.res_encoding.is_none() {
+            let decoded = entry.res_body.as_ref().and_then(|b| decode::decode(&entry.res_headers, b, self.max_body_bytes));
+            if let Some((body, encoding)) = decoded {
+                entry.res_body = Some(body);
+                entry.res_encoding = Some(encoding);
+            }
+        }
+        for body in [&mut entry.req_body, &mut entry.res_body].into_iter().flatten() {
+            body.truncate(self.max_body_bytes);
+        }
+        let entry = Arc::new(entry);
         {
-            let mut entries = self.entries.lock().await;
-            if entries.len() >= MAX_ENTRIES {
-                entries.pop_back();
+            self.entries.lock().await.push_front(entry.clone());
+        }
+        if let Some(history) = &self.history {
+            if let Err(e) = history.insert(&entry).await {
+                warn!("Could not save request to history: {}", e);
             }
-            entries.push_front(entry.clone());
         }
         // Broadcast to all SSE listeners (ignore if no receivers)
         let _ = self.tx.send(entry);
     }
 
-    /// Get an entry by ID for replay
+    /// Record exported entries (newest first), skipping IDs already
+    /// present. Returns how many were added.
+    pub async fn import(&self, entries: Vec<InspectorEntry>) -> usize {
+        let mut imported = 0;
+        for entry in entries.into_iter().rev() {
+            if self.get_entry(&entry.id).await.is_none() {
+                self.record(entry).await;
+                imported += 1;
+            }
+        }
+        imported
+    }
+
+    /// Delete one entry, from memory and history. False if neither had it.
+    pub async fn remove(&self, id: &str) -> Result<bool, String> {
+        let removed = {
+            let mut entries = self.entries.lock().await;
+            let before = entries.len();
+            entries.retain(|e| e.id != id);
+            entries.len() < before
+        };
+        self.frames.remove(id);
+        let stored = match &self.history {
+            Some(history) => history.delete(id).await.map_err(|e| e.to_string())?,
+            None => false,
+        };
+        Ok(removed || stored)
+    }
+
+    /// Delete every entry, or only one tunnel's. Returns how many went.
+    pub async fn clear(&self, tunnel: Option<&str>) -> Result<usize, String> {
+        let removed = {
+            let mut entries = self.entries.lock().await;
+            let before = entries.len();
+            match tunnel {
+                Some(tunnel) => entries.retain(|e| {
+                    let keep = e.tunnel != tunnel;
+                    if !keep {
+                        self.frames.remove(&e.id);
+                    }
+                    keep
+                }),
+                None => {
+                    entries.clear();
+                    self.frames.clear();
+                }
+            }
+            before - entries.len()
+        };
+        match &self.history {
+            // The history is a superset of the ring buffer
+            Some(history) => history.clear(tunnel).await.map_err(|e| e.to_string()),
+            None => Ok(removed),
+        }
+    }
+
+    /// Get an entry by ID for replay, falling back to the history
     pub async fn get_entry(&self, id: &str) -> Option<InspectorEntry> {
-        let entries = self.entries.lock().await;
-        entries.iter().find(|e| e.id == id).cloned()
+        let cached = self.entries.lock().await.iter().find(|e| e.id == id).cloned();
+        match (cached, &self.history) {
+            (Some(entry), _) => Some(entry),
+            (None, Some(history)) => history.get(id).await.unwrap_or_else(|e| {
+                warn!("History lookup failed: {}", e);
+                None
+            }),
+            (None, None) => None,
+        }
     }
 }
 
-/// Start the inspector HTTP server on the given port
-pub async fn start_inspector(state: InspectorState, port: u16) {
-    let app = Router::new()
+/// The inspector's routes; `/openapi.json` describes the API ones
+pub fn router(state: InspectorState) -> Router {
+    Router::new()
         .route("/", get(dashboard_handler))
+        .route("/openapi.json", get(openapi_handler))
         .route("/events", get(sse_handler))
-        .route("/replay/{id}", post(replay_handler))
-        .route("/api/entries", get(entries_handler))
-        .with_state(state);
+        .route("/replay/:id", post(replay_handler))
+        .route("/api/entries", get(entries_handler).delete(clear_handler))
+        .route("/api/entries/:id", axum::routing::delete(delete_handler))
+        .route("/api/entries/:id/body", get(body_handler))
+        .route("/api/entries/:id/curl", get(curl_handler))
+        .route("/api/export", get(export_handler))
+        .route("/api/import", post(import_handler).layer(axum::extract::DefaultBodyLimit::disable()))
+        .route("/api/diff", get(diff_handler))
+        .route("/api/stats", get(stats_handler))
+        .route("/api/timeline", get(timeline_handler))
+        .route("/api/entries/:id/frames", get(frames_handler))
+        .route("/api/tunnels/:name/restart", post(restart_handler))
+        .route("/api/tunnels/:name/pause", post(pause_handler))
+        .route("/api/tunnels/:name/resume", post(resume_handler))
+        .route("/api/intercepts", get(intercepts_handler))
+        .route("/api/intercepts/:id/approve", post(approve_handler))
+        .route("/api/intercepts/:id/reject", post(reject_handler))
+        .with_state(state)
+}
 
-    let addr = std::net::SocketAddr::from(([127, 0, 0, 1], port));
+/// Start the inspector HTTP server on the given port
+pub async fn start_inspector(state: InspectorState, port: u16) {
+    serve_inspector(state, std::net::SocketAddr::from(([127, 0, 0, 1], port))).await
+}
+
+/// The inspector for other machines to watch: they can read it, but
+/// replays, intercept decisions and every other change stay local
+pub fn lan_router(state: InspectorState) -> Router {
+    router(state).layer(axum::middleware::from_fn(local_writes_only))
+}
+
+async fn local_writes_only(
+    ConnectInfo(peer): ConnectInfo<std::net::SocketAddr>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let read = matches!(*request.method(), axum::http::Method::GET | axum::http::Method::HEAD);
+    if read || peer.ip().is_loopback() {
+        return next.run(request).await;
+    }
+    (StatusCode::FORBIDDEN, "The inspector is read-only from other machines").into_response()
+}
+
+/// Start the inspector HTTP server on `addr`; anything but loopback
+/// lets others on the network see captured requests
+pub async fn serve_inspector(state: InspectorState, addr: std::net::SocketAddr) {
+    let port = addr.port();
+    let app = if addr.ip().is_loopback() { router(state) } else { lan_router(state) };
     info!("Inspector dashboard: http://localhost:{}", port);
 
     let listener = match tokio::net::TcpListener::bind(addr).await {
@@ -99,7 +470,7 @@ pub async fn start_inspector(state: InspectorState, port: u16) {
         }
     };
 
-    if let Err(e) = axum::serve(listener, app).await {
+    if let Err(e) = axum::serve(listener, app.into_make_service_with_connect_info::<std::net::SocketAddr>()).await {
         warn!("Inspector server error: {}", e);
     }
 }
@@ -109,18 +480,32 @@ async fn dashboard_handler() -> impl IntoResponse {
     Html(include_str!("../assets/inspector.html"))
 }
 
-/// SSE endpoint for real-time request streaming
+/// The API's OpenAPI document, kept in step with `router` by hand
+pub const OPENAPI: &str = include_str!("../assets/openapi.json");
+
+async fn openapi_handler() -> impl IntoResponse {
+    ([("content-type", "application/json")], OPENAPI)
+}
+
+/// SSE endpoint for real-time request streaming, filtered like
+/// `/api/entries` (`limit` and `offset` are ignored)
 async fn sse_handler(
     AxumState(state): AxumState<InspectorState>,
-) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    Query(query): Query<EntryQuery>,
+) -> axum::response::Response {
+    let filter = match EntryFilter::from_query(&query) {
+        Ok(f) => f,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
     let mut rx = state.tx.subscribe();
 
     let stream = async_stream::stream! {
         loop {
             match rx.recv().await {
+                Ok(entry) if !filter.matches(&entry) => {}
                 Ok(entry) => {
-                    if let Ok(json) = serde_json::to_string(&entry) {
-                        yield Ok(Event::default().data(json));
+                    if let Ok(json) = serde_json::to_string(&entry.preview()) {
+                        yield Ok::<_, Infallible>(Event::default().data(json));
                     }
                 }
                 Err(broadcast::error::RecvError::Lagged(n)) => {
@@ -131,29 +516,670 @@ async fn sse_handler(
         }
     };
 
-    Sse::new(stream).keep_alive(KeepAlive::default())
+    Sse::new(stream).keep_alive(KeepAlive::default()).into_response()
 }
 
-/// Replay a previously recorded request
+/// Query for `/replay/{id}`
+#[derive(Debug, Default, Deserialize)]
+struct ReplayQuery {
+    /// `PORT`, `HOST:PORT`, or `unix:PATH` to send the replay to
+    target: Option<String>,
+}
+
+/// Replay a recorded request, optionally modified by a JSON body of
+/// `ReplayOverrides`. Responds with the new entry's ID.
 async fn replay_handler(
     AxumState(state): AxumState<InspectorState>,
     axum::extract::Path(id): axum::extract::Path<String>,
-) -> impl IntoResponse {
-    if let Some(_entry) = state.get_entry(&id).await {
-        match state.replay_tx.send(id).await {
-            Ok(_) => (StatusCode::OK, "Replaying request"),
-            Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Replay channel closed"),
+    Query(query): Query<ReplayQuery>,
+    body: axum::body::Bytes,
+) -> axum::response::Response {
+    if state.get_entry(&id).await.is_none() {
+        return (StatusCode::NOT_FOUND, "Request not found").into_response();
+    }
+    let target = match query.target.as_deref().map(|spec| (spec, LocalTarget::parse(spec))) {
+        None => None,
+        Some((_, Some(target))) => Some(target),
+        Some((spec, None)) => {
+            return (StatusCode::BAD_REQUEST, format!("invalid target '{}', expected PORT, HOST:PORT, or unix:PATH", spec))
+                .into_response()
+        }
+    };
+    let overrides: ReplayOverrides = match json_or_default(&body) {
+        Ok(o) => o,
+        Err(e) => return (StatusCode::BAD_REQUEST, format!("Invalid replay body: {}", e)).into_response(),
+    };
+
+    let (reply, rx) = tokio::sync::oneshot::channel();
+    if state.replay_tx.send(ReplayRequest { id, overrides, target, reply }).await.is_err() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "Replay channel closed").into_response();
+    }
+    match rx.await {
+        Ok(Ok(new_id)) => axum::Json(serde_json::json!({ "id": new_id })).into_response(),
+        Ok(Err(e)) => (StatusCode::BAD_GATEWAY, e).into_response(),
+        Err(_) => (StatusCode::SERVICE_UNAVAILABLE, "Replay channel closed").into_response(),
+    }
+}
+
+/// Query for `/api/entries/{id}/body`
+#[derive(Debug, Default, Deserialize)]
+struct BodyQuery {
+    /// `request` or `response` (the default)
+    part: Option<String>,
+}
+
+/// One body of an entry, as captured, with its original content type
+async fn body_handler(
+    AxumState(state): AxumState<InspectorState>,
+    axum::extract::Path(id): axum::extract::Path<String>,
+    Query(query): Query<BodyQuery>,
+) -> axum::response::Response {
+    let Some(entry) = state.get_entry(&id).await else {
+        return (StatusCode::NOT_FOUND, "Request not found").into_response();
+    };
+    let (body, headers) = match query.part.as_deref() {
+        None | Some("response") => (entry.res_body, entry.res_headers),
+        Some("request") => (entry.req_body, entry.req_headers),
+        Some(other) => {
+            return (StatusCode::BAD_REQUEST, format!("invalid part '{}', expected request or response", other))
+                .into_response()
         }
+    };
+    let body = body.unwrap_or_default();
+    let content_type = headers
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case("content-type"))
+        .map(|(_, v)| v.clone())
+        .unwrap_or_else(|| "application/octet-stream".to_string());
+    (
+        [
+            ("content-type", content_type),
+            ("x-body-size", body.size().to_string()),
+            ("x-body-truncated", body.truncated().to_string()),
+        ],
+        body.bytes().to_vec(),
+    )
+        .into_response()
+}
+
+/// Query for `/api/entries/{id}/curl`
+#[derive(Debug, Default, Deserialize)]
+struct CurlQuery {
+    /// `local` (the default) or `public`
+    target: Option<String>,
+}
+
+/// A curl command reproducing an entry's request
+async fn curl_handler(
+    AxumState(state): AxumState<InspectorState>,
+    axum::extract::Path(id): axum::extract::Path<String>,
+    Query(query): Query<CurlQuery>,
+) -> axum::response::Response {
+    let Some(entry) = state.get_entry(&id).await else {
+        return (StatusCode::NOT_FOUND, "Request not found").into_response();
+    };
+    let local = state.replay_targets.resolve(&entry);
+    let target = match query.target.as_deref() {
+        None | Some("local") => match &local {
+            Some(to) => CurlTarget::Local(to),
+            None => {
+                return (StatusCode::NOT_FOUND, format!("No local service for tunnel '{}'", entry.tunnel))
+                    .into_response()
+            }
+        },
+        Some("public") => CurlTarget::Public,
+        Some(other) => {
+            return (StatusCode::BAD_REQUEST, format!("invalid target '{}', expected local or public", other))
+                .into_response()
+        }
+    };
+    match crate::curl::command(&entry, target) {
+        Ok(cmd) => ([("content-type", "text/plain; charset=utf-8")], cmd + "\n").into_response(),
+        Err(e) => (StatusCode::UNPROCESSABLE_ENTITY, e).into_response(),
+    }
+}
+
+/// Query for `/api/diff`
+#[derive(Debug, Deserialize)]
+struct DiffQuery {
+    a: String,
+    b: String,
+}
+
+/// Structured diff of two entries' responses
+async fn diff_handler(
+    AxumState(state): AxumState<InspectorState>,
+    Query(query): Query<DiffQuery>,
+) -> axum::response::Response {
+    let (Some(a), Some(b)) = (state.get_entry(&query.a).await, state.get_entry(&query.b).await) else {
+        return (StatusCode::NOT_FOUND, "Request not found").into_response();
+    };
+    axum::Json(crate::diff::diff_entries(&a, &b)).into_response()
+}
+
+/// Frames of a WebSocket entry, oldest first
+async fn frames_handler(
+    AxumState(state): AxumState<InspectorState>,
+    axum::extract::Path(id): axum::extract::Path<String>,
+) -> axum::response::Response {
+    match state.frames.frames(&id) {
+        Some(frames) => axum::Json(frames).into_response(),
+        None => (StatusCode::NOT_FOUND, "No frames captured for this request").into_response(),
+    }
+}
+
+/// Reconnect one tunnel without touching the others
+async fn restart_handler(
+    AxumState(state): AxumState<InspectorState>,
+    axum::extract::Path(name): axum::extract::Path<String>,
+) -> axum::response::Response {
+    let Some(restart_tx) = &state.restart_tx else {
+        return (StatusCode::NOT_FOUND, "Tunnels can only be restarted in multi-tunnel mode").into_response();
+    };
+    let (reply, rx) = tokio::sync::oneshot::channel();
+    if restart_tx.send(RestartRequest { name, reply }).await.is_err() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "Restart channel closed").into_response();
+    }
+    match rx.await {
+        Ok(Ok(())) => StatusCode::NO_CONTENT.into_response(),
+        Ok(Err(e)) => (StatusCode::NOT_FOUND, e).into_response(),
+        Err(_) => (StatusCode::SERVICE_UNAVAILABLE, "Restart channel closed").into_response(),
+    }
+}
+
+/// Body of `/api/tunnels/{name}/pause`
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct PauseRequest {
+    /// Served instead of the tunnel's `pause_page`
+    #[serde(default)]
+    pub page: Option<String>,
+}
+
+/// Answer 503 on a tunnel until it's resumed
+async fn pause_handler(
+    AxumState(state): AxumState<InspectorState>,
+    axum::extract::Path(name): axum::extract::Path<String>,
+    body: axum::body::Bytes,
+) -> axum::response::Response {
+    let request: PauseRequest = match json_or_default(&body) {
+        Ok(request) => request,
+        Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    };
+    let Some(pause) = state.pauses.get(&name) else {
+        return (StatusCode::NOT_FOUND, format!("No tunnel named '{}'", name)).into_response();
+    };
+    pause.pause(request.page);
+    info!("Paused tunnel '{}'", name);
+    StatusCode::NO_CONTENT.into_response()
+}
+
+async fn resume_handler(
+    AxumState(state): AxumState<InspectorState>,
+    axum::extract::Path(name): axum::extract::Path<String>,
+) -> axum::response::Response {
+    let Some(pause) = state.pauses.get(&name) else {
+        return (StatusCode::NOT_FOUND, format!("No tunnel named '{}'", name)).into_response();
+    };
+    if pause.resume() {
+        info!("Resumed tunnel '{}'", name);
+    }
+    StatusCode::NO_CONTENT.into_response()
+}
+
+/// Optional JSON request body; empty means the default
+fn json_or_default<T: Default + serde::de::DeserializeOwned>(body: &[u8]) -> serde_json::Result<T> {
+    if body.iter().all(u8::is_ascii_whitespace) {
+        Ok(T::default())
     } else {
-        (StatusCode::NOT_FOUND, "Request not found")
+        serde_json::from_slice(body)
     }
 }
 
-/// Get all stored entries as JSON
+/// Requests currently held for approval, oldest first
+async fn intercepts_handler(AxumState(state): AxumState<InspectorState>) -> impl IntoResponse {
+    axum::Json(state.interceptor.list())
+}
+
+/// Forward a held request, with edits from an optional JSON body of
+/// `ReplayOverrides`
+async fn approve_handler(
+    AxumState(state): AxumState<InspectorState>,
+    axum::extract::Path(id): axum::extract::Path<String>,
+    body: axum::body::Bytes,
+) -> axum::response::Response {
+    let edits: ReplayOverrides = match json_or_default(&body) {
+        Ok(e) => e,
+        Err(e) => return (StatusCode::BAD_REQUEST, format!("Invalid approve body: {}", e)).into_response(),
+    };
+    decide(&state, &id, Verdict::Approve(edits))
+}
+
+/// Body of `/api/intercepts/:id/reject`
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RejectBody {
+    status: Option<u16>,
+    body: Option<String>,
+}
+
+/// Answer a held request without forwarding it; 403 unless the JSON
+/// body says otherwise
+async fn reject_handler(
+    AxumState(state): AxumState<InspectorState>,
+    axum::extract::Path(id): axum::extract::Path<String>,
+    body: axum::body::Bytes,
+) -> axum::response::Response {
+    let reject: RejectBody = match json_or_default(&body) {
+        Ok(r) => r,
+        Err(e) => return (StatusCode::BAD_REQUEST, format!("Invalid reject body: {}", e)).into_response(),
+    };
+    let status = reject.status.unwrap_or(403);
+    if !(100..=599).contains(&status) {
+        return (StatusCode::BAD_REQUEST, format!("Invalid status {}", status)).into_response();
+    }
+    let body = reject.body.unwrap_or_else(|| "Rejected by ztunnel intercept".to_string());
+    decide(&state, &id, Verdict::Reject(FixedResponse { status, body }))
+}
+
+fn decide(state: &InspectorState, id: &str, verdict: Verdict) -> axum::response::Response {
+    if state.interceptor.decide(id, verdict) {
+        StatusCode::OK.into_response()
+    } else {
+        (StatusCode::NOT_FOUND, "Request is not held").into_response()
+    }
+}
+
+/// Query parameters for `/api/entries`
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct EntryQuery {
+    /// Tunnel name, exact
+    pub tunnel: Option<String>,
+    /// Exact method, case-insensitive
+    pub method: Option<String>,
+    /// Exact code (`404`) or class (`5xx`)
+    pub status: Option<String>,
+    /// Substring of the path
+    pub path: Option<String>,
+    /// Regex matched against the path
+    pub path_regex: Option<String>,
+    /// RFC 3339 lower bound on the timestamp, inclusive
+    pub since: Option<String>,
+    /// RFC 3339 upper bound on the timestamp, inclusive
+    pub until: Option<String>,
+    pub min_latency_ms: Option<u64>,
+    /// Page size; all matches when unset
+    pub limit: Option<usize>,
+    /// Matches to skip, newest first
+    #[serde(default)]
+    pub offset: usize,
+}
+
+/// Status filter: one code, or a class like `4xx`
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum StatusFilter {
+    Exact(u16),
+    Class(u16),
+}
+
+/// Compiled form of `EntryQuery`
+#[derive(Debug, Default)]
+pub struct EntryFilter {
+    tunnel: Option<String>,
+    method: Option<String>,
+    status: Option<StatusFilter>,
+    path: Option<String>,
+    path_regex: Option<Regex>,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+    min_latency_ms: Option<u64>,
+}
+
+impl EntryFilter {
+    /// Validate and compile the query; the error names the bad parameter
+    pub fn from_query(query: &EntryQuery) -> Result<Self, String> {
+        let status = match query.status.as_deref() {
+            None => None,
+            Some(s) => Some(parse_status(s).ok_or_else(|| format!("invalid status '{}', expected e.g. 404 or 4xx", s))?),
+        };
+        let path_regex = match &query.path_regex {
+            None => None,
+            Some(re) => Some(Regex::new(re).map_err(|e| format!("invalid path_regex: {}", e))?),
+        };
+        Ok(Self {
+            tunnel: query.tunnel.clone(),
+            method: query.method.as_ref().map(|m| m.to_uppercase()),
+            status,
+            path: query.path.clone(),
+            path_regex,
+            since: parse_time("since", query.since.as_deref())?,
+            until: parse_time("until", query.until.as_deref())?,
+            min_latency_ms: query.min_latency_ms,
+        })
+    }
+
+    pub fn matches(&self, entry: &InspectorEntry) -> bool {
+        if self.tunnel.as_ref().is_some_and(|t| entry.tunnel != *t) {
+            return false;
+        }
+        if self.method.as_ref().is_some_and(|m| !entry.method.eq_ignore_ascii_case(m)) {
+            return false;
+        }
+        match self.status {
+            Some(StatusFilter::Exact(code)) if entry.status != code => return false,
+            Some(StatusFilter::Class(class)) if entry.status / 100 != class => return false,
+            _ => {}
+        }
+        if self.path.as_ref().is_some_and(|p| !entry.path.contains(p.as_str())) {
+            return false;
+        }
+        if self.path_regex.as_ref().is_some_and(|re| !re.is_match(&entry.path)) {
+            return false;
+        }
+        if self.min_latency_ms.is_some_and(|min| entry.latency_ms < min) {
+            return false;
+        }
+        if self.since.is_some() || self.until.is_some() {
+            let Ok(at) = DateTime::parse_from_rfc3339(&entry.timestamp) else {
+                return false;
+            };
+            let at = at.with_timezone(&Utc);
+            if self.since.is_some_and(|since| at < since) || self.until.is_some_and(|until| at > until) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+fn parse_status(s: &str) -> Option<StatusFilter> {
+    let s = s.to_ascii_lowercase();
+    match s.strip_suffix("xx") {
+        Some(class) => match class.parse::<u16>() {
+            Ok(c @ 1..=5) => Some(StatusFilter::Class(c)),
+            _ => None,
+        },
+        None => match s.parse::<u16>() {
+            Ok(code @ 100..=599) => Some(StatusFilter::Exact(code)),
+            _ => None,
+        },
+    }
+}
+
+fn parse_time(name: &str, value: Option<&str>) -> Result<Option<DateTime<Utc>>, String> {
+    value
+        .map(|v| {
+            DateTime::parse_from_rfc3339(v)
+                .map(|t| t.with_timezone(&Utc))
+                .map_err(|e| format!("invalid {} '{}': {}", name, v, e))
+        })
+        .transpose()
+}
+
+/// Stored entries as JSON, newest first, filtered and paginated by the
+/// query string. The total number of matches is in `X-Total-Count`.
+/// With persistent history the whole history is searched.
 async fn entries_handler(
     AxumState(state): AxumState<InspectorState>,
-) -> impl IntoResponse {
-    let entries = state.entries.lock().await;
-    let vec: Vec<InspectorEntry> = entries.iter().cloned().collect();
-    axum::Json(vec)
+    Query(query): Query<EntryQuery>,
+) -> axum::response::Response {
+    let filter = match EntryFilter::from_query(&query) {
+        Ok(f) => f,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
+
+    let (total, page) = match &state.history {
+        Some(history) => match history.query(filter, query.offset, query.limit).await {
+            Ok(result) => result,
+            Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+        },
+        None => {
+            let entries = state.entries.lock().await;
+            let matched: Vec<&InspectorEntry> = entries.iter().filter(|e| filter.matches(e)).collect();
+            let page = matched
+                .iter()
+                .skip(query.offset)
+                .take(query.limit.unwrap_or(usize::MAX))
+                .map(|e| (*e).clone())
+                .collect();
+            (matched.len(), page)
+        }
+    };
+    let page: Vec<_> = page.iter().map(InspectorEntry::preview).collect();
+
+    ([("x-total-count", total.to_string())], axum::Json(page)).into_response()
+}
+
+/// Query for `DELETE /api/entries`
+#[derive(Debug, Default, Deserialize)]
+struct ClearQuery {
+    /// Only this tunnel's entries
+    tunnel: Option<String>,
+}
+
+/// Delete every entry, or one tunnel's with `?tunnel=NAME`
+async fn clear_handler(
+    AxumState(state): AxumState<InspectorState>,
+    Query(query): Query<ClearQuery>,
+) -> axum::response::Response {
+    match state.clear(query.tunnel.as_deref()).await {
+        Ok(deleted) => {
+            info!("Cleared {} inspector entries", deleted);
+            axum::Json(serde_json::json!({ "deleted": deleted })).into_response()
+        }
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
+    }
+}
+
+async fn delete_handler(
+    AxumState(state): AxumState<InspectorState>,
+    axum::extract::Path(id): axum::extract::Path<String>,
+) -> axum::response::Response {
+    match state.remove(&id).await {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => (StatusCode::NOT_FOUND, "Request not found").into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
+    }
+}
+
+/// Every entry matching the `/api/entries` filters, with full bodies,
+/// as a downloadable session file; `limit` and `offset` are ignored
+async fn export_handler(
+    AxumState(state): AxumState<InspectorState>,
+    Query(query): Query<EntryQuery>,
+) -> axum::response::Response {
+    let filter = match EntryFilter::from_query(&query) {
+        Ok(f) => f,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
+    let entries = match &state.history {
+        Some(history) => match history.query(filter, 0, None).await {
+            Ok((_, entries)) => entries,
+            Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+        },
+        None => state.entries.lock().await.iter().filter(|e| filter.matches(e)).cloned().collect(),
+    };
+    let export = crate::export::SessionExport::new(entries);
+    let filename = format!("ztunnel-session-{}.json", Utc::now().format("%Y%m%d-%H%M%S"));
+    (
+        [(axum::http::header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", filename))],
+        axum::Json(export),
+    )
+        .into_response()
+}
+
+/// Load a session file from `/api/export`
+async fn import_handler(
+    AxumState(state): AxumState<InspectorState>,
+    body: axum::body::Bytes,
+) -> axum::response::Response {
+    let export = match crate::export::SessionExport::parse(&body) {
+        Ok(export) => export,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
+    let total = export.entries.len();
+    let imported = state.import(export.entries).await;
+    info!("Imported {} inspector entries", imported);
+    axum::Json(serde_json::json!({ "imported": imported, "skipped": total - imported })).into_response()
+}
+
+/// Aggregates over every entry matching the `/api/entries` filters,
+/// plus the ring buffer's usage; `limit` and `offset` are ignored
+async fn stats_handler(
+    AxumState(state): AxumState<InspectorState>,
+    Query(query): Query<EntryQuery>,
+) -> axum::response::Response {
+    let filter = match EntryFilter::from_query(&query) {
+        Ok(f) => f,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
+    let mut stats = match &state.history {
+        Some(history) => match history.query(filter, 0, None).await {
+            Ok((_, entries)) => crate::stats::compute(&entries),
+            Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+        },
+        None => crate::stats::compute(state.entries.lock().await.iter().filter(|e| filter.matches(e))),
+    };
+    stats.usage = Some(state.entries.lock().await.usage());
+    axum::Json(stats).into_response()
+}
+
+/// Per-minute traffic over every entry matching the `/api/entries`
+/// filters; `limit` and `offset` are ignored
+async fn timeline_handler(
+    AxumState(state): AxumState<InspectorState>,
+    Query(query): Query<EntryQuery>,
+) -> axum::response::Response {
+    let filter = match EntryFilter::from_query(&query) {
+        Ok(f) => f,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
+    let timeline = match &state.history {
+        Some(history) => match history.query(filter, 0, None).await {
+            Ok((_, entries)) => crate::stats::timeline(&entries),
+            Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+        },
+        None => crate::stats::timeline(state.entries.lock().await.iter().filter(|e| filter.matches(e))),
+    };
+    axum::Json(timeline).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(method: &str, path: &str, status: u16, latency_ms: u64, timestamp: &str) -> InspectorEntry {
+        InspectorEntry {
+            id: format!("{} {}", method, path),
+            timestamp: timestamp.to_string(),
+            method: method.to_string(),
+            path: path.to_string(),
+            status,
+            latency_ms,
+            ..Default::default()
+        }
+    }
+
+    fn filter(query: &str) -> Result<EntryFilter, String> {
+        let uri = format!("/api/entries?{}", query).parse().unwrap();
+        let Query(query) = Query::<EntryQuery>::try_from_uri(&uri).unwrap();
+        EntryFilter::from_query(&query)
+    }
+
+    #[test]
+    fn test_entry_filter() {
+        let slow_error = entry("POST", "/api/orders/42", 503, 900, "2024-05-01T10:00:00Z");
+        let fast_ok = entry("GET", "/health", 200, 3, "2024-05-01T12:00:00Z");
+
+        let f = filter("method=post&status=5xx").unwrap();
+        assert!(f.matches(&slow_error) && !f.matches(&fast_ok));
+
+        let f = filter("status=200&path=heal").unwrap();
+        assert!(!f.matches(&slow_error) && f.matches(&fast_ok));
+
+        let f = filter("path_regex=%5E/api/orders/%5Cd%2B%24&min_latency_ms=500").unwrap();
+        assert!(f.matches(&slow_error) && !f.matches(&fast_ok));
+
+        let f = filter("since=2024-05-01T11:00:00Z&until=2024-05-01T12:00:00Z").unwrap();
+        assert!(!f.matches(&slow_error) && f.matches(&fast_ok));
+
+        let tagged = InspectorEntry { tunnel: "api".to_string(), ..fast_ok.clone() };
+        let f = filter("tunnel=api").unwrap();
+        assert!(f.matches(&tagged) && !f.matches(&fast_ok));
+
+        assert!(filter("").unwrap().matches(&fast_ok));
+        assert!(filter("status=6xx").unwrap_err().contains("status"));
+        assert!(filter("path_regex=(").unwrap_err().contains("path_regex"));
+        assert!(filter("since=yesterday").unwrap_err().contains("since"));
+    }
+
+    #[tokio::test]
+    async fn test_clear_and_remove() {
+        let state = InspectorState::new(tokio::sync::mpsc::channel(1).0);
+        for (path, tunnel) in [("/a", "web"), ("/b", "api"), ("/c", "web")] {
+            let e = InspectorEntry { tunnel: tunnel.to_string(), ..entry("GET", path, 200, 1, "2024-05-01T10:00:00Z") };
+            state.record(e).await;
+        }
+
+        assert_eq!(state.remove("GET /b").await, Ok(true));
+        assert_eq!(state.remove("GET /b").await, Ok(false));
+        assert_eq!(state.clear(Some("web")).await, Ok(2));
+        assert_eq!(state.entries.lock().await.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_lan_inspector_is_read_only() {
+        let state = InspectorState::new(tokio::sync::mpsc::channel(1).0);
+        state.record(entry("GET", "/a", 200, 1, "2024-05-01T10:00:00Z")).await;
+        let serve = |peer: [u8; 4]| {
+            let app = lan_router(state.clone())
+                .layer(axum::extract::connect_info::MockConnectInfo(std::net::SocketAddr::from((peer, 50000))));
+            async move {
+                let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+                let url = format!("http://{}", listener.local_addr().unwrap());
+                tokio::spawn(async move { axum::serve(listener, app).await });
+                url
+            }
+        };
+        let client = reqwest::Client::new();
+
+        let lan = serve([192, 168, 1, 20]).await;
+        let status = |r: reqwest::Response| r.status().as_u16();
+        assert_eq!(status(client.get(format!("{}/api/entries", lan)).send().await.unwrap()), 200);
+        for path in ["/replay/GET%20%2Fa", "/api/tunnels/web/pause", "/api/intercepts/1/approve"] {
+            assert_eq!(status(client.post(format!("{}{}", lan, path)).send().await.unwrap()), 403, "{}", path);
+        }
+        assert_eq!(status(client.delete(format!("{}/api/entries", lan)).send().await.unwrap()), 403);
+        assert_eq!(state.entries.lock().await.len(), 1);
+
+        // This machine keeps full control
+        let local = serve([127, 0, 0, 1]).await;
+        assert_eq!(status(client.delete(format!("{}/api/entries", local)).send().await.unwrap()), 200);
+        assert_eq!(state.entries.lock().await.len(), 0);
+    }
+
+    #[test]
+    fn test_ring_evicts_oldest() {
+        let sized = |path: &str, body: usize| InspectorEntry {
+            res_body: Some(Body::new(vec![b'x'; body])),
+            ..entry("GET", path, 200, 1, "2024-05-01T10:00:00Z")
+        };
+
+        let mut ring = Ring::new(2, 0);
+        for path in ["/a", "/b", "/c"] {
+            ring.push_front(sized(path, 0));
+        }
+        let paths: Vec<_> = ring.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!((paths, ring.usage().evicted), (vec!["/c", "/b"], 1));
+
+        let one = sized("/a", 1000).approx_size();
+        let mut ring = Ring::new(10, one * 2);
+        for path in ["/a", "/b", "/c"] {
+            ring.push_front(sized(path, 1000));
+        }
+        assert_eq!((ring.len(), ring.usage().memory_bytes), (2, one * 2));
+
+        // The newest entry stays even when it alone is over budget
+        ring.push_front(sized("/big", 10_000));
+        assert_eq!(ring.iter().map(|e| e.path.as_str()).collect::<Vec<_>>(), ["/big"]);
+    }
 }
@@ -0,0 +1,224 @@
+//! Request interception
+//!
+//! Requests matching a tunnel's `intercept` rules are held by the client
+//! and listed at `/api/intercepts` until someone approves (optionally
+//! with edits) or rejects them. The relay gives up on a request after
+//! 30 seconds, so undecided requests are rejected with 504 a little
+//! before that.
+
+use crate::proxy::FixedResponse;
+use crate::replay::ReplayOverrides;
+use crate::tunnel::TunnelRequest;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::oneshot;
+use ztunnel_shared::glob::matches_glob;
+
+/// How long a request may wait for a decision
+const HOLD_TIMEOUT: Duration = Duration::from_secs(25);
+
+/// One intercept rule: `[METHOD] PATH_GLOB`, e.g. `POST /webhooks/**`
+#[derive(Debug, Clone, PartialEq)]
+pub struct InterceptRule {
+    /// Uppercase method, or any when unset
+    method: Option<String>,
+    path: String,
+}
+
+impl InterceptRule {
+    pub fn parse(spec: &str) -> Option<Self> {
+        let mut parts = spec.split_whitespace();
+        let (method, path) = match (parts.next()?, parts.next(), parts.next()) {
+            (path, None, _) => (None, path),
+            (method, Some(path), None) => (Some(method.to_uppercase()), path),
+            _ => return None,
+        };
+        if !path.starts_with('/') || method.as_ref().is_some_and(|m| !m.chars().all(|c| c.is_ascii_alphabetic())) {
+            return None;
+        }
+        Some(Self { method, path: path.to_string() })
+    }
+
+    pub fn matches(&self, method: &str, path: &str) -> bool {
+        let path = path.split(['?', '#']).next().unwrap_or(path);
+        self.method.as_ref().is_none_or(|m| m.eq_ignore_ascii_case(method)) && matches_glob(&self.path, path)
+    }
+}
+
+/// A request waiting for a decision
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HeldRequest {
+    pub id: String,
+    pub tunnel: String,
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Option<String>,
+    pub held_at: String,
+}
+
+impl HeldRequest {
+    pub fn new(tunnel: &str, request: &TunnelRequest) -> Self {
+        Self {
+            id: request.id.clone(),
+            tunnel: tunnel.to_string(),
+            method: request.method.clone(),
+            path: request.path.clone(),
+            headers: request.headers.clone(),
+            body: request.body.as_ref().map(|b| String::from_utf8_lossy(b).to_string()),
+            held_at: chrono::Utc::now().to_rfc3339(),
+        }
+    }
+}
+
+/// What to do with a held request
+#[derive(Debug)]
+pub enum Verdict {
+    /// Forward it, with any edits applied
+    Approve(ReplayOverrides),
+    /// Answer it without contacting the local service
+    Reject(FixedResponse),
+}
+
+struct Pending {
+    held: HeldRequest,
+    decide: oneshot::Sender<Verdict>,
+}
+
+/// Held requests shared between the tunnels and the inspector API
+#[derive(Clone, Default)]
+pub struct Interceptor {
+    pending: Arc<Mutex<HashMap<String, Pending>>>,
+}
+
+impl Interceptor {
+    /// Hold a request until it is decided, or reject it on timeout
+    pub async fn hold(&self, held: HeldRequest) -> Verdict {
+        let (decide, decision) = oneshot::channel();
+        let id = held.id.clone();
+        self.lock().insert(id.clone(), Pending { held, decide });
+
+        let verdict = tokio::time::timeout(HOLD_TIMEOUT, decision).await;
+        self.lock().remove(&id);
+        match verdict {
+            Ok(Ok(verdict)) => verdict,
+            _ => Verdict::Reject(FixedResponse { status: 504, body: "No intercept decision in time".to_string() }),
+        }
+    }
+
+    /// Held requests, oldest first
+    pub fn list(&self) -> Vec<HeldRequest> {
+        let mut held: Vec<_> = self.lock().values().map(|p| p.held.clone()).collect();
+        held.sort_by(|a, b| a.held_at.cmp(&b.held_at));
+        held
+    }
+
+    /// Release a held request. Returns false if it isn't held (anymore).
+    pub fn decide(&self, id: &str, verdict: Verdict) -> bool {
+        match self.lock().remove(id) {
+            Some(pending) => pending.decide.send(verdict).is_ok(),
+            None => false,
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Pending>> {
+        self.pending.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Apply an approval's edits to a held request
+pub fn apply_edits(edits: ReplayOverrides, request: &mut TunnelRequest) {
+    if let Some(method) = edits.method {
+        request.method = method.to_uppercase();
+    }
+    if let Some(path) = edits.path {
+        request.path = path;
+    }
+    if let Some(headers) = edits.headers {
+        request.headers = headers;
+    }
+    if let Some(body) = edits.body {
+        request.body = Some(body.into());
+        // The new body goes out with a fresh Content-Length
+        request.headers.retain(|(k, _)| {
+            !k.eq_ignore_ascii_case("content-length") && !k.eq_ignore_ascii_case("transfer-encoding")
+        });
+    }
+}
+
+/// A tunnel's rules plus the shared interceptor
+#[derive(Clone)]
+pub struct Intercept {
+    pub rules: Vec<InterceptRule>,
+    pub interceptor: Interceptor,
+}
+
+impl Intercept {
+    /// Rules from a tunnel's `intercept` list; none when the list is empty
+    pub fn from_config(specs: &[String], interceptor: Interceptor) -> Option<Self> {
+        let rules: Vec<_> = specs.iter().filter_map(|s| InterceptRule::parse(s)).collect();
+        (!rules.is_empty()).then_some(Self { rules, interceptor })
+    }
+
+    pub fn matches(&self, request: &TunnelRequest) -> bool {
+        self.rules.iter().any(|r| r.matches(&request.method, &request.path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    #[test]
+    fn test_rule_parse_and_match() {
+        let rule = InterceptRule::parse("post /webhooks/**").unwrap();
+        assert!(rule.matches("POST", "/webhooks/stripe?x=1"));
+        assert!(!rule.matches("GET", "/webhooks/stripe"));
+        assert!(!rule.matches("POST", "/api"));
+
+        let any = InterceptRule::parse("/api/*").unwrap();
+        assert!(any.matches("DELETE", "/api/users"));
+
+        assert!(InterceptRule::parse("").is_none());
+        assert!(InterceptRule::parse("api").is_none());
+        assert!(InterceptRule::parse("POST /a /b").is_none());
+        assert!(InterceptRule::parse("P0ST /a").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_hold_and_decide() {
+        let interceptor = Interceptor::default();
+        let request = TunnelRequest {
+            id: "r1".into(),
+            method: "POST".into(),
+            path: "/hook".into(),
+            headers: vec![("Content-Length".into(), "2".into())],
+            body: Some(Bytes::from_static(b"{}")),
+            streamed: false,
+        };
+
+        let held = tokio::spawn({
+            let interceptor = interceptor.clone();
+            let request = request.clone();
+            async move { interceptor.hold(HeldRequest::new("web", &request)).await }
+        });
+        while interceptor.list().is_empty() {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(interceptor.list()[0].path, "/hook");
+        assert!(!interceptor.decide("nope", Verdict::Approve(ReplayOverrides::default())));
+
+        let edits = ReplayOverrides { body: Some("{\"n\":1}".into()), ..Default::default() };
+        assert!(interceptor.decide("r1", Verdict::Approve(edits)));
+        let Verdict::Approve(edits) = held.await.unwrap() else { panic!("expected approval") };
+        assert!(interceptor.list().is_empty());
+
+        let mut request = request;
+        apply_edits(edits, &mut request);
+        assert_eq!(request.body.as_deref(), Some(&b"{\"n\":1}"[..]));
+        assert!(request.headers.is_empty());
+    }
+}
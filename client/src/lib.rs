@@ -0,0 +1,73 @@
+//! ZTunnel client library
+//!
+//! The tunnel client core behind the `ztunnel` binary: relay connection
+//! and registration, forwarding to the local service, reconnects with
+//! failover, and the inspector. `TunnelBuilder` opens a tunnel from Rust
+//! code, e.g. in a test harness:
+//!
+//! ```no_run
+//! # async fn run() -> anyhow::Result<()> {
+//! use ztunnel_client::{TunnelBuilder, TunnelEvent};
+//!
+//! let tunnel = TunnelBuilder::http(3000)
+//!     .relay("wss://relay.example.com/tunnel")
+//!     .subdomain("myapp")
+//!     .on_event(|event| {
+//!         if let TunnelEvent::Request(entry) = event {
+//!             println!("{} {} → {}", entry.method, entry.path, entry.status);
+//!         }
+//!     })
+//!     .start()
+//!     .await?;
+//! println!("Public URL: {}", tunnel.url());
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The lower-level pieces (`session`, `multi`, `inspector`, ...) are
+//! public too, for callers that need more control.
+
+pub mod api;
+pub mod auth;
+pub mod backoff;
+pub mod batch;
+pub mod body;
+pub mod builder;
+pub mod cache;
+pub mod compose;
+pub mod config;
+pub mod connect;
+pub mod curl;
+pub mod decode;
+pub mod diff;
+pub mod e2e;
+pub mod export;
+pub mod filter;
+pub mod frames;
+pub mod headers;
+pub mod history;
+pub mod hooks;
+pub mod inspector;
+pub mod intercept;
+pub mod logging;
+pub mod mdns;
+pub mod multi;
+pub mod ngrok;
+pub mod pause;
+pub mod probe;
+pub mod proxy;
+pub mod proxy_protocol;
+pub mod record;
+pub mod regions;
+pub mod replay;
+pub mod schedule;
+pub mod session;
+pub mod socks;
+pub mod stats;
+pub mod stream;
+pub mod summary;
+pub mod throttle;
+pub mod tunnel;
+pub mod webhook;
+
+pub use builder::{Tunnel, TunnelBuilder, TunnelEvent};
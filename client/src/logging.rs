@@ -0,0 +1,94 @@
+//! Log and console output setup
+//!
+//! `--log-format json` writes one JSON object per line with the event's
+//! fields (`event`, `tunnel`, `status`, `latency_ms`, ...) at the top
+//! level, for log shippers. Banners are skipped in that mode so the log
+//! stream carries nothing but JSON.
+//!
+//! `--output json` keeps stdout for a single machine-readable result;
+//! logs and banners move to stderr.
+
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+
+/// How log events are written
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogFormat {
+    /// Human-readable lines
+    #[default]
+    Text,
+    /// One JSON object per event
+    Json,
+}
+
+/// What goes to stdout
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// Banners and logs
+    #[default]
+    Text,
+    /// Only a JSON result; everything else goes to stderr
+    Json,
+}
+
+static BANNERS: AtomicBool = AtomicBool::new(false);
+static JSON_LOGS: AtomicBool = AtomicBool::new(false);
+static STDOUT_RESERVED: AtomicBool = AtomicBool::new(false);
+
+/// Install the global subscriber
+pub fn init(format: LogFormat, output: OutputFormat, verbose: bool) {
+    let level = if verbose { tracing::Level::DEBUG } else { tracing::Level::INFO };
+    let reserved = output == OutputFormat::Json;
+    STDOUT_RESERVED.store(reserved, Ordering::Relaxed);
+    BANNERS.store(true, Ordering::Relaxed);
+    let writer = if reserved { BoxMakeWriter::new(std::io::stderr) } else { BoxMakeWriter::new(std::io::stdout) };
+
+    match format {
+        LogFormat::Text => tracing_subscriber::fmt().with_max_level(level).with_writer(writer).init(),
+        LogFormat::Json => {
+            JSON_LOGS.store(true, Ordering::Relaxed);
+            tracing_subscriber::fmt()
+                .with_max_level(level)
+                .with_writer(writer)
+                .json()
+                .flatten_event(true)
+                .with_current_span(false)
+                .init();
+        }
+    }
+}
+
+/// Whether logs are JSON, so banners are suppressed
+pub fn is_json() -> bool {
+    JSON_LOGS.load(Ordering::Relaxed)
+}
+
+/// Whether `--output json` owns stdout
+pub fn stdout_reserved() -> bool {
+    STDOUT_RESERVED.load(Ordering::Relaxed)
+}
+
+/// Print decorative output unless logs are JSON; see `banner!`.
+/// Nothing is printed before `init`, so programs embedding the client
+/// library stay quiet.
+pub fn write_banner(args: fmt::Arguments) {
+    if !BANNERS.load(Ordering::Relaxed) || is_json() {
+        return;
+    }
+    if stdout_reserved() {
+        eprintln!("{}", args);
+    } else {
+        println!("{}", args);
+    }
+}
+
+/// `println!` for banners and status lines that aren't log events
+#[macro_export]
+#[doc(hidden)]
+macro_rules! banner {
+    ($($arg:tt)*) => {
+        $crate::logging::write_banner(format_args!($($arg)*))
+    };
+}
+pub use banner;
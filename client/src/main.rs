@@ -1,20 +1,20 @@
-use anyhow::{Context, Result};
+use anyhow::Result;
 use clap::{Parser, Subcommand};
-use futures_util::{SinkExt, StreamExt};
-use std::net::SocketAddr;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpListener;
 use tokio::sync::mpsc;
-use tokio_tungstenite::{connect_async, tungstenite::Message};
 use tracing::{error, info, warn};
 
-mod tunnel;
-mod proxy;
-mod inspector;
-mod config;
-mod multi;
+mod config_cmd;
+mod reload;
+#[cfg(unix)]
+mod daemon;
+#[cfg(unix)]
+mod docker;
 
-use inspector::{InspectorEntry, InspectorState};
+use ztunnel_client::inspector::{self, InspectorEntry, InspectorState};
+use ztunnel_client::logging::{self, banner};
+use ztunnel_client::summary::Summary;
+use ztunnel_shared::telemetry::Fanout;
+use ztunnel_client::{auth, compose, config, connect, e2e, export, history, intercept, mdns, multi, probe, proxy, proxy_protocol, regions, replay, session, socks};
 
 #[derive(Parser)]
 #[command(name = "ztunnel")]
@@ -25,13 +25,30 @@ struct Cli {
     #[command(subcommand)]
     command: Commands,
     
-    /// Relay server URL
-    #[arg(short, long, default_value = "ws://localhost:8080/tunnel")]
-    relay: String,
-    
+    /// Relay server URL; separate several with commas for failover
+    #[arg(short, long, default_value = "ws://localhost:8080/tunnel", value_delimiter = ',')]
+    relay: Vec<String>,
+
+    /// Auth token sent to the relay during registration
+    #[arg(long, env = "ZTUNNEL_AUTH_TOKEN", hide_env_values = true)]
+    auth_token: Option<String>,
+
     /// Enable verbose logging
     #[arg(short, long)]
     verbose: bool,
+
+    /// Log as human-readable text or one JSON object per event
+    #[arg(long, value_enum, default_value_t, env = "ZTUNNEL_LOG_FORMAT")]
+    log_format: logging::LogFormat,
+
+    /// Print the tunnel URL as JSON on stdout; logs and banners go to stderr
+    #[arg(short, long, value_enum, default_value_t)]
+    output: logging::OutputFormat,
+
+    /// Report panics and repeated failures to this Sentry DSN; overrides
+    /// `error_reporting` in the config file
+    #[arg(long, env = "ZTUNNEL_SENTRY_DSN", hide_env_values = true)]
+    sentry_dsn: Option<String>,
 }
 
 #[derive(Subcommand)]
@@ -39,7 +56,8 @@ enum Commands {
     /// Expose HTTP service
     Http {
         /// Local port to expose
-        port: u16,
+        #[arg(required_unless_present = "respond")]
+        port: Option<u16>,
         
         /// Custom subdomain
         #[arg(short, long)]
@@ -53,30 +71,279 @@ enum Commands {
         #[arg(long, default_value = "4040")]
         inspect_port: u16,
 
+        /// Requests kept in the inspector
+        #[arg(long, value_name = "N", default_value_t = config::DEFAULT_MAX_ENTRIES)]
+        inspect_entries: usize,
+
+        /// Inspector memory budget in MiB; oldest requests are dropped past it (0 = unlimited)
+        #[arg(long, value_name = "MB", default_value_t = config::DEFAULT_MAX_MEMORY_MB)]
+        inspect_memory: usize,
+
+        /// Keep inspector history in this SQLite file across restarts
+        #[arg(long, value_name = "PATH")]
+        history: Option<String>,
+
         /// Bandwidth throttle (e.g., "3kbps", "1mbps", "500kb/s")
         #[arg(long)]
         throttle: Option<String>,
 
+        /// Bytes the throttle lets through at once before capping (e.g., "256kb"; default: one second's worth)
+        #[arg(long, value_name = "SIZE", requires = "throttle", value_parser = parse_size)]
+        throttle_burst: Option<u32>,
+
         /// Artificial latency in milliseconds
         #[arg(long)]
         latency: Option<u64>,
+
+        /// Host header sent to the local service (default: localhost:PORT)
+        #[arg(long)]
+        host_header: Option<String>,
+
+        /// Require HTTP basic auth from visitors (user:pass)
+        #[arg(long)]
+        basic_auth: Option<String>,
+
+        /// Answer every request with a canned response instead of a local
+        /// service (e.g., 200:'{"ok":true}')
+        #[arg(long)]
+        respond: Option<String>,
+
+        /// Hold matching requests for approval in the inspector
+        /// (e.g., "POST /webhooks/**"); repeatable
+        #[arg(long, value_name = "SPEC")]
+        intercept: Vec<String>,
+
+        /// Send inspector replays here instead of the local port
+        /// (PORT, HOST:PORT, or unix:PATH)
+        #[arg(long, value_name = "TARGET")]
+        replay_target: Option<String>,
+
+        /// Clear the inspector whenever the tunnel reconnects
+        #[arg(long)]
+        clear_on_reconnect: bool,
+
+        /// Accept only requests encrypted end to end by `ztunnel receive`
+        #[arg(long, conflicts_with = "respond")]
+        e2e: bool,
+
+        /// Answer 504 when the local service is slower than this (e.g. 10s, 500ms)
+        #[arg(long, value_name = "DURATION")]
+        local_timeout: Option<String>,
+
+        /// Resend idempotent requests that time out or are refused up to N times
+        #[arg(long, value_name = "N", default_value_t = 0)]
+        retries: u32,
+
+        /// Add permissive CORS headers and answer preflights locally
+        #[arg(long)]
+        cors: bool,
+
+        /// Announce the tunnel's URL on the LAN over mDNS
+        #[arg(long)]
+        mdns: bool,
+
+        /// Connect to this region's relay instead of --relay (e.g. eu)
+        #[arg(long)]
+        region: Option<String>,
     },
     /// Expose TCP service
     Tcp {
         /// Local port to expose
         port: u16,
+
+        /// Send a PROXY protocol header with the visitor's address
+        /// before each connection's data
+        #[arg(long, value_enum)]
+        proxy_protocol: Option<proxy_protocol::Version>,
+
+        /// Connect to this region's relay instead of --relay (e.g. eu)
+        #[arg(long)]
+        region: Option<String>,
+    },
+    /// Serve someone else's TCP tunnel on a local port
+    Forward {
+        /// Name of the TCP tunnel (its subdomain)
+        tunnel: String,
+
+        /// Local port to serve it on
+        port: u16,
+
+        /// Address to listen on
+        #[arg(long, default_value = "127.0.0.1")]
+        bind: String,
+    },
+    /// Let `ztunnel socks` users reach hosts on this machine's network
+    Gateway {
+        /// Destination they may connect to as HOST:PORT, `*` matching
+        /// anything (e.g. 10.0.0.*:5432); repeatable
+        #[arg(long, value_name = "HOST:PORT", required = true)]
+        allow: Vec<String>,
+
+        /// Token consumers must present
+        #[arg(long, env = "ZTUNNEL_GATEWAY_TOKEN", hide_env_values = true)]
+        token: String,
+
+        /// Custom subdomain
+        #[arg(short, long)]
+        subdomain: Option<String>,
+    },
+    /// Run a local SOCKS5 proxy that connects through a `ztunnel gateway`
+    Socks {
+        /// Gateway's tunnel name (its subdomain)
+        tunnel: String,
+
+        /// The gateway's token
+        #[arg(long, env = "ZTUNNEL_GATEWAY_TOKEN", hide_env_values = true)]
+        token: String,
+
+        /// Address to listen on
+        #[arg(short, long, default_value = "127.0.0.1:1080")]
+        listen: String,
+    },
+    /// Serve an end-to-end encrypted tunnel on a local port
+    Receive {
+        /// Public URL of the tunnel started with --e2e
+        url: String,
+
+        /// Local port to serve it on
+        #[arg(short, long, default_value = "8000")]
+        port: u16,
+
+        /// Tunnel's key as printed by `ztunnel http --e2e` (default: fetch
+        /// it through the relay)
+        #[arg(long)]
+        key: Option<String>,
+    },
+    /// Proxy a local port to a service and record it in the inspector, without a relay
+    Proxy {
+        /// Local port of the service
+        port: u16,
+
+        /// Port to accept requests on
+        #[arg(long, default_value = "8081")]
+        listen: u16,
+
+        /// Inspector dashboard port
+        #[arg(long, default_value = "4040")]
+        inspect_port: u16,
     },
     /// Start tunnels from config file (ztunnel.yml)
     Start {
         /// Path to config file (default: auto-detect)
         #[arg(short, long)]
         config: Option<String>,
+
+        /// Config profile to apply (e.g. dev, staging, demo)
+        #[arg(short, long, env = "ZTUNNEL_PROFILE")]
+        profile: Option<String>,
+
+        /// Run in the background with a control socket and pidfile
+        #[arg(long)]
+        daemon: bool,
+
+        /// With --daemon, stay attached (for systemd, supervisord, ...)
+        #[arg(long, requires = "daemon")]
+        foreground: bool,
+
+        /// Run the tunnels of an ngrok config (v2 or v3) through --relay
+        #[arg(long, value_name = "PATH", conflicts_with_all = ["config", "profile"])]
+        from_ngrok: Option<String>,
     },
-    /// Show tunnel status and relay health
+    /// Open tunnels for Docker containers labeled ztunnel.enable=true as they start and stop
+    Docker {
+        /// Docker socket (default: a unix:// DOCKER_HOST, else /var/run/docker.sock)
+        #[arg(long)]
+        socket: Option<String>,
+
+        /// Inspector dashboard port
+        #[arg(long, default_value = "4040")]
+        inspect_port: u16,
+    },
+    /// Tunnel to the published ports of a docker-compose project's services
+    Compose {
+        /// Services to tunnel (default: every service that publishes a port)
+        services: Vec<String>,
+
+        /// Compose file (default: compose.yaml or docker-compose.yml here)
+        #[arg(short, long)]
+        file: Option<String>,
+
+        /// Ask for PREFIX-SERVICE subdomains instead of SERVICE
+        #[arg(long)]
+        prefix: Option<String>,
+
+        /// Tunnel this service as raw TCP; repeatable
+        #[arg(long, value_name = "SERVICE")]
+        tcp: Vec<String>,
+
+        /// Inspector dashboard port
+        #[arg(long, default_value = "4040")]
+        inspect_port: u16,
+    },
+    /// Show daemon tunnels and relay health
     Status {
         /// Relay server URL to check
         #[arg(short, long, default_value = "http://localhost:8080")]
         relay: String,
+
+        /// Print machine-readable JSON instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+    /// Measure latency to each relay, fastest first
+    PingRelays {
+        /// Relay URLs (default: the config file's relays, else --relay)
+        relays: Vec<String>,
+
+        /// Path to config file (default: auto-detect)
+        #[arg(short, long)]
+        config: Option<String>,
+
+        /// Use this profile's relays
+        #[arg(short, long, env = "ZTUNNEL_PROFILE")]
+        profile: Option<String>,
+    },
+    /// Stop a daemon tunnel, or the whole daemon when no name is given
+    Stop {
+        /// Tunnel name
+        name: Option<String>,
+    },
+    /// Reconnect one daemon tunnel to the relay, leaving the others running
+    Restart {
+        /// Tunnel name
+        name: String,
+    },
+    /// Answer a tunnel's requests with 503 until resumed, keeping its URL
+    Pause {
+        /// Tunnel name (`http` for `ztunnel http`)
+        name: String,
+
+        /// HTML or text file to serve instead of the tunnel's pause_page
+        #[arg(long, value_name = "FILE")]
+        page: Option<std::path::PathBuf>,
+
+        /// Inspector port of a tunnel not run by the daemon
+        #[arg(long, default_value = "4040")]
+        inspect_port: u16,
+    },
+    /// Forward a paused tunnel's requests again
+    Resume {
+        /// Tunnel name (`http` for `ztunnel http`)
+        name: String,
+
+        /// Inspector port of a tunnel not run by the daemon
+        #[arg(long, default_value = "4040")]
+        inspect_port: u16,
+    },
+    /// Validate or create a config file
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+    /// Save or load recorded inspector sessions
+    Inspect {
+        #[command(subcommand)]
+        action: InspectAction,
     },
     /// Check for updates
     Update {
@@ -86,32 +353,227 @@ enum Commands {
     },
 }
 
+#[derive(Subcommand)]
+enum ConfigAction {
+    /// Check ztunnel.yml for errors, unknown keys, and unreachable targets
+    Validate {
+        /// Path to config file (default: auto-detect)
+        #[arg(short, long)]
+        config: Option<String>,
+
+        /// Validate with this profile applied
+        #[arg(short, long, env = "ZTUNNEL_PROFILE")]
+        profile: Option<String>,
+
+        /// Skip connecting to local targets
+        #[arg(long)]
+        offline: bool,
+    },
+    /// Interactively write a starter ztunnel.yml
+    Init {
+        /// Where to write the config
+        #[arg(short, long, default_value = "ztunnel.yml")]
+        output: String,
+
+        /// Overwrite an existing file
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum InspectAction {
+    /// Save a running inspector's requests to a file
+    Export {
+        /// Where to write the session (default: stdout)
+        #[arg(short, long)]
+        output: Option<String>,
+
+        /// Inspector dashboard port
+        #[arg(long, default_value = "4040")]
+        inspect_port: u16,
+    },
+    /// Load a saved session into a running inspector, or serve it on its own
+    Import {
+        /// Session file from `ztunnel inspect export`
+        file: String,
+
+        /// Inspector dashboard port
+        #[arg(long, default_value = "4040")]
+        inspect_port: u16,
+    },
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli = Cli::parse();
-    
-    if cli.verbose {
-        tracing_subscriber::fmt()
-            .with_max_level(tracing::Level::DEBUG)
-            .init();
-    } else {
-        tracing_subscriber::fmt()
-            .with_max_level(tracing::Level::INFO)
-            .init();
+    logging::init(cli.log_format, cli.output, cli.verbose);
+    if let Some(dsn) = &cli.sentry_dsn {
+        init_error_reporting(dsn, None)?;
     }
 
     match cli.command {
-        Commands::Http { port, subdomain, no_inspect, inspect_port, throttle, latency } => {
-            run_http_tunnel(&cli.relay, port, subdomain, !no_inspect, inspect_port, throttle, latency).await?;
+        Commands::Http { port, subdomain, no_inspect, inspect_port, inspect_entries, inspect_memory, history, throttle, throttle_burst, latency, host_header, basic_auth, respond, intercept, replay_target, clear_on_reconnect, e2e, local_timeout, retries, cors, mdns, region } => {
+            if let Some(spec) = &basic_auth {
+                if auth::BasicAuth::parse(spec).is_none() {
+                    anyhow::bail!("Invalid --basic-auth '{}', expected user:pass", spec);
+                }
+            }
+            let respond = match respond {
+                Some(spec) => Some(proxy::FixedResponse::parse(&spec).ok_or_else(|| {
+                    anyhow::anyhow!("Invalid --respond '{}', expected STATUS[:BODY]", spec)
+                })?),
+                None => None,
+            };
+            for spec in &intercept {
+                if intercept::InterceptRule::parse(spec).is_none() {
+                    anyhow::bail!("Invalid --intercept '{}', expected [METHOD] /path", spec);
+                }
+            }
+            if !intercept.is_empty() && no_inspect {
+                anyhow::bail!("--intercept needs the inspector to approve requests");
+            }
+            if let Some(spec) = &replay_target {
+                if proxy::LocalTarget::parse(spec).is_none() {
+                    anyhow::bail!("Invalid --replay-target '{}', expected PORT, HOST:PORT, or unix:PATH", spec);
+                }
+            }
+            if let Some(spec) = &local_timeout {
+                if config::parse_duration(spec).is_none_or(|d| d.is_zero()) {
+                    anyhow::bail!("Invalid --local-timeout '{}', expected e.g. 10s or 500ms", spec);
+                }
+            }
+            if retries > config::MAX_RETRIES {
+                anyhow::bail!("--retries is at most {}", config::MAX_RETRIES);
+            }
+            let opts = HttpOptions {
+                local_port: port.unwrap_or(0),
+                subdomain,
+                inspect: !no_inspect,
+                inspect_port,
+                inspect_entries,
+                inspect_memory,
+                history,
+                throttle,
+                throttle_burst,
+                latency_ms: latency,
+                host_header,
+                basic_auth,
+                respond,
+                intercept,
+                replay_target,
+                clear_on_reconnect,
+                e2e,
+                local_timeout,
+                retries,
+                cors,
+                mdns,
+                auth_token: cli.auth_token,
+            };
+            let relays = region_relays(region.as_deref(), cli.relay)?;
+            run_http_tunnel(&relays, opts).await?;
+        }
+        Commands::Tcp { port, proxy_protocol, region } => {
+            let conf = config::TunnelConfig {
+                name: "tcp".to_string(),
+                proto: "tcp".to_string(),
+                local_port: port,
+                inspect: false,
+                proxy_protocol,
+                ..Default::default()
+            };
+            let relays = region_relays(region.as_deref(), cli.relay)?;
+            run_tcp_tunnel(&relays, conf, cli.auth_token).await?;
+        }
+        Commands::Forward { tunnel, port, bind } => {
+            run_forward(&cli.relay, tunnel, &bind, port).await?;
+        }
+        Commands::Gateway { allow, token, subdomain } => {
+            let conf = config::TunnelConfig {
+                name: "gateway".to_string(),
+                proto: "tcp".to_string(),
+                subdomain,
+                inspect: false,
+                gateway: Some(config::GatewayConfig { token, allow }),
+                ..Default::default()
+            };
+            conf.validate()?;
+            run_tcp_tunnel(&cli.relay, conf, cli.auth_token).await?;
+        }
+        Commands::Socks { tunnel, token, listen } => {
+            run_socks(&cli.relay, tunnel, token, &listen).await?;
+        }
+        Commands::Receive { url, port, key } => {
+            e2e::run_receiver(&url, port, key.as_deref()).await?;
+        }
+        Commands::Proxy { port, listen, inspect_port } => {
+            run_proxy(port, listen, inspect_port).await?;
+        }
+        Commands::Start { config: config_path, profile, daemon, foreground, from_ngrok } => {
+            if daemon && !foreground {
+                return start_daemon();
+            }
+            let ngrok_relays = from_ngrok.is_some().then_some(cli.relay);
+            let config_path = from_ngrok.or(config_path);
+            let opts = reload::LoadOptions { profile, auth_token: cli.auth_token, ngrok_relays };
+            run_multi_tunnel(config_path, opts, daemon).await?;
+        }
+        Commands::Docker { socket, inspect_port } => {
+            let cfg = flag_config(cli.relay, cli.auth_token, inspect_port);
+            run_docker(socket, cfg).await?;
         }
-        Commands::Tcp { port } => {
-            run_tcp_tunnel(&cli.relay, port).await?;
+        Commands::Compose { services, file, prefix, tcp, inspect_port } => {
+            let path = file.map(std::path::PathBuf::from).or_else(compose::find).ok_or_else(|| {
+                anyhow::anyhow!("No compose file found here; pass one with --file")
+            })?;
+            let mut cfg = flag_config(cli.relay, cli.auth_token, inspect_port);
+            cfg.tunnels = compose::load(&path, &compose::Options { services, prefix, tcp })?;
+            run_compose(cfg).await?;
         }
-        Commands::Start { config: config_path } => {
-            run_multi_tunnel(config_path).await?;
+        Commands::Status { relay, json } => {
+            run_status(&relay, json).await?;
         }
-        Commands::Status { relay } => {
-            run_status(&relay).await?;
+        Commands::PingRelays { relays, config, profile } => {
+            let relays = if !relays.is_empty() {
+                relays
+            } else if let Some(path) = config.map(std::path::PathBuf::from).or_else(config::ZTunnelConfig::find_config) {
+                config::ZTunnelConfig::load(&path, profile.as_deref())?.relay_urls()
+            } else {
+                cli.relay
+            };
+            run_ping_relays(&relays).await?;
+        }
+        Commands::Stop { name } => {
+            run_stop(name).await?;
+        }
+        Commands::Restart { name } => {
+            run_restart(&name).await?;
+        }
+        Commands::Pause { name, page, inspect_port } => {
+            let page = match page {
+                Some(path) => Some(
+                    std::fs::read_to_string(&path)
+                        .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path.display(), e))?,
+                ),
+                None => None,
+            };
+            run_pause(&name, Some(page), inspect_port).await?;
+        }
+        Commands::Resume { name, inspect_port } => {
+            run_pause(&name, None, inspect_port).await?;
+        }
+        Commands::Config { action: ConfigAction::Validate { config, profile, offline } } => {
+            let path = resolve_config_path(config)?;
+            config_cmd::run_validate(&path, profile.as_deref(), !offline).await?;
+        }
+        Commands::Config { action: ConfigAction::Init { output, force } } => {
+            config_cmd::run_init(std::path::Path::new(&output), force)?;
+        }
+        Commands::Inspect { action: InspectAction::Export { output, inspect_port } } => {
+            export::run_export(inspect_port, output.as_deref().map(std::path::Path::new)).await?;
+        }
+        Commands::Inspect { action: InspectAction::Import { file, inspect_port } } => {
+            export::run_import(inspect_port, std::path::Path::new(&file)).await?;
         }
         Commands::Update { check } => {
             run_update(check).await?;
@@ -121,29 +583,151 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
-/// Run multi-tunnel mode from config file
-async fn run_multi_tunnel(config_path: Option<String>) -> Result<()> {
-    let path = if let Some(p) = config_path {
-        std::path::PathBuf::from(p)
-    } else {
-        config::ZTunnelConfig::find_config()
-            .ok_or_else(|| anyhow::anyhow!("No config file found. Create ztunnel.yml or specify --config"))?
+/// Detach a daemon process running the configured tunnels
+#[cfg(unix)]
+fn start_daemon() -> Result<()> {
+    let paths = daemon::DaemonPaths::from_env();
+    let pid = daemon::detach(&paths)?;
+    println!("\x1b[32m✓ ZTunnel daemon started (pid {})\x1b[0m", pid);
+    println!("  Log:     {}", paths.log().display());
+    println!("  Control: {}", paths.socket().display());
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn start_daemon() -> Result<()> {
+    anyhow::bail!("Daemon mode is only supported on unix platforms")
+}
+
+/// Explicit --config path, or the first ztunnel.yml found
+fn resolve_config_path(config_path: Option<String>) -> Result<std::path::PathBuf> {
+    match config_path {
+        Some(p) => Ok(std::path::PathBuf::from(p)),
+        None => config::ZTunnelConfig::find_config().ok_or_else(|| {
+            anyhow::anyhow!("No config file found. Create one with `ztunnel config init` or specify --config")
+        }),
+    }
+}
+
+/// Run multi-tunnel mode from config file, optionally as the daemon
+async fn run_multi_tunnel(config_path: Option<String>, opts: reload::LoadOptions, daemon: bool) -> Result<()> {
+    let path = resolve_config_path(config_path)?;
+
+    let cfg = opts.load(&path)?;
+    match &opts.profile {
+        Some(profile) => info!("Loaded config from {} (profile '{}')", path.display(), profile),
+        None if opts.ngrok_relays.is_some() => info!("Imported ngrok config from {}", path.display()),
+        None => info!("Loaded config from {}", path.display()),
+    }
+
+    let mdns_conf = cfg.mdns.clone();
+    let inspector_port = cfg.inspector.enabled.then_some(cfg.inspector.port);
+    let manager = start_manager(cfg).await?;
+
+    // Announce tunnels on the LAN as they come and go
+    let mut announcing = None;
+    if let Some(conf) = mdns_conf {
+        let host = conf.name.clone().unwrap_or_else(mdns::hostname);
+        if let Some(announcer) = mdns::start_or_warn(&host) {
+            let inspector_port = inspector_port.filter(|_| conf.inspector);
+            let (stop, stopped) = tokio::sync::oneshot::channel();
+            let task = tokio::spawn(announce_tunnels(announcer, host, inspector_port, manager.clone(), stopped));
+            announcing = Some((stop, task));
+        }
+    }
+
+    // Pick up edits to the config file without restarting
+    let watched = manager.clone();
+    tokio::spawn(async move {
+        if let Err(e) = reload::watch(path, opts, watched).await {
+            warn!("Config hot reload disabled: {}", e);
+        }
+    });
+
+    let result = match daemon {
+        #[cfg(unix)]
+        true => daemon::serve(manager, &daemon::DaemonPaths::from_env()).await,
+        _ => {
+            banner!("Press Ctrl+C to stop all tunnels\n");
+            multi::wait_for_shutdown(&manager).await;
+            Ok(())
+        }
     };
+    if let Some((stop, task)) = announcing {
+        let _ = stop.send(());
+        let _ = task.await;
+    }
+    result
+}
 
-    let cfg = config::ZTunnelConfig::load(&path)?;
-    info!("Loaded config from {}", path.display());
+/// How often the mDNS announcement catches up with the running tunnels
+const ANNOUNCE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// Keep announcing the manager's registered tunnels, and the inspector
+/// when `inspector_port` is set, until `stop` fires; then withdraw them
+async fn announce_tunnels(
+    announcer: mdns::Announcer,
+    host: String,
+    inspector_port: Option<u16>,
+    manager: std::sync::Arc<tokio::sync::Mutex<multi::TunnelManager>>,
+    mut stop: tokio::sync::oneshot::Receiver<()>,
+) {
+    let mut timer = tokio::time::interval(ANNOUNCE_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = timer.tick() => {
+                let mut services: Vec<mdns::Service> =
+                    inspector_port.map(|port| mdns::Service::inspector(&host, port)).into_iter().collect();
+                for tunnel in manager.lock().await.list() {
+                    if let Some(url) = &tunnel.url {
+                        services.push(mdns::Service::tunnel(&tunnel.name, &host, &tunnel.proto, url, &tunnel.target));
+                    }
+                }
+                announcer.set(services);
+            }
+            _ = &mut stop => break,
+        }
+    }
+    announcer.stop().await;
+}
+
+/// Send panics and failure reports to a Sentry DSN from here on
+fn init_error_reporting(dsn: &str, environment: Option<String>) -> Result<()> {
+    let dsn = dsn.parse().map_err(anyhow::Error::msg)?;
+    let release = concat!("ztunnel@", env!("CARGO_PKG_VERSION")).to_string();
+    ztunnel_shared::report::init(ztunnel_shared::report::Options { dsn, release, environment });
+    Ok(())
+}
+
+/// Start the inspector and `cfg`'s tunnels
+async fn start_manager(cfg: config::ZTunnelConfig) -> Result<std::sync::Arc<tokio::sync::Mutex<multi::TunnelManager>>> {
+    if let Some(conf) = &cfg.error_reporting {
+        init_error_reporting(&conf.dsn, conf.environment.clone())?;
+    }
 
     // Setup inspector
-    let (replay_tx, mut replay_rx) = mpsc::channel::<String>(32);
+    let (replay_tx, replay_rx) = mpsc::channel::<replay::ReplayRequest>(32);
     let (entry_tx, mut entry_rx) = mpsc::channel::<InspectorEntry>(256);
-    let inspector = InspectorState::new(replay_tx);
+    let (restart_tx, restart_rx) = mpsc::channel::<multi::RestartRequest>(8);
+    let mut inspector = InspectorState::new(replay_tx)
+        .with_body_limit(cfg.inspector.max_body_bytes)
+        .with_capacity(cfg.inspector.max_entries, cfg.inspector.max_memory_mb)
+        .with_restart(restart_tx);
+    if let Some(conf) = &cfg.inspector.history {
+        inspector = inspector.with_history(history::History::open(conf)?).await;
+    }
 
-    // Start inspector server if enabled
+    // Start inspector server if enabled; on every interface, read-only
+    // to other machines, when it's announced on the LAN
     if cfg.inspector.enabled {
         let insp = inspector.clone();
-        let port = cfg.inspector.port;
+        let ip = match &cfg.mdns {
+            Some(mdns) if mdns.inspector => [0, 0, 0, 0],
+            _ => [127, 0, 0, 1],
+        };
+        let addr = std::net::SocketAddr::from((ip, cfg.inspector.port));
         tokio::spawn(async move {
-            inspector::start_inspector(insp, port).await;
+            inspector::serve_inspector(insp, addr).await;
         });
     }
 
@@ -155,440 +739,574 @@ async fn run_multi_tunnel(config_path: Option<String>) -> Result<()> {
         }
     });
 
-    // Handle replay requests
-    let cfg_clone = cfg.clone();
-    let entry_tx_clone = entry_tx.clone();
-    tokio::spawn(async move {
-        while let Some(id) = replay_rx.recv().await {
-            info!("Replaying request: {}", id);
-            let insp = InspectorState::new(tokio::sync::mpsc::channel(1).0);
-            if let Some(entry) = insp.get_entry(&id).await {
-                info!("Found entry for replay: {} {}", entry.method, entry.path);
-            }
-        }
-    });
-
-    let mut manager = multi::TunnelManager::new(cfg, inspector, entry_tx);
+    let inspector_port = cfg.inspector.port;
+    let mut manager = multi::TunnelManager::new(cfg, entry_tx, inspector.clone());
     manager.start_all().await?;
 
-    println!("\n  Inspector: http://localhost:{}\n", cfg_clone.inspector.port);
-    println!("Press Ctrl+C to stop all tunnels\n");
+    // Replays go to whichever tunnel recorded the request
+    tokio::spawn(replay::serve(replay_rx, inspector, manager.replay_targets()));
+    let manager = std::sync::Arc::new(tokio::sync::Mutex::new(manager));
+    tokio::spawn(multi::serve_restarts(restart_rx, manager.clone()));
 
-    manager.wait_for_shutdown().await;
+    banner!("\n  Inspector: http://localhost:{}\n", inspector_port);
+
+    Ok(manager)
+}
+
+/// The relay of `region` when one is given, else `relays`. Regions
+/// from ztunnel.yml count, if there is one here.
+fn region_relays(region: Option<&str>, relays: Vec<String>) -> Result<Vec<String>> {
+    let Some(region) = region else {
+        return Ok(relays);
+    };
+    let configured = match config::ZTunnelConfig::find_config() {
+        Some(path) => regions::load(&path)?,
+        None => Default::default(),
+    };
+    let relay = regions::resolve(region, &configured)?;
+    banner!("Region: {} ({})", region, relay);
+    Ok(vec![relay])
+}
+
+/// Config for commands that take their relays and token from flags
+fn flag_config(relay: Vec<String>, auth_token: Option<String>, inspect_port: u16) -> config::ZTunnelConfig {
+    let mut cfg = config::ZTunnelConfig { auth_token, ..Default::default() };
+    if let Some((first, rest)) = relay.split_first() {
+        cfg.relay = first.clone();
+        cfg.relays = rest.to_vec();
+    }
+    cfg.inspector.port = inspect_port;
+    cfg
+}
+
+/// Longest `ztunnel compose` waits for URLs before printing the table
+const COMPOSE_URL_WAIT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Run a compose project's tunnels and print where each service is
+async fn run_compose(cfg: config::ZTunnelConfig) -> Result<()> {
+    let manager = start_manager(cfg).await?;
+
+    let deadline = tokio::time::Instant::now() + COMPOSE_URL_WAIT;
+    let mut tunnels = manager.lock().await.list();
+    while tunnels.iter().any(|t| t.url.is_none()) && tokio::time::Instant::now() < deadline {
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        tunnels = manager.lock().await.list();
+    }
+    println!("\n  {:<20} {:<5} {:<40} LOCAL", "SERVICE", "PROTO", "URL");
+    for t in &tunnels {
+        println!("  {:<20} {:<5} {:<40} {}", t.name, t.proto, t.url.as_deref().unwrap_or("(connecting)"), t.target);
+    }
+    println!();
+
+    banner!("Press Ctrl+C to stop all tunnels\n");
+    multi::wait_for_shutdown(&manager).await;
     Ok(())
 }
 
-/// Run HTTP tunnel with optional inspector
-async fn run_http_tunnel(
-    relay_url: &str,
+/// Follow labeled Docker containers with tunnels
+#[cfg(unix)]
+async fn run_docker(socket: Option<String>, cfg: config::ZTunnelConfig) -> Result<()> {
+    let manager = start_manager(cfg.clone()).await?;
+    tokio::spawn(docker::watch(docker::socket_path(socket), cfg, manager.clone()));
+    banner!("Press Ctrl+C to stop all tunnels\n");
+    multi::wait_for_shutdown(&manager).await;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn run_docker(_socket: Option<String>, _cfg: config::ZTunnelConfig) -> Result<()> {
+    anyhow::bail!("Docker autodiscovery is only supported on unix platforms")
+}
+
+/// Options for an ad-hoc `ztunnel http` tunnel
+struct HttpOptions {
     local_port: u16,
     subdomain: Option<String>,
     inspect: bool,
     inspect_port: u16,
-    throttle_spec: Option<String>,
+    inspect_entries: usize,
+    inspect_memory: usize,
+    history: Option<String>,
+    throttle: Option<String>,
+    throttle_burst: Option<u32>,
     latency_ms: Option<u64>,
-) -> Result<()> {
+    host_header: Option<String>,
+    basic_auth: Option<String>,
+    respond: Option<proxy::FixedResponse>,
+    intercept: Vec<String>,
+    replay_target: Option<String>,
+    clear_on_reconnect: bool,
+    e2e: bool,
+    local_timeout: Option<String>,
+    retries: u32,
+    cors: bool,
+    mdns: bool,
+    auth_token: Option<String>,
+}
+
+/// A byte count such as `500000`, `256kb` or `1MiB`, up to 4 GB
+fn parse_size(spec: &str) -> Result<u32, String> {
+    let bytes = ztunnel_shared::throttle::parse_size(spec);
+    bytes.and_then(|b| u32::try_from(b).ok()).ok_or_else(|| format!("invalid size '{}', expected e.g. 256kb", spec))
+}
+
+/// Run HTTP tunnel with optional inspector
+async fn run_http_tunnel(relays: &[String], opts: HttpOptions) -> Result<()> {
+    let local_port = opts.local_port;
+    let relays = &probe::rank(relays.to_vec()).await;
+
     // Setup inspector
-    let (replay_tx, mut replay_rx) = mpsc::channel::<String>(32);
-    let inspector = InspectorState::new(replay_tx);
+    let (replay_tx, replay_rx) = mpsc::channel::<replay::ReplayRequest>(32);
+    let (entry_tx, mut entry_rx) = mpsc::channel::<InspectorEntry>(256);
+    let mut inspector = InspectorState::new(replay_tx).with_capacity(opts.inspect_entries, opts.inspect_memory);
+    if let Some(path) = &opts.history {
+        let conf = config::HistoryConfig::at(path.clone());
+        inspector = inspector.with_history(history::History::open(&conf)?).await;
+    }
 
-    if inspect {
+    if opts.inspect {
         let insp = inspector.clone();
+        let inspect_port = opts.inspect_port;
         tokio::spawn(async move {
             inspector::start_inspector(insp, inspect_port).await;
         });
     }
 
+    // Pipe entries from the tunnel to the inspector
+    let insp_for_entries = inspector.clone();
+    tokio::spawn(async move {
+        while let Some(entry) = entry_rx.recv().await {
+            insp_for_entries.record(entry).await;
+        }
+    });
+
     // Setup bandwidth throttle
-    let throttle = if let Some(spec) = throttle_spec {
-        match ztunnel_shared::throttle::parse_bandwidth(&spec) {
+    let throttle_bps = match opts.throttle {
+        Some(spec) => match ztunnel_shared::throttle::parse_bandwidth(&spec) {
             Some(bps) => {
                 info!("Bandwidth throttle: {} bytes/sec", bps);
-                ztunnel_shared::throttle::BandwidthThrottle::new(bps)
+                bps
             }
             None => {
                 warn!("Invalid throttle spec '{}', ignoring", spec);
-                None
+                0
             }
-        }
-    } else {
-        None
+        },
+        None => 0,
+    };
+
+    let conf = config::TunnelConfig {
+        name: "http".to_string(),
+        local_port,
+        subdomain: opts.subdomain.clone(),
+        throttle_bps,
+        throttle_burst: opts.throttle_burst.map_or(0, u64::from),
+        basic_auth: opts.basic_auth.clone(),
+        host_header: Some(opts.host_header.clone().unwrap_or_else(|| format!("localhost:{}", local_port))),
+        intercept: opts.intercept.clone(),
+        replay_target: opts.replay_target.clone(),
+        e2e: opts.e2e,
+        local_timeout: opts.local_timeout.clone(),
+        retries: opts.retries,
+        cors: opts.cors,
+        ..Default::default()
     };
-    let throttle = std::sync::Arc::new(tokio::sync::Mutex::new(throttle));
+    if let Some(subdomain) = &conf.subdomain {
+        ztunnel_shared::validate::check_subdomain(subdomain)?;
+    }
+    let mut ctx = session::TunnelContext::new(conf, entry_tx);
+    let e2e_key = ctx.e2e.as_ref().map(|e2e| e2e.public_key());
+    ctx.auth_token = opts.auth_token.clone();
+    let summary = with_summary(&mut ctx);
+    inspector.pauses().insert(&ctx.conf.name, ctx.pause.clone());
+    ctx.intercept = intercept::Intercept::from_config(&ctx.conf.intercept, inspector.interceptor());
+    ctx.frames = opts.inspect.then(|| inspector.frames());
+    if let Some(intercept) = &ctx.intercept {
+        info!("Intercepting {} rule(s); decide at /api/intercepts", intercept.rules.len());
+    }
 
     // Artificial latency
-    let latency = latency_ms.map(std::time::Duration::from_millis);
-    if let Some(lat) = latency {
+    ctx.latency = opts.latency_ms.map(std::time::Duration::from_millis);
+    if let Some(lat) = ctx.latency {
         info!("Artificial latency: {:?}", lat);
     }
 
+    if let Some(ref r) = opts.respond {
+        info!("Responding locally with status {}", r.status);
+    }
+    ctx.respond = opts.respond.clone();
+
     // Handle replay requests
-    let insp_for_replay = inspector.clone();
-    let relay_for_replay = relay_url.to_string();
-    tokio::spawn(async move {
-        while let Some(id) = replay_rx.recv().await {
-            info!("Replay request: {}", id);
-            if let Some(entry) = insp_for_replay.get_entry(&id).await {
-                // Re-execute the request against local server
-                let _ = replay_local_request(&entry, local_port).await;
-            }
+    let replay_targets = inspector.replay_targets();
+    if opts.respond.is_none() {
+        replay_targets.insert(&ctx.conf.name, replay::ReplayTarget::for_tunnel(&ctx));
+    }
+    tokio::spawn(replay::serve(replay_rx, inspector.clone(), replay_targets));
+
+    info!("Connecting to relay: {}", relays.join(", "));
+
+    let host = mdns::hostname();
+    let announcer = if opts.mdns { mdns::start_or_warn(&host) } else { None };
+
+    let mut connected = false;
+    let on_registered = |reg: &session::Registration, attempts: u32| {
+        if let Some(announcer) = &announcer {
+            let local = format!("localhost:{}", local_port);
+            announcer.set(vec![mdns::Service::tunnel(&reg.subdomain, &host, "http", &reg.url, &local)]);
         }
-    });
+        if connected && opts.clear_on_reconnect {
+            let inspector = inspector.clone();
+            tokio::spawn(async move { inspector.clear(None).await });
+        }
+        connected = true;
+        if attempts > 0 {
+            banner!("\x1b[32m✓ Reconnected after {} attempt(s): {}\x1b[0m\n", attempts, reg.url);
+            return;
+        }
+        if logging::stdout_reserved() {
+            let inspector_url = opts.inspect.then(|| format!("http://localhost:{}", opts.inspect_port));
+            print_tunnel_output(reg, &format!("http://localhost:{}", local_port), inspector_url);
+        }
+        banner!("\n╔══════════════════════════════════════════════════════════════╗");
+        banner!("║  🚀 ZTunnel Active                                           ║");
+        banner!("╠══════════════════════════════════════════════════════════════╣");
+        banner!("║  Public URL: {:<47} ║", reg.url);
+        if relays.len() > 1 {
+            banner!("║  Relay:      {:<47} ║", reg.relay);
+        }
+        match &opts.respond {
+            Some(r) => banner!("║  Respond:    {:<47} ║", format!("{} (fixed response)", r.status)),
+            None => banner!("║  Local:      http://localhost:{:<34} ║", local_port),
+        }
+        if opts.inspect {
+            banner!("║  Inspector:  http://localhost:{:<34} ║", opts.inspect_port);
+        }
+        if let Some(key) = &e2e_key {
+            banner!("║  E2E key:    {:<47} ║", key);
+        }
+        banner!("╚══════════════════════════════════════════════════════════════╝\n");
+        if let Some(key) = &e2e_key {
+            banner!("Visitors connect with: ztunnel receive {} --key {}\n", reg.url, key);
+        }
+        if reg.reassigned {
+            banner!("\x1b[33m⚠  Subdomain '{}' was taken, assigned '{}' instead\x1b[0m\n",
+                opts.subdomain.as_deref().unwrap_or("?"),
+                reg.subdomain);
+        }
+        banner!("Press Ctrl+C to stop the tunnel\n");
+    };
 
-    info!("Connecting to relay: {}", relay_url);
-    
-    let (ws_stream, _) = connect_async(relay_url)
-        .await
-        .context("Failed to connect to relay server")?;
-    
-    let (mut write, mut read) = ws_stream.split();
-    
-    // Send registration
-    let registration = serde_json::json!({
-        "subdomain": subdomain,
-        "type": "http",
-        "local_port": local_port,
-    });
-    
-    write.send(Message::Text(registration.to_string().into())).await?;
-    info!("Sent registration request");
-    
-    // Wait for confirmation
-    if let Some(Ok(Message::Text(text))) = read.next().await {
-        let response: serde_json::Value = serde_json::from_str(&text)?;
-        
-        if response.get("success").and_then(|v| v.as_bool()).unwrap_or(false) {
-            let url = response.get("url").and_then(|v| v.as_str()).unwrap_or("unknown");
-            let reassigned = response.get("reassigned").and_then(|v| v.as_bool()).unwrap_or(false);
-            println!("\n╔══════════════════════════════════════════════════════════════╗");
-            println!("║  🚀 ZTunnel Active                                           ║");
-            println!("╠══════════════════════════════════════════════════════════════╣");
-            println!("║  Public URL: {:<47} ║", url);
-            println!("║  Local:      http://localhost:{:<34} ║", local_port);
-            if inspect {
-                println!("║  Inspector:  http://localhost:{:<34} ║", inspect_port);
+    let result = tokio::select! {
+        result = session::run_with_reconnect(relays, &mut ctx, on_registered) => {
+            if let Err(e) = &result {
+                error!("{}", e);
             }
-            println!("╚══════════════════════════════════════════════════════════════╝\n");
-            if reassigned {
-                println!("\x1b[33m⚠  Subdomain '{}' was taken, assigned '{}' instead\x1b[0m\n",
-                    subdomain.as_deref().unwrap_or("?"),
-                    response.get("subdomain").and_then(|v| v.as_str()).unwrap_or("?"));
-            }
-            println!("Press Ctrl+C to stop the tunnel\n");
-        } else {
-            let err = response.get("error").and_then(|v| v.as_str()).unwrap_or("Unknown error");
-            error!("Registration failed: {}", err);
-            return Err(anyhow::anyhow!("Registration failed: {}", err));
+            result
         }
-    }
-    
-    // Main tunnel loop
-    loop {
-        tokio::select! {
-            msg = read.next() => {
-                match msg {
-                    Some(Ok(Message::Binary(data))) => {
-                        let start = std::time::Instant::now();
-                        let throttle_clone = throttle.clone();
-                        if let Err(e) = handle_tunnel_request_with_inspector(
-                            &data, local_port, &mut write, &inspector, start, throttle_clone, latency
-                        ).await {
-                            warn!("Error handling request: {}", e);
-                        }
-                    }
-                    Some(Ok(Message::Ping(data))) => {
-                        write.send(Message::Pong(data)).await?;
-                    }
-                    Some(Ok(Message::Close(_))) | None => {
-                        info!("Connection closed");
-                        break;
-                    }
-                    Some(Err(e)) => {
-                        error!("WebSocket error: {}", e);
-                        break;
-                    }
-                    _ => {}
-                }
-            }
-            _ = tokio::signal::ctrl_c() => {
-                info!("Shutting down...");
-                write.send(Message::Close(None)).await?;
-                break;
-            }
+        _ = tokio::signal::ctrl_c() => {
+            info!("Shutting down...");
+            Ok(())
         }
+    };
+    if let Some(announcer) = announcer {
+        announcer.stop().await;
     }
-    
-    Ok(())
+    print_summary(&summary);
+    result
 }
 
-/// Handle tunnel request with inspector recording
-async fn handle_tunnel_request_with_inspector<S>(
-    data: &[u8],
-    local_port: u16,
-    write: &mut S,
-    inspector: &InspectorState,
-    start: std::time::Instant,
-    throttle: std::sync::Arc<tokio::sync::Mutex<Option<ztunnel_shared::throttle::BandwidthThrottle>>>,
-    latency: Option<std::time::Duration>,
-) -> Result<()>
-where
-    S: futures_util::Sink<Message> + Unpin,
-    S::Error: std::error::Error + Send + Sync + 'static,
-{
-    let request: tunnel::TunnelRequest = serde_json::from_slice(data)?;
-    info!("Proxying {} {} to localhost:{}", request.method, request.path, local_port);
-    
-    // Apply artificial latency
-    if let Some(delay) = latency {
-        tokio::time::sleep(delay).await;
-    }
-    
-    let mut stream = tokio::net::TcpStream::connect(format!("127.0.0.1:{}", local_port)).await?;
-    
-    let mut http_request = format!(
-        "{} {} HTTP/1.1\r\nHost: localhost:{}\r\n",
-        request.method, request.path, local_port
-    );
-    for (key, value) in &request.headers {
-        http_request.push_str(&format!("{}: {}\r\n", key, value));
-    }
-    if let Some(body) = &request.body {
-        http_request.push_str(&format!("Content-Length: {}\r\n", body.len()));
-    }
-    http_request.push_str("\r\n");
-    
-    stream.write_all(http_request.as_bytes()).await?;
-    if let Some(body) = &request.body {
-        stream.write_all(body).await?;
-    }
-    
-    // Read response
-    let mut buf = Vec::new();
-    let mut tmp = [0u8; 8192];
-    let mut header_end = None;
-    
-    for _ in 0..64 {
-        let n = stream.read(&mut tmp).await?;
-        if n == 0 { break; }
-        buf.extend_from_slice(&tmp[..n]);
-        if header_end.is_none() {
-            if let Some(pos) = find_header_end(&buf) {
-                header_end = Some(pos);
-                break;
-            }
+/// Run TCP tunnel
+async fn run_tcp_tunnel(relays: &[String], conf: config::TunnelConfig, auth_token: Option<String>) -> Result<()> {
+    let local = match &conf.gateway {
+        Some(gateway) => format!("gateway to {}", gateway.allow.join(", ")),
+        None => format!("localhost:{}", conf.local_port),
+    };
+    info!("TCP tunnel mode for {}", local);
+    let relays = &probe::rank(relays.to_vec()).await;
+    let gateway = conf.gateway.is_some();
+
+    // TCP streams aren't recorded by the inspector
+    let (entry_tx, _) = mpsc::channel::<InspectorEntry>(1);
+    let mut ctx = session::TunnelContext::new(conf, entry_tx);
+    ctx.auth_token = auth_token;
+
+    let on_registered = |reg: &session::Registration, attempts: u32| {
+        if attempts > 0 {
+            banner!("\x1b[32m✓ Reconnected after {} attempt(s): {}\x1b[0m\n", attempts, reg.url);
+            return;
         }
-    }
-    
-    let (status, headers, body) = if let Some(hend) = header_end {
-        let header_bytes = &buf[..hend];
-        let mut lines = header_bytes.split(|b| *b == b'\r' || *b == b'\n').filter(|l| !l.is_empty());
-        let status_line = lines.next().unwrap_or(&[]);
-        let status = parse_status_code(status_line).unwrap_or(200);
-        let mut headers_vec: Vec<(String, String)> = Vec::new();
-        let mut content_len: Option<usize> = None;
-        
-        for line in lines {
-            if let Some((k, v)) = split_header_kv(line) {
-                if k.eq_ignore_ascii_case("content-length") {
-                    if let Ok(cl) = v.trim().parse::<usize>() {
-                        content_len = Some(cl);
-                    }
-                }
-                headers_vec.push((k.to_string(), v.to_string()));
-            }
+        if logging::stdout_reserved() {
+            print_tunnel_output(reg, &local, None);
         }
-        
-        let mut body = buf[hend + 4..].to_vec();
-        if let Some(cl) = content_len {
-            while body.len() < cl {
-                let n = stream.read(&mut tmp).await?;
-                if n == 0 { break; }
-                body.extend_from_slice(&tmp[..n]);
-            }
-            if body.len() > cl {
-                body.truncate(cl);
-            }
+        banner!("\n╔══════════════════════════════════════════════════════════════╗");
+        banner!("║  🚀 ZTunnel TCP Active                                       ║");
+        banner!("╠══════════════════════════════════════════════════════════════╣");
+        banner!("║  Public:     {:<47} ║", reg.url);
+        if relays.len() > 1 {
+            banner!("║  Relay:      {:<47} ║", reg.relay);
+        }
+        banner!("║  Local:      {:<47} ║", local);
+        banner!("╚══════════════════════════════════════════════════════════════╝\n");
+        if gateway {
+            banner!("Connect with: ztunnel --relay {} socks {} --token ...\n", reg.relay, reg.subdomain);
+        } else {
+            banner!("Connect with: ztunnel --relay {} forward {} PORT\n", reg.relay, reg.subdomain);
         }
-        (status, headers_vec, body)
-    } else {
-        (200, Vec::new(), buf)
-    };
-    
-    let latency_ms = start.elapsed().as_millis() as u64;
-    let body_size = body.len();
-    
-    // Send tunnel response
-    let response = tunnel::TunnelResponse {
-        id: request.id.clone(),
-        status,
-        headers: headers.clone(),
-        body: Some(body.clone()),
     };
-    let response_data = serde_json::to_vec(&response)?;
-    write
-        .send(Message::Binary(response_data.into()))
-        .await
-        .map_err(|e| anyhow::anyhow!("Failed to send response: {}", e))?;
-    
-    // Apply bandwidth throttle
-    if let Some(ref mut t) = *throttle.lock().await {
-        t.throttle(body_size);
+
+    tokio::select! {
+        result = session::run_with_reconnect(relays, &mut ctx, on_registered) => {
+            if let Err(e) = &result {
+                error!("{}", e);
+            }
+            result
+        }
+        _ = tokio::signal::ctrl_c() => {
+            info!("Shutting down...");
+            Ok(())
+        }
     }
-    
-    // Record in inspector
-    let entry = InspectorEntry {
-        id: request.id,
-        timestamp: chrono::Utc::now().to_rfc3339(),
-        method: request.method,
-        path: request.path,
-        status,
-        latency_ms,
-        req_headers: request.headers,
-        req_body: request.body.map(|b| String::from_utf8_lossy(&b).to_string()),
-        res_headers: headers,
-        res_body: Some(String::from_utf8_lossy(&body).to_string()),
-        res_body_size: body_size,
-    };
-    inspector.record(entry).await;
-    
-    Ok(())
 }
 
-/// Replay a request against the local server
-async fn replay_local_request(entry: &InspectorEntry, local_port: u16) -> Result<()> {
-    use tokio::io::{AsyncWriteExt, AsyncReadExt};
+/// Record requests to a local service with no relay involved
+async fn run_proxy(port: u16, listen: u16, inspect_port: u16) -> Result<()> {
+    let listener = tokio::net::TcpListener::bind(("127.0.0.1", listen))
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to listen on port {}: {}", listen, e))?;
 
-    let mut stream = tokio::net::TcpStream::connect(format!("127.0.0.1:{}", local_port)).await?;
+    let (replay_tx, replay_rx) = mpsc::channel::<replay::ReplayRequest>(32);
+    let (entry_tx, mut entry_rx) = mpsc::channel::<InspectorEntry>(256);
+    let inspector = InspectorState::new(replay_tx);
+    let insp = inspector.clone();
+    tokio::spawn(async move { inspector::start_inspector(insp, inspect_port).await });
+    let insp_for_entries = inspector.clone();
+    tokio::spawn(async move {
+        while let Some(entry) = entry_rx.recv().await {
+            insp_for_entries.record(entry).await;
+        }
+    });
 
-    let mut http_request = format!(
-        "{} {} HTTP/1.1\r\nHost: localhost:{}\r\n",
-        entry.method, entry.path, local_port
-    );
-    for (key, value) in &entry.req_headers {
-        http_request.push_str(&format!("{}: {}\r\n", key, value));
+    let conf = config::TunnelConfig { name: "proxy".to_string(), local_port: port, ..Default::default() };
+    let mut ctx = session::TunnelContext::new(conf, entry_tx);
+    let summary = with_summary(&mut ctx);
+    let replay_targets = inspector.replay_targets();
+    replay_targets.insert(&ctx.conf.name, replay::ReplayTarget::for_tunnel(&ctx));
+    tokio::spawn(replay::serve(replay_rx, inspector.clone(), replay_targets));
+
+    banner!("\n╔══════════════════════════════════════════════════════════════╗");
+    banner!("║  📼 ZTunnel Local Proxy (no relay)                           ║");
+    banner!("╠══════════════════════════════════════════════════════════════╣");
+    banner!("║  Listening:  http://localhost:{:<34} ║", listen);
+    banner!("║  Local:      http://localhost:{:<34} ║", port);
+    banner!("║  Inspector:  http://localhost:{:<34} ║", inspect_port);
+    banner!("╚══════════════════════════════════════════════════════════════╝\n");
+
+    let result = tokio::select! {
+        result = ztunnel_client::record::serve(listener, std::sync::Arc::new(ctx)) => result,
+        _ = tokio::signal::ctrl_c() => {
+            info!("Shutting down...");
+            Ok(())
+        }
+    };
+    print_summary(&summary);
+    result
+}
+
+/// Count what the tunnel does alongside its other telemetry
+fn with_summary(ctx: &mut session::TunnelContext) -> std::sync::Arc<Summary> {
+    let summary = std::sync::Arc::new(Summary::new());
+    ctx.telemetry = std::sync::Arc::new(Fanout(vec![ctx.telemetry.clone(), summary.clone()]));
+    summary
+}
+
+/// Print what the tunnel did, once it has stopped
+fn print_summary(summary: &Summary) {
+    banner!("\n\x1b[1mSession summary\x1b[0m");
+    for line in summary.report() {
+        banner!("  {}", line);
     }
-    http_request.push_str("\r\n");
+    banner!("");
+}
+
+/// Serve a TCP tunnel from the first relay on a local port
+async fn run_forward(relays: &[String], tunnel: String, bind: &str, port: u16) -> Result<()> {
+    let relay = relays.first().cloned().unwrap_or_default();
+    let listener = tokio::net::TcpListener::bind((bind, port))
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to listen on {}:{}: {}", bind, port, e))?;
+    banner!("\n\x1b[1;36m↪ Forwarding tunnel '{}' to {}:{}\x1b[0m (Ctrl-C to stop)\n", tunnel, bind, port);
 
-    stream.write_all(http_request.as_bytes()).await?;
-    if let Some(body) = &entry.req_body {
-        stream.write_all(body.as_bytes()).await?;
+    tokio::select! {
+        result = connect::forward(listener, relay, tunnel) => result,
+        _ = tokio::signal::ctrl_c() => Ok(()),
     }
+}
 
-    let mut response = vec![0u8; 65536];
-    let n = stream.read(&mut response).await?;
-    info!("Replay response: {} bytes", n);
+/// Serve a local SOCKS5 proxy through a gateway tunnel on the first relay
+async fn run_socks(relays: &[String], tunnel: String, token: String, listen: &str) -> Result<()> {
+    let relay = relays.first().cloned().unwrap_or_default();
+    let listener = tokio::net::TcpListener::bind(listen)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to listen on {}: {}", listen, e))?;
+    banner!("\n\x1b[1;36m🧦 SOCKS5 proxy on {} through tunnel '{}'\x1b[0m", listen, tunnel);
+    banner!("  e.g. curl --socks5-hostname {} http://HOST:PORT/ (Ctrl-C to stop)\n", listen);
 
-    Ok(())
+    tokio::select! {
+        result = socks::serve(listener, relay, tunnel, token) => result,
+        _ = tokio::signal::ctrl_c() => Ok(()),
+    }
 }
 
-// Helper functions (pub(crate) for use in multi.rs)
-pub(crate) fn find_header_end(buf: &[u8]) -> Option<usize> {
-    let pat = b"\r\n\r\n";
-    buf.windows(4).position(|w| w == pat)
+/// `--output json` result for `http` and `tcp`
+#[derive(serde::Serialize)]
+struct TunnelOutput<'a> {
+    /// Relay-side tunnel ID (the assigned subdomain)
+    tunnel_id: &'a str,
+    url: &'a str,
+    relay: &'a str,
+    local: &'a str,
+    inspector_url: Option<String>,
 }
 
-pub(crate) fn parse_status_code(line: &[u8]) -> Option<u16> {
-    let s = std::str::from_utf8(line).ok()?;
-    let parts: Vec<&str> = s.split_whitespace().collect();
-    if parts.len() >= 2 {
-        parts[1].parse::<u16>().ok()
-    } else {
-        None
+/// Print the `--output json` result as one line on stdout
+fn print_tunnel_output(reg: &session::Registration, local: &str, inspector_url: Option<String>) {
+    let out = TunnelOutput { tunnel_id: &reg.subdomain, url: &reg.url, relay: &reg.relay, local, inspector_url };
+    if let Ok(json) = serde_json::to_string(&out) {
+        println!("{}", json);
     }
 }
 
-pub(crate) fn split_header_kv(line: &[u8]) -> Option<(&str, &str)> {
-    let s = std::str::from_utf8(line).ok()?;
-    let mut iter = s.splitn(2, ':');
-    let k = iter.next()?.trim();
-    let v = iter.next()?.trim();
-    Some((k, v))
+/// Result of probing the relay's /health endpoint
+#[derive(serde::Serialize)]
+struct RelayHealth {
+    url: String,
+    /// online, degraded, or offline
+    state: &'static str,
+    detail: Option<String>,
+    active_tunnels: Option<u64>,
 }
 
-/// Run TCP tunnel
-async fn run_tcp_tunnel(relay_url: &str, local_port: u16) -> Result<()> {
-    info!("TCP tunnel mode for port {}", local_port);
-    
-    let (ws_stream, _) = connect_async(relay_url)
-        .await
-        .context("Failed to connect to relay server")?;
-    
-    let (mut write, mut read) = ws_stream.split();
-    
-    let registration = serde_json::json!({
-        "type": "tcp",
-        "local_port": local_port,
-    });
-    
-    write.send(Message::Text(registration.to_string().into())).await?;
-    
-    if let Some(Ok(Message::Text(text))) = read.next().await {
-        let response: serde_json::Value = serde_json::from_str(&text)?;
-        
-        if response.get("success").and_then(|v| v.as_bool()).unwrap_or(false) {
-            let url = response.get("url").and_then(|v| v.as_str()).unwrap_or("unknown");
-            println!("\n╔══════════════════════════════════════════════════════════════╗");
-            println!("║  🚀 ZTunnel TCP Active                                       ║");
-            println!("╠══════════════════════════════════════════════════════════════╣");
-            println!("║  Public:     {:<47} ║", url);
-            println!("║  Local:      localhost:{:<38} ║", local_port);
-            println!("╚══════════════════════════════════════════════════════════════╝\n");
+async fn check_relay(relay_url: &str) -> RelayHealth {
+    let health_url = format!("{}/health", relay_url.trim_end_matches('/'));
+    let mut health = RelayHealth {
+        url: relay_url.to_string(),
+        state: "offline",
+        detail: None,
+        active_tunnels: None,
+    };
+    match reqwest::get(&health_url).await {
+        Ok(resp) if resp.status().is_success() => {
+            health.state = "online";
+            let body = resp.text().await.unwrap_or_default();
+            if let Ok(json) = serde_json::from_str::<serde_json::Value>(&body) {
+                health.detail = json["status"].as_str().map(String::from);
+                health.active_tunnels = json["active_tunnels"].as_u64();
+            }
         }
+        Ok(resp) => {
+            health.state = "degraded";
+            health.detail = Some(format!("HTTP {}", resp.status()));
+        }
+        Err(e) => health.detail = Some(e.to_string()),
     }
-    
-    loop {
-        tokio::select! {
-            msg = read.next() => {
-                match msg {
-                    Some(Ok(Message::Binary(data))) => {
-                        if let Ok(mut stream) = tokio::net::TcpStream::connect(
-                            format!("127.0.0.1:{}", local_port)
-                        ).await {
-                            let _ = stream.write_all(&data).await;
-                            let mut response = vec![0u8; 65536];
-                            if let Ok(n) = stream.read(&mut response).await {
-                                response.truncate(n);
-                                let _ = write.send(Message::Binary(response.into())).await;
-                            }
-                        }
-                    }
-                    Some(Ok(Message::Close(_))) | None => {
-                        break;
-                    }
-                    _ => {}
-                }
-            }
-            _ = tokio::signal::ctrl_c() => {
-                info!("Shutting down...");
-                break;
-            }
+    health
+}
+
+/// Tunnels of the running daemon, if there is one
+#[cfg(unix)]
+async fn daemon_tunnels() -> Option<(u32, Vec<multi::TunnelInfo>)> {
+    let paths = daemon::DaemonPaths::from_env();
+    let pid = daemon::running_pid(&paths)?;
+    let resp = daemon::request(&paths, &daemon::ControlRequest::List).await.ok()?;
+    Some((pid, resp.tunnels))
+}
+
+#[cfg(not(unix))]
+async fn daemon_tunnels() -> Option<(u32, Vec<multi::TunnelInfo>)> {
+    None
+}
+
+/// Print relay latencies, fastest first
+async fn run_ping_relays(relays: &[String]) -> Result<()> {
+    let probes = probe::probe_all(relays).await;
+
+    println!("\n\x1b[1;36m⚡ Relay latency\x1b[0m\n");
+    println!("    {:<44} {:>9} {:>9}", "RELAY", "CONNECT", "RTT");
+    for (i, p) in probes.iter().enumerate() {
+        let marker = if i == 0 && p.latency().is_some() { "\x1b[32m★\x1b[0m" } else { " " };
+        print!("  {} {:<44} {:>9} {:>9}", marker, p.url, probe::format_ms(p.connect), probe::format_ms(p.rtt));
+        match &p.error {
+            Some(e) => println!("  \x1b[31m{}\x1b[0m", e),
+            None => println!(),
         }
     }
-    
+    println!();
+
+    if probes.iter().all(|p| p.latency().is_none()) {
+        anyhow::bail!("No relay is reachable");
+    }
     Ok(())
 }
 
-/// Show tunnel status and relay health
-async fn run_status(relay_url: &str) -> Result<()> {
+/// Show daemon tunnels and relay health
+async fn run_status(relay_url: &str, json: bool) -> Result<()> {
+    let daemon = daemon_tunnels().await;
+    let relay = check_relay(relay_url).await;
+
+    if json {
+        let daemon = daemon.as_ref().map(|(pid, tunnels)| {
+            serde_json::json!({ "pid": pid, "tunnels": tunnels })
+        });
+        let out = serde_json::json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "daemon": daemon,
+            "relay": relay,
+        });
+        println!("{}", serde_json::to_string_pretty(&out)?);
+        return Ok(());
+    }
+
     println!("\n\x1b[1;36m⚡ ZTunnel Status\x1b[0m\n");
 
-    // Check relay health
-    let health_url = format!("{}/health", relay_url.trim_end_matches('/'));
-    print!("  Relay ({})  ", relay_url);
-    match reqwest::get(&health_url).await {
-        Ok(resp) if resp.status().is_success() => {
-            if let Ok(body) = resp.text().await {
-                if let Ok(json) = serde_json::from_str::<serde_json::Value>(&body) {
-                    let tunnels = json["active_tunnels"].as_u64().unwrap_or(0);
-                    let status = json["status"].as_str().unwrap_or("unknown");
-                    println!("\x1b[32m● online\x1b[0m  ({}, {} tunnels)", status, tunnels);
-                } else {
-                    println!("\x1b[32m● online\x1b[0m");
+    match &daemon {
+        Some((pid, tunnels)) => {
+            println!("  Daemon      \x1b[32m● running\x1b[0m (pid {}, {} tunnel(s))", pid, tunnels.len());
+            if !tunnels.is_empty() {
+                println!();
+                println!("  {:<14} {:<5} {:<36} {:<22} {:>8} {:>8}", "NAME", "PROTO", "URL", "LOCAL", "UPTIME", "REQS");
+                for t in tunnels {
+                    println!(
+                        "  {:<14} {:<5} {:<36} {:<22} {:>8} {:>8}",
+                        t.name,
+                        t.proto,
+                        t.url.as_deref().unwrap_or("(connecting)"),
+                        t.target,
+                        multi::format_uptime(t.uptime_secs),
+                        t.requests
+                    );
+                    if t.paused {
+                        println!("  \x1b[33m  ⏸ paused; answering 503 until `ztunnel resume {}`\x1b[0m", t.name);
+                    }
+                    if t.local_failures > 0 {
+                        println!("  \x1b[33m  ⚠ {} is not answering ({} failed connection(s))\x1b[0m", t.target, t.local_failures);
+                    }
                 }
+                println!();
             }
         }
-        Ok(resp) => println!("\x1b[33m● degraded\x1b[0m (HTTP {})", resp.status()),
-        Err(e) => println!("\x1b[31m● offline\x1b[0m ({})", e),
+        None => println!("  Daemon      \x1b[90m○ not running\x1b[0m"),
+    }
+
+    // Relay health
+    print!("  Relay ({})  ", relay.url);
+    let detail = relay.detail.as_deref().unwrap_or("");
+    match relay.state {
+        "online" => match relay.active_tunnels {
+            Some(n) => println!("\x1b[32m● online\x1b[0m  ({}, {} tunnels)", detail, n),
+            None => println!("\x1b[32m● online\x1b[0m"),
+        },
+        "degraded" => println!("\x1b[33m● degraded\x1b[0m ({})", detail),
+        _ => println!("\x1b[31m● offline\x1b[0m ({})", detail),
     }
 
     // Show version
@@ -601,6 +1319,90 @@ async fn run_status(relay_url: &str) -> Result<()> {
     Ok(())
 }
 
+/// Stop one daemon tunnel, or the daemon itself
+#[cfg(unix)]
+async fn run_stop(name: Option<String>) -> Result<()> {
+    let paths = daemon::DaemonPaths::from_env();
+    let request = match &name {
+        Some(name) => daemon::ControlRequest::Remove { name: name.clone() },
+        None => daemon::ControlRequest::Stop,
+    };
+    let resp = daemon::request(&paths, &request).await?;
+    if !resp.ok {
+        anyhow::bail!(resp.error.unwrap_or_else(|| "Daemon refused the request".to_string()));
+    }
+    match name {
+        Some(name) => println!("\x1b[32m✓ Stopped tunnel '{}'\x1b[0m", name),
+        None => println!("\x1b[32m✓ ZTunnel daemon stopped\x1b[0m"),
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn run_stop(_name: Option<String>) -> Result<()> {
+    anyhow::bail!("Daemon mode is only supported on unix platforms")
+}
+
+/// Reconnect one tunnel of the running daemon
+#[cfg(unix)]
+async fn run_restart(name: &str) -> Result<()> {
+    let paths = daemon::DaemonPaths::from_env();
+    let resp = daemon::request(&paths, &daemon::ControlRequest::Restart { name: name.to_string() }).await?;
+    if !resp.ok {
+        anyhow::bail!(resp.error.unwrap_or_else(|| "Daemon refused the request".to_string()));
+    }
+    println!("\x1b[32m✓ Restarted tunnel '{}'\x1b[0m", name);
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn run_restart(_name: &str) -> Result<()> {
+    anyhow::bail!("Daemon mode is only supported on unix platforms")
+}
+
+/// Pause a tunnel (`pause` is `Some`, with an optional page) or resume
+/// it: the daemon's tunnel if the daemon is running, else through the
+/// inspector of a `ztunnel http` or `ztunnel start` in the foreground
+async fn run_pause(name: &str, pause: Option<Option<String>>, inspect_port: u16) -> Result<()> {
+    #[cfg(unix)]
+    {
+        let paths = daemon::DaemonPaths::from_env();
+        if daemon::running_pid(&paths).is_some() {
+            let request = match pause.clone() {
+                Some(page) => daemon::ControlRequest::Pause { name: name.to_string(), page },
+                None => daemon::ControlRequest::Resume { name: name.to_string() },
+            };
+            let resp = daemon::request(&paths, &request).await?;
+            if !resp.ok {
+                anyhow::bail!(resp.error.unwrap_or_else(|| "Daemon refused the request".to_string()));
+            }
+            print_paused(name, pause.is_some());
+            return Ok(());
+        }
+    }
+    let client = ztunnel_client::api::InspectorClient::new(inspect_port);
+    let sent = match pause.clone() {
+        Some(page) => client.pause_tunnel(name, page).await,
+        None => client.resume_tunnel(name).await,
+    };
+    if let Err(e) = sent {
+        if e.downcast_ref::<reqwest::Error>().is_some_and(reqwest::Error::is_connect) {
+            anyhow::bail!("No daemon running, and no inspector on port {}", inspect_port);
+        }
+        return Err(e);
+    }
+    print_paused(name, pause.is_some());
+    Ok(())
+}
+
+fn print_paused(name: &str, paused: bool) {
+    if paused {
+        println!("\x1b[33m⏸ Paused tunnel '{}'; requests get 503 until `ztunnel resume {}`\x1b[0m", name, name);
+    } else {
+        println!("\x1b[32m✓ Resumed tunnel '{}'\x1b[0m", name);
+    }
+}
+
 /// Check for updates from GitHub releases
 async fn run_update(check_only: bool) -> Result<()> {
     let current = env!("CARGO_PKG_VERSION");
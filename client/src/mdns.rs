@@ -0,0 +1,434 @@
+//! LAN announcement over mDNS / DNS-SD
+//!
+//! With `mdns` in the config (or `--mdns`), each tunnel is announced as
+//! a `_ztunnel._tcp` service whose TXT record carries its public URL, so
+//! teammates find it with any service browser (`dns-sd -B _ztunnel._tcp`,
+//! `avahi-browse -r _ztunnel._tcp`) instead of asking for the link. With
+//! `mdns.inspector` the dashboard is announced too, as `_http._tcp`.
+//!
+//! This is a small responder, not a full mDNS stack: it shares port
+//! 5353 with the system's daemon, announces on start and on every
+//! change, answers queries naming any of its records with all of them,
+//! and says goodbye (TTL 0) when stopped. Only IPv4 is announced.
+
+use anyhow::{Context, Result};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+/// Service type tunnels are announced under
+pub const TUNNEL_SERVICE: &str = "_ztunnel._tcp";
+/// Service type for the inspector dashboard
+pub const HTTP_SERVICE: &str = "_http._tcp";
+
+const GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const PORT: u16 = 5353;
+
+/// RFC 6762 TTLs: records naming a host, and everything else
+const HOST_TTL: u32 = 120;
+const OTHER_TTL: u32 = 4500;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+/// Set on records only this host answers for
+const CACHE_FLUSH: u16 = 0x8000;
+
+/// One announced service
+#[derive(Debug, Clone, PartialEq)]
+pub struct Service {
+    /// Human-readable instance name, e.g. `web (alice-laptop)`
+    pub instance: String,
+    /// `TUNNEL_SERVICE` or `HTTP_SERVICE`
+    pub service_type: &'static str,
+    pub port: u16,
+    /// `key=value` entries
+    pub txt: Vec<String>,
+}
+
+impl Service {
+    /// A tunnel, with its URL and local target in the TXT record
+    pub fn tunnel(name: &str, host: &str, proto: &str, url: &str, local: &str) -> Self {
+        Self {
+            instance: format!("{} ({})", name, host),
+            service_type: TUNNEL_SERVICE,
+            port: local.rsplit_once(':').and_then(|(_, port)| port.parse().ok()).unwrap_or(0),
+            txt: vec![format!("url={}", url), format!("proto={}", proto), format!("local={}", local)],
+        }
+    }
+
+    /// The inspector dashboard on `port`
+    pub fn inspector(host: &str, port: u16) -> Self {
+        Self {
+            instance: format!("ztunnel inspector ({})", host),
+            service_type: HTTP_SERVICE,
+            port,
+            txt: vec!["path=/".to_string()],
+        }
+    }
+}
+
+/// Runs the responder until stopped; `set` changes what it announces
+pub struct Announcer {
+    services: watch::Sender<Vec<Service>>,
+    task: JoinHandle<()>,
+}
+
+impl Announcer {
+    /// Join the mDNS group and start answering for `ztunnel-HOST.local`
+    pub fn start(host: &str) -> Result<Self> {
+        let ip = lan_ip().context("No LAN address to announce")?;
+        let socket = bind().context("Could not join the mDNS group")?;
+        let hostname = format!("ztunnel-{}.local", label(host));
+        info!("Announcing on the LAN over mDNS as {} ({})", hostname, ip);
+        let (services, rx) = watch::channel(Vec::new());
+        let task = tokio::spawn(respond(socket, Records { hostname, ip }, rx));
+        Ok(Self { services, task })
+    }
+
+    /// Announce `services` instead of what was announced before
+    pub fn set(&self, services: Vec<Service>) {
+        self.services.send_if_modified(|current| {
+            let changed = *current != services;
+            *current = services;
+            changed
+        });
+    }
+
+    /// Withdraw everything and stop answering
+    pub async fn stop(self) {
+        drop(self.services);
+        let _ = self.task.await;
+    }
+}
+
+/// This machine's name as a DNS label, for instance and host names
+pub fn hostname() -> String {
+    let name = std::env::var("HOSTNAME")
+        .or_else(|_| std::env::var("COMPUTERNAME"))
+        .or_else(|_| std::fs::read_to_string("/proc/sys/kernel/hostname"))
+        .or_else(|_| std::fs::read_to_string("/etc/hostname"))
+        .unwrap_or_default();
+    let name = name.trim().split('.').next().unwrap_or_default();
+    if name.is_empty() { "ztunnel".to_string() } else { name.to_string() }
+}
+
+/// Lowercase letters, digits and hyphens only
+fn label(name: &str) -> String {
+    let label: String =
+        name.chars().map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '-' }).collect();
+    label.trim_matches('-').chars().take(50).collect()
+}
+
+/// The address multicast leaves from
+fn lan_ip() -> Result<Ipv4Addr> {
+    let probe = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    probe.connect((GROUP, PORT))?;
+    match probe.local_addr()? {
+        SocketAddr::V4(addr) if !addr.ip().is_unspecified() => Ok(*addr.ip()),
+        _ => anyhow::bail!("no IPv4 route to {}", GROUP),
+    }
+}
+
+/// Port 5353, shared with any other responder on this machine
+fn bind() -> Result<UdpSocket> {
+    use socket2::{Domain, Protocol, Socket, Type};
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, PORT).into())?;
+    socket.join_multicast_v4(&GROUP, &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_multicast_loop_v4(true)?;
+    socket.set_multicast_ttl_v4(255)?;
+    socket.set_nonblocking(true)?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+/// Announce, answer, and re-announce on changes until the announcer
+/// goes away
+async fn respond(socket: UdpSocket, records: Records, mut services: watch::Receiver<Vec<Service>>) {
+    let group = SocketAddr::from((GROUP, PORT));
+    let mut buf = vec![0u8; 9000];
+    let mut current: Vec<Service> = Vec::new();
+    // Announcements go out twice, a second apart (RFC 6762 8.3)
+    let mut repeat = None;
+    loop {
+        let repeat_at = repeat.unwrap_or_else(|| tokio::time::Instant::now() + Duration::from_secs(3600));
+        tokio::select! {
+            changed = services.changed() => {
+                if changed.is_err() {
+                    break;
+                }
+                // Names no longer announced are withdrawn first
+                let gone: Vec<Service> = current.iter().filter(|s| !services.borrow().contains(s)).cloned().collect();
+                if !gone.is_empty() {
+                    send(&socket, &records.packet(&gone, 0, None), group).await;
+                }
+                current = services.borrow_and_update().clone();
+                if !current.is_empty() {
+                    send(&socket, &records.packet(&current, 1, None), group).await;
+                    repeat = Some(tokio::time::Instant::now() + Duration::from_secs(1));
+                }
+            }
+            _ = tokio::time::sleep_until(repeat_at), if repeat.is_some() => {
+                repeat = None;
+                send(&socket, &records.packet(&current, 1, None), group).await;
+            }
+            received = socket.recv_from(&mut buf) => {
+                let Ok((n, from)) = received else { continue };
+                let Some(query) = Query::parse(&buf[..n]) else { continue };
+                if current.is_empty() || !query.names.iter().any(|name| records.answers(&current, name)) {
+                    continue;
+                }
+                if from.port() == PORT {
+                    send(&socket, &records.packet(&current, 1, None), group).await;
+                } else {
+                    // One-shot query from an ordinary resolver, answered
+                    // directly (RFC 6762 6.7)
+                    send(&socket, &records.packet(&current, 1, Some(&query)), from).await;
+                }
+            }
+        }
+    }
+    if !current.is_empty() {
+        send(&socket, &records.packet(&current, 0, None), group).await;
+    }
+}
+
+async fn send(socket: &UdpSocket, packet: &[u8], to: SocketAddr) {
+    if let Err(e) = socket.send_to(packet, to).await {
+        debug!("mDNS send to {} failed: {}", to, e);
+    }
+}
+
+/// This host's name and address, which every service points at
+struct Records {
+    hostname: String,
+    ip: Ipv4Addr,
+}
+
+impl Records {
+    /// Whether a query for `name` is about anything announced
+    fn answers(&self, services: &[Service], name: &str) -> bool {
+        let is = |other: &str| name.eq_ignore_ascii_case(other.trim_end_matches('.'));
+        is("_services._dns-sd._udp.local")
+            || is(&self.hostname)
+            || services.iter().any(|s| is(&format!("{}.local", s.service_type)) || is(&instance_name(s)))
+    }
+
+    /// A response carrying every record, with TTLs scaled by `live` (0
+    /// withdraws them). `legacy` is a unicast query to echo.
+    fn packet(&self, services: &[Service], live: u32, legacy: Option<&Query>) -> Vec<u8> {
+        let mut out = Vec::with_capacity(512);
+        out.extend(legacy.map_or(0, |q| q.id).to_be_bytes());
+        out.extend(0x8400u16.to_be_bytes());
+        out.extend(legacy.map_or(0, |q| q.names.len() as u16).to_be_bytes());
+        let count_at = out.len();
+        out.extend([0; 6]);
+        if let Some(query) = legacy {
+            out.extend(&query.question);
+        }
+
+        let mut count = 0u16;
+        let mut record = |out: &mut Vec<u8>, name: &str, rtype: u16, unique: bool, ttl: u32, rdata: &[u8]| {
+            write_name(out, name);
+            out.extend(rtype.to_be_bytes());
+            // Legacy answers mustn't carry the cache-flush bit
+            let class = if unique && legacy.is_none() { CLASS_IN | CACHE_FLUSH } else { CLASS_IN };
+            out.extend(class.to_be_bytes());
+            out.extend((ttl * live).to_be_bytes());
+            out.extend((rdata.len() as u16).to_be_bytes());
+            out.extend(rdata);
+            count += 1;
+        };
+
+        let mut types: Vec<&str> = services.iter().map(|s| s.service_type).collect();
+        types.sort();
+        types.dedup();
+        for service_type in types {
+            let mut rdata = Vec::new();
+            write_name(&mut rdata, &format!("{}.local", service_type));
+            record(&mut out, "_services._dns-sd._udp.local", TYPE_PTR, false, OTHER_TTL, &rdata);
+        }
+        for service in services {
+            let instance = instance_name(service);
+            let mut rdata = Vec::new();
+            write_name(&mut rdata, &instance);
+            record(&mut out, &format!("{}.local", service.service_type), TYPE_PTR, false, OTHER_TTL, &rdata);
+
+            let mut rdata = vec![0, 0, 0, 0];
+            rdata.extend(service.port.to_be_bytes());
+            write_name(&mut rdata, &self.hostname);
+            record(&mut out, &instance, TYPE_SRV, true, HOST_TTL, &rdata);
+
+            let mut rdata = Vec::new();
+            for entry in &service.txt {
+                let entry = &entry.as_bytes()[..entry.len().min(255)];
+                rdata.push(entry.len() as u8);
+                rdata.extend(entry);
+            }
+            if rdata.is_empty() {
+                rdata.push(0);
+            }
+            record(&mut out, &instance, TYPE_TXT, true, OTHER_TTL, &rdata);
+        }
+        record(&mut out, &self.hostname, TYPE_A, true, HOST_TTL, &self.ip.octets());
+
+        out[count_at..count_at + 2].copy_from_slice(&count.to_be_bytes());
+        out
+    }
+}
+
+/// `Instance Name._type._tcp.local`; the instance label may hold spaces
+/// and dots of its own
+fn instance_name(service: &Service) -> String {
+    format!("{}\u{0}{}.local", service.instance, service.service_type)
+}
+
+/// Uncompressed DNS name. The instance label, up to a NUL from
+/// `instance_name`, stays one label whatever it contains.
+fn write_name(out: &mut Vec<u8>, name: &str) {
+    let (first, rest) = match name.split_once('\u{0}') {
+        Some((instance, rest)) => (Some(instance), rest),
+        None => (None, name),
+    };
+    for label in first.into_iter().chain(rest.split('.').filter(|l| !l.is_empty())) {
+        let label = &label.as_bytes()[..label.len().min(63)];
+        out.push(label.len() as u8);
+        out.extend(label);
+    }
+    out.push(0);
+}
+
+/// The parts of an incoming query the responder looks at
+struct Query {
+    id: u16,
+    /// Names asked about, dot-separated without the trailing dot
+    names: Vec<String>,
+    /// The raw question section, echoed in legacy answers
+    question: Vec<u8>,
+}
+
+impl Query {
+    fn parse(packet: &[u8]) -> Option<Self> {
+        let header = packet.get(..12)?;
+        let flags = u16::from_be_bytes([header[2], header[3]]);
+        // Responses and non-standard queries are ignored
+        if flags & 0xF800 != 0 {
+            return None;
+        }
+        let questions = u16::from_be_bytes([header[4], header[5]]);
+        let mut names = Vec::new();
+        let mut at = 12;
+        for _ in 0..questions {
+            let (name, next) = read_name(packet, at)?;
+            names.push(name);
+            at = next + 4;
+        }
+        Some(Self { id: u16::from_be_bytes([header[0], header[1]]), names, question: packet.get(12..at)?.to_vec() })
+    }
+}
+
+/// Read a possibly compressed name at `at`, returning it with the
+/// offset just past it
+fn read_name(packet: &[u8], mut at: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    // Bounds pointer loops
+    for _ in 0..64 {
+        let len = *packet.get(at)? as usize;
+        match len {
+            0 => {
+                let name = labels.join(".");
+                return Some((name, end.unwrap_or(at + 1)));
+            }
+            l if l & 0xC0 == 0xC0 => {
+                let pointer = ((l & 0x3F) << 8) | *packet.get(at + 1)? as usize;
+                end.get_or_insert(at + 2);
+                at = pointer;
+            }
+            l => {
+                labels.push(String::from_utf8_lossy(packet.get(at + 1..at + 1 + l)?).into_owned());
+                at += 1 + l;
+            }
+        }
+    }
+    None
+}
+
+/// Start announcing, or say why not; a missing LAN isn't fatal
+pub fn start_or_warn(host: &str) -> Option<Announcer> {
+    match Announcer::start(host) {
+        Ok(announcer) => Some(announcer),
+        Err(e) => {
+            warn!("mDNS announcement disabled: {:#}", e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records() -> Records {
+        Records { hostname: "ztunnel-alice.local".into(), ip: Ipv4Addr::new(192, 168, 1, 20) }
+    }
+
+    fn query(name: &str) -> Vec<u8> {
+        let mut packet = vec![0x12, 0x34, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        write_name(&mut packet, name);
+        packet.extend([0, 12, 0, 1]);
+        packet
+    }
+
+    #[test]
+    fn test_announcement_records() {
+        let services = vec![Service::tunnel("web", "alice", "http", "https://web.example.com", "localhost:3000")];
+        let packet = records().packet(&services, 1, None);
+        // Shared type PTR, instance PTR, SRV, TXT, A
+        assert_eq!(&packet[6..8], &[0, 5]);
+        let (name, at) = read_name(&packet, 12).unwrap();
+        assert_eq!(name, "_services._dns-sd._udp.local");
+        let (target, _) = read_name(&packet, at + 10).unwrap();
+        assert_eq!(target, "_ztunnel._tcp.local");
+        assert!(packet.windows(28).any(|w| w == b"\x1burl=https://web.example.com"));
+        assert!(packet.ends_with(&[0, 1, 0x80, 1, 0, 0, 0, 120, 0, 4, 192, 168, 1, 20]));
+
+        // Goodbye: same records, TTL 0
+        let goodbye = records().packet(&services, 0, None);
+        assert!(goodbye.ends_with(&[0, 1, 0x80, 1, 0, 0, 0, 0, 0, 4, 192, 168, 1, 20]));
+    }
+
+    #[test]
+    fn test_query_matching() {
+        let services = vec![Service::inspector("alice", 4040)];
+        let records = records();
+        let asked = |name: &str| Query::parse(&query(name)).unwrap().names.iter().any(|n| records.answers(&services, n));
+        assert!(asked("_http._tcp.local"));
+        assert!(asked("_services._dns-sd._udp.local"));
+        assert!(asked("ZTUNNEL-ALICE.local"));
+        assert!(!asked("_ztunnel._tcp.local"));
+        assert!(!asked("_printer._tcp.local"));
+
+        // Compressed names in later questions
+        let mut packet = query("_http._tcp.local");
+        packet[5] = 2;
+        packet.extend([4, b'w', b'e', b'b', b'1', 0xC0, 12, 0, 12, 0, 1]);
+        let parsed = Query::parse(&packet).unwrap();
+        assert_eq!(parsed.names, ["_http._tcp.local", "web1._http._tcp.local"]);
+        assert_eq!(parsed.question.len(), packet.len() - 12);
+
+        // Legacy answers echo the id and question, without cache-flush
+        let answer = records.packet(&services, 1, Some(&parsed));
+        assert_eq!(&answer[..6], &[0x12, 0x34, 0x84, 0, 0, 2]);
+        assert!(answer.ends_with(&[0, 1, 0, 1, 0, 0, 0, 120, 0, 4, 192, 168, 1, 20]));
+        assert_eq!(label("Alice's MacBook.lan"), "alice-s-macbook-lan");
+    }
+}
@@ -2,310 +2,430 @@
 //!
 //! Spawns and manages multiple tunnel connections from a single
 //! configuration file, with shared inspector and graceful shutdown.
+//! Tunnels are keyed by name so the daemon can add and remove them
+//! while the others keep running.
 
 use crate::config::{TunnelConfig, ZTunnelConfig};
-use crate::inspector::{InspectorEntry, InspectorState};
+use crate::inspector::InspectorEntry;
+use crate::inspector::InspectorState;
+use crate::intercept::Intercept;
+use crate::logging::banner;
+use crate::pause::{Pause, Pauses};
+use crate::probe;
+use crate::replay::{ReplayTarget, ReplayTargets};
+use crate::session::{self, Registration, TunnelContext};
 use anyhow::Result;
-use futures_util::{SinkExt, StreamExt};
-use tokio::sync::mpsc;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::{mpsc, oneshot};
 use tokio::task::JoinHandle;
-use tokio_tungstenite::{connect_async, tungstenite::Message};
 use tracing::{error, info, warn};
 
+/// Snapshot of a running tunnel
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TunnelInfo {
+    pub name: String,
+    pub proto: String,
+    pub target: String,
+    /// Public URL, once registered with the relay
+    pub url: Option<String>,
+    /// Relay currently carrying the tunnel
+    #[serde(default)]
+    pub relay: Option<String>,
+    pub uptime_secs: u64,
+    /// HTTP requests handled / TCP connections opened
+    pub requests: u64,
+    /// Failed connections to the local service since the last success
+    #[serde(default)]
+    pub local_failures: u64,
+    /// Answering 503 until resumed
+    #[serde(default)]
+    pub paused: bool,
+}
+
+/// A tunnel task and the state it reports back
+struct RunningTunnel {
+    conf: TunnelConfig,
+    target: String,
+    registration: Arc<Mutex<Option<Registration>>>,
+    requests: Arc<AtomicU64>,
+    local_failures: Arc<AtomicU64>,
+    pause: Pause,
+    started: Instant,
+    handle: JoinHandle<()>,
+}
+
 /// Manages multiple tunnel connections
 pub struct TunnelManager {
     config: ZTunnelConfig,
-    inspector: InspectorState,
     inspector_tx: mpsc::Sender<InspectorEntry>,
-    handles: Vec<JoinHandle<()>>,
+    /// Held requests, WebSocket frames, and clearing on reconnect
+    inspector: InspectorState,
+    /// Where inspector replays of each tunnel's requests go
+    replay_targets: ReplayTargets,
+    /// Pause switches, for the inspector
+    pauses: Pauses,
+    /// Relay URLs in the order tunnels try them
+    relays: Vec<String>,
+    tunnels: Vec<RunningTunnel>,
 }
 
 impl TunnelManager {
-    pub fn new(config: ZTunnelConfig, inspector: InspectorState, inspector_tx: mpsc::Sender<InspectorEntry>) -> Self {
+    pub fn new(
+        config: ZTunnelConfig,
+        inspector_tx: mpsc::Sender<InspectorEntry>,
+        inspector: InspectorState,
+    ) -> Self {
         Self {
+            relays: config.relay_urls(),
             config,
-            inspector,
             inspector_tx,
-            handles: Vec::new(),
+            replay_targets: inspector.replay_targets(),
+            pauses: inspector.pauses(),
+            inspector,
+            tunnels: Vec::new(),
         }
     }
 
-    /// Start all tunnels defined in the configuration
+    /// Start all tunnels defined in the configuration, on the fastest relay
     pub async fn start_all(&mut self) -> Result<()> {
-        println!("\n╔══════════════════════════════════════════════════════════════╗");
-        println!("║  🚀 ZTunnel Multi-Tunnel Mode                                ║");
-        println!("╠══════════════════════════════════════════════════════════════╣");
-        println!("║  Starting {} tunnel(s)...                                     ║", self.config.tunnels.len());
-        println!("╚══════════════════════════════════════════════════════════════╝\n");
-
-        for tunnel_conf in &self.config.tunnels {
-            let relay = self.config.relay.clone();
-            let conf = tunnel_conf.clone();
-            let inspector_tx = self.inspector_tx.clone();
-
-            let handle = tokio::spawn(async move {
-                loop {
-                    match run_single_tunnel(&relay, &conf, inspector_tx.clone()).await {
-                        Ok(_) => {
-                            info!("Tunnel '{}' closed gracefully", conf.name);
-                            break;
-                        }
-                        Err(e) => {
-                            error!("Tunnel '{}' error: {}. Reconnecting in 5s...", conf.name, e);
-                            tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
-                        }
-                    }
-                }
-            });
+        self.relays = probe::rank(self.config.relay_urls()).await;
 
-            self.handles.push(handle);
+        banner!("\n╔══════════════════════════════════════════════════════════════╗");
+        banner!("║  🚀 ZTunnel Multi-Tunnel Mode                                ║");
+        banner!("╠══════════════════════════════════════════════════════════════╣");
+        banner!("║  Starting {} tunnel(s)...                                     ║", self.config.tunnels.len());
+        banner!("╚══════════════════════════════════════════════════════════════╝\n");
+
+        for tunnel_conf in self.config.tunnels.clone() {
+            self.start(tunnel_conf)?;
         }
 
         Ok(())
     }
 
-    /// Wait for all tunnels to complete or Ctrl+C
-    pub async fn wait_for_shutdown(self) {
-        tokio::signal::ctrl_c().await.ok();
-        info!("Shutting down all tunnels...");
-        for handle in self.handles {
-            handle.abort();
+    /// Start one tunnel; names must be unique
+    pub fn start(&mut self, conf: TunnelConfig) -> Result<()> {
+        conf.validate()?;
+        if self.tunnels.iter().any(|t| t.conf.name == conf.name) {
+            anyhow::bail!("Tunnel '{}' is already running", conf.name);
         }
-        println!("\n✓ All tunnels stopped.");
+        let pause = Pause::new(conf.pause_page.as_ref().map(Into::into));
+        let tunnel = self.spawn(conf, pause);
+        self.tunnels.push(tunnel);
+        Ok(())
     }
-}
 
-/// Run a single tunnel connection
-async fn run_single_tunnel(
-    relay_url: &str,
-    conf: &TunnelConfig,
-    inspector_tx: mpsc::Sender<InspectorEntry>,
-) -> Result<()> {
-    info!("Connecting tunnel '{}' ({}) to {}", conf.name, conf.proto, relay_url);
-
-    let (ws_stream, _) = connect_async(relay_url).await?;
-    let (mut write, mut read) = ws_stream.split();
-
-    // Send registration with IP filter info
-    let registration = serde_json::json!({
-        "subdomain": conf.subdomain,
-        "type": conf.proto,
-        "local_port": conf.local_port,
-        "name": conf.name,
-        "ip_filter": {
-            "allow": conf.ip_filter.as_ref().map(|f| &f.allow).unwrap_or(&vec![]),
-            "deny": conf.ip_filter.as_ref().map(|f| &f.deny).unwrap_or(&vec![]),
-        }
-    });
-
-    write.send(Message::Text(registration.to_string().into())).await?;
-
-    // Wait for confirmation
-    if let Some(Ok(Message::Text(text))) = read.next().await {
-        let response: serde_json::Value = serde_json::from_str(&text)?;
-        if response.get("success").and_then(|v| v.as_bool()).unwrap_or(false) {
-            let url = response.get("url").and_then(|v| v.as_str()).unwrap_or("unknown");
-            println!("  ✓ {} ({}) → {} ↔ localhost:{}",
-                conf.name, conf.proto.to_uppercase(), url, conf.local_port);
-        } else {
-            let err = response.get("error").and_then(|v| v.as_str()).unwrap_or("Unknown");
-            anyhow::bail!("Registration failed for '{}': {}", conf.name, err);
+    /// Drop one tunnel's relay connection and register it again, for
+    /// when it is wedged. The other tunnels are left alone, and a paused
+    /// tunnel stays paused.
+    pub fn restart(&mut self, name: &str) -> Result<()> {
+        let Some(tunnel) = self.tunnels.iter().position(|t| t.conf.name == name) else {
+            anyhow::bail!("No tunnel named '{}'", name);
+        };
+        self.tunnels[tunnel].handle.abort();
+        let (conf, pause) = (self.tunnels[tunnel].conf.clone(), self.tunnels[tunnel].pause.clone());
+        self.tunnels[tunnel] = self.spawn(conf, pause);
+        info!("Restarted tunnel '{}'", name);
+        Ok(())
+    }
+
+    /// Answer one tunnel's requests with 503 and `page` (else its
+    /// `pause_page`), keeping it registered
+    pub fn pause(&mut self, name: &str, page: Option<String>) -> Result<()> {
+        let Some(tunnel) = self.tunnels.iter().find(|t| t.conf.name == name) else {
+            anyhow::bail!("No tunnel named '{}'", name);
+        };
+        tunnel.pause.pause(page);
+        info!("Paused tunnel '{}'", name);
+        Ok(())
+    }
+
+    pub fn resume(&mut self, name: &str) -> Result<()> {
+        let Some(tunnel) = self.tunnels.iter().find(|t| t.conf.name == name) else {
+            anyhow::bail!("No tunnel named '{}'", name);
+        };
+        if tunnel.pause.resume() {
+            info!("Resumed tunnel '{}'", name);
         }
+        Ok(())
     }
 
-    // Main loop
-    loop {
-        tokio::select! {
-            msg = read.next() => {
-                match msg {
-                    Some(Ok(Message::Binary(data))) => {
-                        let start = std::time::Instant::now();
-                        match conf.proto.as_str() {
-                            "http" => {
-                                if let Err(e) = handle_http_request(
-                                    &data, conf.local_port, &conf.local_host,
-                                    &mut write, &inspector_tx, start
-                                ).await {
-                                    warn!("[{}] Error: {}", conf.name, e);
-                                }
-                            }
-                            "tcp" => {
-                                if let Err(e) = handle_tcp_data(
-                                    &data, conf.local_port, &conf.local_host, &mut write
-                                ).await {
-                                    warn!("[{}] TCP error: {}", conf.name, e);
-                                }
-                            }
-                            _ => {}
-                        }
-                    }
-                    Some(Ok(Message::Ping(data))) => {
-                        write.send(Message::Pong(data)).await?;
-                    }
-                    Some(Ok(Message::Close(_))) | None => break,
-                    Some(Err(e)) => {
-                        error!("[{}] WebSocket error: {}", conf.name, e);
-                        break;
-                    }
-                    _ => {}
+    /// Run a tunnel task for a validated definition
+    fn spawn(&mut self, conf: TunnelConfig, pause: Pause) -> RunningTunnel {
+        let relays = self.relays.clone();
+        let inspector_tx = self.inspector_tx.clone();
+        let auth_token = self.config.auth_token.clone();
+        let registration = Arc::new(Mutex::new(None));
+        let reported = registration.clone();
+        let mut ctx = TunnelContext::new(conf.clone(), inspector_tx);
+        ctx.auth_token = auth_token;
+        let target = ctx.target.to_string();
+        let requests = ctx.requests.clone();
+        let local_failures = ctx.local_failures.clone();
+        ctx.pause = pause.clone();
+        self.pauses.insert(&conf.name, pause.clone());
+        if conf.proto == "http" {
+            ctx.intercept = Intercept::from_config(&conf.intercept, self.inspector.interceptor());
+            ctx.frames = (conf.inspect && self.config.inspector.enabled).then(|| self.inspector.frames());
+            self.replay_targets.insert(&conf.name, ReplayTarget::for_tunnel(&ctx));
+        }
+
+        let clear_on_reconnect = self.config.inspector.clear_on_reconnect.then(|| self.inspector.clone());
+
+        let handle = tokio::spawn(async move {
+            let name = ctx.conf.name.clone();
+            let proto = ctx.conf.proto.to_uppercase();
+            let target = ctx.target.to_string();
+            let multiple = relays.len() > 1;
+            let e2e_key = ctx.e2e.as_ref().map(|e2e| e2e.public_key());
+            let mut connected = false;
+            let result = session::run_with_reconnect(&relays, &mut ctx, |reg, _| {
+                if let (true, Some(inspector)) = (connected, &clear_on_reconnect) {
+                    let (inspector, name) = (inspector.clone(), name.clone());
+                    tokio::spawn(async move { inspector.clear(Some(&name)).await });
+                }
+                connected = true;
+                if multiple {
+                    banner!("  ✓ {} ({}) → {} ↔ {} via {}", name, proto, reg.url, target, reg.relay);
+                } else {
+                    banner!("  ✓ {} ({}) → {} ↔ {}", name, proto, reg.url, target);
+                }
+                if let Some(key) = &e2e_key {
+                    banner!("    🔒 ztunnel receive {} --key {}", reg.url, key);
                 }
+                *reported.lock().unwrap_or_else(|e| e.into_inner()) = Some(reg.clone());
+            }).await;
+            if let Err(e) = result {
+                error!("{}", e);
             }
-        }
-    }
+        });
 
-    Ok(())
-}
+        RunningTunnel { conf, target, registration, requests, local_failures, pause, started: Instant::now(), handle }
+    }
 
-/// Handle an HTTP tunnel request with inspector integration
-async fn handle_http_request<S>(
-    data: &[u8],
-    local_port: u16,
-    local_host: &str,
-    write: &mut S,
-    inspector_tx: &mpsc::Sender<InspectorEntry>,
-    start: std::time::Instant,
-) -> Result<()>
-where
-    S: futures_util::Sink<Message> + Unpin,
-    S::Error: std::error::Error + Send + Sync + 'static,
-{
-    use crate::tunnel::{TunnelRequest, TunnelResponse};
-    use tokio::io::{AsyncReadExt, AsyncWriteExt};
-
-    let request: TunnelRequest = serde_json::from_slice(data)?;
-    info!("Proxying {} {} to {}:{}", request.method, request.path, local_host, local_port);
-
-    let mut stream = tokio::net::TcpStream::connect(format!("{}:{}", local_host, local_port)).await?;
-
-    // Build HTTP request
-    let mut http_request = format!(
-        "{} {} HTTP/1.1\r\nHost: {}:{}\r\n",
-        request.method, request.path, local_host, local_port
-    );
-    for (key, value) in &request.headers {
-        http_request.push_str(&format!("{}: {}\r\n", key, value));
+    /// Stop one tunnel by name. Returns false if it wasn't running.
+    pub fn stop(&mut self, name: &str) -> bool {
+        let Some(pos) = self.tunnels.iter().position(|t| t.conf.name == name) else {
+            return false;
+        };
+        let tunnel = self.tunnels.remove(pos);
+        tunnel.handle.abort();
+        self.replay_targets.remove(name);
+        self.pauses.remove(name);
+        info!("Stopped tunnel '{}'", name);
+        true
     }
-    if let Some(body) = &request.body {
-        http_request.push_str(&format!("Content-Length: {}\r\n", body.len()));
+
+    /// Stop every tunnel
+    pub fn stop_all(&mut self) {
+        for tunnel in self.tunnels.drain(..) {
+            tunnel.handle.abort();
+            self.replay_targets.remove(&tunnel.conf.name);
+            self.pauses.remove(&tunnel.conf.name);
+        }
     }
-    http_request.push_str("\r\n");
 
-    stream.write_all(http_request.as_bytes()).await?;
-    if let Some(body) = &request.body {
-        stream.write_all(body).await?;
+    /// Replay targets of the running tunnels, for `replay::serve`
+    pub fn replay_targets(&self) -> ReplayTargets {
+        self.replay_targets.clone()
     }
 
-    // Read and parse response
-    let mut buf = Vec::new();
-    let mut tmp = [0u8; 8192];
-    let mut header_end = None;
-
-    for _ in 0..64 {
-        let n = stream.read(&mut tmp).await?;
-        if n == 0 { break; }
-        buf.extend_from_slice(&tmp[..n]);
-        if header_end.is_none() {
-            if let Some(pos) = crate::find_header_end(&buf) {
-                header_end = Some(pos);
-                break;
-            }
+    /// Apply an edited config: start new tunnels, stop removed ones, and
+    /// restart those whose definition changed. Untouched tunnels keep their
+    /// relay connection. Tunnels added through the daemon are left alone
+    /// unless the new file defines one with the same name. A changed relay
+    /// list is used in its configured order, without probing.
+    pub fn reload(&mut self, new: ZTunnelConfig) -> ReloadSummary {
+        let mut summary = ReloadSummary::default();
+        let relays_changed = self.config.relay_urls() != new.relay_urls();
+        let global_changed = relays_changed || self.config.auth_token != new.auth_token;
+        if relays_changed {
+            self.relays = new.relay_urls();
+        }
+        if self.config.inspector != new.inspector {
+            warn!("Inspector settings changed; restart ztunnel to apply them");
         }
-    }
 
-    let (status, headers, body) = if let Some(hend) = header_end {
-        let header_bytes = &buf[..hend];
-        let mut lines = header_bytes.split(|b| *b == b'\r' || *b == b'\n').filter(|l| !l.is_empty());
-        let status_line = lines.next().unwrap_or(&[]);
-        let status = crate::parse_status_code(status_line).unwrap_or(200);
-        let mut headers_vec: Vec<(String, String)> = Vec::new();
-        let mut content_len: Option<usize> = None;
-
-        for line in lines {
-            if let Some((k, v)) = crate::split_header_kv(line) {
-                if k.eq_ignore_ascii_case("content-length") {
-                    if let Ok(cl) = v.trim().parse::<usize>() {
-                        content_len = Some(cl);
-                    }
-                }
-                headers_vec.push((k.to_string(), v.to_string()));
+        let gone: Vec<String> = self
+            .config
+            .tunnels
+            .iter()
+            .filter(|old| !new.tunnels.iter().any(|t| t.name == old.name))
+            .map(|old| old.name.clone())
+            .collect();
+        for name in gone {
+            if self.stop(&name) {
+                summary.removed.push(name);
             }
         }
 
-        let mut body = buf[hend + 4..].to_vec();
-        if let Some(cl) = content_len {
-            while body.len() < cl {
-                let n = stream.read(&mut tmp).await?;
-                if n == 0 { break; }
-                body.extend_from_slice(&tmp[..n]);
+        self.config = new;
+        for conf in self.config.tunnels.clone() {
+            let running = self.tunnels.iter().find(|t| t.conf.name == conf.name);
+            let changed = match running {
+                None => false,
+                Some(t) => global_changed || t.conf != conf,
+            };
+            if running.is_some() && !changed {
+                continue;
             }
-            if body.len() > cl {
-                body.truncate(cl);
+            if changed {
+                self.stop(&conf.name);
+            }
+            let name = conf.name.clone();
+            match self.start(conf) {
+                Ok(()) if changed => summary.restarted.push(name),
+                Ok(()) => summary.added.push(name),
+                Err(e) => {
+                    error!("Could not start tunnel '{}': {}", name, e);
+                    summary.failed.push(name);
+                }
             }
         }
-        (status, headers_vec, body)
-    } else {
-        (200, Vec::new(), buf)
-    };
-
-    let latency_ms = start.elapsed().as_millis() as u64;
-    let body_size = body.len();
-
-    // Send response back through tunnel
-    let response = TunnelResponse {
-        id: request.id.clone(),
-        status,
-        headers: headers.clone(),
-        body: Some(body.clone()),
-    };
-    let response_data = serde_json::to_vec(&response)?;
-    write
-        .send(Message::Binary(response_data.into()))
-        .await
-        .map_err(|e| anyhow::anyhow!("Failed to send response: {}", e))?;
-
-    // Record in inspector
-    let entry = InspectorEntry {
-        id: request.id,
-        timestamp: chrono::Utc::now().to_rfc3339(),
-        method: request.method,
-        path: request.path,
-        status,
-        latency_ms,
-        req_headers: request.headers,
-        req_body: request.body.map(|b| String::from_utf8_lossy(&b).to_string()),
-        res_headers: headers,
-        res_body: Some(String::from_utf8_lossy(&body).to_string()),
-        res_body_size: body_size,
-    };
-    let _ = inspector_tx.send(entry).await;
-
-    Ok(())
+
+        summary
+    }
+
+    /// Running tunnels, in start order
+    pub fn list(&self) -> Vec<TunnelInfo> {
+        self.tunnels
+            .iter()
+            .map(|t| {
+                let reg = t.registration.lock().unwrap_or_else(|e| e.into_inner()).clone();
+                TunnelInfo {
+                    name: t.conf.name.clone(),
+                    proto: t.conf.proto.clone(),
+                    target: t.target.clone(),
+                    url: reg.as_ref().map(|r| r.url.clone()),
+                    relay: reg.map(|r| r.relay),
+                    uptime_secs: t.started.elapsed().as_secs(),
+                    requests: t.requests.load(Ordering::Relaxed),
+                    local_failures: t.local_failures.load(Ordering::Relaxed),
+                    paused: t.pause.is_paused(),
+                }
+            })
+            .collect()
+    }
+}
+
+/// What a config reload changed
+#[derive(Debug, Default, PartialEq)]
+pub struct ReloadSummary {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub restarted: Vec<String>,
+    pub failed: Vec<String>,
+}
+
+impl ReloadSummary {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.restarted.is_empty() && self.failed.is_empty()
+    }
+}
+
+/// A tunnel restart asked for from the inspector
+#[derive(Debug)]
+pub struct RestartRequest {
+    pub name: String,
+    pub reply: oneshot::Sender<Result<(), String>>,
 }
 
-/// Handle raw TCP data
-async fn handle_tcp_data<S>(
-    data: &[u8],
-    local_port: u16,
-    local_host: &str,
-    write: &mut S,
-) -> Result<()>
-where
-    S: futures_util::Sink<Message> + Unpin,
-    S::Error: std::error::Error + Send + Sync + 'static,
-{
-    use tokio::io::{AsyncReadExt, AsyncWriteExt};
-
-    let mut stream = tokio::net::TcpStream::connect(format!("{}:{}", local_host, local_port)).await?;
-    stream.write_all(data).await?;
-
-    let mut response = vec![0u8; 65536];
-    let n = stream.read(&mut response).await?;
-    response.truncate(n);
-
-    write
-        .send(Message::Binary(response.into()))
-        .await
-        .map_err(|e| anyhow::anyhow!("Failed to send: {}", e))?;
-
-    Ok(())
+/// Answer restart requests until the inspector goes away
+pub async fn serve_restarts(mut rx: mpsc::Receiver<RestartRequest>, manager: Arc<tokio::sync::Mutex<TunnelManager>>) {
+    while let Some(req) = rx.recv().await {
+        let result = manager.lock().await.restart(&req.name).map_err(|e| e.to_string());
+        let _ = req.reply.send(result);
+    }
+}
+
+/// Wait for Ctrl+C, then stop every tunnel
+pub async fn wait_for_shutdown(manager: &tokio::sync::Mutex<TunnelManager>) {
+    tokio::signal::ctrl_c().await.ok();
+    info!("Shutting down all tunnels...");
+    manager.lock().await.stop_all();
+    println!("\n✓ All tunnels stopped.");
+}
+
+/// Compact uptime for tables, e.g. `45s`, `3m12s`, `2h05m`, `4d03h`
+pub fn format_uptime(secs: u64) -> String {
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m{:02}s", secs / 60, secs % 60),
+        3600..=86399 => format!("{}h{:02}m", secs / 3600, secs % 3600 / 60),
+        _ => format!("{}d{:02}h", secs / 86400, secs % 86400 / 3600),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manager(yaml: &str) -> TunnelManager {
+        let config: ZTunnelConfig = serde_yaml::from_str(yaml).unwrap();
+        TunnelManager::new(config, mpsc::channel(1).0, InspectorState::new(mpsc::channel(1).0))
+    }
+
+    #[tokio::test]
+    async fn test_reload_touches_only_changed_tunnels() {
+        let base = "relay: ws://127.0.0.1:9/tunnel\ntunnels:\n";
+        let mut mgr = manager(&format!(
+            "{}  - {{name: web, local_port: 3000}}\n  - {{name: api, local_port: 8000}}\n  - {{name: old, local_port: 9000}}\n",
+            base
+        ));
+        mgr.start_all().await.unwrap();
+        let web_started = mgr.tunnels[0].started;
+
+        let new: ZTunnelConfig = serde_yaml::from_str(&format!(
+            "{}  - {{name: web, local_port: 3000}}\n  - {{name: api, local_port: 8001}}\n  - {{name: new, local_port: 7000}}\n",
+            base
+        ))
+        .unwrap();
+        let summary = mgr.reload(new);
+
+        assert_eq!(summary, ReloadSummary {
+            added: vec!["new".into()],
+            removed: vec!["old".into()],
+            restarted: vec!["api".into()],
+            failed: vec![],
+        });
+        let names: Vec<_> = mgr.list().into_iter().map(|t| t.name).collect();
+        assert_eq!(names, ["web", "api", "new"]);
+        // web kept its original task
+        assert_eq!(mgr.tunnels[0].started, web_started);
+        assert_eq!(mgr.tunnels[1].target, "127.0.0.1:8001");
+        mgr.stop_all();
+    }
+
+    #[tokio::test]
+    async fn test_restart_keeps_other_tunnels() {
+        let mut mgr = manager("relay: ws://127.0.0.1:9/tunnel\ntunnels:\n  - {name: web, local_port: 3000}\n  - {name: api, local_port: 8000}\n");
+        mgr.start_all().await.unwrap();
+        let (web_started, api_started) = (mgr.tunnels[0].started, mgr.tunnels[1].started);
+
+        mgr.restart("web").unwrap();
+        let names: Vec<_> = mgr.list().into_iter().map(|t| t.name).collect();
+        assert_eq!(names, ["web", "api"]);
+        assert_ne!(mgr.tunnels[0].started, web_started);
+        assert_eq!(mgr.tunnels[1].started, api_started);
+        assert!(mgr.restart("nope").is_err());
+
+        mgr.pause("web", None).unwrap();
+        mgr.restart("web").unwrap();
+        assert_eq!(mgr.list().iter().map(|t| t.paused).collect::<Vec<_>>(), [true, false]);
+        mgr.resume("web").unwrap();
+        assert!(!mgr.list()[0].paused);
+        assert!(mgr.pause("nope", None).is_err());
+        mgr.stop_all();
+    }
+
+    #[test]
+    fn test_format_uptime() {
+        assert_eq!(format_uptime(45), "45s");
+        assert_eq!(format_uptime(192), "3m12s");
+        assert_eq!(format_uptime(7500), "2h05m");
+        assert_eq!(format_uptime(356_400), "4d03h");
+    }
 }
@@ -0,0 +1,341 @@
+//! ngrok config import
+//!
+//! `ztunnel start --from-ngrok ngrok.yml` runs the tunnels of an ngrok
+//! agent config (v2 or v3) without writing a ztunnel.yml first. Each
+//! tunnel becomes a `TunnelConfig`; settings with no ztunnel
+//! counterpart (region, TLS tunnels, OAuth, ...) are skipped with a note
+//! instead of failing the import. ngrok's authtoken is a credential for
+//! ngrok's service, so it is never sent to a ztunnel relay.
+
+use crate::config::{InspectorConfig, IpFilterConfig, TunnelConfig, ZTunnelConfig};
+use crate::proxy_protocol::Version as ProxyVersion;
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::path::Path;
+use ztunnel_shared::validate;
+
+/// A converted config, with what didn't carry over
+#[derive(Debug)]
+pub struct Imported {
+    pub config: ZTunnelConfig,
+    pub notes: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct NgrokConfig {
+    #[serde(default)]
+    version: Option<serde_yaml::Value>,
+    /// v3 puts agent settings here; v2 has them at the top level
+    #[serde(default)]
+    agent: Option<Agent>,
+    #[serde(flatten)]
+    top: Agent,
+    #[serde(default)]
+    tunnels: serde_yaml::Mapping,
+    #[serde(default)]
+    endpoints: Option<serde_yaml::Value>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Agent {
+    #[serde(default)]
+    authtoken: Option<String>,
+    #[serde(default)]
+    region: Option<String>,
+    /// `host:port`, or `false` to turn the inspector off
+    #[serde(default)]
+    web_addr: Option<serde_yaml::Value>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct NgrokTunnel {
+    #[serde(default)]
+    proto: Option<String>,
+    #[serde(default)]
+    addr: Option<serde_yaml::Value>,
+    #[serde(default)]
+    subdomain: Option<String>,
+    /// v2
+    #[serde(default)]
+    hostname: Option<String>,
+    /// v3
+    #[serde(default)]
+    domain: Option<String>,
+    /// v2 `user:pass`
+    #[serde(default)]
+    auth: Option<String>,
+    /// v3 list of `user:pass`
+    #[serde(default)]
+    basic_auth: Vec<String>,
+    #[serde(default)]
+    inspect: Option<bool>,
+    /// `rewrite`, `preserve`, or a host
+    #[serde(default)]
+    host_header: Option<String>,
+    #[serde(default)]
+    ip_restriction: Option<IpRestriction>,
+    #[serde(default)]
+    region: Option<String>,
+    /// v2 PROXY protocol version for TCP tunnels, 1 or 2
+    #[serde(default)]
+    proxy_proto: Option<u8>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct IpRestriction {
+    #[serde(default)]
+    allow_cidrs: Vec<String>,
+    #[serde(default)]
+    deny_cidrs: Vec<String>,
+}
+
+/// Read and convert an ngrok config file
+pub fn load(path: &Path) -> Result<Imported> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read ngrok config: {}", path.display()))?;
+    convert(&content).with_context(|| format!("Failed to import ngrok config: {}", path.display()))
+}
+
+/// Convert the text of an ngrok config
+pub fn convert(content: &str) -> Result<Imported> {
+    let ngrok: NgrokConfig = serde_yaml::from_str(content)?;
+    let mut notes = Vec::new();
+    let version = match &ngrok.version {
+        Some(serde_yaml::Value::String(v)) => Some(v.clone()),
+        Some(serde_yaml::Value::Number(v)) => Some(v.to_string()),
+        _ => None,
+    };
+    if let Some(version) = version.filter(|v| v != "2" && v != "3") {
+        notes.push(format!("Unknown ngrok config version {}, reading it as v3", version));
+    }
+    if ngrok.endpoints.is_some() {
+        notes.push("ngrok endpoints are not imported, only tunnels".into());
+    }
+
+    let agent = ngrok.agent.unwrap_or_default();
+    if agent.authtoken.is_some() || ngrok.top.authtoken.is_some() {
+        notes.push("ngrok authtoken ignored; pass the relay's token with --auth-token".into());
+    }
+    if agent.region.is_some() || ngrok.top.region.is_some() {
+        notes.push("ngrok region ignored; pick relays with --relay".into());
+    }
+
+    let mut inspector = InspectorConfig::default();
+    match agent.web_addr.or(ngrok.top.web_addr) {
+        Some(serde_yaml::Value::Bool(false)) => inspector.enabled = false,
+        Some(serde_yaml::Value::String(addr)) => match addr.rsplit_once(':').and_then(|(_, p)| p.parse().ok()) {
+            Some(port) => inspector.port = port,
+            None => notes.push(format!("web_addr '{}' has no port, inspector stays on {}", addr, inspector.port)),
+        },
+        _ => {}
+    }
+
+    let mut tunnels = Vec::new();
+    for (name, tunnel) in &ngrok.tunnels {
+        let Some(name) = name.as_str() else {
+            notes.push("Skipped a tunnel without a name".into());
+            continue;
+        };
+        let tunnel: NgrokTunnel = serde_yaml::from_value(tunnel.clone())
+            .with_context(|| format!("Invalid ngrok tunnel '{}'", name))?;
+        if let Some(tunnel) = convert_tunnel(name, tunnel, &mut notes) {
+            tunnels.push(tunnel);
+        }
+    }
+    if tunnels.is_empty() {
+        anyhow::bail!("No ngrok tunnels could be imported");
+    }
+
+    let config = ZTunnelConfig { tunnels, inspector, ..ZTunnelConfig::default() };
+    config.validate()?;
+    Ok(Imported { config, notes })
+}
+
+fn convert_tunnel(name: &str, ngrok: NgrokTunnel, notes: &mut Vec<String>) -> Option<TunnelConfig> {
+    let skip = |notes: &mut Vec<String>, why: String| {
+        notes.push(format!("Skipped tunnel '{}': {}", name, why));
+        None
+    };
+    let proto = match ngrok.proto.as_deref().unwrap_or("http") {
+        "http" | "https" => "http",
+        "tcp" => "tcp",
+        other => return skip(notes, format!("{} tunnels aren't supported", other)),
+    };
+    let Some(addr) = ngrok.addr else {
+        return skip(notes, "no addr".into());
+    };
+    let addr = match addr {
+        serde_yaml::Value::Number(port) => port.to_string(),
+        serde_yaml::Value::String(addr) => addr,
+        _ => return skip(notes, "addr is not a port or address".into()),
+    };
+    let Some((local_host, local_port)) = parse_addr(&addr) else {
+        return skip(notes, format!("can't read addr '{}'", addr));
+    };
+    if addr.starts_with("https://") {
+        notes.push(format!("Tunnel '{}': local TLS isn't supported, forwarding plain HTTP to {}", name, addr));
+    }
+
+    let mut tunnel = TunnelConfig {
+        name: name.to_string(),
+        proto: proto.to_string(),
+        local_port,
+        ..TunnelConfig::default()
+    };
+    if let Some(host) = local_host {
+        tunnel.local_host = host;
+    }
+
+    // A reserved domain keeps only its first label as the subdomain
+    let requested = ngrok.subdomain.or_else(|| {
+        let domain = ngrok.domain.or(ngrok.hostname)?;
+        let label = domain.split('.').next().unwrap_or_default().to_ascii_lowercase();
+        notes.push(format!("Tunnel '{}': domain '{}' requested as subdomain '{}'", name, domain, label));
+        Some(label)
+    });
+    if let Some(subdomain) = requested {
+        match validate::check_subdomain(&subdomain) {
+            Ok(()) => tunnel.subdomain = Some(subdomain),
+            Err(e) => notes.push(format!("Tunnel '{}': {}, letting the relay pick one", name, e)),
+        }
+    }
+
+    let mut credentials = ngrok.auth.into_iter().chain(ngrok.basic_auth);
+    tunnel.basic_auth = credentials.next();
+    if credentials.next().is_some() {
+        notes.push(format!("Tunnel '{}': only the first basic_auth credential is kept", name));
+    }
+    if let Some(inspect) = ngrok.inspect {
+        tunnel.inspect = inspect;
+    }
+    match ngrok.host_header.as_deref() {
+        // Our default already sends the local address
+        None | Some("rewrite") => {}
+        Some("preserve") => notes.push(format!("Tunnel '{}': host_header preserve isn't supported, rewriting", name)),
+        Some(host) => tunnel.host_header = Some(host.to_string()),
+    }
+    if let Some(ip) = ngrok.ip_restriction {
+        tunnel.ip_filter = Some(IpFilterConfig { allow: ip.allow_cidrs, deny: ip.deny_cidrs });
+    }
+    if ngrok.region.is_some() {
+        notes.push(format!("Tunnel '{}': region ignored", name));
+    }
+    match ngrok.proxy_proto {
+        None | Some(0) => {}
+        Some(1) if proto == "tcp" => tunnel.proxy_protocol = Some(ProxyVersion::V1),
+        Some(2) if proto == "tcp" => tunnel.proxy_protocol = Some(ProxyVersion::V2),
+        Some(v) => notes.push(format!("Tunnel '{}': proxy_proto {} ignored", name, v)),
+    }
+    if proto == "tcp" {
+        tunnel.subdomain = None;
+    }
+
+    if let Err(e) = tunnel.validate() {
+        return skip(notes, e.to_string());
+    }
+    Some(tunnel)
+}
+
+/// `8080`, `host:8080` or `http://host:8080` → host (if given) and port
+fn parse_addr(addr: &str) -> Option<(Option<String>, u16)> {
+    let (scheme, rest) = match addr.split_once("://") {
+        Some((scheme, rest)) => (Some(scheme), rest),
+        None => (None, addr),
+    };
+    let rest = rest.split('/').next().unwrap_or_default();
+    if let Ok(port) = rest.parse() {
+        return Some((None, port));
+    }
+    match rest.rsplit_once(':') {
+        Some((host, port)) => Some((Some(host.to_string()), port.parse().ok()?)),
+        None => {
+            let port = match scheme? {
+                "http" => 80,
+                "https" => 443,
+                _ => return None,
+            };
+            Some((Some(rest.to_string()), port))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_convert_v2() {
+        let imported = convert(
+            r#"
+authtoken: 2abc
+region: eu
+web_addr: localhost:4041
+tunnels:
+  web:
+    proto: http
+    addr: 3000
+    subdomain: myapp
+    auth: "user:pass"
+    host_header: rewrite
+  db:
+    proto: tcp
+    addr: 10.0.0.5:5432
+    proxy_proto: 2
+  tls:
+    proto: tls
+    addr: 443
+"#,
+        )
+        .unwrap();
+        let config = &imported.config;
+        assert_eq!(config.auth_token, None);
+        assert_eq!(config.inspector.port, 4041);
+        assert_eq!(config.tunnels.len(), 2);
+
+        let web = &config.tunnels[0];
+        assert_eq!((web.name.as_str(), web.proto.as_str(), web.local_port), ("web", "http", 3000));
+        assert_eq!((web.subdomain.as_deref(), web.basic_auth.as_deref()), (Some("myapp"), Some("user:pass")));
+
+        let db = &config.tunnels[1];
+        assert_eq!((db.proto.as_str(), db.local_host.as_str(), db.local_port), ("tcp", "10.0.0.5", 5432));
+        assert_eq!(db.proxy_protocol, Some(ProxyVersion::V2));
+
+        let notes = imported.notes.join("\n");
+        for expected in ["authtoken ignored", "region ignored", "Skipped tunnel 'tls'"] {
+            assert!(notes.contains(expected), "{}", notes);
+        }
+    }
+
+    #[test]
+    fn test_convert_v3() {
+        let imported = convert(
+            r#"
+version: "3"
+agent:
+  authtoken: 2abc
+  web_addr: false
+tunnels:
+  api:
+    proto: http
+    addr: https://localhost:8443
+    domain: Shop.ngrok.app
+    basic_auth: ["a:b", "c:d"]
+    inspect: false
+    ip_restriction:
+      allow_cidrs: [10.0.0.0/8]
+"#,
+        )
+        .unwrap();
+        assert!(!imported.config.inspector.enabled);
+        let api = &imported.config.tunnels[0];
+        assert_eq!((api.local_host.as_str(), api.local_port, api.subdomain.as_deref()), ("localhost", 8443, Some("shop")));
+        assert_eq!(api.basic_auth.as_deref(), Some("a:b"));
+        assert!(!api.inspect);
+        assert_eq!(api.ip_filter.as_ref().unwrap().allow, ["10.0.0.0/8"]);
+        assert!(imported.notes.iter().any(|n| n.contains("only the first basic_auth")));
+        assert!(!imported.notes.iter().any(|n| n.contains("version")));
+
+        assert!(convert("tunnels: {}").is_err());
+        assert_eq!(parse_addr("http://example.com"), Some((Some("example.com".into()), 80)));
+    }
+}
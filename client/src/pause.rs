@@ -0,0 +1,120 @@
+//! Pausing a tunnel
+//!
+//! A paused tunnel stays registered with the relay, so its URL is kept,
+//! but answers every public request with a 503 page and refuses new TCP
+//! connections until it is resumed: handy while the local service is
+//! migrating. The page is the one given when pausing, else the file in
+//! the tunnel's `pause_page`, else a short default. Tunnels are paused
+//! with `ztunnel pause`, the daemon's control socket, or the inspector's
+//! `/api/tunnels/{name}/pause`.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use tracing::warn;
+
+/// Status, headers, and body, as `proxy::forward_http` returns them
+type Response = (u16, Vec<(String, String)>, Vec<u8>);
+
+const DEFAULT_PAGE: &str = "503 Service Unavailable: this tunnel is paused; try again shortly\n";
+
+/// One tunnel's pause switch, shared by its session and the controls
+#[derive(Debug, Clone, Default)]
+pub struct Pause {
+    /// Page served while paused; `None` when running
+    page: Arc<RwLock<Option<String>>>,
+    /// The tunnel's `pause_page`
+    default_page: Option<PathBuf>,
+}
+
+impl Pause {
+    pub fn new(default_page: Option<PathBuf>) -> Self {
+        Self { page: Arc::default(), default_page }
+    }
+
+    /// Start answering 503 with `page`, or the tunnel's default page
+    pub fn pause(&self, page: Option<String>) {
+        let page = page.or_else(|| {
+            let path = self.default_page.as_ref()?;
+            std::fs::read_to_string(path)
+                .map_err(|e| warn!("Could not read pause page {}: {}", path.display(), e))
+                .ok()
+        });
+        *self.page.write().unwrap() = Some(page.unwrap_or_else(|| DEFAULT_PAGE.to_string()));
+    }
+
+    /// Forward requests again; false if the tunnel wasn't paused
+    pub fn resume(&self) -> bool {
+        self.page.write().unwrap().take().is_some()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.page.read().unwrap().is_some()
+    }
+
+    /// The answer to every request while paused
+    pub fn response(&self) -> Option<Response> {
+        let page = self.page.read().unwrap().clone()?;
+        let content_type = if page.trim_start().starts_with('<') {
+            "text/html; charset=utf-8"
+        } else {
+            "text/plain; charset=utf-8"
+        };
+        let headers = vec![
+            ("Content-Type".to_string(), content_type.to_string()),
+            ("Content-Length".to_string(), page.len().to_string()),
+            ("Cache-Control".to_string(), "no-store".to_string()),
+        ];
+        Some((503, headers, page.into_bytes()))
+    }
+}
+
+/// Pause switches of the running tunnels by name, for the inspector
+#[derive(Debug, Clone, Default)]
+pub struct Pauses(Arc<RwLock<HashMap<String, Pause>>>);
+
+impl Pauses {
+    pub fn insert(&self, tunnel: &str, pause: Pause) {
+        self.0.write().unwrap().insert(tunnel.to_string(), pause);
+    }
+
+    pub fn remove(&self, tunnel: &str) {
+        self.0.write().unwrap().remove(tunnel);
+    }
+
+    pub fn get(&self, tunnel: &str) -> Option<Pause> {
+        self.0.read().unwrap().get(tunnel).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pause_and_resume() {
+        let path = std::env::temp_dir().join(format!("ztunnel-pause-{}.html", std::process::id()));
+        std::fs::write(&path, "<h1>Migrating, back soon</h1>").unwrap();
+
+        let pause = Pause::new(Some(path.clone()));
+        assert!(pause.response().is_none());
+        assert!(!pause.resume());
+
+        pause.pause(None);
+        let (status, headers, body) = pause.response().unwrap();
+        assert_eq!(status, 503);
+        assert_eq!(headers[0].1, "text/html; charset=utf-8");
+        assert_eq!(body, b"<h1>Migrating, back soon</h1>");
+
+        // A page given when pausing wins; the switch is shared by clones
+        let pauses = Pauses::default();
+        pauses.insert("web", pause.clone());
+        pauses.get("web").unwrap().pause(Some("Down for maintenance".into()));
+        let (_, headers, body) = pause.response().unwrap();
+        assert_eq!((headers[0].1.as_str(), body.as_slice()), ("text/plain; charset=utf-8", &b"Down for maintenance"[..]));
+
+        assert!(pause.resume());
+        assert!(!pauses.get("web").unwrap().is_paused());
+        let _ = std::fs::remove_file(&path);
+    }
+}
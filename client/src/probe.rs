@@ -0,0 +1,148 @@
+//! Relay latency probing
+//!
+//! Each relay is timed with two requests to its `/health` endpoint over
+//! one keep-alive connection: the first covers TCP + TLS setup, the
+//! second is a bare round trip. With several relays configured the
+//! client starts on whichever answered fastest.
+
+use futures_util::future::join_all;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// Give up on a relay after this long
+const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Timing for one relay
+#[derive(Debug, Clone)]
+pub struct RelayProbe {
+    pub url: String,
+    /// First request, including connection setup
+    pub connect: Option<Duration>,
+    /// Second request on the same connection
+    pub rtt: Option<Duration>,
+    pub error: Option<String>,
+}
+
+impl RelayProbe {
+    /// Sort key; unreachable relays have none
+    pub fn latency(&self) -> Option<Duration> {
+        Some(self.connect? + self.rtt?)
+    }
+}
+
+/// `/health` URL served next to a relay's `/tunnel` endpoint
+pub fn health_url(relay: &str) -> String {
+    let (scheme, rest) = relay.split_once("://").unwrap_or(("ws", relay));
+    let scheme = match scheme {
+        "wss" | "https" => "https",
+        _ => "http",
+    };
+    let host = rest.split('/').next().unwrap_or(rest);
+    format!("{}://{}/health", scheme, host)
+}
+
+/// Time one relay
+pub async fn probe(relay: &str) -> RelayProbe {
+    let mut result = RelayProbe { url: relay.to_string(), connect: None, rtt: None, error: None };
+    let client = match reqwest::Client::builder().timeout(PROBE_TIMEOUT).build() {
+        Ok(client) => client,
+        Err(e) => {
+            result.error = Some(e.to_string());
+            return result;
+        }
+    };
+    let url = health_url(relay);
+
+    for slot in [&mut result.connect, &mut result.rtt] {
+        let started = Instant::now();
+        match client.get(&url).send().await {
+            Ok(resp) if resp.status().is_success() => {
+                // Drain the body so the connection goes back to the pool
+                let _ = resp.bytes().await;
+                *slot = Some(started.elapsed());
+            }
+            Ok(resp) => {
+                result.error = Some(format!("HTTP {}", resp.status()));
+                break;
+            }
+            Err(e) => {
+                result.error = Some(if e.is_timeout() { "timed out".to_string() } else { root_cause(&e) });
+                break;
+            }
+        }
+    }
+    result
+}
+
+/// Innermost error, e.g. `Connection refused (os error 111)`
+fn root_cause(e: &(dyn std::error::Error + 'static)) -> String {
+    let mut cause = e;
+    while let Some(source) = cause.source() {
+        cause = source;
+    }
+    cause.to_string()
+}
+
+/// Probe every relay concurrently, fastest first. Unreachable relays
+/// go last, in their configured order.
+pub async fn probe_all(relays: &[String]) -> Vec<RelayProbe> {
+    let mut probes = join_all(relays.iter().map(|r| probe(r))).await;
+    // Stable sort, so ties and failures keep the configured order
+    probes.sort_by_key(|p| p.latency().unwrap_or(Duration::MAX));
+    probes
+}
+
+/// Reorder relays fastest first. A single relay is returned untouched.
+pub async fn rank(relays: Vec<String>) -> Vec<String> {
+    if relays.len() < 2 {
+        return relays;
+    }
+    let probes = probe_all(&relays).await;
+    match probes.first().and_then(|p| Some((p, p.latency()?))) {
+        Some((fastest, latency)) => info!("Using relay {} ({} ms)", fastest.url, latency.as_millis()),
+        None => info!("No relay answered the latency probe; keeping configured order"),
+    }
+    probes.into_iter().map(|p| p.url).collect()
+}
+
+/// Latency cell for tables, e.g. `42 ms`
+pub fn format_ms(d: Option<Duration>) -> String {
+    match d {
+        Some(d) => format!("{} ms", d.as_millis()),
+        None => "-".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_health_url() {
+        assert_eq!(health_url("ws://localhost:8080/tunnel"), "http://localhost:8080/health");
+        assert_eq!(health_url("wss://eu.example.com/tunnel"), "https://eu.example.com/health");
+        assert_eq!(health_url("eu.example.com"), "http://eu.example.com/health");
+    }
+
+    #[tokio::test]
+    async fn test_rank_puts_unreachable_last() {
+        let app = axum::Router::new().route("/health", axum::routing::get(|| async { "ok" }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let live = format!("ws://{}/tunnel", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let dead = {
+            let l = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("ws://{}/tunnel", l.local_addr().unwrap())
+        };
+
+        let probes = probe_all(&[dead.clone(), live.clone()]).await;
+        assert_eq!(probes[0].url, live);
+        assert!(probes[0].connect.is_some() && probes[0].rtt.is_some());
+        assert!(probes[1].latency().is_none());
+        assert!(probes[1].error.is_some());
+
+        assert_eq!(rank(vec![dead.clone(), live.clone()]).await, [live, dead.clone()]);
+        assert_eq!(rank(vec![dead.clone()]).await, [dead]);
+    }
+}
@@ -1,39 +1,559 @@
 //! Local proxy for forwarding requests
 
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::fmt;
+use std::path::PathBuf;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
+use tokio::sync::mpsc;
 use anyhow::Result;
+use bytes::Bytes;
+use ztunnel_shared::http;
 
-/// Forward HTTP request to local server
+use crate::body::Body;
+use crate::config::TunnelConfig;
+
+/// Any bidirectional byte stream to a local service
+pub trait LocalStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> LocalStream for T {}
+
+/// Where tunneled traffic is delivered on this machine
+#[derive(Debug, Clone, PartialEq)]
+pub enum LocalTarget {
+    /// TCP host:port
+    Tcp { host: String, port: u16 },
+    /// Unix domain socket path (php-fpm, gunicorn, docker, ...)
+    Unix(PathBuf),
+}
+
+impl LocalTarget {
+    /// Build the target for a tunnel definition (socket wins over host:port)
+    pub fn from_config(conf: &TunnelConfig) -> Self {
+        match &conf.local_socket {
+            Some(path) => LocalTarget::Unix(PathBuf::from(path)),
+            None => LocalTarget::Tcp {
+                host: conf.local_host.clone(),
+                port: conf.local_port,
+            },
+        }
+    }
+
+    /// Parse `PORT`, `HOST:PORT`, or `unix:PATH`
+    pub fn parse(spec: &str) -> Option<Self> {
+        if let Some(path) = spec.strip_prefix("unix:") {
+            return (!path.is_empty()).then(|| LocalTarget::Unix(PathBuf::from(path)));
+        }
+        let (host, port) = spec.rsplit_once(':').unwrap_or(("127.0.0.1", spec));
+        let port = port.parse().ok().filter(|p| *p != 0)?;
+        (!host.is_empty()).then(|| LocalTarget::Tcp { host: host.to_string(), port })
+    }
+
+    /// Default Host header for requests sent to this target
+    pub fn host_header(&self) -> String {
+        match self {
+            LocalTarget::Tcp { host, port } => format!("{}:{}", host, port),
+            LocalTarget::Unix(_) => "localhost".to_string(),
+        }
+    }
+
+    /// The target's address when its host is an IP (or `localhost`)
+    pub fn socket_addr(&self) -> Option<std::net::SocketAddr> {
+        let LocalTarget::Tcp { host, port } = self else { return None };
+        let ip = match host.as_str() {
+            "localhost" => std::net::Ipv4Addr::LOCALHOST.into(),
+            host => host.trim_start_matches('[').trim_end_matches(']').parse().ok()?,
+        };
+        Some(std::net::SocketAddr::new(ip, *port))
+    }
+
+    /// Open a new connection to the local service
+    pub async fn connect(&self) -> std::io::Result<Box<dyn LocalStream>> {
+        match self {
+            LocalTarget::Tcp { host, port } => {
+                let stream = TcpStream::connect(format!("{}:{}", host, port)).await?;
+                Ok(Box::new(stream))
+            }
+            #[cfg(unix)]
+            LocalTarget::Unix(path) => {
+                let stream = tokio::net::UnixStream::connect(path).await?;
+                Ok(Box::new(stream))
+            }
+            #[cfg(not(unix))]
+            LocalTarget::Unix(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "unix socket targets are not supported on this platform",
+            )),
+        }
+    }
+}
+
+impl fmt::Display for LocalTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LocalTarget::Tcp { host, port } => write!(f, "{}:{}", host, port),
+            LocalTarget::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// The local service couldn't be connected to
+#[derive(Debug, thiserror::Error)]
+#[error("Could not reach {target}: {source}")]
+pub struct Unreachable {
+    pub target: String,
+    #[source]
+    pub source: std::io::Error,
+}
+
+impl Unreachable {
+    /// The 502 sent back through the tunnel, so the visitor learns what
+    /// is wrong instead of waiting for the relay to time out. A page for
+    /// browsers, plain text for everything else.
+    pub fn to_parts(&self, request_headers: &[(String, String)]) -> (u16, Vec<(String, String)>, Vec<u8>) {
+        let html = request_headers
+            .iter()
+            .any(|(k, v)| k.eq_ignore_ascii_case("accept") && v.contains("text/html"));
+        let (content_type, body) = if html {
+            ("text/html; charset=utf-8", format!(
+                "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>502 Local service not running</title>\n\
+                 <style>body{{font-family:system-ui,sans-serif;max-width:40rem;margin:4rem auto;padding:0 1rem;color:#222}}\
+                 code{{background:#f2f2f2;padding:.1rem .3rem;border-radius:3px}}</style></head>\n\
+                 <body><h1>Local service not running</h1>\n\
+                 <p>The tunnel is up, but nothing answered on <code>{}</code> ({}).</p>\n\
+                 <p>Start the service and reload this page.</p></body></html>\n",
+                escape_html(&self.target),
+                escape_html(&self.source.to_string()),
+            ))
+        } else {
+            ("text/plain; charset=utf-8", format!("502 Bad Gateway: local service on {} is not running ({})\n", self.target, self.source))
+        };
+        let headers = vec![
+            ("Content-Type".to_string(), content_type.to_string()),
+            ("Content-Length".to_string(), body.len().to_string()),
+            ("Cache-Control".to_string(), "no-store".to_string()),
+        ];
+        (502, headers, body.into_bytes())
+    }
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+/// Canned response served instead of contacting a local service
+#[derive(Debug, Clone, PartialEq)]
+pub struct FixedResponse {
+    pub status: u16,
+    pub body: String,
+}
+
+impl FixedResponse {
+    /// Parse a `STATUS[:BODY]` spec, e.g. `200:{"ok":true}` or `204`
+    pub fn parse(spec: &str) -> Option<Self> {
+        let (status, body) = match spec.split_once(':') {
+            Some((status, body)) => (status, body),
+            None => (spec, ""),
+        };
+        let status: u16 = status.trim().parse().ok()?;
+        if !(100..=599).contains(&status) {
+            return None;
+        }
+        Some(Self { status, body: body.to_string() })
+    }
+
+    /// Status, headers, and body as returned by `forward_http`
+    pub fn to_parts(&self) -> (u16, Vec<(String, String)>, Vec<u8>) {
+        let trimmed = self.body.trim_start();
+        let content_type = if trimmed.starts_with('{') || trimmed.starts_with('[') {
+            "application/json"
+        } else {
+            "text/plain; charset=utf-8"
+        };
+        let headers = vec![
+            ("Content-Type".to_string(), content_type.to_string()),
+            ("Content-Length".to_string(), self.body.len().to_string()),
+        ];
+        (self.status, headers, self.body.as_bytes().to_vec())
+    }
+}
+
+/// Forward HTTP request to local server over an open connection
 pub async fn forward_http(
-    port: u16,
+    mut stream: Box<dyn LocalStream>,
+    host: &str,
     method: &str,
     path: &str,
     headers: &[(String, String)],
     body: Option<&[u8]>,
 ) -> Result<(u16, Vec<(String, String)>, Vec<u8>)> {
-    let mut stream = TcpStream::connect(format!("127.0.0.1:{}", port)).await?;
-    
-    // Build request
-    let mut request = format!("{} {} HTTP/1.1\r\nHost: localhost:{}\r\n", method, path, port);
-    for (key, value) in headers {
-        request.push_str(&format!("{}: {}\r\n", key, value));
-    }
-    
+    write_request(&mut stream, host, method, path, headers, body).await?;
+    read_response(&mut stream, method).await
+}
+
+/// Write a request whose body is still arriving, each chunk as it comes
+/// off `body`. Without a Content-Length the body is sent chunked.
+/// Returns the connection, ready for `read_response`, and the first
+/// `keep` bytes of the body.
+pub async fn write_streamed_request(
+    mut stream: Box<dyn LocalStream>,
+    host: &str,
+    method: &str,
+    path: &str,
+    headers: &[(String, String)],
+    mut body: mpsc::Receiver<Bytes>,
+    keep: usize,
+) -> Result<(Box<dyn LocalStream>, Body)> {
+    let chunked = http::header(headers, "content-length").is_none();
+    let mut head = request_headers(host, headers, chunked);
+    if chunked {
+        head.push(("Transfer-Encoding".to_string(), "chunked".to_string()));
+    }
+    stream.write_all(http::request_head(method, path, &head).as_bytes()).await?;
+
+    let mut kept = Vec::new();
+    let mut size = 0;
+    while let Some(chunk) = body.recv().await {
+        if chunk.is_empty() {
+            continue;
+        }
+        size += chunk.len();
+        let room = keep.saturating_sub(kept.len());
+        kept.extend_from_slice(&chunk[..chunk.len().min(room)]);
+        if chunked {
+            stream.write_all(format!("{:x}\r\n", chunk.len()).as_bytes()).await?;
+            stream.write_all(&chunk).await?;
+            stream.write_all(b"\r\n").await?;
+        } else {
+            stream.write_all(&chunk).await?;
+        }
+    }
+    if chunked {
+        stream.write_all(b"0\r\n\r\n").await?;
+    }
+    stream.flush().await?;
+    Ok((stream, Body::partial(kept, size)))
+}
+
+/// Read a local response to a `method` request after the request has
+/// been written
+pub async fn read_response(
+    stream: &mut Box<dyn LocalStream>,
+    method: &str,
+) -> Result<(u16, Vec<(String, String)>, Vec<u8>)> {
+    let head = read_head(stream).await?;
+    read_body(stream, method, head).await
+}
+
+/// Outcome of forwarding an `Upgrade` request
+pub enum Upgrade {
+    /// The local service switched protocols; `stream` now carries raw
+    /// bytes and `leftover` holds any that arrived with the 101.
+    Switched {
+        headers: Vec<(String, String)>,
+        stream: Box<dyn LocalStream>,
+        leftover: Vec<u8>,
+    },
+    /// The local service answered with an ordinary response
+    Declined(u16, Vec<(String, String)>, Vec<u8>),
+}
+
+/// Forward an `Upgrade` request, keeping the connection if it is accepted
+pub async fn forward_upgrade(
+    mut stream: Box<dyn LocalStream>,
+    host: &str,
+    method: &str,
+    path: &str,
+    headers: &[(String, String)],
+) -> Result<Upgrade> {
+    write_request(&mut stream, host, method, path, headers, None).await?;
+    let head = read_head(&mut stream).await?;
+    if let (Some(response), 101) = (&head.response, head.status()) {
+        return Ok(Upgrade::Switched { headers: response.headers.clone(), stream, leftover: head.rest });
+    }
+    let (status, headers, body) = read_body(&mut stream, method, head).await?;
+    Ok(Upgrade::Declined(status, headers, body))
+}
+
+/// Whether a request asks to switch protocols
+pub fn is_upgrade_request(headers: &[(String, String)]) -> bool {
+    let has = |name: &str, token: &str| {
+        headers.iter().any(|(k, v)| {
+            k.eq_ignore_ascii_case(name)
+                && v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token))
+        })
+    };
+    has("connection", "upgrade") && headers.iter().any(|(k, _)| k.eq_ignore_ascii_case("upgrade"))
+}
+
+async fn write_request(
+    stream: &mut Box<dyn LocalStream>,
+    host: &str,
+    method: &str,
+    path: &str,
+    headers: &[(String, String)],
+    body: Option<&[u8]>,
+) -> Result<()> {
+    let mut head = request_headers(host, headers, false);
     if let Some(body) = body {
-        request.push_str(&format!("Content-Length: {}\r\n", body.len()));
+        head.push(("Content-Length".to_string(), body.len().to_string()));
     }
-    request.push_str("\r\n");
-    
-    stream.write_all(request.as_bytes()).await?;
+
+    stream.write_all(http::request_head(method, path, &head).as_bytes()).await?;
     if let Some(body) = body {
         stream.write_all(body).await?;
     }
-    
-    // Read response
-    let mut response = Vec::new();
-    stream.read_to_end(&mut response).await?;
-    
-    // Parse response (simplified - just return raw)
-    Ok((200, vec![], response))
+    Ok(())
+}
+
+/// Headers for the local request. Our Host replaces the public one;
+/// with `rechunk`, so does our Transfer-Encoding. `Expect: 100-continue`
+/// is dropped since the body follows the head regardless; the relay
+/// already answered it.
+fn request_headers(host: &str, headers: &[(String, String)], rechunk: bool) -> Vec<(String, String)> {
+    let mut head = vec![("Host".to_string(), host.to_string())];
+    head.extend(
+        headers
+            .iter()
+            .filter(|(key, _)| {
+                !(key.eq_ignore_ascii_case("host")
+                    || key.eq_ignore_ascii_case("expect")
+                    || (rechunk && key.eq_ignore_ascii_case("transfer-encoding")))
+            })
+            .cloned(),
+    );
+    head
+}
+
+/// Largest response head we wait for before treating the reply as raw bytes
+const MAX_HEAD_BYTES: usize = 512 * 1024;
+
+/// Head of a local response and the bytes read past it
+struct LocalHead {
+    /// None when the service didn't answer in HTTP
+    response: Option<http::ResponseHead>,
+    rest: Vec<u8>,
+}
+
+impl LocalHead {
+    fn status(&self) -> u16 {
+        self.response.as_ref().map_or(200, |r| r.status)
+    }
+}
+
+async fn read_head(stream: &mut Box<dyn LocalStream>) -> Result<LocalHead> {
+    let mut buf = Vec::new();
+    let mut tmp = [0u8; 8192];
+
+    loop {
+        match http::parse_response(&buf) {
+            // Skip 100 Continue and friends; the real response follows
+            Ok(Some(head)) if head.is_interim() => {
+                buf.drain(..head.len);
+                continue;
+            }
+            Ok(Some(head)) => {
+                let rest = buf.split_off(head.len);
+                return Ok(LocalHead { response: Some(head), rest });
+            }
+            Ok(None) if buf.len() < MAX_HEAD_BYTES => {}
+            // Not HTTP: hand back whatever we got as the body
+            _ => return Ok(LocalHead { response: None, rest: buf }),
+        }
+        let n = stream.read(&mut tmp).await?;
+        if n == 0 {
+            return Ok(LocalHead { response: None, rest: buf });
+        }
+        buf.extend_from_slice(&tmp[..n]);
+    }
+}
+
+async fn read_body(
+    stream: &mut Box<dyn LocalStream>,
+    method: &str,
+    head: LocalHead,
+) -> Result<(u16, Vec<(String, String)>, Vec<u8>)> {
+    let Some(response) = head.response else {
+        return Ok((200, Vec::new(), head.rest));
+    };
+    let framing = http::response_body_length(method, &response)?;
+    let mut tmp = [0u8; 8192];
+    let mut body = head.rest;
+    let mut headers = response.headers;
+
+    match framing {
+        http::BodyLength::Empty => body.clear(),
+        http::BodyLength::Fixed(len) => {
+            while body.len() < len {
+                let n = stream.read(&mut tmp).await?;
+                if n == 0 { break; }
+                body.extend_from_slice(&tmp[..n]);
+            }
+            body.truncate(len);
+        }
+        http::BodyLength::Chunked => {
+            let mut decoder = http::ChunkedDecoder::default();
+            let mut decoded = Vec::new();
+            decoder.push(&body, &mut decoded)?;
+            while !decoder.is_done() {
+                let n = stream.read(&mut tmp).await?;
+                if n == 0 {
+                    anyhow::bail!("Local service closed the connection mid-body");
+                }
+                decoder.push(&tmp[..n], &mut decoded)?;
+            }
+            // The body goes on whole, so it's framed by length now
+            headers.retain(|(k, _)| !k.eq_ignore_ascii_case("transfer-encoding"));
+            headers.push(("Content-Length".to_string(), decoded.len().to_string()));
+            body = decoded;
+        }
+        http::BodyLength::UntilClose => {
+            stream.read_to_end(&mut body).await?;
+        }
+    }
+
+    Ok((response.status, headers, body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_target_display() {
+        let tcp = LocalTarget::Tcp { host: "127.0.0.1".into(), port: 3000 };
+        assert_eq!(tcp.to_string(), "127.0.0.1:3000");
+        assert_eq!(tcp.host_header(), "127.0.0.1:3000");
+
+        let unix = LocalTarget::Unix(PathBuf::from("/var/run/app.sock"));
+        assert_eq!(unix.to_string(), "unix:/var/run/app.sock");
+        assert_eq!(unix.host_header(), "localhost");
+    }
+
+    #[test]
+    fn test_target_parse() {
+        let tcp = |host: &str, port| Some(LocalTarget::Tcp { host: host.into(), port });
+        assert_eq!(LocalTarget::parse("3001"), tcp("127.0.0.1", 3001));
+        assert_eq!(LocalTarget::parse("staging.local:8080"), tcp("staging.local", 8080));
+        assert_eq!(LocalTarget::parse("[::1]:3001"), tcp("[::1]", 3001));
+        assert_eq!(LocalTarget::parse("unix:/tmp/app.sock"), Some(LocalTarget::Unix(PathBuf::from("/tmp/app.sock"))));
+
+        for bad in ["", "0", "host:", ":3000", "host:http", "unix:"] {
+            assert_eq!(LocalTarget::parse(bad), None, "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_fixed_response_parse() {
+        let r = FixedResponse::parse(r#"200:{"ok":true}"#).unwrap();
+        assert_eq!(r.status, 200);
+        assert_eq!(r.body, r#"{"ok":true}"#);
+        let (_, headers, _) = r.to_parts();
+        assert!(headers.iter().any(|(k, v)| k == "Content-Type" && v == "application/json"));
+
+        let r = FixedResponse::parse("204").unwrap();
+        assert_eq!(r.status, 204);
+        assert!(r.body.is_empty());
+
+        assert!(FixedResponse::parse("ok:hello").is_none());
+        assert!(FixedResponse::parse("999:x").is_none());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_forward_over_unix_socket() {
+        let path = std::env::temp_dir().join(format!("ztunnel-test-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+
+        tokio::spawn(async move {
+            let (mut sock, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            let n = sock.read(&mut buf).await.unwrap();
+            let req = String::from_utf8_lossy(&buf[..n]).to_string();
+            assert!(req.contains("Host: myapp.test\r\n"));
+            assert!(!req.contains("public.example.com"));
+            sock.write_all(b"HTTP/1.1 201 Created\r\nContent-Length: 2\r\n\r\nok").await.unwrap();
+        });
+
+        let target = LocalTarget::Unix(path.clone());
+        let stream = target.connect().await.unwrap();
+        let headers = vec![("host".to_string(), "public.example.com".to_string())];
+        let (status, headers, body) = forward_http(stream, "myapp.test", "GET", "/", &headers, None)
+            .await
+            .unwrap();
+        assert_eq!(status, 201);
+        assert_eq!(headers.len(), 1);
+        assert_eq!(body, b"ok");
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_forward_upgrade_keeps_stream() {
+        let (local, mut service) = tokio::io::duplex(4096);
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            let n = service.read(&mut buf).await.unwrap();
+            assert!(String::from_utf8_lossy(&buf[..n]).contains("Upgrade: websocket\r\n"));
+            service
+                .write_all(b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\r\nhi")
+                .await
+                .unwrap();
+            let n = service.read(&mut buf).await.unwrap();
+            service.write_all(&buf[..n]).await.unwrap();
+        });
+
+        let headers = vec![
+            ("Connection".to_string(), "keep-alive, Upgrade".to_string()),
+            ("Upgrade".to_string(), "websocket".to_string()),
+        ];
+        assert!(is_upgrade_request(&headers));
+        assert!(!is_upgrade_request(&headers[1..]));
+
+        let upgrade = forward_upgrade(Box::new(local), "localhost", "GET", "/ws", &headers)
+            .await
+            .unwrap();
+        let Upgrade::Switched { mut stream, leftover, .. } = upgrade else {
+            panic!("expected 101");
+        };
+        assert_eq!(leftover, b"hi");
+
+        stream.write_all(b"echo").await.unwrap();
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"echo");
+    }
+
+    #[tokio::test]
+    async fn test_read_response_framing() {
+        // Interim 100, then a chunked body split across writes
+        let (local, mut service) = tokio::io::duplex(4096);
+        tokio::spawn(async move {
+            service.write_all(b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\n").await.unwrap();
+            service.write_all(b"Transfer-Encoding: chunked\r\n\r\n5\r\nhel").await.unwrap();
+            service.write_all(b"lo\r\n6\r\n world\r\n0\r\n\r\n").await.unwrap();
+        });
+        let (status, headers, body) = read_response(&mut (Box::new(local) as Box<dyn LocalStream>), "GET").await.unwrap();
+        assert_eq!((status, body.as_slice()), (200, &b"hello world"[..]));
+        assert_eq!(http::header(&headers, "content-length"), Some("11"));
+        assert_eq!(http::header(&headers, "transfer-encoding"), None);
+
+        // No length: the body runs until the service closes
+        let (local, mut service) = tokio::io::duplex(4096);
+        tokio::spawn(async move {
+            service.write_all(b"HTTP/1.0 200 OK\r\n\r\nstreamed ").await.unwrap();
+            service.write_all(b"until close").await.unwrap();
+        });
+        let (_, _, body) = read_response(&mut (Box::new(local) as Box<dyn LocalStream>), "GET").await.unwrap();
+        assert_eq!(body, b"streamed until close");
+
+        // HEAD answers carry a length but no body
+        let (local, mut service) = tokio::io::duplex(4096);
+        tokio::spawn(async move {
+            service.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 1000\r\n\r\n").await.unwrap();
+            std::future::pending::<()>().await;
+        });
+        let (_, _, body) = read_response(&mut (Box::new(local) as Box<dyn LocalStream>), "HEAD").await.unwrap();
+        assert!(body.is_empty());
+    }
 }
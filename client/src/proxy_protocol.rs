@@ -0,0 +1,104 @@
+//! PROXY protocol headers for local TCP services
+//!
+//! With `proxy_protocol: v1` or `v2` on a TCP tunnel, each connection
+//! to the local service starts with a PROXY protocol header naming the
+//! visitor, so PostgreSQL, HAProxy, Postfix and the like see the real
+//! client address instead of the tunnel's. The destination is the local
+//! service. When the relay didn't say who connected the header says
+//! so (`UNKNOWN` / `LOCAL`), which receivers accept and ignore.
+
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+
+/// Which header to send
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Version {
+    /// Text header
+    V1,
+    /// Binary header
+    V2,
+}
+
+/// Start of every v2 header
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Header for a connection from `source` to `dest`
+pub fn header(version: Version, source: Option<SocketAddr>, dest: Option<SocketAddr>) -> Vec<u8> {
+    let addrs = source.map(|source| {
+        let dest = dest.unwrap_or_else(|| {
+            let unspecified = match source.ip() {
+                IpAddr::V4(_) => IpAddr::from([0, 0, 0, 0]),
+                IpAddr::V6(_) => IpAddr::from(Ipv6Addr::UNSPECIFIED),
+            };
+            SocketAddr::new(unspecified, 0)
+        });
+        same_family(source, dest)
+    });
+    match version {
+        Version::V1 => match addrs {
+            Some((source, dest)) => {
+                let family = if source.is_ipv4() { "TCP4" } else { "TCP6" };
+                format!("PROXY {} {} {} {} {}\r\n", family, source.ip(), dest.ip(), source.port(), dest.port())
+                    .into_bytes()
+            }
+            None => b"PROXY UNKNOWN\r\n".to_vec(),
+        },
+        Version::V2 => {
+            let mut out = V2_SIGNATURE.to_vec();
+            let Some((source, dest)) = addrs else {
+                // LOCAL command, no addresses
+                out.extend([0x20, 0x00, 0, 0]);
+                return out;
+            };
+            let (family, mut body) = match (source.ip(), dest.ip()) {
+                (IpAddr::V4(s), IpAddr::V4(d)) => (0x11, [s.octets(), d.octets()].concat()),
+                (IpAddr::V6(s), IpAddr::V6(d)) => (0x21, [s.octets(), d.octets()].concat()),
+                _ => unreachable!("same_family"),
+            };
+            body.extend(source.port().to_be_bytes());
+            body.extend(dest.port().to_be_bytes());
+            out.extend([0x21, family]);
+            out.extend((body.len() as u16).to_be_bytes());
+            out.extend(body);
+            out
+        }
+    }
+}
+
+/// Both addresses as IPv4 if they can be, otherwise both as IPv6
+fn same_family(a: SocketAddr, b: SocketAddr) -> (SocketAddr, SocketAddr) {
+    if a.is_ipv4() == b.is_ipv4() {
+        return (a, b);
+    }
+    let v6 = |addr: SocketAddr| match addr.ip() {
+        IpAddr::V4(ip) => SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), addr.port()),
+        IpAddr::V6(_) => addr,
+    };
+    (v6(a), v6(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_proxy_headers() {
+        let visitor: SocketAddr = "203.0.113.7:51234".parse().unwrap();
+        let local: SocketAddr = "127.0.0.1:5432".parse().unwrap();
+        assert_eq!(header(Version::V1, Some(visitor), Some(local)), b"PROXY TCP4 203.0.113.7 127.0.0.1 51234 5432\r\n");
+        assert_eq!(header(Version::V1, None, Some(local)), b"PROXY UNKNOWN\r\n");
+        let v6: SocketAddr = "[2001:db8::1]:443".parse().unwrap();
+        assert_eq!(
+            header(Version::V1, Some(v6), Some(local)),
+            b"PROXY TCP6 2001:db8::1 ::ffff:127.0.0.1 443 5432\r\n"
+        );
+
+        let v2 = header(Version::V2, Some(visitor), Some(local));
+        assert_eq!(&v2[..12], &V2_SIGNATURE);
+        assert_eq!(&v2[12..16], &[0x21, 0x11, 0, 12]);
+        assert_eq!(&v2[16..], &[203, 0, 113, 7, 127, 0, 0, 1, 0xC8, 0x22, 0x15, 0x38]);
+        assert_eq!(header(Version::V2, Some(v6), None).len(), 16 + 36);
+        assert_eq!(&header(Version::V2, None, None)[12..], &[0x20, 0, 0, 0]);
+    }
+}
@@ -0,0 +1,111 @@
+//! Record-only local proxy
+//!
+//! `ztunnel proxy 3000 --listen 8081` answers on a local port by
+//! forwarding to the service, recording every exchange in the inspector
+//! as a tunnel would, but with no relay: the inspector, replay and
+//! export work offline for debugging an API on this machine.
+
+use axum::body::Body;
+use axum::extract::{Request, State};
+use axum::http::{HeaderName, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Router;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tracing::warn;
+
+use crate::config::DEFAULT_MAX_BODY_BYTES;
+use crate::session::{self, TunnelContext};
+use crate::tunnel::TunnelRequest;
+
+/// Proxy requests arriving on `listener` to `ctx`'s local service
+pub async fn serve(listener: tokio::net::TcpListener, ctx: Arc<TunnelContext>) -> anyhow::Result<()> {
+    let app = Router::new().fallback(handle).with_state(ctx);
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+async fn handle(State(ctx): State<Arc<TunnelContext>>, req: Request) -> Response {
+    let (parts, body) = req.into_parts();
+    let body = match axum::body::to_bytes(body, DEFAULT_MAX_BODY_BYTES).await {
+        Ok(body) => body,
+        Err(_) => return (StatusCode::PAYLOAD_TOO_LARGE, "Request body too large").into_response(),
+    };
+    let request = TunnelRequest {
+        id: next_id(),
+        method: parts.method.to_string(),
+        path: parts.uri.path_and_query().map_or("/", |p| p.as_str()).to_string(),
+        headers: parts
+            .headers
+            .iter()
+            .filter_map(|(k, v)| Some((k.to_string(), v.to_str().ok()?.to_string())))
+            .collect(),
+        body: (!body.is_empty()).then_some(body),
+        streamed: false,
+    };
+    let response = match session::handle_direct(request, &ctx).await {
+        Ok(response) => response,
+        Err(e) => {
+            warn!("[{}] Error: {}", ctx.conf.name, e);
+            return (StatusCode::BAD_GATEWAY, "Local service error").into_response();
+        }
+    };
+    let mut builder = Response::builder().status(StatusCode::from_u16(response.status).unwrap_or(StatusCode::OK));
+    if let Some(headers) = builder.headers_mut() {
+        for (k, v) in &response.headers {
+            if let (Ok(name), Ok(value)) = (HeaderName::from_bytes(k.as_bytes()), HeaderValue::from_str(v)) {
+                headers.append(name, value);
+            }
+        }
+    }
+    builder.body(Body::from(response.body.unwrap_or_default())).unwrap_or_else(|_| StatusCode::BAD_GATEWAY.into_response())
+}
+
+fn next_id() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default();
+    format!("local-{:x}-{}", nanos, COUNTER.fetch_add(1, Ordering::Relaxed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TunnelConfig;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    async fn test_records_without_relay() {
+        // A local service that answers every request the same way
+        let service = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = service.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut conn, _)) = service.accept().await {
+                let mut buf = [0u8; 4096];
+                let _ = conn.read(&mut buf).await;
+                let _ = conn.write_all(b"HTTP/1.1 201 Created\r\nContent-Length: 2\r\nX-Seen: yes\r\n\r\nok").await;
+            }
+        });
+
+        let (entry_tx, mut entry_rx) = mpsc::channel(4);
+        let conf = TunnelConfig { name: "proxy".into(), local_port: port, ..Default::default() };
+        let ctx = Arc::new(TunnelContext::new(conf, entry_tx));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, ctx));
+
+        let response = reqwest::Client::new()
+            .post(format!("http://{}/items?page=2", addr))
+            .body("hello")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 201);
+        assert_eq!(response.headers()["x-seen"], "yes");
+        assert_eq!(response.text().await.unwrap(), "ok");
+
+        let entry = entry_rx.recv().await.unwrap();
+        assert_eq!((entry.method.as_str(), entry.path.as_str(), entry.status), ("POST", "/items?page=2", 201));
+        assert_eq!(entry.req_body.unwrap().bytes(), b"hello");
+    }
+}
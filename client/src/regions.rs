@@ -0,0 +1,93 @@
+//! Relays by region name
+//!
+//! `ztunnel http --region eu` connects to the relay the name stands for
+//! instead of `--relay`. The hosted regions are built in; `regions:` in
+//! ztunnel.yml adds names (or points built-in ones elsewhere) for
+//! self-hosted multi-region setups:
+//!
+//! ```yaml
+//! regions:
+//!   eu: wss://eu.relay.example.com/tunnel
+//!   home: ws://10.0.0.2:8080/tunnel
+//! ```
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Regions every client knows
+pub const BUILTIN: &[(&str, &str)] = &[
+    ("ap", "wss://ap.connectus.net.in/tunnel"),
+    ("eu", "wss://eu.connectus.net.in/tunnel"),
+    ("us", "wss://us.connectus.net.in/tunnel"),
+];
+
+/// `regions:` from a config file, which needn't define any tunnels
+pub fn load(path: &Path) -> Result<BTreeMap<String, String>> {
+    #[derive(Deserialize)]
+    struct Regions {
+        #[serde(default)]
+        regions: BTreeMap<String, String>,
+    }
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read config file: {}", path.display()))?;
+    let content = crate::config::interpolate_env(&content, |var| std::env::var(var).ok())
+        .with_context(|| format!("Failed to expand config file: {}", path.display()))?;
+    let file: Regions = serde_yaml::from_str(&content)
+        .with_context(|| format!("Failed to parse config file: {}", path.display()))?;
+    validate(&file.regions)?;
+    Ok(file.regions)
+}
+
+/// Every configured region names a WebSocket URL
+pub fn validate(configured: &BTreeMap<String, String>) -> Result<()> {
+    for (name, url) in configured {
+        if !(url.starts_with("ws://") || url.starts_with("wss://")) {
+            anyhow::bail!("Region '{}': relay URL must start with ws:// or wss://, got '{}'", name, url);
+        }
+    }
+    Ok(())
+}
+
+/// Built-in regions with `configured` ones added over them
+pub fn all(configured: &BTreeMap<String, String>) -> BTreeMap<String, String> {
+    let mut regions: BTreeMap<String, String> =
+        BUILTIN.iter().map(|(name, url)| (name.to_string(), url.to_string())).collect();
+    regions.extend(configured.iter().map(|(name, url)| (name.to_ascii_lowercase(), url.clone())));
+    regions
+}
+
+/// The relay URL of region `name`
+pub fn resolve(name: &str, configured: &BTreeMap<String, String>) -> Result<String> {
+    let mut regions = all(configured);
+    match regions.remove(&name.to_ascii_lowercase()) {
+        Some(url) => Ok(url),
+        None => anyhow::bail!(
+            "Unknown region '{}'; known regions: {}",
+            name,
+            regions.keys().cloned().collect::<Vec<_>>().join(", ")
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_region() {
+        let configured = BTreeMap::from([
+            ("home".to_string(), "ws://10.0.0.2:8080/tunnel".to_string()),
+            ("EU".to_string(), "wss://eu.relay.example.com/tunnel".to_string()),
+        ]);
+        assert_eq!(resolve("us", &configured).unwrap(), "wss://us.connectus.net.in/tunnel");
+        assert_eq!(resolve("Home", &configured).unwrap(), "ws://10.0.0.2:8080/tunnel");
+        assert_eq!(resolve("eu", &configured).unwrap(), "wss://eu.relay.example.com/tunnel");
+        let err = resolve("mars", &configured).unwrap_err().to_string();
+        assert_eq!(err, "Unknown region 'mars'; known regions: ap, eu, home, us");
+
+        let bad = BTreeMap::from([("eu".to_string(), "https://eu.relay.example.com".to_string())]);
+        assert!(validate(&bad).is_err());
+    }
+}
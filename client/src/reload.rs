@@ -0,0 +1,110 @@
+//! Config hot reload
+//!
+//! Watches ztunnel.yml and hands each valid edit to the `TunnelManager`,
+//! which restarts only the tunnels that changed. Invalid edits are
+//! reported and ignored, so a typo never takes running tunnels down.
+//! An ngrok config given with `--from-ngrok` is re-imported the same way.
+
+use ztunnel_client::config::ZTunnelConfig;
+use ztunnel_client::logging::{self, banner};
+use ztunnel_client::multi::TunnelManager;
+use ztunnel_client::ngrok;
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tracing::{error, info, warn};
+use ztunnel_shared::watch::FileWatch;
+
+/// How the config was selected at startup, reapplied on every reload
+#[derive(Debug, Clone, Default)]
+pub struct LoadOptions {
+    /// `--profile`
+    pub profile: Option<String>,
+    /// `--auth-token`, overriding the file's token
+    pub auth_token: Option<String>,
+    /// With `--from-ngrok` the file is an ngrok config, served through
+    /// these relays since ngrok configs don't name one
+    pub ngrok_relays: Option<Vec<String>>,
+}
+
+impl LoadOptions {
+    /// Read the config file the way it was selected
+    pub fn load(&self, path: &Path) -> Result<ZTunnelConfig> {
+        let mut config = match &self.ngrok_relays {
+            Some(relays) => {
+                let imported = ngrok::load(path)?;
+                for note in &imported.notes {
+                    warn!("{}", note);
+                }
+                let mut config = imported.config;
+                if let Some((first, rest)) = relays.split_first() {
+                    config.relay = first.clone();
+                    config.relays = rest.to_vec();
+                }
+                config
+            }
+            None => ZTunnelConfig::load(path, self.profile.as_deref())?,
+        };
+        // --auth-token / ZTUNNEL_AUTH_TOKEN override the config file
+        if self.auth_token.is_some() {
+            config.auth_token = self.auth_token.clone();
+        }
+        Ok(config)
+    }
+}
+
+/// Reload `path` into `manager` whenever it changes
+pub async fn watch(
+    path: PathBuf,
+    opts: LoadOptions,
+    manager: Arc<Mutex<TunnelManager>>,
+) -> Result<()> {
+    let mut changes = FileWatch::new(&path)?;
+    info!("Watching {} for changes", path.display());
+
+    while changes.changed().await.is_some() {
+        reload_once(&path, &opts, &manager).await;
+    }
+
+    Ok(())
+}
+
+async fn reload_once(path: &Path, opts: &LoadOptions, manager: &Mutex<TunnelManager>) {
+    let config = match opts.load(path) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Config reload failed, keeping current tunnels: {:#}", e);
+            return;
+        }
+    };
+
+    let summary = manager.lock().await.reload(config);
+    if summary.is_empty() {
+        info!("Config reloaded, no tunnel changes");
+        return;
+    }
+    if logging::is_json() {
+        info!(
+            event = "config_reloaded",
+            added = ?summary.added,
+            restarted = ?summary.restarted,
+            removed = ?summary.removed,
+            "Config reloaded"
+        );
+    } else {
+        banner!("\n\x1b[36m↻ Reloaded {}\x1b[0m", path.display());
+        for name in &summary.added {
+            banner!("  + {}", name);
+        }
+        for name in &summary.restarted {
+            banner!("  ~ {}", name);
+        }
+        for name in &summary.removed {
+            banner!("  - {}", name);
+        }
+    }
+    for name in &summary.failed {
+        warn!("Tunnel '{}' failed to start after reload", name);
+    }
+}
@@ -0,0 +1,267 @@
+//! Inspector replay
+//!
+//! `POST /replay/{id}` re-sends a recorded request to the local service,
+//! optionally with its method, path, headers, or body changed first. The
+//! response is recorded as a new inspector entry whose `replay_of` points
+//! back at the original.
+//!
+//! Replays can go somewhere else than the tunnel's local service: to a
+//! tunnel's `replay_target`, or per replay with `?target=HOST:PORT`, e.g.
+//! to run captured webhooks against a fix on another port. The request
+//! is sent as the tunnel would send it, Host header included.
+
+use crate::body::Body;
+use crate::inspector::{EntryKind, InspectorEntry, InspectorState};
+use crate::proxy::{self, LocalTarget};
+use crate::session::TunnelContext;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{info, warn};
+
+/// Changes applied to a request before it is replayed
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReplayOverrides {
+    pub method: Option<String>,
+    pub path: Option<String>,
+    /// Replaces the full header list
+    pub headers: Option<Vec<(String, String)>>,
+    pub body: Option<String>,
+}
+
+/// A replay asked for by the dashboard; answered with the new entry's ID
+#[derive(Debug)]
+pub struct ReplayRequest {
+    pub id: String,
+    pub overrides: ReplayOverrides,
+    /// Send here instead of the tunnel's replay target
+    pub target: Option<LocalTarget>,
+    pub reply: oneshot::Sender<Result<String, String>>,
+}
+
+/// Where a tunnel's replays are sent
+#[derive(Debug, Clone)]
+pub struct ReplayTarget {
+    pub target: LocalTarget,
+    pub host_header: String,
+}
+
+impl ReplayTarget {
+    /// Where a tunnel's replays go: its `replay_target` if set, else
+    /// its local service
+    pub fn for_tunnel(ctx: &TunnelContext) -> Self {
+        let target = ctx.conf.replay_target.as_deref().and_then(LocalTarget::parse);
+        Self { target: target.unwrap_or_else(|| ctx.target.clone()), host_header: ctx.local_host_header() }
+    }
+}
+
+/// Replay targets by tunnel name, kept current as tunnels start and stop
+#[derive(Debug, Clone, Default)]
+pub struct ReplayTargets(Arc<RwLock<HashMap<String, ReplayTarget>>>);
+
+impl ReplayTargets {
+    pub fn insert(&self, tunnel: &str, target: ReplayTarget) {
+        self.0.write().unwrap_or_else(|e| e.into_inner()).insert(tunnel.to_string(), target);
+    }
+
+    pub fn remove(&self, tunnel: &str) {
+        self.0.write().unwrap_or_else(|e| e.into_inner()).remove(tunnel);
+    }
+
+    /// Target for an entry's tunnel. Entries recorded without a tunnel
+    /// name go to the only tunnel, if there is just one.
+    pub fn resolve(&self, entry: &InspectorEntry) -> Option<ReplayTarget> {
+        let targets = self.0.read().unwrap_or_else(|e| e.into_inner());
+        match targets.get(&entry.tunnel) {
+            Some(target) => Some(target.clone()),
+            None if entry.tunnel.is_empty() && targets.len() == 1 => targets.values().next().cloned(),
+            None => None,
+        }
+    }
+}
+
+/// Send `original`, with `overrides` applied, to the local service and
+/// build the entry for the result
+pub async fn execute(
+    original: &InspectorEntry,
+    overrides: ReplayOverrides,
+    to: &ReplayTarget,
+) -> Result<InspectorEntry> {
+    let method = overrides.method.unwrap_or_else(|| original.method.clone()).to_uppercase();
+    let path = overrides.path.unwrap_or_else(|| original.path.clone());
+    let mut headers = overrides.headers.unwrap_or_else(|| original.req_headers.clone());
+    let body = match (overrides.body, &original.req_body) {
+        (Some(body), _) => Some(body.into_bytes()),
+        (None, Some(body)) if body.truncated() => anyhow::bail!(
+            "Request body was recorded only in part ({} of {} bytes); replay it with a body override",
+            body.bytes().len(),
+            body.size()
+        ),
+        (None, body) => body.as_ref().map(|b| b.bytes().to_vec()),
+    };
+    // The body is sent whole with a fresh Content-Length
+    headers.retain(|(k, _)| !k.eq_ignore_ascii_case("content-length") && !k.eq_ignore_ascii_case("transfer-encoding"));
+
+    let start = Instant::now();
+    let (status, res_headers, res_body) = proxy::forward_http(
+        to.target.connect().await?,
+        &to.host_header,
+        &method,
+        &path,
+        &headers,
+        body.as_deref(),
+    )
+    .await?;
+
+    Ok(InspectorEntry {
+        id: next_id(),
+        tunnel: original.tunnel.clone(),
+        kind: EntryKind::Http,
+        timestamp: chrono::Utc::now().to_rfc3339(),
+        method,
+        path,
+        status,
+        latency_ms: start.elapsed().as_millis() as u64,
+        req_headers: headers,
+        req_body: body.map(Body::new),
+        res_headers,
+        res_body_size: res_body.len(),
+        res_encoding: None,
+        res_body: Some(Body::new(res_body)),
+        replay_of: Some(original.id.clone()),
+        signature: None,
+        cache: None,
+        slow: false,
+    })
+}
+
+/// Answer replay requests until the inspector goes away, sending each
+/// to the local service of the tunnel that recorded it
+pub async fn serve(mut rx: mpsc::Receiver<ReplayRequest>, inspector: InspectorState, targets: ReplayTargets) {
+    while let Some(req) = rx.recv().await {
+        let result = match inspector.get_entry(&req.id).await {
+            None => Err(format!("Request {} not found", req.id)),
+            Some(original) => match resolve(&targets, &original, req.target) {
+                None => Err(format!("Replay is not available for tunnel '{}'", original.tunnel)),
+                Some(to) => match execute(&original, req.overrides, &to).await {
+                    Ok(entry) => {
+                        info!(
+                            "Replayed {} as {}: {} {} → {}",
+                            original.id, entry.id, entry.method, entry.path, entry.status
+                        );
+                        let id = entry.id.clone();
+                        inspector.record(entry).await;
+                        Ok(id)
+                    }
+                    Err(e) => {
+                        warn!("Replay of {} failed: {}", original.id, e);
+                        Err(e.to_string())
+                    }
+                },
+            },
+        };
+        let _ = req.reply.send(result);
+    }
+}
+
+/// The tunnel's replay target, with the connection moved to `target`
+/// when one was asked for
+fn resolve(targets: &ReplayTargets, original: &InspectorEntry, target: Option<LocalTarget>) -> Option<ReplayTarget> {
+    match (targets.resolve(original), target) {
+        (Some(to), Some(target)) => Some(ReplayTarget { target, ..to }),
+        (None, Some(target)) => Some(ReplayTarget { host_header: target.host_header(), target }),
+        (to, None) => to,
+    }
+}
+
+/// IDs for replayed entries, unique within this process
+fn next_id() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default();
+    format!("replay-{:x}-{}", nanos, COUNTER.fetch_add(1, Ordering::Relaxed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_replay_with_overrides() {
+        // Local service that echoes the request head back as the body
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut sock, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0u8; 4096];
+            let n = sock.read(&mut buf).await.unwrap();
+            let echo = String::from_utf8_lossy(&buf[..n]).to_string();
+            let resp = format!("HTTP/1.1 201 Created\r\nContent-Length: {}\r\n\r\n{}", echo.len(), echo);
+            sock.write_all(resp.as_bytes()).await.unwrap();
+        });
+
+        let original = InspectorEntry {
+            id: "r1".into(),
+            method: "GET".into(),
+            path: "/orders".into(),
+            req_headers: vec![("X-Trace".into(), "abc".into()), ("Content-Length".into(), "0".into())],
+            ..Default::default()
+        };
+        let overrides = ReplayOverrides {
+            method: Some("post".into()),
+            body: Some("{\"qty\":2}".into()),
+            ..Default::default()
+        };
+        let to = ReplayTarget {
+            target: LocalTarget::Tcp { host: "127.0.0.1".into(), port },
+            host_header: "localhost".into(),
+        };
+
+        let entry = execute(&original, overrides, &to).await.unwrap();
+        assert_eq!(entry.replay_of.as_deref(), Some("r1"));
+        assert_ne!(entry.id, "r1");
+        assert_eq!((entry.method.as_str(), entry.path.as_str(), entry.status), ("POST", "/orders", 201));
+        let echoed = entry.res_body.unwrap().text().unwrap().to_string();
+        assert!(echoed.starts_with("POST /orders HTTP/1.1\r\n"));
+        assert!(echoed.contains("X-Trace: abc\r\n"));
+        assert!(echoed.contains("Content-Length: 9\r\n"));
+        assert!(!echoed.contains("Content-Length: 0"));
+        assert!(echoed.ends_with("{\"qty\":2}"));
+    }
+
+    #[test]
+    fn test_targets_resolve_by_tunnel() {
+        let targets = ReplayTargets::default();
+        let target = |port| ReplayTarget {
+            target: LocalTarget::Tcp { host: "127.0.0.1".into(), port },
+            host_header: "localhost".into(),
+        };
+        let entry = |tunnel: &str| InspectorEntry { tunnel: tunnel.into(), ..Default::default() };
+        let port_of = |t: Option<ReplayTarget>| match t.map(|t| t.target) {
+            Some(LocalTarget::Tcp { port, .. }) => Some(port),
+            _ => None,
+        };
+
+        targets.insert("web", target(3000));
+        // Untagged entries fall back to a lone tunnel
+        assert_eq!(port_of(targets.resolve(&entry(""))), Some(3000));
+
+        targets.insert("api", target(8000));
+        assert_eq!(port_of(targets.resolve(&entry("api"))), Some(8000));
+        assert_eq!(port_of(targets.resolve(&entry(""))), None);
+
+        targets.remove("api");
+        assert_eq!(port_of(targets.resolve(&entry("api"))), None);
+
+        // An explicit target moves the connection but keeps the Host header
+        let moved = resolve(&targets, &entry("web"), LocalTarget::parse("3001")).unwrap();
+        assert_eq!((port_of(Some(moved.clone())), moved.host_header.as_str()), (Some(3001), "localhost"));
+        let orphan = resolve(&targets, &entry("gone"), LocalTarget::parse("3001")).unwrap();
+        assert_eq!(orphan.host_header, "127.0.0.1:3001");
+    }
+}
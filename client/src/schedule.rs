@@ -0,0 +1,49 @@
+//! Hours a tunnel is connected
+//!
+//! `active_hours` in a tunnel's definition keeps it registered only
+//! during the given windows of local time and disconnected otherwise,
+//! so a demo isn't reachable overnight:
+//!
+//! ```yaml
+//! active_hours: "Mon-Fri 09:00-18:00; Sat,Sun 10:00-14:00"
+//! ```
+//!
+//! The format is `ztunnel_shared::schedule`'s.
+
+use chrono::Local;
+use tracing::info;
+
+pub use ztunnel_shared::schedule::Schedule;
+
+use crate::logging::banner;
+
+/// Longest sleep between looks at the clock
+const RECHECK: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Wait for the next window, saying when it opens
+pub async fn wait_active(schedule: &Schedule, tunnel: &str) {
+    let now = Local::now().naive_local();
+    if !schedule.is_active(now) {
+        let opens = schedule.next_change(now);
+        info!("Tunnel '{}' is outside its active hours until {}", tunnel, opens);
+        banner!("\x1b[90m⏾ {}: outside active hours; connecting {}\x1b[0m", tunnel, opens.format("%a %H:%M"));
+    }
+    wait_until(schedule, true).await
+}
+
+/// Resolves once the current window closes
+pub async fn wait_inactive(schedule: &Schedule) {
+    wait_until(schedule, false).await
+}
+
+async fn wait_until(schedule: &Schedule, active: bool) {
+    loop {
+        let now = Local::now().naive_local();
+        if schedule.is_active(now) == active {
+            return;
+        }
+        // Checked every so often in case the clock jumps
+        let left = (schedule.next_change(now) - now).to_std().unwrap_or_default();
+        tokio::time::sleep(left.min(RECHECK)).await;
+    }
+}
@@ -0,0 +1,1596 @@
+//! Single tunnel session
+//!
+//! Connects to the relay, registers a tunnel, serves requests until the
+//! connection drops, and reconnects with backoff. Used by both the ad-hoc
+//! `http` command and the multi-tunnel manager.
+
+use crate::auth::BasicAuth;
+use crate::backoff::Backoff;
+use crate::batch::BatchSink;
+use crate::body::Body;
+use crate::cache::{self, CacheStatus, ResponseCache};
+use crate::e2e::{self, E2eEndpoint, Unwrapped};
+use crate::config::{TunnelConfig, DEFAULT_MAX_BODY_BYTES};
+use crate::filter::PathFilter;
+use crate::headers::HeaderRules;
+use crate::hooks::Hooks;
+use crate::frames::{self, FrameLog};
+use crate::inspector::{EntryKind, InspectorEntry};
+use crate::intercept::{self, HeldRequest, Intercept, Verdict};
+use crate::logging::banner;
+use crate::pause::Pause;
+use crate::proxy::{self, FixedResponse, LocalStream, LocalTarget, Unreachable, Upgrade};
+use crate::proxy_protocol;
+use crate::schedule::{self, Schedule};
+use crate::socks;
+use crate::stream::Streams;
+use crate::tunnel::{StreamEvent, StreamFrame, TunnelRequest, TunnelResponse};
+use crate::throttle::Throttle;
+use crate::webhook::WebhookVerifier;
+use anyhow::Result;
+use bytes::Bytes;
+use futures_util::stream::{FuturesUnordered, SplitSink, SplitStream};
+use futures_util::{SinkExt, StreamExt};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
+use tracing::{debug, error, info, warn};
+use ztunnel_shared::{batch, deflate};
+use ztunnel_shared::protocol::{capability, IpFilterRules, Notice, Register, RegisterAck};
+use ztunnel_shared::report;
+use ztunnel_shared::telemetry::{metric, Noop, Telemetry};
+use ztunnel_shared::RetryAdvice;
+
+type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+type WsWrite = SplitSink<WsStream, Message>;
+type WsRead = SplitStream<WsStream>;
+
+/// Consecutive failures to reach the local service before they're
+/// reported as an error, once per run of failures
+const REPORT_AFTER_FAILURES: u64 = 5;
+
+/// Per-tunnel state shared by every request on the tunnel
+pub struct TunnelContext {
+    pub conf: TunnelConfig,
+    pub target: LocalTarget,
+    pub inspector_tx: mpsc::Sender<InspectorEntry>,
+    /// Credentials required from visitors
+    pub basic_auth: Option<BasicAuth>,
+    /// Paths allowed to reach the local service
+    pub path_filter: PathFilter,
+    /// Rewrites for request headers sent to the local service
+    pub request_headers: HeaderRules,
+    /// Rewrites for response headers sent back through the tunnel
+    pub response_headers: HeaderRules,
+    /// Token presented to the relay when registering
+    pub auth_token: Option<String>,
+    /// Answer locally instead of contacting the target
+    pub respond: Option<FixedResponse>,
+    /// Artificial latency added before each request
+    pub latency: Option<Duration>,
+    /// Bandwidth limit on traffic to and from the local service
+    pub throttle: Option<Throttle>,
+    /// Requests held for approval in the inspector
+    pub intercept: Option<Intercept>,
+    /// Where WebSocket frames are captured, if the tunnel is inspected
+    pub frames: Option<FrameLog>,
+    /// Lifecycle hook commands from the tunnel's config
+    pub hooks: Option<Hooks>,
+    /// Webhook signature checks from the tunnel's config
+    pub webhook: Option<WebhookVerifier>,
+    /// Key for end-to-end encrypted requests, with `e2e`
+    pub e2e: Option<E2eEndpoint>,
+    /// Give up on the local service after this long, with `local_timeout`
+    pub local_timeout: Option<Duration>,
+    /// Responses reused for repeated requests, with `cache`
+    pub cache: Option<ResponseCache>,
+    /// Answers 503 to everything while switched on
+    pub pause: Pause,
+    /// Hours the tunnel stays registered, with `active_hours`
+    pub schedule: Option<Schedule>,
+    /// HTTP requests handled / TCP connections opened
+    pub requests: Arc<AtomicU64>,
+    /// Failed connections to the local service since the last success
+    pub local_failures: Arc<AtomicU64>,
+    /// Handed out by the relay to get the same tunnel back on reconnect
+    pub resume_token: Option<String>,
+    /// Where request and reconnect metrics go; `Noop` unless an
+    /// embedding program supplies a backend
+    pub telemetry: Arc<dyn Telemetry>,
+    /// The relay takes large response bodies as stream frames; set at
+    /// each registration
+    pub stream_responses: bool,
+    /// The relay takes small messages joined into batches; set at each
+    /// registration
+    pub batch_messages: bool,
+    /// Binary messages on the relay link are compressed; set at each
+    /// registration
+    pub deflate_messages: bool,
+}
+
+impl TunnelContext {
+    pub fn new(conf: TunnelConfig, inspector_tx: mpsc::Sender<InspectorEntry>) -> Self {
+        let throttle = (conf.throttle_bps > 0).then(|| Throttle::new(conf.throttle_bps, conf.throttle_burst));
+        Self {
+            target: LocalTarget::from_config(&conf),
+            basic_auth: conf.basic_auth.as_deref().and_then(BasicAuth::parse),
+            path_filter: PathFilter::new(&conf.allow_paths, &conf.deny_paths),
+            webhook: conf.webhook.as_ref().and_then(WebhookVerifier::from_config),
+            hooks: Hooks::from_config(&conf.name, &conf.hooks),
+            e2e: conf.e2e.then(E2eEndpoint::default),
+            local_timeout: conf.local_timeout.as_deref().and_then(crate::config::parse_duration),
+            cache: conf.cache.as_ref().and_then(ResponseCache::from_config),
+            pause: Pause::new(conf.pause_page.as_ref().map(Into::into)),
+            schedule: conf.active_hours.as_deref().and_then(|spec| Schedule::parse(spec).ok()),
+            request_headers: HeaderRules::from_config(&conf.request_headers),
+            response_headers: HeaderRules::from_config(&conf.response_headers),
+            conf,
+            inspector_tx,
+            auth_token: None,
+            respond: None,
+            latency: None,
+            throttle,
+            intercept: None,
+            frames: None,
+            requests: Arc::new(AtomicU64::new(0)),
+            local_failures: Arc::new(AtomicU64::new(0)),
+            resume_token: None,
+            telemetry: Arc::new(Noop),
+            stream_responses: false,
+            batch_messages: false,
+            deflate_messages: false,
+        }
+    }
+
+    /// Host header sent to the local service
+    pub fn local_host_header(&self) -> String {
+        self.conf.host_header.clone().unwrap_or_else(|| self.target.host_header())
+    }
+
+    /// Whether a request passes the tunnel's own checks, so holding it
+    /// for approval makes sense
+    fn would_forward(&self, request: &TunnelRequest) -> bool {
+        self.basic_auth.as_ref().is_none_or(|auth| auth.is_authorized(&request.headers))
+            && self.path_filter.is_allowed(&request.path)
+            && !self.pause.is_paused()
+    }
+
+    /// Connect to the local service, applying the tunnel's throttle and
+    /// counting consecutive failures
+    pub async fn connect_local(&self) -> Result<Box<dyn LocalStream>, Unreachable> {
+        let stream = match self.target.connect().await {
+            Ok(stream) => stream,
+            Err(source) => {
+                self.local_failures.fetch_add(1, Ordering::Relaxed);
+                return Err(Unreachable { target: self.target.to_string(), source });
+            }
+        };
+        self.local_failures.store(0, Ordering::Relaxed);
+        Ok(match &self.throttle {
+            Some(throttle) => Box::new(throttle.wrap(stream)),
+            None => stream,
+        })
+    }
+
+    /// Answer for a request the local service wasn't there for
+    fn unreachable(&self, request: &TunnelRequest, down: &Unreachable) -> (u16, Vec<(String, String)>, Vec<u8>) {
+        let failures = self.local_failures.load(Ordering::Relaxed);
+        warn!("[{}] {} {}: {} ({} in a row)", self.conf.name, request.method, request.path, down, failures);
+        if failures == REPORT_AFTER_FAILURES {
+            report::capture(
+                report::Level::Error,
+                "proxy",
+                &format!("Tunnel {}: {} ({} requests in a row)", self.conf.name, down, failures),
+                &[("tunnel", &self.conf.name), ("target", &down.target)],
+            );
+        }
+        down.to_parts(&request.headers)
+    }
+}
+
+/// Relay's answer to a successful registration
+#[derive(Debug, Clone)]
+pub struct Registration {
+    pub url: String,
+    pub subdomain: String,
+    pub reassigned: bool,
+    /// Relay the tunnel is registered with
+    pub relay: String,
+    /// Present when the relay can resume this tunnel after a reconnect
+    pub resume_token: Option<String>,
+    /// Optional features the relay supports, from `protocol::capability`
+    pub capabilities: Vec<String>,
+}
+
+/// Keep a tunnel registered, reconnecting with backoff whenever the relay
+/// connection drops. With several relays, each round tries the active one
+/// first and then fails over to the others in order; once every relay has
+/// failed, the next round starts again from the primary. `on_registered`
+/// receives each registration together with the number of failed rounds
+/// that preceded it.
+///
+/// Returns only when a relay rejects the registration. A relay that asks
+/// the client to back off (rate limiting) is honoured: the next round
+/// waits at least that long.
+pub async fn run_with_reconnect<F>(
+    relays: &[String],
+    ctx: &mut TunnelContext,
+    mut on_registered: F,
+) -> Result<()>
+where
+    F: FnMut(&Registration, u32),
+{
+    if relays.is_empty() {
+        anyhow::bail!("No relay configured for '{}'", ctx.conf.name);
+    }
+    let mut backoff = Backoff::default();
+    let mut active = 0;
+    let mut last_url: Option<String> = None;
+    let mut wait_at_least = Duration::ZERO;
+
+    loop {
+        if let Some(schedule) = &ctx.schedule {
+            schedule::wait_active(schedule, &ctx.conf.name).await;
+        }
+        match connect_any(relays, active, ctx).await {
+            Ok((index, reg, write, read)) => {
+                if index != active {
+                    warn!(event = "relay_failover", tunnel = %ctx.conf.name, relay = %reg.relay, "Relay failover");
+                    banner!("\x1b[33m⇄ {}: failed over to {}\x1b[0m", ctx.conf.name, reg.relay);
+                }
+                info!(
+                    event = "tunnel_established",
+                    tunnel = %ctx.conf.name,
+                    proto = %ctx.conf.proto,
+                    url = %reg.url,
+                    relay = %reg.relay,
+                    attempts = backoff.attempt(),
+                    "Tunnel established"
+                );
+                active = index;
+                on_registered(&reg, backoff.attempt());
+                if let Some(hooks) = &ctx.hooks {
+                    hooks.connected(&reg.url, &reg.subdomain, &reg.relay, last_url.as_deref());
+                }
+                last_url = Some(reg.url.clone());
+                backoff.reset();
+                // Ask for the same subdomain if we have to reconnect
+                ctx.conf.subdomain = Some(reg.subdomain.clone());
+                ctx.resume_token = reg.resume_token.clone();
+                ctx.stream_responses = reg.capabilities.iter().any(|c| c == capability::RESPONSE_STREAM);
+                ctx.batch_messages = reg.capabilities.iter().any(|c| c == capability::BATCH);
+                ctx.deflate_messages = reg.capabilities.iter().any(|c| c == capability::DEFLATE);
+
+                let closing = async {
+                    match &ctx.schedule {
+                        Some(schedule) => schedule::wait_inactive(schedule).await,
+                        None => std::future::pending().await,
+                    }
+                };
+                let (reason, closed) = tokio::select! {
+                    result = serve(write, read, ctx) => match result {
+                        Ok(()) => {
+                            info!("Tunnel '{}' disconnected from {}", ctx.conf.name, reg.relay);
+                            (None, false)
+                        }
+                        Err(e) => {
+                            warn!("Tunnel '{}' error: {}", ctx.conf.name, e);
+                            (Some(e.to_string()), false)
+                        }
+                    },
+                    () = closing => {
+                        info!("Tunnel '{}' disconnecting: outside its active hours", ctx.conf.name);
+                        (Some("outside active hours".to_string()), true)
+                    }
+                };
+                if let Some(hooks) = &ctx.hooks {
+                    hooks.disconnected(&reg.url, &reg.relay, reason.as_deref());
+                }
+                if closed {
+                    // Not a failure; the top of the loop waits for the next window
+                    continue;
+                }
+            }
+            Err(e) => {
+                match retry_advice(&e) {
+                    RetryAdvice::Never => {
+                        let reason = e.downcast_ref::<ztunnel_shared::Error>().map(|r| r.message());
+                        anyhow::bail!("Registration failed for '{}': {}", ctx.conf.name, reason.unwrap_or(e.to_string()));
+                    }
+                    RetryAdvice::After(hint) => wait_at_least = hint,
+                    RetryAdvice::Backoff => {}
+                }
+                warn!("Tunnel '{}' could not connect: {}", ctx.conf.name, e);
+                // Every relay failed; prefer the primary again next round
+                active = 0;
+            }
+        }
+
+        let delay = backoff.next_delay().max(std::mem::take(&mut wait_at_least));
+        ctx.telemetry.counter(metric::RECONNECTS, &[("tunnel", &ctx.conf.name)], 1);
+        info!(
+            event = "reconnect",
+            tunnel = %ctx.conf.name,
+            attempt = backoff.attempt(),
+            delay_ms = delay.as_millis() as u64,
+            "Reconnecting"
+        );
+        banner!(
+            "\x1b[33m⟳ {}: reconnecting in {:.1}s (attempt {})\x1b[0m",
+            ctx.conf.name,
+            delay.as_secs_f64(),
+            backoff.attempt()
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Try each relay once, starting at `start`; returns the index of the
+/// relay that accepted the tunnel
+async fn connect_any(
+    relays: &[String],
+    start: usize,
+    ctx: &TunnelContext,
+) -> Result<(usize, Registration, WsWrite, WsRead)> {
+    let mut last_err = None;
+    for i in 0..relays.len() {
+        let index = (start + i) % relays.len();
+        match connect_and_register(&relays[index], ctx).await {
+            Ok((reg, write, read)) => return Ok((index, reg, write, read)),
+            // A rejection is the same on every relay; don't fail over
+            Err(e) if retry_advice(&e) == RetryAdvice::Never => return Err(e),
+            Err(e) => {
+                if relays.len() > 1 {
+                    warn!("Relay {} unreachable for '{}': {}", relays[index], ctx.conf.name, e);
+                }
+                last_err = Some(e);
+            }
+        }
+    }
+    Err(last_err.unwrap_or_else(|| anyhow::anyhow!("No relay configured")))
+}
+
+/// Typed errors from the relay say whether to retry; anything else
+/// (refused connection, broken handshake) is worth another try
+fn retry_advice(e: &anyhow::Error) -> RetryAdvice {
+    e.downcast_ref::<ztunnel_shared::Error>().map_or(RetryAdvice::Backoff, |e| e.retry_advice())
+}
+
+/// Open the relay WebSocket and register the tunnel
+async fn connect_and_register(relay_url: &str, ctx: &TunnelContext) -> Result<(Registration, WsWrite, WsRead)> {
+    let conf = &ctx.conf;
+    info!("Connecting tunnel '{}' ({}) to {}", conf.name, conf.proto, relay_url);
+
+    let (ws_stream, _) = connect_async(relay_url).await?;
+    let (mut write, mut read) = ws_stream.split();
+
+    let mut registration = Register::new(&conf.proto);
+    registration.subdomain = conf.subdomain.clone();
+    registration.local_port = conf.local_port;
+    registration.name = conf.name.clone();
+    registration.auth_token = ctx.auth_token.clone();
+    registration.ip_filter = conf
+        .ip_filter
+        .as_ref()
+        .map(|f| IpFilterRules { allow: f.allow.clone(), deny: f.deny.clone() })
+        .unwrap_or_default();
+    registration.policies = conf.policies.clone();
+    registration.resume_token = ctx.resume_token.clone();
+    registration.capabilities = vec![capability::BODY_STREAM.to_string(), capability::BATCH.to_string()];
+    if conf.compress {
+        registration.capabilities.push(capability::DEFLATE.to_string());
+    }
+
+    write.send(Message::Text(serde_json::to_string(&registration)?)).await?;
+
+    // Wait for confirmation
+    let Some(Ok(Message::Text(text))) = read.next().await else {
+        anyhow::bail!("Relay closed the connection during registration");
+    };
+    let ack: RegisterAck = serde_json::from_str(&text)?;
+    if let Some(err) = ack.error {
+        return Err(err.into());
+    }
+
+    let reg = Registration {
+        url: ack.url,
+        subdomain: ack.subdomain,
+        reassigned: ack.reassigned,
+        relay: relay_url.to_string(),
+        resume_token: ack.resume_token,
+        capabilities: ack.capabilities,
+    };
+
+    Ok((reg, write, read))
+}
+
+/// Serve tunneled traffic until the relay connection closes
+async fn serve(write: WsWrite, mut read: WsRead, ctx: &TunnelContext) -> Result<()> {
+    let mut write = BatchSink::new(write, ctx.batch_messages).with_deflate(ctx.deflate_messages);
+    // Messages of a batch from the relay not yet handled
+    let mut batched = VecDeque::new();
+    // Stream pumps queue their frames here; we own the sink
+    let (out_tx, mut out_rx) = mpsc::channel::<Message>(256);
+    let mut streams = Streams::new(out_tx.clone());
+    // Intercepted requests come back here once decided, so holding one
+    // doesn't stall the rest of the tunnel
+    let (release_tx, mut release_rx) = mpsc::channel::<(TunnelRequest, Instant, Verdict)>(16);
+    // Requests whose bodies are still arriving as stream frames
+    let mut uploads = FuturesUnordered::new();
+
+    loop {
+        // Handling a request can take a while, so a due batch doesn't
+        // wait for the next one as well
+        if write.deadline().is_some_and(|deadline| deadline <= Instant::now()) {
+            write.release();
+            write.flush().await?;
+        }
+        let flush_at = write.deadline().map(tokio::time::Instant::from_std);
+        let msg = if let Some(data) = batched.pop_front() {
+            Ok(Message::Binary(data))
+        } else {
+            tokio::select! {
+                msg = read.next() => match msg {
+                    Some(msg) => msg,
+                    None => break,
+                },
+                Some(out) = out_rx.recv() => {
+                    write.send(out).await?;
+                    continue;
+                }
+                _ = tokio::time::sleep_until(flush_at.unwrap_or_else(tokio::time::Instant::now)), if flush_at.is_some() => {
+                    write.release();
+                    write.flush().await?;
+                    continue;
+                }
+                Some(result) = uploads.next(), if !uploads.is_empty() => {
+                    if let Err(e) = result {
+                        warn!("[{}] Error: {}", ctx.conf.name, e);
+                    }
+                    continue;
+                }
+                Some((mut request, start, verdict)) = release_rx.recv() => {
+                    let reject = match verdict {
+                        Verdict::Approve(edits) => {
+                            intercept::apply_edits(edits, &mut request);
+                            None
+                        }
+                        Verdict::Reject(fixed) => Some(fixed),
+                    };
+                    if let Err(e) = handle_http_request(request, ctx, &mut write, Some(&mut streams), start, reject, None).await {
+                        warn!("[{}] Error: {}", ctx.conf.name, e);
+                    }
+                    continue;
+                }
+            }
+        };
+
+        match msg {
+            Ok(Message::Binary(data)) if deflate::is_compressed(&data) => match deflate::decompress(&data) {
+                Ok(data) => batched.extend(batch::split(&data).map(<[u8]>::to_vec)),
+                Err(e) => warn!("[{}] {}", ctx.conf.name, e),
+            },
+            Ok(Message::Binary(data)) if data.contains(&b'\n') => {
+                batched.extend(batch::split(&data).map(<[u8]>::to_vec));
+            }
+            Ok(Message::Binary(data)) => {
+                let start = Instant::now();
+                match ctx.conf.proto.as_str() {
+                    "http" => {
+                        if let Ok(frame) = serde_json::from_slice::<StreamFrame>(&data) {
+                            // An upload's channel is drained by its future, so
+                            // keep those running while this one waits for room
+                            let deliver = streams.deliver(frame);
+                            tokio::pin!(deliver);
+                            loop {
+                                tokio::select! {
+                                    () = &mut deliver => break,
+                                    Some(out) = out_rx.recv() => write.send(out).await?,
+                                    Some(result) = uploads.next(), if !uploads.is_empty() => {
+                                        if let Err(e) = result {
+                                            warn!("[{}] Error: {}", ctx.conf.name, e);
+                                        }
+                                    }
+                                }
+                            }
+                            continue;
+                        }
+                        let request: TunnelRequest = match serde_json::from_slice(&data) {
+                            Ok(request) => request,
+                            Err(e) => {
+                                warn!("[{}] Bad request frame: {}", ctx.conf.name, e);
+                                continue;
+                            }
+                        };
+                        if request.streamed {
+                            let body = streams.receive(request.id.clone());
+                            uploads.push(handle_upload(request, body, ctx, out_tx.clone(), start));
+                            continue;
+                        }
+                        match &ctx.intercept {
+                            Some(intercept) if intercept.matches(&request) && ctx.would_forward(&request) => {
+                                info!("[{}] Holding {} {} for approval", ctx.conf.name, request.method, request.path);
+                                let held = HeldRequest::new(&ctx.conf.name, &request);
+                                let interceptor = intercept.interceptor.clone();
+                                let release_tx = release_tx.clone();
+                                tokio::spawn(async move {
+                                    let verdict = interceptor.hold(held).await;
+                                    let _ = release_tx.send((request, start, verdict)).await;
+                                });
+                            }
+                            _ => {
+                                if let Err(e) =
+                                    handle_http_request(request, ctx, &mut write, Some(&mut streams), start, None, None).await
+                                {
+                                    warn!("[{}] Error: {}", ctx.conf.name, e);
+                                }
+                            }
+                        }
+                    }
+                    "tcp" => match serde_json::from_slice::<StreamFrame>(&data) {
+                        Ok(frame) => handle_tcp_frame(frame, ctx, &mut streams).await,
+                        Err(e) => warn!("[{}] Bad TCP frame: {}", ctx.conf.name, e),
+                    },
+                    _ => {}
+                }
+            }
+            Ok(Message::Text(text)) => match serde_json::from_str::<Notice>(&text) {
+                Ok(notice) => show_notice(&ctx.conf.name, &notice),
+                Err(e) => debug!("[{}] Unknown text message: {}", ctx.conf.name, e),
+            },
+            Ok(Message::Ping(data)) => {
+                write.send(Message::Pong(data)).await?;
+            }
+            Ok(Message::Close(_)) => break,
+            Err(e) => {
+                error!("[{}] WebSocket error: {}", ctx.conf.name, e);
+                break;
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Pass on what the relay announced, such as upcoming maintenance
+fn show_notice(tunnel: &str, notice: &Notice) {
+    let closing = match notice.closes_in {
+        Some(secs) if secs >= 60 => format!("; tunnel closes in {}m", secs / 60),
+        Some(secs) => format!("; tunnel closes in {}s", secs),
+        None => String::new(),
+    };
+    warn!("Tunnel '{}': relay notice: {}{}", tunnel, notice.message, closing);
+    banner!("\x1b[33m⚠ {}: {}{}\x1b[0m", tunnel, notice.message, closing);
+}
+
+/// Handle a request whose body arrives as stream frames. It answers
+/// through `out` so it can run alongside the serve loop, and can't
+/// switch protocols.
+async fn handle_upload(
+    request: TunnelRequest,
+    body: mpsc::Receiver<Bytes>,
+    ctx: &TunnelContext,
+    out: mpsc::Sender<Message>,
+    start: Instant,
+) -> Result<()> {
+    let mut sink = Box::pin(futures_util::sink::unfold(out, |out, message: Message| async move {
+        out.send(message).await.map(|()| out)
+    }));
+    handle_http_request(request, ctx, &mut sink, None, start, None, Some(body)).await
+}
+
+/// Answer a request that didn't come through a relay, as a tunnel would:
+/// forwarded to the local service and recorded in the inspector. It
+/// can't upgrade or be intercepted.
+pub async fn handle_direct(request: TunnelRequest, ctx: &TunnelContext) -> Result<TunnelResponse> {
+    let mut sent: Vec<Message> = Vec::new();
+    handle_http_request(request, ctx, &mut sent, None, Instant::now(), None, None).await?;
+    match sent.first() {
+        Some(Message::Binary(data)) => Ok(serde_json::from_slice(data)?),
+        _ => anyhow::bail!("No response"),
+    }
+}
+
+/// Handle an HTTP tunnel request with inspector integration. `reject`
+/// answers it without contacting the local service; `upload` carries
+/// the body of a streamed request. Without `streams` the request isn't
+/// allowed to upgrade.
+async fn handle_http_request<S>(
+    mut request: TunnelRequest,
+    ctx: &TunnelContext,
+    write: &mut S,
+    streams: Option<&mut Streams>,
+    start: Instant,
+    reject: Option<FixedResponse>,
+    upload: Option<mpsc::Receiver<Bytes>>,
+) -> Result<()>
+where
+    S: futures_util::Sink<Message> + Unpin,
+    S::Error: std::error::Error + Send + Sync + 'static,
+{
+    ctx.requests.fetch_add(1, Ordering::Relaxed);
+
+    // Decrypt end-to-end requests before anything looks at them; the
+    // response is sealed again below
+    let (sealed, e2e_reply) = match ctx.e2e.as_ref().map(|e2e| e2e.unwrap(&mut request)) {
+        Some(Unwrapped::Request(channel)) => (Some(channel), None),
+        Some(Unwrapped::Respond(fixed)) => (None, Some(fixed)),
+        None => (None, None),
+    };
+
+    // A signature covers the whole body, so a streamed one is gathered
+    // to be checked and then forwarded like any other
+    let (upload, reject) = match (upload, &ctx.webhook) {
+        (Some(body), Some(_)) => match gather(body, MAX_SIGNED_BODY).await {
+            Some(body) => {
+                request.headers.retain(|(k, _)| !k.eq_ignore_ascii_case("transfer-encoding"));
+                request.body = Some(body);
+                (None, reject)
+            }
+            None => {
+                let too_large = FixedResponse { status: 413, body: "Webhook body too large to verify".to_string() };
+                (None, reject.or(Some(too_large)))
+            }
+        },
+        (upload, _) => (upload, reject),
+    };
+
+    let authorized = match &ctx.basic_auth {
+        Some(auth) => {
+            let ok = auth.is_authorized(&request.headers);
+            // Credentials are for the tunnel, not the local service
+            request.headers.retain(|(k, _)| !k.eq_ignore_ascii_case("authorization"));
+            ok
+        }
+        None => true,
+    };
+    // Checked on the request as the provider sent it
+    let signature = ctx
+        .webhook
+        .as_ref()
+        .map(|v| v.verify(&request.headers, request.body.as_deref().unwrap_or_default()));
+    ctx.request_headers.apply(&mut request.headers);
+
+    // Apply artificial latency
+    if let Some(delay) = ctx.latency {
+        tokio::time::sleep(delay).await;
+    }
+
+    // Local connection that switched protocols, pumped once we've replied
+    let mut upgraded = None;
+    let mut cache_status = None;
+    let mut streamed_body = None;
+
+    let (status, mut headers, body) = if let Some(fixed) = reject {
+        info!("[{}] Rejected {} {} by intercept", ctx.conf.name, request.method, request.path);
+        fixed.to_parts()
+    } else if let Some(paused) = ctx.pause.response() {
+        debug!("[{}] Paused; answering {} {} with 503", ctx.conf.name, request.method, request.path);
+        paused
+    } else if let Some(fixed) = e2e_reply {
+        if fixed.status >= 400 {
+            warn!("[{}] Refused {} {}: {}", ctx.conf.name, request.method, request.path, fixed.body);
+        }
+        fixed.to_parts()
+    } else if ctx.conf.cors && crate::headers::is_preflight(&request.method, &request.headers) {
+        debug!("Answering CORS preflight for {}", request.path);
+        (204, Vec::new(), Vec::new())
+    } else if !authorized {
+        warn!("[{}] Rejected {} {}: bad credentials", ctx.conf.name, request.method, request.path);
+        let (status, mut headers, body) =
+            FixedResponse { status: 401, body: "Authentication required".to_string() }.to_parts();
+        headers.push(("WWW-Authenticate".to_string(), "Basic realm=\"ztunnel\"".to_string()));
+        (status, headers, body)
+    } else if !ctx.path_filter.is_allowed(&request.path) {
+        warn!("[{}] Blocked {} {} by path filter", ctx.conf.name, request.method, request.path);
+        FixedResponse { status: 403, body: "Forbidden by tunnel path filter".to_string() }.to_parts()
+    } else if let Some(reason) = signature
+        .as_ref()
+        .filter(|_| ctx.webhook.as_ref().is_some_and(|v| v.reject_invalid))
+        .and_then(|check| check.reason.as_ref())
+    {
+        warn!("[{}] Rejected {} {}: {}", ctx.conf.name, request.method, request.path, reason);
+        FixedResponse { status: 401, body: "Invalid webhook signature".to_string() }.to_parts()
+    } else if let Some(fixed) = &ctx.respond {
+        info!("[{}] Responding {} to {} {}", ctx.conf.name, fixed.status, request.method, request.path);
+        fixed.to_parts()
+    } else if let Some(body) = upload {
+        debug!("Streaming {} {} to {}", request.method, request.path, ctx.target);
+        let (response, captured) = forward_upload(ctx, &request, body).await?;
+        streamed_body = captured;
+        response
+    } else if streams.is_some() && proxy::is_upgrade_request(&request.headers) {
+        info!("Upgrading {} {} at {}", request.method, request.path, ctx.target);
+        match ctx.connect_local().await {
+            Ok(local) => {
+                let upgrade = proxy::forward_upgrade(
+                    local,
+                    &ctx.local_host_header(),
+                    &request.method,
+                    &request.path,
+                    &request.headers,
+                ).await?;
+                match upgrade {
+                    Upgrade::Switched { headers, stream, leftover } => {
+                        let capture = match &ctx.frames {
+                            Some(log) if frames::is_websocket(&request.headers) => Some(log.start(&request.id)),
+                            _ => None,
+                        };
+                        upgraded = Some((stream, leftover, capture));
+                        (101, headers, Vec::new())
+                    }
+                    Upgrade::Declined(status, headers, body) => (status, headers, body),
+                }
+            }
+            Err(down) => ctx.unreachable(&request, &down),
+        }
+    } else {
+        let key = ctx.cache.as_ref().and_then(|c| c.key(&request));
+        match (&ctx.cache, &key) {
+            (Some(cache), Some(key)) => match cache.get(key) {
+                Some(response) => {
+                    debug!("Answering {} {} from cache", request.method, request.path);
+                    cache_status = Some(CacheStatus::Hit);
+                    response
+                }
+                None => {
+                    debug!("Proxying {} {} to {}", request.method, request.path, ctx.target);
+                    let response = forward_local(ctx, &request).await?;
+                    cache.put(key.clone(), &response);
+                    cache_status = Some(CacheStatus::Miss);
+                    response
+                }
+            },
+            _ => {
+                debug!("Proxying {} {} to {}", request.method, request.path, ctx.target);
+                forward_local(ctx, &request).await?
+            }
+        }
+    };
+    if let Some(outcome) = cache_status {
+        cache::tag(&mut headers, outcome);
+    }
+
+    if ctx.conf.cors {
+        crate::headers::inject_cors(&request.headers, &mut headers);
+    }
+    ctx.response_headers.apply(&mut headers);
+
+    let latency_ms = start.elapsed().as_millis() as u64;
+    // Shared by the tunnel response and the inspector entry
+    let body = Bytes::from(body);
+    let body_size = body.len();
+    info!(
+        event = "request",
+        tunnel = %ctx.conf.name,
+        method = %request.method,
+        path = %request.path,
+        status,
+        latency_ms,
+        bytes = body_size,
+        "Request completed"
+    );
+    let bytes_in = match &streamed_body {
+        Some(captured) => captured.size(),
+        None => request.body.as_ref().map_or(0, Bytes::len),
+    };
+    ctx.telemetry.request(&ctx.conf.name, status, start.elapsed(), bytes_in as u64, body_size as u64);
+    let slow = ctx.conf.slow_threshold_ms.is_some_and(|threshold| latency_ms > threshold);
+    if slow {
+        ctx.telemetry.counter(metric::SLOW_REQUESTS, &[("tunnel", &ctx.conf.name)], 1);
+        if ctx.conf.slow_warn {
+            warn!(
+                "[{}] Slow response: {} {} took {}ms (threshold {}ms)",
+                ctx.conf.name,
+                request.method,
+                request.path,
+                latency_ms,
+                ctx.conf.slow_threshold_ms.unwrap_or_default()
+            );
+        }
+    }
+
+    // Send response back through tunnel. The headers are lent to the
+    // response and taken back for the inspector entry. Large bodies
+    // follow as stream frames when the relay takes them.
+    let streamed = sealed.is_none() && ctx.stream_responses && body.len() > STREAM_RESPONSE_THRESHOLD;
+    let mut response = match &sealed {
+        Some(channel) => {
+            let (status, headers, body) = e2e::seal_response(channel, &request.id, status, &headers, &body);
+            TunnelResponse { id: request.id.clone(), status, headers, body: Some(body.into()), streamed: false }
+        }
+        None => TunnelResponse {
+            id: request.id.clone(),
+            status,
+            headers: std::mem::take(&mut headers),
+            body: (!streamed).then(|| body.clone()),
+            streamed,
+        },
+    };
+    let response_data = serde_json::to_vec(&response)?;
+    if sealed.is_none() {
+        headers = std::mem::take(&mut response.headers);
+    }
+    write
+        .send(Message::Binary(response_data))
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to send response: {}", e))?;
+    if streamed {
+        send_body_frames(write, &request.id, &body).await?;
+    }
+
+    if let Some(hooks) = &ctx.hooks {
+        hooks.request(&request.id, &request.method, &request.path, status, latency_ms);
+    }
+
+    let kind = if upgraded.is_some() && frames::is_websocket(&request.headers) {
+        EntryKind::WebSocket
+    } else {
+        EntryKind::Http
+    };
+    if let (Some((stream, leftover, capture)), Some(streams)) = (upgraded, streams) {
+        streams.open(request.id.clone(), stream, leftover, capture);
+    }
+
+    // Record in inspector
+    let entry = InspectorEntry {
+        id: request.id,
+        tunnel: ctx.conf.name.clone(),
+        kind,
+        timestamp: chrono::Utc::now().to_rfc3339(),
+        method: request.method,
+        path: request.path,
+        status,
+        latency_ms,
+        req_headers: request.headers,
+        req_body: streamed_body.or(request.body.map(Body::new)),
+        res_headers: headers,
+        res_body: Some(Body::new(body)),
+        res_body_size: body_size,
+        res_encoding: None,
+        replay_of: None,
+        signature,
+        cache: cache_status,
+        slow,
+    };
+    let _ = ctx.inspector_tx.send(entry).await;
+
+    Ok(())
+}
+
+/// Response bodies larger than this are streamed to relays that take them
+const STREAM_RESPONSE_THRESHOLD: usize = 1024 * 1024;
+
+/// Largest streamed body gathered for a webhook signature; GitHub's
+/// payload limit
+const MAX_SIGNED_BODY: usize = 25 * 1024 * 1024;
+
+/// A streamed body in one piece, or None if it passes `limit`
+async fn gather(mut body: mpsc::Receiver<Bytes>, limit: usize) -> Option<Bytes> {
+    let mut whole = Vec::new();
+    while let Some(chunk) = body.recv().await {
+        if whole.len() + chunk.len() > limit {
+            return None;
+        }
+        whole.extend_from_slice(&chunk);
+    }
+    Some(whole.into())
+}
+
+/// Size of each response body frame
+const RESPONSE_FRAME_SIZE: usize = 64 * 1024;
+
+/// Send a response body as data frames on the request's id, then close
+async fn send_body_frames<S>(write: &mut S, id: &str, body: &Bytes) -> Result<()>
+where
+    S: futures_util::Sink<Message> + Unpin,
+    S::Error: std::error::Error + Send + Sync + 'static,
+{
+    let chunks = (0..body.len()).step_by(RESPONSE_FRAME_SIZE).map(|at| {
+        StreamEvent::Data(body.slice(at..body.len().min(at + RESPONSE_FRAME_SIZE)))
+    });
+    for event in chunks.chain([StreamEvent::Close]) {
+        let frame = StreamFrame::new(id, event);
+        write
+            .send(Message::Binary(serde_json::to_vec(&frame)?))
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to send response body: {}", e))?;
+    }
+    Ok(())
+}
+
+/// Methods that are safe to send twice
+const IDEMPOTENT: &[&str] = &["GET", "HEAD", "OPTIONS", "PUT", "DELETE", "TRACE"];
+
+/// Forward a request to the local service within `local_timeout`,
+/// retrying idempotent ones that time out or are refused. A request
+/// that still times out is answered 504, and one that still can't
+/// connect gets the 502 page.
+async fn forward_local(ctx: &TunnelContext, request: &TunnelRequest) -> Result<(u16, Vec<(String, String)>, Vec<u8>)> {
+    let retries = if IDEMPOTENT.contains(&request.method.to_ascii_uppercase().as_str()) { ctx.conf.retries } else { 0 };
+    let mut attempt = 0;
+    loop {
+        let forward = async {
+            proxy::forward_http(
+                ctx.connect_local().await?,
+                &ctx.local_host_header(),
+                &request.method,
+                &request.path,
+                &request.headers,
+                request.body.as_deref(),
+            )
+            .await
+        };
+        let result = match ctx.local_timeout {
+            Some(limit) => tokio::time::timeout(limit, forward).await.ok(),
+            None => Some(forward.await),
+        };
+        let failure = match result {
+            Some(Ok(response)) => return Ok(response),
+            Some(Err(e)) if attempt >= retries => {
+                return match e.downcast::<Unreachable>() {
+                    Ok(down) => Ok(ctx.unreachable(request, &down)),
+                    Err(e) => Err(e),
+                };
+            }
+            Some(Err(e)) => e.to_string(),
+            None if attempt >= retries => return Ok(timed_out(ctx, request)),
+            None => "timed out".to_string(),
+        };
+        attempt += 1;
+        warn!("[{}] Retrying {} {} ({}), attempt {}", ctx.conf.name, request.method, request.path, failure, attempt + 1);
+    }
+}
+
+/// Forward a request whose body is still arriving, writing each chunk
+/// to the local service as its frame comes in. The body can't be sent
+/// twice, so there are no retries, and `local_timeout` starts once it
+/// has been written.
+async fn forward_upload(
+    ctx: &TunnelContext,
+    request: &TunnelRequest,
+    body: mpsc::Receiver<Bytes>,
+) -> Result<((u16, Vec<(String, String)>, Vec<u8>), Option<Body>)> {
+    let local = match ctx.connect_local().await {
+        Ok(local) => local,
+        Err(down) => return Ok((ctx.unreachable(request, &down), None)),
+    };
+    let (host, method, path, headers) =
+        (ctx.local_host_header(), request.method.clone(), request.path.clone(), request.headers.clone());
+    // Spawned so the body keeps flowing whatever the serve loop is doing;
+    // the inspector never keeps more than its default limit
+    let (mut local, captured) = tokio::spawn(async move {
+        proxy::write_streamed_request(local, &host, &method, &path, &headers, body, DEFAULT_MAX_BODY_BYTES).await
+    })
+    .await??;
+
+    let response = match ctx.local_timeout {
+        Some(limit) => match tokio::time::timeout(limit, proxy::read_response(&mut local, &request.method)).await {
+            Ok(response) => response?,
+            Err(_) => timed_out(ctx, request),
+        },
+        None => proxy::read_response(&mut local, &request.method).await?,
+    };
+    Ok((response, Some(captured)))
+}
+
+/// The 504 for a request the local service didn't answer in time
+fn timed_out(ctx: &TunnelContext, request: &TunnelRequest) -> (u16, Vec<(String, String)>, Vec<u8>) {
+    let limit = ctx.local_timeout.unwrap_or_default();
+    warn!("[{}] {} {} timed out after {:?}", ctx.conf.name, request.method, request.path, limit);
+    let body = format!("Local service did not respond within {:?}", limit);
+    FixedResponse { status: 504, body }.to_parts()
+}
+
+/// Handle a TCP stream frame, dialing the local service on open
+async fn handle_tcp_frame(frame: StreamFrame, ctx: &TunnelContext, streams: &mut Streams) {
+    if frame.event != StreamEvent::Open {
+        streams.deliver(frame).await;
+        return;
+    }
+
+    if streams.contains(&frame.stream) {
+        warn!("[{}] Connection {} is already open", ctx.conf.name, frame.stream);
+        return;
+    }
+    if ctx.pause.is_paused() {
+        info!("[{}] Refusing connection {}: tunnel is paused", ctx.conf.name, frame.stream);
+        streams.reject(&frame.stream).await;
+        return;
+    }
+    let limit = ctx.conf.max_connections;
+    if limit > 0 && streams.len() >= limit {
+        warn!("[{}] Refusing connection {}: {} already open", ctx.conf.name, frame.stream, limit);
+        streams.reject(&frame.stream).await;
+        return;
+    }
+
+    ctx.requests.fetch_add(1, Ordering::Relaxed);
+    if let Some(gateway) = &ctx.conf.gateway {
+        match socks::dial(gateway, frame.target.as_deref(), frame.token.as_deref()).await {
+            Ok(remote) => {
+                info!("[{}] Connection {} → {}", ctx.conf.name, frame.stream, frame.target.unwrap_or_default());
+                streams.open(frame.stream, Box::new(remote), Vec::new(), None);
+            }
+            Err(e) => {
+                warn!("[{}] Refusing gateway connection {}: {:#}", ctx.conf.name, frame.stream, e);
+                streams.reject(&frame.stream).await;
+            }
+        }
+        return;
+    }
+    match ctx.connect_local().await {
+        Ok(mut local) => {
+            info!("[{}] Connection {} → {}", ctx.conf.name, frame.stream, ctx.target);
+            if let Some(version) = ctx.conf.proxy_protocol {
+                let header = proxy_protocol::header(version, frame.remote_addr, ctx.target.socket_addr());
+                if let Err(e) = local.write_all(&header).await {
+                    warn!("[{}] Connection {}: {}", ctx.conf.name, frame.stream, e);
+                    streams.reject(&frame.stream).await;
+                    return;
+                }
+            }
+            streams.open(frame.stream, local, Vec::new(), None);
+        }
+        Err(e) => {
+            warn!("[{}] {}", ctx.conf.name, e);
+            streams.reject(&frame.stream).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn tcp_context(port: u16) -> TunnelContext {
+        let conf = TunnelConfig {
+            name: "db".to_string(),
+            proto: "tcp".to_string(),
+            local_host: "127.0.0.1".to_string(),
+            local_port: port,
+            ..Default::default()
+        };
+        TunnelContext::new(conf, mpsc::channel(1).0)
+    }
+
+    fn frame(stream: &str, event: StreamEvent) -> StreamFrame {
+        StreamFrame::new(stream, event)
+    }
+
+    async fn next_frame(rx: &mut mpsc::Receiver<Message>) -> StreamFrame {
+        match rx.recv().await {
+            Some(Message::Binary(data)) => serde_json::from_slice(&data).unwrap(),
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+
+    async fn next_event(rx: &mut mpsc::Receiver<Message>) -> StreamEvent {
+        next_frame(rx).await.event
+    }
+
+    #[tokio::test]
+    async fn test_tcp_stream_lifecycle() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let ctx = tcp_context(listener.local_addr().unwrap().port());
+        let (out_tx, mut out_rx) = mpsc::channel(16);
+        let mut streams = Streams::new(out_tx);
+
+        handle_tcp_frame(frame("c1", StreamEvent::Open), &ctx, &mut streams).await;
+        let (mut local, _) = listener.accept().await.unwrap();
+
+        // Several round trips on one connection
+        for msg in [&b"PING\r\n"[..], b"INFO\r\n"] {
+            handle_tcp_frame(frame("c1", StreamEvent::Data(Bytes::copy_from_slice(msg))), &ctx, &mut streams).await;
+            let mut buf = vec![0u8; msg.len()];
+            local.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, msg);
+            local.write_all(b"+OK\r\n").await.unwrap();
+            assert_eq!(next_event(&mut out_rx).await, StreamEvent::Data(Bytes::from_static(b"+OK\r\n")));
+        }
+
+        // Remote close reaches the local socket
+        handle_tcp_frame(frame("c1", StreamEvent::Close), &ctx, &mut streams).await;
+        let mut buf = [0u8; 1];
+        assert_eq!(local.read(&mut buf).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_response_body_frames() {
+        let body = Bytes::from((0..150_000).map(|i| i as u8).collect::<Vec<_>>());
+        let (out_tx, mut out_rx) = mpsc::channel(16);
+        let mut sink = Box::pin(futures_util::sink::unfold(out_tx, |out, message: Message| async move {
+            out.send(message).await.map(|()| out)
+        }));
+        send_body_frames(&mut sink, "r1", &body).await.unwrap();
+
+        let mut received = Vec::new();
+        loop {
+            let frame = next_frame(&mut out_rx).await;
+            assert_eq!(frame.stream, "r1");
+            match frame.event {
+                StreamEvent::Data(data) => {
+                    assert!(data.len() <= RESPONSE_FRAME_SIZE);
+                    received.extend_from_slice(&data);
+                }
+                StreamEvent::Close => break,
+                StreamEvent::Open => panic!("unexpected open"),
+            }
+        }
+        assert_eq!(received, body);
+    }
+
+    #[tokio::test]
+    async fn test_websocket_frames_are_captured() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let conf = TunnelConfig {
+            name: "web".to_string(),
+            local_host: "127.0.0.1".to_string(),
+            local_port: listener.local_addr().unwrap().port(),
+            ..Default::default()
+        };
+        let mut ctx = TunnelContext::new(conf, mpsc::channel(1).0);
+        let log = FrameLog::default();
+        ctx.frames = Some(log.clone());
+
+        // Local service: accept the upgrade and greet with a text frame
+        let service = tokio::spawn(async move {
+            let (mut sock, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0u8; 4096];
+            let _ = sock.read(&mut buf).await.unwrap();
+            sock.write_all(b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n\x81\x02hi")
+                .await
+                .unwrap();
+            let mut frame = [0u8; 11];
+            sock.read_exact(&mut frame).await.unwrap();
+            sock
+        });
+
+        let (out_tx, _out_rx) = mpsc::channel(16);
+        let mut streams = Streams::new(out_tx);
+        let request = TunnelRequest {
+            id: "ws1".to_string(),
+            method: "GET".to_string(),
+            path: "/chat".to_string(),
+            headers: vec![("Connection".into(), "Upgrade".into()), ("Upgrade".into(), "websocket".into())],
+            body: None,
+            streamed: false,
+        };
+        let mut sink = futures_util::sink::drain();
+        handle_http_request(request, &ctx, &mut sink, Some(&mut streams), Instant::now(), None, None).await.unwrap();
+
+        // Masked "hello" from the visitor
+        let mask = [1u8, 2, 3, 4];
+        let mut data = vec![0x81, 0x85];
+        data.extend_from_slice(&mask);
+        data.extend(b"hello".iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        streams.deliver(frame("ws1", StreamEvent::Data(data.into()))).await;
+        let _service = service.await.unwrap();
+
+        while log.frames("ws1").unwrap().len() < 2 {
+            tokio::task::yield_now().await;
+        }
+        let frames = log.frames("ws1").unwrap();
+        let seen: Vec<_> = frames.iter().map(|f| (f.direction, f.payload.as_str())).collect();
+        assert!(seen.contains(&(frames::Direction::Outbound, "hi")));
+        assert!(seen.contains(&(frames::Direction::Inbound, "hello")));
+    }
+
+    #[tokio::test]
+    async fn test_local_timeout_and_retries() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let conf = TunnelConfig {
+            name: "web".to_string(),
+            local_host: "127.0.0.1".to_string(),
+            local_port: listener.local_addr().unwrap().port(),
+            local_timeout: Some("100ms".to_string()),
+            retries: 1,
+            ..Default::default()
+        };
+        let ctx = TunnelContext::new(conf, mpsc::channel(1).0);
+
+        // Local service: hang on the first connection, answer the second
+        tokio::spawn(async move {
+            let (_stalled, _) = listener.accept().await.unwrap();
+            let (mut sock, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0u8; 4096];
+            let _ = sock.read(&mut buf).await.unwrap();
+            sock.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").await.unwrap();
+            let (_stalled, _) = listener.accept().await.unwrap();
+            std::future::pending::<()>().await;
+        });
+
+        let request = |method: &str| TunnelRequest {
+            id: "r1".to_string(),
+            method: method.to_string(),
+            path: "/slow".to_string(),
+            headers: Vec::new(),
+            body: None,
+            streamed: false,
+        };
+        let (status, _, body) = forward_local(&ctx, &request("GET")).await.unwrap();
+        assert_eq!((status, body.as_slice()), (200, &b"ok"[..]));
+
+        // POST isn't retried
+        let (status, _, _) = forward_local(&ctx, &request("POST")).await.unwrap();
+        assert_eq!(status, 504);
+    }
+
+    #[tokio::test]
+    async fn test_slow_responses_flagged() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let conf = TunnelConfig {
+            name: "web".to_string(),
+            local_port: listener.local_addr().unwrap().port(),
+            slow_threshold_ms: Some(30),
+            ..Default::default()
+        };
+        let (entry_tx, mut entries) = mpsc::channel(4);
+        let mut ctx = TunnelContext::new(conf, entry_tx);
+        let summary = Arc::new(crate::summary::Summary::new());
+        ctx.telemetry = summary.clone();
+
+        // Local service: answer the first request at once, the second late
+        tokio::spawn(async move {
+            for delay in [0, 80] {
+                let (mut sock, _) = listener.accept().await.unwrap();
+                let mut buf = vec![0u8; 4096];
+                let _ = sock.read(&mut buf).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(delay)).await;
+                sock.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok").await.unwrap();
+            }
+        });
+
+        for path in ["/fast", "/slow"] {
+            let request = TunnelRequest {
+                id: path.to_string(),
+                method: "GET".to_string(),
+                path: path.to_string(),
+                headers: Vec::new(),
+                body: None,
+                streamed: false,
+            };
+            handle_direct(request, &ctx).await.unwrap();
+        }
+        let (fast, slow) = (entries.recv().await.unwrap(), entries.recv().await.unwrap());
+        assert_eq!((fast.slow, slow.slow), (false, true));
+        assert!(summary.report().contains(&"Slow:        1".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_local_down_answers_502() {
+        let port = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        let conf = TunnelConfig {
+            name: "web".to_string(),
+            local_host: "127.0.0.1".to_string(),
+            local_port: port,
+            retries: 1,
+            ..Default::default()
+        };
+        let ctx = TunnelContext::new(conf, mpsc::channel(1).0);
+        let request = |accept: &str| TunnelRequest {
+            id: "r1".to_string(),
+            method: "GET".to_string(),
+            path: "/".to_string(),
+            headers: vec![("Accept".into(), accept.into())],
+            body: None,
+            streamed: false,
+        };
+
+        let (status, headers, body) = forward_local(&ctx, &request("text/html,*/*")).await.unwrap();
+        assert_eq!(status, 502);
+        assert!(headers.contains(&("Content-Type".to_string(), "text/html; charset=utf-8".to_string())));
+        assert!(String::from_utf8(body).unwrap().contains(&format!("<code>127.0.0.1:{}</code>", port)));
+        // The retry failed too
+        assert_eq!(ctx.local_failures.load(Ordering::Relaxed), 2);
+
+        let (_, _, body) = forward_local(&ctx, &request("application/json")).await.unwrap();
+        let text = String::from_utf8(body).unwrap();
+        assert!(text.starts_with(&format!("502 Bad Gateway: local service on 127.0.0.1:{} is not running", port)));
+
+        // A connection that works resets the count
+        let _listener = tokio::net::TcpListener::bind(("127.0.0.1", port)).await.unwrap();
+        ctx.connect_local().await.unwrap();
+        assert_eq!(ctx.local_failures.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn test_streamed_upload() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let conf = TunnelConfig {
+            name: "web".to_string(),
+            local_host: "127.0.0.1".to_string(),
+            local_port: listener.local_addr().unwrap().port(),
+            ..Default::default()
+        };
+        let (entry_tx, mut entries) = mpsc::channel(1);
+        let ctx = TunnelContext::new(conf, entry_tx);
+
+        // Local service: read the chunked body to its end, answer with it
+        let service = tokio::spawn(async move {
+            let (mut sock, _) = listener.accept().await.unwrap();
+            let mut received = Vec::new();
+            let mut buf = vec![0u8; 4096];
+            while !received.ends_with(b"0\r\n\r\n") {
+                let n = sock.read(&mut buf).await.unwrap();
+                received.extend_from_slice(&buf[..n]);
+            }
+            sock.write_all(b"HTTP/1.1 201 Created\r\nContent-Length: 2\r\n\r\nok").await.unwrap();
+            String::from_utf8(received).unwrap()
+        });
+
+        let (out_tx, mut out_rx) = mpsc::channel(16);
+        let mut streams = Streams::new(out_tx.clone());
+        let request = TunnelRequest {
+            id: "up1".to_string(),
+            method: "POST".to_string(),
+            path: "/upload".to_string(),
+            headers: vec![("Transfer-Encoding".into(), "chunked".into())],
+            body: None,
+            streamed: true,
+        };
+        let body = streams.receive(request.id.clone());
+        let relay = async {
+            for part in [&b"hello "[..], b"world"] {
+                streams.deliver(frame("up1", StreamEvent::Data(Bytes::copy_from_slice(part)))).await;
+            }
+            streams.deliver(frame("up1", StreamEvent::Close)).await;
+        };
+        let (handled, ()) = tokio::join!(handle_upload(request, body, &ctx, out_tx, Instant::now()), relay);
+        handled.unwrap();
+
+        let sent = service.await.unwrap();
+        assert!(sent.starts_with("POST /upload HTTP/1.1\r\n"));
+        assert_eq!(sent.matches("Transfer-Encoding").count(), 1);
+        assert!(sent.ends_with("\r\n\r\n6\r\nhello \r\n5\r\nworld\r\n0\r\n\r\n"));
+
+        let response: crate::tunnel::TunnelResponse = match out_rx.recv().await {
+            Some(Message::Binary(data)) => serde_json::from_slice(&data).unwrap(),
+            other => panic!("unexpected message: {:?}", other),
+        };
+        assert_eq!((response.id.as_str(), response.status), ("up1", 201));
+        let entry = entries.recv().await.unwrap();
+        assert_eq!(entry.req_body.unwrap().bytes(), b"hello world");
+        assert!(streams.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_streamed_webhook_verified() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let conf = TunnelConfig {
+            name: "hooks".to_string(),
+            local_host: "127.0.0.1".to_string(),
+            local_port: listener.local_addr().unwrap().port(),
+            webhook: Some(crate::config::WebhookConfig {
+                provider: "generic".to_string(),
+                secret: "whsec".to_string(),
+                header: Some("X-Signature".to_string()),
+                algorithm: None,
+                reject_invalid: true,
+            }),
+            ..Default::default()
+        };
+        let ctx = TunnelContext::new(conf, mpsc::channel(4).0);
+
+        // The signed body arrives whole, with a length instead of chunks
+        let service = tokio::spawn(async move {
+            let (mut sock, _) = listener.accept().await.unwrap();
+            let mut received = Vec::new();
+            let mut buf = vec![0u8; 4096];
+            while !received.ends_with(b"hello world") {
+                let n = sock.read(&mut buf).await.unwrap();
+                received.extend_from_slice(&buf[..n]);
+            }
+            sock.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await.unwrap();
+            String::from_utf8(received).unwrap()
+        });
+
+        let tag = ring::hmac::sign(&ring::hmac::Key::new(ring::hmac::HMAC_SHA256, b"whsec"), b"hello world");
+        let good: String = tag.as_ref().iter().map(|b| format!("{:02x}", b)).collect();
+        let (out_tx, mut out_rx) = mpsc::channel(16);
+        let mut streams = Streams::new(out_tx.clone());
+        for (id, signature, status) in [("w1", good.as_str(), 200), ("w2", "00", 401)] {
+            let request = TunnelRequest {
+                id: id.to_string(),
+                method: "POST".to_string(),
+                path: "/hook".to_string(),
+                headers: vec![("X-Signature".into(), signature.into()), ("Transfer-Encoding".into(), "chunked".into())],
+                body: None,
+                streamed: true,
+            };
+            let body = streams.receive(request.id.clone());
+            let relay = async {
+                for part in [&b"hello "[..], b"world"] {
+                    streams.deliver(frame(id, StreamEvent::Data(Bytes::copy_from_slice(part)))).await;
+                }
+                streams.deliver(frame(id, StreamEvent::Close)).await;
+            };
+            let (handled, ()) = tokio::join!(handle_upload(request, body, &ctx, out_tx.clone(), Instant::now()), relay);
+            handled.unwrap();
+            let response: crate::tunnel::TunnelResponse = match out_rx.recv().await {
+                Some(Message::Binary(data)) => serde_json::from_slice(&data).unwrap(),
+                other => panic!("unexpected message: {:?}", other),
+            };
+            assert_eq!((response.id.as_str(), response.status), (id, status));
+        }
+
+        let sent = service.await.unwrap();
+        assert!(sent.contains("Content-Length: 11\r\n"));
+        assert!(!sent.contains("Transfer-Encoding"));
+    }
+
+    #[tokio::test]
+    async fn test_tcp_open_rejected_when_local_down() {
+        let port = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        let ctx = tcp_context(port);
+        let (out_tx, mut out_rx) = mpsc::channel(16);
+        let mut streams = Streams::new(out_tx);
+
+        handle_tcp_frame(frame("c1", StreamEvent::Open), &ctx, &mut streams).await;
+        assert_eq!(next_event(&mut out_rx).await, StreamEvent::Close);
+        assert_eq!(streams.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_tcp_open_sends_proxy_header() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut ctx = tcp_context(port);
+        ctx.conf.proxy_protocol = Some(proxy_protocol::Version::V1);
+        let (out_tx, _out_rx) = mpsc::channel(16);
+        let mut streams = Streams::new(out_tx);
+
+        let mut open = frame("c1", StreamEvent::Open);
+        open.remote_addr = Some("198.51.100.4:40000".parse().unwrap());
+        handle_tcp_frame(open, &ctx, &mut streams).await;
+        handle_tcp_frame(frame("c1", StreamEvent::Data(Bytes::from_static(b"EHLO\r\n"))), &ctx, &mut streams).await;
+        let (mut local, _) = listener.accept().await.unwrap();
+        let expected = format!("PROXY TCP4 198.51.100.4 127.0.0.1 40000 {}\r\nEHLO\r\n", port);
+        let mut buf = vec![0u8; expected.len()];
+        local.read_exact(&mut buf).await.unwrap();
+        assert_eq!(String::from_utf8(buf).unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn test_gateway_dials_requested_target() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap().to_string();
+        // The local port is never dialed
+        let mut ctx = tcp_context(1);
+        ctx.conf.gateway = Some(crate::config::GatewayConfig { token: "s3cret".into(), allow: vec!["127.0.0.1:*".into()] });
+        let (out_tx, mut out_rx) = mpsc::channel(16);
+        let mut streams = Streams::new(out_tx);
+
+        let mut open = frame("g1", StreamEvent::Open);
+        open.target = Some(target.clone());
+        open.token = Some("s3cret".into());
+        handle_tcp_frame(open, &ctx, &mut streams).await;
+        listener.accept().await.unwrap();
+        assert!(streams.contains("g1"));
+
+        for (token, target) in [("wrong", target.as_str()), ("s3cret", "10.0.0.1:22")] {
+            let mut open = frame("g2", StreamEvent::Open);
+            open.target = Some(target.into());
+            open.token = Some(token.into());
+            handle_tcp_frame(open, &ctx, &mut streams).await;
+            assert_eq!(next_event(&mut out_rx).await, StreamEvent::Close);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tcp_connections_are_multiplexed() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut ctx = tcp_context(listener.local_addr().unwrap().port());
+        ctx.conf.max_connections = 2;
+        let (out_tx, mut out_rx) = mpsc::channel(16);
+        let mut streams = Streams::new(out_tx);
+
+        handle_tcp_frame(frame("a", StreamEvent::Open), &ctx, &mut streams).await;
+        let (mut sock_a, _) = listener.accept().await.unwrap();
+        handle_tcp_frame(frame("b", StreamEvent::Open), &ctx, &mut streams).await;
+        let (mut sock_b, _) = listener.accept().await.unwrap();
+
+        // A duplicate open doesn't replace the live connection
+        handle_tcp_frame(frame("a", StreamEvent::Open), &ctx, &mut streams).await;
+        assert_eq!(streams.len(), 2);
+
+        // Over the limit
+        handle_tcp_frame(frame("c", StreamEvent::Open), &ctx, &mut streams).await;
+        let refused = next_frame(&mut out_rx).await;
+        assert_eq!((refused.stream.as_str(), refused.event), ("c", StreamEvent::Close));
+
+        // Each remote connection reaches its own socket
+        handle_tcp_frame(frame("b", StreamEvent::Data(Bytes::from_static(b"to-b"))), &ctx, &mut streams).await;
+        handle_tcp_frame(frame("a", StreamEvent::Data(Bytes::from_static(b"to-a"))), &ctx, &mut streams).await;
+        let mut buf = [0u8; 4];
+        sock_a.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"to-a");
+        sock_b.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"to-b");
+
+        // ...and replies are tagged with the right id
+        sock_b.write_all(b"from-b").await.unwrap();
+        let reply = next_frame(&mut out_rx).await;
+        assert_eq!(reply.stream, "b");
+        assert_eq!(reply.event, StreamEvent::Data(Bytes::from_static(b"from-b")));
+
+        // Closing one leaves the other open
+        drop(sock_a);
+        let closed = next_frame(&mut out_rx).await;
+        assert_eq!((closed.stream.as_str(), closed.event), ("a", StreamEvent::Close));
+        handle_tcp_frame(frame("a", StreamEvent::Close), &ctx, &mut streams).await;
+        assert!(streams.contains("b"));
+        assert_eq!(streams.len(), 1);
+    }
+
+    /// Minimal relay that answers every registration with `reply`
+    async fn fake_relay(reply: serde_json::Value) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/tunnel", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let reply = reply.to_string();
+                tokio::spawn(async move {
+                    let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+                    ws.next().await;
+                    ws.send(Message::Text(reply)).await.unwrap();
+                    // Hold the connection open like a real relay
+                    while ws.next().await.is_some() {}
+                });
+            }
+        });
+        url
+    }
+
+    fn dead_relay() -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        format!("ws://{}/tunnel", listener.local_addr().unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_connect_fails_over_to_next_relay() {
+        let live = fake_relay(serde_json::json!({
+            "success": true, "url": "https://web.example.com", "subdomain": "web",
+        }))
+        .await;
+        let relays = vec![dead_relay(), live.clone()];
+        let ctx = tcp_context(1);
+
+        let (index, reg, _, _) = connect_any(&relays, 0, &ctx).await.unwrap();
+        assert_eq!(index, 1);
+        assert_eq!(reg.relay, live);
+        assert_eq!(reg.url, "https://web.example.com");
+
+        // A rejection is final, even with another relay available
+        let rejecting = fake_relay(serde_json::json!({ "success": false, "error": "bad token" })).await;
+        let relays = vec![rejecting, live.clone()];
+        let err = connect_any(&relays, 0, &ctx).await.err().unwrap();
+        assert!(matches!(err.downcast_ref(), Some(ztunnel_shared::Error::Tunnel(r)) if r == "bad token"));
+
+        // Typed errors come back as the same variant
+        let error = serde_json::to_value(ztunnel_shared::Error::AuthFailed).unwrap();
+        let rejecting = fake_relay(serde_json::json!({ "success": false, "error": error })).await;
+        let err = connect_any(&[rejecting], 0, &ctx).await.err().unwrap();
+        assert!(matches!(err.downcast_ref(), Some(ztunnel_shared::Error::AuthFailed)));
+
+        // A busy relay is retryable, so the next one gets a chance
+        let error = serde_json::to_value(ztunnel_shared::Error::RateLimited(30)).unwrap();
+        let busy = fake_relay(serde_json::json!({ "success": false, "error": error })).await;
+        let relays = vec![busy, live];
+        let (index, _, _, _) = connect_any(&relays, 0, &ctx).await.unwrap();
+        assert_eq!(index, 1);
+    }
+}
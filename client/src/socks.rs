@@ -0,0 +1,191 @@
+//! SOCKS5 gateway through a TCP tunnel
+//!
+//! A TCP tunnel with `gateway` set doesn't forward to its local
+//! service: each connection names a `host:port`, and the client dials
+//! it if the consumer has the token and the address is in `allow`. That
+//! exposes the network the client runs on, as far as `allow` lets it.
+//!
+//! `ztunnel socks TUNNEL` is the consumer side: a local SOCKS5 server
+//! (CONNECT, no authentication) opening one relay stream per
+//! connection, for browsers and `curl --socks5-hostname`. A destination
+//! the gateway refuses shows up as the connection closing.
+
+use crate::config::GatewayConfig;
+use crate::connect;
+use anyhow::{Context, Result};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info, warn};
+
+/// How long the gateway waits for a destination to accept
+const DIAL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Dial what a consumer asked for, if it may
+pub async fn dial(gateway: &GatewayConfig, target: Option<&str>, token: Option<&str>) -> Result<TcpStream> {
+    let token = token.unwrap_or_default();
+    if !crate::auth::constant_time_eq(token.as_bytes(), gateway.token.as_bytes()) {
+        anyhow::bail!("wrong gateway token");
+    }
+    let target = target.context("no destination given")?;
+    if !is_allowed(&gateway.allow, target) {
+        anyhow::bail!("{} is not in the gateway's allow list", target);
+    }
+    match tokio::time::timeout(DIAL_TIMEOUT, TcpStream::connect(target)).await {
+        Ok(stream) => stream.with_context(|| format!("connecting to {}", target)),
+        Err(_) => anyhow::bail!("connecting to {} timed out", target),
+    }
+}
+
+/// Whether `target` (`host:port`) matches one of the `allow` patterns
+pub fn is_allowed(allow: &[String], target: &str) -> bool {
+    let target = target.to_ascii_lowercase();
+    allow.iter().any(|pattern| wildcard(&pattern.to_ascii_lowercase(), &target))
+}
+
+/// `*` matches any run of characters, everything else itself
+fn wildcard(pattern: &str, text: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == text,
+        Some((prefix, rest)) => {
+            let Some(text) = text.strip_prefix(prefix) else {
+                return false;
+            };
+            (0..=text.len()).filter(|&i| text.is_char_boundary(i)).any(|i| wildcard(rest, &text[i..]))
+        }
+    }
+}
+
+/// SOCKS5 reply codes
+const SUCCEEDED: u8 = 0x00;
+const GENERAL_FAILURE: u8 = 0x01;
+const COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const ADDRESS_NOT_SUPPORTED: u8 = 0x08;
+
+/// Read a SOCKS5 greeting and CONNECT request, returning the
+/// destination as `host:port`. Unsupported requests are answered
+/// before the error comes back.
+pub async fn handshake<S: AsyncReadExt + AsyncWriteExt + Unpin>(stream: &mut S) -> Result<String> {
+    let mut head = [0u8; 2];
+    stream.read_exact(&mut head).await?;
+    if head[0] != 5 {
+        anyhow::bail!("not a SOCKS5 client (version {})", head[0]);
+    }
+    let mut methods = vec![0u8; head[1] as usize];
+    stream.read_exact(&mut methods).await?;
+    if !methods.contains(&0) {
+        stream.write_all(&[5, 0xFF]).await?;
+        anyhow::bail!("client requires authentication");
+    }
+    stream.write_all(&[5, 0]).await?;
+
+    let mut request = [0u8; 4];
+    stream.read_exact(&mut request).await?;
+    let host = match request[3] {
+        1 => {
+            let mut ip = [0u8; 4];
+            stream.read_exact(&mut ip).await?;
+            Ipv4Addr::from(ip).to_string()
+        }
+        3 => {
+            let mut name = vec![0u8; stream.read_u8().await? as usize];
+            stream.read_exact(&mut name).await?;
+            String::from_utf8(name).context("host name is not UTF-8")?
+        }
+        4 => {
+            let mut ip = [0u8; 16];
+            stream.read_exact(&mut ip).await?;
+            format!("[{}]", Ipv6Addr::from(ip))
+        }
+        other => {
+            reply(stream, ADDRESS_NOT_SUPPORTED).await?;
+            anyhow::bail!("address type {} not supported", other);
+        }
+    };
+    let port = stream.read_u16().await?;
+    if request[1] != 1 {
+        reply(stream, COMMAND_NOT_SUPPORTED).await?;
+        anyhow::bail!("only CONNECT is supported (command {})", request[1]);
+    }
+    Ok(format!("{}:{}", host, port))
+}
+
+/// Answer the CONNECT request; the bound address is left unspecified
+async fn reply<S: AsyncWriteExt + Unpin>(stream: &mut S, code: u8) -> std::io::Result<()> {
+    stream.write_all(&[5, code, 0, 1, 0, 0, 0, 0, 0, 0]).await
+}
+
+/// Accept SOCKS5 connections on `listener` and carry each through
+/// `tunnel`'s gateway
+pub async fn serve(listener: TcpListener, relay: String, tunnel: String, token: String) -> Result<()> {
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let (relay, tunnel, token) = (relay.clone(), tunnel.clone(), token.clone());
+        tokio::spawn(async move {
+            if let Err(e) = proxy(stream, &relay, &tunnel, &token).await {
+                warn!("SOCKS connection from {}: {:#}", peer, e);
+            }
+        });
+    }
+}
+
+async fn proxy(mut stream: TcpStream, relay: &str, tunnel: &str, token: &str) -> Result<()> {
+    let target = handshake(&mut stream).await?;
+    let remote = match connect::open(relay, tunnel, Some(&target), Some(token)).await {
+        Ok(remote) => remote,
+        Err(e) => {
+            reply(&mut stream, GENERAL_FAILURE).await?;
+            return Err(e);
+        }
+    };
+    reply(&mut stream, SUCCEEDED).await?;
+    info!("SOCKS → {}", target);
+    let (sent, received) = connect::pipe(stream, remote).await?;
+    debug!("SOCKS {} closed ({} bytes out, {} in)", target, sent, received);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allow_patterns() {
+        let allow = vec!["10.0.0.*:5432".to_string(), "*.internal:*".to_string(), "localhost:3000".to_string()];
+        assert!(is_allowed(&allow, "10.0.0.12:5432"));
+        assert!(!is_allowed(&allow, "10.0.0.12:22"));
+        assert!(is_allowed(&allow, "grafana.internal:3000"));
+        assert!(is_allowed(&allow, "API.Internal:443"));
+        assert!(!is_allowed(&allow, "internal:443"));
+        assert!(is_allowed(&allow, "localhost:3000"));
+        assert!(!is_allowed(&allow, "localhost:30001"));
+    }
+
+    #[tokio::test]
+    async fn test_socks_handshake() {
+        let (mut consumer, mut server) = tokio::io::duplex(256);
+        consumer.write_all(&[5, 1, 0]).await.unwrap();
+        consumer.write_all(&[5, 1, 0, 3, 11]).await.unwrap();
+        consumer.write_all(b"db.internal").await.unwrap();
+        consumer.write_all(&5432u16.to_be_bytes()).await.unwrap();
+        assert_eq!(handshake(&mut server).await.unwrap(), "db.internal:5432");
+        let mut answer = [0u8; 2];
+        consumer.read_exact(&mut answer).await.unwrap();
+        assert_eq!(answer, [5, 0]);
+
+        let (mut consumer, mut server) = tokio::io::duplex(256);
+        consumer.write_all(&[5, 1, 0, 5, 1, 0, 4]).await.unwrap();
+        consumer.write_all(&Ipv6Addr::LOCALHOST.octets()).await.unwrap();
+        consumer.write_all(&22u16.to_be_bytes()).await.unwrap();
+        assert_eq!(handshake(&mut server).await.unwrap(), "[::1]:22");
+
+        // BIND is refused with a reply
+        let (mut consumer, mut server) = tokio::io::duplex(256);
+        consumer.write_all(&[5, 1, 0, 5, 2, 0, 1, 127, 0, 0, 1, 0, 80]).await.unwrap();
+        assert!(handshake(&mut server).await.is_err());
+        let mut answer = [0u8; 12];
+        consumer.read_exact(&mut answer).await.unwrap();
+        assert_eq!(answer[3], COMMAND_NOT_SUPPORTED);
+    }
+}
@@ -0,0 +1,300 @@
+//! Traffic statistics over recorded inspector entries
+//!
+//! `/api/stats` groups entries by path (query string dropped) and by
+//! status code, reporting count, 5xx error rate, p50/p95 latency, and
+//! body bytes each way. It takes the same filters as `/api/entries`, so
+//! a script can e.g. assert the p95 of `/api/orders` since a timestamp.
+//!
+//! `/api/timeline` takes the same filters and counts requests, errors
+//! and average latency per minute, for rate and error graphs.
+
+use crate::cache::CacheStatus;
+use crate::inspector::{InspectorEntry, Usage};
+use chrono::{DateTime, DurationRound, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Most minutes a timeline covers; older ones are dropped
+pub const MAX_BUCKETS: usize = 24 * 60;
+
+/// Aggregates for one group of entries
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Aggregate {
+    pub count: usize,
+    /// Responses with a 5xx status
+    pub errors: usize,
+    pub error_rate: f64,
+    pub p50_ms: u64,
+    pub p95_ms: u64,
+    pub max_ms: u64,
+    /// Request body bytes
+    pub bytes_in: u64,
+    /// Response body bytes
+    pub bytes_out: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PathStats {
+    pub path: String,
+    #[serde(flatten)]
+    pub stats: Aggregate,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StatusStats {
+    pub status: u16,
+    #[serde(flatten)]
+    pub stats: Aggregate,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TrafficStats {
+    pub total: Aggregate,
+    /// Busiest first
+    pub by_path: Vec<PathStats>,
+    pub by_status: Vec<StatusStats>,
+    /// Oldest and newest timestamps covered
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub to: Option<String>,
+    /// In-memory buffer fill, when served by the inspector
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
+    /// Response cache outcomes, when any entry went through a cache
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache: Option<CacheCounts>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CacheCounts {
+    pub hits: usize,
+    pub misses: usize,
+    pub hit_rate: f64,
+}
+
+/// Running totals for one group
+#[derive(Default)]
+struct Acc {
+    latencies: Vec<u64>,
+    errors: usize,
+    bytes_in: u64,
+    bytes_out: u64,
+}
+
+impl Acc {
+    fn add(&mut self, entry: &InspectorEntry) {
+        self.latencies.push(entry.latency_ms);
+        if entry.status >= 500 {
+            self.errors += 1;
+        }
+        self.bytes_in += entry.req_body.as_ref().map_or(0, |b| b.size()) as u64;
+        self.bytes_out += entry.res_body_size as u64;
+    }
+
+    fn finish(mut self) -> Aggregate {
+        self.latencies.sort_unstable();
+        let count = self.latencies.len();
+        Aggregate {
+            count,
+            errors: self.errors,
+            error_rate: if count == 0 { 0.0 } else { self.errors as f64 / count as f64 },
+            p50_ms: percentile(&self.latencies, 50),
+            p95_ms: percentile(&self.latencies, 95),
+            max_ms: self.latencies.last().copied().unwrap_or(0),
+            bytes_in: self.bytes_in,
+            bytes_out: self.bytes_out,
+        }
+    }
+}
+
+/// Nearest-rank percentile of sorted values
+fn percentile(sorted: &[u64], p: usize) -> u64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    let rank = (p * sorted.len()).div_ceil(100).max(1);
+    sorted[rank - 1]
+}
+
+pub fn compute<'a>(entries: impl IntoIterator<Item = &'a InspectorEntry>) -> TrafficStats {
+    let mut total = Acc::default();
+    let mut by_path: BTreeMap<&str, Acc> = BTreeMap::new();
+    let mut by_status: BTreeMap<u16, Acc> = BTreeMap::new();
+    let (mut from, mut to): (Option<&str>, Option<&str>) = (None, None);
+    let (mut hits, mut misses) = (0, 0);
+
+    for entry in entries {
+        total.add(entry);
+        let path = entry.path.split(['?', '#']).next().unwrap_or(&entry.path);
+        by_path.entry(path).or_default().add(entry);
+        by_status.entry(entry.status).or_default().add(entry);
+        match entry.cache {
+            Some(CacheStatus::Hit) => hits += 1,
+            Some(CacheStatus::Miss) => misses += 1,
+            None => {}
+        }
+        // RFC 3339 timestamps in UTC sort as strings
+        let ts = entry.timestamp.as_str();
+        if from.is_none_or(|f| ts < f) {
+            from = Some(ts);
+        }
+        if to.is_none_or(|t| ts > t) {
+            to = Some(ts);
+        }
+    }
+
+    let mut by_path: Vec<_> =
+        by_path.into_iter().map(|(path, acc)| PathStats { path: path.to_string(), stats: acc.finish() }).collect();
+    by_path.sort_by_key(|p| std::cmp::Reverse(p.stats.count));
+    TrafficStats {
+        total: total.finish(),
+        by_path,
+        by_status: by_status.into_iter().map(|(status, acc)| StatusStats { status, stats: acc.finish() }).collect(),
+        from: from.map(str::to_string),
+        to: to.map(str::to_string),
+        usage: None,
+        cache: (hits + misses > 0).then(|| CacheCounts {
+            hits,
+            misses,
+            hit_rate: hits as f64 / (hits + misses) as f64,
+        }),
+    }
+}
+
+/// Traffic in one minute
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Bucket {
+    /// Start of the minute, RFC 3339 in UTC
+    pub start: String,
+    pub requests: usize,
+    /// Responses with a 5xx status
+    pub errors: usize,
+    pub avg_latency_ms: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Timeline {
+    pub bucket_secs: u64,
+    /// Oldest first, every minute from the first request to the last,
+    /// including quiet ones
+    pub buckets: Vec<Bucket>,
+}
+
+/// Requests, errors and mean latency per minute
+pub fn timeline<'a>(entries: impl IntoIterator<Item = &'a InspectorEntry>) -> Timeline {
+    let minute = TimeDelta::minutes(1);
+    // Requests, errors, total latency
+    let mut minutes: BTreeMap<DateTime<Utc>, (usize, usize, u64)> = BTreeMap::new();
+    for entry in entries {
+        let Ok(at) = DateTime::parse_from_rfc3339(&entry.timestamp) else {
+            continue;
+        };
+        let Ok(start) = at.with_timezone(&Utc).duration_trunc(minute) else {
+            continue;
+        };
+        let bucket = minutes.entry(start).or_default();
+        bucket.0 += 1;
+        bucket.1 += usize::from(entry.status >= 500);
+        bucket.2 += entry.latency_ms;
+    }
+
+    let mut buckets = Vec::new();
+    if let (Some(first), Some(last)) = (minutes.keys().next(), minutes.keys().next_back()) {
+        let mut start = (*first).max(*last - minute * (MAX_BUCKETS as i32 - 1));
+        while start <= *last {
+            let (requests, errors, latency) = minutes.get(&start).copied().unwrap_or_default();
+            buckets.push(Bucket {
+                start: start.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+                requests,
+                errors,
+                avg_latency_ms: if requests == 0 { 0 } else { latency / requests as u64 },
+            });
+            start += minute;
+        }
+    }
+    Timeline { bucket_secs: 60, buckets }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: &str, status: u16, latency_ms: u64) -> InspectorEntry {
+        InspectorEntry {
+            path: path.to_string(),
+            status,
+            latency_ms,
+            res_body_size: 10,
+            timestamp: format!("2024-05-01T10:00:{:02}Z", latency_ms % 60),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_percentile() {
+        let values: Vec<u64> = (1..=20).collect();
+        assert_eq!((percentile(&values, 50), percentile(&values, 95)), (10, 19));
+        assert_eq!(percentile(&[7], 95), 7);
+        assert_eq!(percentile(&[], 50), 0);
+    }
+
+    #[test]
+    fn test_compute_groups() {
+        let mut entries: Vec<_> = (1..=10).map(|ms| entry("/orders?page=1", 200, ms)).collect();
+        entries.push(entry("/orders", 503, 40));
+        entries.push(entry("/health", 200, 1));
+
+        let stats = compute(&entries);
+        assert_eq!(stats.total.count, 12);
+        assert_eq!(stats.total.bytes_out, 120);
+
+        let orders = &stats.by_path[0];
+        assert_eq!((orders.path.as_str(), orders.stats.count, orders.stats.errors), ("/orders", 11, 1));
+        assert_eq!((orders.stats.p50_ms, orders.stats.p95_ms, orders.stats.max_ms), (6, 40, 40));
+        assert!((orders.stats.error_rate - 1.0 / 11.0).abs() < 1e-9);
+
+        let statuses: Vec<_> = stats.by_status.iter().map(|s| (s.status, s.stats.count)).collect();
+        assert_eq!(statuses, [(200, 11), (503, 1)]);
+        assert_eq!(stats.from.as_deref(), Some("2024-05-01T10:00:01Z"));
+        assert!(stats.cache.is_none());
+
+        entries[0].cache = Some(CacheStatus::Miss);
+        entries[1].cache = Some(CacheStatus::Hit);
+        entries[2].cache = Some(CacheStatus::Hit);
+        let cache = compute(&entries).cache.unwrap();
+        assert_eq!((cache.hits, cache.misses), (2, 1));
+    }
+
+    #[test]
+    fn test_timeline() {
+        let at = |time: &str, status: u16, latency_ms: u64| InspectorEntry {
+            status,
+            latency_ms,
+            timestamp: format!("2024-05-01T{}+02:00", time),
+            ..Default::default()
+        };
+        let entries = [
+            at("10:00:05", 200, 10),
+            at("10:00:59", 502, 30),
+            at("10:03:00", 200, 7),
+            InspectorEntry { timestamp: "not a time".into(), ..Default::default() },
+        ];
+        let minutes = timeline(&entries);
+        let buckets: Vec<_> =
+            minutes.buckets.iter().map(|b| (b.start.as_str(), b.requests, b.errors, b.avg_latency_ms)).collect();
+        assert_eq!(buckets, [
+            ("2024-05-01T08:00:00Z", 2, 1, 20),
+            ("2024-05-01T08:01:00Z", 0, 0, 0),
+            ("2024-05-01T08:02:00Z", 0, 0, 0),
+            ("2024-05-01T08:03:00Z", 1, 0, 7),
+        ]);
+        assert!(timeline([]).buckets.is_empty());
+
+        // A long recording keeps its latest day
+        let later = InspectorEntry { timestamp: "2024-05-03T10:00:00Z".into(), ..Default::default() };
+        let entries = [at("10:00:00", 200, 1), later];
+        let buckets = timeline(&entries).buckets;
+        assert_eq!((buckets.len(), buckets[0].start.as_str()), (MAX_BUCKETS, "2024-05-02T10:01:00Z"));
+    }
+}
@@ -0,0 +1,316 @@
+//! Raw byte streams over the relay connection
+//!
+//! TCP tunnels and HTTP connections that switched protocols (WebSocket
+//! upgrade) aren't request/response. Each such connection gets a pair of
+//! pump tasks that shuttle bytes between the local socket and
+//! `StreamFrame`s on the relay WebSocket until either side closes.
+//! Large request bodies arrive the same way, on the request's id.
+
+use crate::frames::{Capture, Direction, FrameParser};
+use crate::proxy::LocalStream;
+use crate::tunnel::{StreamEvent, StreamFrame};
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::sync::mpsc;
+use tokio_util::sync::PollSender;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{debug, warn};
+
+/// Read size for local → relay frames of captured streams
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// Copy buffer, each way, for streams that aren't captured
+const PUMP_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Open streams on one relay connection
+pub struct Streams {
+    /// Senders feeding each stream's local writer
+    inbound: HashMap<String, mpsc::Sender<Bytes>>,
+    /// Outgoing messages for the relay WebSocket
+    out: mpsc::Sender<Message>,
+}
+
+impl Streams {
+    pub fn new(out: mpsc::Sender<Message>) -> Self {
+        Self { inbound: HashMap::new(), out }
+    }
+
+    /// Number of open streams
+    pub fn len(&self) -> usize {
+        self.inbound.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inbound.is_empty()
+    }
+
+    /// Whether a stream with this id is open
+    pub fn contains(&self, id: &str) -> bool {
+        self.inbound.contains_key(id)
+    }
+
+    /// Start pumping a local connection. `initial` holds bytes already
+    /// read from the local side (e.g. frames that followed the 101).
+    /// With `capture`, WebSocket frames are recorded both ways; without,
+    /// bytes are copied through untouched.
+    pub fn open(&mut self, id: String, local: Box<dyn LocalStream>, initial: Vec<u8>, capture: Option<Capture>) {
+        let (tx, rx) = mpsc::channel::<Bytes>(64);
+        match capture {
+            Some(capture) => self.pump_captured(id.clone(), local, rx, initial, capture),
+            None => {
+                let relay = RelayIo::new(id.clone(), rx, self.out.clone());
+                tokio::spawn(pump(local, relay, initial, self.out.clone()));
+            }
+        }
+        self.inbound.insert(id, tx);
+        debug!("{} stream(s) open", self.len());
+    }
+
+    /// A pair of pump tasks that parse WebSocket frames as they pass
+    fn pump_captured(
+        &self,
+        id: String,
+        local: Box<dyn LocalStream>,
+        mut rx: mpsc::Receiver<Bytes>,
+        initial: Vec<u8>,
+        capture: Capture,
+    ) {
+        let (mut local_read, mut local_write) = tokio::io::split(local);
+
+        // relay → local
+        let mut tap = Some((capture.clone(), FrameParser::default()));
+        tokio::spawn(async move {
+            while let Some(chunk) = rx.recv().await {
+                observe(&mut tap, Direction::Inbound, &chunk);
+                if local_write.write_all(&chunk).await.is_err() {
+                    break;
+                }
+            }
+            let _ = local_write.shutdown().await;
+        });
+
+        // local → relay
+        let out = self.out.clone();
+        let mut tap = Some((capture, FrameParser::default()));
+        tokio::spawn(async move {
+            observe(&mut tap, Direction::Outbound, &initial);
+            if !initial.is_empty() && !send(&out, &id, StreamEvent::Data(initial.into())).await {
+                return;
+            }
+            let mut buf = vec![0u8; CHUNK_SIZE];
+            loop {
+                match local_read.read(&mut buf).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => {
+                        observe(&mut tap, Direction::Outbound, &buf[..n]);
+                        if !send(&out, &id, StreamEvent::Data(Bytes::copy_from_slice(&buf[..n]))).await {
+                            return;
+                        }
+                    }
+                }
+            }
+            send(&out, &id, StreamEvent::Close).await;
+            debug!("Stream {} closed by local side", id);
+        });
+    }
+
+    /// Collect the frames of a streamed request body on a channel
+    /// instead of a local connection. The channel ends at the close
+    /// frame.
+    pub fn receive(&mut self, id: String) -> mpsc::Receiver<Bytes> {
+        let (tx, rx) = mpsc::channel::<Bytes>(64);
+        self.inbound.insert(id, tx);
+        rx
+    }
+
+    /// Tell the relay a stream is finished without opening it locally
+    pub async fn reject(&self, id: &str) {
+        send(&self.out, id, StreamEvent::Close).await;
+    }
+
+    /// Deliver a data or close frame received from the relay
+    pub async fn deliver(&mut self, frame: StreamFrame) {
+        match frame.event {
+            StreamEvent::Open => {
+                warn!("Stream {} opened without a local connection", frame.stream);
+            }
+            StreamEvent::Data(data) => {
+                let Some(tx) = self.inbound.get(&frame.stream) else {
+                    return;
+                };
+                if tx.send(data).await.is_err() {
+                    self.inbound.remove(&frame.stream);
+                }
+            }
+            StreamEvent::Close => {
+                // Dropping the sender shuts down the local write half
+                if self.inbound.remove(&frame.stream).is_some() {
+                    debug!("Stream {} closed by remote side", frame.stream);
+                }
+            }
+        }
+    }
+}
+
+/// Copy between a local connection and the relay until both sides
+/// have closed, through one pair of buffers for the stream's lifetime
+async fn pump(mut local: Box<dyn LocalStream>, mut relay: RelayIo, initial: Vec<u8>, out: mpsc::Sender<Message>) {
+    let copied = async {
+        relay.write_all(&initial).await?;
+        tokio::io::copy_bidirectional_with_sizes(&mut local, &mut relay, PUMP_BUFFER_SIZE, PUMP_BUFFER_SIZE).await
+    };
+    match copied.await {
+        Ok((sent, received)) => debug!("Stream {} closed ({} bytes out, {} in)", relay.id, sent, received),
+        Err(e) => debug!("Stream {} failed: {}", relay.id, e),
+    }
+    // An error skips the shutdown that would have told the relay
+    if !relay.closed {
+        send(&out, &relay.id, StreamEvent::Close).await;
+    }
+}
+
+/// The relay end of one stream as a byte stream: reads come from the
+/// data frames the relay sends, writes go out as data frames, and
+/// shutdown sends the close frame
+struct RelayIo {
+    id: String,
+    inbound: mpsc::Receiver<Bytes>,
+    /// Rest of the last frame not yet read
+    pending: Bytes,
+    out: PollSender<Message>,
+    /// The close frame has been sent
+    closed: bool,
+}
+
+impl RelayIo {
+    fn new(id: String, inbound: mpsc::Receiver<Bytes>, out: mpsc::Sender<Message>) -> Self {
+        Self { id, inbound, pending: Bytes::new(), out: PollSender::new(out), closed: false }
+    }
+
+    /// Queue a frame once the outgoing channel has room
+    fn poll_send(&mut self, cx: &mut Context<'_>, event: StreamEvent) -> Poll<io::Result<()>> {
+        let gone = |_| io::Error::from(io::ErrorKind::BrokenPipe);
+        ready!(self.out.poll_reserve(cx)).map_err(gone)?;
+        let frame = StreamFrame::new(self.id.as_str(), event);
+        let data = serde_json::to_vec(&frame)?;
+        self.out.send_item(Message::Binary(data)).map_err(gone)?;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for RelayIo {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        while self.pending.is_empty() {
+            match ready!(self.inbound.poll_recv(cx)) {
+                Some(chunk) => self.pending = chunk,
+                // The relay closed its side
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+        let n = self.pending.len().min(buf.remaining());
+        buf.put_slice(&self.pending.split_to(n));
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for RelayIo {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        ready!(self.poll_send(cx, StreamEvent::Data(Bytes::copy_from_slice(buf))))?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if !self.closed {
+            ready!(self.poll_send(cx, StreamEvent::Close))?;
+            self.closed = true;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Run bytes through a pump's frame parser, if it has one
+fn observe(tap: &mut Option<(Capture, FrameParser)>, direction: Direction, bytes: &[u8]) {
+    if let Some((capture, parser)) = tap {
+        for frame in parser.push(bytes) {
+            capture.record(direction, frame);
+        }
+    }
+}
+
+async fn send(out: &mpsc::Sender<Message>, stream: &str, event: StreamEvent) -> bool {
+    let frame = StreamFrame::new(stream, event);
+    match serde_json::to_vec(&frame) {
+        Ok(data) => out.send(Message::Binary(data)).await.is_ok(),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn next_frame(rx: &mut mpsc::Receiver<Message>) -> StreamFrame {
+        match rx.recv().await {
+            Some(Message::Binary(data)) => serde_json::from_slice(&data).unwrap(),
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stream_pumps_both_ways() {
+        let (out_tx, mut out_rx) = mpsc::channel(16);
+        let mut streams = Streams::new(out_tx);
+        let (local, mut service) = tokio::io::duplex(1024);
+
+        streams.open("r1".into(), Box::new(local), b"hello".to_vec(), None);
+        let frame = next_frame(&mut out_rx).await;
+        assert_eq!(frame.stream, "r1");
+        assert_eq!(frame.event, StreamEvent::Data(Bytes::from_static(b"hello")));
+
+        // relay → local
+        let data = StreamFrame::new("r1", StreamEvent::Data(Bytes::from_static(b"ping")));
+        streams.deliver(data).await;
+        let mut buf = [0u8; 4];
+        service.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        // local → relay
+        service.write_all(b"pong").await.unwrap();
+        assert_eq!(next_frame(&mut out_rx).await.event, StreamEvent::Data(Bytes::from_static(b"pong")));
+
+        // Local close is propagated
+        drop(service);
+        assert_eq!(next_frame(&mut out_rx).await.event, StreamEvent::Close);
+
+        streams.deliver(StreamFrame::new("r1", StreamEvent::Close)).await;
+        assert_eq!(streams.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_stream_large_frames_and_relay_close() {
+        let (out_tx, mut out_rx) = mpsc::channel(16);
+        let mut streams = Streams::new(out_tx);
+        let (local, mut service) = tokio::io::duplex(4096);
+        streams.open("r1".into(), Box::new(local), Vec::new(), None);
+
+        // A frame larger than both copy buffers arrives whole
+        let big: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+        let frame = StreamFrame::new("r1", StreamEvent::Data(Bytes::from(big.clone())));
+        streams.deliver(frame).await;
+        streams.deliver(StreamFrame::new("r1", StreamEvent::Close)).await;
+        let mut received = Vec::new();
+        service.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, big);
+
+        drop(service);
+        assert_eq!(next_frame(&mut out_rx).await.event, StreamEvent::Close);
+    }
+}
@@ -0,0 +1,172 @@
+//! What a tunnel did, printed when it stops
+//!
+//! `Summary` is a telemetry backend fed the same measurements a metrics
+//! endpoint would export: requests by status, bytes each way, request
+//! durations and reconnects. `ztunnel http` and `ztunnel proxy` fan
+//! their telemetry out to one and print the report when they stop.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use ztunnel_shared::telemetry::{label, metric, Labels};
+use ztunnel_shared::Telemetry;
+
+#[derive(Default)]
+struct Counts {
+    by_status: BTreeMap<u16, u64>,
+    bytes_in: u64,
+    bytes_out: u64,
+    reconnects: u64,
+    /// Requests over the tunnel's `slow_threshold_ms`
+    slow: u64,
+    /// Requests by whole milliseconds of latency, so memory is bounded
+    /// by distinct durations rather than requests
+    latency_ms: BTreeMap<u64, u64>,
+}
+
+/// Counters for one run of a tunnel
+pub struct Summary {
+    started: Instant,
+    counts: Mutex<Counts>,
+}
+
+impl Default for Summary {
+    fn default() -> Self {
+        Self { started: Instant::now(), counts: Mutex::default() }
+    }
+}
+
+impl Summary {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn requests(&self) -> u64 {
+        self.counts.lock().unwrap().by_status.values().sum()
+    }
+
+    /// Latency under which 95% of requests finished
+    pub fn p95(&self) -> Option<Duration> {
+        let counts = self.counts.lock().unwrap();
+        let total: u64 = counts.latency_ms.values().sum();
+        let rank = total - total / 20;
+        let mut seen = 0;
+        for (ms, n) in &counts.latency_ms {
+            seen += n;
+            if seen >= rank {
+                return Some(Duration::from_millis(*ms));
+            }
+        }
+        None
+    }
+
+    /// The lines printed when the tunnel stops
+    pub fn report(&self) -> Vec<String> {
+        let p95 = self.p95();
+        let counts = self.counts.lock().unwrap();
+        let requests: u64 = counts.by_status.values().sum();
+        let mut lines = vec![format!("Duration:    {}", format_duration(self.started.elapsed()))];
+        if requests > 0 {
+            let statuses: Vec<String> = counts.by_status.iter().map(|(s, n)| format!("{} ×{}", s, n)).collect();
+            lines.push(format!("Requests:    {} ({})", requests, statuses.join(", ")));
+        } else {
+            lines.push("Requests:    0".to_string());
+        }
+        lines.push(format!(
+            "Transferred: {} in, {} out",
+            format_bytes(counts.bytes_in),
+            format_bytes(counts.bytes_out)
+        ));
+        if let Some(p95) = p95 {
+            lines.push(format!("p95 latency: {} ms", p95.as_millis()));
+        }
+        if counts.slow > 0 {
+            lines.push(format!("Slow:        {}", counts.slow));
+        }
+        if counts.reconnects > 0 {
+            lines.push(format!("Reconnects:  {}", counts.reconnects));
+        }
+        lines
+    }
+}
+
+impl Telemetry for Summary {
+    fn counter(&self, name: &str, labels: Labels<'_>, delta: u64) {
+        let mut counts = self.counts.lock().unwrap();
+        match (name, label(labels, "direction")) {
+            (metric::REQUESTS, _) => {
+                if let Some(status) = label(labels, "status").and_then(|s| s.parse().ok()) {
+                    *counts.by_status.entry(status).or_default() += delta;
+                }
+            }
+            (metric::BYTES, Some("in")) => counts.bytes_in += delta,
+            (metric::BYTES, Some("out")) => counts.bytes_out += delta,
+            (metric::RECONNECTS, _) => counts.reconnects += delta,
+            (metric::SLOW_REQUESTS, _) => counts.slow += delta,
+            _ => {}
+        }
+    }
+
+    fn gauge(&self, _: &str, _: Labels<'_>, _: f64) {}
+
+    fn histogram(&self, name: &str, _: Labels<'_>, value: f64) {
+        if name == metric::REQUEST_DURATION {
+            let ms = (value * 1000.0).round() as u64;
+            *self.counts.lock().unwrap().latency_ms.entry(ms).or_default() += 1;
+        }
+    }
+}
+
+fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m {}s", m, s),
+        (h, m, s) => format!("{}h {}m {}s", h, m, s),
+    }
+}
+
+fn format_bytes(n: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    if n < 1024 {
+        return format!("{} B", n);
+    }
+    let mut value = n as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summary_report() {
+        let summary = Summary::new();
+        assert_eq!(summary.p95(), None);
+        for ms in 1..=100 {
+            summary.request("http", 200, Duration::from_millis(ms), 100, 1000);
+        }
+        summary.request("http", 404, Duration::from_millis(3), 50, 20);
+        summary.counter(metric::RECONNECTS, &[("tunnel", "http")], 1);
+        summary.counter(metric::SLOW_REQUESTS, &[("tunnel", "http")], 2);
+
+        assert_eq!(summary.requests(), 101);
+        assert_eq!(summary.p95(), Some(Duration::from_millis(95)));
+        let report = summary.report();
+        assert_eq!(report[0], "Duration:    0s");
+        assert_eq!(report[1..], [
+            "Requests:    101 (200 ×100, 404 ×1)",
+            "Transferred: 9.8 KB in, 97.7 KB out",
+            "p95 latency: 95 ms",
+            "Slow:        2",
+            "Reconnects:  1",
+        ]);
+        assert_eq!(format_duration(Duration::from_secs(3725)), "1h 2m 5s");
+        assert_eq!(format_bytes(512), "512 B");
+    }
+}
@@ -1,5 +1,6 @@
 //! Tunnel types for client-server communication
 
+use bytes::Bytes;
 use serde::{Deserialize, Serialize};
 
 /// Request forwarded through tunnel
@@ -9,7 +10,11 @@ pub struct TunnelRequest {
     pub method: String,
     pub path: String,
     pub headers: Vec<(String, String)>,
-    pub body: Option<Vec<u8>>,
+    pub body: Option<Bytes>,
+    /// The body follows as `StreamFrame` data on this request's id,
+    /// ended by a close frame, instead of in `body`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub streamed: bool,
 }
 
 /// Response from local server
@@ -18,5 +23,42 @@ pub struct TunnelResponse {
     pub id: String,
     pub status: u16,
     pub headers: Vec<(String, String)>,
-    pub body: Option<Vec<u8>>,
+    pub body: Option<Bytes>,
+    /// The body follows as `StreamFrame` data on the request's id,
+    /// ended by a close frame, instead of in `body`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub streamed: bool,
+}
+
+/// Raw bytes for a long-lived stream, keyed by the id of the request
+/// that upgraded it or the relay's id for a TCP connection
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StreamFrame {
+    pub stream: String,
+    pub event: StreamEvent,
+    /// Visitor's address, sent by the relay with `Open`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remote_addr: Option<std::net::SocketAddr>,
+    /// `host:port` a gateway tunnel should dial, sent with `Open`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    /// Consumer's gateway token, sent with `Open`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+impl StreamFrame {
+    pub fn new(stream: impl Into<String>, event: StreamEvent) -> Self {
+        Self { stream: stream.into(), event, remote_addr: None, target: None, token: None }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum StreamEvent {
+    /// A new remote TCP connection; the client dials the local service
+    Open,
+    /// Bytes to deliver to the other side
+    Data(Bytes),
+    /// The sender closed its side of the stream
+    Close,
 }
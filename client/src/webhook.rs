@@ -0,0 +1,240 @@
+//! Webhook signature verification
+//!
+//! A tunnel with a `webhook` section checks the HMAC signature of every
+//! incoming request the way the sending provider defines it, and tags
+//! the inspector entry with the result. With `reject_invalid`, requests
+//! that fail are answered 401 without reaching the local service.
+//!
+//! Presets cover Stripe (`Stripe-Signature`), GitHub
+//! (`X-Hub-Signature-256`) and Slack (`X-Slack-Signature`); `generic`
+//! takes the header and algorithm from the config and accepts a hex or
+//! base64 digest, optionally prefixed with `sha256=`.
+
+use crate::config::WebhookConfig;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+
+/// Stripe and Slack reject signatures older than this, against replays
+const TIMESTAMP_TOLERANCE_SECS: i64 = 300;
+
+pub const PROVIDERS: &[&str] = &["stripe", "github", "slack", "generic"];
+pub const ALGORITHMS: &[&str] = &["sha256", "sha1"];
+
+/// Outcome recorded on the inspector entry
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SignatureCheck {
+    pub provider: String,
+    pub valid: bool,
+    /// Why the signature was rejected
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+enum Provider {
+    Stripe,
+    GitHub,
+    Slack,
+    Generic { header: String },
+}
+
+/// A tunnel's webhook settings with the key prepared
+#[derive(Debug, Clone)]
+pub struct WebhookVerifier {
+    provider: Provider,
+    key: hmac::Key,
+    pub reject_invalid: bool,
+}
+
+impl WebhookVerifier {
+    /// None for an unknown provider or algorithm, which
+    /// `TunnelConfig::validate` reports
+    pub fn from_config(conf: &WebhookConfig) -> Option<Self> {
+        let provider = match conf.provider.as_str() {
+            "stripe" => Provider::Stripe,
+            "github" => Provider::GitHub,
+            "slack" => Provider::Slack,
+            "generic" => Provider::Generic { header: conf.header.clone()? },
+            _ => return None,
+        };
+        let algorithm = match (&provider, conf.algorithm.as_deref().unwrap_or("sha256")) {
+            (Provider::Generic { .. }, "sha1") => hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY,
+            (_, "sha256") => hmac::HMAC_SHA256,
+            _ => return None,
+        };
+        Some(Self { provider, key: hmac::Key::new(algorithm, conf.secret.as_bytes()), reject_invalid: conf.reject_invalid })
+    }
+
+    /// Check a request's signature
+    pub fn verify(&self, headers: &[(String, String)], body: &[u8]) -> SignatureCheck {
+        self.verify_at(headers, body, chrono::Utc::now().timestamp())
+    }
+
+    fn verify_at(&self, headers: &[(String, String)], body: &[u8], now: i64) -> SignatureCheck {
+        let (name, result) = match &self.provider {
+            Provider::Stripe => ("stripe", self.stripe(headers, body, now)),
+            Provider::GitHub => ("github", self.github(headers, body)),
+            Provider::Slack => ("slack", self.slack(headers, body, now)),
+            Provider::Generic { header } => ("generic", self.generic(headers, header, body)),
+        };
+        SignatureCheck { provider: name.to_string(), valid: result.is_ok(), reason: result.err() }
+    }
+
+    /// `Stripe-Signature: t=TIMESTAMP,v1=HEX[,v1=HEX...]` over `TIMESTAMP.BODY`
+    fn stripe(&self, headers: &[(String, String)], body: &[u8], now: i64) -> Result<(), String> {
+        let value = header(headers, "stripe-signature")?;
+        let mut timestamp = None;
+        let mut signatures = Vec::new();
+        for part in value.split(',') {
+            match part.trim().split_once('=') {
+                Some(("t", t)) => timestamp = Some(t),
+                Some(("v1", sig)) => signatures.push(sig),
+                _ => {}
+            }
+        }
+        let timestamp = timestamp.ok_or("Stripe-Signature has no timestamp")?;
+        check_timestamp(timestamp, now)?;
+        let signed = [timestamp.as_bytes(), b".", body].concat();
+        if signatures.iter().any(|sig| self.matches(&signed, sig)) {
+            Ok(())
+        } else {
+            Err("No v1 signature matches".to_string())
+        }
+    }
+
+    /// `X-Hub-Signature-256: sha256=HEX` over the body
+    fn github(&self, headers: &[(String, String)], body: &[u8]) -> Result<(), String> {
+        let value = header(headers, "x-hub-signature-256")?;
+        let sig = value.strip_prefix("sha256=").ok_or("X-Hub-Signature-256 is not sha256=...")?;
+        self.check(body, sig)
+    }
+
+    /// `X-Slack-Signature: v0=HEX` over `v0:TIMESTAMP:BODY`
+    fn slack(&self, headers: &[(String, String)], body: &[u8], now: i64) -> Result<(), String> {
+        let value = header(headers, "x-slack-signature")?;
+        let timestamp = header(headers, "x-slack-request-timestamp")?;
+        check_timestamp(timestamp, now)?;
+        let sig = value.strip_prefix("v0=").ok_or("X-Slack-Signature is not v0=...")?;
+        self.check(&[b"v0:", timestamp.as_bytes(), b":", body].concat(), sig)
+    }
+
+    fn generic(&self, headers: &[(String, String)], name: &str, body: &[u8]) -> Result<(), String> {
+        let value = header(headers, name)?;
+        let sig = value.split_once('=').filter(|(algo, _)| ALGORITHMS.contains(algo)).map_or(value, |(_, sig)| sig);
+        self.check(body, sig)
+    }
+
+    fn check(&self, message: &[u8], signature: &str) -> Result<(), String> {
+        if self.matches(message, signature) {
+            Ok(())
+        } else {
+            Err("Signature does not match".to_string())
+        }
+    }
+
+    /// Constant-time comparison against a hex or base64 digest
+    fn matches(&self, message: &[u8], signature: &str) -> bool {
+        let signature = signature.trim();
+        [decode_hex(signature), STANDARD.decode(signature).ok()]
+            .into_iter()
+            .flatten()
+            .any(|tag| hmac::verify(&self.key, message, &tag).is_ok())
+    }
+}
+
+fn header<'a>(headers: &'a [(String, String)], name: &str) -> Result<&'a str, String> {
+    headers
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str())
+        .ok_or_else(|| format!("Missing {} header", name))
+}
+
+fn check_timestamp(timestamp: &str, now: i64) -> Result<(), String> {
+    let ts: i64 = timestamp.trim().parse().map_err(|_| format!("Bad signature timestamp '{}'", timestamp))?;
+    if (now - ts).abs() > TIMESTAMP_TOLERANCE_SECS {
+        return Err(format!("Signature timestamp is {}s off", now - ts));
+    }
+    Ok(())
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn verifier(provider: &str, header: Option<&str>) -> WebhookVerifier {
+        WebhookVerifier::from_config(&WebhookConfig {
+            provider: provider.to_string(),
+            secret: "whsec".to_string(),
+            header: header.map(String::from),
+            algorithm: None,
+            reject_invalid: false,
+        })
+        .unwrap()
+    }
+
+    fn sign(message: &[u8]) -> String {
+        let tag = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, b"whsec"), message);
+        tag.as_ref().iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn headers(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn test_presets() {
+        let body = b"{\"id\":1}";
+        let now = 1_700_000_000;
+
+        let sig = format!("t={},v1=00,v1={}", now, sign(&[format!("{}.", now).as_bytes(), body].concat()));
+        let stripe = verifier("stripe", None);
+        assert!(stripe.verify_at(&headers(&[("Stripe-Signature", &sig)]), body, now).valid);
+        let stale = stripe.verify_at(&headers(&[("Stripe-Signature", &sig)]), body, now + 600);
+        assert!(stale.reason.unwrap().contains("600s off"));
+
+        let github = verifier("github", None);
+        let sig = format!("sha256={}", sign(body));
+        assert!(github.verify_at(&headers(&[("X-Hub-Signature-256", &sig)]), body, now).valid);
+        assert!(!github.verify_at(&headers(&[("X-Hub-Signature-256", &sig)]), b"{}", now).valid);
+        let missing = github.verify_at(&[], body, now);
+        assert_eq!(missing.reason.as_deref(), Some("Missing x-hub-signature-256 header"));
+
+        let slack = verifier("slack", None);
+        let sig = format!("v0={}", sign(&[format!("v0:{}:", now).as_bytes(), body].concat()));
+        let ts = now.to_string();
+        let h = headers(&[("X-Slack-Signature", &sig), ("X-Slack-Request-Timestamp", &ts)]);
+        assert!(slack.verify_at(&h, body, now).valid);
+    }
+
+    #[test]
+    fn test_generic() {
+        let body = b"payload";
+        let v = verifier("generic", Some("X-Signature"));
+        assert!(v.verify_at(&headers(&[("X-Signature", &sign(body))]), body, 0).valid);
+        assert!(v.verify_at(&headers(&[("x-signature", &format!("sha256={}", sign(body)))]), body, 0).valid);
+
+        let b64 = STANDARD.encode(hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, b"whsec"), body));
+        assert!(v.verify_at(&headers(&[("X-Signature", &b64)]), body, 0).valid);
+
+        let conf = |provider: &str, header: Option<&str>, algorithm: &str| WebhookConfig {
+            provider: provider.to_string(),
+            secret: "s".to_string(),
+            header: header.map(String::from),
+            algorithm: Some(algorithm.to_string()),
+            reject_invalid: false,
+        };
+        assert!(WebhookVerifier::from_config(&conf("generic", None, "sha256")).is_none());
+        assert!(WebhookVerifier::from_config(&conf("generic", Some("X-Sig"), "sha1")).is_some());
+        assert!(WebhookVerifier::from_config(&conf("github", None, "sha1")).is_none());
+    }
+}
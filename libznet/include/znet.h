@@ -89,6 +89,14 @@ typedef struct znet_throttle znet_throttle_t;
  */
 znet_throttle_t* znet_throttle_create(uint64_t bytes_per_sec);
 
+/**
+ * Create a bandwidth throttler that lets bursts through up to a size.
+ * @param bytes_per_sec  Maximum long-term bytes per second (0 = unlimited)
+ * @param burst_bytes    Bucket depth (0 = one second at bytes_per_sec)
+ * @return Handle or NULL on failure
+ */
+znet_throttle_t* znet_throttle_create_burst(uint64_t bytes_per_sec, uint64_t burst_bytes);
+
 /**
  * Consume tokens for the given number of bytes.
  * Returns immediately; call znet_throttle_wait() after.
@@ -115,6 +123,16 @@ uint64_t znet_throttle_get_rate(znet_throttle_t *throttle);
  */
 void znet_throttle_set_rate(znet_throttle_t *throttle, uint64_t bytes_per_sec);
 
+/**
+ * Get the bucket depth in bytes.
+ */
+uint64_t znet_throttle_get_burst(znet_throttle_t *throttle);
+
+/**
+ * Update the bucket depth (0 = one second at the current rate).
+ */
+void znet_throttle_set_burst(znet_throttle_t *throttle, uint64_t burst_bytes);
+
 /**
  * Destroy the throttler.
  */
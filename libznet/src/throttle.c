@@ -19,6 +19,7 @@ struct znet_throttle {
   uint64_t rate_bps;    /**< Bytes per second limit */
   uint64_t tokens;      /**< Available tokens (bytes) */
   uint64_t max_tokens;  /**< Max burst size */
+  uint64_t burst;       /**< Configured burst (0 = one second of rate) */
   uint64_t last_refill; /**< Last refill timestamp (ns) */
   uint64_t wait_ns;     /**< How long to wait for tokens (ns) */
 };
@@ -46,14 +47,27 @@ static void sleep_ns(uint64_t ns) {
   nanosleep(&ts, NULL);
 }
 
+/** Bucket depth for the current rate and burst */
+static uint64_t bucket_depth(const znet_throttle_t *t) {
+  if (t->rate_bps == 0)
+    return UINT64_MAX;
+  /* Without a burst, allow up to 1 second worth of data */
+  return t->burst > 0 ? t->burst : t->rate_bps;
+}
+
 znet_throttle_t *znet_throttle_create(uint64_t bytes_per_sec) {
+  return znet_throttle_create_burst(bytes_per_sec, 0);
+}
+
+znet_throttle_t *znet_throttle_create_burst(uint64_t bytes_per_sec,
+                                            uint64_t burst_bytes) {
   znet_throttle_t *t = (znet_throttle_t *)calloc(1, sizeof(znet_throttle_t));
   if (!t)
     return NULL;
 
   t->rate_bps = bytes_per_sec;
-  /* Allow burst of up to 1 second worth of data */
-  t->max_tokens = bytes_per_sec > 0 ? bytes_per_sec : UINT64_MAX;
+  t->burst = burst_bytes;
+  t->max_tokens = bucket_depth(t);
   t->tokens = t->max_tokens;
   t->last_refill = now_ns();
   t->wait_ns = 0;
@@ -136,7 +150,21 @@ void znet_throttle_set_rate(znet_throttle_t *t, uint64_t bytes_per_sec) {
   if (!t)
     return;
   t->rate_bps = bytes_per_sec;
-  t->max_tokens = bytes_per_sec > 0 ? bytes_per_sec : UINT64_MAX;
+  t->max_tokens = bucket_depth(t);
+  if (t->tokens > t->max_tokens) {
+    t->tokens = t->max_tokens;
+  }
+}
+
+uint64_t znet_throttle_get_burst(znet_throttle_t *t) {
+  return t ? t->max_tokens : 0;
+}
+
+void znet_throttle_set_burst(znet_throttle_t *t, uint64_t burst_bytes) {
+  if (!t)
+    return;
+  t->burst = burst_bytes;
+  t->max_tokens = bucket_depth(t);
   if (t->tokens > t->max_tokens) {
     t->tokens = t->max_tokens;
   }
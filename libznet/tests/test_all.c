@@ -69,6 +69,26 @@ void test_throttle_consume(void) {
   PASS();
 }
 
+void test_throttle_burst(void) {
+  TEST(throttle_burst);
+  znet_throttle_t *t = znet_throttle_create_burst(100, 300);
+  if (!t) {
+    FAIL("NULL");
+    return;
+  }
+  /* Three seconds' worth goes through at once */
+  assert(znet_throttle_get_burst(t) == 300);
+  assert(znet_throttle_consume(t, 300) == 0);
+  assert(znet_throttle_consume(t, 50) == 1);
+  /* A rate change keeps the burst */
+  znet_throttle_set_rate(t, 200);
+  assert(znet_throttle_get_burst(t) == 300);
+  znet_throttle_set_burst(t, 0);
+  assert(znet_throttle_get_burst(t) == 200);
+  znet_throttle_destroy(t);
+  PASS();
+}
+
 void test_throttle_set_rate(void) {
   TEST(throttle_set_rate);
   znet_throttle_t *t = znet_throttle_create(1000);
@@ -184,6 +204,7 @@ int main(void) {
   test_throttle_create();
   test_throttle_unlimited();
   test_throttle_consume();
+  test_throttle_burst();
   test_throttle_set_rate();
 
   /* Packets */
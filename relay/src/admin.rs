@@ -0,0 +1,279 @@
+//! Operator API under `/admin`
+//!
+//! Off unless admin tokens are configured: ZTUNNEL_ADMIN_TOKEN for a
+//! single admin, and ZTUNNEL_ADMIN_TOKENS=`alice=TOKEN,bob=TOKEN2` to
+//! tell admins apart in the audit log. Every request needs one as
+//! `Authorization: Bearer TOKEN`. It answers on the relay's own host
+//! only; on a tunnel's host `/admin` paths go to the tunnel.
+//!
+//! - `GET /admin/usage?from=YYYY-MM-DD&to=YYYY-MM-DD&account=NAME&format=csv`:
+//!   per-account usage by day, both dates inclusive. `from` defaults to
+//!   the first of this month and `to` to today (UTC).
+//! - `DELETE /admin/tunnels/NAME`: disconnect a tunnel's client.
+//! - `GET /admin/circuits`: each tunnel's circuit breaker state, how
+//!   often it entered each state and how long it has spent in each.
+//! - `GET /admin/bans`: bans in force.
+//! - `POST /admin/bans` with `{"target": "IP or IPv4/CIDR", "reason":
+//!   "...", "duration": "12h"}`: ban an address everywhere; without a
+//!   duration it's permanent.
+//! - `DELETE /admin/bans/TARGET`: lift a ban; a range's `/` is `%2F`.
+//! - `GET /admin/audit?since=&until=&actor=&action=&target=&limit=`: the
+//!   audit log, oldest first; times are RFC 3339.
+//! - `GET /admin/maintenance`: the maintenance window, if any.
+//! - `POST /admin/maintenance` with `{"message": "...", "drain": "10m",
+//!   "duration": "30m"}`: start maintenance. Tunnels close after `drain`
+//!   (5m by default) and clients are told to come back after `duration`
+//!   more (30m by default).
+//! - `DELETE /admin/maintenance`: end it.
+
+use anyhow::Result;
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Path, Query, State},
+    http::{header, request::Parts, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{delete, get},
+    Json, Router,
+};
+use chrono::{DateTime, Datelike, NaiveDate, Utc};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::namespace::constant_time_eq;
+use crate::bans::{self, Ban};
+use crate::maintenance::Window;
+use crate::{audit, usage, AppState};
+
+/// Most audit entries one request returns
+const MAX_AUDIT_ENTRIES: usize = 1000;
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/admin/usage", get(usage_handler))
+        .route("/admin/tunnels/:subdomain", delete(kick_handler))
+        .route("/admin/circuits", get(circuits_handler))
+        .route("/admin/bans", get(list_bans).post(add_ban))
+        .route("/admin/bans/:target", delete(remove_ban))
+        .route("/admin/audit", get(audit_handler))
+        .route("/admin/maintenance", get(get_maintenance).post(start_maintenance).delete(end_maintenance))
+}
+
+/// Admin names and tokens from the environment
+pub fn tokens_from_env() -> Result<Vec<(String, String)>> {
+    let mut tokens = Vec::new();
+    if let Ok(token) = std::env::var("ZTUNNEL_ADMIN_TOKEN") {
+        if !token.is_empty() {
+            tokens.push(("admin".to_string(), token));
+        }
+    }
+    let named = std::env::var("ZTUNNEL_ADMIN_TOKENS").unwrap_or_default();
+    for entry in named.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        match entry.split_once('=') {
+            Some((name, token)) if !name.is_empty() && !token.is_empty() => {
+                tokens.push((name.to_string(), token.to_string()));
+            }
+            _ => anyhow::bail!("Invalid ZTUNNEL_ADMIN_TOKENS entry '{}', expected name=token", entry),
+        }
+    }
+    Ok(tokens)
+}
+
+/// A request made with an admin token, and whose it is
+pub struct Admin(pub String);
+
+#[async_trait]
+impl FromRequestParts<AppState> for Admin {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Response> {
+        if state.admin_tokens.is_empty() {
+            return Err((StatusCode::NOT_FOUND, "Admin API disabled").into_response());
+        }
+        let given = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .unwrap_or_default();
+        // Check every token, so timing doesn't tell which one matched
+        let mut admin = None;
+        for (name, token) in state.admin_tokens.iter() {
+            if constant_time_eq(given.as_bytes(), token.as_bytes()) {
+                admin = Some(name.clone());
+            }
+        }
+        admin.map(Admin).ok_or_else(|| {
+            (StatusCode::UNAUTHORIZED, [(header::WWW_AUTHENTICATE, "Bearer")], "Unauthorized").into_response()
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct UsageQuery {
+    from: Option<NaiveDate>,
+    to: Option<NaiveDate>,
+    account: Option<String>,
+    format: Option<String>,
+}
+
+async fn usage_handler(_: Admin, State(state): State<AppState>, Query(query): Query<UsageQuery>) -> Response {
+    let today = Utc::now().date_naive();
+    let from = query.from.unwrap_or_else(|| today.with_day(1).unwrap_or(today));
+    let to = query.to.unwrap_or(today);
+    if from > to {
+        return (StatusCode::BAD_REQUEST, "'from' is after 'to'").into_response();
+    }
+    let rows = state.usage.rows(from, to, query.account.as_deref());
+    match query.format.as_deref() {
+        Some("csv") => ([(header::CONTENT_TYPE, "text/csv")], usage::to_csv(&rows)).into_response(),
+        None | Some("json") => {
+            let rows: Vec<_> = rows
+                .iter()
+                .map(|r| json!({
+                    "date": r.date,
+                    "account": r.account,
+                    "requests": r.requests,
+                    "bytes_in": r.bytes_in,
+                    "bytes_out": r.bytes_out,
+                    "tunnel_hours": r.tunnel_hours(),
+                }))
+                .collect();
+            Json(json!({ "from": from, "to": to, "usage": rows })).into_response()
+        }
+        Some(other) => (StatusCode::BAD_REQUEST, format!("Unknown format '{}', use json or csv", other)).into_response(),
+    }
+}
+
+async fn kick_handler(Admin(admin): Admin, State(state): State<AppState>, Path(subdomain): Path<String>) -> Response {
+    let Some(tunnel) = state.tunnels.get(&subdomain).map(|t| t.clone()) else {
+        return (StatusCode::NOT_FOUND, "Tunnel not found").into_response();
+    };
+    tunnel.kicked.notify_one();
+    let before = json!({
+        "account": tunnel.account,
+        "proto": if tunnel.tcp { "tcp" } else { "http" },
+        "connected_secs": tunnel.created_at.elapsed().as_secs(),
+    });
+    state.audit.record(&admin, "tunnel.kick", &subdomain, before, Value::Null);
+    StatusCode::NO_CONTENT.into_response()
+}
+
+async fn circuits_handler(_: Admin, State(state): State<AppState>) -> Response {
+    let circuits: Vec<_> = state
+        .circuits()
+        .await
+        .into_iter()
+        .map(|(tunnel, stats)| {
+            let mut entry = json!(stats);
+            entry["tunnel"] = json!(tunnel);
+            entry
+        })
+        .collect();
+    Json(json!({ "circuits": circuits })).into_response()
+}
+
+async fn list_bans(_: Admin, State(state): State<AppState>) -> Response {
+    Json(json!({ "bans": state.bans.list() })).into_response()
+}
+
+#[derive(Deserialize)]
+struct BanRequest {
+    target: String,
+    #[serde(default)]
+    reason: String,
+    duration: Option<String>,
+}
+
+async fn add_ban(Admin(admin): Admin, State(state): State<AppState>, Json(request): Json<BanRequest>) -> Response {
+    let Some(target) = bans::normalize(&request.target) else {
+        return (StatusCode::BAD_REQUEST, format!("'{}' is not an IP address or IPv4 range", request.target)).into_response();
+    };
+    let created = Utc::now();
+    let expires = match request.duration.as_deref().map(bans::parse_duration) {
+        None => None,
+        Some(Some(duration)) => match chrono::Duration::from_std(duration).ok().and_then(|d| created.checked_add_signed(d)) {
+            Some(expires) => Some(expires),
+            None => return (StatusCode::BAD_REQUEST, "Duration too long").into_response(),
+        },
+        Some(None) => return (StatusCode::BAD_REQUEST, "Invalid duration, use e.g. 30m, 12h or 7d").into_response(),
+    };
+    let ban = Ban { target: target.clone(), reason: request.reason, by: admin.clone(), created, expires };
+    let previous = state.bans.add(ban.clone());
+    state.audit.record(&admin, "ban.add", &target, json!(previous), json!(ban));
+    (StatusCode::CREATED, Json(ban)).into_response()
+}
+
+async fn remove_ban(Admin(admin): Admin, State(state): State<AppState>, Path(target): Path<String>) -> Response {
+    let Some(removed) = bans::normalize(&target).and_then(|t| state.bans.remove(&t)) else {
+        return (StatusCode::NOT_FOUND, "No such ban").into_response();
+    };
+    state.audit.record(&admin, "ban.remove", &removed.target, json!(removed), Value::Null);
+    StatusCode::NO_CONTENT.into_response()
+}
+
+#[derive(Deserialize)]
+struct AuditQuery {
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+    actor: Option<String>,
+    action: Option<String>,
+    target: Option<String>,
+    limit: Option<usize>,
+}
+
+async fn audit_handler(_: Admin, State(state): State<AppState>, Query(query): Query<AuditQuery>) -> Response {
+    // Not flattened into the query: numbers don't survive that
+    let filter = audit::Filter {
+        since: query.since,
+        until: query.until,
+        actor: query.actor,
+        action: query.action,
+        target: query.target,
+    };
+    let limit = query.limit.unwrap_or(100).min(MAX_AUDIT_ENTRIES);
+    Json(json!({ "entries": state.audit.query(&filter, limit) })).into_response()
+}
+
+async fn get_maintenance(_: Admin, State(state): State<AppState>) -> Response {
+    Json(json!({ "maintenance": state.maintenance.current() })).into_response()
+}
+
+#[derive(Deserialize)]
+struct MaintenanceRequest {
+    message: Option<String>,
+    drain: Option<String>,
+    duration: Option<String>,
+}
+
+async fn start_maintenance(
+    Admin(admin): Admin,
+    State(state): State<AppState>,
+    Json(request): Json<MaintenanceRequest>,
+) -> Response {
+    let parse = |given: Option<String>, default: &str| {
+        bans::parse_duration(given.as_deref().unwrap_or(default)).and_then(|d| chrono::Duration::from_std(d).ok())
+    };
+    let (Some(drain), Some(duration)) = (parse(request.drain, "5m"), parse(request.duration, "30m")) else {
+        return (StatusCode::BAD_REQUEST, "Invalid duration, use e.g. 30m, 12h or 7d").into_response();
+    };
+    let started = Utc::now();
+    let Some((closes, until)) = started
+        .checked_add_signed(drain)
+        .and_then(|closes| Some((closes, closes.checked_add_signed(duration)?)))
+    else {
+        return (StatusCode::BAD_REQUEST, "Duration too long").into_response();
+    };
+    let message = request.message.unwrap_or_else(|| "Scheduled relay maintenance".to_string());
+    let window = Window { message, by: admin.clone(), started, closes, until };
+    let previous = state.maintenance.start(window.clone());
+    state.audit.record(&admin, "maintenance.start", &state.domain, json!(previous), json!(window));
+    (StatusCode::CREATED, Json(window)).into_response()
+}
+
+async fn end_maintenance(Admin(admin): Admin, State(state): State<AppState>) -> Response {
+    let Some(ended) = state.maintenance.end() else {
+        return (StatusCode::NOT_FOUND, "Not in maintenance").into_response();
+    };
+    state.audit.record(&admin, "maintenance.end", &state.domain, json!(ended), Value::Null);
+    StatusCode::NO_CONTENT.into_response()
+}
@@ -0,0 +1,150 @@
+//! Audit log of admin API actions
+//!
+//! Every change made through `/admin` is recorded with who made it,
+//! when, and the state before and after. With ZTUNNEL_AUDIT_FILE the
+//! entries are appended to that file as JSON lines, and read back at
+//! startup; nothing is ever rewritten or removed.
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing::{info, warn};
+
+/// One admin action
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    pub time: DateTime<Utc>,
+    /// Name of the admin token used
+    pub actor: String,
+    /// What was done, such as `tunnel.kick`
+    pub action: String,
+    /// What it was done to
+    pub target: String,
+    pub before: Value,
+    pub after: Value,
+}
+
+/// Which entries to return
+#[derive(Debug, Default)]
+pub struct Filter {
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub actor: Option<String>,
+    pub action: Option<String>,
+    pub target: Option<String>,
+}
+
+impl Filter {
+    fn matches(&self, entry: &Entry) -> bool {
+        self.since.is_none_or(|t| entry.time >= t)
+            && self.until.is_none_or(|t| entry.time < t)
+            && self.actor.as_ref().is_none_or(|a| &entry.actor == a)
+            && self.action.as_ref().is_none_or(|a| &entry.action == a)
+            && self.target.as_ref().is_none_or(|t| &entry.target == t)
+    }
+}
+
+#[derive(Default)]
+pub struct AuditLog {
+    entries: Mutex<Vec<Entry>>,
+    file: Option<Mutex<File>>,
+    path: Option<PathBuf>,
+}
+
+impl AuditLog {
+    /// A log appended to `path`, holding what's already there
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut entries = Vec::new();
+        match File::open(path) {
+            Ok(file) => {
+                for (n, line) in BufReader::new(file).lines().enumerate() {
+                    let line = line.with_context(|| format!("Could not read {}", path.display()))?;
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    let entry = serde_json::from_str(&line)
+                        .with_context(|| format!("Invalid audit entry at {}:{}", path.display(), n + 1))?;
+                    entries.push(entry);
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("Could not read {}", path.display())),
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Could not open {}", path.display()))?;
+        Ok(Self { entries: Mutex::new(entries), file: Some(Mutex::new(file)), path: Some(path.to_path_buf()) })
+    }
+
+    /// Where entries are appended, if anywhere
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    pub fn record(&self, actor: &str, action: &str, target: &str, before: Value, after: Value) {
+        let entry = Entry {
+            time: Utc::now(),
+            actor: actor.to_string(),
+            action: action.to_string(),
+            target: target.to_string(),
+            before,
+            after,
+        };
+        info!("Admin {}: {} {}", entry.actor, entry.action, entry.target);
+        if let Some(file) = &self.file {
+            let written = serde_json::to_string(&entry)
+                .map_err(std::io::Error::from)
+                .and_then(|line| writeln!(file.lock().unwrap(), "{}", line));
+            if let Err(e) = written {
+                warn!("Audit entry not written: {}", e);
+            }
+        }
+        self.entries.lock().unwrap().push(entry);
+    }
+
+    /// Matching entries, oldest first, at most the `limit` newest
+    pub fn query(&self, filter: &Filter, limit: usize) -> Vec<Entry> {
+        let entries = self.entries.lock().unwrap();
+        let mut found: Vec<Entry> = entries.iter().rev().filter(|e| filter.matches(e)).take(limit).cloned().collect();
+        found.reverse();
+        found
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_audit_log_persists() {
+        let path = std::env::temp_dir().join(format!("ztunnel-audit-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let log = AuditLog::open(&path).unwrap();
+        log.record("alice", "tunnel.kick", "web", json!({ "connected_secs": 30 }), Value::Null);
+        log.record("bob", "tunnel.kick", "db", json!({ "connected_secs": 5 }), Value::Null);
+        drop(log);
+
+        let log = AuditLog::open(&path).unwrap();
+        log.record("alice", "tunnel.kick", "api", Value::Null, Value::Null);
+        let all = log.query(&Filter::default(), 100);
+        assert_eq!(all.iter().map(|e| e.target.as_str()).collect::<Vec<_>>(), ["web", "db", "api"]);
+        assert_eq!(all[0].before, json!({ "connected_secs": 30 }));
+
+        let alice = Filter { actor: Some("alice".into()), ..Default::default() };
+        assert_eq!(log.query(&alice, 100).len(), 2);
+        assert_eq!(log.query(&alice, 1)[0].target, "api");
+        let later = Filter { since: Some(all[2].time), ..Default::default() };
+        assert!(log.query(&later, 100).iter().all(|e| e.time >= all[2].time));
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
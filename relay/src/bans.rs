@@ -0,0 +1,181 @@
+//! Relay-wide IP bans
+//!
+//! A banned address or IPv4 range gets 403 for every tunnel, and can't
+//! register tunnels either. Bans are managed through `/admin/bans`, may
+//! expire, and with ZTUNNEL_BANS_FILE are saved there on every change
+//! and loaded at startup.
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::Duration;
+use tracing::warn;
+
+use crate::ip_filter::IpMatcher;
+
+/// One banned address or range
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Ban {
+    /// An IP address, or an IPv4 range like `203.0.113.0/24`
+    pub target: String,
+    pub reason: String,
+    /// Admin who added it
+    pub by: String,
+    pub created: DateTime<Utc>,
+    /// Permanent when absent
+    pub expires: Option<DateTime<Utc>>,
+}
+
+impl Ban {
+    fn is_active(&self, now: DateTime<Utc>) -> bool {
+        self.expires.is_none_or(|t| t > now)
+    }
+}
+
+/// The same target written one way, so it can be looked up and removed
+pub fn normalize(target: &str) -> Option<String> {
+    match IpMatcher::parse(target.trim())? {
+        IpMatcher::Ip(ip) => Some(ip.to_string()),
+        IpMatcher::Range(range) => Some(format!("{}/{}", Ipv4Addr::from(range.network), range.mask.count_ones())),
+    }
+}
+
+/// Parse `30s`, `15m`, `12h` or `7d`; a plain number is seconds
+pub fn parse_duration(s: &str) -> Option<Duration> {
+    let s = s.trim().to_lowercase();
+    let pos = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let num: u64 = s[..pos].parse().ok()?;
+    let unit = match &s[pos..] {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        _ => return None,
+    };
+    num.checked_mul(unit).map(Duration::from_secs)
+}
+
+#[derive(Default)]
+pub struct Bans {
+    list: RwLock<Vec<(Ban, IpMatcher)>>,
+    path: Option<PathBuf>,
+}
+
+impl Bans {
+    /// Bans kept in `path`, starting from what it already holds
+    pub fn load(path: PathBuf) -> Result<Self> {
+        let bans: Vec<Ban> = match std::fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data).with_context(|| format!("Invalid bans file {}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e).with_context(|| format!("Could not read {}", path.display())),
+        };
+        let mut list = Vec::new();
+        for ban in bans {
+            let matcher = IpMatcher::parse(&ban.target)
+                .with_context(|| format!("Invalid ban target '{}' in {}", ban.target, path.display()))?;
+            list.push((ban, matcher));
+        }
+        Ok(Self { list: RwLock::new(list), path: Some(path) })
+    }
+
+    /// Where bans are saved, if anywhere
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// The active ban covering `ip`, if any
+    pub fn find(&self, ip: IpAddr) -> Option<Ban> {
+        let now = Utc::now();
+        let list = self.list.read().unwrap();
+        list.iter().find(|(ban, m)| m.contains(ip) && ban.is_active(now)).map(|(ban, _)| ban.clone())
+    }
+
+    /// Bans still in force
+    pub fn list(&self) -> Vec<Ban> {
+        let now = Utc::now();
+        self.list.read().unwrap().iter().map(|(ban, _)| ban).filter(|b| b.is_active(now)).cloned().collect()
+    }
+
+    /// Add `ban`, replacing one for the same target; returns that one.
+    /// `ban.target` must be normalized.
+    pub fn add(&self, ban: Ban) -> Option<Ban> {
+        let matcher = IpMatcher::parse(&ban.target)?;
+        let mut list = self.list.write().unwrap();
+        let now = Utc::now();
+        list.retain(|(b, _)| b.is_active(now));
+        let previous = list.iter().position(|(b, _)| b.target == ban.target).map(|i| list.remove(i).0);
+        list.push((ban, matcher));
+        self.save(&list);
+        previous
+    }
+
+    pub fn remove(&self, target: &str) -> Option<Ban> {
+        let mut list = self.list.write().unwrap();
+        let removed = list.iter().position(|(b, _)| b.target == target).map(|i| list.remove(i).0);
+        if removed.is_some() {
+            self.save(&list);
+        }
+        removed
+    }
+
+    fn save(&self, list: &[(Ban, IpMatcher)]) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let bans: Vec<&Ban> = list.iter().map(|(ban, _)| ban).collect();
+        // Replace the file whole, so a crash can't leave half of it
+        let tmp = path.with_extension("tmp");
+        let saved = serde_json::to_vec_pretty(&bans)
+            .map_err(std::io::Error::from)
+            .and_then(|data| std::fs::write(&tmp, data))
+            .and_then(|()| std::fs::rename(&tmp, path));
+        if let Err(e) = saved {
+            warn!("Bans not saved to {}: {}", path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ban(target: &str, expires: Option<DateTime<Utc>>) -> Ban {
+        Ban { target: target.into(), reason: "abuse".into(), by: "alice".into(), created: Utc::now(), expires }
+    }
+
+    #[test]
+    fn test_bans() {
+        let path = std::env::temp_dir().join(format!("ztunnel-bans-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let bans = Bans::load(path.clone()).unwrap();
+        assert_eq!(bans.add(ban("203.0.113.0/24", None)), None);
+        assert!(bans.add(ban("198.51.100.7", Some(Utc::now() - chrono::Duration::seconds(1)))).is_none());
+        assert!(bans.add(ban(&normalize("2001:DB8::1").unwrap(), None)).is_none());
+
+        assert!(bans.find("203.0.113.200".parse().unwrap()).is_some());
+        assert!(bans.find("2001:db8::1".parse().unwrap()).is_some());
+        assert!(bans.find("203.0.114.1".parse().unwrap()).is_none());
+        // Expired
+        assert!(bans.find("198.51.100.7".parse().unwrap()).is_none());
+        assert_eq!(bans.list().len(), 2);
+
+        // Survives a restart; removing is by the normalized target
+        let bans = Bans::load(path.clone()).unwrap();
+        assert!(bans.remove(&normalize(" 2001:db8:0::1 ").unwrap()).is_some());
+        assert!(bans.remove("2001:db8::1").is_none());
+        let bans = Bans::load(path.clone()).unwrap();
+        assert_eq!(bans.list().iter().map(|b| b.target.as_str()).collect::<Vec<_>>(), ["203.0.113.0/24"]);
+
+        assert_eq!(normalize("10.1.2.3/8").as_deref(), Some("10.0.0.0/8"));
+        assert_eq!(normalize("10.0.0.0/33"), None);
+        assert_eq!(parse_duration("12h"), Some(Duration::from_secs(12 * 3600)));
+        assert_eq!(parse_duration("7d"), Some(Duration::from_secs(7 * 86400)));
+        assert_eq!(parse_duration("1w"), None);
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -2,16 +2,24 @@
 //!
 //! Automatically queues requests when a client is disconnected
 //! and replays them upon reconnection.
+//!
+//! Once open for `open_timeout` the circuit goes HalfOpen and lets a few
+//! probe requests through (ZTUNNEL_CIRCUIT_HALF_OPEN_PROBES at a time).
+//! It closes after ZTUNNEL_CIRCUIT_SUCCESS_THRESHOLD of them succeed in
+//! a row, and opens again if one fails.
 
+use serde::Serialize;
 use std::collections::VecDeque;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use tokio::sync::Mutex;
 use tokio::time::{Duration, Instant};
 use tracing::{info, warn};
+use ztunnel_shared::{Error, RetryAdvice};
 
 /// Circuit breaker states
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum CircuitState {
     /// Normal operation — requests flow through
     Closed,
@@ -21,6 +29,19 @@ pub enum CircuitState {
     HalfOpen,
 }
 
+impl CircuitState {
+    pub const ALL: [CircuitState; 3] = [CircuitState::Closed, CircuitState::Open, CircuitState::HalfOpen];
+
+    /// Label in metrics
+    pub fn name(self) -> &'static str {
+        match self {
+            CircuitState::Closed => "closed",
+            CircuitState::Open => "open",
+            CircuitState::HalfOpen => "half_open",
+        }
+    }
+}
+
 /// Circuit breaker configuration
 #[derive(Debug, Clone)]
 pub struct CircuitBreakerConfig {
@@ -32,6 +53,10 @@ pub struct CircuitBreakerConfig {
     pub max_request_age: Duration,
     /// Number of consecutive failures before opening circuit
     pub failure_threshold: u32,
+    /// Requests let through at a time while HalfOpen; the rest are queued
+    pub half_open_probes: u32,
+    /// Successful probes in a row needed to close the circuit again
+    pub success_threshold: u32,
 }
 
 impl Default for CircuitBreakerConfig {
@@ -41,6 +66,22 @@ impl Default for CircuitBreakerConfig {
             open_timeout: Duration::from_secs(30),
             max_request_age: Duration::from_secs(60),
             failure_threshold: 3,
+            half_open_probes: 1,
+            success_threshold: 1,
+        }
+    }
+}
+
+impl CircuitBreakerConfig {
+    /// Defaults, with ZTUNNEL_CIRCUIT_HALF_OPEN_PROBES and
+    /// ZTUNNEL_CIRCUIT_SUCCESS_THRESHOLD if set
+    pub fn from_env() -> Self {
+        let count = |name: &str| std::env::var(name).ok().and_then(|v| v.parse::<u32>().ok()).filter(|n| *n > 0);
+        let defaults = Self::default();
+        Self {
+            half_open_probes: count("ZTUNNEL_CIRCUIT_HALF_OPEN_PROBES").unwrap_or(defaults.half_open_probes),
+            success_threshold: count("ZTUNNEL_CIRCUIT_SUCCESS_THRESHOLD").unwrap_or(defaults.success_threshold),
+            ..defaults
         }
     }
 }
@@ -52,89 +93,214 @@ pub struct QueuedRequest {
     pub queued_at: Instant,
 }
 
+/// One value per state
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct PerState<T> {
+    pub closed: T,
+    pub open: T,
+    pub half_open: T,
+}
+
+impl<T: Copy> PerState<T> {
+    pub fn get(&self, state: CircuitState) -> T {
+        match state {
+            CircuitState::Closed => self.closed,
+            CircuitState::Open => self.open,
+            CircuitState::HalfOpen => self.half_open,
+        }
+    }
+
+    fn get_mut(&mut self, state: CircuitState) -> &mut T {
+        match state {
+            CircuitState::Closed => &mut self.closed,
+            CircuitState::Open => &mut self.open,
+            CircuitState::HalfOpen => &mut self.half_open,
+        }
+    }
+}
+
+/// A circuit's state and history, for metrics and the admin API
+#[derive(Debug, Clone, Serialize)]
+pub struct CircuitStats {
+    pub state: CircuitState,
+    /// Seconds since the current state was entered
+    pub state_secs: f64,
+    /// Times each state was entered
+    pub transitions: PerState<u64>,
+    /// Seconds spent in each state, the current one included
+    pub time_in_state: PerState<f64>,
+    pub consecutive_failures: u64,
+    /// HalfOpen probes awaiting their response
+    pub probes_in_flight: u32,
+    /// HalfOpen probes answered in a row
+    pub probe_successes: u32,
+    pub queued: usize,
+}
+
+/// State and what's happened in it
+#[derive(Debug)]
+struct Circuit {
+    state: CircuitState,
+    since: Instant,
+    /// Probes let through in this HalfOpen period and not yet answered
+    probes: u32,
+    successes: u32,
+    /// When the last probe was let through
+    last_probe: Instant,
+    /// Times each state was entered
+    entered: PerState<u64>,
+    /// Time spent in each state before the current period
+    time_in: PerState<Duration>,
+}
+
+impl Circuit {
+    fn new() -> Self {
+        let now = Instant::now();
+        Self {
+            state: CircuitState::Closed,
+            since: now,
+            probes: 0,
+            successes: 0,
+            last_probe: now,
+            entered: PerState::default(),
+            time_in: PerState::default(),
+        }
+    }
+
+    fn enter(&mut self, state: CircuitState) {
+        let now = Instant::now();
+        *self.time_in.get_mut(self.state) += now - self.since;
+        *self.entered.get_mut(state) += 1;
+        self.state = state;
+        self.since = now;
+        self.probes = 0;
+        self.successes = 0;
+    }
+
+    /// Let a probe through
+    fn probe(&mut self) {
+        self.probes += 1;
+        self.last_probe = Instant::now();
+    }
+}
+
 /// Circuit breaker for a single tunnel
+#[derive(Clone)]
 pub struct CircuitBreaker {
-    state: Arc<Mutex<CircuitState>>,
+    circuit: Arc<Mutex<Circuit>>,
     queue: Arc<Mutex<VecDeque<QueuedRequest>>>,
     config: CircuitBreakerConfig,
     consecutive_failures: Arc<AtomicU64>,
-    last_state_change: Arc<Mutex<Instant>>,
 }
 
 impl CircuitBreaker {
     pub fn new(config: CircuitBreakerConfig) -> Self {
         Self {
-            state: Arc::new(Mutex::new(CircuitState::Closed)),
+            circuit: Arc::new(Mutex::new(Circuit::new())),
             queue: Arc::new(Mutex::new(VecDeque::with_capacity(config.max_queue_size))),
             config,
             consecutive_failures: Arc::new(AtomicU64::new(0)),
-            last_state_change: Arc::new(Mutex::new(Instant::now())),
         }
     }
 
     /// Get current circuit state
     pub async fn state(&self) -> CircuitState {
-        *self.state.lock().await
+        self.circuit.lock().await.state
     }
 
-    /// Record a successful request — reset failure count
+    /// Record a successful request — reset failure count, and close the
+    /// circuit once enough probes in a row have succeeded
     pub async fn record_success(&self) {
         self.consecutive_failures.store(0, Ordering::SeqCst);
-        let mut state = self.state.lock().await;
-        if *state == CircuitState::HalfOpen {
-            *state = CircuitState::Closed;
-            *self.last_state_change.lock().await = Instant::now();
-            info!("Circuit breaker: HalfOpen → Closed");
+        let mut circuit = self.circuit.lock().await;
+        if circuit.state == CircuitState::HalfOpen {
+            circuit.probes = circuit.probes.saturating_sub(1);
+            circuit.successes += 1;
+            if circuit.successes >= self.config.success_threshold {
+                info!("Circuit breaker: HalfOpen → Closed (after {} successes)", circuit.successes);
+                circuit.enter(CircuitState::Closed);
+            }
         }
     }
 
-    /// Record a failed request — potentially open the circuit
-    pub async fn record_failure(&self) {
+    /// Record a failed request — potentially open the circuit. True if
+    /// this failure opened it.
+    pub async fn record_failure(&self) -> bool {
         let failures = self.consecutive_failures.fetch_add(1, Ordering::SeqCst) + 1;
-        
-        if failures >= self.config.failure_threshold as u64 {
-            let mut state = self.state.lock().await;
-            if *state == CircuitState::Closed {
-                *state = CircuitState::Open;
-                *self.last_state_change.lock().await = Instant::now();
+        let mut circuit = self.circuit.lock().await;
+        match circuit.state {
+            CircuitState::Closed if failures >= self.config.failure_threshold as u64 => {
+                circuit.enter(CircuitState::Open);
                 warn!("Circuit breaker: Closed → Open (after {} failures)", failures);
+                true
+            }
+            CircuitState::HalfOpen => {
+                circuit.enter(CircuitState::Open);
+                warn!("Circuit breaker: HalfOpen → Open (probe failed)");
+                true
             }
+            _ => false,
+        }
+    }
+
+    /// Record a failed request by its cause. Only transient failures
+    /// count toward opening the circuit; queueing and replaying won't
+    /// change the answer to a rejection.
+    pub async fn record_error(&self, error: &Error) -> bool {
+        error.is_retryable() && self.record_failure().await
+    }
+
+    /// When a caller turned away by an open circuit should come back
+    pub async fn retry_advice(&self) -> RetryAdvice {
+        let circuit = self.circuit.lock().await;
+        if circuit.state != CircuitState::Open {
+            return RetryAdvice::Backoff;
         }
+        RetryAdvice::After(self.config.open_timeout.saturating_sub(circuit.since.elapsed()))
     }
 
     /// Attempt to send a request through the circuit
     /// Returns Ok(data) if the request should be sent
     /// Returns Err(()) if the request was queued
     pub async fn try_send(&self, data: Vec<u8>) -> Result<Vec<u8>, ()> {
-        let mut state = self.state.lock().await;
+        let mut circuit = self.circuit.lock().await;
 
-        match *state {
-            CircuitState::Closed => Ok(data),
-            CircuitState::HalfOpen => Ok(data), // Let it through as a test
+        match circuit.state {
+            CircuitState::Closed => return Ok(data),
+            CircuitState::HalfOpen if circuit.probes < self.config.half_open_probes => {
+                circuit.probe();
+                return Ok(data);
+            }
+            CircuitState::HalfOpen => {
+                // Probes that never got an answer count as failed
+                if circuit.last_probe.elapsed() >= self.config.open_timeout {
+                    circuit.enter(CircuitState::Open);
+                    warn!("Circuit breaker: HalfOpen → Open (probes unanswered)");
+                }
+            }
             CircuitState::Open => {
                 // Check if it's time to try again
-                let last_change = *self.last_state_change.lock().await;
-                if last_change.elapsed() >= self.config.open_timeout {
-                    *state = CircuitState::HalfOpen;
-                    *self.last_state_change.lock().await = Instant::now();
+                if circuit.since.elapsed() >= self.config.open_timeout {
+                    circuit.enter(CircuitState::HalfOpen);
+                    circuit.probe();
                     info!("Circuit breaker: Open → HalfOpen (testing)");
-                    Ok(data)
-                } else {
-                    // Queue the request
-                    let mut queue = self.queue.lock().await;
-                    if queue.len() < self.config.max_queue_size {
-                        queue.push_back(QueuedRequest {
-                            data,
-                            queued_at: Instant::now(),
-                        });
-                        info!("Circuit breaker: Request queued ({}/{})", queue.len(), self.config.max_queue_size);
-                    } else {
-                        warn!("Circuit breaker: Queue full, dropping request");
-                    }
-                    Err(())
+                    return Ok(data);
                 }
             }
         }
+
+        // Queue the request
+        let mut queue = self.queue.lock().await;
+        if queue.len() < self.config.max_queue_size {
+            queue.push_back(QueuedRequest {
+                data,
+                queued_at: Instant::now(),
+            });
+            info!("Circuit breaker: Request queued ({}/{})", queue.len(), self.config.max_queue_size);
+        } else {
+            warn!("Circuit breaker: Queue full, dropping request");
+        }
+        Err(())
     }
 
     /// Drain all valid queued requests (called when client reconnects)
@@ -149,10 +315,11 @@ impl CircuitBreaker {
             .collect();
 
         // Reset state
-        let mut state = self.state.lock().await;
-        *state = CircuitState::Closed;
+        let mut circuit = self.circuit.lock().await;
+        if circuit.state != CircuitState::Closed {
+            circuit.enter(CircuitState::Closed);
+        }
         self.consecutive_failures.store(0, Ordering::SeqCst);
-        *self.last_state_change.lock().await = Instant::now();
 
         info!("Circuit breaker: Drained {} queued requests", valid.len());
         valid
@@ -162,16 +329,88 @@ impl CircuitBreaker {
     pub async fn queue_size(&self) -> usize {
         self.queue.lock().await.len()
     }
+
+    pub async fn stats(&self) -> CircuitStats {
+        let queued = self.queue_size().await;
+        let circuit = self.circuit.lock().await;
+        let current = circuit.since.elapsed();
+        let secs = |state: CircuitState| {
+            let extra = if state == circuit.state { current } else { Duration::ZERO };
+            (circuit.time_in.get(state) + extra).as_secs_f64()
+        };
+        CircuitStats {
+            state: circuit.state,
+            state_secs: current.as_secs_f64(),
+            transitions: circuit.entered,
+            time_in_state: PerState {
+                closed: secs(CircuitState::Closed),
+                open: secs(CircuitState::Open),
+                half_open: secs(CircuitState::HalfOpen),
+            },
+            consecutive_failures: self.consecutive_failures.load(Ordering::SeqCst),
+            probes_in_flight: circuit.probes,
+            probe_successes: circuit.successes,
+            queued,
+        }
+    }
 }
 
-impl Clone for CircuitBreaker {
-    fn clone(&self) -> Self {
-        Self {
-            state: self.state.clone(),
-            queue: self.queue.clone(),
-            config: self.config.clone(),
-            consecutive_failures: self.consecutive_failures.clone(),
-            last_state_change: self.last_state_change.clone(),
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_only_transient_errors_open_the_circuit() {
+        let cb = CircuitBreaker::new(CircuitBreakerConfig::default());
+        for _ in 0..5 {
+            cb.record_error(&Error::AuthFailed).await;
         }
+        assert_eq!(cb.state().await, CircuitState::Closed);
+        assert_eq!(cb.retry_advice().await, RetryAdvice::Backoff);
+
+        for _ in 0..3 {
+            cb.record_error(&Error::Timeout).await;
+        }
+        assert_eq!(cb.state().await, CircuitState::Open);
+        assert!(matches!(cb.retry_advice().await, RetryAdvice::After(d) if d > Duration::from_secs(25)));
+    }
+
+    #[tokio::test]
+    async fn test_half_open_probes() {
+        let config = CircuitBreakerConfig {
+            open_timeout: Duration::from_millis(20),
+            failure_threshold: 1,
+            half_open_probes: 2,
+            success_threshold: 2,
+            ..Default::default()
+        };
+        let cb = CircuitBreaker::new(config);
+        assert!(cb.record_failure().await);
+        assert!(cb.try_send(vec![1]).await.is_err());
+
+        // Two probes at a time; the third waits in the queue
+        tokio::time::sleep(Duration::from_millis(25)).await;
+        assert!(cb.try_send(vec![2]).await.is_ok());
+        assert!(cb.try_send(vec![3]).await.is_ok());
+        assert!(cb.try_send(vec![4]).await.is_err());
+        assert_eq!(cb.state().await, CircuitState::HalfOpen);
+
+        // A failed probe reopens it
+        assert!(cb.record_failure().await);
+        assert_eq!(cb.state().await, CircuitState::Open);
+
+        // It closes only after two successes in a row
+        tokio::time::sleep(Duration::from_millis(25)).await;
+        assert!(cb.try_send(vec![5]).await.is_ok());
+        cb.record_success().await;
+        assert_eq!(cb.state().await, CircuitState::HalfOpen);
+        assert!(cb.try_send(vec![6]).await.is_ok());
+        cb.record_success().await;
+        assert_eq!(cb.state().await, CircuitState::Closed);
+
+        let stats = cb.stats().await;
+        assert_eq!(stats.transitions, PerState { closed: 1, open: 2, half_open: 2 });
+        assert_eq!(stats.queued, 2);
+        assert!(stats.time_in_state.open >= 0.04, "{:?}", stats);
     }
 }
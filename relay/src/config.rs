@@ -0,0 +1,162 @@
+//! Operator configuration from relay.yml
+//!
+//! ZTUNNEL_CONFIG names a YAML file of policy rules the relay applies to
+//! every tunnel, and to tunnels by subdomain, in the same form clients
+//! send theirs:
+//!
+//! ```yaml
+//! policies:
+//!   - path: /.env
+//!     action: block
+//!     status: 404
+//! subdomains:
+//!   api:
+//!     policies:
+//!       - path: /admin/**
+//!         action: block
+//!         status: 403
+//!         except_from: [10.0.0.0/8]
+//! ```
+//!
+//! A tunnel's own rules come first; the relay's apply to requests none
+//! of them match, its subdomain's before the global ones. The file is
+//! watched, and edits apply to open tunnels at once. An invalid edit is
+//! logged and the rules in force are kept.
+//!
+//! `from` and `except_from` match the address a request arrived from.
+//! Behind a load balancer, list it in ZTUNNEL_TRUSTED_PROXIES so the
+//! visitor's address from X-Forwarded-For is matched instead of its own.
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use tracing::{error, info};
+use ztunnel_shared::protocol::Policy;
+use ztunnel_shared::watch::FileWatch;
+
+use crate::policy::PolicyEngine;
+use crate::AppState;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RelayConfig {
+    #[serde(default)]
+    policies: Vec<Policy>,
+    #[serde(default)]
+    subdomains: HashMap<String, SubdomainConfig>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SubdomainConfig {
+    #[serde(default)]
+    policies: Vec<Policy>,
+}
+
+/// The operator's rules, compiled
+#[derive(Debug, Clone, Default)]
+pub struct OperatorPolicies {
+    global: PolicyEngine,
+    by_subdomain: HashMap<String, PolicyEngine>,
+}
+
+impl OperatorPolicies {
+    /// Read and check relay.yml
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path).with_context(|| format!("Reading {}", path.display()))?;
+        let config: RelayConfig = serde_yaml::from_str(&text).with_context(|| format!("Parsing {}", path.display()))?;
+        let sections = std::iter::once(&config.policies).chain(config.subdomains.values().map(|s| &s.policies));
+        for policy in sections.flatten() {
+            policy.validate()?;
+        }
+        Ok(Self {
+            global: PolicyEngine::from_policies(&config.policies),
+            by_subdomain: config
+                .subdomains
+                .iter()
+                .map(|(name, sub)| (name.clone(), PolicyEngine::from_policies(&sub.policies)))
+                .collect(),
+        })
+    }
+
+    /// Rule count, for the log
+    pub fn len(&self) -> usize {
+        self.global.rules.len() + self.by_subdomain.values().map(|e| e.rules.len()).sum::<usize>()
+    }
+
+    /// What a tunnel enforces: its client's rules, then these
+    pub fn layered(&self, subdomain: &str, client: &PolicyEngine) -> PolicyEngine {
+        let mut engine = client.clone();
+        if let Some(own) = self.by_subdomain.get(subdomain) {
+            engine.rules.extend(own.rules.iter().cloned());
+        }
+        engine.rules.extend(self.global.rules.iter().cloned());
+        engine
+    }
+}
+
+/// Reload `path` whenever it changes, re-layering open tunnels' rules
+pub async fn watch(path: PathBuf, state: AppState) -> Result<()> {
+    let mut changes = FileWatch::new(&path)?;
+    while changes.changed().await.is_some() {
+        match OperatorPolicies::load(&path) {
+            Ok(policies) => {
+                info!("Reloaded {}: {} policy rule(s)", path.display(), policies.len());
+                state.set_operator_policies(policies);
+            }
+            Err(e) => error!("Reloading {} failed, keeping current rules: {:#}", path.display(), e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy::PolicyAction;
+
+    const CONFIG: &str = r#"
+policies:
+  - path: /.env
+    action: block
+    status: 404
+  - path: /**
+    action: rate_limit
+    per_minute: 600
+subdomains:
+  api:
+    policies:
+      - path: /admin/**
+        action: block
+        status: 403
+"#;
+
+    #[test]
+    fn test_operator_policies_layered() {
+        let path = std::env::temp_dir().join(format!("ztunnel-relay-{}.yml", std::process::id()));
+        std::fs::write(&path, CONFIG).unwrap();
+        let operator = OperatorPolicies::load(&path).unwrap();
+        assert_eq!(operator.len(), 3);
+
+        // The client's rules win; the subdomain's come before the global ones
+        let visitor = "203.0.113.9".parse().unwrap();
+        let client = PolicyEngine::from_policies(&[serde_yaml::from_str("{path: /admin/**, action: allow}").unwrap()]);
+        let action = |engine: &PolicyEngine, path| engine.matching(path, "GET", visitor).map(|r| r.action.clone());
+        let api = operator.layered("api", &client);
+        assert!(matches!(action(&api, "/admin/users"), Some(PolicyAction::Allow)));
+        assert!(matches!(action(&api, "/.env"), Some(PolicyAction::Block(404))));
+        assert!(matches!(action(&api, "/"), Some(PolicyAction::RateLimit(600))));
+        let api = operator.layered("api", &PolicyEngine::default());
+        assert!(matches!(action(&api, "/admin/users"), Some(PolicyAction::Block(403))));
+        let web = operator.layered("web", &PolicyEngine::default());
+        assert!(matches!(action(&web, "/admin/users"), Some(PolicyAction::RateLimit(600))));
+
+        // Invalid rules and unknown keys are refused
+        std::fs::write(&path, "policies:\n  - path: admin\n    action: allow\n").unwrap();
+        assert!(OperatorPolicies::load(&path).is_err());
+        std::fs::write(&path, "policy: []\n").unwrap();
+        assert!(OperatorPolicies::load(&path).is_err());
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,130 @@
+//! Consumer connections to TCP tunnels
+//!
+//! `GET /connect/NAME` upgrades to a WebSocket carrying one stream to
+//! TCP tunnel NAME, for `ztunnel forward` and `ztunnel socks`. The
+//! client gets an `Open` frame with the consumer's address (and the
+//! target and token headers, for gateway tunnels); after that binary
+//! messages become `Data` frames and the client's frames become binary
+//! messages. Either side closing closes the other.
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        ConnectInfo, Path, State,
+    },
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use futures_util::{SinkExt, StreamExt};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+use ztunnel_shared::protocol::connect::{TARGET_HEADER, TOKEN_HEADER};
+use ztunnel_shared::telemetry::metric;
+use ztunnel_shared::{Error, Telemetry};
+
+use crate::tunnel::{StreamEvent, StreamFrame, Tunnel};
+use crate::{gen_request_id, send_frame, too_many_requests, AppState};
+
+/// Upgrade to a stream through `subdomain`, after the same visitor
+/// checks as a proxied request
+pub async fn handler(
+    ws: WebSocketUpgrade,
+    Path(subdomain): Path<String>,
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Response {
+    let pairs: Vec<(String, String)> = headers
+        .iter()
+        .filter_map(|(k, v)| v.to_str().ok().map(|v| (k.as_str().to_string(), v.to_string())))
+        .collect();
+    let visitor = state.trusted_proxies.client_ip(&pairs, peer.ip());
+    if state.is_banned(visitor, peer.ip()) {
+        return (StatusCode::FORBIDDEN, "Access denied").into_response();
+    }
+    if let Some(limits) = &state.ip_limits {
+        if let Err(Error::RateLimited(secs)) = limits.check(&visitor) {
+            return too_many_requests(secs);
+        }
+    }
+    if let Some(page) = state.maintenance.page() {
+        return page;
+    }
+
+    let tunnel = match state.tunnels.get(&subdomain) {
+        Some(t) if t.tcp => t.clone(),
+        Some(_) => return (StatusCode::BAD_REQUEST, "Not a TCP tunnel").into_response(),
+        None => return (StatusCode::NOT_FOUND, "Tunnel not found").into_response(),
+    };
+    if !tunnel.ip_filter.is_empty() && !tunnel.ip_filter.is_allowed(visitor) {
+        warn!("IP {} blocked for tunnel {}", visitor, subdomain);
+        return (StatusCode::FORBIDDEN, "Access denied").into_response();
+    }
+
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok()).map(String::from);
+    let mut open = StreamFrame::new(gen_request_id(), StreamEvent::Open);
+    open.remote_addr = Some(SocketAddr::new(visitor, peer.port()));
+    open.target = header(TARGET_HEADER);
+    open.token = header(TOKEN_HEADER);
+    ws.on_upgrade(move |socket| relay_stream(socket, tunnel, open, state.telemetry))
+}
+
+/// Pass bytes between the consumer's socket and the tunnel client
+/// until either closes
+async fn relay_stream(socket: WebSocket, tunnel: Tunnel, open: StreamFrame, telemetry: Arc<dyn Telemetry>) {
+    let id = open.stream.clone();
+    // The client's frames for this stream arrive the way streamed
+    // response bodies do
+    let (tx, mut from_client) = mpsc::unbounded_channel();
+    tunnel.response_bodies.insert(id.clone(), tx);
+
+    // Every frame of the stream goes to the same client
+    let client = tunnel.client().await;
+    let opened = match serde_json::to_vec(&open) {
+        Ok(data) => client.send(data).await.is_ok(),
+        Err(_) => false,
+    };
+    if !opened {
+        tunnel.response_bodies.remove(&id);
+        return;
+    }
+
+    let (mut sender, mut receiver) = socket.split();
+    let (mut bytes_in, mut bytes_out) = (0, 0);
+    let client_closed = loop {
+        tokio::select! {
+            msg = receiver.next() => match msg {
+                Some(Ok(Message::Binary(data))) => {
+                    bytes_in += data.len();
+                    if send_frame(&client, &id, StreamEvent::Data(data.into())).await.is_err() {
+                        break true;
+                    }
+                }
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break false,
+                _ => {}
+            },
+            data = from_client.recv() => match data {
+                Some(Ok(data)) => {
+                    bytes_out += data.len();
+                    if sender.send(Message::Binary(data.into())).await.is_err() {
+                        break false;
+                    }
+                }
+                // The client closed its end, or the tunnel went away
+                _ => break true,
+            },
+        }
+    };
+
+    tunnel.response_bodies.remove(&id);
+    if !client_closed {
+        let _ = send_frame(&client, &id, StreamEvent::Close).await;
+    }
+    let _ = sender.send(Message::Close(None)).await;
+    for (direction, bytes) in [("in", bytes_in), ("out", bytes_out)] {
+        telemetry.counter(metric::BYTES, &[("tunnel", &tunnel.subdomain), ("direction", direction)], bytes as u64);
+    }
+    debug!("Stream {} to {} closed ({} bytes in, {} out)", id, tunnel.subdomain, bytes_in, bytes_out);
+}
@@ -2,6 +2,10 @@
 //!
 //! Lightweight middleware to inject standard proxy headers
 //! and apply custom add/remove/replace rules.
+//!
+//! Hop-by-hop headers (RFC 9110 §7.6.1) are dropped in both directions
+//! by default: they describe one connection, and passed through the
+//! tunnel they could frame a message differently on the far side.
 
 use std::collections::HashMap;
 
@@ -16,6 +20,10 @@ pub enum HeaderRule {
     Remove(String),
 }
 
+/// Headers that only mean something on a single connection
+const HOP_BY_HOP: &[&str] =
+    &["connection", "keep-alive", "proxy-authenticate", "te", "trailer", "transfer-encoding", "upgrade"];
+
 /// Header rewriter configuration
 #[derive(Debug, Clone)]
 pub struct HeaderRewriter {
@@ -23,6 +31,8 @@ pub struct HeaderRewriter {
     pub inject_proxy_headers: bool,
     /// Auto-inject CORS headers for dev
     pub inject_cors: bool,
+    /// Drop hop-by-hop headers before anything else
+    pub strip_hop_by_hop: bool,
     /// Custom rules applied in order
     pub rules: Vec<HeaderRule>,
 }
@@ -32,26 +42,35 @@ impl Default for HeaderRewriter {
         Self {
             inject_proxy_headers: true,
             inject_cors: false,
+            strip_hop_by_hop: true,
             rules: Vec::new(),
         }
     }
 }
 
 impl HeaderRewriter {
-    /// Rewrite request headers before forwarding to local service
+    /// Rewrite request headers before forwarding to local service.
+    /// The relay's peer is appended to any X-Forwarded-For and RFC 7239
+    /// `Forwarded` chain already present, so proxies in front of the
+    /// relay stay visible; X-Real-IP is the visitor behind them.
     pub fn rewrite_request(
         &self,
         headers: &mut Vec<(String, String)>,
         client_ip: Option<&str>,
+        visitor: Option<&str>,
         host: &str,
     ) {
+        if self.strip_hop_by_hop {
+            strip_hop_by_hop(headers);
+        }
         if self.inject_proxy_headers {
             if let Some(ip) = client_ip {
-                upsert(headers, "X-Forwarded-For", ip);
+                append(headers, "X-Forwarded-For", ip);
             }
+            append(headers, "Forwarded", &forwarded_element(client_ip, host, "https"));
             upsert(headers, "X-Forwarded-Proto", "https");
             upsert(headers, "X-Forwarded-Host", host);
-            upsert(headers, "X-Real-IP", client_ip.unwrap_or("unknown"));
+            upsert(headers, "X-Real-IP", visitor.or(client_ip).unwrap_or("unknown"));
         }
 
         self.apply_rules(headers);
@@ -59,6 +78,9 @@ impl HeaderRewriter {
 
     /// Rewrite response headers before sending back to client
     pub fn rewrite_response(&self, headers: &mut Vec<(String, String)>) {
+        if self.strip_hop_by_hop {
+            strip_hop_by_hop(headers);
+        }
         if self.inject_cors {
             upsert(headers, "Access-Control-Allow-Origin", "*");
             upsert(headers, "Access-Control-Allow-Methods", "GET, POST, PUT, DELETE, PATCH, OPTIONS");
@@ -88,6 +110,44 @@ impl HeaderRewriter {
     }
 }
 
+/// One `Forwarded` element: `for=...;host=...;proto=...`. IPv6
+/// addresses and hosts with a port are quoted as RFC 7239 requires.
+fn forwarded_element(client_ip: Option<&str>, host: &str, proto: &str) -> String {
+    let node = match client_ip {
+        Some(ip) if ip.contains(':') => format!("\"[{}]\"", ip),
+        Some(ip) => ip.to_string(),
+        None => "unknown".to_string(),
+    };
+    let mut element = format!("for={}", node);
+    if !host.is_empty() {
+        let token = host.chars().all(|c| c.is_ascii_alphanumeric() || "-._~".contains(c));
+        if token {
+            element.push_str(&format!(";host={}", host));
+        } else {
+            element.push_str(&format!(";host=\"{}\"", host.replace(['"', '\\'], "")));
+        }
+    }
+    element.push_str(&format!(";proto={}", proto));
+    element
+}
+
+/// Add to a list-valued header, joining repeats of it into one
+fn append(headers: &mut Vec<(String, String)>, key: &str, value: &str) {
+    let existing: Vec<String> = headers
+        .iter()
+        .filter(|(k, _)| k.eq_ignore_ascii_case(key))
+        .map(|(_, v)| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+        .collect();
+    if existing.is_empty() {
+        headers.push((key.to_string(), value.to_string()));
+        return;
+    }
+    let joined = format!("{}, {}", existing.join(", "), value);
+    headers.retain(|(k, _)| !k.eq_ignore_ascii_case(key));
+    headers.push((key.to_string(), joined));
+}
+
 /// Insert or update a header
 fn upsert(headers: &mut Vec<(String, String)>, key: &str, value: &str) {
     if let Some(h) = headers.iter_mut().find(|(k, _)| k.eq_ignore_ascii_case(key)) {
@@ -97,6 +157,27 @@ fn upsert(headers: &mut Vec<(String, String)>, key: &str, value: &str) {
     }
 }
 
+/// Remove hop-by-hop headers and any others `Connection` names. An
+/// upgrade keeps `Upgrade` and a bare `Connection: upgrade`, which the
+/// other end needs to complete it.
+pub fn strip_hop_by_hop(headers: &mut Vec<(String, String)>) {
+    let named: Vec<String> = headers
+        .iter()
+        .filter(|(k, _)| k.eq_ignore_ascii_case("connection"))
+        .flat_map(|(_, v)| v.split(',').map(|t| t.trim().to_ascii_lowercase()))
+        .filter(|t| !t.is_empty())
+        .collect();
+    let upgrading = named.iter().any(|t| t == "upgrade") && headers.iter().any(|(k, _)| k.eq_ignore_ascii_case("upgrade"));
+
+    headers.retain(|(k, _)| {
+        let k = k.to_ascii_lowercase();
+        (upgrading && k == "upgrade") || !(HOP_BY_HOP.contains(&k.as_str()) || named.contains(&k))
+    });
+    if upgrading {
+        headers.push(("Connection".into(), "upgrade".into()));
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -105,9 +186,41 @@ mod tests {
     fn test_proxy_headers() {
         let rw = HeaderRewriter::default();
         let mut h = vec![("Host".into(), "example.com".into())];
-        rw.rewrite_request(&mut h, Some("1.2.3.4"), "myapp.example.com");
+        rw.rewrite_request(&mut h, Some("1.2.3.4"), None, "myapp.example.com");
         assert!(h.iter().any(|(k, v)| k == "X-Forwarded-For" && v == "1.2.3.4"));
         assert!(h.iter().any(|(k, v)| k == "X-Forwarded-Proto" && v == "https"));
+        assert!(h.iter().any(|(k, v)| k == "Forwarded" && v == "for=1.2.3.4;host=myapp.example.com;proto=https"));
+    }
+
+    #[test]
+    fn test_forwarding_chains() {
+        let rw = HeaderRewriter::default();
+        let mut h = vec![
+            ("x-forwarded-for".into(), "203.0.113.7".into()),
+            ("X-Forwarded-For".into(), "10.0.0.1".into()),
+            ("Forwarded".into(), "for=203.0.113.7".into()),
+        ];
+        rw.rewrite_request(&mut h, Some("2001:db8::1"), None, "myapp.example.com:8443");
+        let get = |name: &str| h.iter().filter(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str()).collect::<Vec<_>>();
+        assert_eq!(get("X-Forwarded-For"), ["203.0.113.7, 10.0.0.1, 2001:db8::1"]);
+        assert_eq!(
+            get("Forwarded"),
+            ["for=203.0.113.7, for=\"[2001:db8::1]\";host=\"myapp.example.com:8443\";proto=https"]
+        );
+        assert_eq!(get("X-Real-IP"), ["2001:db8::1"]);
+    }
+
+    #[test]
+    fn test_real_ip_behind_trusted_proxy() {
+        let proxies = crate::ip_filter::TrustedProxies::parse("10.0.0.0/8").unwrap();
+        let peer: std::net::IpAddr = "10.0.0.5".parse().unwrap();
+        let mut h = vec![("X-Forwarded-For".to_string(), "198.51.100.4".to_string())];
+        let visitor = proxies.client_ip(&h, peer).to_string();
+        HeaderRewriter::default().rewrite_request(&mut h, Some("10.0.0.5"), Some(&visitor), "myapp.example.com");
+        let get = |name: &str| h.iter().filter(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str()).collect::<Vec<_>>();
+        assert_eq!(get("X-Real-IP"), ["198.51.100.4"]);
+        assert_eq!(get("X-Forwarded-For"), ["198.51.100.4, 10.0.0.5"]);
+        assert_eq!(get("Forwarded"), ["for=10.0.0.5;host=myapp.example.com;proto=https"]);
     }
 
     #[test]
@@ -123,14 +236,51 @@ mod tests {
         let rw = HeaderRewriter {
             inject_proxy_headers: false,
             inject_cors: false,
+            strip_hop_by_hop: false,
             rules: vec![
                 HeaderRule::Set("X-Custom".into(), "hello".into()),
                 HeaderRule::Remove("Cookie".into()),
             ],
         };
         let mut h = vec![("Cookie".into(), "secret".into())];
-        rw.rewrite_request(&mut h, None, "");
+        rw.rewrite_request(&mut h, None, None, "");
         assert!(!h.iter().any(|(k, _)| k == "Cookie"));
         assert!(h.iter().any(|(k, v)| k == "X-Custom" && v == "hello"));
     }
+
+    #[test]
+    fn test_hop_by_hop_stripped() {
+        let rw = HeaderRewriter::default();
+        let mut h = vec![
+            ("Connection".into(), "keep-alive, X-Forwarded-For, X-Secret".into()),
+            ("Keep-Alive".into(), "timeout=5".into()),
+            ("Transfer-Encoding".into(), "chunked".into()),
+            ("TE".into(), "trailers".into()),
+            ("Upgrade".into(), "h2c".into()),
+            ("X-Secret".into(), "1".into()),
+            ("Content-Type".into(), "text/plain".into()),
+        ];
+        rw.rewrite_request(&mut h, Some("1.2.3.4"), None, "myapp.example.com");
+        let names: Vec<&str> = h.iter().map(|(k, _)| k.as_str()).collect();
+        assert!(names.contains(&"Content-Type"));
+        // Naming our own header in Connection doesn't remove it
+        assert!(h.iter().any(|(k, v)| k == "X-Forwarded-For" && v == "1.2.3.4"));
+        for gone in ["Connection", "Keep-Alive", "Transfer-Encoding", "TE", "Upgrade", "X-Secret"] {
+            assert!(!names.contains(&gone), "{gone} kept");
+        }
+
+        // A WebSocket handshake keeps what it needs, either way round
+        let mut h = vec![
+            ("connection".into(), "Upgrade".into()),
+            ("upgrade".into(), "websocket".into()),
+            ("trailer".into(), "Expires".into()),
+        ];
+        rw.rewrite_response(&mut h);
+        assert_eq!(h, [("upgrade".to_string(), "websocket".to_string()), ("Connection".into(), "upgrade".into())]);
+
+        let rw = HeaderRewriter { strip_hop_by_hop: false, ..Default::default() };
+        let mut h = vec![("Transfer-Encoding".into(), "chunked".into())];
+        rw.rewrite_response(&mut h);
+        assert_eq!(h.len(), 1);
+    }
 }
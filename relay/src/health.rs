@@ -0,0 +1,105 @@
+//! Liveness and readiness probes
+//!
+//! `/livez` answers whenever the process does. `/readyz` is for load
+//! balancers and orchestrators deciding whether to send traffic: 503
+//! while any check fails, with a JSON entry per check. It also fails
+//! once the relay starts draining on SIGTERM, so traffic moves away
+//! during the ZTUNNEL_DRAIN_SECS before the relay exits. Both answer on
+//! the relay's own host only, since apps often serve the same paths.
+
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use serde_json::{json, Value};
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tracing::info;
+
+use crate::AppState;
+
+pub async fn livez() -> impl IntoResponse {
+    Json(json!({ "status": "ok" }))
+}
+
+pub async fn readyz(State(state): State<AppState>) -> impl IntoResponse {
+    let draining = state.draining.load(Ordering::Relaxed);
+    let mut checks = vec![
+        check("listener", Ok(())),
+        check("draining", if draining { Err("shutting down".into()) } else { Ok(()) }),
+    ];
+    let stores = [("usage", state.usage.path()), ("audit", state.audit.path()), ("bans", state.bans.path())];
+    for (name, path) in stores {
+        if let Some(path) = path {
+            checks.push(check(&format!("persistence.{}", name), writable(path)));
+        }
+    }
+    let ready = checks.iter().all(|c| c["ok"] == true);
+    let status = if ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    let body = json!({
+        "status": if ready { "ready" } else { "not ready" },
+        "active_tunnels": state.tunnels.len(),
+        "checks": checks,
+    });
+    (status, Json(body))
+}
+
+fn check(name: &str, result: Result<(), String>) -> Value {
+    match result {
+        Ok(()) => json!({ "name": name, "ok": true }),
+        Err(error) => json!({ "name": name, "ok": false, "error": error }),
+    }
+}
+
+/// Whether `path` can be written: the file opens for appending, or
+/// before it exists, its directory is there and writable
+fn writable(path: &Path) -> Result<(), String> {
+    if path.exists() {
+        return std::fs::OpenOptions::new().append(true).open(path).map(drop).map_err(|e| e.to_string());
+    }
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    match std::fs::metadata(dir) {
+        Ok(meta) if !meta.is_dir() => Err(format!("{} is not a directory", dir.display())),
+        Ok(meta) if meta.permissions().readonly() => Err(format!("{} is read-only", dir.display())),
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("{}: {}", dir.display(), e)),
+    }
+}
+
+/// Resolves when the relay should exit: at once on Ctrl-C, and after
+/// the drain period on SIGTERM, with `/readyz` failing meanwhile
+pub async fn shutdown_signal(state: AppState, drain: Duration) {
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(_) => std::future::pending().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => return,
+        _ = terminate => {}
+    }
+    state.draining.store(true, Ordering::Relaxed);
+    info!("Draining for {:?} before exiting", drain);
+    tokio::time::sleep(drain).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_writable() {
+        let dir = std::env::temp_dir();
+        assert!(writable(&dir.join("ztunnel-not-yet.json")).is_ok());
+        assert!(writable(&dir.join("no-such-dir").join("usage.json")).is_err());
+        assert!(writable(Path::new("usage.json")).is_ok());
+    }
+}
@@ -0,0 +1,302 @@
+//! Kubernetes ingress controller mode
+//!
+//! With the `kubernetes` feature and ZTUNNEL_INGRESS_CLASS set, the
+//! relay acts as the controller for that ingress class. It polls the
+//! cluster's Ingress resources and sends each rule's host to a tunnel:
+//! the one named by the `ztunnel.io/tunnel` annotation, or else the
+//! one whose subdomain is the rule's backend service name, as
+//! registered by a ztunnel client running next to the service. Paths
+//! aren't split; a host goes to one tunnel.
+//!
+//! Each Ingress's status lists the relay's address once every tunnel
+//! behind it is connected, and nothing while any is missing, so
+//! `kubectl get ingress` shows which ones are actually served.
+//!
+//! In a pod the service account's token and CA are used; elsewhere
+//! ZTUNNEL_KUBE_API points at an API server such as `kubectl proxy`.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use dashmap::DashMap;
+use serde::Deserialize;
+use serde_json::json;
+use tracing::{info, warn};
+
+use crate::tunnel::Tunnel;
+use ztunnel_shared::validate;
+
+/// Annotation naming the tunnel for every host of an Ingress
+pub const TUNNEL_ANNOTATION: &str = "ztunnel.io/tunnel";
+
+/// Class annotation used before `spec.ingressClassName`
+const LEGACY_CLASS_ANNOTATION: &str = "kubernetes.io/ingress.class";
+
+/// How often Ingresses are listed
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+const SERVICE_ACCOUNT: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+
+const INGRESSES: &str = "/apis/networking.k8s.io/v1/ingresses";
+
+#[derive(Debug, Deserialize)]
+struct List<T> {
+    items: Vec<T>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Ingress {
+    metadata: Metadata,
+    #[serde(default)]
+    spec: IngressSpec,
+    #[serde(default)]
+    status: serde_json::Value,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Metadata {
+    name: String,
+    #[serde(default)]
+    namespace: String,
+    #[serde(default)]
+    annotations: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct IngressSpec {
+    ingress_class_name: Option<String>,
+    #[serde(default)]
+    rules: Vec<IngressRule>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct IngressRule {
+    host: Option<String>,
+    http: Option<HttpRule>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct HttpRule {
+    #[serde(default)]
+    paths: Vec<HttpPath>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct HttpPath {
+    backend: Backend,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Backend {
+    service: Option<ServiceBackend>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct ServiceBackend {
+    name: String,
+}
+
+impl Ingress {
+    fn class(&self) -> Option<&str> {
+        self.spec
+            .ingress_class_name
+            .as_deref()
+            .or_else(|| self.metadata.annotations.get(LEGACY_CLASS_ANNOTATION).map(String::as_str))
+    }
+
+    /// Host → tunnel subdomain for each rule that names both
+    fn routes(&self) -> Vec<(String, String)> {
+        let annotated = self.metadata.annotations.get(TUNNEL_ANNOTATION);
+        self.spec
+            .rules
+            .iter()
+            .filter_map(|rule| {
+                let host = rule.host.as_deref()?.to_ascii_lowercase();
+                let service = rule.http.as_ref()?.paths.iter().find_map(|p| p.backend.service.as_ref());
+                let tunnel = annotated.cloned().or_else(|| service.map(|s| s.name.clone()))?;
+                Some((host, tunnel))
+            })
+            .collect()
+    }
+
+    /// The `status.loadBalancer.ingress` this Ingress should have
+    fn wanted_status(&self, address: &str, connected: impl Fn(&str) -> bool) -> serde_json::Value {
+        let routes = self.routes();
+        if routes.is_empty() || !routes.iter().all(|(_, tunnel)| connected(tunnel)) {
+            return json!([]);
+        }
+        match address.parse::<std::net::IpAddr>() {
+            Ok(_) => json!([{ "ip": address }]),
+            Err(_) => json!([{ "hostname": address }]),
+        }
+    }
+}
+
+/// Hosts of the Ingresses of `class`; the first Ingress to claim a
+/// host keeps it
+pub fn hosts(ingresses: &[Ingress], class: &str) -> HashMap<String, String> {
+    let mut hosts = HashMap::new();
+    for ingress in ingresses.iter().filter(|i| i.class() == Some(class)) {
+        for (host, tunnel) in ingress.routes() {
+            if validate::check_subdomain(&tunnel).is_err() {
+                warn!(
+                    "Ingress {}/{}: '{}' is not a tunnel subdomain, skipping {}",
+                    ingress.metadata.namespace, ingress.metadata.name, tunnel, host
+                );
+                continue;
+            }
+            hosts.entry(host).or_insert(tunnel);
+        }
+    }
+    hosts
+}
+
+/// Minimal Kubernetes API client
+struct KubeClient {
+    http: reqwest::Client,
+    base: String,
+    token: Option<String>,
+}
+
+impl KubeClient {
+    fn from_env() -> Result<Self> {
+        if let Ok(base) = std::env::var("ZTUNNEL_KUBE_API") {
+            let base = base.trim_end_matches('/').to_string();
+            return Ok(Self { http: reqwest::Client::new(), base, token: None });
+        }
+        let host = std::env::var("KUBERNETES_SERVICE_HOST")
+            .context("Not in a cluster: set ZTUNNEL_KUBE_API or run in a pod")?;
+        let port = std::env::var("KUBERNETES_SERVICE_PORT").unwrap_or_else(|_| "443".into());
+        let dir = Path::new(SERVICE_ACCOUNT);
+        let token = std::fs::read_to_string(dir.join("token")).context("Reading service account token")?;
+        let ca = std::fs::read(dir.join("ca.crt")).context("Reading cluster CA")?;
+        let http = reqwest::Client::builder().add_root_certificate(reqwest::Certificate::from_pem(&ca)?).build()?;
+        let host = if host.contains(':') { format!("[{}]", host) } else { host };
+        Ok(Self { http, base: format!("https://{}:{}", host, port), token: Some(token.trim().to_string()) })
+    }
+
+    fn request(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        let request = self.http.request(method, format!("{}{}", self.base, path));
+        match &self.token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+
+    async fn ingresses(&self) -> Result<Vec<Ingress>> {
+        let list: List<Ingress> = self.request(reqwest::Method::GET, INGRESSES).send().await?.error_for_status()?.json().await?;
+        Ok(list.items)
+    }
+
+    async fn set_status(&self, ingress: &Ingress, load_balancer: serde_json::Value) -> Result<()> {
+        let path = format!(
+            "/apis/networking.k8s.io/v1/namespaces/{}/ingresses/{}/status",
+            ingress.metadata.namespace, ingress.metadata.name
+        );
+        let patch = json!({ "status": { "loadBalancer": { "ingress": load_balancer } } });
+        self.request(reqwest::Method::PATCH, &path)
+            .header(reqwest::header::CONTENT_TYPE, "application/merge-patch+json")
+            .body(patch.to_string())
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+/// Keeps the relay's ingress hosts in step with the cluster
+pub struct Controller {
+    client: KubeClient,
+    class: String,
+    /// Reported in Ingress status: ZTUNNEL_INGRESS_ADDRESS, or the
+    /// relay's domain
+    address: String,
+}
+
+impl Controller {
+    pub fn from_env(class: String, domain: &str) -> Result<Self> {
+        let address = std::env::var("ZTUNNEL_INGRESS_ADDRESS").unwrap_or_else(|_| domain.to_string());
+        Ok(Self { client: KubeClient::from_env()?, class, address })
+    }
+
+    pub async fn run(self, routes: Arc<DashMap<String, String>>, tunnels: Arc<DashMap<String, Tunnel>>) {
+        info!("Ingress controller for class '{}'", self.class);
+        let mut timer = tokio::time::interval(POLL_INTERVAL);
+        loop {
+            timer.tick().await;
+            if let Err(e) = self.sync(&routes, &tunnels).await {
+                warn!("Ingress sync failed: {:#}", e);
+            }
+        }
+    }
+
+    async fn sync(&self, routes: &DashMap<String, String>, tunnels: &DashMap<String, Tunnel>) -> Result<()> {
+        let ingresses = self.client.ingresses().await?;
+        let wanted = hosts(&ingresses, &self.class);
+        routes.retain(|host, _| wanted.contains_key(host));
+        for (host, tunnel) in wanted {
+            if routes.insert(host.clone(), tunnel.clone()).as_ref() != Some(&tunnel) {
+                info!("Ingress host {} → tunnel '{}'", host, tunnel);
+            }
+        }
+
+        for ingress in ingresses.iter().filter(|i| i.class() == Some(self.class.as_str())) {
+            let status = ingress.wanted_status(&self.address, |tunnel| tunnels.contains_key(tunnel));
+            let current = ingress.status.pointer("/loadBalancer/ingress").cloned().unwrap_or(json!([]));
+            if current != status {
+                if let Err(e) = self.client.set_status(ingress, status).await {
+                    warn!("Updating status of {}/{}: {:#}", ingress.metadata.namespace, ingress.metadata.name, e);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ingress(json: serde_json::Value) -> Ingress {
+        serde_json::from_value(json).unwrap()
+    }
+
+    #[test]
+    fn test_hosts_for_class() {
+        let ours = ingress(json!({
+            "metadata": { "name": "shop", "namespace": "default" },
+            "spec": {
+                "ingressClassName": "ztunnel",
+                "rules": [
+                    { "host": "Shop.example.com", "http": { "paths": [{ "backend": { "service": { "name": "shop" } } }] } },
+                    { "host": "no-backend.example.com" }
+                ]
+            }
+        }));
+        let annotated = ingress(json!({
+            "metadata": {
+                "name": "blog",
+                "namespace": "web",
+                "annotations": { "kubernetes.io/ingress.class": "ztunnel", "ztunnel.io/tunnel": "blog-v2" }
+            },
+            "spec": { "rules": [{ "host": "blog.example.com", "http": { "paths": [{ "backend": { "service": { "name": "blog" } } }] } }] }
+        }));
+        let other = ingress(json!({
+            "metadata": { "name": "x", "namespace": "default" },
+            "spec": { "ingressClassName": "nginx", "rules": [{ "host": "x.example.com", "http": { "paths": [{ "backend": { "service": { "name": "x" } } }] } }] }
+        }));
+
+        let hosts = hosts(&[ours.clone(), annotated, other], "ztunnel");
+        assert_eq!(hosts.len(), 2);
+        assert_eq!(hosts["shop.example.com"], "shop");
+        assert_eq!(hosts["blog.example.com"], "blog-v2");
+
+        assert_eq!(ours.wanted_status("relay.example.com", |_| false), json!([]));
+        assert_eq!(ours.wanted_status("relay.example.com", |t| t == "shop"), json!([{ "hostname": "relay.example.com" }]));
+        assert_eq!(ours.wanted_status("10.0.0.1", |_| true), json!([{ "ip": "10.0.0.1" }]));
+    }
+}
@@ -3,6 +3,7 @@
 //! Axum middleware layer that checks incoming requests against
 //! per-tunnel allow/deny CIDR rules.
 
+use anyhow::Result;
 use std::net::{IpAddr, Ipv4Addr};
 use std::str::FromStr;
 
@@ -64,6 +65,30 @@ impl CidrRange {
     }
 }
 
+/// A single address, or an IPv4 range
+#[derive(Debug, Clone)]
+pub enum IpMatcher {
+    Ip(IpAddr),
+    Range(CidrRange),
+}
+
+impl IpMatcher {
+    /// Parse `203.0.113.7`, `2001:db8::1` or `203.0.113.0/24`
+    pub fn parse(target: &str) -> Option<Self> {
+        match target.parse() {
+            Ok(ip) => Some(IpMatcher::Ip(ip)),
+            Err(_) => CidrRange::parse(target).map(IpMatcher::Range),
+        }
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match self {
+            IpMatcher::Ip(addr) => *addr == ip,
+            IpMatcher::Range(range) => range.contains(ip),
+        }
+    }
+}
+
 impl IpFilter {
     /// Create an IP filter from string lists
     pub fn from_strings(allow: &[String], deny: &[String]) -> Self {
@@ -103,40 +128,96 @@ impl IpFilter {
     }
 }
 
-/// Extract client IP from request headers or socket address
-pub fn extract_client_ip(
-    headers: &[(String, String)],
-    peer_addr: Option<std::net::SocketAddr>,
-) -> Option<IpAddr> {
-    // Check X-Forwarded-For header first
-    for (key, value) in headers {
-        if key.eq_ignore_ascii_case("x-forwarded-for") {
-            // Take the first IP in the chain
-            if let Some(ip_str) = value.split(',').next() {
-                if let Ok(ip) = IpAddr::from_str(ip_str.trim()) {
-                    return Some(ip);
-                }
+/// Proxies in front of the relay, from ZTUNNEL_TRUSTED_PROXIES. Only
+/// their forwarding headers are believed; anyone else could send any
+/// address they like.
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies(Vec<IpMatcher>);
+
+impl TrustedProxies {
+    pub fn from_env() -> Result<Self> {
+        Self::parse(&std::env::var("ZTUNNEL_TRUSTED_PROXIES").unwrap_or_default())
+    }
+
+    /// Addresses and IPv4 ranges separated by commas
+    pub fn parse(spec: &str) -> Result<Self> {
+        let mut proxies = Vec::new();
+        for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            match IpMatcher::parse(entry) {
+                Some(matcher) => proxies.push(matcher),
+                None => anyhow::bail!("Invalid ZTUNNEL_TRUSTED_PROXIES entry '{}'", entry),
             }
         }
+        Ok(Self(proxies))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
     }
 
-    // Check X-Real-IP
-    for (key, value) in headers {
-        if key.eq_ignore_ascii_case("x-real-ip") {
-            if let Ok(ip) = IpAddr::from_str(value.trim()) {
-                return Some(ip);
+    fn contains(&self, ip: IpAddr) -> bool {
+        self.0.iter().any(|m| m.contains(ip))
+    }
+
+    /// Who sent a request. Through trusted proxies, X-Forwarded-For is
+    /// read from the right, past each trusted hop, to the first address
+    /// that isn't one; X-Real-IP is used when there's no chain. From
+    /// anywhere else it's the peer.
+    pub fn client_ip(&self, headers: &[(String, String)], peer: IpAddr) -> IpAddr {
+        if !self.contains(peer) {
+            return peer;
+        }
+        let chain: Vec<&str> = headers
+            .iter()
+            .filter(|(k, _)| k.eq_ignore_ascii_case("x-forwarded-for"))
+            .flat_map(|(_, v)| v.split(','))
+            .collect();
+        if chain.is_empty() {
+            return headers
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case("x-real-ip"))
+                .and_then(|(_, v)| IpAddr::from_str(v.trim()).ok())
+                .unwrap_or(peer);
+        }
+        let mut client = peer;
+        for hop in chain.iter().rev() {
+            if !self.contains(client) {
+                break;
+            }
+            match IpAddr::from_str(hop.trim()) {
+                Ok(ip) => client = ip,
+                Err(_) => break,
             }
         }
+        client
     }
-
-    // Fall back to peer address
-    peer_addr.map(|addr| addr.ip())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_trusted_proxies() {
+        let headers = |xff: &str| vec![("X-Forwarded-For".to_string(), xff.to_string())];
+        let peer: IpAddr = "10.0.0.2".parse().unwrap();
+
+        // Nobody trusted: the header is ignored
+        let visitor: IpAddr = "203.0.113.9".parse().unwrap();
+        assert_eq!(TrustedProxies::default().client_ip(&headers("10.0.0.1"), visitor), visitor);
+
+        // A forged entry to the left of the real one doesn't help
+        let lb = TrustedProxies::parse("10.0.0.0/8, 192.0.2.1").unwrap();
+        assert_eq!(lb.len(), 2);
+        assert_eq!(lb.client_ip(&headers("10.0.0.1, 198.51.100.4"), peer), "198.51.100.4".parse::<IpAddr>().unwrap());
+        assert_eq!(lb.client_ip(&headers("198.51.100.4, 192.0.2.1"), peer), "198.51.100.4".parse::<IpAddr>().unwrap());
+        assert_eq!(lb.client_ip(&headers("junk"), peer), peer);
+        let real_ip = vec![("X-Real-IP".to_string(), "198.51.100.4".to_string())];
+        assert_eq!(lb.client_ip(&real_ip, peer), "198.51.100.4".parse::<IpAddr>().unwrap());
+        assert_eq!(lb.client_ip(&real_ip, visitor), visitor);
+        assert!(TrustedProxies::parse("10.0.0.0/33").is_err());
+    }
+
     #[test]
     fn test_cidr_parse() {
         let cidr = CidrRange::parse("192.168.1.0/24").unwrap();
@@ -0,0 +1,140 @@
+//! Caps on simultaneous tunnels per client IP and per auth token
+//!
+//! ZTUNNEL_MAX_TUNNELS_PER_IP and ZTUNNEL_MAX_TUNNELS_PER_TOKEN bound
+//! how many tunnels one source can hold open, so a client stuck in a
+//! loop can't take every subdomain or the relay's memory. A
+//! registration over either cap is refused with `Error::TunnelLimit`.
+
+use dashmap::DashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::Arc;
+use ztunnel_shared::Error;
+
+/// Open tunnels by key, up to a maximum each
+pub struct Counts<K: Hash + Eq + Clone> {
+    max: usize,
+    open: Arc<DashMap<K, usize>>,
+}
+
+impl<K: Hash + Eq + Clone> Counts<K> {
+    pub fn new(max: usize) -> Self {
+        Self { max, open: Arc::new(DashMap::new()) }
+    }
+
+    /// Take one of `key`'s slots, held until the `Slot` drops
+    pub fn acquire(&self, key: K) -> Option<Slot<K>> {
+        // Refused before the entry, which would otherwise stay at zero
+        if self.max == 0 {
+            return None;
+        }
+        let mut count = self.open.entry(key.clone()).or_insert(0);
+        if *count >= self.max {
+            return None;
+        }
+        *count += 1;
+        Some(Slot { key, open: self.open.clone() })
+    }
+}
+
+/// One open tunnel's place in a count
+pub struct Slot<K: Hash + Eq + Clone> {
+    key: K,
+    open: Arc<DashMap<K, usize>>,
+}
+
+impl<K: Hash + Eq + Clone> Drop for Slot<K> {
+    fn drop(&mut self) {
+        if let Some(mut count) = self.open.get_mut(&self.key) {
+            *count -= 1;
+        }
+        self.open.remove_if(&self.key, |_, count| *count == 0);
+    }
+}
+
+/// The caps configured on this relay
+#[derive(Clone, Default)]
+pub struct TunnelLimits {
+    per_ip: Option<Arc<Counts<IpAddr>>>,
+    per_token: Option<Arc<Counts<String>>>,
+}
+
+/// Slots a registration holds while its tunnel is open
+pub type Held = (Option<Slot<IpAddr>>, Option<Slot<String>>);
+
+impl TunnelLimits {
+    pub fn new(per_ip: Option<usize>, per_token: Option<usize>) -> Self {
+        Self {
+            per_ip: per_ip.map(|max| Arc::new(Counts::new(max))),
+            per_token: per_token.map(|max| Arc::new(Counts::new(max))),
+        }
+    }
+
+    pub fn from_env() -> Self {
+        let max = |name: &str| std::env::var(name).ok().and_then(|v| v.parse().ok());
+        Self::new(max("ZTUNNEL_MAX_TUNNELS_PER_IP"), max("ZTUNNEL_MAX_TUNNELS_PER_TOKEN"))
+    }
+
+    /// The caps in force, for the startup log
+    pub fn describe(&self) -> Option<String> {
+        let mut caps = Vec::new();
+        if let Some(counts) = &self.per_ip {
+            caps.push(format!("{} per IP", counts.max));
+        }
+        if let Some(counts) = &self.per_token {
+            caps.push(format!("{} per token", counts.max));
+        }
+        (!caps.is_empty()).then(|| caps.join(", "))
+    }
+
+    /// Count a new tunnel from `ip` with `token` against the caps
+    pub fn acquire(&self, ip: IpAddr, token: Option<&str>) -> Result<Held, Error> {
+        let by_ip = match &self.per_ip {
+            Some(counts) => match counts.acquire(ip) {
+                Some(slot) => Some(slot),
+                None => {
+                    return Err(Error::TunnelLimit(format!("{} open per IP address ({})", counts.max, ip)))
+                }
+            },
+            None => None,
+        };
+        let by_token = match (&self.per_token, token) {
+            (Some(counts), Some(token)) => match counts.acquire(token.to_string()) {
+                Some(slot) => Some(slot),
+                None => return Err(Error::TunnelLimit(format!("{} open per auth token", counts.max))),
+            },
+            _ => None,
+        };
+        Ok((by_ip, by_token))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tunnel_limits() {
+        let limits = TunnelLimits::new(Some(2), Some(1));
+        let ip: IpAddr = "198.51.100.7".parse().unwrap();
+        let other: IpAddr = "198.51.100.8".parse().unwrap();
+
+        let first = limits.acquire(ip, None).unwrap();
+        let _second = limits.acquire(ip, Some("abc")).unwrap();
+        assert!(matches!(limits.acquire(ip, None), Err(Error::TunnelLimit(_))));
+        // The token's one tunnel is taken, from any address
+        assert!(matches!(limits.acquire(other, Some("abc")), Err(Error::TunnelLimit(_))));
+        // ...and a refused registration doesn't keep its IP slot
+        assert!(limits.acquire(other, Some("xyz")).is_ok());
+
+        drop(first);
+        assert!(limits.acquire(ip, None).is_ok());
+        assert_eq!(TunnelLimits::new(None, None).describe(), None);
+        assert_eq!(limits.describe().as_deref(), Some("2 per IP, 1 per token"));
+
+        // Nobody allowed: refusals leave nothing behind
+        let none = Counts::new(0);
+        assert!(none.acquire(ip).is_none());
+        assert!(none.open.is_empty());
+    }
+}
@@ -1,54 +1,198 @@
 use anyhow::Result;
 use axum::{
     extract::{
-        ws::{Message, WebSocket, WebSocketUpgrade},
-        State,
+        ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade},
+        ConnectInfo, State,
     },
-    http::{StatusCode, header::HOST, Request},
+    http::{StatusCode, header::{self, HOST}, Request},
     body::Body,
+    middleware::{self, Next},
     response::IntoResponse,
     routing::{get, any},
     Router,
 };
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
-use tokio::sync::{mpsc, RwLock, oneshot};
-use tracing::{info, warn};
+use std::{net::{IpAddr, SocketAddr}, sync::{atomic::AtomicBool, Arc}};
+use tokio::sync::mpsc;
+use dashmap::{mapref::entry::Entry, DashMap};
+use tracing::{debug, info, warn};
+use futures_util::stream::SplitSink;
 use futures_util::{SinkExt, StreamExt};
 use hyper::Response;
 use hyper::header::{HeaderName, HeaderValue};
-use tokio::time::{timeout, Duration, Instant};
+use tokio::time::{timeout_at, Duration, Instant};
+use ztunnel_shared::protocol::{capability, Register, RegisterAck};
+use ztunnel_shared::ratelimit::{Quota, RateLimiter};
+use ztunnel_shared::telemetry::{metric, Fanout};
+use ztunnel_shared::batch::{self, Batcher};
+use ztunnel_shared::deflate;
+#[cfg(feature = "sentry")]
+use ztunnel_shared::report;
+use ztunnel_shared::error::code;
+use ztunnel_shared::{http, validate, Error, RetryAdvice, Telemetry};
 
 mod tunnel;
 mod router;
 mod ip_filter;
 mod circuit_breaker;
+mod config;
 mod metrics;
 mod tls;
 mod log_export;
 mod headers;
 mod policy;
 mod acme;
+mod connect;
+mod namespace;
+mod usage;
+mod admin;
+mod audit;
+mod bans;
+mod health;
+mod limits;
+mod maintenance;
+#[cfg(feature = "kubernetes")]
+mod ingress;
+#[cfg(feature = "otlp")]
+mod otlp;
+mod statsd;
 
 use tunnel::Tunnel;
+use policy::PolicyAction;
 use metrics::Metrics;
 use log_export::{LogExporter, LogExportConfig, LogEntry};
 
 #[derive(Clone)]
 pub struct AppState {
-    tunnels: Arc<RwLock<HashMap<String, Tunnel>>>,
+    /// Live tunnels by subdomain; sharded, so lookups on the request
+    /// path don't contend with registrations
+    tunnels: Arc<DashMap<String, Tunnel>>,
     domain: String,
+    /// Rendered at /metrics
     metrics: Metrics,
+    /// Where request and tunnel events are recorded; `metrics` by default
+    telemetry: Arc<dyn Telemetry>,
     log_exporter: LogExporter,
+    /// Proxy headers added to requests sent down a tunnel, and hop-by-hop
+    /// headers dropped both ways unless ZTUNNEL_STRIP_HOP_BY_HOP=off
+    rewriter: headers::HeaderRewriter,
+    /// Proxies whose X-Forwarded-For names the visitor
+    trusted_proxies: Arc<ip_filter::TrustedProxies>,
+    /// Requests per visitor IP across all tunnels, with ZTUNNEL_IP_RATE_LIMIT
+    ip_limits: Option<Arc<RateLimiter<IpAddr>>>,
+    /// Offer compressed messages to clients; off with ZTUNNEL_DEFLATE=off
+    deflate: bool,
+    /// Hosts outside `domain` and the tunnel serving each, kept by the
+    /// ingress controller
+    ingress_hosts: Arc<DashMap<String, String>>,
+    /// Subdomain prefixes reserved for tokens, from ZTUNNEL_NAMESPACES
+    namespaces: Arc<namespace::Namespaces>,
+    /// Requests, bytes and tunnel-hours per account
+    usage: Arc<usage::Usage>,
+    /// Bearer tokens for `/admin` and the admin each belongs to
+    admin_tokens: Arc<Vec<(String, String)>>,
+    /// Changes made through `/admin`
+    audit: Arc<audit::AuditLog>,
+    /// Addresses refused everywhere, managed at `/admin/bans`
+    bans: Arc<bans::Bans>,
+    /// Caps on open tunnels per client IP and per token
+    tunnel_limits: limits::TunnelLimits,
+    /// Set on SIGTERM, so `/readyz` sends traffic elsewhere
+    draining: Arc<AtomicBool>,
+    /// Set through `/admin/maintenance`
+    maintenance: Arc<maintenance::Maintenance>,
+    /// Requests taking longer are logged, with ZTUNNEL_SLOW_REQUEST_MS
+    slow_request: Option<Duration>,
+    /// How each tunnel's circuit breaker behaves
+    circuit: circuit_breaker::CircuitBreakerConfig,
+    /// Policy rules from relay.yml, layered under each client's
+    operator_policies: Arc<std::sync::RwLock<config::OperatorPolicies>>,
 }
 
 impl AppState {
     pub fn new(domain: String) -> Self {
         let log_config = LogExportConfig::default();
+        let metrics = Metrics::new();
         Self {
-            tunnels: Arc::new(RwLock::new(HashMap::new())),
+            tunnels: Arc::new(DashMap::new()),
             domain,
-            metrics: Metrics::new(),
+            telemetry: Arc::new(metrics.clone()),
+            metrics,
             log_exporter: LogExporter::new(log_config),
+            rewriter: headers::HeaderRewriter::default(),
+            trusted_proxies: Arc::default(),
+            ip_limits: None,
+            deflate: true,
+            ingress_hosts: Arc::new(DashMap::new()),
+            namespaces: Arc::default(),
+            usage: Arc::default(),
+            admin_tokens: Arc::default(),
+            audit: Arc::default(),
+            bans: Arc::default(),
+            tunnel_limits: limits::TunnelLimits::default(),
+            draining: Arc::default(),
+            maintenance: Arc::default(),
+            slow_request: None,
+            circuit: Default::default(),
+            operator_policies: Arc::default(),
+        }
+    }
+
+    /// Record to `backend` as well as what's recorded to already
+    fn add_telemetry(&mut self, backend: Arc<dyn Telemetry>) {
+        self.telemetry = Arc::new(Fanout(vec![self.telemetry.clone(), backend]));
+    }
+
+    /// The tunnel a Host header names: one label under `domain`, or an
+    /// ingress host. Any other host is the relay's own.
+    fn tunnel_for_host(&self, host: &str) -> Option<String> {
+        validate::subdomain_of(host, &self.domain)
+            .or_else(|| self.ingress_hosts.get(&validate::strip_port(host).to_ascii_lowercase()).map(|t| t.clone()))
+    }
+
+    /// Report the tunnel count after one opens or closes
+    fn tunnels_changed(&self) {
+        self.telemetry.gauge(metric::ACTIVE_TUNNELS, &[], self.tunnels.len() as f64);
+    }
+
+    /// Whether `ip`, or the peer it came through, is banned
+    fn is_banned(&self, ip: IpAddr, peer: IpAddr) -> bool {
+        match self.bans.find(ip).or_else(|| self.bans.find(peer)) {
+            Some(ban) => {
+                debug!("Refused {} (banned {}: {})", ip, ban.target, ban.reason);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Swap in new relay.yml rules, for open tunnels too
+    fn set_operator_policies(&self, policies: config::OperatorPolicies) {
+        // Taken before any tunnel, in the same order as registration
+        let mut current = self.operator_policies.write().unwrap();
+        for mut tunnel in self.tunnels.iter_mut() {
+            let layered = policies.layered(&tunnel.subdomain, &tunnel.client_policy);
+            tunnel.set_policy(layered);
+        }
+        *current = policies;
+    }
+
+    /// Every tunnel's circuit breaker stats, by subdomain
+    async fn circuits(&self) -> Vec<(String, circuit_breaker::CircuitStats)> {
+        let tunnels: Vec<Tunnel> = self.tunnels.iter().map(|t| t.clone()).collect();
+        let mut circuits = Vec::with_capacity(tunnels.len());
+        for tunnel in tunnels {
+            circuits.push((tunnel.subdomain.clone(), tunnel.circuit_breaker.stats().await));
+        }
+        circuits.sort_by(|a, b| a.0.cmp(&b.0));
+        circuits
+    }
+
+    /// Take a closed tunnel out of the registry, unless its subdomain
+    /// already belongs to another connection
+    fn remove_tunnel(&self, subdomain: &str, tunnel: &Tunnel) {
+        if self.tunnels.remove_if(subdomain, |_, t| t.tx.same_channel(&tunnel.tx)).is_some() {
+            self.usage.tunnel_closed(subdomain);
+            self.tunnels_changed();
         }
     }
 }
@@ -59,31 +203,186 @@ async fn main() -> Result<()> {
         .with_env_filter("ztunnel_relay=info")
         .init();
 
+    #[cfg(feature = "sentry")]
+    if let Ok(dsn) = std::env::var("ZTUNNEL_SENTRY_DSN") {
+        match dsn.parse() {
+            Ok(dsn) => report::init(report::Options {
+                dsn,
+                release: concat!("ztunnel-relay@", env!("CARGO_PKG_VERSION")).to_string(),
+                environment: std::env::var("ZTUNNEL_SENTRY_ENVIRONMENT").ok(),
+            }),
+            Err(e) => warn!("Error reporting not started: {}", e),
+        }
+    }
+
     let domain = std::env::var("ZTUNNEL_DOMAIN").unwrap_or_else(|_| "connectus.net.in".to_string());
     let port: u16 = std::env::var("PORT").unwrap_or_else(|_| "8080".to_string()).parse().unwrap_or(8080);
 
-    let state = AppState::new(domain.clone());
+    let mut state = AppState::new(domain.clone());
+    let trusted_proxies = ip_filter::TrustedProxies::from_env()?;
+    if trusted_proxies.len() > 0 {
+        info!("Taking visitor addresses from {} trusted prox(ies)", trusted_proxies.len());
+    }
+    state.trusted_proxies = Arc::new(trusted_proxies);
+    if let Some(per_minute) = std::env::var("ZTUNNEL_IP_RATE_LIMIT").ok().and_then(|v| v.parse::<u32>().ok()) {
+        info!("Limiting each visitor IP to {} requests/min", per_minute);
+        state.ip_limits = Some(Arc::new(RateLimiter::new(Quota::per_minute(per_minute))));
+    }
+
+    if let Some(ms) = std::env::var("ZTUNNEL_SLOW_REQUEST_MS").ok().and_then(|v| v.parse::<u64>().ok()) {
+        info!("Logging requests slower than {}ms", ms);
+        state.slow_request = Some(Duration::from_millis(ms));
+    }
+
+    state.circuit = circuit_breaker::CircuitBreakerConfig::from_env();
+
+    if std::env::var("ZTUNNEL_DEFLATE").is_ok_and(|v| matches!(v.as_str(), "0" | "false" | "off")) {
+        info!("Message compression disabled");
+        state.deflate = false;
+    }
+
+    if std::env::var("ZTUNNEL_STRIP_HOP_BY_HOP").is_ok_and(|v| matches!(v.as_str(), "0" | "false" | "off")) {
+        info!("Hop-by-hop headers passed through");
+        state.rewriter.strip_hop_by_hop = false;
+    }
+
+    if let Ok(path) = std::env::var("ZTUNNEL_CONFIG") {
+        let path = std::path::PathBuf::from(path);
+        let policies = config::OperatorPolicies::load(&path)?;
+        info!("Loaded {} policy rule(s) from {}", policies.len(), path.display());
+        state.set_operator_policies(policies);
+        let watched = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = config::watch(path, watched).await {
+                warn!("Not watching relay config for changes: {:#}", e);
+            }
+        });
+    }
 
+    let namespaces = namespace::Namespaces::from_env()?;
+    if !namespaces.is_empty() {
+        info!("Reserving {} subdomain namespace(s)", namespaces.len());
+    }
+    state.namespaces = Arc::new(namespaces);
+
+    state.tunnel_limits = limits::TunnelLimits::from_env();
+    if let Some(caps) = state.tunnel_limits.describe() {
+        info!("Limiting open tunnels to {}", caps);
+    }
+
+    if let Ok(path) = std::env::var("ZTUNNEL_USAGE_FILE") {
+        info!("Keeping account usage in {}", path);
+        state.usage = Arc::new(usage::Usage::load(path.into())?);
+    }
+    state.add_telemetry(state.usage.clone());
+    state.admin_tokens = Arc::new(admin::tokens_from_env()?);
+    if let Ok(path) = std::env::var("ZTUNNEL_AUDIT_FILE") {
+        info!("Appending admin actions to {}", path);
+        state.audit = Arc::new(audit::AuditLog::open(path.as_ref())?);
+    }
+    if let Ok(path) = std::env::var("ZTUNNEL_BANS_FILE") {
+        let bans = bans::Bans::load(path.clone().into())?;
+        info!("Loaded {} ban(s) from {}", bans.list().len(), path);
+        state.bans = Arc::new(bans);
+    }
+    state.maintenance = Arc::new(maintenance::Maintenance::from_env()?);
+
+    #[cfg(feature = "kubernetes")]
+    if let Ok(class) = std::env::var("ZTUNNEL_INGRESS_CLASS") {
+        match ingress::Controller::from_env(class, &domain) {
+            Ok(controller) => {
+                tokio::spawn(controller.run(state.ingress_hosts.clone(), state.tunnels.clone()));
+            }
+            Err(e) => warn!("Ingress controller not started: {:#}", e),
+        }
+    }
+
+    #[cfg(feature = "otlp")]
+    match otlp::OtlpConfig::from_env() {
+        Ok(Some(config)) => {
+            info!("Pushing metrics to {} every {:?}", config.endpoint, config.interval);
+            let exporter = otlp::OtlpExporter::new();
+            state.add_telemetry(Arc::new(exporter.clone()));
+            tokio::spawn(exporter.run(config));
+        }
+        Ok(None) => {}
+        Err(e) => warn!("OTLP export not started: {:#}", e),
+    }
+
+    match statsd::StatsdConfig::from_env() {
+        Ok(Some(config)) => match statsd::Statsd::connect(&config) {
+            Ok(sink) => {
+                info!("Sending metrics to statsd at {}", config.addr);
+                state.add_telemetry(Arc::new(sink));
+            }
+            Err(e) => warn!("StatsD not started: {:#}", e),
+        },
+        Ok(None) => {}
+        Err(e) => warn!("StatsD not started: {:#}", e),
+    }
+
+    tokio::spawn(sweep_pending(state.clone()));
+    tokio::spawn(state.usage.clone().run());
+
+    // On a tunnel's host these paths belong to the app behind it
+    let own_host = Router::new()
+        .route("/connect/:subdomain", get(connect::handler))
+        .route("/livez", get(health::livez))
+        .route("/readyz", get(health::readyz))
+        .route("/openapi.json", get(openapi_handler))
+        .merge(admin::routes())
+        .route_layer(middleware::from_fn_with_state(state.clone(), own_host_only));
     let app = Router::new()
         .route("/tunnel", get(ws_handler))
         .route("/health", get(health_handler))
         .route("/metrics", get(metrics_handler))
+        .merge(own_host)
         .fallback(any(proxy_handler))
-        .with_state(state);
+        .with_state(state.clone());
 
     let addr = SocketAddr::from(([0, 0, 0, 0], port));
     info!("ZTunnel Relay on {} (domain: {})", addr, domain);
 
+    let drain = std::env::var("ZTUNNEL_DRAIN_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(0);
     let listener = tokio::net::TcpListener::bind(addr).await?;
-    axum::serve(listener, app).await?;
+    // Tunnels are long-lived WebSockets, so exit rather than wait for
+    // connections to finish
+    tokio::select! {
+        served = axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()) => served?,
+        _ = health::shutdown_signal(state.clone(), Duration::from_secs(drain)) => info!("Shutting down"),
+    }
+    state.usage.save();
     Ok(())
 }
 
+/// Suffixes tried for a taken subdomain before the registration is refused
+const MAX_RENAME_ATTEMPTS: usize = 16;
+
+/// How often tunnels are checked for requests nobody is waiting on
+const SWEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Drop pending requests that are past their deadline or whose visitor
+/// went away, and report how many are still outstanding
+async fn sweep_pending(state: AppState) {
+    let mut timer = tokio::time::interval(SWEEP_INTERVAL);
+    loop {
+        timer.tick().await;
+        let now = Instant::now();
+        let mut outstanding = 0;
+        for tunnel in state.tunnels.iter() {
+            let expired = tunnel.pending_requests.sweep(now);
+            if expired > 0 {
+                debug!("Dropped {} expired request(s) on tunnel {}", expired, tunnel.key());
+            }
+            outstanding += tunnel.pending_requests.len();
+        }
+        state.telemetry.gauge(metric::PENDING_REQUESTS, &[], outstanding as f64);
+    }
+}
+
 /// Health check endpoint
 async fn health_handler(State(state): State<AppState>) -> impl IntoResponse {
-    let tunnels = state.tunnels.read().await;
-    let count = tunnels.len();
-    drop(tunnels);
+    let count = state.tunnels.len();
     axum::Json(serde_json::json!({
         "status": "ok",
         "active_tunnels": count,
@@ -92,78 +391,166 @@ async fn health_handler(State(state): State<AppState>) -> impl IntoResponse {
 
 /// Prometheus metrics endpoint
 async fn metrics_handler(State(state): State<AppState>) -> impl IntoResponse {
-    let body = state.metrics.to_prometheus().await;
+    let mut body = state.metrics.to_prometheus();
+    metrics::render_circuits(&mut body, &state.circuits().await);
     (StatusCode::OK, [("content-type", "text/plain")], body)
 }
 
+/// Pass requests for a tunnel's host to the tunnel, whatever the path
+async fn own_host_only(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    req: Request<Body>,
+    next: Next,
+) -> axum::response::Response {
+    let host = req.headers().get(HOST).and_then(|h| h.to_str().ok()).unwrap_or("");
+    if state.tunnel_for_host(host).is_some() {
+        return proxy_handler(State(state), ConnectInfo(peer), req).await.into_response();
+    }
+    next.run(req).await
+}
+
+/// OpenAPI document for the relay's own endpoints
+async fn openapi_handler() -> impl IntoResponse {
+    ([("content-type", "application/json")], include_str!("../assets/openapi.json"))
+}
+
 /// WebSocket upgrade handler
-async fn ws_handler(ws: WebSocketUpgrade, State(state): State<AppState>) -> impl IntoResponse {
-    ws.on_upgrade(|socket| handle_socket(socket, state))
+async fn ws_handler(
+    ws: WebSocketUpgrade,
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: axum::http::HeaderMap,
+) -> axum::response::Response {
+    let pairs: Vec<(String, String)> = headers
+        .iter()
+        .filter_map(|(k, v)| v.to_str().ok().map(|v| (k.as_str().to_string(), v.to_string())))
+        .collect();
+    let client = state.trusted_proxies.client_ip(&pairs, peer.ip());
+    if state.is_banned(client, peer.ip()) {
+        return (StatusCode::FORBIDDEN, "Access denied").into_response();
+    }
+    ws.on_upgrade(move |socket| handle_socket(socket, state, client))
 }
 
 /// Handle a new WebSocket connection (tunnel registration)
-async fn handle_socket(mut socket: WebSocket, state: AppState) {
-    // Parse registration message
-    let (subdomain, ip_filter_conf) = if let Some(Ok(Message::Text(text))) = socket.recv().await {
-        let v = serde_json::from_str::<serde_json::Value>(&text).unwrap_or_default();
-        
-        let sub = v.get("subdomain")
-            .and_then(|s| s.as_str())
-            .map(String::from)
-            .unwrap_or_else(gen_subdomain);
-        
-        // Parse IP filter from registration
-        let ip_f = if let Some(ip_cfg) = v.get("ip_filter") {
-            let allow: Vec<String> = ip_cfg.get("allow")
-                .and_then(|a| serde_json::from_value(a.clone()).ok())
-                .unwrap_or_default();
-            let deny: Vec<String> = ip_cfg.get("deny")
-                .and_then(|a| serde_json::from_value(a.clone()).ok())
-                .unwrap_or_default();
-            ip_filter::IpFilter::from_strings(&allow, &deny)
-        } else {
-            ip_filter::IpFilter::default()
-        };
-
-        (sub, ip_f)
-    } else {
-        (gen_subdomain(), ip_filter::IpFilter::default())
+async fn handle_socket(mut socket: WebSocket, state: AppState, client_ip: IpAddr) {
+    let registration = match socket.recv().await {
+        Some(Ok(Message::Text(text))) => serde_json::from_str::<Register>(&text)
+            .map_err(|e| Error::Protocol(format!("Invalid registration: {}", e))),
+        _ => return,
+    };
+    let registration = match registration {
+        Ok(r) => r,
+        Err(e) => {
+            warn!("Registration rejected: {}", e);
+            reject(socket, e).await;
+            return;
+        }
+    };
+    // Subscribed before the check, so a tunnel can't miss the close
+    let mut maintenance = state.maintenance.subscribe();
+    if let Some(e) = state.maintenance.refusal() {
+        info!("Registration from {} refused: {}", client_ip, e);
+        reject(socket, e).await;
+        return;
+    }
+    let claimed = state.namespaces.claim(registration.subdomain.as_deref(), registration.auth_token.as_deref());
+    let subdomain = match claimed {
+        Ok(name) => name.unwrap_or_else(gen_subdomain),
+        Err(e) => {
+            warn!("Registration rejected: {}", e);
+            reject(socket, e).await;
+            return;
+        }
     };
+    // Held until the tunnel closes
+    let _slots = match state.tunnel_limits.acquire(client_ip, registration.auth_token.as_deref()) {
+        Ok(slots) => slots,
+        Err(e) => {
+            warn!("Registration from {} rejected: {}", client_ip, e);
+            reject(socket, e).await;
+            return;
+        }
+    };
+    let account = state.namespaces.account(registration.auth_token.as_deref()).map(String::from);
+    let ip_filter_conf = ip_filter::IpFilter::from_strings(&registration.ip_filter.allow, &registration.ip_filter.deny);
+    let stream_bodies = registration.has_capability(capability::BODY_STREAM);
+    let batch_messages = registration.has_capability(capability::BATCH);
+    let compress = state.deflate && registration.has_capability(capability::DEFLATE);
+    let policy = policy::PolicyEngine::from_policies(&registration.policies);
 
     let (tx, mut rx) = mpsc::channel::<Vec<u8>>(100);
-    let cb = circuit_breaker::CircuitBreaker::new(circuit_breaker::CircuitBreakerConfig::default());
+    let cb = circuit_breaker::CircuitBreaker::new(state.circuit.clone());
 
     // ─── Subdomain conflict resolution ───
-    let final_subdomain = {
-        let tunnels = state.tunnels.read().await;
-        if tunnels.contains_key(&subdomain) {
-            // Subdomain taken → append random suffix
-            let suffix = gen_subdomain_short();
-            let alt = format!("{}-{}", subdomain, suffix);
-            warn!("Subdomain '{}' taken, assigning '{}'", subdomain, alt);
-            alt
-        } else {
-            subdomain.clone()
+    // The name is claimed in the same step as the check, so two clients
+    // asking at once can't both get it
+    let mut final_subdomain = subdomain.clone();
+    let mut attempts = 0;
+    let tunnel = {
+        // Held while claiming, so a relay.yml reload can't miss this tunnel
+        let operator_policies = state.operator_policies.read().unwrap();
+        loop {
+            match state.tunnels.entry(final_subdomain.clone()) {
+                Entry::Vacant(slot) => {
+                    let mut tunnel = Tunnel::new(
+                        final_subdomain.clone(),
+                        tx,
+                        ip_filter_conf,
+                        cb.clone(),
+                        stream_bodies,
+                        batch_messages,
+                        policy,
+                    );
+                    tunnel.tcp = registration.proto == "tcp";
+                    tunnel.account = account.clone();
+                    tunnel.policy = operator_policies.layered(&final_subdomain, &tunnel.client_policy);
+                    break Some(slot.insert(tunnel).clone());
+                }
+                Entry::Occupied(_) => {
+                    // Subdomain taken → append random suffix, skipping
+                    // any that land in another account's namespace
+                    attempts += 1;
+                    if attempts > MAX_RENAME_ATTEMPTS {
+                        break None;
+                    }
+                    let suffix = gen_subdomain_short();
+                    // Keep the result a valid label
+                    let keep = validate::MAX_LABEL_LEN - suffix.len() - 1;
+                    let base = subdomain[..subdomain.len().min(keep)].trim_end_matches('-');
+                    final_subdomain = format!("{}-{}", base, suffix);
+                    if !state.namespaces.allows(&final_subdomain, registration.auth_token.as_deref()) {
+                        continue;
+                    }
+                    warn!("Subdomain '{}' taken, trying '{}'", subdomain, final_subdomain);
+                }
+            }
         }
     };
-
-    let tunnel = Tunnel::new(final_subdomain.clone(), tx, ip_filter_conf, cb.clone());
-    
-    state.tunnels.write().await.insert(final_subdomain.clone(), tunnel.clone());
-    state.metrics.tunnel_opened();
+    let Some(tunnel) = tunnel else {
+        warn!("Registration rejected: no free name near '{}'", subdomain);
+        reject(socket, Error::Tunnel(format!("Subdomain '{}' is taken", subdomain))).await;
+        return;
+    };
+    state.tunnels_changed();
+    if let Some(account) = &account {
+        state.usage.tunnel_opened(&final_subdomain, account);
+    }
 
     let url = format!("https://{}.{}", final_subdomain, state.domain);
     let was_reassigned = final_subdomain != subdomain;
-    let resp = serde_json::json!({
-        "success": true,
-        "subdomain": &final_subdomain,
-        "url": &url,
-        "reassigned": was_reassigned,
-    });
-    
-    if socket.send(Message::Text(resp.to_string().into())).await.is_err() {
-        state.tunnels.write().await.remove(&final_subdomain);
-        state.metrics.tunnel_closed();
+    let mut ack = RegisterAck::accepted(&final_subdomain, &url, was_reassigned);
+    ack.capabilities = [capability::BODY_STREAM, capability::RESPONSE_STREAM, capability::BATCH]
+        .map(String::from)
+        .to_vec();
+    if compress {
+        ack.capabilities.push(capability::DEFLATE.to_string());
+    }
+    let ack = serde_json::to_string(&ack).unwrap_or_default();
+
+    if socket.send(Message::Text(ack)).await.is_err() {
+        state.remove_tunnel(&final_subdomain, &tunnel);
         return;
     }
     
@@ -186,27 +573,44 @@ async fn handle_socket(mut socket: WebSocket, state: AppState) {
     // Ping/pong keepalive
     let keepalive_interval = Duration::from_secs(30);
     let mut ping_timer = tokio::time::interval(keepalive_interval);
+    // Small messages for the client wait here to go out together
+    let mut batcher = Batcher::default();
 
     loop {
+        let flush_at = batcher.deadline().map(Instant::from_std);
         tokio::select! {
             msg = receiver.next() => {
                 match msg {
                     Some(Ok(Message::Ping(d))) => { let _ = sender.send(Message::Pong(d)).await; }
-                    Some(Ok(Message::Binary(data))) => {
-                        if let Ok(resp) = serde_json::from_slice::<tunnel::TunnelResponse>(&data) {
-                            tunnel.circuit_breaker.record_success().await;
-                            if let Some((_id, tx)) = tunnel.pending_requests.remove(&resp.id) {
-                                let _ = tx.send(resp);
+                    Some(Ok(Message::Binary(data))) => match deflate::decompress(&data) {
+                        Ok(data) => {
+                            for message in batch::split(&data) {
+                                handle_client_message(&tunnel, message).await;
                             }
                         }
-                    }
+                        Err(e) => warn!("Tunnel {}: {}", final_subdomain, e),
+                    },
                     Some(Ok(Message::Close(_))) | None => break,
                     _ => {}
                 }
             }
             Some(data) = rx.recv() => {
-                if sender.send(Message::Binary(data.into())).await.is_err() {
-                    tunnel.circuit_breaker.record_failure().await;
+                let mut ready = Vec::new();
+                if tunnel.batch_messages && batch::fits(&data) {
+                    ready.extend(batcher.push(&data));
+                } else {
+                    // Anything batched was queued before this
+                    ready.extend(batcher.take());
+                    ready.push(data);
+                }
+                if send_binary(&mut sender, ready, compress).await.is_err() {
+                    circuit_failure(&final_subdomain, &tunnel, Error::Connection("client send failed".into())).await;
+                    break;
+                }
+            }
+            _ = tokio::time::sleep_until(flush_at.unwrap_or_else(Instant::now)), if flush_at.is_some() => {
+                if send_binary(&mut sender, batcher.take().into_iter().collect(), compress).await.is_err() {
+                    circuit_failure(&final_subdomain, &tunnel, Error::Connection("client send failed".into())).await;
                     break;
                 }
             }
@@ -215,67 +619,204 @@ async fn handle_socket(mut socket: WebSocket, state: AppState) {
                     break;
                 }
             }
+            _ = tunnel.kicked.notified() => {
+                let frame = CloseFrame { code: close_code::POLICY, reason: "Disconnected by the relay operator".into() };
+                let _ = sender.send(Message::Close(Some(frame))).await;
+                break;
+            }
+            Ok(event) = maintenance.recv() => match event {
+                maintenance::Event::Notice(notice) => {
+                    let notice = serde_json::to_string(&notice).unwrap_or_default();
+                    if sender.send(Message::Text(notice)).await.is_err() {
+                        break;
+                    }
+                }
+                maintenance::Event::Close => {
+                    let frame = CloseFrame { code: code::MAINTENANCE, reason: "Relay maintenance".into() };
+                    let _ = sender.send(Message::Close(Some(frame))).await;
+                    break;
+                }
+            },
+        }
+    }
+
+    // Bodies still on their way fail rather than look complete
+    for body in tunnel.response_bodies.iter() {
+        let _ = body.send(Err(std::io::Error::new(std::io::ErrorKind::ConnectionAborted, "tunnel closed")));
+    }
+    tunnel.response_bodies.clear();
+    // Requests still waiting get an answer now rather than at their timeout
+    tunnel.pending_requests.clear();
+    state.remove_tunnel(&final_subdomain, &tunnel);
+    info!("Tunnel {} closed", final_subdomain);
+}
+
+/// One message from the client: a response, or a frame of a response body
+async fn handle_client_message(tunnel: &Tunnel, data: &[u8]) {
+    if let Ok(resp) = serde_json::from_slice::<tunnel::TunnelResponse>(data) {
+        tunnel.circuit_breaker.record_success().await;
+        if let Some(tx) = tunnel.pending_requests.remove(&resp.id) {
+            let body = resp.streamed.then(|| {
+                let (body_tx, body_rx) = mpsc::unbounded_channel();
+                tunnel.response_bodies.insert(resp.id.clone(), body_tx);
+                body_rx
+            });
+            let _ = tx.send(tunnel::Reply { response: resp, body });
+        }
+    } else if let Ok(frame) = serde_json::from_slice::<tunnel::StreamFrame>(data) {
+        deliver_body_frame(tunnel, frame);
+    }
+}
+
+/// Write messages to the client, compressed if it agreed to that, and
+/// flush them together
+async fn send_binary(
+    sender: &mut SplitSink<WebSocket, Message>,
+    messages: Vec<Vec<u8>>,
+    compress: bool,
+) -> Result<(), axum::Error> {
+    for data in messages {
+        let data = if compress { deflate::compress(data) } else { data };
+        sender.feed(Message::Binary(data)).await?;
+    }
+    sender.flush().await
+}
+
+/// Pass a streamed response body frame to the request waiting for it
+fn deliver_body_frame(tunnel: &Tunnel, frame: tunnel::StreamFrame) {
+    match frame.event {
+        tunnel::StreamEvent::Data(data) => {
+            // A visitor that went away has dropped its receiver
+            let delivered = tunnel.response_bodies.get(&frame.stream).is_some_and(|body| body.send(Ok(data)).is_ok());
+            if !delivered {
+                tunnel.response_bodies.remove(&frame.stream);
+            }
+        }
+        tunnel::StreamEvent::Close | tunnel::StreamEvent::Open => {
+            tunnel.response_bodies.remove(&frame.stream);
         }
     }
+}
 
-    state.tunnels.write().await.remove(&subdomain);
-    state.metrics.tunnel_closed();
-    info!("Tunnel {} closed", subdomain);
+/// Refuse a registration: the typed error in the ack, then a close frame
+/// carrying the same code
+async fn reject(mut socket: WebSocket, error: Error) {
+    let frame = CloseFrame { code: error.code(), reason: error.message().into() };
+    if let Ok(ack) = serde_json::to_string(&RegisterAck::rejected(error)) {
+        let _ = socket.send(Message::Text(ack)).await;
+    }
+    let _ = socket.send(Message::Close(Some(frame))).await;
 }
 
 /// Main proxy handler with IP filtering, metrics, and circuit breaker
 async fn proxy_handler(
     State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
     req: Request<Body>,
 ) -> impl IntoResponse {
     let start = Instant::now();
     
-    let host = req.headers().get(HOST).and_then(|h| h.to_str().ok()).unwrap_or("");
-    let subdomain = host.split('.').next().unwrap_or("").to_string();
+    let host = req.headers().get(HOST).and_then(|h| h.to_str().ok()).unwrap_or("").to_string();
+    let subdomain = state.tunnel_for_host(&host).unwrap_or_default();
     let path = req.uri().path().to_string();
     let method = req.method().to_string();
     let headers: Vec<(String, String)> = req.headers().iter().filter_map(|(k, v)| {
         v.to_str().ok().map(|val| (k.as_str().to_string(), val.to_string()))
     }).collect();
 
-    // Read request body
-    let body_bytes = match axum::body::to_bytes(req.into_body(), 10 * 1024 * 1024).await {
-        Ok(b) if !b.is_empty() => Some(b.to_vec()),
-        _ => None,
-    };
+    let body = req.into_body();
 
-    let bytes_in = body_bytes.as_ref().map(|b| b.len() as u64).unwrap_or(0);
+    // Who's asking, as the IP filter and rate limits see it
+    let visitor = state.trusted_proxies.client_ip(&headers, peer.ip());
+    if state.is_banned(visitor, peer.ip()) {
+        return (StatusCode::FORBIDDEN, "Access denied".to_string()).into_response();
+    }
+    if let Some(limits) = &state.ip_limits {
+        if let Err(Error::RateLimited(secs)) = limits.check(&visitor) {
+            return too_many_requests(secs);
+        }
+    }
+    if let Some(page) = state.maintenance.page() {
+        return page;
+    }
 
-    // Get tunnel (clone + drop lock)
-    let tunnel = {
-        let tunnels = state.tunnels.read().await;
-        match tunnels.get(&subdomain) {
-            Some(t) => t.clone(),
-            None => {
-                warn!("No tunnel: {}", subdomain);
-                return (StatusCode::NOT_FOUND, "Tunnel not found".to_string()).into_response();
-            }
+    // Get tunnel (clone + release the shard)
+    let tunnel = match state.tunnels.get(&subdomain) {
+        Some(t) => t.clone(),
+        None => {
+            warn!("No tunnel: {}", subdomain);
+            return (StatusCode::NOT_FOUND, "Tunnel not found".to_string()).into_response();
+        }
+    };
+
+    // Large and open-ended bodies go to clients that take them as frames,
+    // so the relay never holds them whole
+    let (body_bytes, upload) = if tunnel.stream_bodies && should_stream(&headers) {
+        (None, Some(body))
+    } else {
+        // Read request body
+        match axum::body::to_bytes(body, 10 * 1024 * 1024).await {
+            Ok(b) if !b.is_empty() => (Some(b), None),
+            _ => (None, None),
         }
     };
 
+    let mut bytes_in = body_bytes.as_ref().map(|b| b.len() as u64).unwrap_or(0);
+
     // IP filtering
-    if !tunnel.ip_filter.is_empty() {
-        if let Some(client_ip) = ip_filter::extract_client_ip(&headers, None) {
-            if !tunnel.ip_filter.is_allowed(client_ip) {
-                warn!("IP {} blocked for tunnel {}", client_ip, subdomain);
-                state.metrics.record_request(&subdomain, 403, start.elapsed().as_micros() as u64, bytes_in, 0).await;
-                return (StatusCode::FORBIDDEN, "Access denied".to_string()).into_response();
+    if !tunnel.ip_filter.is_empty() && !tunnel.ip_filter.is_allowed(visitor) {
+        warn!("IP {} blocked for tunnel {}", visitor, subdomain);
+        state.telemetry.request(&subdomain, 403, start.elapsed(), bytes_in, 0);
+        return (StatusCode::FORBIDDEN, "Access denied".to_string()).into_response();
+    }
+
+    // Client and relay.yml policies: answered here without a trip through
+    // the tunnel
+    let mut policy_header = None;
+    let (rule, action) = match tunnel.policy.matching(&path, &method, visitor) {
+        Some(rule) => (rule.id, rule.action.clone()),
+        None => (0, PolicyAction::Allow),
+    };
+    match action {
+        PolicyAction::Block(status) => {
+            let status = StatusCode::from_u16(status).unwrap_or(StatusCode::FORBIDDEN);
+            state.telemetry.request(&subdomain, status.as_u16(), start.elapsed(), bytes_in, 0);
+            return (status, "Blocked by policy").into_response();
+        }
+        PolicyAction::Redirect(location) => {
+            state.telemetry.request(&subdomain, 302, start.elapsed(), bytes_in, 0);
+            return (StatusCode::FOUND, [(header::LOCATION, location)]).into_response();
+        }
+        PolicyAction::RequireAuth if !headers.iter().any(|(k, _)| k.eq_ignore_ascii_case("authorization")) => {
+            state.telemetry.request(&subdomain, 401, start.elapsed(), bytes_in, 0);
+            let challenge = [(header::WWW_AUTHENTICATE, "Basic realm=\"ztunnel\"")];
+            return (StatusCode::UNAUTHORIZED, challenge, "Authentication required").into_response();
+        }
+        PolicyAction::RateLimit(per_minute) => {
+            let key = (rule, visitor);
+            if let Err(Error::RateLimited(secs)) = tunnel.policy_limits.check_with(&key, Quota::per_minute(per_minute)) {
+                state.telemetry.request(&subdomain, 429, start.elapsed(), bytes_in, 0);
+                return too_many_requests(secs);
             }
         }
+        PolicyAction::AddHeader(name, value) => policy_header = Some((name, value)),
+        // Credentials themselves are checked by the client
+        PolicyAction::RequireAuth | PolicyAction::Allow => {}
     }
 
+    // The local service sees who the visitor is, after any proxies
+    // already in front of us
+    let mut forwarded = headers.clone();
+    state.rewriter.rewrite_request(&mut forwarded, Some(&peer.ip().to_string()), Some(&visitor.to_string()), &host);
+
     let id = gen_request_id();
     let tr = tunnel::TunnelRequest {
         id: id.clone(),
         method: method.clone(),
         path: path.clone(),
-        headers: headers.clone(),
+        headers: forwarded,
         body: body_bytes,
+        streamed: upload.is_some(),
     };
     let data = match serde_json::to_vec(&tr) {
         Ok(d) => d,
@@ -288,47 +829,80 @@ async fn proxy_handler(
     let data = match tunnel.circuit_breaker.try_send(data).await {
         Ok(d) => d,
         Err(()) => {
-            let latency = start.elapsed().as_micros() as u64;
-            state.metrics.record_request(&subdomain, 503, latency, bytes_in, 0).await;
-            return (StatusCode::SERVICE_UNAVAILABLE, "Service temporarily unavailable (queued)").into_response();
+            state.telemetry.request(&subdomain, 503, start.elapsed(), bytes_in, 0);
+            let mut response = (StatusCode::SERVICE_UNAVAILABLE, "Service temporarily unavailable (queued)").into_response();
+            if let RetryAdvice::After(wait) = tunnel.circuit_breaker.retry_advice().await {
+                response.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(wait.as_secs().max(1)));
+            }
+            return response;
         }
     };
 
-    let (tx, rx) = oneshot::channel::<tunnel::TunnelResponse>();
-    tunnel.pending_requests.insert(id.clone(), tx);
+    let deadline = Instant::now() + tunnel::REQUEST_TIMEOUT;
+    let rx = tunnel.pending_requests.insert(id.clone(), deadline);
     
-    if tunnel.send(data).await.is_err() {
+    // The body's frames must follow the request to the same client
+    let client = tunnel.client().await;
+    if client.send(data).await.is_err() {
         tunnel.pending_requests.remove(&id);
-        tunnel.circuit_breaker.record_failure().await;
-        let latency = start.elapsed().as_micros() as u64;
-        state.metrics.record_request(&subdomain, 502, latency, bytes_in, 0).await;
+        circuit_failure(&subdomain, &tunnel, Error::Connection("upstream send failed".into())).await;
+        state.telemetry.request(&subdomain, 502, start.elapsed(), bytes_in, 0);
         return (StatusCode::BAD_GATEWAY, "Upstream send failed").into_response();
     }
 
-    match timeout(Duration::from_secs(30), rx).await {
-        Ok(Ok(resp)) => {
+    if let Some(body) = upload {
+        match stream_body(&client, &id, body).await {
+            Ok(sent) => bytes_in = sent,
+            Err(status) => {
+                tunnel.pending_requests.remove(&id);
+                state.telemetry.request(&subdomain, status.as_u16(), start.elapsed(), bytes_in, 0);
+                let message = if status == StatusCode::BAD_GATEWAY { "Upstream send failed" } else { "Request body aborted" };
+                return (status, message).into_response();
+            }
+        }
+    }
+    // Everything before this is the relay's side: reading the body, the
+    // circuit breaker's queue, and room in the client's channel
+    let queued = start.elapsed();
+
+    match timeout_at(deadline, rx).await {
+        Ok(Ok(tunnel::Reply { response: mut resp, body: frames })) => {
+            state.rewriter.rewrite_response(&mut resp.headers);
             let status_code = StatusCode::from_u16(resp.status).unwrap_or(StatusCode::OK);
             let mut builder = Response::builder().status(status_code);
             if let Some(headers_mut) = builder.headers_mut() {
-                for (k, v) in &resp.headers {
+                for (k, v) in resp.headers.iter().chain(&policy_header) {
                     if let (Ok(hn), Ok(hv)) = (HeaderName::from_bytes(k.as_bytes()), HeaderValue::from_str(v)) {
                         headers_mut.insert(hn, hv);
                     }
                 }
             }
-            let body = resp.body.unwrap_or_default();
-            let bytes_out = body.len() as u64;
-            let latency = start.elapsed().as_micros() as u64;
+            let (body, bytes_out) = match frames {
+                // Passed on as the frames arrive; the size is what the
+                // client declared
+                Some(mut frames) => {
+                    let declared = http::header(&resp.headers, "content-length").and_then(|v| v.parse().ok());
+                    (Body::from_stream(futures_util::stream::poll_fn(move |cx| frames.poll_recv(cx))), declared.unwrap_or(0))
+                }
+                None => {
+                    let body = resp.body.unwrap_or_default();
+                    let len = body.len() as u64;
+                    (Body::from(body), len)
+                }
+            };
+            let latency = start.elapsed();
 
             // Record metrics
-            state.metrics.record_request(&subdomain, resp.status, latency, bytes_in, bytes_out).await;
+            state.telemetry.request(&subdomain, resp.status, latency, bytes_in, bytes_out);
+            if state.slow_request.is_some_and(|threshold| latency >= threshold) {
+                log_slow(&state, &subdomain, &method, &path, resp.status, queued, latency);
+            }
 
             // Export log
             let user_agent = headers.iter()
                 .find(|(k, _)| k.eq_ignore_ascii_case("user-agent"))
                 .map(|(_, v)| v.clone());
-            let client_ip = ip_filter::extract_client_ip(&headers, None)
-                .map(|ip| ip.to_string());
+            let client_ip = Some(visitor.to_string());
 
             let log_entry = LogEntry {
                 timestamp: chrono::Utc::now().to_rfc3339(),
@@ -337,7 +911,7 @@ async fn proxy_handler(
                 method,
                 path,
                 status: resp.status,
-                latency_us: latency,
+                latency_us: latency.as_micros() as u64,
                 bytes_in,
                 bytes_out,
                 client_ip,
@@ -345,28 +919,114 @@ async fn proxy_handler(
             };
             state.log_exporter.log(&log_entry).await;
 
-            match builder.body(Body::from(body)) {
+            match builder.body(body) {
                 Ok(r) => r.into_response(),
                 Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Response build error").into_response()
             }
         }
-        Ok(Err(_)) => {
+        // Past the deadline, whether the timer or the sweeper noticed first
+        _ if Instant::now() >= deadline => {
             tunnel.pending_requests.remove(&id);
-            tunnel.circuit_breaker.record_failure().await;
-            let latency = start.elapsed().as_micros() as u64;
-            state.metrics.record_request(&subdomain, 502, latency, bytes_in, 0).await;
-            (StatusCode::BAD_GATEWAY, "Upstream closed").into_response()
+            circuit_failure(&subdomain, &tunnel, Error::Timeout).await;
+            state.telemetry.request(&subdomain, 504, start.elapsed(), bytes_in, 0);
+            (StatusCode::GATEWAY_TIMEOUT, "Timeout").into_response()
         }
-        Err(_) => {
+        _ => {
             tunnel.pending_requests.remove(&id);
-            tunnel.circuit_breaker.record_failure().await;
-            let latency = start.elapsed().as_micros() as u64;
-            state.metrics.record_request(&subdomain, 504, latency, bytes_in, 0).await;
-            (StatusCode::GATEWAY_TIMEOUT, "Timeout").into_response()
+            circuit_failure(&subdomain, &tunnel, Error::Connection("upstream closed".into())).await;
+            state.telemetry.request(&subdomain, 502, start.elapsed(), bytes_in, 0);
+            (StatusCode::BAD_GATEWAY, "Upstream closed").into_response()
         }
     }
 }
 
+/// Log and count a request over ZTUNNEL_SLOW_REQUEST_MS, split into the
+/// wait before it went down the tunnel and the round trip through it
+fn log_slow(
+    state: &AppState,
+    subdomain: &str,
+    method: &str,
+    path: &str,
+    status: u16,
+    queued: Duration,
+    latency: Duration,
+) {
+    state.telemetry.counter(metric::SLOW_REQUESTS, &[("tunnel", subdomain)], 1);
+    warn!(
+        "Slow request: {} {} {} -> {} in {}ms (queued {}ms, tunnel round trip {}ms)",
+        subdomain,
+        method,
+        path,
+        status,
+        latency.as_millis(),
+        queued.as_millis(),
+        latency.saturating_sub(queued).as_millis()
+    );
+}
+
+/// Count a failed request against the tunnel's circuit, reporting the
+/// circuit opening
+#[cfg_attr(not(feature = "sentry"), allow(unused_variables))]
+async fn circuit_failure(subdomain: &str, tunnel: &Tunnel, error: Error) {
+    if tunnel.circuit_breaker.record_error(&error).await {
+        #[cfg(feature = "sentry")]
+        report::capture(
+            report::Level::Error,
+            "circuit_breaker",
+            &format!("Circuit opened for tunnel {}: {}", subdomain, error),
+            &[("tunnel", subdomain)],
+        );
+    }
+}
+
+/// 429 with the wait until the next request would pass
+fn too_many_requests(retry_after: u64) -> axum::response::Response {
+    let mut response = (StatusCode::TOO_MANY_REQUESTS, "Too many requests").into_response();
+    response.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+    response
+}
+
+/// Bodies larger than this, or of unknown length, are streamed to
+/// clients that support it
+const STREAM_BODY_THRESHOLD: u64 = 1024 * 1024;
+
+fn should_stream(headers: &[(String, String)]) -> bool {
+    headers.iter().any(|(k, v)| {
+        k.eq_ignore_ascii_case("transfer-encoding")
+            || (k.eq_ignore_ascii_case("content-length")
+                && v.trim().parse::<u64>().is_ok_and(|len| len > STREAM_BODY_THRESHOLD))
+    })
+}
+
+/// Send a request body to the client as stream frames as it arrives,
+/// ending with a close frame even if the visitor aborts. Returns the
+/// bytes sent.
+async fn stream_body(client: &mpsc::Sender<Vec<u8>>, id: &str, body: Body) -> Result<u64, StatusCode> {
+    let mut chunks = body.into_data_stream();
+    let mut sent = 0;
+    let mut aborted = false;
+    while let Some(chunk) = chunks.next().await {
+        let Ok(chunk) = chunk else {
+            aborted = true;
+            break;
+        };
+        sent += chunk.len() as u64;
+        send_frame(client, id, tunnel::StreamEvent::Data(chunk)).await?;
+    }
+    send_frame(client, id, tunnel::StreamEvent::Close).await?;
+    if aborted {
+        Err(StatusCode::BAD_REQUEST)
+    } else {
+        Ok(sent)
+    }
+}
+
+async fn send_frame(client: &mpsc::Sender<Vec<u8>>, id: &str, event: tunnel::StreamEvent) -> Result<(), StatusCode> {
+    let frame = tunnel::StreamFrame::new(id, event);
+    let data = serde_json::to_vec(&frame).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    client.send(data).await.map_err(|_| StatusCode::BAD_GATEWAY)
+}
+
 fn gen_subdomain() -> String {
     use std::time::{SystemTime, UNIX_EPOCH};
     format!("t{:x}", SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() % 0xFFFFFF)
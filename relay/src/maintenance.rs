@@ -0,0 +1,208 @@
+//! Maintenance mode, for taking a relay down in a controlled way
+//!
+//! Started at `/admin/maintenance`. New registrations are refused with
+//! `Error::Maintenance`, which tells clients when to come back.
+//! Connected clients get a `Notice` at the start and again 10, 5 and 1
+//! minutes and 10 seconds before the drain period is up, when their
+//! tunnels are closed. From then until maintenance ends, visitors get a
+//! 503 page with `Retry-After`. ZTUNNEL_MAINTENANCE_PAGE names an HTML
+//! file to use instead of the built-in page; `{message}` and `{until}`
+//! in it are filled in.
+
+use anyhow::{Context, Result};
+use axum::http::{header, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::sync::{Mutex, RwLock};
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+use tracing::info;
+use ztunnel_shared::protocol::Notice;
+use ztunnel_shared::Error;
+
+/// Seconds before tunnels close that notices go out, besides at the start
+const NOTICE_BEFORE: [u64; 4] = [600, 300, 60, 10];
+
+/// Shortest `Retry-After`, once maintenance runs past its expected end
+const MIN_RETRY_SECS: i64 = 60;
+
+const DEFAULT_PAGE: &str = include_str!("../assets/maintenance.html");
+
+/// One maintenance period
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Window {
+    pub message: String,
+    /// Admin who started it
+    pub by: String,
+    pub started: DateTime<Utc>,
+    /// When connected tunnels are closed
+    pub closes: DateTime<Utc>,
+    /// When it's expected to be over
+    pub until: DateTime<Utc>,
+}
+
+impl Window {
+    /// Seconds to wait before trying again
+    pub fn retry_after(&self, now: DateTime<Utc>) -> u64 {
+        (self.until - now).num_seconds().max(MIN_RETRY_SECS) as u64
+    }
+}
+
+/// What connected tunnels are told
+#[derive(Debug, Clone)]
+pub enum Event {
+    Notice(Notice),
+    /// Close the tunnel now
+    Close,
+}
+
+pub struct Maintenance {
+    window: RwLock<Option<Window>>,
+    page: String,
+    events: broadcast::Sender<Event>,
+    /// Sends the current window's notices
+    drain: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Default for Maintenance {
+    fn default() -> Self {
+        Self::with_page(DEFAULT_PAGE.to_string())
+    }
+}
+
+impl Maintenance {
+    fn with_page(page: String) -> Self {
+        let (events, _) = broadcast::channel(16);
+        Self { window: RwLock::default(), page, events, drain: Mutex::default() }
+    }
+
+    /// With the page in ZTUNNEL_MAINTENANCE_PAGE, if set
+    pub fn from_env() -> Result<Self> {
+        match std::env::var("ZTUNNEL_MAINTENANCE_PAGE") {
+            Ok(path) => {
+                let page = std::fs::read_to_string(&path).with_context(|| format!("Reading maintenance page {}", path))?;
+                Ok(Self::with_page(page))
+            }
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    pub fn current(&self) -> Option<Window> {
+        self.window.read().unwrap().clone()
+    }
+
+    /// Events for a connected tunnel
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    /// Start maintenance, replacing any window already set; returns the
+    /// one replaced
+    pub fn start(&self, window: Window) -> Option<Window> {
+        info!("Maintenance by {}: tunnels close at {}, back by {}", window.by, window.closes, window.until);
+        let previous = self.window.write().unwrap().replace(window.clone());
+        let task = tokio::spawn(drain(self.events.clone(), window));
+        if let Some(replaced) = self.drain.lock().unwrap().replace(task) {
+            replaced.abort();
+        }
+        previous
+    }
+
+    /// End maintenance; tunnels not yet closed stay open
+    pub fn end(&self) -> Option<Window> {
+        if let Some(task) = self.drain.lock().unwrap().take() {
+            task.abort();
+        }
+        let ended = self.window.write().unwrap().take();
+        if ended.is_some() {
+            info!("Maintenance over");
+        }
+        ended
+    }
+
+    /// Why a registration is refused right now
+    pub fn refusal(&self) -> Option<Error> {
+        let window = self.window.read().unwrap();
+        window.as_ref().map(|w| Error::Maintenance(w.retry_after(Utc::now())))
+    }
+
+    /// What visitors get once the tunnels are closed
+    pub fn page(&self) -> Option<Response> {
+        let now = Utc::now();
+        let window = self.current().filter(|w| w.closes <= now)?;
+        let body = self
+            .page
+            .replace("{message}", &escape(&window.message))
+            .replace("{until}", &window.until.format("%H:%M UTC").to_string());
+        let headers = [(header::CONTENT_TYPE, "text/html; charset=utf-8"), (header::CACHE_CONTROL, "no-store")];
+        let mut response = (StatusCode::SERVICE_UNAVAILABLE, headers, body).into_response();
+        response.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(window.retry_after(now)));
+        Some(response)
+    }
+}
+
+/// Tell connected tunnels when they close, then close them
+async fn drain(events: broadcast::Sender<Event>, window: Window) {
+    let left = || (window.closes - Utc::now()).to_std().unwrap_or_default();
+    let notice = |closes_in| Event::Notice(Notice { message: window.message.clone(), closes_in: Some(closes_in) });
+    let total = left();
+    if !total.is_zero() {
+        let _ = events.send(notice(total.as_secs_f64().round() as u64));
+    }
+    for before in NOTICE_BEFORE.map(Duration::from_secs).into_iter().filter(|b| *b < total) {
+        tokio::time::sleep(left().saturating_sub(before)).await;
+        let _ = events.send(notice(before.as_secs()));
+    }
+    tokio::time::sleep(left()).await;
+    info!("Maintenance: closing {} tunnel(s)", events.receiver_count());
+    let _ = events.send(Event::Close);
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(closes_in: i64) -> Window {
+        let now = Utc::now();
+        Window {
+            message: "Upgrading <relay>".into(),
+            by: "alice".into(),
+            started: now,
+            closes: now + chrono::Duration::seconds(closes_in),
+            until: now + chrono::Duration::seconds(closes_in + 1800),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_maintenance() {
+        let maintenance = Maintenance::default();
+        let mut events = maintenance.subscribe();
+        assert!(maintenance.refusal().is_none());
+
+        // Tunnels are warned and keep serving until the drain is up
+        assert_eq!(maintenance.start(window(120)), None);
+        match events.recv().await.unwrap() {
+            Event::Notice(notice) => assert!((119..=120).contains(&notice.closes_in.unwrap()), "{:?}", notice),
+            Event::Close => panic!("closed before the drain was up"),
+        }
+        assert!(matches!(maintenance.refusal(), Some(Error::Maintenance(secs)) if secs > 1800));
+        assert!(maintenance.page().is_none());
+        assert!(maintenance.end().is_some());
+        assert!(maintenance.refusal().is_none());
+
+        // With no drain they close at once, and visitors get the page
+        maintenance.start(window(0));
+        assert!(matches!(events.recv().await.unwrap(), Event::Close));
+        let page = maintenance.page().unwrap();
+        assert_eq!(page.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(page.headers()[header::RETRY_AFTER].to_str().unwrap().parse::<u64>().unwrap() > 1700);
+        let body = axum::body::to_bytes(page.into_body(), usize::MAX).await.unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("Upgrading &lt;relay&gt;"));
+    }
+}
@@ -1,11 +1,17 @@
 //! Metrics Collection for ZTunnel Relay
 //!
 //! Provides atomic counters, latency histograms, and a
-//! Prometheus-compatible /metrics endpoint.
+//! Prometheus-compatible /metrics endpoint. `Metrics` is the relay's
+//! default `Telemetry` backend: it keeps the standard metrics and
+//! ignores names it doesn't render.
 
+use dashmap::DashMap;
+use std::fmt::Write;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use ztunnel_shared::telemetry::{self, metric, Labels, Telemetry};
+
+use crate::circuit_breaker::{CircuitState, CircuitStats};
 
 /// Relay-wide metrics
 #[derive(Clone)]
@@ -18,78 +24,91 @@ struct MetricsInner {
     total_requests: AtomicU64,
     /// Active tunnel count
     active_tunnels: AtomicU64,
+    /// Requests waiting on a client's response
+    pending_requests: AtomicU64,
     /// Status code counts
     status_2xx: AtomicU64,
     status_3xx: AtomicU64,
     status_4xx: AtomicU64,
     status_5xx: AtomicU64,
+    /// Count per exact status code, 100 to 599; a fixed array, so a
+    /// flood of odd codes can't grow it
+    by_code: [AtomicU64; CODES],
+    /// Requests over ZTUNNEL_SLOW_REQUEST_MS
+    slow_requests: AtomicU64,
     /// Total bytes in/out
     bytes_in: AtomicU64,
     bytes_out: AtomicU64,
     /// Latency tracking
-    latencies: Mutex<LatencyHistogram>,
+    latencies: LatencyHistogram,
     /// Per-subdomain metrics
-    subdomain_metrics: Mutex<std::collections::HashMap<String, SubdomainMetrics>>,
+    subdomain_metrics: DashMap<String, SubdomainMetrics>,
 }
 
-/// Latency histogram for percentile calculation
+/// Status codes counted one by one, from 100
+const CODES: usize = 500;
+
+/// Upper bounds of the latency buckets, in microseconds
+pub(crate) const BUCKETS_US: [u64; 16] = [
+    500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 500_000, 1_000_000, 2_500_000,
+    5_000_000, 10_000_000, 30_000_000, 60_000_000,
+];
+
+/// Latency histogram with fixed buckets, so recording is one atomic add
+/// and a scrape reads a fixed number of counters
 struct LatencyHistogram {
-    /// Recent latencies (ring buffer, microseconds)
-    values: Vec<u64>,
-    /// Write position
-    pos: usize,
-    /// Total count
-    count: u64,
-    /// Sum for average
-    sum: u64,
+    /// Count per bucket; the extra last one holds everything slower
+    buckets: [AtomicU64; BUCKETS_US.len() + 1],
+    /// Sum for average (microseconds)
+    sum: AtomicU64,
 }
 
 impl LatencyHistogram {
-    fn new(capacity: usize) -> Self {
-        Self {
-            values: vec![0; capacity],
-            pos: 0,
-            count: 0,
-            sum: 0,
-        }
+    fn new() -> Self {
+        Self { buckets: std::array::from_fn(|_| AtomicU64::new(0)), sum: AtomicU64::new(0) }
     }
 
-    fn record(&mut self, latency_us: u64) {
-        self.values[self.pos] = latency_us;
-        self.pos = (self.pos + 1) % self.values.len();
-        self.count += 1;
-        self.sum += latency_us;
+    fn record(&self, latency_us: u64) {
+        let bucket = BUCKETS_US.partition_point(|&bound| bound < latency_us);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(latency_us, Ordering::Relaxed);
     }
 
-    fn percentile(&self, p: f64) -> u64 {
-        let count = self.count.min(self.values.len() as u64) as usize;
-        if count == 0 {
-            return 0;
-        }
-
-        let mut sorted: Vec<u64> = if self.count < self.values.len() as u64 {
-            self.values[..count].to_vec()
-        } else {
-            self.values.clone()
-        };
-        sorted.sort_unstable();
-
-        let idx = ((count as f64 * p / 100.0) as usize).min(count - 1);
-        sorted[idx]
+    fn counts(&self) -> [u64; BUCKETS_US.len() + 1] {
+        std::array::from_fn(|i| self.buckets[i].load(Ordering::Relaxed))
     }
 
-    fn average(&self) -> u64 {
-        if self.count == 0 { 0 } else { self.sum / self.count }
+    /// Estimate from bucket counts, interpolating within the bucket the
+    /// percentile falls in. The overflow bucket reports its lower bound.
+    fn percentile(counts: &[u64], p: f64) -> u64 {
+        let total: u64 = counts.iter().sum();
+        if total == 0 {
+            return 0;
+        }
+        let rank = ((total as f64 * p / 100.0).ceil() as u64).clamp(1, total);
+        let mut seen = 0;
+        for (i, &count) in counts.iter().enumerate() {
+            if seen + count >= rank {
+                let lower = if i == 0 { 0 } else { BUCKETS_US[i - 1] };
+                let Some(&upper) = BUCKETS_US.get(i) else {
+                    return lower;
+                };
+                let within = (rank - seen) as f64 / count as f64;
+                return lower + ((upper - lower) as f64 * within) as u64;
+            }
+            seen += count;
+        }
+        BUCKETS_US[BUCKETS_US.len() - 1]
     }
 }
 
 /// Per-subdomain metrics
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Default)]
 pub struct SubdomainMetrics {
-    pub requests: u64,
-    pub errors: u64,
-    pub bytes_in: u64,
-    pub bytes_out: u64,
+    pub requests: AtomicU64,
+    pub errors: AtomicU64,
+    pub bytes_in: AtomicU64,
+    pub bytes_out: AtomicU64,
 }
 
 impl Metrics {
@@ -98,72 +117,32 @@ impl Metrics {
             inner: Arc::new(MetricsInner {
                 total_requests: AtomicU64::new(0),
                 active_tunnels: AtomicU64::new(0),
+                pending_requests: AtomicU64::new(0),
                 status_2xx: AtomicU64::new(0),
                 status_3xx: AtomicU64::new(0),
                 status_4xx: AtomicU64::new(0),
                 status_5xx: AtomicU64::new(0),
+                by_code: std::array::from_fn(|_| AtomicU64::new(0)),
+                slow_requests: AtomicU64::new(0),
                 bytes_in: AtomicU64::new(0),
                 bytes_out: AtomicU64::new(0),
-                latencies: Mutex::new(LatencyHistogram::new(10000)),
-                subdomain_metrics: Mutex::new(std::collections::HashMap::new()),
+                latencies: LatencyHistogram::new(),
+                subdomain_metrics: DashMap::new(),
             }),
         }
     }
 
-    /// Record a completed request
-    pub async fn record_request(
-        &self,
-        subdomain: &str,
-        status: u16,
-        latency_us: u64,
-        bytes_in: u64,
-        bytes_out: u64,
-    ) {
-        self.inner.total_requests.fetch_add(1, Ordering::Relaxed);
-        self.inner.bytes_in.fetch_add(bytes_in, Ordering::Relaxed);
-        self.inner.bytes_out.fetch_add(bytes_out, Ordering::Relaxed);
-
-        match status / 100 {
-            2 => { self.inner.status_2xx.fetch_add(1, Ordering::Relaxed); }
-            3 => { self.inner.status_3xx.fetch_add(1, Ordering::Relaxed); }
-            4 => { self.inner.status_4xx.fetch_add(1, Ordering::Relaxed); }
-            5 => { self.inner.status_5xx.fetch_add(1, Ordering::Relaxed); }
-            _ => {}
-        }
-
-        self.inner.latencies.lock().await.record(latency_us);
-
-        // Per-subdomain
-        let mut subs = self.inner.subdomain_metrics.lock().await;
-        let entry = subs.entry(subdomain.to_string()).or_default();
-        entry.requests += 1;
-        if status >= 400 {
-            entry.errors += 1;
-        }
-        entry.bytes_in += bytes_in;
-        entry.bytes_out += bytes_out;
-    }
-
-    /// Increment active tunnel count
-    pub fn tunnel_opened(&self) {
-        self.inner.active_tunnels.fetch_add(1, Ordering::Relaxed);
-    }
-
-    /// Decrement active tunnel count
-    pub fn tunnel_closed(&self) {
-        self.inner.active_tunnels.fetch_sub(1, Ordering::Relaxed);
-    }
-
     /// Generate Prometheus-format metrics text
-    pub async fn to_prometheus(&self) -> String {
-        let lat = self.inner.latencies.lock().await;
-        let p50 = lat.percentile(50.0);
-        let p95 = lat.percentile(95.0);
-        let p99 = lat.percentile(99.0);
-        let avg = lat.average();
-        drop(lat);
-
-        format!(
+    pub fn to_prometheus(&self) -> String {
+        let counts = self.inner.latencies.counts();
+        let total: u64 = counts.iter().sum();
+        let sum = self.inner.latencies.sum.load(Ordering::Relaxed);
+        let p50 = LatencyHistogram::percentile(&counts, 50.0);
+        let p95 = LatencyHistogram::percentile(&counts, 95.0);
+        let p99 = LatencyHistogram::percentile(&counts, 99.0);
+        let avg = sum.checked_div(total).unwrap_or(0);
+
+        let mut text = format!(
 r#"# HELP ztunnel_requests_total Total number of requests processed
 # TYPE ztunnel_requests_total counter
 ztunnel_requests_total {}
@@ -172,6 +151,10 @@ ztunnel_requests_total {}
 # TYPE ztunnel_active_tunnels gauge
 ztunnel_active_tunnels {}
 
+# HELP ztunnel_pending_requests Requests waiting for a tunnel client's response
+# TYPE ztunnel_pending_requests gauge
+ztunnel_pending_requests {}
+
 # HELP ztunnel_requests_by_status Requests by HTTP status class
 # TYPE ztunnel_requests_by_status counter
 ztunnel_requests_by_status{{status="2xx"}} {}
@@ -179,6 +162,10 @@ ztunnel_requests_by_status{{status="3xx"}} {}
 ztunnel_requests_by_status{{status="4xx"}} {}
 ztunnel_requests_by_status{{status="5xx"}} {}
 
+# HELP ztunnel_slow_requests_total Requests slower than the slow request threshold
+# TYPE ztunnel_slow_requests_total counter
+ztunnel_slow_requests_total {}
+
 # HELP ztunnel_bytes_total Total bytes transferred
 # TYPE ztunnel_bytes_total counter
 ztunnel_bytes_total{{direction="in"}} {}
@@ -193,13 +180,222 @@ ztunnel_latency_us_avg {}
 "#,
             self.inner.total_requests.load(Ordering::Relaxed),
             self.inner.active_tunnels.load(Ordering::Relaxed),
+            self.inner.pending_requests.load(Ordering::Relaxed),
             self.inner.status_2xx.load(Ordering::Relaxed),
             self.inner.status_3xx.load(Ordering::Relaxed),
             self.inner.status_4xx.load(Ordering::Relaxed),
             self.inner.status_5xx.load(Ordering::Relaxed),
+            self.inner.slow_requests.load(Ordering::Relaxed),
             self.inner.bytes_in.load(Ordering::Relaxed),
             self.inner.bytes_out.load(Ordering::Relaxed),
             p50, p95, p99, avg,
-        )
+        );
+
+        text.push_str("\n# HELP ztunnel_requests_by_code Requests by exact HTTP status code\n");
+        text.push_str("# TYPE ztunnel_requests_by_code counter\n");
+        for (i, count) in self.inner.by_code.iter().enumerate() {
+            let count = count.load(Ordering::Relaxed);
+            if count > 0 {
+                let _ = writeln!(text, "ztunnel_requests_by_code{{status=\"{}\"}} {}", i + 100, count);
+            }
+        }
+
+        text.push_str("\n# HELP ztunnel_request_duration_seconds Request latency\n");
+        text.push_str("# TYPE ztunnel_request_duration_seconds histogram\n");
+        let mut cumulative = 0;
+        for (bound, count) in BUCKETS_US.iter().zip(counts) {
+            cumulative += count;
+            let le = *bound as f64 / 1_000_000.0;
+            let _ = writeln!(text, "ztunnel_request_duration_seconds_bucket{{le=\"{}\"}} {}", le, cumulative);
+        }
+        let _ = writeln!(text, "ztunnel_request_duration_seconds_bucket{{le=\"+Inf\"}} {}", total);
+        let _ = writeln!(text, "ztunnel_request_duration_seconds_sum {}", sum as f64 / 1_000_000.0);
+        let _ = writeln!(text, "ztunnel_request_duration_seconds_count {}", total);
+        text
+    }
+
+    /// Counters for one tunnel, created on first use. Existing tunnels
+    /// only take a shard read lock.
+    fn subdomain(&self, name: &str) -> dashmap::mapref::one::Ref<'_, String, SubdomainMetrics> {
+        if let Some(entry) = self.inner.subdomain_metrics.get(name) {
+            return entry;
+        }
+        self.inner.subdomain_metrics.entry(name.to_string()).or_default().downgrade()
+    }
+}
+
+/// Append each tunnel's circuit breaker state and history
+pub fn render_circuits(text: &mut String, circuits: &[(String, CircuitStats)]) {
+    text.push_str("\n# HELP ztunnel_circuit_state Circuit breaker state per tunnel, 1 for the current one\n");
+    text.push_str("# TYPE ztunnel_circuit_state gauge\n");
+    circuit_lines(text, circuits, "ztunnel_circuit_state", "state", |s, state| u8::from(s.state == state).to_string());
+
+    text.push_str("\n# HELP ztunnel_circuit_transitions_total Times a tunnel's circuit entered each state\n");
+    text.push_str("# TYPE ztunnel_circuit_transitions_total counter\n");
+    circuit_lines(text, circuits, "ztunnel_circuit_transitions_total", "to", |s, state| {
+        s.transitions.get(state).to_string()
+    });
+
+    text.push_str("\n# HELP ztunnel_circuit_state_seconds_total Time a tunnel's circuit spent in each state\n");
+    text.push_str("# TYPE ztunnel_circuit_state_seconds_total counter\n");
+    circuit_lines(text, circuits, "ztunnel_circuit_state_seconds_total", "state", |s, state| {
+        format!("{:.3}", s.time_in_state.get(state))
+    });
+}
+
+/// A line per tunnel and state
+fn circuit_lines(
+    text: &mut String,
+    circuits: &[(String, CircuitStats)],
+    name: &str,
+    label: &str,
+    value: impl Fn(&CircuitStats, CircuitState) -> String,
+) {
+    for (tunnel, stats) in circuits {
+        for state in CircuitState::ALL {
+            let value = value(stats, state);
+            let _ = writeln!(text, "{}{{tunnel=\"{}\",{}=\"{}\"}} {}", name, tunnel, label, state.name(), value);
+        }
+    }
+}
+
+impl Telemetry for Metrics {
+    fn counter(&self, name: &str, labels: Labels<'_>, delta: u64) {
+        let tunnel = telemetry::label(labels, "tunnel");
+        match name {
+            metric::REQUESTS => {
+                self.inner.total_requests.fetch_add(delta, Ordering::Relaxed);
+                let status: u16 = telemetry::label(labels, "status").and_then(|s| s.parse().ok()).unwrap_or(0);
+                let class = match status / 100 {
+                    2 => Some(&self.inner.status_2xx),
+                    3 => Some(&self.inner.status_3xx),
+                    4 => Some(&self.inner.status_4xx),
+                    5 => Some(&self.inner.status_5xx),
+                    _ => None,
+                };
+                if let Some(class) = class {
+                    class.fetch_add(delta, Ordering::Relaxed);
+                }
+                if let Some(count) = (status as usize).checked_sub(100).and_then(|i| self.inner.by_code.get(i)) {
+                    count.fetch_add(delta, Ordering::Relaxed);
+                }
+                if let Some(tunnel) = tunnel {
+                    let entry = self.subdomain(tunnel);
+                    entry.requests.fetch_add(delta, Ordering::Relaxed);
+                    if status >= 400 {
+                        entry.errors.fetch_add(delta, Ordering::Relaxed);
+                    }
+                }
+            }
+            metric::BYTES => {
+                let outbound = telemetry::label(labels, "direction") == Some("out");
+                let total = if outbound { &self.inner.bytes_out } else { &self.inner.bytes_in };
+                total.fetch_add(delta, Ordering::Relaxed);
+                if let Some(tunnel) = tunnel {
+                    let entry = self.subdomain(tunnel);
+                    let bytes = if outbound { &entry.bytes_out } else { &entry.bytes_in };
+                    bytes.fetch_add(delta, Ordering::Relaxed);
+                }
+            }
+            metric::SLOW_REQUESTS => {
+                self.inner.slow_requests.fetch_add(delta, Ordering::Relaxed);
+            }
+            _ => {}
+        }
+    }
+
+    fn gauge(&self, name: &str, _: Labels<'_>, value: f64) {
+        let gauge = match name {
+            metric::ACTIVE_TUNNELS => &self.inner.active_tunnels,
+            metric::PENDING_REQUESTS => &self.inner.pending_requests,
+            _ => return,
+        };
+        gauge.store(value as u64, Ordering::Relaxed);
+    }
+
+    fn histogram(&self, name: &str, _: Labels<'_>, value: f64) {
+        if name == metric::REQUEST_DURATION {
+            self.inner.latencies.record((value * 1_000_000.0) as u64);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::circuit_breaker::{CircuitBreaker, CircuitBreakerConfig};
+    use std::time::Duration;
+
+    #[test]
+    fn test_standard_metrics_render() {
+        let metrics = Metrics::new();
+        metrics.request("demo", 200, Duration::from_millis(2), 100, 300);
+        metrics.request("demo", 502, Duration::from_millis(4), 50, 0);
+        metrics.gauge(metric::ACTIVE_TUNNELS, &[], 1.0);
+        metrics.gauge(metric::PENDING_REQUESTS, &[], 4.0);
+        metrics.counter(metric::SLOW_REQUESTS, &[("tunnel", "demo")], 1);
+
+        let text = metrics.to_prometheus();
+        for line in [
+            "ztunnel_requests_total 2",
+            "ztunnel_active_tunnels 1",
+            "ztunnel_pending_requests 4",
+            "ztunnel_slow_requests_total 1",
+            "ztunnel_requests_by_status{status=\"5xx\"} 1",
+            "ztunnel_bytes_total{direction=\"in\"} 150",
+            "ztunnel_bytes_total{direction=\"out\"} 300",
+            "ztunnel_latency_us_avg 3000",
+            "ztunnel_request_duration_seconds_bucket{le=\"0.001\"} 0",
+            "ztunnel_request_duration_seconds_bucket{le=\"0.0025\"} 1",
+            "ztunnel_request_duration_seconds_bucket{le=\"+Inf\"} 2",
+            "ztunnel_request_duration_seconds_count 2",
+        ] {
+            assert!(text.lines().any(|l| l == line), "missing {}", line);
+        }
+        let demo = metrics.subdomain("demo");
+        let load = |n: &AtomicU64| n.load(Ordering::Relaxed);
+        assert_eq!((load(&demo.requests), load(&demo.errors), load(&demo.bytes_in)), (2, 1, 150));
+
+        // Exact codes, apart from ones no status can have
+        for status in [401, 401, 404, 999] {
+            metrics.request("demo", status, Duration::from_millis(1), 0, 0);
+        }
+        let text = metrics.to_prometheus();
+        let codes: Vec<_> = text.lines().filter(|l| l.starts_with("ztunnel_requests_by_code{")).collect();
+        assert_eq!(codes, [
+            "ztunnel_requests_by_code{status=\"200\"} 1",
+            "ztunnel_requests_by_code{status=\"401\"} 2",
+            "ztunnel_requests_by_code{status=\"404\"} 1",
+            "ztunnel_requests_by_code{status=\"502\"} 1",
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_circuit_metrics_render() {
+        let config = CircuitBreakerConfig { failure_threshold: 1, ..Default::default() };
+        let cb = CircuitBreaker::new(config);
+        cb.record_failure().await;
+        let mut text = String::new();
+        render_circuits(&mut text, &[("demo".to_string(), cb.stats().await)]);
+        for line in [
+            "ztunnel_circuit_state{tunnel=\"demo\",state=\"open\"} 1",
+            "ztunnel_circuit_state{tunnel=\"demo\",state=\"closed\"} 0",
+            "ztunnel_circuit_transitions_total{tunnel=\"demo\",to=\"open\"} 1",
+            "ztunnel_circuit_transitions_total{tunnel=\"demo\",to=\"half_open\"} 0",
+        ] {
+            assert!(text.lines().any(|l| l == line), "missing {}", line);
+        }
+        assert!(text.contains("ztunnel_circuit_state_seconds_total{tunnel=\"demo\",state=\"closed\"} 0.0"));
+    }
+
+    #[test]
+    fn test_percentile_from_buckets() {
+        let mut counts = [0; BUCKETS_US.len() + 1];
+        assert_eq!(LatencyHistogram::percentile(&counts, 50.0), 0);
+        counts[0] = 100;
+        assert_eq!(LatencyHistogram::percentile(&counts, 50.0), 250);
+        counts[BUCKETS_US.len()] = 100;
+        assert_eq!(LatencyHistogram::percentile(&counts, 99.0), 60_000_000);
+        assert_eq!(LatencyHistogram::percentile(&counts, 25.0), 250);
     }
 }
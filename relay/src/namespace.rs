@@ -0,0 +1,149 @@
+//! Subdomain namespaces per account
+//!
+//! `ZTUNNEL_NAMESPACES=acme=TOKEN,beta-*=TOKEN2` gives each token a
+//! prefix: namespace `acme` is `acme` itself and every `acme-*` name.
+//! Only a client registering with the token gets those names, and its
+//! tunnels all land inside the namespace: `api` becomes `acme-api`, and
+//! a random name becomes `acme-<random>`. Where namespaces nest, the
+//! longest prefix owns the name.
+
+use anyhow::Result;
+use ztunnel_shared::{validate, Error};
+
+#[derive(Debug, Clone)]
+struct Namespace {
+    prefix: String,
+    token: String,
+}
+
+impl Namespace {
+    fn contains(&self, name: &str) -> bool {
+        name.strip_prefix(self.prefix.as_str()).is_some_and(|rest| rest.is_empty() || rest.starts_with('-'))
+    }
+}
+
+/// The namespaces configured on this relay
+#[derive(Debug, Clone, Default)]
+pub struct Namespaces(Vec<Namespace>);
+
+impl Namespaces {
+    pub fn from_env() -> Result<Self> {
+        Self::parse(&std::env::var("ZTUNNEL_NAMESPACES").unwrap_or_default())
+    }
+
+    /// `prefix=token` pairs separated by commas; `acme-*` and `acme`
+    /// are the same namespace
+    pub fn parse(spec: &str) -> Result<Self> {
+        let mut namespaces: Vec<Namespace> = Vec::new();
+        for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let Some((prefix, token)) = entry.split_once('=') else {
+                anyhow::bail!("Invalid ZTUNNEL_NAMESPACES entry '{}', expected prefix=token", entry);
+            };
+            let prefix = prefix.trim().trim_end_matches('*').trim_end_matches('-');
+            validate::check_subdomain(prefix).map_err(|e| anyhow::anyhow!("Invalid namespace: {}", e))?;
+            if token.is_empty() {
+                anyhow::bail!("Namespace '{}' has no token", prefix);
+            }
+            if namespaces.iter().any(|n| n.prefix == prefix) {
+                anyhow::bail!("Namespace '{}' is configured twice", prefix);
+            }
+            namespaces.push(Namespace { prefix: prefix.to_string(), token: token.to_string() });
+        }
+        Ok(Self(namespaces))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The namespace `name` falls in
+    fn owner(&self, name: &str) -> Option<&Namespace> {
+        self.0.iter().filter(|n| n.contains(name)).max_by_key(|n| n.prefix.len())
+    }
+
+    /// The namespace registering with `token` places tunnels in
+    fn of_token(&self, token: &str) -> Option<&Namespace> {
+        self.0.iter().find(|n| constant_time_eq(n.token.as_bytes(), token.as_bytes()))
+    }
+
+    /// The account a registration with `token` belongs to
+    pub fn account(&self, token: Option<&str>) -> Option<&str> {
+        token.and_then(|t| self.of_token(t)).map(|n| n.prefix.as_str())
+    }
+
+    /// The subdomain a registration gets before conflicts are resolved,
+    /// `None` for a random one outside any namespace
+    pub fn claim(&self, requested: Option<&str>, token: Option<&str>) -> Result<Option<String>, Error> {
+        let ours = token.and_then(|t| self.of_token(t));
+        let Some(requested) = requested else {
+            return Ok(ours.map(|n| format!("{}-{}", n.prefix, crate::gen_subdomain_short())));
+        };
+        let name = match ours {
+            Some(n) if !n.contains(requested) => format!("{}-{}", n.prefix, requested),
+            _ => requested.to_string(),
+        };
+        if name.len() > validate::MAX_LABEL_LEN {
+            return Err(Error::Tunnel(format!("Subdomain '{}' is longer than 63 characters", name)));
+        }
+        match self.owner(&name) {
+            Some(owner) if !owned_by(owner, token) => Err(Error::Tunnel(format!(
+                "Subdomain '{}' is in the '{}-*' namespace reserved for another account",
+                name, owner.prefix
+            ))),
+            _ => Ok(Some(name)),
+        }
+    }
+
+    /// Whether a registration with `token` may hold `name` as it is,
+    /// for names the relay makes up rather than the client asks for
+    pub fn allows(&self, name: &str, token: Option<&str>) -> bool {
+        self.owner(name).is_none_or(|owner| owned_by(owner, token))
+    }
+}
+
+fn owned_by(namespace: &Namespace, token: Option<&str>) -> bool {
+    token.is_some_and(|t| constant_time_eq(namespace.token.as_bytes(), t.as_bytes()))
+}
+
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_namespace_claims() {
+        let ns = Namespaces::parse("acme-*=secret, acme-labs=labs").unwrap();
+
+        assert_eq!(ns.claim(Some("api"), Some("secret")).unwrap().as_deref(), Some("acme-api"));
+        assert_eq!(ns.claim(Some("acme-api"), Some("secret")).unwrap().as_deref(), Some("acme-api"));
+        assert_eq!(ns.claim(Some("acme"), Some("secret")).unwrap().as_deref(), Some("acme"));
+        assert!(ns.claim(None, Some("secret")).unwrap().unwrap().starts_with("acme-"));
+
+        // Someone else's namespace, nested or not
+        assert!(ns.claim(Some("acme-api"), None).is_err());
+        assert!(ns.claim(Some("acme-api"), Some("wrong")).is_err());
+        assert!(ns.claim(Some("acme-labs-db"), Some("secret")).is_err());
+        assert_eq!(ns.claim(Some("db"), Some("labs")).unwrap().as_deref(), Some("acme-labs-db"));
+
+        // Names outside every namespace are anyone's
+        assert_eq!(ns.claim(Some("acmeco"), None).unwrap().as_deref(), Some("acmeco"));
+        assert_eq!(ns.claim(Some("blog"), Some("wrong")).unwrap().as_deref(), Some("blog"));
+        assert_eq!(ns.claim(None, None).unwrap(), None);
+
+        assert!(ns.allows("acme-api-1f3", Some("secret")));
+        assert!(ns.allows("blog-1f3", None));
+        assert!(!ns.allows("acme-1f3", None));
+        assert!(!ns.allows("acme-labs-1f3", Some("secret")));
+
+        assert!(Namespaces::parse("acme").is_err());
+        assert!(Namespaces::parse("Acme=x").is_err());
+        assert!(Namespaces::parse("acme=x,acme-*=y").is_err());
+    }
+}
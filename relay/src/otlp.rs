@@ -0,0 +1,251 @@
+//! OTLP metrics export
+//!
+//! With the `otlp` feature and ZTUNNEL_OTLP_ENDPOINT set, the relay
+//! pushes its metrics to an OpenTelemetry collector over OTLP/HTTP
+//! (JSON encoding), for relays nothing can scrape: behind NAT, or on
+//! platforms that only allow outbound connections. /metrics keeps
+//! working alongside.
+//!
+//! - ZTUNNEL_OTLP_ENDPOINT: collector base URL such as
+//!   `http://collector:4318`; `/v1/metrics` is appended unless present
+//! - ZTUNNEL_OTLP_HEADERS: `key=value,key=value`, e.g. an API key
+//! - ZTUNNEL_OTLP_INTERVAL: seconds between pushes, 60 by default
+//!
+//! Sums and histograms are cumulative since the relay started, so a
+//! failed push loses nothing but resolution.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use tracing::{debug, warn};
+
+use crate::metrics::BUCKETS_US;
+use ztunnel_shared::telemetry::{Labels, Telemetry};
+
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Where and how often to push
+#[derive(Debug, Clone)]
+pub struct OtlpConfig {
+    /// Full `/v1/metrics` URL
+    pub endpoint: String,
+    pub headers: Vec<(String, String)>,
+    pub interval: Duration,
+}
+
+impl OtlpConfig {
+    /// `None` when ZTUNNEL_OTLP_ENDPOINT isn't set
+    pub fn from_env() -> Result<Option<Self>> {
+        let Ok(endpoint) = std::env::var("ZTUNNEL_OTLP_ENDPOINT") else {
+            return Ok(None);
+        };
+        let endpoint = endpoint.trim_end_matches('/');
+        let endpoint = if endpoint.ends_with("/v1/metrics") {
+            endpoint.to_string()
+        } else {
+            format!("{}/v1/metrics", endpoint)
+        };
+        let headers = std::env::var("ZTUNNEL_OTLP_HEADERS").unwrap_or_default();
+        let headers = parse_headers(&headers).context("Invalid ZTUNNEL_OTLP_HEADERS")?;
+        let interval = match std::env::var("ZTUNNEL_OTLP_INTERVAL") {
+            Ok(secs) => {
+                let secs: u64 = secs.parse().context("Invalid ZTUNNEL_OTLP_INTERVAL")?;
+                Duration::from_secs(secs.max(1))
+            }
+            Err(_) => DEFAULT_INTERVAL,
+        };
+        Ok(Some(Self { endpoint, headers, interval }))
+    }
+}
+
+/// `key=value,key=value`, as OTEL_EXPORTER_OTLP_HEADERS
+fn parse_headers(spec: &str) -> Result<Vec<(String, String)>> {
+    spec.split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').with_context(|| format!("'{}' is not key=value", pair))?;
+            Ok((key.trim().to_string(), value.trim().to_string()))
+        })
+        .collect()
+}
+
+/// One series: a metric name and its label values
+type SeriesKey = (String, Vec<(String, String)>);
+
+enum Point {
+    Sum(u64),
+    Gauge(f64),
+    /// Count per `BUCKETS_US` bucket plus overflow, and the sum
+    Histogram(Vec<u64>, f64),
+}
+
+/// `Telemetry` backend that aggregates in memory until the next push
+#[derive(Clone)]
+pub struct OtlpExporter {
+    series: Arc<Mutex<BTreeMap<SeriesKey, Point>>>,
+    start: SystemTime,
+}
+
+impl OtlpExporter {
+    pub fn new() -> Self {
+        Self { series: Arc::default(), start: SystemTime::now() }
+    }
+
+    fn update(&self, name: &str, labels: Labels<'_>, new: impl FnOnce() -> Point, apply: impl FnOnce(&mut Point)) {
+        let key = (name.to_string(), labels.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect());
+        let mut series = self.series.lock().unwrap();
+        apply(series.entry(key).or_insert_with(new));
+    }
+
+    /// An `ExportMetricsServiceRequest` with every series as of `now`
+    fn payload(&self, now: SystemTime) -> Value {
+        let nanos = |t: SystemTime| t.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos().to_string();
+        let (start, now) = (nanos(self.start), nanos(now));
+        let bounds: Vec<f64> = BUCKETS_US.iter().map(|&us| us as f64 / 1_000_000.0).collect();
+
+        let series = self.series.lock().unwrap();
+        let mut metrics: Vec<Value> = Vec::new();
+        for ((name, labels), point) in series.iter() {
+            let attributes: Vec<Value> = labels
+                .iter()
+                .map(|(k, v)| json!({ "key": k, "value": { "stringValue": v } }))
+                .collect();
+            let (kind, data) = match point {
+                Point::Sum(value) => (
+                    "sum",
+                    json!({
+                        "aggregationTemporality": 2,
+                        "isMonotonic": true,
+                        "dataPoints": [{ "attributes": attributes, "startTimeUnixNano": start, "timeUnixNano": now, "asInt": value.to_string() }],
+                    }),
+                ),
+                Point::Gauge(value) => (
+                    "gauge",
+                    json!({ "dataPoints": [{ "attributes": attributes, "timeUnixNano": now, "asDouble": value }] }),
+                ),
+                Point::Histogram(counts, sum) => (
+                    "histogram",
+                    json!({
+                        "aggregationTemporality": 2,
+                        "dataPoints": [{
+                            "attributes": attributes,
+                            "startTimeUnixNano": start,
+                            "timeUnixNano": now,
+                            "count": counts.iter().sum::<u64>().to_string(),
+                            "sum": sum,
+                            "bucketCounts": counts.iter().map(u64::to_string).collect::<Vec<_>>(),
+                            "explicitBounds": bounds,
+                        }],
+                    }),
+                ),
+            };
+            // Series of one metric share its entry, as the collector expects
+            match metrics.last_mut() {
+                Some(last) if last["name"] == name.as_str() => {
+                    if let (Some(points), Some(more)) =
+                        (last[kind]["dataPoints"].as_array_mut(), data["dataPoints"].as_array())
+                    {
+                        points.extend(more.iter().cloned());
+                    }
+                }
+                _ => metrics.push(json!({ "name": name, kind: data })),
+            }
+        }
+
+        json!({
+            "resourceMetrics": [{
+                "resource": { "attributes": [
+                    { "key": "service.name", "value": { "stringValue": "ztunnel-relay" } },
+                    { "key": "service.version", "value": { "stringValue": env!("CARGO_PKG_VERSION") } },
+                ] },
+                "scopeMetrics": [{ "scope": { "name": "ztunnel-relay" }, "metrics": metrics }],
+            }]
+        })
+    }
+
+    /// Push every `config.interval`, forever
+    pub async fn run(self, config: OtlpConfig) {
+        let http = reqwest::Client::new();
+        let mut timer = tokio::time::interval(config.interval);
+        timer.tick().await;
+        loop {
+            timer.tick().await;
+            let mut request = http.post(&config.endpoint).json(&self.payload(SystemTime::now()));
+            for (key, value) in &config.headers {
+                request = request.header(key, value);
+            }
+            match request.timeout(config.interval).send().await.and_then(|r| r.error_for_status()) {
+                Ok(_) => debug!("Pushed metrics to {}", config.endpoint),
+                Err(e) => warn!("OTLP export to {} failed: {}", config.endpoint, e),
+            }
+        }
+    }
+}
+
+impl Telemetry for OtlpExporter {
+    fn counter(&self, name: &str, labels: Labels<'_>, delta: u64) {
+        self.update(name, labels, || Point::Sum(0), |point| {
+            if let Point::Sum(total) = point {
+                *total += delta;
+            }
+        });
+    }
+
+    fn gauge(&self, name: &str, labels: Labels<'_>, value: f64) {
+        self.update(name, labels, || Point::Gauge(value), |point| *point = Point::Gauge(value));
+    }
+
+    fn histogram(&self, name: &str, labels: Labels<'_>, value: f64) {
+        let bucket = BUCKETS_US.partition_point(|&bound| (bound as f64) < value * 1_000_000.0);
+        self.update(name, labels, || Point::Histogram(vec![0; BUCKETS_US.len() + 1], 0.0), |point| {
+            if let Point::Histogram(counts, sum) = point {
+                counts[bucket] += 1;
+                *sum += value;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ztunnel_shared::telemetry::metric;
+
+    #[test]
+    fn test_otlp_payload() {
+        let exporter = OtlpExporter::new();
+        exporter.request("demo", 200, Duration::from_millis(2), 100, 300);
+        exporter.request("demo", 200, Duration::from_millis(40), 50, 0);
+        exporter.request("api", 502, Duration::from_millis(2), 0, 0);
+        exporter.gauge(metric::ACTIVE_TUNNELS, &[], 2.0);
+
+        let payload = exporter.payload(SystemTime::now());
+        let metrics = payload["resourceMetrics"][0]["scopeMetrics"][0]["metrics"].as_array().unwrap();
+        let find = |name: &str| metrics.iter().find(|m| m["name"] == name).unwrap();
+
+        let requests = &find(metric::REQUESTS)["sum"];
+        assert_eq!(requests["isMonotonic"], true);
+        let points = requests["dataPoints"].as_array().unwrap();
+        assert_eq!(points.len(), 2);
+        let demo = points.iter().find(|p| p["attributes"][1]["value"]["stringValue"] == "200").unwrap();
+        assert_eq!(demo["asInt"], "2");
+
+        assert_eq!(find(metric::ACTIVE_TUNNELS)["gauge"]["dataPoints"][0]["asDouble"], 2.0);
+
+        let duration = &find(metric::REQUEST_DURATION)["histogram"]["dataPoints"];
+        let demo = duration.as_array().unwrap().iter().find(|p| p["attributes"][0]["value"]["stringValue"] == "demo").unwrap();
+        assert_eq!(demo["count"], "2");
+        assert_eq!(demo["bucketCounts"][2], "1");
+        assert_eq!(demo["explicitBounds"][1], 0.001);
+
+        assert_eq!(
+            parse_headers("api-key=abc, x-tenant = t1").unwrap(),
+            [("api-key".into(), "abc".into()), ("x-tenant".into(), "t1".into())]
+        );
+        assert!(parse_headers("nonsense").is_err());
+    }
+}
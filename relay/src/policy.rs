@@ -3,6 +3,15 @@
 //! Lightweight rule matching for blocking, redirecting,
 //! rate-limiting, or requiring auth per path/method.
 
+use chrono::{DateTime, Utc};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::net::IpAddr;
+use ztunnel_shared::glob::matches_glob;
+use ztunnel_shared::protocol;
+use ztunnel_shared::schedule::{Schedule, Zone};
+
+use crate::ip_filter::IpMatcher;
+
 /// Action to take when a rule matches
 #[derive(Debug, Clone)]
 pub enum PolicyAction {
@@ -23,14 +32,56 @@ pub enum PolicyAction {
 /// A single traffic policy rule
 #[derive(Debug, Clone)]
 pub struct PolicyRule {
+    /// Hash of the rule as written, the same wherever it sits in the list
+    pub id: u64,
     /// Path glob pattern (e.g., "/admin/*", "/api/v1/**")
     pub path_pattern: String,
     /// Optional method filter (None = all methods)
     pub method: Option<String>,
+    /// Visitors it applies to (empty = everyone)
+    pub from: Vec<IpMatcher>,
+    /// Visitors it doesn't apply to
+    pub except_from: Vec<IpMatcher>,
+    /// When it applies (None = always)
+    pub during: Option<Schedule>,
+    /// When it doesn't
+    pub except_during: Option<Schedule>,
+    /// Timezone the schedules are in
+    pub timezone: Zone,
     /// Action to take
     pub action: PolicyAction,
 }
 
+impl From<&protocol::Policy> for PolicyRule {
+    fn from(policy: &protocol::Policy) -> Self {
+        let action = match &policy.action {
+            protocol::PolicyAction::Allow => PolicyAction::Allow,
+            protocol::PolicyAction::Block { status } => PolicyAction::Block(*status),
+            protocol::PolicyAction::Redirect { to } => PolicyAction::Redirect(to.clone()),
+            protocol::PolicyAction::RequireAuth => PolicyAction::RequireAuth,
+            protocol::PolicyAction::RateLimit { per_minute } => PolicyAction::RateLimit(*per_minute),
+            protocol::PolicyAction::AddHeader { name, value } => PolicyAction::AddHeader(name.clone(), value.clone()),
+        };
+        // Registration validates these, so none are dropped here
+        let sources = |list: &[String]| list.iter().filter_map(|s| IpMatcher::parse(s)).collect();
+        let windows = |spec: &Option<String>| spec.as_deref().and_then(|s| Schedule::parse(s).ok());
+        let timezone = policy.timezone.as_deref().and_then(|tz| Zone::parse(tz).ok());
+        let mut id = DefaultHasher::new();
+        policy.hash(&mut id);
+        Self {
+            id: id.finish(),
+            path_pattern: policy.path.clone(),
+            method: policy.method.clone(),
+            from: sources(&policy.from),
+            except_from: sources(&policy.except_from),
+            during: windows(&policy.during),
+            except_during: windows(&policy.except_during),
+            timezone: timezone.unwrap_or_default(),
+            action,
+        }
+    }
+}
+
 /// Policy engine that evaluates rules in order
 #[derive(Debug, Clone, Default)]
 pub struct PolicyEngine {
@@ -47,111 +98,128 @@ impl PolicyEngine {
         self.rules.push(rule);
     }
 
-    /// Evaluate request against rules. Returns first matching action.
-    pub fn evaluate(&self, path: &str, method: &str) -> PolicyAction {
-        for rule in &self.rules {
+    /// Compile the rules a client sent when registering
+    pub fn from_policies(policies: &[protocol::Policy]) -> Self {
+        Self { rules: policies.iter().map(PolicyRule::from).collect() }
+    }
+
+    /// First rule matching a request. `visitor` is the address from
+    /// `TrustedProxies::client_ip`, which a visitor can't forge
+    pub fn matching(&self, path: &str, method: &str, visitor: IpAddr) -> Option<&PolicyRule> {
+        self.matching_at(path, method, visitor, Utc::now())
+    }
+
+    /// First rule matching a request made at `now`
+    pub fn matching_at(
+        &self,
+        path: &str,
+        method: &str,
+        visitor: IpAddr,
+        now: DateTime<Utc>,
+    ) -> Option<&PolicyRule> {
+        self.rules.iter().find(|rule| {
             // Check method filter
             if let Some(ref m) = rule.method {
                 if !m.eq_ignore_ascii_case(method) {
-                    continue;
+                    return false;
                 }
             }
 
-            // Check path pattern
-            if matches_glob(&rule.path_pattern, path) {
-                return rule.action.clone();
+            // Check visitor address
+            if !rule.from.is_empty() && !rule.from.iter().any(|m| m.contains(visitor)) {
+                return false;
             }
-        }
-
-        PolicyAction::Allow
-    }
-}
-
-/// Simple glob matcher supporting * (single segment) and ** (any depth)
-fn matches_glob(pattern: &str, path: &str) -> bool {
-    // Exact match
-    if pattern == path {
-        return true;
-    }
-
-    // "**" matches everything
-    if pattern == "**" || pattern == "/**" {
-        return true;
-    }
-
-    let pat_parts: Vec<&str> = pattern.split('/').filter(|s| !s.is_empty()).collect();
-    let path_parts: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
-
-    matches_parts(&pat_parts, &path_parts)
-}
-
-fn matches_parts(pattern: &[&str], path: &[&str]) -> bool {
-    if pattern.is_empty() {
-        return path.is_empty();
-    }
-
-    if pattern[0] == "**" {
-        // ** matches zero or more path segments
-        for i in 0..=path.len() {
-            if matches_parts(&pattern[1..], &path[i..]) {
-                return true;
+            if rule.except_from.iter().any(|m| m.contains(visitor)) {
+                return false;
             }
-        }
-        return false;
-    }
 
-    if path.is_empty() {
-        return false;
-    }
+            // Check time windows, in the rule's timezone
+            let local = rule.timezone.local(now);
+            if rule.during.as_ref().is_some_and(|w| !w.is_active(local)) {
+                return false;
+            }
+            if rule.except_during.as_ref().is_some_and(|w| w.is_active(local)) {
+                return false;
+            }
 
-    // * matches any single segment, otherwise exact match
-    let seg_match = pattern[0] == "*" || pattern[0] == path[0];
-    if seg_match {
-        return matches_parts(&pattern[1..], &path[1..]);
+            // Check path pattern
+            matches_glob(&rule.path_pattern, path)
+        })
     }
-
-    false
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    #[test]
-    fn test_exact_match() {
-        assert!(matches_glob("/api/users", "/api/users"));
-        assert!(!matches_glob("/api/users", "/api/posts"));
-    }
-
-    #[test]
-    fn test_wildcard() {
-        assert!(matches_glob("/api/*", "/api/users"));
-        assert!(!matches_glob("/api/*", "/api/users/123"));
-    }
-
-    #[test]
-    fn test_double_wildcard() {
-        assert!(matches_glob("/admin/**", "/admin/settings"));
-        assert!(matches_glob("/admin/**", "/admin/users/123/edit"));
-    }
+    const RULES: &str = r#"
+- path: /admin/**
+  action: block
+  status: 403
+  except_from: [10.0.0.0/8]
+- path: /api/**
+  method: DELETE
+  action: require_auth
+- path: /metrics
+  action: allow
+  from: [192.0.2.1]
+- path: /metrics
+  action: block
+  status: 404
+- path: /reports/**
+  action: require_auth
+  except_during: Mon-Fri 09:00-18:00
+  timezone: "+02:00"
+- path: /office/**
+  action: block
+  status: 403
+  during: Mon-Fri 09:00-18:00
+  timezone: Europe/Paris
+"#;
 
     #[test]
     fn test_policy_engine() {
         let mut engine = PolicyEngine::new();
-        engine.add_rule(PolicyRule {
-            path_pattern: "/admin/**".into(),
-            method: None,
-            action: PolicyAction::Block(403),
-        });
-        engine.add_rule(PolicyRule {
-            path_pattern: "/api/**".into(),
-            method: Some("DELETE".into()),
-            action: PolicyAction::RequireAuth,
-        });
-
-        assert!(matches!(engine.evaluate("/admin/settings", "GET"), PolicyAction::Block(403)));
-        assert!(matches!(engine.evaluate("/api/users", "DELETE"), PolicyAction::RequireAuth));
-        assert!(matches!(engine.evaluate("/api/users", "GET"), PolicyAction::Allow));
-        assert!(matches!(engine.evaluate("/public", "GET"), PolicyAction::Allow));
+        for policy in serde_yaml::from_str::<Vec<protocol::Policy>>(RULES).unwrap() {
+            engine.add_rule(PolicyRule::from(&policy));
+        }
+
+        let visitor: IpAddr = "203.0.113.9".parse().unwrap();
+        let internal: IpAddr = "10.1.2.3".parse().unwrap();
+        let action = |path, method, visitor| engine.matching(path, method, visitor).map(|r| r.action.clone());
+        assert!(matches!(action("/admin/settings", "GET", visitor), Some(PolicyAction::Block(403))));
+        assert!(action("/admin/settings", "GET", internal).is_none());
+        assert!(matches!(action("/api/users", "DELETE", visitor), Some(PolicyAction::RequireAuth)));
+        assert!(action("/api/users", "GET", visitor).is_none());
+        assert!(action("/public", "GET", visitor).is_none());
+        assert!(matches!(action("/metrics", "GET", "192.0.2.1".parse().unwrap()), Some(PolicyAction::Allow)));
+        assert!(matches!(action("/metrics", "GET", visitor), Some(PolicyAction::Block(404))));
+
+        // A rule keeps its id wherever it sits, so a reordered list keeps
+        // its rate limit buckets
+        let mut policies = serde_yaml::from_str::<Vec<protocol::Policy>>(RULES).unwrap();
+        policies.reverse();
+        let reordered = PolicyEngine::from_policies(&policies);
+        let id = |engine: &PolicyEngine| engine.matching("/api/users", "DELETE", visitor).unwrap().id;
+        assert_eq!(id(&reordered), id(&engine));
+        assert_ne!(engine.rules[2].id, engine.rules[3].id);
+
+        // Open during business hours at +02:00, behind auth otherwise.
+        // 2024-05-03 is a Friday.
+        let at = |utc: &str| format!("2024-05-03T{}:00Z", utc).parse::<DateTime<Utc>>().unwrap();
+        let reports = |now| engine.matching_at("/reports/q1", "GET", visitor, now).map(|r| r.action.clone());
+        assert!(reports(at("08:00")).is_none());
+        assert!(matches!(reports(at("06:59")), Some(PolicyAction::RequireAuth)));
+        assert!(matches!(reports(at("16:00")), Some(PolicyAction::RequireAuth)));
+
+        // A named zone keeps to local hours across daylight saving: 09:30
+        // in Paris is 08:30 UTC in winter and 07:30 UTC in summer
+        let office = |utc: &str| {
+            let now = utc.parse::<DateTime<Utc>>().unwrap();
+            engine.matching_at("/office/desk", "GET", visitor, now).map(|r| r.action.clone())
+        };
+        assert!(matches!(office("2024-01-08T08:30:00Z"), Some(PolicyAction::Block(403))));
+        assert!(office("2024-01-08T07:30:00Z").is_none());
+        assert!(matches!(office("2024-07-08T07:30:00Z"), Some(PolicyAction::Block(403))));
     }
 }
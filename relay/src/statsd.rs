@@ -0,0 +1,150 @@
+//! StatsD / DogStatsD metrics
+//!
+//! With ZTUNNEL_STATSD_ADDR set (`host:port`, e.g. a Datadog agent on
+//! `127.0.0.1:8125`), every measurement is also sent as a StatsD line
+//! over UDP: counters as `c`, request durations as `ms` timings, other
+//! histograms as `h` and gauges as `g`.
+//!
+//! - ZTUNNEL_STATSD_PREFIX: put before every name, e.g. `ztunnel.`
+//! - ZTUNNEL_STATSD_TAGS: `key:value,...` added to every line
+//!
+//! Labels and tags use the DogStatsD `|#key:value` extension, which the
+//! Datadog agent, Telegraf and statsd_exporter understand. Sending
+//! never blocks; a full socket buffer drops the line.
+
+use std::fmt::Write;
+use std::net::{ToSocketAddrs, UdpSocket};
+
+use anyhow::{Context, Result};
+use tracing::debug;
+
+use ztunnel_shared::telemetry::{Labels, Telemetry};
+
+/// Where to send and what to add
+#[derive(Debug, Clone)]
+pub struct StatsdConfig {
+    pub addr: String,
+    pub prefix: String,
+    /// `key:value` pairs, already joined
+    pub tags: String,
+}
+
+impl StatsdConfig {
+    /// `None` when ZTUNNEL_STATSD_ADDR isn't set
+    pub fn from_env() -> Result<Option<Self>> {
+        let Ok(addr) = std::env::var("ZTUNNEL_STATSD_ADDR") else {
+            return Ok(None);
+        };
+        let tags = std::env::var("ZTUNNEL_STATSD_TAGS").unwrap_or_default();
+        let tags: Vec<&str> = tags.split(',').map(str::trim).filter(|t| !t.is_empty()).collect();
+        if let Some(bad) = tags.iter().find(|t| t.contains(['|', '#', ' '])) {
+            anyhow::bail!("Invalid ZTUNNEL_STATSD_TAGS: '{}'", bad);
+        }
+        Ok(Some(Self {
+            addr,
+            prefix: std::env::var("ZTUNNEL_STATSD_PREFIX").unwrap_or_default(),
+            tags: tags.join(","),
+        }))
+    }
+}
+
+/// `Telemetry` backend writing StatsD lines to a UDP socket
+pub struct Statsd {
+    socket: UdpSocket,
+    prefix: String,
+    tags: String,
+}
+
+impl Statsd {
+    pub fn connect(config: &StatsdConfig) -> Result<Self> {
+        let addr = config
+            .addr
+            .to_socket_addrs()
+            .with_context(|| format!("Resolving {}", config.addr))?
+            .next()
+            .with_context(|| format!("No address for {}", config.addr))?;
+        let local = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(addr)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self { socket, prefix: config.prefix.clone(), tags: config.tags.clone() })
+    }
+
+    /// `prefix.name:value|kind|#tags,labels`
+    fn line(&self, name: &str, labels: Labels<'_>, value: impl std::fmt::Display, kind: &str) -> String {
+        let mut line = format!("{}{}:{}|{}", self.prefix, name, value, kind);
+        let mut sep = "|#";
+        if !self.tags.is_empty() {
+            let _ = write!(line, "{}{}", sep, self.tags);
+            sep = ",";
+        }
+        for (key, value) in labels {
+            // Separators inside a value would end the tag or the line
+            let value = value.replace([',', '|', '#', ' ', '\n'], "_");
+            let _ = write!(line, "{}{}:{}", sep, key, value);
+            sep = ",";
+        }
+        line
+    }
+
+    fn send(&self, line: String) {
+        if let Err(e) = self.socket.send(line.as_bytes()) {
+            debug!("StatsD send failed: {}", e);
+        }
+    }
+}
+
+impl Telemetry for Statsd {
+    fn counter(&self, name: &str, labels: Labels<'_>, delta: u64) {
+        self.send(self.line(name, labels, delta, "c"));
+    }
+
+    fn gauge(&self, name: &str, labels: Labels<'_>, value: f64) {
+        self.send(self.line(name, labels, value, "g"));
+    }
+
+    fn histogram(&self, name: &str, labels: Labels<'_>, value: f64) {
+        match name.strip_suffix("_seconds") {
+            Some(base) => self.send(self.line(&format!("{}_ms", base), labels, value * 1000.0, "ms")),
+            None => self.send(self.line(name, labels, value, "h")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_statsd_lines() {
+        let agent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        agent.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let config = StatsdConfig {
+            addr: agent.local_addr().unwrap().to_string(),
+            prefix: "relay.".into(),
+            tags: "env:test".into(),
+        };
+        let statsd = Statsd::connect(&config).unwrap();
+        statsd.request("demo", 200, Duration::from_millis(25), 10, 0);
+        statsd.gauge("ztunnel_active_tunnels", &[], 3.0);
+
+        let mut buf = [0; 512];
+        let lines: Vec<String> = (0..5)
+            .map(|_| {
+                let n = agent.recv(&mut buf).unwrap();
+                String::from_utf8_lossy(&buf[..n]).into_owned()
+            })
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                "relay.ztunnel_requests_total:1|c|#env:test,tunnel:demo,status:200",
+                "relay.ztunnel_request_duration_ms:25|ms|#env:test,tunnel:demo",
+                "relay.ztunnel_bytes_total:10|c|#env:test,tunnel:demo,direction:in",
+                "relay.ztunnel_bytes_total:0|c|#env:test,tunnel:demo,direction:out",
+                "relay.ztunnel_active_tunnels:3|g|#env:test",
+            ]
+        );
+    }
+}
@@ -2,13 +2,19 @@
 //!
 //! Extended with IP filtering, circuit breaker, and load balancing support.
 
+use std::net::IpAddr;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::mpsc;
 use tokio::sync::oneshot;
+use tokio::time::Instant;
+use bytes::Bytes;
 use dashmap::DashMap;
 
 use crate::ip_filter::IpFilter;
 use crate::circuit_breaker::CircuitBreaker;
+use crate::policy::PolicyEngine;
+use ztunnel_shared::ratelimit::{Quota, RateLimiter};
 
 /// Unique tunnel identifier
 pub type TunnelId = String;
@@ -23,7 +29,9 @@ pub struct Tunnel {
     /// Tunnel metadata
     pub created_at: std::time::Instant,
     /// Pending request correlation map
-    pub pending_requests: Arc<DashMap<String, oneshot::Sender<TunnelResponse>>>,
+    pub pending_requests: PendingRequests,
+    /// Streamed response bodies still arriving, by request id
+    pub response_bodies: Arc<DashMap<String, mpsc::UnboundedSender<std::io::Result<Bytes>>>>,
     /// IP access control
     pub ip_filter: IpFilter,
     /// Circuit breaker for this tunnel
@@ -32,6 +40,22 @@ pub struct Tunnel {
     pub lb_clients: Arc<tokio::sync::RwLock<Vec<mpsc::Sender<Vec<u8>>>>>,
     /// Round-robin counter for load balancing
     pub lb_counter: Arc<std::sync::atomic::AtomicUsize>,
+    /// Client accepts request bodies as stream frames
+    pub stream_bodies: bool,
+    /// Client takes small messages joined into batches
+    pub batch_messages: bool,
+    /// Client registered a TCP tunnel, so takes `/connect` streams
+    pub tcp: bool,
+    /// Namespace account the client registered with
+    pub account: Option<String>,
+    /// Notified to disconnect the client
+    pub kicked: Arc<tokio::sync::Notify>,
+    /// Rules checked before forwarding: the client's, then the relay's
+    pub policy: PolicyEngine,
+    /// Rules from the client's registration alone
+    pub client_policy: PolicyEngine,
+    /// Buckets for `rate_limit` rules, by rule id and visitor
+    pub policy_limits: Arc<RateLimiter<(u64, IpAddr)>>,
 }
 
 impl Tunnel {
@@ -40,31 +64,50 @@ impl Tunnel {
         tx: mpsc::Sender<Vec<u8>>,
         ip_filter: IpFilter,
         circuit_breaker: CircuitBreaker,
+        stream_bodies: bool,
+        batch_messages: bool,
+        policy: PolicyEngine,
     ) -> Self {
         Self {
             subdomain,
             tx: tx.clone(),
             created_at: std::time::Instant::now(),
-            pending_requests: Arc::new(DashMap::new()),
+            pending_requests: PendingRequests::default(),
+            response_bodies: Arc::new(DashMap::new()),
             ip_filter,
             circuit_breaker,
             lb_clients: Arc::new(tokio::sync::RwLock::new(vec![tx])),
             lb_counter: Arc::new(std::sync::atomic::AtomicUsize::new(0)),
+            stream_bodies,
+            batch_messages,
+            tcp: false,
+            account: None,
+            kicked: Arc::new(tokio::sync::Notify::new()),
+            client_policy: policy.clone(),
+            policy,
+            policy_limits: Arc::new(RateLimiter::new(Quota::per_minute(60))),
         }
     }
 
-    /// Send data to a tunnel client (with load balancing)
-    pub async fn send(&self, data: Vec<u8>) -> Result<(), mpsc::error::SendError<Vec<u8>>> {
+    /// Enforce `policy` from now on. Rate limit buckets are by rule id,
+    /// so rules that stay keep theirs wherever they move.
+    pub fn set_policy(&mut self, policy: PolicyEngine) {
+        self.policy = policy;
+    }
+
+    /// Pick the client for the next request (with load balancing).
+    /// Everything belonging to one request must go to the same client.
+    pub async fn client(&self) -> mpsc::Sender<Vec<u8>> {
         let clients = self.lb_clients.read().await;
-        
+
         if clients.len() <= 1 {
             // Single client, use primary
-            return self.tx.send(data).await;
+            return self.tx.clone();
         }
 
         // Round-robin across connected clients
         let idx = self.lb_counter.fetch_add(1, std::sync::atomic::Ordering::Relaxed) % clients.len();
-        clients[idx].send(data).await
+        clients[idx].clone()
     }
 
     /// Add a load-balanced client
@@ -80,6 +123,54 @@ impl Tunnel {
     }
 }
 
+/// How long a request waits for the client's response
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Requests sent down a tunnel and waiting for their response, by id
+#[derive(Clone, Default)]
+pub struct PendingRequests {
+    entries: Arc<DashMap<String, Pending>>,
+}
+
+struct Pending {
+    tx: oneshot::Sender<Reply>,
+    deadline: Instant,
+}
+
+impl PendingRequests {
+    /// Wait for the response to `id`, until `deadline`
+    pub fn insert(&self, id: String, deadline: Instant) -> oneshot::Receiver<Reply> {
+        let (tx, rx) = oneshot::channel();
+        self.entries.insert(id, Pending { tx, deadline });
+        rx
+    }
+
+    pub fn remove(&self, id: &str) -> Option<oneshot::Sender<Reply>> {
+        self.entries.remove(id).map(|(_, pending)| pending.tx)
+    }
+
+    /// Drop entries past their deadline or whose request has gone away,
+    /// so their waiters see the channel close. Returns how many went.
+    pub fn sweep(&self, now: Instant) -> usize {
+        let mut expired = 0;
+        self.entries.retain(|_, pending| {
+            let keep = pending.deadline > now && !pending.tx.is_closed();
+            expired += usize::from(!keep);
+            keep
+        });
+        expired
+    }
+
+    /// Cancel every waiting request
+    pub fn clear(&self) {
+        self.entries.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
 /// Tunnel request/response for HTTP proxying
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct TunnelRequest {
@@ -87,7 +178,10 @@ pub struct TunnelRequest {
     pub method: String,
     pub path: String,
     pub headers: Vec<(String, String)>,
-    pub body: Option<Vec<u8>>,
+    pub body: Option<Bytes>,
+    /// The body follows as `StreamFrame`s on this request's id
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub streamed: bool,
 }
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
@@ -95,5 +189,66 @@ pub struct TunnelResponse {
     pub id: String,
     pub status: u16,
     pub headers: Vec<(String, String)>,
-    pub body: Option<Vec<u8>>,
+    pub body: Option<Bytes>,
+    /// The body follows as `StreamFrame`s on the request's id
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub streamed: bool,
+}
+
+/// A response handed to the waiting request, with its body's frames
+/// arriving on `body` when it was streamed
+pub struct Reply {
+    pub response: TunnelResponse,
+    pub body: Option<mpsc::UnboundedReceiver<std::io::Result<Bytes>>>,
+}
+
+/// A piece of a streamed body, or of a `/connect` stream
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct StreamFrame {
+    pub stream: String,
+    pub event: StreamEvent,
+    /// Consumer's address, with `Open`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remote_addr: Option<std::net::SocketAddr>,
+    /// `host:port` for a gateway tunnel, with `Open`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    /// Consumer's gateway token, with `Open`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+impl StreamFrame {
+    pub fn new(stream: impl Into<String>, event: StreamEvent) -> Self {
+        Self { stream: stream.into(), event, remote_addr: None, target: None, token: None }
+    }
+}
+
+/// Same encoding as the client's
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub enum StreamEvent {
+    /// A consumer connected; only the relay sends this
+    Open,
+    Data(Bytes),
+    Close,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_sweep_pending_requests() {
+        let pending = PendingRequests::default();
+        let now = Instant::now();
+        let mut late = pending.insert("late".into(), now + Duration::from_secs(1));
+        let _waiting = pending.insert("waiting".into(), now + Duration::from_secs(60));
+        drop(pending.insert("abandoned".into(), now + Duration::from_secs(60)));
+
+        assert_eq!(pending.sweep(now), 1);
+        assert_eq!(pending.sweep(now + Duration::from_secs(2)), 1);
+        assert!(late.try_recv().is_err());
+        assert_eq!(pending.len(), 1);
+        assert!(pending.remove("waiting").is_some());
+    }
 }
@@ -0,0 +1,210 @@
+//! Usage per account, for billing
+//!
+//! Tunnels registered with a namespace token (see `namespace`) belong to
+//! that namespace's account. Their requests and bytes are counted from
+//! the same telemetry the Prometheus endpoint gets, and the time they
+//! stay connected as tunnel-hours, in one row per account per UTC day.
+//! With ZTUNNEL_USAGE_FILE the rows are saved there every minute and
+//! loaded again at startup. Tunnels without an account aren't counted.
+
+use anyhow::{Context, Result};
+use chrono::{NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::warn;
+use ztunnel_shared::telemetry::{metric, Labels};
+use ztunnel_shared::Telemetry;
+
+/// How often live tunnels are credited and the file is saved
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// One account's usage on one day
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Row {
+    pub date: NaiveDate,
+    pub account: String,
+    pub requests: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub tunnel_seconds: u64,
+}
+
+impl Row {
+    pub fn tunnel_hours(&self) -> f64 {
+        self.tunnel_seconds as f64 / 3600.0
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    rows: BTreeMap<(NaiveDate, String), Row>,
+    /// Account and last time credited, by live tunnel
+    live: HashMap<String, (String, Instant)>,
+}
+
+impl Inner {
+    fn today(&mut self, account: &str) -> &mut Row {
+        let date = Utc::now().date_naive();
+        self.rows.entry((date, account.to_string())).or_insert_with(|| Row {
+            date,
+            account: account.to_string(),
+            ..Default::default()
+        })
+    }
+
+    /// Add the time since `tunnel` was last credited to its account
+    fn credit(&mut self, tunnel: &str, now: Instant) {
+        let Some((account, since)) = self.live.get_mut(tunnel) else {
+            return;
+        };
+        let seconds = now.duration_since(*since).as_secs();
+        // Whole seconds only, so the remainder carries over
+        *since += Duration::from_secs(seconds);
+        let account = account.clone();
+        self.today(&account).tunnel_seconds += seconds;
+    }
+}
+
+/// Usage rows, and the tunnels being counted now
+#[derive(Default)]
+pub struct Usage {
+    inner: Mutex<Inner>,
+    path: Option<PathBuf>,
+}
+
+impl Usage {
+    /// Counts kept in `path`, starting from what it already holds
+    pub fn load(path: PathBuf) -> Result<Self> {
+        let rows: Vec<Row> = match std::fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data).with_context(|| format!("Invalid usage file {}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e).with_context(|| format!("Could not read {}", path.display())),
+        };
+        let rows = rows.into_iter().map(|r| ((r.date, r.account.clone()), r)).collect();
+        Ok(Self { inner: Mutex::new(Inner { rows, live: HashMap::new() }), path: Some(path) })
+    }
+
+    /// Where the rows are saved, if anywhere
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Start counting connected time for `tunnel` against `account`
+    pub fn tunnel_opened(&self, tunnel: &str, account: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.live.insert(tunnel.to_string(), (account.to_string(), Instant::now()));
+    }
+
+    pub fn tunnel_closed(&self, tunnel: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.credit(tunnel, Instant::now());
+        inner.live.remove(tunnel);
+    }
+
+    /// Rows from `from` to `to` inclusive, for `account` or everyone
+    pub fn rows(&self, from: NaiveDate, to: NaiveDate, account: Option<&str>) -> Vec<Row> {
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        let live: Vec<String> = inner.live.keys().cloned().collect();
+        live.iter().for_each(|t| inner.credit(t, now));
+        inner
+            .rows
+            .values()
+            .filter(|r| r.date >= from && r.date <= to && account.is_none_or(|a| r.account == a))
+            .cloned()
+            .collect()
+    }
+
+    /// Credit live tunnels and save, every minute
+    pub async fn run(self: std::sync::Arc<Self>) {
+        let mut timer = tokio::time::interval(SAVE_INTERVAL);
+        loop {
+            timer.tick().await;
+            self.save();
+        }
+    }
+
+    /// Credit live tunnels and write the file now
+    pub fn save(&self) {
+        let rows = self.rows(NaiveDate::MIN, NaiveDate::MAX, None);
+        if let Some(path) = &self.path {
+            if let Err(e) = save(path, &rows) {
+                warn!("Usage not saved: {:#}", e);
+            }
+        }
+    }
+}
+
+/// Write to a temporary file first, so a crash can't leave half a file
+fn save(path: &PathBuf, rows: &[Row]) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, serde_json::to_vec(rows)?).with_context(|| format!("Could not write {}", tmp.display()))?;
+    std::fs::rename(&tmp, path).with_context(|| format!("Could not replace {}", path.display()))
+}
+
+/// CSV with a header line, as `/admin/usage?format=csv` returns
+pub fn to_csv(rows: &[Row]) -> String {
+    let mut csv = String::from("date,account,requests,bytes_in,bytes_out,tunnel_hours\n");
+    for r in rows {
+        csv.push_str(&format!(
+            "{},{},{},{},{},{:.3}\n",
+            r.date, r.account, r.requests, r.bytes_in, r.bytes_out, r.tunnel_hours()
+        ));
+    }
+    csv
+}
+
+impl Telemetry for Usage {
+    fn counter(&self, name: &str, labels: Labels<'_>, delta: u64) {
+        let label = |key: &str| labels.iter().find(|(k, _)| *k == key).map(|(_, v)| *v);
+        let Some(tunnel) = label("tunnel") else {
+            return;
+        };
+        let mut inner = self.inner.lock().unwrap();
+        let Some((account, _)) = inner.live.get(tunnel) else {
+            return;
+        };
+        let account = account.clone();
+        let row = inner.today(&account);
+        match (name, label("direction")) {
+            (metric::REQUESTS, _) => row.requests += delta,
+            (metric::BYTES, Some("in")) => row.bytes_in += delta,
+            (metric::BYTES, Some("out")) => row.bytes_out += delta,
+            _ => {}
+        }
+    }
+
+    fn gauge(&self, _: &str, _: Labels<'_>, _: f64) {}
+    fn histogram(&self, _: &str, _: Labels<'_>, _: f64) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_usage_by_account() {
+        let usage = Usage::default();
+        usage.tunnel_opened("acme-web", "acme");
+        usage.request("acme-web", 200, Duration::ZERO, 100, 2000);
+        usage.request("acme-web", 404, Duration::ZERO, 50, 10);
+        // Not an account's tunnel
+        usage.request("t1a2b3", 200, Duration::ZERO, 1, 1);
+
+        let today = Utc::now().date_naive();
+        let rows = usage.rows(today, today, None);
+        assert_eq!(rows.len(), 1);
+        assert_eq!((rows[0].requests, rows[0].bytes_in, rows[0].bytes_out), (2, 150, 2010));
+        assert!(usage.rows(today, today, Some("beta")).is_empty());
+        assert!(usage.rows(today.pred_opt().unwrap(), today.pred_opt().unwrap(), None).is_empty());
+
+        usage.tunnel_closed("acme-web");
+        usage.request("acme-web", 200, Duration::ZERO, 1, 1);
+        assert_eq!(usage.rows(today, today, Some("acme"))[0].requests, 2);
+
+        assert!(to_csv(&rows).starts_with(&format!("date,account,requests,bytes_in,bytes_out,tunnel_hours\n{},acme,2,150,2010,", today)));
+    }
+}
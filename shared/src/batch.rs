@@ -0,0 +1,108 @@
+//! Message batching
+//!
+//! When both ends announce `capability::BATCH`, small tunnel messages
+//! waiting to go out are joined into one WebSocket message, separated by
+//! newlines. Messages are compact JSON, which never holds a raw newline,
+//! so the receiver splits a batch without parsing it, and a message on
+//! its own reads as a batch of one.
+//!
+//! A batch goes out when it is full or `FLUSH_DELAY` after its first
+//! message, whichever comes first, so batching never holds a message
+//! longer than that.
+
+use std::time::{Duration, Instant};
+
+/// Messages this large or larger go out on their own
+pub const MAX_ITEM: usize = 16 * 1024;
+
+/// Most bytes joined into one batch
+pub const MAX_BATCH: usize = 64 * 1024;
+
+/// Longest a message waits for others to join it
+pub const FLUSH_DELAY: Duration = Duration::from_millis(2);
+
+/// Whether `message` may be batched
+pub fn fits(message: &[u8]) -> bool {
+    message.len() < MAX_ITEM && !message.contains(&b'\n')
+}
+
+/// The messages in a batch
+pub fn split(data: &[u8]) -> impl Iterator<Item = &[u8]> {
+    data.split(|b| *b == b'\n').filter(|message| !message.is_empty())
+}
+
+/// Messages collected for the next batch
+#[derive(Debug, Default)]
+pub struct Batcher {
+    buf: Vec<u8>,
+    /// When the first message of this batch arrived
+    started: Option<Instant>,
+}
+
+impl Batcher {
+    /// Add a message that `fits`. When it would overflow the batch, the
+    /// batch so far comes back to be sent and this message starts the
+    /// next one.
+    pub fn push(&mut self, message: &[u8]) -> Option<Vec<u8>> {
+        let full = if self.buf.len() + 1 + message.len() > MAX_BATCH { self.take() } else { None };
+        if self.buf.is_empty() {
+            self.started = Some(Instant::now());
+        } else {
+            self.buf.push(b'\n');
+        }
+        self.buf.extend_from_slice(message);
+        full
+    }
+
+    /// The batch so far, if there is one
+    pub fn take(&mut self) -> Option<Vec<u8>> {
+        self.started = None;
+        (!self.buf.is_empty()).then(|| std::mem::take(&mut self.buf))
+    }
+
+    /// When the current batch must go out
+    pub fn deadline(&self) -> Option<Instant> {
+        self.started.map(|started| started + FLUSH_DELAY)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_batch_round_trip() {
+        let mut batcher = Batcher::default();
+        assert_eq!(batcher.deadline(), None);
+        assert_eq!(batcher.push(br#"{"id":"a"}"#), None);
+        assert_eq!(batcher.push(br#"{"id":"b"}"#), None);
+        assert!(batcher.deadline().is_some());
+
+        let batch = batcher.take().unwrap();
+        assert_eq!(split(&batch).collect::<Vec<_>>(), [&br#"{"id":"a"}"#[..], br#"{"id":"b"}"#]);
+        assert!(batcher.is_empty() && batcher.take().is_none() && batcher.deadline().is_none());
+
+        // A message on its own is a batch of one
+        assert_eq!(split(b"{}").count(), 1);
+        assert!(!fits(&[b'x'; MAX_ITEM]));
+        assert!(!fits(b"{\n}"));
+    }
+
+    #[test]
+    fn test_batch_overflow_starts_next() {
+        let mut batcher = Batcher::default();
+        let message = vec![b'x'; MAX_ITEM - 1];
+        let mut sent = Vec::new();
+        for _ in 0..5 {
+            sent.extend(batcher.push(&message));
+        }
+        assert_eq!(sent.len(), 1);
+        assert!(sent[0].len() <= MAX_BATCH);
+        assert_eq!(split(&sent[0]).count(), 4);
+        assert_eq!(split(&batcher.take().unwrap()).count(), 1);
+    }
+}
@@ -4,6 +4,10 @@
 
 use crate::{Error, Result};
 
+/// Whether the real ciphers are linked; without libzcrypto the
+/// placeholders below provide no security
+pub const HAS_LIBZCRYPTO: bool = cfg!(feature = "libzcrypto");
+
 /// X25519 keypair
 #[derive(Clone)]
 pub struct X25519Keypair {
@@ -127,11 +131,17 @@ impl X25519Keypair {
 impl Session {
     /// Create a new session from shared secret
     pub fn new(shared_secret: &[u8; 32]) -> Self {
+        Self::derive(shared_secret, b"ztunnel-session-v1")
+    }
+
+    /// Create a session whose key is bound to `info`, so one shared
+    /// secret can key several independent sessions (e.g. one per
+    /// direction) without reusing nonces under the same key
+    pub fn derive(shared_secret: &[u8; 32], info: &[u8]) -> Self {
         let mut session_key = [0u8; 32];
         
         #[cfg(feature = "libzcrypto")]
         {
-            let info = b"ztunnel-session-v1";
             unsafe {
                 ffi::zcrypto_hkdf_sha256(
                     session_key.as_mut_ptr(),
@@ -148,8 +158,10 @@ impl Session {
         
         #[cfg(not(feature = "libzcrypto"))]
         {
-            // Placeholder - just copy shared secret
-            session_key.copy_from_slice(shared_secret);
+            // Placeholder - mix in info so derived keys differ
+            for (i, b) in session_key.iter_mut().enumerate() {
+                *b = shared_secret[i] ^ info.get(i % info.len().max(1)).copied().unwrap_or(0);
+            }
         }
         
         Session {
@@ -167,9 +179,15 @@ impl Session {
     }
 
     /// Encrypt data
-    #[cfg(feature = "libzcrypto")]
     pub fn encrypt(&mut self, plaintext: &[u8]) -> Result<(Vec<u8>, [u8; 12], [u8; 16])> {
         let nonce = self.next_nonce();
+        let (ciphertext, tag) = self.encrypt_with_nonce(plaintext, &nonce)?;
+        Ok((ciphertext, nonce, tag))
+    }
+
+    /// Encrypt under a nonce the caller keeps unique for this key
+    #[cfg(feature = "libzcrypto")]
+    pub fn encrypt_with_nonce(&self, plaintext: &[u8], nonce: &[u8; 12]) -> Result<(Vec<u8>, [u8; 16])> {
         let mut ciphertext = vec![0u8; plaintext.len()];
         let mut tag = [0u8; 16];
 
@@ -186,12 +204,11 @@ impl Session {
             );
         }
 
-        Ok((ciphertext, nonce, tag))
+        Ok((ciphertext, tag))
     }
 
     #[cfg(not(feature = "libzcrypto"))]
-    pub fn encrypt(&mut self, plaintext: &[u8]) -> Result<(Vec<u8>, [u8; 12], [u8; 16])> {
-        let nonce = self.next_nonce();
+    pub fn encrypt_with_nonce(&self, plaintext: &[u8], nonce: &[u8; 12]) -> Result<(Vec<u8>, [u8; 16])> {
         // Placeholder XOR encryption - NOT secure
         let ciphertext: Vec<u8> = plaintext
             .iter()
@@ -199,7 +216,7 @@ impl Session {
             .map(|(i, b)| b ^ self.session_key[i % 32] ^ nonce[i % 12])
             .collect();
         let tag = [0u8; 16]; // Placeholder
-        Ok((ciphertext, nonce, tag))
+        Ok((ciphertext, tag))
     }
 
     /// Decrypt data  
@@ -0,0 +1,77 @@
+//! Compressed tunnel messages
+//!
+//! The WebSocket libraries on either end don't implement the
+//! permessage-deflate extension, so peers that both announce
+//! `capability::DEFLATE` do the same thing one layer up: each binary
+//! message (a batch counts as one) is raw DEFLATE compressed on its own,
+//! as permessage-deflate does without context takeover. A compressed
+//! message starts with a zero byte, which never starts JSON, so small
+//! messages that don't gain anything go out as they are.
+
+use crate::protocol::MAX_MESSAGE_SIZE;
+use crate::{Error, Result};
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use std::borrow::Cow;
+use std::io::{Read, Write};
+
+/// Messages smaller than this aren't worth compressing
+pub const MIN_SIZE: usize = 256;
+
+/// First byte of a compressed message
+const MARKER: u8 = 0;
+
+pub fn is_compressed(data: &[u8]) -> bool {
+    data.first() == Some(&MARKER)
+}
+
+/// The message to send for `message`: compressed when that makes it
+/// smaller
+pub fn compress(message: Vec<u8>) -> Vec<u8> {
+    if message.len() < MIN_SIZE {
+        return message;
+    }
+    let mut encoder = DeflateEncoder::new(vec![MARKER], Compression::fast());
+    match encoder.write_all(&message).and_then(|()| encoder.finish()) {
+        Ok(compressed) if compressed.len() < message.len() => compressed,
+        _ => message,
+    }
+}
+
+/// The original of a received message. Messages inflating past
+/// `MAX_MESSAGE_SIZE` are refused.
+pub fn decompress(data: &[u8]) -> Result<Cow<'_, [u8]>> {
+    let Some(compressed) = data.strip_prefix(&[MARKER]) else {
+        return Ok(Cow::Borrowed(data));
+    };
+    let mut message = Vec::with_capacity(compressed.len() * 4);
+    DeflateDecoder::new(compressed)
+        .take(MAX_MESSAGE_SIZE as u64 + 1)
+        .read_to_end(&mut message)
+        .map_err(|e| Error::Protocol(format!("Bad compressed message: {}", e)))?;
+    if message.len() > MAX_MESSAGE_SIZE {
+        return Err(Error::Protocol("Compressed message too large".into()));
+    }
+    Ok(Cow::Owned(message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compress_round_trip() {
+        let json = br#"{"id":"r1","status":200,"headers":[["content-type","application/json"]]}"#.repeat(20);
+        let sent = compress(json.clone());
+        assert!(is_compressed(&sent) && sent.len() < json.len() / 4, "{}", sent.len());
+        assert_eq!(decompress(&sent).unwrap(), &json[..]);
+
+        // Small messages pass through untouched
+        let small = br#"{"id":"r1"}"#.to_vec();
+        assert_eq!(compress(small.clone()), small);
+        assert!(matches!(decompress(&small).unwrap(), Cow::Borrowed(_)));
+
+        assert!(decompress(&[MARKER, 0xff, 0xff]).is_err());
+    }
+}
@@ -1,5 +1,12 @@
 //! Error types for ZTunnel.
+//!
+//! Errors cross the wire as `{"code": 4003, "message": "..."}`. Codes are
+//! stable and sit in the WebSocket private-use range, so the same number
+//! can close a connection; the other side gets the typed error back with
+//! `Error::from_code`.
 
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::time::Duration;
 use thiserror::Error;
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -29,4 +36,173 @@ pub enum Error {
 
     #[error("Timeout")]
     Timeout,
+
+    #[error("Rate limited, retry in {0}s")]
+    RateLimited(u64),
+
+    #[error("Tunnel limit reached: {0}")]
+    TunnelLimit(String),
+
+    #[error("Relay under maintenance, retry in {0}s")]
+    Maintenance(u64),
+}
+
+/// What a caller should do after an error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryAdvice {
+    /// Retrying gives the same answer
+    Never,
+    /// Transient; retry with the caller's usual backoff
+    Backoff,
+    /// Retry, but not before this long
+    After(Duration),
+}
+
+/// Wire codes, one per variant
+pub mod code {
+    pub const PROTOCOL: u16 = 4000;
+    pub const INVALID_MESSAGE: u16 = 4001;
+    pub const AUTH_FAILED: u16 = 4003;
+    pub const TUNNEL: u16 = 4004;
+    pub const TIMEOUT: u16 = 4008;
+    pub const CONNECTION: u16 = 4010;
+    pub const IO: u16 = 4011;
+    pub const CRYPTO: u16 = 4020;
+    pub const RATE_LIMITED: u16 = 4029;
+    pub const TUNNEL_LIMIT: u16 = 4030;
+    pub const MAINTENANCE: u16 = 4031;
+}
+
+impl Error {
+    /// Stable code identifying the variant on the wire
+    pub fn code(&self) -> u16 {
+        match self {
+            Error::Protocol(_) => code::PROTOCOL,
+            Error::InvalidMessage => code::INVALID_MESSAGE,
+            Error::AuthFailed => code::AUTH_FAILED,
+            Error::Tunnel(_) => code::TUNNEL,
+            Error::Timeout => code::TIMEOUT,
+            Error::Connection(_) => code::CONNECTION,
+            Error::Io(_) => code::IO,
+            Error::Crypto(_) => code::CRYPTO,
+            Error::RateLimited(_) => code::RATE_LIMITED,
+            Error::TunnelLimit(_) => code::TUNNEL_LIMIT,
+            Error::Maintenance(_) => code::MAINTENANCE,
+        }
+    }
+
+    /// How to react to this error. Rejections (bad credentials, a
+    /// refused tunnel, a peer speaking another protocol) come back the
+    /// same on every attempt; connection trouble and timeouts don't, a
+    /// tunnel limit frees up as other tunnels close, and maintenance ends.
+    pub fn retry_advice(&self) -> RetryAdvice {
+        match self {
+            Error::Connection(_) | Error::Io(_) | Error::Timeout | Error::TunnelLimit(_) => RetryAdvice::Backoff,
+            Error::RateLimited(secs) | Error::Maintenance(secs) => RetryAdvice::After(Duration::from_secs(*secs)),
+            Error::Tunnel(_) | Error::Crypto(_) | Error::Protocol(_) | Error::AuthFailed | Error::InvalidMessage => {
+                RetryAdvice::Never
+            }
+        }
+    }
+
+    /// Whether trying again can succeed
+    pub fn is_retryable(&self) -> bool {
+        self.retry_advice() != RetryAdvice::Never
+    }
+
+    /// The detail carried on the wire: the variant's own text, without
+    /// the prefix `Display` adds
+    pub fn message(&self) -> String {
+        match self {
+            Error::Connection(m) | Error::Tunnel(m) | Error::Crypto(m) | Error::Protocol(m) | Error::TunnelLimit(m) => {
+                m.clone()
+            }
+            Error::Io(e) => e.to_string(),
+            Error::RateLimited(secs) | Error::Maintenance(secs) => secs.to_string(),
+            other => other.to_string(),
+        }
+    }
+
+    /// Rebuild an error received from the other side. Codes from a
+    /// newer peer come back as `Protocol`.
+    pub fn from_code(code: u16, message: impl Into<String>) -> Self {
+        let message = message.into();
+        match code {
+            code::PROTOCOL => Error::Protocol(message),
+            code::INVALID_MESSAGE => Error::InvalidMessage,
+            code::AUTH_FAILED => Error::AuthFailed,
+            code::TUNNEL => Error::Tunnel(message),
+            code::TIMEOUT => Error::Timeout,
+            code::CONNECTION => Error::Connection(message),
+            code::IO => Error::Io(std::io::Error::other(message)),
+            code::CRYPTO => Error::Crypto(message),
+            code::RATE_LIMITED => Error::RateLimited(message.parse().unwrap_or(1)),
+            code::TUNNEL_LIMIT => Error::TunnelLimit(message),
+            code::MAINTENANCE => Error::Maintenance(message.parse().unwrap_or(60)),
+            other => Error::Protocol(format!("{} (code {})", message, other)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Wire {
+    code: u16,
+    message: String,
+}
+
+impl Serialize for Error {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        Wire { code: self.code(), message: self.message() }.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Error {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let wire = Wire::deserialize(deserializer)?;
+        Ok(Error::from_code(wire.code, wire.message))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wire_round_trip() {
+        let errors = [
+            Error::Connection("refused".into()),
+            Error::Tunnel("subdomain taken".into()),
+            Error::Crypto("bad key".into()),
+            Error::Protocol("version 9".into()),
+            Error::Io(std::io::Error::other("disk full")),
+            Error::AuthFailed,
+            Error::InvalidMessage,
+            Error::Timeout,
+            Error::RateLimited(30),
+            Error::TunnelLimit("3 tunnels per IP".into()),
+            Error::Maintenance(600),
+        ];
+        for error in errors {
+            let json = serde_json::to_string(&error).unwrap();
+            let back: Error = serde_json::from_str(&json).unwrap();
+            assert_eq!((back.code(), back.to_string()), (error.code(), error.to_string()), "{}", json);
+        }
+
+        let json = serde_json::to_string(&Error::Tunnel("bad token".into())).unwrap();
+        assert_eq!(json, r#"{"code":4004,"message":"bad token"}"#);
+
+        let newer: Error = serde_json::from_str(r#"{"code":4999,"message":"quota"}"#).unwrap();
+        assert_eq!(newer.to_string(), "Protocol error: quota (code 4999)");
+    }
+
+    #[test]
+    fn test_retry_advice() {
+        assert_eq!(Error::AuthFailed.retry_advice(), RetryAdvice::Never);
+        assert!(!Error::Tunnel("subdomain taken".into()).is_retryable());
+        assert_eq!(Error::Timeout.retry_advice(), RetryAdvice::Backoff);
+        assert!(Error::Connection("reset".into()).is_retryable());
+        assert_eq!(Error::RateLimited(5).retry_advice(), RetryAdvice::After(Duration::from_secs(5)));
+        assert_eq!(Error::TunnelLimit("3 tunnels per IP".into()).retry_advice(), RetryAdvice::Backoff);
+        assert_eq!(Error::Maintenance(600).retry_advice(), RetryAdvice::After(Duration::from_secs(600)));
+    }
 }
@@ -0,0 +1,70 @@
+//! Path glob matching shared by relay policies and client filters.
+
+/// Simple glob matcher supporting * (single segment) and ** (any depth)
+pub fn matches_glob(pattern: &str, path: &str) -> bool {
+    // Exact match
+    if pattern == path {
+        return true;
+    }
+
+    // "**" matches everything
+    if pattern == "**" || pattern == "/**" {
+        return true;
+    }
+
+    let pat_parts: Vec<&str> = pattern.split('/').filter(|s| !s.is_empty()).collect();
+    let path_parts: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+
+    matches_parts(&pat_parts, &path_parts)
+}
+
+fn matches_parts(pattern: &[&str], path: &[&str]) -> bool {
+    if pattern.is_empty() {
+        return path.is_empty();
+    }
+
+    if pattern[0] == "**" {
+        // ** matches zero or more path segments
+        for i in 0..=path.len() {
+            if matches_parts(&pattern[1..], &path[i..]) {
+                return true;
+            }
+        }
+        return false;
+    }
+
+    if path.is_empty() {
+        return false;
+    }
+
+    // * matches any single segment, otherwise exact match
+    let seg_match = pattern[0] == "*" || pattern[0] == path[0];
+    if seg_match {
+        return matches_parts(&pattern[1..], &path[1..]);
+    }
+
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exact_match() {
+        assert!(matches_glob("/api/users", "/api/users"));
+        assert!(!matches_glob("/api/users", "/api/posts"));
+    }
+
+    #[test]
+    fn test_wildcard() {
+        assert!(matches_glob("/api/*", "/api/users"));
+        assert!(!matches_glob("/api/*", "/api/users/123"));
+    }
+
+    #[test]
+    fn test_double_wildcard() {
+        assert!(matches_glob("/admin/**", "/admin/settings"));
+        assert!(matches_glob("/admin/**", "/admin/users/123/edit"));
+    }
+}
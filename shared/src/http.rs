@@ -0,0 +1,332 @@
+//! HTTP/1.1 message heads for raw sockets
+//!
+//! Enough HTTP for code that talks to a service over a plain byte
+//! stream: parse a request or response head out of a buffer that may
+//! not hold all of it yet (via httparse), work out where the body ends,
+//! decode chunked bodies as they arrive, and write heads back out.
+
+use crate::{Error, Result};
+
+/// Most headers accepted in one head
+pub const MAX_HEADERS: usize = 128;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestHead {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    /// Bytes taken by the head, blank line included
+    pub len: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResponseHead {
+    pub status: u16,
+    pub reason: String,
+    pub headers: Vec<(String, String)>,
+    /// Bytes taken by the head, blank line included
+    pub len: usize,
+}
+
+impl ResponseHead {
+    /// 1xx responses other than 101 come ahead of the real one
+    /// (`100 Continue`, `103 Early Hints`)
+    pub fn is_interim(&self) -> bool {
+        (100..200).contains(&self.status) && self.status != 101
+    }
+}
+
+/// Parse a request head from the start of `buf`; `None` until all of
+/// it has arrived
+pub fn parse_request(buf: &[u8]) -> Result<Option<RequestHead>> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut req = httparse::Request::new(&mut headers);
+    match req.parse(buf).map_err(malformed)? {
+        httparse::Status::Partial => Ok(None),
+        httparse::Status::Complete(len) => Ok(Some(RequestHead {
+            method: req.method.unwrap_or_default().to_string(),
+            path: req.path.unwrap_or_default().to_string(),
+            headers: collect(req.headers),
+            len,
+        })),
+    }
+}
+
+/// Parse a response head from the start of `buf`; `None` until all of
+/// it has arrived
+pub fn parse_response(buf: &[u8]) -> Result<Option<ResponseHead>> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut res = httparse::Response::new(&mut headers);
+    match res.parse(buf).map_err(malformed)? {
+        httparse::Status::Partial => Ok(None),
+        httparse::Status::Complete(len) => Ok(Some(ResponseHead {
+            status: res.code.unwrap_or_default(),
+            reason: res.reason.unwrap_or_default().to_string(),
+            headers: collect(res.headers),
+            len,
+        })),
+    }
+}
+
+fn collect(headers: &[httparse::Header]) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|h| (h.name.to_string(), String::from_utf8_lossy(h.value).into_owned()))
+        .collect()
+}
+
+fn malformed(e: httparse::Error) -> Error {
+    Error::Protocol(format!("Malformed HTTP head: {}", e))
+}
+
+/// First value of a header, by case-insensitive name
+pub fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+}
+
+/// How the body after a head is delimited
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BodyLength {
+    Empty,
+    Fixed(usize),
+    Chunked,
+    /// Everything until the connection closes
+    UntilClose,
+}
+
+/// Body framing of a response to a `method` request (RFC 9112 §6.3)
+pub fn response_body_length(method: &str, head: &ResponseHead) -> Result<BodyLength> {
+    if method.eq_ignore_ascii_case("HEAD") || head.status < 200 || head.status == 204 || head.status == 304 {
+        return Ok(BodyLength::Empty);
+    }
+    if let Some(coding) = transfer_coding(&head.headers) {
+        // Any coding other than a final chunked runs until close
+        return Ok(if coding.eq_ignore_ascii_case("chunked") { BodyLength::Chunked } else { BodyLength::UntilClose });
+    }
+    Ok(content_length(&head.headers)?.map_or(BodyLength::UntilClose, BodyLength::Fixed))
+}
+
+/// Body framing of a request; requests without a length have no body
+pub fn request_body_length(head: &RequestHead) -> Result<BodyLength> {
+    if let Some(coding) = transfer_coding(&head.headers) {
+        if !coding.eq_ignore_ascii_case("chunked") {
+            return Err(Error::Protocol(format!("Unsupported transfer coding '{}'", coding)));
+        }
+        return Ok(BodyLength::Chunked);
+    }
+    Ok(content_length(&head.headers)?.map_or(BodyLength::Empty, BodyLength::Fixed))
+}
+
+/// The last transfer coding applied, if any
+fn transfer_coding(headers: &[(String, String)]) -> Option<&str> {
+    headers
+        .iter()
+        .filter(|(k, _)| k.eq_ignore_ascii_case("transfer-encoding"))
+        .flat_map(|(_, v)| v.split(','))
+        .map(str::trim)
+        .rfind(|c| !c.is_empty())
+}
+
+/// Content-Length, which may repeat but must agree with itself
+fn content_length(headers: &[(String, String)]) -> Result<Option<usize>> {
+    let mut length = None;
+    for value in headers.iter().filter(|(k, _)| k.eq_ignore_ascii_case("content-length")) {
+        for part in value.1.split(',') {
+            let n = part
+                .trim()
+                .parse::<usize>()
+                .map_err(|_| Error::Protocol(format!("Invalid Content-Length '{}'", value.1)))?;
+            if length.is_some_and(|l| l != n) {
+                return Err(Error::Protocol("Conflicting Content-Length headers".into()));
+            }
+            length = Some(n);
+        }
+    }
+    Ok(length)
+}
+
+/// Incremental decoder for a chunked body
+#[derive(Debug, Default)]
+pub struct ChunkedDecoder {
+    state: ChunkState,
+    /// Input not yet consumed
+    pending: Vec<u8>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum ChunkState {
+    #[default]
+    Size,
+    Data(usize),
+    /// The CRLF after a chunk's data
+    DataEnd,
+    /// Trailer fields after the last chunk, up to a blank line
+    Trailers,
+    Done,
+}
+
+impl ChunkedDecoder {
+    /// Feed bytes as they arrive, appending decoded body bytes to `out`
+    pub fn push(&mut self, input: &[u8], out: &mut Vec<u8>) -> Result<()> {
+        self.pending.extend_from_slice(input);
+        let mut pos = 0;
+        loop {
+            let buf = &self.pending[pos..];
+            match self.state {
+                ChunkState::Done => break,
+                ChunkState::Size => match httparse::parse_chunk_size(buf) {
+                    Ok(httparse::Status::Complete((used, 0))) => {
+                        pos += used;
+                        self.state = ChunkState::Trailers;
+                    }
+                    Ok(httparse::Status::Complete((used, size))) => {
+                        let size = usize::try_from(size).map_err(|_| Error::Protocol("Chunk too large".into()))?;
+                        pos += used;
+                        self.state = ChunkState::Data(size);
+                    }
+                    Ok(httparse::Status::Partial) => break,
+                    Err(_) => return Err(Error::Protocol("Malformed chunk size".into())),
+                },
+                ChunkState::Data(left) => {
+                    if buf.is_empty() {
+                        break;
+                    }
+                    let take = left.min(buf.len());
+                    out.extend_from_slice(&buf[..take]);
+                    pos += take;
+                    self.state = if take == left { ChunkState::DataEnd } else { ChunkState::Data(left - take) };
+                }
+                ChunkState::DataEnd => {
+                    if buf.len() < 2 {
+                        break;
+                    }
+                    if &buf[..2] != b"\r\n" {
+                        return Err(Error::Protocol("Chunk data not followed by CRLF".into()));
+                    }
+                    pos += 2;
+                    self.state = ChunkState::Size;
+                }
+                ChunkState::Trailers => {
+                    let Some(eol) = buf.windows(2).position(|w| w == b"\r\n") else {
+                        break;
+                    };
+                    pos += eol + 2;
+                    if eol == 0 {
+                        self.state = ChunkState::Done;
+                    }
+                }
+            }
+        }
+        self.pending.drain(..pos);
+        Ok(())
+    }
+
+    /// Whether the last chunk and its trailers have been read
+    pub fn is_done(&self) -> bool {
+        self.state == ChunkState::Done
+    }
+}
+
+/// Request line and headers, ending with the blank line
+pub fn request_head(method: &str, path: &str, headers: &[(String, String)]) -> String {
+    let mut head = format!("{} {} HTTP/1.1\r\n", method, path);
+    push_headers(&mut head, headers);
+    head
+}
+
+/// Status line and headers, ending with the blank line
+pub fn response_head(status: u16, headers: &[(String, String)]) -> String {
+    let mut head = format!("HTTP/1.1 {} {}\r\n", status, reason(status));
+    push_headers(&mut head, headers);
+    head
+}
+
+fn push_headers(head: &mut String, headers: &[(String, String)]) {
+    for (key, value) in headers {
+        head.push_str(key);
+        head.push_str(": ");
+        head.push_str(value);
+        head.push_str("\r\n");
+    }
+    head.push_str("\r\n");
+}
+
+/// Reason phrase for common status codes
+pub fn reason(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        413 => "Content Too Large",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_heads() {
+        let raw = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nX-Id: 7\r\n\r\nhello";
+        assert_eq!(parse_response(&raw[..20]).unwrap(), None);
+        let head = parse_response(raw).unwrap().unwrap();
+        assert_eq!((head.status, head.reason.as_str(), &raw[head.len..]), (200, "OK", &b"hello"[..]));
+        assert_eq!(header(&head.headers, "x-id"), Some("7"));
+        assert_eq!(response_body_length("GET", &head).unwrap(), BodyLength::Fixed(5));
+        assert_eq!(response_body_length("HEAD", &head).unwrap(), BodyLength::Empty);
+
+        let serialized = response_head(head.status, &head.headers);
+        assert_eq!(parse_response(serialized.as_bytes()).unwrap().unwrap().headers, head.headers);
+
+        let raw = request_head("POST", "/upload?x=1", &[("Transfer-Encoding".into(), "gzip, chunked".into())]);
+        let head = parse_request(raw.as_bytes()).unwrap().unwrap();
+        assert_eq!((head.method.as_str(), head.path.as_str(), head.len), ("POST", "/upload?x=1", raw.len()));
+        assert_eq!(request_body_length(&head).unwrap(), BodyLength::Chunked);
+
+        assert!(parse_response(b"SSH-2.0-OpenSSH\r\n").is_err());
+        let conflicting = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\n";
+        assert!(response_body_length("GET", &parse_response(conflicting).unwrap().unwrap()).is_err());
+        let close_delimited = parse_response(b"HTTP/1.0 200 OK\r\n\r\n").unwrap().unwrap();
+        assert_eq!(response_body_length("GET", &close_delimited).unwrap(), BodyLength::UntilClose);
+    }
+
+    #[test]
+    fn test_chunked_decoder() {
+        let raw = b"5\r\nhello\r\n1;ext=1\r\n \r\n5\r\nworld\r\n0\r\nTrailer: x\r\n\r\n";
+        // Byte by byte, to cross every boundary
+        let mut decoder = ChunkedDecoder::default();
+        let mut body = Vec::new();
+        for b in raw.iter() {
+            assert!(!decoder.is_done());
+            decoder.push(&[*b], &mut body).unwrap();
+        }
+        assert!(decoder.is_done());
+        assert_eq!(body, b"hello world");
+
+        let mut decoder = ChunkedDecoder::default();
+        assert!(decoder.push(b"5\r\nhelloXX", &mut Vec::new()).is_err());
+    }
+}
@@ -3,8 +3,20 @@
 //! Common types, protocols, and FFI bindings for libzcrypto.
 
 pub mod protocol;
+pub mod batch;
+pub mod deflate;
 pub mod crypto;
 pub mod error;
 pub mod throttle;
+pub mod ratelimit;
+pub mod glob;
+pub mod schedule;
+pub mod http;
+pub mod validate;
+pub mod telemetry;
+pub mod watch;
+#[cfg(feature = "report")]
+pub mod report;
 
-pub use error::{Error, Result};
+pub use error::{Error, Result, RetryAdvice};
+pub use telemetry::Telemetry;
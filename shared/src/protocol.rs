@@ -1,5 +1,10 @@
-//! Binary protocol types for ZTunnel communication.
+//! Protocol types for ZTunnel communication.
+//!
+//! The binary handshake and framing types, plus the JSON registration
+//! exchanged when a tunnel WebSocket opens.
 
+use crate::schedule::{self, Schedule};
+use crate::{validate, Error, Result};
 use serde::{Deserialize, Serialize};
 
 /// Maximum message size (16 MB)
@@ -75,3 +80,465 @@ pub struct DataFrame {
     pub ciphertext: Vec<u8>,
     pub tag: [u8; 16],
 }
+
+/// Version of the registration handshake
+pub const REGISTER_VERSION: u32 = 1;
+
+/// Tunnel protocols a client can register
+pub const PROTOCOLS: &[&str] = &["http", "tcp", "udp"];
+
+/// Optional features announced in `capabilities`
+pub mod capability {
+    /// Large request bodies may follow the request as stream frames
+    pub const BODY_STREAM: &str = "body_stream";
+    /// Large response bodies may follow the response as stream frames
+    pub const RESPONSE_STREAM: &str = "response_stream";
+    /// Small messages may be joined into one WebSocket message (see
+    /// `batch`)
+    pub const BATCH: &str = "batch";
+    /// Binary messages may be compressed (see `deflate`)
+    pub const DEFLATE: &str = "deflate";
+}
+
+/// Request headers on the relay's `/connect/NAME` WebSocket, passed to
+/// the tunnel with the new stream
+pub mod connect {
+    /// `host:port` a gateway tunnel should dial
+    pub const TARGET_HEADER: &str = "x-ztunnel-target";
+    /// Token the gateway checks
+    pub const TOKEN_HEADER: &str = "x-ztunnel-token";
+}
+
+fn first_version() -> u32 {
+    1
+}
+
+/// First message on a tunnel WebSocket: the client asks for a tunnel.
+/// Deserializing validates it, so a relay never acts on a half-read
+/// registration.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "RegisterFields")]
+pub struct Register {
+    pub version: u32,
+    /// Requested subdomain; the relay picks one when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subdomain: Option<String>,
+    /// One of `PROTOCOLS`
+    #[serde(rename = "type")]
+    pub proto: String,
+    pub local_port: u16,
+    /// Tunnel name, for the relay's logs
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth_token: Option<String>,
+    #[serde(skip_serializing_if = "IpFilterRules::is_empty")]
+    pub ip_filter: IpFilterRules,
+    /// Rules the relay applies before forwarding a request
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub policies: Vec<Policy>,
+    /// Token from an earlier `RegisterAck`, to get the same tunnel back
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resume_token: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub capabilities: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct RegisterFields {
+    #[serde(default = "first_version")]
+    version: u32,
+    #[serde(default)]
+    subdomain: Option<String>,
+    #[serde(rename = "type")]
+    proto: String,
+    #[serde(default)]
+    local_port: u16,
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    auth_token: Option<String>,
+    #[serde(default)]
+    ip_filter: IpFilterRules,
+    #[serde(default)]
+    policies: Vec<Policy>,
+    #[serde(default)]
+    resume_token: Option<String>,
+    #[serde(default)]
+    capabilities: Vec<String>,
+}
+
+impl TryFrom<RegisterFields> for Register {
+    type Error = Error;
+
+    fn try_from(f: RegisterFields) -> Result<Self> {
+        let register = Register {
+            version: f.version,
+            subdomain: f.subdomain,
+            proto: f.proto,
+            local_port: f.local_port,
+            name: f.name,
+            auth_token: f.auth_token,
+            ip_filter: f.ip_filter,
+            policies: f.policies,
+            resume_token: f.resume_token,
+            capabilities: f.capabilities,
+        };
+        register.validate()?;
+        Ok(register)
+    }
+}
+
+impl Register {
+    pub fn new(proto: impl Into<String>) -> Self {
+        Self {
+            version: REGISTER_VERSION,
+            subdomain: None,
+            proto: proto.into(),
+            local_port: 0,
+            name: String::new(),
+            auth_token: None,
+            ip_filter: IpFilterRules::default(),
+            policies: Vec::new(),
+            resume_token: None,
+            capabilities: Vec::new(),
+        }
+    }
+
+    pub fn has_capability(&self, name: &str) -> bool {
+        self.capabilities.iter().any(|c| c == name)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        check_version(self.version)?;
+        if !PROTOCOLS.contains(&self.proto.as_str()) {
+            return Err(Error::Tunnel(format!("Unsupported tunnel type '{}'", self.proto)));
+        }
+        if let Some(subdomain) = &self.subdomain {
+            validate::check_subdomain(subdomain)?;
+        }
+        self.ip_filter.validate()?;
+        for policy in &self.policies {
+            policy.validate()?;
+        }
+        Ok(())
+    }
+}
+
+/// The relay's answer to a `Register`
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(try_from = "RegisterAckFields")]
+pub struct RegisterAck {
+    pub version: u32,
+    pub success: bool,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub subdomain: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub url: String,
+    /// The requested subdomain was taken and another was assigned
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub reassigned: bool,
+    /// Why the registration was refused
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<Error>,
+    /// Present this in the next `Register` to resume the tunnel
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resume_token: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub capabilities: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct RegisterAckFields {
+    #[serde(default = "first_version")]
+    version: u32,
+    success: bool,
+    #[serde(default)]
+    subdomain: String,
+    #[serde(default)]
+    url: String,
+    #[serde(default)]
+    reassigned: bool,
+    #[serde(default)]
+    error: Option<AckError>,
+    #[serde(default)]
+    resume_token: Option<String>,
+    #[serde(default)]
+    capabilities: Vec<String>,
+}
+
+/// Relays before `REGISTER_VERSION` 1 sent a bare reason string
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum AckError {
+    Typed(Error),
+    Reason(String),
+}
+
+impl TryFrom<RegisterAckFields> for RegisterAck {
+    type Error = Error;
+
+    fn try_from(f: RegisterAckFields) -> Result<Self> {
+        check_version(f.version)?;
+        let error = f.error.map(|e| match e {
+            AckError::Typed(e) => e,
+            AckError::Reason(reason) => Error::Tunnel(reason),
+        });
+        let error = match (f.success, error) {
+            (true, _) if f.subdomain.is_empty() || f.url.is_empty() => {
+                return Err(Error::Protocol("Registration accepted without a subdomain and URL".into()));
+            }
+            (true, _) => None,
+            (false, error) => Some(error.unwrap_or_else(|| Error::Tunnel("Registration refused".into()))),
+        };
+        Ok(RegisterAck {
+            version: f.version,
+            success: f.success,
+            subdomain: f.subdomain,
+            url: f.url,
+            reassigned: f.reassigned,
+            error,
+            resume_token: f.resume_token,
+            capabilities: f.capabilities,
+        })
+    }
+}
+
+impl RegisterAck {
+    pub fn accepted(subdomain: impl Into<String>, url: impl Into<String>, reassigned: bool) -> Self {
+        Self {
+            version: REGISTER_VERSION,
+            success: true,
+            subdomain: subdomain.into(),
+            url: url.into(),
+            reassigned,
+            error: None,
+            resume_token: None,
+            capabilities: Vec::new(),
+        }
+    }
+
+    pub fn rejected(error: Error) -> Self {
+        Self {
+            version: REGISTER_VERSION,
+            success: false,
+            subdomain: String::new(),
+            url: String::new(),
+            reassigned: false,
+            error: Some(error),
+            resume_token: None,
+            capabilities: Vec::new(),
+        }
+    }
+}
+
+/// Something the relay tells a registered client outside of requests,
+/// sent as a text message. Clients that predate it ignore text messages.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Notice {
+    pub message: String,
+    /// Seconds until the relay closes the tunnel
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub closes_in: Option<u64>,
+}
+
+fn check_version(version: u32) -> Result<()> {
+    if version == 0 || version > REGISTER_VERSION {
+        return Err(Error::Protocol(format!(
+            "Unsupported registration version {} (this side speaks up to {})",
+            version, REGISTER_VERSION
+        )));
+    }
+    Ok(())
+}
+
+/// Source address rules, as IPs or CIDR ranges
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct IpFilterRules {
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+impl IpFilterRules {
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        for range in self.allow.iter().chain(&self.deny) {
+            if !is_cidr(range) {
+                return Err(Error::Tunnel(format!("Invalid IP range '{}'", range)));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn is_cidr(s: &str) -> bool {
+    let (addr, prefix) = match s.split_once('/') {
+        Some((addr, prefix)) => (addr, Some(prefix)),
+        None => (s, None),
+    };
+    let Ok(addr) = addr.parse::<std::net::IpAddr>() else {
+        return false;
+    };
+    let max = if addr.is_ipv4() { 32 } else { 128 };
+    prefix.is_none_or(|p| p.parse::<u8>().is_ok_and(|p| p <= max))
+}
+
+/// An address, or an IPv4 range: what a policy can match visitors by
+fn is_source(s: &str) -> bool {
+    match s.split_once('/') {
+        Some((addr, _)) => addr.parse::<std::net::Ipv4Addr>().is_ok() && is_cidr(s),
+        None => s.parse::<std::net::IpAddr>().is_ok(),
+    }
+}
+
+/// A traffic rule evaluated at the relay. The first rule whose path
+/// glob (and method, visitor address and time, if set) matches decides
+/// the request.
+///
+/// ```yaml
+/// - path: /admin/**
+///   action: block
+///   status: 403
+///   except_from: [10.0.0.0/8]
+/// - path: /**
+///   action: require_auth
+///   except_during: Mon-Fri 09:00-18:00
+///   timezone: Europe/Paris
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Policy {
+    /// Path glob, e.g. "/admin/**"
+    pub path: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub method: Option<String>,
+    /// Only for visitors from these addresses or IPv4 ranges. The relay
+    /// sees the connection's address, or the one its trusted proxies
+    /// forward; never a header the visitor sets
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub from: Vec<String>,
+    /// Not for visitors from these
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub except_from: Vec<String>,
+    /// Only during these windows, e.g. "Sat 02:00-04:00"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub during: Option<String>,
+    /// Not during these
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub except_during: Option<String>,
+    /// Timezone the windows are in, e.g. "Europe/Paris" or "+02:00";
+    /// UTC by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,
+    #[serde(flatten)]
+    pub action: PolicyAction,
+}
+
+/// What a matching `Policy` does
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum PolicyAction {
+    Allow,
+    /// Answer with this status
+    Block { status: u16 },
+    /// Send the visitor elsewhere
+    Redirect { to: String },
+    /// Challenge for credentials before forwarding
+    RequireAuth,
+    RateLimit { per_minute: u32 },
+    /// Add a header to the response
+    AddHeader { name: String, value: String },
+}
+
+impl Policy {
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |why: &str| Err(Error::Tunnel(format!("Invalid policy for '{}': {}", self.path, why)));
+        if !self.path.starts_with('/') {
+            return invalid("path must start with /");
+        }
+        if let Some(source) = self.from.iter().chain(&self.except_from).find(|s| !is_source(s)) {
+            return invalid(&format!("'{}' is not an IP address or IPv4 range", source));
+        }
+        for windows in self.during.iter().chain(&self.except_during) {
+            if let Err(e) = Schedule::parse(windows) {
+                return invalid(&e.to_string());
+            }
+        }
+        if let Some(Err(e)) = self.timezone.as_deref().map(schedule::Zone::parse) {
+            return invalid(&e.to_string());
+        }
+        match &self.action {
+            PolicyAction::Block { status } if !(400..=599).contains(status) => invalid("block status must be 4xx or 5xx"),
+            PolicyAction::Redirect { to } if to.is_empty() => invalid("empty redirect target"),
+            PolicyAction::RateLimit { per_minute: 0 } => invalid("per_minute must be at least 1"),
+            PolicyAction::AddHeader { name, .. } if name.is_empty() || name.contains(|c: char| c == ':' || c.is_whitespace()) => {
+                invalid("bad header name")
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register_validates() {
+        let mut register = Register::new("http");
+        register.subdomain = Some("myapp".into());
+        register.capabilities.push(capability::BODY_STREAM.into());
+        register.policies.push(Policy {
+            path: "/admin/**".into(),
+            method: None,
+            from: Vec::new(),
+            except_from: vec!["10.0.0.0/8".into()],
+            during: None,
+            except_during: Some("Mon-Fri 09:00-18:00".into()),
+            timezone: Some("Europe/Paris".into()),
+            action: PolicyAction::Block { status: 403 },
+        });
+        let json = serde_json::to_string(&register).unwrap();
+        assert_eq!(serde_json::from_str::<Register>(&json).unwrap(), register);
+
+        // Version defaults to 1 for clients that don't send one
+        let old: Register = serde_json::from_str(r#"{"type": "tcp", "local_port": 5432}"#).unwrap();
+        assert_eq!((old.version, old.local_port), (1, 5432));
+
+        for bad in [
+            r#"{"subdomain": "x"}"#,
+            r#"{"type": "ftp"}"#,
+            r#"{"type": "http", "version": 99}"#,
+            r#"{"type": "http", "subdomain": "Not_A_Label"}"#,
+            r#"{"type": "http", "ip_filter": {"allow": ["10.0.0.0/33"]}}"#,
+            r#"{"type": "http", "policies": [{"path": "/", "action": "block", "status": 200}]}"#,
+            r#"{"type": "http", "policies": [{"path": "/", "action": "allow", "from": ["2001:db8::/32"]}]}"#,
+            r#"{"type": "http", "policies": [{"path": "/", "action": "allow", "during": "9-5"}]}"#,
+            r#"{"type": "http", "policies": [{"path": "/", "action": "allow", "timezone": "Mars/Olympus_Mons"}]}"#,
+        ] {
+            assert!(serde_json::from_str::<Register>(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_register_ack() {
+        let ack: RegisterAck = serde_json::from_str(
+            r#"{"success": true, "subdomain": "web", "url": "https://web.example.com"}"#,
+        )
+        .unwrap();
+        assert!(ack.error.is_none() && !ack.reassigned);
+
+        let legacy: RegisterAck = serde_json::from_str(r#"{"success": false, "error": "bad token"}"#).unwrap();
+        assert!(matches!(legacy.error, Some(Error::Tunnel(r)) if r == "bad token"));
+
+        let json = serde_json::to_string(&RegisterAck::rejected(Error::AuthFailed)).unwrap();
+        let typed: RegisterAck = serde_json::from_str(&json).unwrap();
+        assert!(matches!(typed.error, Some(Error::AuthFailed)));
+
+        assert!(serde_json::from_str::<RegisterAck>(r#"{"success": true}"#).is_err());
+    }
+}
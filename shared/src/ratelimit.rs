@@ -0,0 +1,202 @@
+//! Token-bucket rate limiting
+//!
+//! `Bucket` is the single token bucket behind every limit: bandwidth
+//! pacing (`throttle::TokenBucket`), policy `rate_limit` rules and
+//! per-IP request limits. `RateLimiter` keeps one bucket per key and
+//! forgets keys once they have been idle long enough to refill.
+//!
+//! Nothing here sleeps. Callers either get a yes/no with a retry hint
+//! (`check`) or a pause to wait out on their own runtime (`reserve`).
+
+use crate::Error;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How fast tokens come back, and how many can pile up
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quota {
+    /// Tokens per second
+    pub rate: f64,
+    /// Most tokens held at once
+    pub burst: f64,
+}
+
+impl Quota {
+    /// `n` per second, with one second of burst
+    pub fn per_second(n: u64) -> Self {
+        let rate = n.max(1) as f64;
+        Self { rate, burst: rate }
+    }
+
+    /// `n` per minute, all of which may be used at once
+    pub fn per_minute(n: u32) -> Self {
+        let n = n.max(1) as f64;
+        Self { rate: n / 60.0, burst: n }
+    }
+
+    pub fn with_burst(self, burst: u32) -> Self {
+        Self { burst: burst.max(1) as f64, ..self }
+    }
+
+    /// Time for an empty bucket to fill back up
+    fn refill_time(&self) -> Duration {
+        Duration::from_secs_f64(self.burst / self.rate)
+    }
+}
+
+/// A token bucket. The quota is passed in on each call so one bucket
+/// type serves limits that change at runtime.
+#[derive(Debug, Clone)]
+pub struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    /// A bucket with the whole burst available
+    pub fn full(quota: Quota) -> Self {
+        Self { tokens: quota.burst, last: Instant::now() }
+    }
+
+    fn refill(&mut self, quota: Quota, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * quota.rate).min(quota.burst);
+    }
+
+    /// Take `n` tokens if they're there; otherwise how long until they
+    /// will be
+    pub fn try_take(&mut self, quota: Quota, n: f64) -> Result<(), Duration> {
+        self.refill(quota, Instant::now());
+        if self.tokens >= n {
+            self.tokens -= n;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((n - self.tokens) / quota.rate))
+        }
+    }
+
+    /// Take `n` tokens even if that goes into debt, returning how long
+    /// to pause until the debt is paid. A large take is paid for by a
+    /// proportionally long pause.
+    pub fn reserve(&mut self, quota: Quota, n: f64) -> Duration {
+        self.refill(quota, Instant::now());
+        self.tokens -= n;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / quota.rate)
+        }
+    }
+
+    /// Apply a smaller burst right away
+    pub fn clamp(&mut self, quota: Quota) {
+        self.refill(quota, Instant::now());
+        self.tokens = self.tokens.min(quota.burst);
+    }
+}
+
+/// How often a limiter looks for idle keys
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// One bucket per key (visitor IP, auth token, policy rule, ...)
+#[derive(Debug)]
+pub struct RateLimiter<K> {
+    quota: Quota,
+    state: Mutex<Keys<K>>,
+}
+
+#[derive(Debug)]
+struct Keys<K> {
+    buckets: HashMap<K, Bucket>,
+    last_sweep: Instant,
+}
+
+impl<K: Hash + Eq + Clone> RateLimiter<K> {
+    pub fn new(quota: Quota) -> Self {
+        Self { quota, state: Mutex::new(Keys { buckets: HashMap::new(), last_sweep: Instant::now() }) }
+    }
+
+    pub fn quota(&self) -> Quota {
+        self.quota
+    }
+
+    /// Count one event for `key` against the limiter's quota
+    pub fn check(&self, key: &K) -> Result<(), Error> {
+        self.check_with(key, self.quota)
+    }
+
+    /// Count one event for `key` against `quota`, for keys with limits
+    /// of their own. Over the limit comes back as `Error::RateLimited`
+    /// with the seconds until the next event would pass.
+    pub fn check_with(&self, key: &K, quota: Quota) -> Result<(), Error> {
+        self.with_bucket(key, quota, |bucket| bucket.try_take(quota, 1.0))
+            .map_err(|wait| Error::RateLimited(wait.as_secs_f64().ceil().max(1.0) as u64))
+    }
+
+    /// Take `n` tokens for `key`, returning how long to pause first
+    pub fn reserve(&self, key: &K, n: f64) -> Duration {
+        let quota = self.quota;
+        self.with_bucket(key, quota, |bucket| bucket.reserve(quota, n))
+    }
+
+    /// Keys currently tracked
+    pub fn len(&self) -> usize {
+        self.lock().buckets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn with_bucket<T>(&self, key: &K, quota: Quota, f: impl FnOnce(&mut Bucket) -> T) -> T {
+        let mut state = self.lock();
+        let now = Instant::now();
+        if now.duration_since(state.last_sweep) >= SWEEP_INTERVAL {
+            state.last_sweep = now;
+            // A bucket untouched for a full refill is back to a fresh
+            // one, so there's nothing to remember
+            let idle = quota.refill_time().max(self.quota.refill_time());
+            state.buckets.retain(|_, b| now.saturating_duration_since(b.last) < idle);
+        }
+        let bucket = state.buckets.entry(key.clone()).or_insert_with(|| Bucket::full(quota));
+        f(bucket)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Keys<K>> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limiter_per_key() {
+        let limiter = RateLimiter::new(Quota::per_minute(2));
+        assert!(limiter.check(&"a").is_ok());
+        assert!(limiter.check(&"a").is_ok());
+        match limiter.check(&"a") {
+            Err(Error::RateLimited(secs)) => assert!((1..=30).contains(&secs), "{}", secs),
+            other => panic!("expected a rate limit, got {:?}", other),
+        }
+        // Other keys have their own bucket
+        assert!(limiter.check(&"b").is_ok());
+        assert_eq!(limiter.len(), 2);
+
+        // A stricter quota for one key
+        assert!(limiter.check_with(&"c", Quota::per_minute(1)).is_ok());
+        assert!(limiter.check_with(&"c", Quota::per_minute(1)).is_err());
+    }
+
+    #[test]
+    fn test_reserve_goes_into_debt() {
+        let limiter = RateLimiter::new(Quota::per_second(1000));
+        assert_eq!(limiter.reserve(&(), 1000.0), Duration::ZERO);
+        let wait = limiter.reserve(&(), 500.0);
+        assert!(wait > Duration::from_millis(400) && wait <= Duration::from_millis(500), "{:?}", wait);
+    }
+}
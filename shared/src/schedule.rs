@@ -0,0 +1,216 @@
+//! Recurring windows of the week
+//!
+//! A tunnel's `active_hours` and a policy's `during` are written as
+//!
+//! ```text
+//! Mon-Fri 09:00-18:00; Sat,Sun 10:00-14:00
+//! ```
+//!
+//! Windows are separated by `;`. Days are names, ranges of names, or
+//! lists of either; without days a window applies daily. A window whose
+//! end is before its start runs past midnight into the next day.
+
+use chrono::{DateTime, Datelike, Duration, FixedOffset, NaiveDate, NaiveDateTime, NaiveTime, Utc};
+use chrono_tz::Tz;
+
+const DAYS: [&str; 7] = ["monday", "tuesday", "wednesday", "thursday", "friday", "saturday", "sunday"];
+
+/// Why a schedule was refused
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("{0}")]
+pub struct Invalid(String);
+
+type Result<T> = std::result::Result<T, Invalid>;
+
+/// One recurring window
+#[derive(Debug, Clone, PartialEq)]
+struct Window {
+    /// Days it starts on, Monday first
+    days: [bool; 7],
+    start: NaiveTime,
+    /// Length, up to a day
+    length: Duration,
+}
+
+/// Windows of the week, e.g. when a tunnel is active
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schedule {
+    windows: Vec<Window>,
+}
+
+impl Schedule {
+    /// Parse e.g. `Mon-Fri 09:00-18:00; Sat 10:00-14:00`
+    pub fn parse(spec: &str) -> Result<Self> {
+        let windows: Vec<Window> =
+            spec.split(';').map(str::trim).filter(|w| !w.is_empty()).map(parse_window).collect::<Result<_>>()?;
+        if windows.is_empty() {
+            return Err(Invalid(format!("No time windows in '{}'", spec)));
+        }
+        Ok(Self { windows })
+    }
+
+    /// Occurrences overlapping the week from `now`, including one that
+    /// began the day before
+    fn intervals(&self, now: NaiveDateTime) -> Vec<(NaiveDateTime, NaiveDateTime)> {
+        let today = now.date();
+        let mut intervals = Vec::new();
+        for offset in -1..=8 {
+            let day: NaiveDate = today + Duration::days(offset);
+            for w in &self.windows {
+                if w.days[day.weekday().num_days_from_monday() as usize] {
+                    let start = day.and_time(w.start);
+                    intervals.push((start, start + w.length));
+                }
+            }
+        }
+        intervals
+    }
+
+    pub fn is_active(&self, now: NaiveDateTime) -> bool {
+        self.intervals(now).iter().any(|(start, end)| *start <= now && now < *end)
+    }
+
+    /// When the tunnel next goes from active to inactive or back
+    pub fn next_change(&self, now: NaiveDateTime) -> NaiveDateTime {
+        let intervals = self.intervals(now);
+        if !self.is_active(now) {
+            let next = intervals.iter().map(|(start, _)| *start).filter(|s| *s > now).min();
+            return next.unwrap_or(now + Duration::days(7));
+        }
+        // Back-to-back windows make one stretch
+        let mut until = now;
+        while let Some(end) = intervals.iter().filter(|(s, e)| *s <= until && until < *e).map(|(_, e)| *e).max() {
+            until = end;
+        }
+        until
+    }
+}
+
+/// The timezone a schedule is read in: a name such as `Europe/Paris`,
+/// which follows daylight saving, or `UTC` or a fixed offset like `+02:00`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Zone {
+    Named(Tz),
+    Fixed(FixedOffset),
+}
+
+impl Default for Zone {
+    fn default() -> Self {
+        Zone::Fixed(FixedOffset::east_opt(0).unwrap())
+    }
+}
+
+impl Zone {
+    pub fn parse(spec: &str) -> Result<Self> {
+        let spec = spec.trim();
+        if spec.eq_ignore_ascii_case("utc") || spec.eq_ignore_ascii_case("z") {
+            return Ok(Zone::default());
+        }
+        if let Ok(offset) = spec.parse() {
+            return Ok(Zone::Fixed(offset));
+        }
+        spec.parse().map(Zone::Named).map_err(|_| {
+            Invalid(format!("Invalid timezone '{}', expected a name like Europe/Paris or an offset like +02:00", spec))
+        })
+    }
+
+    /// The wall-clock time here at `at`
+    pub fn local(&self, at: DateTime<Utc>) -> NaiveDateTime {
+        match self {
+            Zone::Named(tz) => at.with_timezone(tz).naive_local(),
+            Zone::Fixed(offset) => at.with_timezone(offset).naive_local(),
+        }
+    }
+}
+
+fn parse_window(spec: &str) -> Result<Window> {
+    let (days, hours) = match spec.rsplit_once(char::is_whitespace) {
+        Some((days, hours)) => (parse_days(days.trim())?, hours),
+        None => ([true; 7], spec),
+    };
+    let Some((start, end)) = hours.split_once('-') else {
+        return Err(Invalid(format!("Invalid hours '{}', expected e.g. 09:00-18:00", hours)));
+    };
+    let (start, end) = (parse_time(start)?, parse_time(end)?);
+    let length = match end - start {
+        length if length > Duration::zero() => length,
+        length => length + Duration::days(1),
+    };
+    Ok(Window { days, start, length })
+}
+
+fn parse_days(spec: &str) -> Result<[bool; 7]> {
+    let mut days = [false; 7];
+    for part in spec.split(',') {
+        let (first, last) = part.split_once('-').unwrap_or((part, part));
+        let (first, last) = (parse_day(first)?, parse_day(last)?);
+        let mut day = first;
+        loop {
+            days[day] = true;
+            if day == last {
+                break;
+            }
+            day = (day + 1) % 7;
+        }
+    }
+    Ok(days)
+}
+
+/// A day's name or its first three letters or more, Monday as 0
+fn parse_day(name: &str) -> Result<usize> {
+    let name = name.trim().to_lowercase();
+    DAYS.iter()
+        .position(|d| name.len() >= 3 && d.starts_with(&name))
+        .ok_or_else(|| Invalid(format!("Invalid day '{}', expected e.g. Mon or Mon-Fri", name)))
+}
+
+/// `HH:MM`; `24:00` is the end of the day
+fn parse_time(spec: &str) -> Result<NaiveTime> {
+    let spec = spec.trim();
+    if spec == "24:00" {
+        return Ok(NaiveTime::MIN);
+    }
+    NaiveTime::parse_from_str(spec, "%H:%M").map_err(|_| Invalid(format!("Invalid time '{}', expected HH:MM", spec)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(spec: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(spec, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    #[test]
+    fn test_active_hours() {
+        // 2024-05-03 is a Friday
+        let schedule = Schedule::parse("Mon-Fri 09:00-18:00; Sat,Sun 22:00-02:00").unwrap();
+        assert!(schedule.is_active(at("2024-05-03 09:00")));
+        assert!(!schedule.is_active(at("2024-05-03 18:00")));
+        assert_eq!(schedule.next_change(at("2024-05-03 12:30")), at("2024-05-03 18:00"));
+        assert_eq!(schedule.next_change(at("2024-05-03 18:00")), at("2024-05-04 22:00"));
+        // Saturday night runs into Sunday
+        assert!(schedule.is_active(at("2024-05-05 01:00")));
+        assert_eq!(schedule.next_change(at("2024-05-05 01:00")), at("2024-05-05 02:00"));
+        assert_eq!(schedule.next_change(at("2024-05-05 03:00")), at("2024-05-05 22:00"));
+
+        // Back-to-back windows don't disconnect at midnight
+        let schedule = Schedule::parse("Fri-Sun 00:00-24:00").unwrap();
+        assert_eq!(schedule.next_change(at("2024-05-03 12:00")), at("2024-05-06 00:00"));
+        assert!(Schedule::parse("08:00-20:00").unwrap().is_active(at("2024-05-01 08:00")));
+
+        assert!(Schedule::parse("Mon-Fri").is_err());
+        assert!(Schedule::parse("Funday 09:00-10:00").is_err());
+        assert!(Schedule::parse("Mon 9am-5pm").is_err());
+        assert!(Schedule::parse(" ; ").is_err());
+
+        // Paris is UTC+1 in winter and UTC+2 in summer
+        let utc = |s: &str| format!("{}:00Z", s.replace(' ', "T")).parse::<DateTime<Utc>>().unwrap();
+        let paris = Zone::parse("Europe/Paris").unwrap();
+        assert_eq!(paris.local(utc("2024-01-15 08:00")), at("2024-01-15 09:00"));
+        assert_eq!(paris.local(utc("2024-07-15 08:00")), at("2024-07-15 10:00"));
+        assert_eq!(Zone::parse("+05:30").unwrap().local(utc("2024-01-15 08:00")), at("2024-01-15 13:30"));
+        assert_eq!(Zone::parse("UTC").unwrap(), Zone::default());
+        assert!(Zone::parse("Mars/Olympus_Mons").is_err());
+    }
+}
@@ -0,0 +1,143 @@
+//! Metrics recording, independent of where the numbers end up
+//!
+//! Tunnel code records through `Telemetry`; the relay's Prometheus
+//! endpoint, an OTLP exporter or `Noop` sits behind it. Programs that
+//! embed the client pass their own backend in.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Label pairs attached to one measurement
+pub type Labels<'a> = &'a [(&'a str, &'a str)];
+
+/// Names of the metrics tunnel code records, with their labels
+pub mod metric {
+    /// Counter; `tunnel`, `status`
+    pub const REQUESTS: &str = "ztunnel_requests_total";
+    /// Histogram in seconds; `tunnel`
+    pub const REQUEST_DURATION: &str = "ztunnel_request_duration_seconds";
+    /// Counter; `tunnel`, `direction` (`in` or `out`)
+    pub const BYTES: &str = "ztunnel_bytes_total";
+    /// Gauge
+    pub const ACTIVE_TUNNELS: &str = "ztunnel_active_tunnels";
+    /// Gauge; requests waiting on a client's response
+    pub const PENDING_REQUESTS: &str = "ztunnel_pending_requests";
+    /// Counter; `tunnel`
+    pub const RECONNECTS: &str = "ztunnel_reconnects_total";
+    /// Counter; `tunnel`; requests over the configured slow threshold
+    pub const SLOW_REQUESTS: &str = "ztunnel_slow_requests_total";
+}
+
+/// A metrics backend. Calls come from request paths, so they must not
+/// block for long.
+pub trait Telemetry: Send + Sync {
+    /// Add `delta` to a count that only goes up
+    fn counter(&self, name: &str, labels: Labels<'_>, delta: u64);
+
+    /// Set a value that goes up and down
+    fn gauge(&self, name: &str, labels: Labels<'_>, value: f64);
+
+    /// Record one observation, such as a duration or a size
+    fn histogram(&self, name: &str, labels: Labels<'_>, value: f64);
+
+    /// One finished HTTP request, as the standard metrics
+    fn request(&self, tunnel: &str, status: u16, latency: Duration, bytes_in: u64, bytes_out: u64) {
+        let status = status.to_string();
+        self.counter(metric::REQUESTS, &[("tunnel", tunnel), ("status", &status)], 1);
+        self.histogram(metric::REQUEST_DURATION, &[("tunnel", tunnel)], latency.as_secs_f64());
+        self.counter(metric::BYTES, &[("tunnel", tunnel), ("direction", "in")], bytes_in);
+        self.counter(metric::BYTES, &[("tunnel", tunnel), ("direction", "out")], bytes_out);
+    }
+}
+
+/// Discards everything
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Noop;
+
+impl Telemetry for Noop {
+    fn counter(&self, _: &str, _: Labels<'_>, _: u64) {}
+    fn gauge(&self, _: &str, _: Labels<'_>, _: f64) {}
+    fn histogram(&self, _: &str, _: Labels<'_>, _: f64) {}
+}
+
+/// Sends every measurement to each of several backends, such as the
+/// Prometheus endpoint and a push exporter
+#[derive(Clone, Default)]
+pub struct Fanout(pub Vec<Arc<dyn Telemetry>>);
+
+impl Telemetry for Fanout {
+    fn counter(&self, name: &str, labels: Labels<'_>, delta: u64) {
+        self.0.iter().for_each(|t| t.counter(name, labels, delta));
+    }
+
+    fn gauge(&self, name: &str, labels: Labels<'_>, value: f64) {
+        self.0.iter().for_each(|t| t.gauge(name, labels, value));
+    }
+
+    fn histogram(&self, name: &str, labels: Labels<'_>, value: f64) {
+        self.0.iter().for_each(|t| t.histogram(name, labels, value));
+    }
+}
+
+impl<T: Telemetry + ?Sized> Telemetry for Arc<T> {
+    fn counter(&self, name: &str, labels: Labels<'_>, delta: u64) {
+        (**self).counter(name, labels, delta)
+    }
+
+    fn gauge(&self, name: &str, labels: Labels<'_>, value: f64) {
+        (**self).gauge(name, labels, value)
+    }
+
+    fn histogram(&self, name: &str, labels: Labels<'_>, value: f64) {
+        (**self).histogram(name, labels, value)
+    }
+
+    fn request(&self, tunnel: &str, status: u16, latency: Duration, bytes_in: u64, bytes_out: u64) {
+        (**self).request(tunnel, status, latency, bytes_in, bytes_out)
+    }
+}
+
+/// Value of the label `key`, for backends that pick labels apart
+pub fn label<'a>(labels: Labels<'a>, key: &str) -> Option<&'a str> {
+    labels.iter().find(|(k, _)| *k == key).map(|(_, v)| *v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<String>>);
+
+    impl Telemetry for Recorder {
+        fn counter(&self, name: &str, labels: Labels<'_>, delta: u64) {
+            self.0.lock().unwrap().push(format!("{}{:?} +{}", name, labels, delta));
+        }
+        fn gauge(&self, name: &str, _: Labels<'_>, value: f64) {
+            self.0.lock().unwrap().push(format!("{} = {}", name, value));
+        }
+        fn histogram(&self, name: &str, labels: Labels<'_>, value: f64) {
+            self.0.lock().unwrap().push(format!("{}{:?} ~{}", name, labels, value));
+        }
+    }
+
+    #[test]
+    fn test_request_as_standard_metrics() {
+        let recorder = Arc::new(Recorder::default());
+        let telemetry: Arc<dyn Telemetry> = recorder.clone();
+        telemetry.request("demo", 404, Duration::from_millis(250), 10, 20);
+        telemetry.gauge(metric::ACTIVE_TUNNELS, &[], 3.0);
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            [
+                r#"ztunnel_requests_total[("tunnel", "demo"), ("status", "404")] +1"#,
+                r#"ztunnel_request_duration_seconds[("tunnel", "demo")] ~0.25"#,
+                r#"ztunnel_bytes_total[("tunnel", "demo"), ("direction", "in")] +10"#,
+                r#"ztunnel_bytes_total[("tunnel", "demo"), ("direction", "out")] +20"#,
+                "ztunnel_active_tunnels = 3",
+            ]
+        );
+        assert_eq!(label(&[("tunnel", "demo"), ("status", "200")], "status"), Some("200"));
+    }
+}
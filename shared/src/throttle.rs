@@ -1,6 +1,7 @@
 //! FFI bindings to libznet throttle (C implementation)
 
-use std::ffi::c_void;
+use crate::ratelimit::{Bucket, Quota};
+use std::time::Duration;
 
 #[repr(C)]
 pub struct ZnetThrottle {
@@ -9,10 +10,13 @@ pub struct ZnetThrottle {
 
 extern "C" {
     pub fn znet_throttle_create(bytes_per_sec: u64) -> *mut ZnetThrottle;
+    pub fn znet_throttle_create_burst(bytes_per_sec: u64, burst_bytes: u64) -> *mut ZnetThrottle;
     pub fn znet_throttle_consume(throttle: *mut ZnetThrottle, bytes: usize) -> i32;
     pub fn znet_throttle_wait(throttle: *mut ZnetThrottle);
     pub fn znet_throttle_get_rate(throttle: *mut ZnetThrottle) -> u64;
     pub fn znet_throttle_set_rate(throttle: *mut ZnetThrottle, bytes_per_sec: u64);
+    pub fn znet_throttle_get_burst(throttle: *mut ZnetThrottle) -> u64;
+    pub fn znet_throttle_set_burst(throttle: *mut ZnetThrottle, burst_bytes: u64);
     pub fn znet_throttle_destroy(throttle: *mut ZnetThrottle);
 }
 
@@ -24,7 +28,13 @@ pub struct BandwidthThrottle {
 impl BandwidthThrottle {
     /// Create a new throttle with the given rate in bytes/sec
     pub fn new(bytes_per_sec: u64) -> Option<Self> {
-        let inner = unsafe { znet_throttle_create(bytes_per_sec) };
+        Self::with_burst(bytes_per_sec, 0)
+    }
+
+    /// Create a throttle that lets up to `burst_bytes` through at once
+    /// (0 = one second's worth) while holding the long-term rate
+    pub fn with_burst(bytes_per_sec: u64, burst_bytes: u64) -> Option<Self> {
+        let inner = unsafe { znet_throttle_create_burst(bytes_per_sec, burst_bytes) };
         if inner.is_null() {
             None
         } else {
@@ -53,6 +63,16 @@ impl BandwidthThrottle {
         unsafe { znet_throttle_set_rate(self.inner, bytes_per_sec) }
     }
 
+    /// Get the bucket depth in bytes
+    pub fn get_burst(&self) -> u64 {
+        unsafe { znet_throttle_get_burst(self.inner) }
+    }
+
+    /// Update the bucket depth (0 = one second's worth)
+    pub fn set_burst(&mut self, burst_bytes: u64) {
+        unsafe { znet_throttle_set_burst(self.inner, burst_bytes) }
+    }
+
     /// Throttle a chunk of data (consume + wait if needed)
     pub fn throttle(&mut self, bytes: usize) {
         if self.consume(bytes) {
@@ -70,6 +90,72 @@ impl Drop for BandwidthThrottle {
 unsafe impl Send for BandwidthThrottle {}
 unsafe impl Sync for BandwidthThrottle {}
 
+/// Pure-Rust token bucket for async callers.
+///
+/// Instead of blocking like `BandwidthThrottle::wait`, `reserve` reports how
+/// long the caller should pause, so it can sleep on its own runtime.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: u64,
+    /// Bucket depth in bytes; 0 for one second of `rate`
+    burst: u64,
+    quota: Quota,
+    bucket: Bucket,
+}
+
+impl TokenBucket {
+    /// Create a bucket refilling at `bytes_per_sec`, holding one second of burst
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Self::with_burst(bytes_per_sec, 0)
+    }
+
+    /// Create a bucket refilling at `bytes_per_sec` that lets up to
+    /// `burst_bytes` through at once (0 = one second's worth)
+    pub fn with_burst(bytes_per_sec: u64, burst_bytes: u64) -> Self {
+        let rate = bytes_per_sec.max(1);
+        let quota = quota(rate, burst_bytes);
+        Self { rate, burst: burst_bytes, quota, bucket: Bucket::full(quota) }
+    }
+
+    /// Get current rate limit
+    pub fn get_rate(&self) -> u64 {
+        self.rate
+    }
+
+    /// Update rate limit
+    pub fn set_rate(&mut self, bytes_per_sec: u64) {
+        self.rate = bytes_per_sec.max(1);
+        self.quota = quota(self.rate, self.burst);
+        self.bucket.clamp(self.quota);
+    }
+
+    /// Get the bucket depth in bytes
+    pub fn get_burst(&self) -> u64 {
+        self.quota.burst as u64
+    }
+
+    /// Update the bucket depth (0 = one second's worth)
+    pub fn set_burst(&mut self, burst_bytes: u64) {
+        self.burst = burst_bytes;
+        self.quota = quota(self.rate, burst_bytes);
+        self.bucket.clamp(self.quota);
+    }
+
+    /// Take tokens for `bytes`, returning how long to wait before the
+    /// bytes fit within the rate. Tokens may go into debt, so a large
+    /// chunk is paid for by a proportionally long pause.
+    pub fn reserve(&mut self, bytes: usize) -> Duration {
+        self.bucket.reserve(self.quota, bytes as f64)
+    }
+}
+
+fn quota(rate: u64, burst: u64) -> Quota {
+    match burst {
+        0 => Quota::per_second(rate),
+        burst => Quota { rate: rate as f64, burst: burst as f64 },
+    }
+}
+
 /// Parse human-readable bandwidth string (e.g., "3kbps", "1mbps", "500kB/s")
 pub fn parse_bandwidth(s: &str) -> Option<u64> {
     let s = s.trim().to_lowercase();
@@ -107,6 +193,27 @@ pub fn parse_bandwidth(s: &str) -> Option<u64> {
     Some((num * multiplier) as u64)
 }
 
+/// Parse a byte count (e.g., "500000", "256kb", "1.5MB", "64KiB"); bit
+/// rates aren't sizes and are refused
+pub fn parse_size(s: &str) -> Option<u64> {
+    let s = s.trim().to_lowercase();
+    let pos = s.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(s.len());
+    let num: f64 = s[..pos].parse().ok()?;
+
+    let multiplier = match s[pos..].trim() {
+        "" | "b" => 1.0,
+        "k" | "kb" => 1_000.0,
+        "m" | "mb" => 1_000_000.0,
+        "g" | "gb" => 1_000_000_000.0,
+        "ki" | "kib" => 1_024.0,
+        "mi" | "mib" => 1_048_576.0,
+        "gi" | "gib" => 1_073_741_824.0,
+        _ => return None,
+    };
+
+    Some((num * multiplier) as u64)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -120,6 +227,18 @@ mod tests {
         assert_eq!(parse_bandwidth("1024"), Some(1024)); // Plain bytes
     }
 
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("1024"), Some(1024));
+        assert_eq!(parse_size("512B"), Some(512));
+        assert_eq!(parse_size("256kb"), Some(256_000));
+        assert_eq!(parse_size("1.5MB"), Some(1_500_000));
+        assert_eq!(parse_size("64KiB"), Some(65_536));
+        assert_eq!(parse_size("2 mi"), Some(2_097_152));
+        assert_eq!(parse_size("1mbps"), None);
+        assert_eq!(parse_size("kb"), None);
+    }
+
     #[test]
     fn test_throttle_basic() {
         let mut throttle = BandwidthThrottle::new(1_000_000).unwrap(); // 1 MB/s
@@ -130,5 +249,35 @@ mod tests {
         
         throttle.set_rate(500_000);
         assert_eq!(throttle.get_rate(), 500_000);
+        assert_eq!(throttle.get_burst(), 500_000);
+
+        // A deeper bucket takes a burst, and keeps it across rate changes
+        let mut throttle = BandwidthThrottle::with_burst(100, 300).unwrap();
+        assert!(!throttle.consume(300));
+        assert!(throttle.consume(50));
+        throttle.set_rate(200);
+        assert_eq!(throttle.get_burst(), 300);
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let mut bucket = TokenBucket::new(1000);
+        // The first second of traffic is free
+        assert_eq!(bucket.reserve(1000), Duration::ZERO);
+        // Then each byte costs 1ms
+        let wait = bucket.reserve(500);
+        assert!(wait > Duration::from_millis(450) && wait <= Duration::from_millis(500));
+
+        bucket.set_rate(2000);
+        assert_eq!(bucket.get_rate(), 2000);
+
+        // Three seconds of burst, still refilling at the rate
+        let mut bucket = TokenBucket::with_burst(1000, 3000);
+        assert_eq!(bucket.reserve(3000), Duration::ZERO);
+        assert!(bucket.reserve(500) > Duration::from_millis(450));
+        bucket.set_rate(500);
+        assert_eq!(bucket.get_burst(), 3000);
+        bucket.set_burst(0);
+        assert_eq!(bucket.get_burst(), 500);
     }
 }
@@ -0,0 +1,104 @@
+//! Subdomain and hostname checks shared by the relay and the client,
+//! so a name the client accepts in its config is one the relay accepts
+//! at registration.
+
+use crate::Error;
+
+/// Longest DNS label
+pub const MAX_LABEL_LEN: usize = 63;
+
+/// Names kept for the relay operator's own hosts
+pub const RESERVED: &[&str] = &[
+    "www", "api", "admin", "relay", "tunnel", "dashboard", "status", "metrics", "health", "mail", "smtp",
+    "imap", "ns", "ns1", "ns2", "localhost",
+];
+
+/// Why a name was refused
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("{0}")]
+pub struct Invalid(String);
+
+impl From<Invalid> for Error {
+    fn from(e: Invalid) -> Self {
+        Error::Tunnel(e.0)
+    }
+}
+
+/// A subdomain a tunnel may ask for: one lowercase DNS label that isn't
+/// reserved
+pub fn check_subdomain(name: &str) -> Result<(), Invalid> {
+    let invalid = |why: &str| Err(Invalid(format!("Invalid subdomain '{}': {}", name, why)));
+    if name.is_empty() {
+        return invalid("empty");
+    }
+    if name.len() > MAX_LABEL_LEN {
+        return invalid("longer than 63 characters");
+    }
+    if let Some(c) = name.chars().find(|c| !(c.is_ascii_lowercase() || c.is_ascii_digit() || *c == '-')) {
+        return invalid(&format!("'{}' not allowed, use a-z, 0-9 and -", c));
+    }
+    if name.starts_with('-') || name.ends_with('-') {
+        return invalid("cannot start or end with -");
+    }
+    if is_reserved(name) {
+        return Err(Invalid(format!("Subdomain '{}' is reserved", name)));
+    }
+    Ok(())
+}
+
+pub fn is_reserved(name: &str) -> bool {
+    RESERVED.contains(&name)
+}
+
+/// Drop the port from a Host header value: `example.com:8080` →
+/// `example.com`, `[::1]:8080` → `[::1]`. A trailing dot goes too.
+pub fn strip_port(host: &str) -> &str {
+    let host = match host.strip_prefix('[') {
+        Some(v6) => return v6.find(']').map_or(host, |end| &host[..end + 2]),
+        None => host.rsplit_once(':').map_or(host, |(name, port)| {
+            if port.bytes().all(|b| b.is_ascii_digit()) { name } else { host }
+        }),
+    };
+    host.strip_suffix('.').unwrap_or(host)
+}
+
+/// The tunnel label of a host under `base`: `demo.example.com:443` under
+/// `example.com` is `demo`. Hosts outside `base`, or more than one
+/// label below it, have none.
+pub fn subdomain_of(host: &str, base: &str) -> Option<String> {
+    let host = strip_port(host).to_ascii_lowercase();
+    let base = base.trim_end_matches('.').to_ascii_lowercase();
+    let label = host.strip_suffix(&base)?.strip_suffix('.')?;
+    (!label.is_empty() && !label.contains('.')).then(|| label.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_subdomain() {
+        for good in ["demo", "my-api-2", "a", &"x".repeat(63)] {
+            assert!(check_subdomain(good).is_ok(), "{}", good);
+        }
+        for bad in ["", "Demo", "my_api", "-demo", "demo-", "a.b", &"x".repeat(64), "www", "admin"] {
+            assert!(check_subdomain(bad).is_err(), "{}", bad);
+        }
+        assert_eq!(check_subdomain("www").unwrap_err().to_string(), "Subdomain 'www' is reserved");
+    }
+
+    #[test]
+    fn test_hosts() {
+        assert_eq!(strip_port("example.com:8080"), "example.com");
+        assert_eq!(strip_port("example.com."), "example.com");
+        assert_eq!(strip_port("[::1]:8080"), "[::1]");
+        assert_eq!(strip_port("[::1]"), "[::1]");
+
+        assert_eq!(subdomain_of("Demo.Example.com:443", "example.com").as_deref(), Some("demo"));
+        assert_eq!(subdomain_of("demo.example.com.", "example.com.").as_deref(), Some("demo"));
+        assert_eq!(subdomain_of("example.com", "example.com"), None);
+        assert_eq!(subdomain_of("a.b.example.com", "example.com"), None);
+        assert_eq!(subdomain_of("demo.evil.com", "example.com"), None);
+        assert_eq!(subdomain_of("demoexample.com", "example.com"), None);
+    }
+}
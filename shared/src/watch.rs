@@ -0,0 +1,72 @@
+//! Debounced watching of a config file
+
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// Editors save in bursts (truncate, write, rename); wait for quiet
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// Changes to one file, a burst of writes reported once
+pub struct FileWatch {
+    _watcher: RecommendedWatcher,
+    rx: mpsc::Receiver<()>,
+}
+
+impl FileWatch {
+    /// Watch `path`, which need not exist yet
+    pub fn new(path: &Path) -> notify::Result<Self> {
+        let (tx, rx) = mpsc::channel::<()>(16);
+        let file_name = path.file_name().map(|n| n.to_os_string());
+
+        let mut watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+            let Ok(event) = res else { return };
+            let ours = event.paths.iter().any(|p| p.file_name().map(|n| n.to_os_string()) == file_name);
+            if ours && (event.kind.is_modify() || event.kind.is_create()) {
+                let _ = tx.try_send(());
+            }
+        })?;
+
+        // Watch the directory: editors often replace the file instead of
+        // writing to it, which would end a watch on the file itself
+        let dir = match path.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+        Ok(Self { _watcher: watcher, rx })
+    }
+
+    /// Wait for the file to change and settle; `None` once the watch ends
+    pub async fn changed(&mut self) -> Option<()> {
+        self.rx.recv().await?;
+        tokio::time::sleep(DEBOUNCE).await;
+        while self.rx.try_recv().is_ok() {}
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_burst_reported_once() {
+        let dir = std::env::temp_dir().join(format!("ztunnel-watch-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("relay.yml");
+        let mut watch = FileWatch::new(&path).unwrap();
+
+        std::fs::write(dir.join("other.yml"), "a").unwrap();
+        for i in 0..3 {
+            std::fs::write(&path, i.to_string()).unwrap();
+        }
+        let changed = tokio::time::timeout(Duration::from_secs(5), watch.changed()).await;
+        assert_eq!(changed.unwrap(), Some(()));
+        let again = tokio::time::timeout(Duration::from_millis(500), watch.changed()).await;
+        assert!(again.is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}